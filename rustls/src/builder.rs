@@ -1,6 +1,8 @@
 use crate::error::Error;
 use crate::kx::{SupportedKxGroup, ALL_KX_GROUPS};
+use crate::msgs::enums::SignatureScheme;
 use crate::suites::{SupportedCipherSuite, DEFAULT_CIPHER_SUITES};
+use crate::verify;
 use crate::versions;
 
 use std::fmt;
@@ -84,7 +86,13 @@ use std::marker::PhantomData;
 /// 3. Now you must make
 ///    a decision on which protocol versions to support, typically by calling
 ///    [`ConfigBuilder<S, WantsVersions>::with_safe_default_protocol_versions()`].
-/// 5. Now see [`ConfigBuilder<ClientConfig, WantsVerifier>`] or
+/// 4. Alternatively to steps 1 to 3, cipher suites can be chosen
+///    separately for each protocol version, enabling exactly those versions,
+///    by calling [`ConfigBuilder<S, WantsCipherSuites>::with_cipher_suites_per_version()`].
+///    Then only a decision on key exchange groups remains.
+/// 5. Optionally, choose the signature schemes to advertise with
+///    [`ConfigBuilder<S, WantsVerifier>::with_signature_schemes()`].
+/// 6. Now see [`ConfigBuilder<ClientConfig, WantsVerifier>`] or
 ///    [`ConfigBuilder<ServerConfig, WantsVerifier>`] for further steps.
 ///
 /// [`ServerConfig`]: crate::ServerConfig
@@ -133,6 +141,7 @@ impl<S: ConfigSide> ConfigBuilder<S, WantsCipherSuites> {
                 cipher_suites: DEFAULT_CIPHER_SUITES.to_vec(),
                 kx_groups: ALL_KX_GROUPS.to_vec(),
                 versions: versions::EnabledVersions::new(versions::DEFAULT_VERSIONS),
+                signature_schemes: None,
             },
             side: self.side,
         }
@@ -159,6 +168,60 @@ impl<S: ConfigSide> ConfigBuilder<S, WantsCipherSuites> {
     pub fn with_safe_default_cipher_suites(self) -> ConfigBuilder<S, WantsKxGroups> {
         self.with_cipher_suites(DEFAULT_CIPHER_SUITES)
     }
+
+    /// Choose a set of cipher suites for each protocol version, and
+    /// enable exactly those versions.
+    ///
+    /// For example, TLS1.3 can be enabled with all its suites while
+    /// TLS1.2 is restricted to a few.  Suites listed for a version
+    /// they don't belong to are ignored.
+    ///
+    /// This fails if a version is listed twice, or is left with no
+    /// suites; or if no versions are listed.
+    pub fn with_cipher_suites_per_version(
+        self,
+        per_version: &[(
+            &'static versions::SupportedProtocolVersion,
+            &[SupportedCipherSuite],
+        )],
+    ) -> Result<ConfigBuilder<S, WantsKxGroupsForVersions>, Error> {
+        if per_version.is_empty() {
+            return Err(Error::General("no protocol versions configured".into()));
+        }
+
+        let mut cipher_suites = Vec::new();
+        let mut enabled_versions: Vec<&'static versions::SupportedProtocolVersion> = Vec::new();
+        for &(version, suites) in per_version {
+            if enabled_versions.contains(&version) {
+                return Err(Error::General(format!(
+                    "cipher suites configured more than once for {:?}",
+                    version.version
+                )));
+            }
+
+            let usable = suites
+                .iter()
+                .filter(|suite| suite.version() == version)
+                .collect::<Vec<_>>();
+            if usable.is_empty() {
+                return Err(Error::General(format!(
+                    "no usable cipher suites configured for {:?}",
+                    version.version
+                )));
+            }
+
+            cipher_suites.extend(usable);
+            enabled_versions.push(version);
+        }
+
+        Ok(ConfigBuilder {
+            state: WantsKxGroupsForVersions {
+                cipher_suites,
+                versions: enabled_versions,
+            },
+            side: self.side,
+        })
+    }
 }
 
 /// Config builder state where the caller must supply key exchange groups.
@@ -192,6 +255,42 @@ impl<S: ConfigSide> ConfigBuilder<S, WantsKxGroups> {
     }
 }
 
+/// Config builder state where the caller must supply key exchange groups,
+/// having chosen cipher suites for each protocol version.
+///
+/// For more information, see the [`ConfigBuilder`] documentation.
+pub struct WantsKxGroupsForVersions {
+    cipher_suites: Vec<SupportedCipherSuite>,
+    versions: Vec<&'static versions::SupportedProtocolVersion>,
+}
+
+impl<S: ConfigSide> ConfigBuilder<S, WantsKxGroupsForVersions> {
+    /// Choose a specific set of key exchange groups.
+    pub fn with_kx_groups(
+        self,
+        kx_groups: &[&'static SupportedKxGroup],
+    ) -> Result<ConfigBuilder<S, WantsVerifier>, Error> {
+        if kx_groups.is_empty() {
+            return Err(Error::General("no kx groups configured".into()));
+        }
+
+        Ok(ConfigBuilder {
+            state: WantsVerifier {
+                cipher_suites: self.state.cipher_suites,
+                kx_groups: kx_groups.to_vec(),
+                versions: versions::EnabledVersions::new(&self.state.versions),
+                signature_schemes: None,
+            },
+            side: self.side,
+        })
+    }
+
+    /// Choose the default set of key exchange groups ([`ALL_KX_GROUPS`]).
+    pub fn with_safe_default_kx_groups(self) -> Result<ConfigBuilder<S, WantsVerifier>, Error> {
+        self.with_kx_groups(&ALL_KX_GROUPS)
+    }
+}
+
 /// Config builder state where the caller must supply TLS protocol versions.
 ///
 /// For more information, see the [`ConfigBuilder`] documentation.
@@ -234,6 +333,7 @@ impl<S: ConfigSide> ConfigBuilder<S, WantsVersions> {
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: versions::EnabledVersions::new(versions),
+                signature_schemes: None,
             },
             side: self.side,
         })
@@ -247,6 +347,45 @@ pub struct WantsVerifier {
     pub cipher_suites: Vec<SupportedCipherSuite>,
     pub kx_groups: Vec<&'static SupportedKxGroup>,
     pub versions: versions::EnabledVersions,
+    pub(crate) signature_schemes: Option<Vec<SignatureScheme>>,
+}
+
+impl<S: ConfigSide> ConfigBuilder<S, WantsVerifier> {
+    /// Choose the signature schemes to advertise, most preferred first.
+    ///
+    /// Of these, only those supported by the verifier chosen next are
+    /// advertised, in the order given here.
+    ///
+    /// A client sends these in its `signature_algorithms` extension, which
+    /// limits how the server may sign and so which certificate it may
+    /// choose; the client rejects a server signature made with any other
+    /// scheme.  A server sends them when asking for a client certificate.
+    ///
+    /// This fails if `schemes` is empty, mentions a scheme twice, or
+    /// mentions one rustls can't verify.
+    pub fn with_signature_schemes(mut self, schemes: &[SignatureScheme]) -> Result<Self, Error> {
+        if schemes.is_empty() {
+            return Err(Error::General("no signature schemes configured".into()));
+        }
+
+        for (i, scheme) in schemes.iter().enumerate() {
+            if !verify::supports_signature_scheme(*scheme) {
+                return Err(Error::General(format!(
+                    "unsupported signature scheme {:?}",
+                    scheme
+                )));
+            }
+            if schemes[..i].contains(scheme) {
+                return Err(Error::General(format!(
+                    "signature scheme {:?} configured more than once",
+                    scheme
+                )));
+            }
+        }
+
+        self.state.signature_schemes = Some(schemes.to_vec());
+        Ok(self)
+    }
 }
 
 /// Helper trait to abstract [`ConfigBuilder`] over building a [`ClientConfig`] or [`ServerConfig`].
@@ -1,5 +1,6 @@
 use crate::error::Error;
 use crate::msgs::codec;
+use crate::msgs::enums::{ContentType, ProtocolVersion};
 use crate::msgs::message::{BorrowedPlainMessage, OpaqueMessage, PlainMessage};
 
 use ring::{aead, hkdf};
@@ -14,6 +15,24 @@ pub trait MessageDecrypter: Send + Sync {
 /// Objects with this trait can encrypt TLS messages.
 pub trait MessageEncrypter: Send + Sync {
     fn encrypt(&self, m: BorrowedPlainMessage, seq: u64) -> Result<OpaqueMessage, Error>;
+
+    /// Encrypt `m` like `encrypt`, but write the whole record into
+    /// `out` and return its length.  If `out` is too short, nothing is
+    /// written and `Error::EncryptBufferTooSmall` is returned.
+    ///
+    /// The default implementation copies the output of `encrypt`, so
+    /// does allocate.
+    fn encrypt_into(
+        &self,
+        m: BorrowedPlainMessage,
+        seq: u64,
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        let record = self.encrypt(m, seq)?;
+        let payload = record_payload(out, record.typ, record.version, record.payload.0.len())?;
+        payload.copy_from_slice(&record.payload.0);
+        Ok(usize::from(OpaqueMessage::HEADER_SIZE) + payload.len())
+    }
 }
 
 impl dyn MessageEncrypter {
@@ -82,6 +101,25 @@ pub fn make_nonce(iv: &Iv, seq: u64) -> ring::aead::Nonce {
     aead::Nonce::assume_unique_for_key(nonce)
 }
 
+/// Write the header of a record with `len` bytes of payload into
+/// `out`, and return the part of `out` where its payload goes.
+pub(crate) fn record_payload(
+    out: &mut [u8],
+    typ: ContentType,
+    version: ProtocolVersion,
+    len: usize,
+) -> Result<&mut [u8], Error> {
+    let header_len = usize::from(OpaqueMessage::HEADER_SIZE);
+    let required = header_len + len;
+    let record = out
+        .get_mut(..required)
+        .ok_or(Error::EncryptBufferTooSmall { required })?;
+
+    let (header, payload) = record.split_at_mut(header_len);
+    OpaqueMessage::encode_header(typ, version, len, header);
+    Ok(payload)
+}
+
 /// A `MessageEncrypter` which doesn't work.
 struct InvalidMessageEncrypter {}
 
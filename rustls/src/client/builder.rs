@@ -1,10 +1,14 @@
 use crate::anchors;
 use crate::builder::{ConfigBuilder, WantsVerifier};
 use crate::client::handy;
-use crate::client::{ClientConfig, ResolvesClientCert};
+use crate::client::{ClientConfig, ExtensionOrder, ResolvesClientCert};
+use crate::conn::RenegotiationResponse;
 use crate::error::Error;
 use crate::key;
 use crate::kx::SupportedKxGroup;
+use crate::msgs::enums::SignatureScheme;
+use crate::msgs::hsjoiner;
+use crate::sign;
 use crate::suites::SupportedCipherSuite;
 use crate::verify::{self, CertificateTransparencyPolicy};
 use crate::versions;
@@ -25,6 +29,7 @@ impl ConfigBuilder<ClientConfig, WantsVerifier> {
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
+                signature_schemes: self.state.signature_schemes,
                 root_store,
             },
             side: PhantomData::default(),
@@ -42,6 +47,7 @@ impl ConfigBuilder<ClientConfig, WantsVerifier> {
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
+                signature_schemes: self.state.signature_schemes,
                 verifier,
             },
             side: PhantomData::default(),
@@ -60,6 +66,7 @@ pub struct WantsTransparencyPolicyOrClientCert {
     cipher_suites: Vec<SupportedCipherSuite>,
     kx_groups: Vec<&'static SupportedKxGroup>,
     versions: versions::EnabledVersions,
+    signature_schemes: Option<Vec<SignatureScheme>>,
     root_store: anchors::RootCertStore,
 }
 
@@ -104,6 +111,20 @@ impl ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert> {
             .with_client_cert_resolver(Arc::new(handy::FailResolveClientCert {}))
     }
 
+    /// Choose a client certificate only when the server asks for one.
+    ///
+    /// See [`ConfigBuilder::with_client_cert_on_request`] in the [`WantsClientCert`] state.
+    pub fn with_client_cert_on_request<F>(self, resolve: F) -> ClientConfig
+    where
+        F: Fn(&[&[u8]], &[SignatureScheme]) -> Option<Arc<sign::CertifiedKey>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.with_logs(None)
+            .with_client_cert_on_request(resolve)
+    }
+
     /// Sets a custom [`ResolvesClientCert`].
     pub fn with_client_cert_resolver(
         self,
@@ -122,6 +143,7 @@ impl ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert> {
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
+                signature_schemes: self.state.signature_schemes,
                 verifier: Arc::new(verify::WebPkiVerifier::new(
                     self.state.root_store,
                     ct_policy,
@@ -140,6 +162,7 @@ pub struct WantsClientCert {
     cipher_suites: Vec<SupportedCipherSuite>,
     kx_groups: Vec<&'static SupportedKxGroup>,
     versions: versions::EnabledVersions,
+    signature_schemes: Option<Vec<SignatureScheme>>,
     verifier: Arc<dyn verify::ServerCertVerifier>,
 }
 
@@ -165,6 +188,25 @@ impl ConfigBuilder<ClientConfig, WantsClientCert> {
         self.with_client_cert_resolver(Arc::new(handy::FailResolveClientCert {}))
     }
 
+    /// Choose a client certificate only when the server asks for one.
+    ///
+    /// `resolve` is called with the server's acceptable issuers and
+    /// signature schemes when a `CertificateRequest` arrives, and not
+    /// at all otherwise.  So a chain and key need not be loaded until
+    /// then, and can be picked to suit what the server accepts.
+    ///
+    /// Returning `None` sends an empty `Certificate` message, and the
+    /// handshake continues without client authentication.
+    pub fn with_client_cert_on_request<F>(self, resolve: F) -> ClientConfig
+    where
+        F: Fn(&[&[u8]], &[SignatureScheme]) -> Option<Arc<sign::CertifiedKey>>
+            + Send
+            + Sync
+            + 'static,
+    {
+        self.with_client_cert_resolver(Arc::new(handy::ResolvesClientCertOnRequest(resolve)))
+    }
+
     /// Sets a custom [`ResolvesClientCert`].
     pub fn with_client_cert_resolver(
         self,
@@ -175,14 +217,37 @@ impl ConfigBuilder<ClientConfig, WantsClientCert> {
             kx_groups: self.state.kx_groups,
             alpn_protocols: Vec::new(),
             session_storage: handy::ClientSessionMemoryCache::new(256),
+            max_tickets_per_server: None,
             max_fragment_size: None,
+            max_fragment_length: None,
+            record_size_limit: None,
+            max_handshake_message_size: hsjoiner::DEFAULT_MAX_HANDSHAKE_MESSAGE_SIZE,
+            max_handshake_size: hsjoiner::DEFAULT_MAX_HANDSHAKE_SIZE,
+            cert_decompressors: Vec::new(),
+            max_peer_cert_chain_len: 10,
             client_auth_cert_resolver,
             enable_tickets: true,
+            require_ems: false,
             versions: self.state.versions,
             enable_sni: true,
+            enable_grease: false,
+            extension_order: ExtensionOrder::default(),
             verifier: self.state.verifier,
+            signature_schemes: self.state.signature_schemes,
+            raw_public_key_verifier: None,
+            send_raw_public_key: false,
+            nonce_source: None,
             key_log: Arc::new(NoKeyLog {}),
+            handshake_observer: None,
+            on_alert: None,
+            renegotiation_response: RenegotiationResponse::Decline,
             enable_early_data: false,
+            external_psks: Vec::new(),
+            enable_post_handshake_auth: false,
+            enable_delegated_credentials: false,
+            ocsp_verifier: None,
+            ct_policy: None,
+            server_cert_policy: None,
         }
     }
 }
@@ -1,31 +1,41 @@
 use crate::builder::{ConfigBuilder, WantsCipherSuites};
-use crate::conn::{CommonState, ConnectionCommon, Protocol, Side};
+use crate::compress::CertDecompressor;
+use crate::conn::{AlertCallback, CommonState, ConnectionCommon, HandshakeDetails, Protocol};
+use crate::conn::{RenegotiationResponse, Side};
 use crate::error::Error;
-use crate::kx::SupportedKxGroup;
+use crate::kx::{self, SupportedKxGroup};
 #[cfg(feature = "logging")]
 use crate::log::trace;
 #[cfg(feature = "quic")]
 use crate::msgs::enums::AlertDescription;
 use crate::msgs::enums::CipherSuite;
+use crate::msgs::enums::ExtensionType;
+use crate::msgs::enums::MaxFragmentLength;
+use crate::msgs::enums::NamedGroup;
 use crate::msgs::enums::ProtocolVersion;
 use crate::msgs::enums::SignatureScheme;
+use crate::msgs::fragmenter::{max_record_size_limit, MIN_RECORD_SIZE_LIMIT};
 use crate::msgs::handshake::ClientExtension;
+use crate::rand::{RandomSource, SystemRandomSource};
 use crate::sign;
 use crate::suites::SupportedCipherSuite;
+use crate::tls13::ExternalPsk;
 use crate::verify;
 use crate::versions;
-use crate::KeyLog;
+use crate::{HandshakeObserver, KeyLog};
 
 use super::hs;
 #[cfg(feature = "quic")]
 use crate::quic;
 
+use ring::digest;
 use std::convert::TryFrom;
 use std::error::Error as StdError;
 use std::marker::PhantomData;
+use std::net::IpAddr;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
-use std::{fmt, io, mem};
+use std::{cmp, fmt, io, mem};
 
 /// A trait for the ability to store client session data.
 /// The keys and values are opaque.
@@ -46,6 +56,27 @@ pub trait StoresClientSessions: Send + Sync {
     /// Returns the latest value for `key`.  Returns `None`
     /// if there's no such value.
     fn get(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+    /// Adds `value` to the pool of values for `key`, discarding
+    /// the oldest values so at most `max` are kept.  Returns `true`
+    /// if the value was stored.
+    ///
+    /// This is used for TLS1.3 tickets when
+    /// [`ClientConfig::max_tickets_per_server`] is set.  The default
+    /// implementation keeps only `value`, using `put`.
+    fn put_ticket(&self, key: Vec<u8>, value: Vec<u8>, max: usize) -> bool {
+        let _ = max;
+        self.put(key, value)
+    }
+
+    /// Removes and returns the oldest value in the pool for `key`.
+    /// Returns `None` if the pool is empty.
+    ///
+    /// The default implementation returns the value from `get`
+    /// without removing it, so the same ticket is used repeatedly.
+    fn take_ticket(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get(key)
+    }
 }
 
 /// A trait for the ability to choose a certificate chain and
@@ -57,7 +88,13 @@ pub trait ResolvesClientCert: Send + Sync {
     ///
     /// `acceptable_issuers` is undecoded and unverified by the rustls
     /// library, but it should be expected to contain a DER encodings
-    /// of X501 NAMEs.
+    /// of X501 NAMEs.  They are exactly as the server sent them, in its
+    /// order: from `certificate_authorities` in TLS1.3, or the request's
+    /// own list in TLS1.2.  It is empty if the server named no CAs, in
+    /// which case any chain may be acceptable.
+    ///
+    /// This is only called once the server has sent a
+    /// `CertificateRequest`.
     ///
     /// Return None to continue the handshake without any client
     /// authentication.  The server may reject the handshake later
@@ -83,9 +120,22 @@ pub trait ResolvesClientCert: Send + Sync {
 /// # Defaults
 ///
 /// * [`ClientConfig::max_fragment_size`]: the default is `None`: TLS packets are not fragmented to a specific size.
+/// * [`ClientConfig::max_fragment_length`]: the default is `None`: no fragment length is negotiated.
+/// * [`ClientConfig::record_size_limit`]: the default is `None`: no record size limit is negotiated.
+/// * [`ClientConfig::max_handshake_message_size`]: handshake messages of up to 64KB are accepted.
+/// * [`ClientConfig::max_handshake_size`]: the server's handshake messages may total up to 256KB.
+/// * [`ClientConfig::cert_decompressors`]: the default is empty -- server certificates are not compressed.
+/// * [`ClientConfig::max_peer_cert_chain_len`]: the server may present up to 10 certificates.
 /// * [`ClientConfig::session_storage`]: the default stores 256 sessions in memory.
+/// * [`ClientConfig::max_tickets_per_server`]: the default is `None`: only the latest session for each server is kept.
+/// * [`ClientConfig::require_ems`]: TLS1.2 servers are not required to use the extended master secret.
 /// * [`ClientConfig::alpn_protocols`]: the default is empty -- no ALPN protocol is negotiated.
+/// * [`ClientConfig::external_psks`]: the default is empty -- no external PSKs are offered.
 /// * [`ClientConfig::key_log`]: key material is not logged.
+/// * [`ClientConfig::handshake_observer`]: handshakes are not observed.
+/// * [`ClientConfig::on_alert`]: received alerts are not reported.
+/// * [`ClientConfig::renegotiation_response`]: TLS1.2 renegotiation is declined with a warning alert.
+/// * [`ClientConfig::extension_order`]: `ClientHello` extensions are sent in rustls's usual order.
 #[derive(Clone)]
 pub struct ClientConfig {
     /// List of ciphersuites, in preference order.
@@ -100,11 +150,32 @@ pub struct ClientConfig {
 
     /// Which ALPN protocols we include in our client hello.
     /// If empty, no ALPN extension is sent.
+    ///
+    /// A server may ignore the extension, or be configured to
+    /// continue when it shares none of these protocols.  Then the
+    /// handshake completes with [`ClientConnection::alpn_protocol`]
+    /// returning `None`, and it is for the application to decide
+    /// whether to carry on.  QUIC connections fail instead.
+    ///
+    /// [`ClientConnection::alpn_protocol`]: crate::CommonState::alpn_protocol
     pub alpn_protocols: Vec<Vec<u8>>,
 
     /// How we store session data or tickets.
     pub session_storage: Arc<dyn StoresClientSessions>,
 
+    /// The number of TLS1.3 tickets to keep for each server, if
+    /// the server issues several.
+    ///
+    /// If set, each resumption attempt consumes one ticket from the
+    /// pool, so no ticket is offered twice; once the pool is empty a
+    /// full handshake is done.  This relies on `session_storage`
+    /// implementing [`StoresClientSessions::put_ticket`] and
+    /// [`StoresClientSessions::take_ticket`].
+    ///
+    /// The default is `None`: only the latest session is kept, and it
+    /// is reused until it expires or is replaced.
+    pub max_tickets_per_server: Option<usize>,
+
     /// The maximum size of TLS message we'll emit.  If None, we don't limit TLS
     /// message lengths except to the 2**16 limit specified in the standard.
     ///
@@ -114,6 +185,61 @@ pub struct ClientConfig {
     /// Setting this value to the TCP MSS may improve latency for stream-y workloads.
     pub max_fragment_size: Option<usize>,
 
+    /// The maximum record payload to ask the server to send, using the
+    /// RFC6066 `max_fragment_length` extension.  If the server agrees,
+    /// we don't send larger records either.
+    ///
+    /// The default is `None`: the extension is not sent.  `Unknown`
+    /// values are reported as errors from ClientConnection::new.
+    pub max_fragment_length: Option<MaxFragmentLength>,
+
+    /// The largest record we'll accept from the server, advertised using
+    /// the RFC8449 `record_size_limit` extension.  This counts the record's
+    /// plaintext and, in TLS1.3, its inner content type byte.
+    ///
+    /// If the server supports the extension it replies with its own
+    /// limit, which we respect when sending, and we refuse protected
+    /// records from the server larger than ours with a `record_overflow`
+    /// alert.  The two limits are independent.
+    ///
+    /// The default is `None`: the extension is not sent.  Values below 64,
+    /// or above TLS1.3's maximum of 2^14+1, are reported as errors from
+    /// ClientConnection::new.  If TLS1.3 is disabled, at most 2^14 is sent.
+    pub record_size_limit: Option<u16>,
+
+    /// The largest handshake message we'll accept from the server,
+    /// excluding its four byte header.  Larger messages are refused as
+    /// soon as their header arrives, with a `decode_error` alert.
+    ///
+    /// The default is 65535 bytes.
+    pub max_handshake_message_size: usize,
+
+    /// The most handshake message bytes we'll accept from the server
+    /// before the handshake completes, counting headers.  Once this is
+    /// exceeded the handshake fails with a `decode_error` alert.
+    /// Messages received after the handshake, such as new session
+    /// tickets, are not counted.
+    ///
+    /// The default is 256KB.
+    pub max_handshake_size: usize,
+
+    /// Certificate compression algorithms we'll accept from TLS1.3
+    /// servers, in preference order, offered with RFC8879's
+    /// `compress_certificate` extension.  A certificate compressed
+    /// with any other algorithm is refused with a `bad_certificate`
+    /// alert, as is one which would not fit in
+    /// `max_handshake_message_size` once decompressed.
+    ///
+    /// The default is empty: compressed certificates are not offered.
+    pub cert_decompressors: Vec<Arc<dyn CertDecompressor>>,
+
+    /// The most certificates, end-entity included, we'll accept in
+    /// the server's certificate chain.  A longer chain is refused with a
+    /// `bad_certificate` alert, without being verified.
+    ///
+    /// The default is 10.
+    pub max_peer_cert_chain_len: usize,
+
     /// How to decide what client auth certificate/keys to use.
     pub client_auth_cert_resolver: Arc<dyn ResolvesClientCert>,
 
@@ -124,6 +250,15 @@ pub struct ClientConfig {
     /// The default is true.
     pub enable_tickets: bool,
 
+    /// Whether to refuse TLS1.2 servers which don't agree to use the
+    /// extended master secret (RFC7627).  Without it, a TLS1.2 session's
+    /// keys can be shared with a different connection, which enables the
+    /// "triple handshake" attack.  The handshake is then failed with a
+    /// `handshake_failure` alert.
+    ///
+    /// This has no effect on TLS1.3.  The default is false.
+    pub require_ems: bool,
+
     /// Supported versions, in no particular order.  The default
     /// is all supported versions.
     pub(super) versions: versions::EnabledVersions,
@@ -134,18 +269,111 @@ pub struct ClientConfig {
     /// The default is true.
     pub enable_sni: bool,
 
+    /// Whether to send GREASE (RFC8701) values: cipher suites, groups
+    /// and extensions that are reserved never to mean anything, so that
+    /// servers which fail on unknown values are noticed.  They are chosen
+    /// at random for each connection.
+    ///
+    /// The default is false.
+    pub enable_grease: bool,
+
+    /// The order in which to send `ClientHello` extensions.
+    pub extension_order: ExtensionOrder,
+
     /// How to verify the server certificate chain.
     pub(super) verifier: Arc<dyn verify::ServerCertVerifier>,
 
+    /// The signature schemes to offer the server, if not all those
+    /// `verifier` supports.
+    pub(super) signature_schemes: Option<Vec<SignatureScheme>>,
+
+    /// How to verify the server's raw public key, if we offer to
+    /// accept one in place of a certificate chain (RFC7250).
+    pub(super) raw_public_key_verifier: Option<Arc<dyn verify::RawPublicKeyVerifier>>,
+
+    /// Whether to offer to authenticate with a raw public key, rather
+    /// than the certificate chain chosen by `client_auth_cert_resolver`.
+    pub(super) send_raw_public_key: bool,
+
+    /// Where to get the client random and other nonces from, if not
+    /// the system RNG.
+    pub(super) nonce_source: Option<Arc<dyn RandomSource>>,
+
     /// How to output key material for debugging.  The default
     /// does nothing.
     pub key_log: Arc<dyn KeyLog>,
 
+    /// Where to report the progress of each handshake, for example to
+    /// measure its duration.  The default is `None`: nothing is reported.
+    pub handshake_observer: Option<Arc<dyn HandshakeObserver>>,
+
+    /// Called with each alert received from the server, warnings
+    /// included, before rustls acts on it.  A fatal alert still ends
+    /// the connection.
+    pub on_alert: Option<AlertCallback>,
+
+    /// What to do when a TLS1.2 server sends `HelloRequest` after the
+    /// handshake, asking to renegotiate.
+    pub renegotiation_response: RenegotiationResponse,
+
     /// Whether to send data on the first flight ("early data") in
     /// TLS 1.3 handshakes.
     ///
     /// The default is false.
     pub enable_early_data: bool,
+
+    /// TLS1.3 PSKs provisioned out of band, which are offered to the
+    /// server when no session is being resumed.  If the server accepts
+    /// one it authenticates the server in place of a certificate, and
+    /// no client certificate is sent.
+    ///
+    /// PSKs whose hash function isn't that of any configured TLS1.3
+    /// cipher suite are never offered.  If the server accepts none, the
+    /// handshake continues with certificates.  The default is empty.
+    pub external_psks: Vec<ExternalPsk>,
+
+    /// Whether to offer TLS1.3 post-handshake authentication, letting
+    /// the server ask for a certificate after the handshake.  Such
+    /// requests are answered using `client_auth_cert_resolver`.
+    ///
+    /// This is never offered for QUIC.  The default is false, in which
+    /// case a post-handshake `CertificateRequest` is a fatal error.
+    pub enable_post_handshake_auth: bool,
+
+    /// Whether to offer to accept RFC9345 delegated credentials from
+    /// TLS1.3 servers.  The server may then sign the handshake with a
+    /// short-lived key that its end-entity certificate vouches for.
+    ///
+    /// The certificate chain is verified as usual, but the handshake
+    /// signature is checked against the credential's key rather than by
+    /// the `ServerCertVerifier`.  A credential which has expired, which
+    /// the end-entity certificate did not sign, or which comes with a
+    /// certificate lacking the DelegationUsage extension fails the
+    /// handshake.  The default is false.
+    pub enable_delegated_credentials: bool,
+
+    /// How to check an OCSP response stapled by the server, after its
+    /// certificate chain has been verified.  A revoked certificate fails
+    /// the handshake.
+    ///
+    /// The default is `None`: stapled responses are only passed to the
+    /// certificate verifier.
+    pub ocsp_verifier: Option<Arc<dyn verify::OcspVerifier>>,
+
+    /// A Certificate Transparency policy that the SCTs sent by the
+    /// server must satisfy, after its certificate chain has been verified.
+    /// Setting this always asks the server for SCTs.
+    ///
+    /// The default is `None`: SCTs are only passed to the certificate
+    /// verifier.
+    pub ct_policy: Option<verify::CtPolicy>,
+
+    /// Called with the server's certificate once all the checks above
+    /// have passed, to apply the application's own policy.
+    ///
+    /// This is not called for servers which authenticate with a raw
+    /// public key.  The default is `None`.
+    pub server_cert_policy: Option<Arc<dyn verify::ServerCertPolicy>>,
 }
 
 impl ClientConfig {
@@ -171,6 +399,12 @@ impl ClientConfig {
                 .any(|cs| cs.version().version == v)
     }
 
+    pub(super) fn random(&self) -> &dyn RandomSource {
+        self.nonce_source
+            .as_deref()
+            .unwrap_or(&SystemRandomSource)
+    }
+
     /// Access configuration options whose use is dangerous and requires
     /// extra care.
     #[cfg(feature = "dangerous_configuration")]
@@ -188,20 +422,21 @@ impl ClientConfig {
 
 /// Encodes ways a client can know the expected name of the server.
 ///
-/// This currently covers knowing the DNS name of the server, but
-/// will be extended in the future to knowing the IP address of the
-/// server, as well as supporting privacy-preserving names for the
-/// server ("ECH").  For this reason this enum is `non_exhaustive`.
+/// This currently covers knowing the DNS name or the IP address of the
+/// server, but will be extended in the future to supporting
+/// privacy-preserving names for the server ("ECH").  For this reason this
+/// enum is `non_exhaustive`.
 ///
 /// # Making one
 ///
-/// If you have a DNS name as a `&str`, this type implements `TryFrom<&str>`,
-/// so you can do:
+/// If you have a DNS name or an IP address as a `&str`, this type implements
+/// `TryFrom<&str>`, so you can do:
 ///
 /// ```
 /// # use std::convert::{TryInto, TryFrom};
 /// # use rustls::ServerName;
 /// ServerName::try_from("example.com").expect("invalid DNS name");
+/// ServerName::try_from("198.51.100.1").expect("invalid IP address");
 ///
 /// // or, alternatively...
 ///
@@ -215,6 +450,12 @@ pub enum ServerName {
     /// is sent in the TLS Server Name Indication (SNI)
     /// extension.
     DnsName(verify::DnsName),
+
+    /// The server is identified by an IP address.  RFC6066 does
+    /// not allow IP addresses in SNI, so the extension is not sent.
+    /// The server's certificate must have the address as an
+    /// `iPAddress` subject alternative name.
+    IpAddress(IpAddr),
 }
 
 impl ServerName {
@@ -224,6 +465,7 @@ impl ServerName {
     pub fn for_sni(&self) -> Option<webpki::DnsNameRef> {
         match self {
             Self::DnsName(dns_name) => Some(dns_name.0.as_ref()),
+            Self::IpAddress(_) => None,
         }
     }
 
@@ -231,25 +473,36 @@ impl ServerName {
     pub fn encode(&self) -> Vec<u8> {
         enum UniqueTypeCode {
             DnsName = 0x01,
+            IpAddress = 0x02,
         }
 
-        let Self::DnsName(dns_name) = self;
-        let bytes = dns_name.0.as_ref();
+        let (type_code, bytes) = match self {
+            Self::DnsName(dns_name) => {
+                let bytes = dns_name.0.as_ref();
+                (UniqueTypeCode::DnsName, bytes.as_ref().to_vec())
+            }
+            Self::IpAddress(IpAddr::V4(ip)) => (UniqueTypeCode::IpAddress, ip.octets().to_vec()),
+            Self::IpAddress(IpAddr::V6(ip)) => (UniqueTypeCode::IpAddress, ip.octets().to_vec()),
+        };
 
-        let mut r = Vec::with_capacity(2 + bytes.as_ref().len());
-        r.push(UniqueTypeCode::DnsName as u8);
-        r.push(bytes.as_ref().len() as u8);
-        r.extend_from_slice(bytes.as_ref());
+        let mut r = Vec::with_capacity(2 + bytes.len());
+        r.push(type_code as u8);
+        r.push(bytes.len() as u8);
+        r.extend_from_slice(&bytes);
 
         r
     }
 }
 
 /// Attempt to make a ServerName from a string by parsing
-/// it as a DNS name.
+/// it as an IP address, or else as a DNS name.
 impl TryFrom<&str> for ServerName {
     type Error = InvalidDnsNameError;
     fn try_from(s: &str) -> Result<Self, Self::Error> {
+        if let Ok(ip) = s.parse() {
+            return Ok(Self::IpAddress(ip));
+        }
+
         match webpki::DnsNameRef::try_from_ascii_str(s) {
             Ok(dns) => Ok(Self::DnsName(verify::DnsName(dns.into()))),
             Err(webpki::InvalidDnsNameError) => Err(InvalidDnsNameError),
@@ -275,8 +528,9 @@ impl StdError for InvalidDnsNameError {}
 pub(super) mod danger {
     use std::sync::Arc;
 
-    use super::verify::ServerCertVerifier;
+    use super::verify::{RawPublicKeyVerifier, ServerCertVerifier};
     use super::ClientConfig;
+    use crate::rand::RandomSource;
 
     /// Accessor for dangerous configuration options.
     pub struct DangerousClientConfig<'a> {
@@ -289,6 +543,40 @@ pub(super) mod danger {
         pub fn set_certificate_verifier(&mut self, verifier: Arc<dyn ServerCertVerifier>) {
             self.cfg.verifier = verifier;
         }
+
+        /// Offers to accept a raw public key (RFC7250) from the server in
+        /// place of its certificate chain.
+        ///
+        /// If the server agrees, `verifier` decides whether its key is
+        /// acceptable and the `ServerCertVerifier` is not used at all.
+        /// Otherwise the server's certificate chain is verified as usual.
+        /// This only applies to TLS1.3.
+        pub fn set_raw_public_key_verifier(&mut self, verifier: Arc<dyn RawPublicKeyVerifier>) {
+            self.cfg.raw_public_key_verifier = Some(verifier);
+        }
+
+        /// Offers to authenticate to the server with a raw public key (RFC7250).
+        ///
+        /// If the server agrees, the `SubjectPublicKeyInfo` of the end-entity
+        /// certificate chosen by `client_auth_cert_resolver` is sent in place
+        /// of the chain.  This only applies to TLS1.3.
+        pub fn set_send_raw_public_key(&mut self, send: bool) {
+            self.cfg.send_raw_public_key = send;
+        }
+
+        /// Takes the nonces for connections from `source`, rather than
+        /// the system RNG.  This is for tests, for example with a
+        /// [`SeededNonceSource`](crate::rand::SeededNonceSource): anything
+        /// but a good RNG defeats the security of TLS.
+        ///
+        /// `source` supplies the client random, session ID, and any GREASE
+        /// values or shuffled extension order.  Key shares and signatures
+        /// are always made with the system RNG, so handshakes are not
+        /// reproducible; only a TLS1.2 `ClientHello`, which has no key
+        /// share, comes out the same each time.
+        pub fn set_nonce_source(&mut self, source: Arc<dyn RandomSource>) {
+            self.cfg.nonce_source = Some(source);
+        }
     }
 }
 
@@ -304,12 +592,18 @@ enum EarlyDataState {
 pub(super) struct EarlyData {
     state: EarlyDataState,
     left: usize,
+    sent: usize,
+    /// Plaintext from `writer()` sent as early data, to be sent again
+    /// if the server rejects it.
+    resend: Vec<u8>,
 }
 
 impl EarlyData {
     fn new() -> Self {
         Self {
             left: 0,
+            sent: 0,
+            resend: Vec::new(),
             state: EarlyDataState::Disabled,
         }
     }
@@ -347,33 +641,98 @@ impl EarlyData {
         self.state = match self.state {
             EarlyDataState::Accepted => EarlyDataState::AcceptedFinished,
             _ => panic!("bad EarlyData state"),
-        }
+        };
+        self.resend = Vec::new();
     }
 
-    fn check_write(&mut self, sz: usize) -> io::Result<usize> {
+    /// Records that `sz` bytes of early data were actually sent,
+    /// which may be fewer than `check_write` or `check_send` allowed.
+    fn sent(&mut self, sz: usize) {
+        self.left -= sz;
+        self.sent += sz;
+    }
+
+    /// Records that `data`, written with `writer()`, was sent as early
+    /// data.  Unlike `sent`, this is kept to send again if need be.
+    fn sent_from_writer(&mut self, data: &[u8]) {
+        self.left -= data.len();
+        self.resend.extend_from_slice(data);
+    }
+
+    fn check_write(&self, sz: usize) -> io::Result<usize> {
         match self.state {
             EarlyDataState::Disabled => unreachable!(),
-            EarlyDataState::Ready | EarlyDataState::Accepted => {
-                let take = if self.left < sz {
-                    mem::replace(&mut self.left, 0)
-                } else {
-                    self.left -= sz;
-                    sz
-                };
-
-                Ok(take)
-            }
+            EarlyDataState::Ready | EarlyDataState::Accepted => Ok(cmp::min(self.left, sz)),
             EarlyDataState::Rejected | EarlyDataState::AcceptedFinished => {
                 Err(io::Error::from(io::ErrorKind::InvalidInput))
             }
         }
     }
 
+    fn check_send(&self, sz: usize) -> Result<usize, EarlyDataError> {
+        match self.state {
+            EarlyDataState::Disabled => Err(EarlyDataError::Unavailable),
+            EarlyDataState::Ready | EarlyDataState::Accepted if self.left == 0 && sz > 0 => {
+                Err(EarlyDataError::LimitReached)
+            }
+            EarlyDataState::Ready | EarlyDataState::Accepted => Ok(cmp::min(self.left, sz)),
+            EarlyDataState::AcceptedFinished => Err(EarlyDataError::Finished),
+            EarlyDataState::Rejected => Err(EarlyDataError::Rejected {
+                resend_len: self.sent,
+            }),
+        }
+    }
+
     fn bytes_left(&self) -> usize {
         self.left
     }
 }
 
+/// Why [`ClientConnection::send_early_data`] could not send early data.
+#[derive(Debug, Clone, PartialEq)]
+pub enum EarlyDataError {
+    /// Early data cannot be sent on this connection.  For example, there
+    /// is no ticket for the server, the ticket does not allow early data
+    /// (the server did not send a `max_early_data_size`), or
+    /// `ClientConfig::enable_early_data` is not set.
+    Unavailable,
+
+    /// All the early data the server allows has already been sent.
+    LimitReached,
+
+    /// The server accepted early data, and the handshake is now complete.
+    /// Send further data normally.
+    Finished,
+
+    /// The server rejected early data.  The first `resend_len` bytes
+    /// previously given to [`ClientConnection::send_early_data`] (or written
+    /// via [`ClientConnection::early_data`]) were discarded, and should be
+    /// sent again normally once the handshake completes.  Plaintext
+    /// sent as early data by [`ConnectionCommon::writer`] is not counted:
+    /// that is sent again automatically.
+    Rejected {
+        /// How many bytes of early data were sent and lost.
+        resend_len: usize,
+    },
+}
+
+impl fmt::Display for EarlyDataError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Unavailable => f.write_str("early data is not available"),
+            Self::LimitReached => f.write_str("early data limit reached"),
+            Self::Finished => f.write_str("early data is finished"),
+            Self::Rejected { resend_len } => write!(
+                f,
+                "early data rejected; {} bytes must be resent",
+                resend_len
+            ),
+        }
+    }
+}
+
+impl StdError for EarlyDataError {}
+
 /// Stub that implements io::Write and dispatches to `write_early_data`.
 pub struct WriteEarlyData<'a> {
     sess: &'a mut ClientConnection,
@@ -384,8 +743,9 @@ impl<'a> WriteEarlyData<'a> {
         WriteEarlyData { sess }
     }
 
-    /// How many bytes you may send.  Writes will become short
-    /// once this reaches zero.
+    /// How many more bytes you may send, given what has been written
+    /// so far.  Writes larger than this are short, and once this
+    /// reaches zero they write nothing.
     pub fn bytes_left(&self) -> usize {
         self.sess
             .inner
@@ -405,6 +765,156 @@ impl<'a> io::Write for WriteEarlyData<'a> {
     }
 }
 
+/// The order in which a client sends its `ClientHello` extensions, as
+/// chosen by [`ClientConfig::extension_order`].
+///
+/// Servers must accept extensions in any order, but the order is part
+/// of a client's fingerprint (for example, its JA3 hash).  So a client
+/// may want to send them in the same order as another, or to vary it.
+///
+/// Whatever the order, the `pre_shared_key` extension is sent last, as
+/// TLS1.3 requires.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtensionOrder(Order);
+
+#[derive(Clone, Debug, PartialEq)]
+enum Order {
+    Usual,
+    Shuffled,
+    Custom(Vec<ExtensionType>),
+}
+
+impl ExtensionOrder {
+    /// The extensions rustls sends in every `ClientHello`, which a
+    /// custom order must include.
+    const ALWAYS_SENT: &'static [ExtensionType] = &[
+        ExtensionType::SupportedVersions,
+        ExtensionType::ECPointFormats,
+        ExtensionType::EllipticCurves,
+        ExtensionType::SignatureAlgorithms,
+        ExtensionType::ExtendedMasterSecret,
+        ExtensionType::StatusRequest,
+    ];
+
+    /// rustls's usual order.
+    pub fn usual() -> Self {
+        Self(Order::Usual)
+    }
+
+    /// A random order, chosen afresh for each connection.  A
+    /// `ClientHello` sent after a `HelloRetryRequest` keeps the order
+    /// of the first.
+    pub fn shuffled() -> Self {
+        Self(Order::Shuffled)
+    }
+
+    /// The order of the extensions in `order`.  Extensions which are
+    /// sent but not in `order`, like GREASE ones, follow those which
+    /// are, in their usual order.  Extensions in `order` which are not
+    /// sent are skipped.
+    ///
+    /// This fails if `order` leaves out one of the extensions that are
+    /// always sent, names an extension twice, or includes
+    /// `pre_shared_key`.
+    pub fn custom(order: Vec<ExtensionType>) -> Result<Self, Error> {
+        for typ in Self::ALWAYS_SENT {
+            if !order.contains(typ) {
+                return Err(Error::General(format!(
+                    "extension order must include {:?}",
+                    typ
+                )));
+            }
+        }
+
+        for (i, typ) in order.iter().enumerate() {
+            if order[..i].contains(typ) {
+                return Err(Error::General(format!(
+                    "extension order includes {:?} more than once",
+                    typ
+                )));
+            }
+        }
+
+        if order.contains(&ExtensionType::PreSharedKey) {
+            return Err(Error::General(
+                "extension order cannot include PreSharedKey, which is always last".into(),
+            ));
+        }
+
+        Ok(Self(Order::Custom(order)))
+    }
+
+    pub(super) fn is_shuffled(&self) -> bool {
+        self.0 == Order::Shuffled
+    }
+
+    /// Put `exts` into this order.  `shuffle_seed` is used to choose a
+    /// shuffled order.
+    pub(super) fn apply(&self, exts: &mut [ClientExtension], shuffle_seed: Option<&[u8]>) {
+        let is_psk = |ext: &ClientExtension| ext.get_type() == ExtensionType::PreSharedKey;
+        match (&self.0, shuffle_seed) {
+            (Order::Custom(order), _) => exts.sort_by_key(|ext| {
+                let typ = ext.get_type();
+                let position = order
+                    .iter()
+                    .position(|t| *t == typ)
+                    .unwrap_or(order.len());
+                (is_psk(ext), position)
+            }),
+            (Order::Shuffled, Some(seed)) => exts.sort_by_cached_key(|ext| {
+                let mut ctx = digest::Context::new(&digest::SHA256);
+                ctx.update(seed);
+                ctx.update(&ext.get_type().get_u16().to_be_bytes());
+                (is_psk(ext), ctx.finish().as_ref().to_vec())
+            }),
+            _ => {}
+        }
+    }
+}
+
+impl Default for ExtensionOrder {
+    fn default() -> Self {
+        Self::usual()
+    }
+}
+
+/// The parts of a `ClientHello` that depend only on a [`ClientConfig`],
+/// built once and shared by many connections.
+///
+/// Connections made with [`ClientConnection::from_template`] copy
+/// these rather than working them out again from the config, and only
+/// generate their random, session ID and key share afresh.  Most of
+/// the extensions are kept already encoded, so copying them is cheap.
+/// This saves a little work per connection for clients that make a lot
+/// of them, like connection pools.
+///
+/// The `ClientHello` sent is the same as that sent by a connection made
+/// with [`ClientConnection::new`] and the same config.
+pub struct ClientHelloTemplate {
+    config: Arc<ClientConfig>,
+    pub(super) hello: hs::StaticHello,
+}
+
+impl ClientHelloTemplate {
+    /// Build a template for connections using `config`.
+    pub fn new(config: Arc<ClientConfig>) -> Self {
+        let hello = hs::StaticHello::new(&config, false).into_encoded();
+        Self { config, hello }
+    }
+
+    /// The config this template was built from.
+    pub fn config(&self) -> &Arc<ClientConfig> {
+        &self.config
+    }
+}
+
+impl fmt::Debug for ClientHelloTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClientHelloTemplate")
+            .finish()
+    }
+}
+
 /// This represents a single TLS client connection.
 pub struct ClientConnection {
     inner: ConnectionCommon<ClientConnectionData>,
@@ -422,7 +932,56 @@ impl ClientConnection {
     /// we behave in the TLS protocol, `name` is the
     /// name of the server we want to talk to.
     pub fn new(config: Arc<ClientConfig>, name: ServerName) -> Result<Self, Error> {
-        Self::new_inner(config, name, Vec::new(), Protocol::Tcp)
+        Self::new_inner(config, name, Vec::new(), Protocol::Tcp, true, None)
+    }
+
+    /// Make a new ClientConnection that always performs a full handshake.
+    ///
+    /// This behaves like [`ClientConnection::new`], except that no stored
+    /// session for `name` is offered to the server.  `config.session_storage`
+    /// is left untouched, and still receives any session the server issues
+    /// on this connection.
+    pub fn new_without_resumption(
+        config: Arc<ClientConfig>,
+        name: ServerName,
+    ) -> Result<Self, Error> {
+        Self::new_inner(config, name, Vec::new(), Protocol::Tcp, false, None)
+    }
+
+    /// Make a new ClientConnection which only uses those of the key
+    /// exchange groups of `config` named in `kx_groups`, whatever the
+    /// server prefers.  If the server supports none of them, the
+    /// handshake fails.
+    ///
+    /// This fails if `config` has none of `kx_groups`.  Otherwise it
+    /// behaves like [`ClientConnection::new`].
+    pub fn new_with_kx_groups(
+        config: Arc<ClientConfig>,
+        name: ServerName,
+        kx_groups: &[NamedGroup],
+    ) -> Result<Self, Error> {
+        let mut config = (*config).clone();
+        kx::restrict_groups(&mut config.kx_groups, kx_groups)?;
+        Self::new(Arc::new(config), name)
+    }
+
+    /// Make a new ClientConnection using the config of `template`,
+    /// reusing the parts of the `ClientHello` it has already built.
+    ///
+    /// Otherwise this behaves like [`ClientConnection::new`].
+    pub fn from_template(
+        template: Arc<ClientHelloTemplate>,
+        name: ServerName,
+    ) -> Result<Self, Error> {
+        let config = template.config.clone();
+        Self::new_inner(
+            config,
+            name,
+            Vec::new(),
+            Protocol::Tcp,
+            true,
+            Some(template),
+        )
     }
 
     fn new_inner(
@@ -430,18 +989,41 @@ impl ClientConnection {
         name: ServerName,
         extra_exts: Vec<ClientExtension>,
         proto: Protocol,
+        allow_resumption: bool,
+        hello_template: Option<Arc<ClientHelloTemplate>>,
     ) -> Result<Self, Error> {
+        if let Some(mfl) = config.max_fragment_length {
+            mfl.fragment_len()
+                .ok_or(Error::BadMaxFragmentSize)?;
+        }
+
+        if config
+            .record_size_limit
+            .map_or(false, |limit| {
+                limit < MIN_RECORD_SIZE_LIMIT || limit > max_record_size_limit(true)
+            })
+        {
+            return Err(Error::BadMaxFragmentSize);
+        }
+
         let mut common_state = CommonState::new(config.max_fragment_size, Side::Client)?;
         common_state.protocol = proto;
+        common_state.observe_handshake(config.handshake_observer.as_ref());
+        common_state.set_alert_callback(config.on_alert.as_ref());
+        common_state.set_renegotiation_response(config.renegotiation_response);
         let mut data = ClientConnectionData::new();
+        data.allow_resumption = allow_resumption;
+        data.hello_template = hello_template;
 
         let mut cx = hs::ClientContext {
             common: &mut common_state,
             data: &mut data,
         };
 
-        let state = hs::start_handshake(name, extra_exts, config, &mut cx)?;
-        let inner = ConnectionCommon::new(state, data, common_state);
+        let state = hs::start_handshake(name, extra_exts, config.clone(), &mut cx)?;
+        common_state.update_handshake_observer();
+        let mut inner = ConnectionCommon::new(state, data, common_state);
+        inner.set_handshake_limits(config.max_handshake_message_size, config.max_handshake_size);
 
         Ok(Self { inner })
     }
@@ -472,6 +1054,24 @@ impl ClientConnection {
         }
     }
 
+    /// Sends as much of `data` as TLS1.3 early data as the server's
+    /// limit allows, returning how many bytes were taken.
+    ///
+    /// This is an alternative to [`ClientConnection::early_data`] that
+    /// explains why early data can't be sent.  In particular, once the
+    /// server has rejected early data this returns
+    /// [`EarlyDataError::Rejected`] with the amount of earlier early data
+    /// that must be resent; call this with an empty `data` after the
+    /// handshake to find out.
+    pub fn send_early_data(&mut self, data: &[u8]) -> Result<usize, EarlyDataError> {
+        let sz = self
+            .inner
+            .data
+            .early_data
+            .check_send(data.len())?;
+        Ok(self.send_early_plaintext(&data[..sz]))
+    }
+
     /// Returns True if the server signalled it will process early data.
     ///
     /// If you sent early data and this returns false at the end of the
@@ -486,11 +1086,18 @@ impl ClientConnection {
             .data
             .early_data
             .check_write(data.len())
-            .map(|sz| {
-                self.inner
-                    .common_state
-                    .send_early_plaintext(&data[..sz])
-            })
+            .map(|sz| self.send_early_plaintext(&data[..sz]))
+    }
+
+    /// Sends `data` as early data, counting only what the buffer
+    /// limit let through against the server's allowance.
+    fn send_early_plaintext(&mut self, data: &[u8]) -> usize {
+        let sent = self
+            .inner
+            .common_state
+            .send_early_plaintext(data);
+        self.inner.data.early_data.sent(sent);
+        sent
     }
 }
 
@@ -531,6 +1138,8 @@ impl From<ClientConnection> for crate::Connection {
 pub struct ClientConnectionData {
     pub(super) early_data: EarlyData,
     pub(super) resumption_ciphersuite: Option<SupportedCipherSuite>,
+    pub(super) allow_resumption: bool,
+    pub(super) hello_template: Option<Arc<ClientHelloTemplate>>,
 }
 
 impl ClientConnectionData {
@@ -538,11 +1147,43 @@ impl ClientConnectionData {
         Self {
             early_data: EarlyData::new(),
             resumption_ciphersuite: None,
+            allow_resumption: true,
+            hello_template: None,
         }
     }
 }
 
-impl crate::conn::SideData for ClientConnectionData {}
+impl ClientConnectionData {
+    /// Notes that the server rejected our early data, and queues any
+    /// that came from `writer()` to be sent after the handshake.
+    pub(super) fn reject_early_data(&mut self, common: &mut CommonState) {
+        self.early_data.rejected();
+        common.requeue_rejected_early_plaintext(mem::take(&mut self.early_data.resend));
+    }
+}
+
+impl crate::conn::SideData for ClientConnectionData {
+    fn send_early_plaintext(&mut self, common: &mut CommonState, data: &[u8]) -> usize {
+        if !common.early_traffic || !self.early_data.is_enabled() {
+            return 0;
+        }
+
+        let allowed = cmp::min(self.early_data.bytes_left(), data.len());
+        let sent = common.send_early_plaintext(&data[..allowed]);
+        self.early_data
+            .sent_from_writer(&data[..sent]);
+        sent
+    }
+}
+
+impl ConnectionCommon<ClientConnectionData> {
+    /// Returns what was agreed with the server, or `None` until
+    /// the handshake is complete.
+    pub fn handshake_details(&self) -> Option<HandshakeDetails> {
+        self.common_state
+            .handshake_details(None)
+    }
+}
 
 #[cfg(feature = "quic")]
 impl quic::QuicExt for ClientConnection {
@@ -555,6 +1196,11 @@ impl quic::QuicExt for ClientConnection {
             .map(|v| v.as_ref())
     }
 
+    fn quic_transport_parameters_extension(&self) -> Option<(ExtensionType, &[u8])> {
+        let quic = &self.inner.common_state.quic;
+        Some((quic.params_extension?, quic.params.as_deref()?))
+    }
+
     fn zero_rtt_keys(&self) -> Option<quic::DirectionalKeys> {
         Some(quic::DirectionalKeys::new(
             self.inner
@@ -577,6 +1223,10 @@ impl quic::QuicExt for ClientConnection {
         quic::write_hs(&mut self.inner.common_state, buf)
     }
 
+    fn next_1rtt_keys(&mut self) -> Option<quic::PacketKeySet> {
+        quic::next_1rtt_keys(&mut self.inner.common_state)
+    }
+
     fn alert(&self) -> Option<AlertDescription> {
         self.inner.common_state.quic.alert
     }
@@ -605,7 +1255,7 @@ pub trait ClientQuicExt {
             quic::Version::V1 => ClientExtension::TransportParameters(params),
         };
 
-        ClientConnection::new_inner(config, name, vec![ext], Protocol::Quic)
+        ClientConnection::new_inner(config, name, vec![ext], Protocol::Quic, true, None)
     }
 }
 
@@ -1,10 +1,14 @@
 use super::ResolvesClientCert;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
-use crate::msgs::enums::ExtensionType;
+use crate::msgs::base::Payload;
+use crate::msgs::enums::{CipherSuite, ExtensionType, NamedGroup};
 use crate::msgs::handshake::CertificatePayload;
 use crate::msgs::handshake::SCTList;
 use crate::msgs::handshake::ServerExtension;
+use crate::msgs::handshake::{ClientExtension, UnknownExtension};
+use crate::rand;
+use crate::tls13::ExternalPsk;
 use crate::{sign, DistinguishedNames, SignatureScheme};
 
 use ring::rand::SystemRandom;
@@ -40,12 +44,18 @@ impl ServerCertDetails {
 
 pub(super) struct ClientHelloDetails {
     pub(super) sent_extensions: Vec<ExtensionType>,
+    pub(super) offered_external_psks: Vec<ExternalPsk>,
+    pub(super) grease: Option<Grease>,
+    pub(super) extension_shuffle_seed: Option<Vec<u8>>,
 }
 
 impl ClientHelloDetails {
     pub(super) fn new() -> Self {
         Self {
             sent_extensions: Vec::new(),
+            offered_external_psks: Vec::new(),
+            grease: None,
+            extension_shuffle_seed: None,
         }
     }
 
@@ -72,6 +82,75 @@ impl ClientHelloDetails {
     }
 }
 
+/// The GREASE (RFC8701) values sent by one connection.  They are
+/// kept so that a `ClientHello` sent after a `HelloRetryRequest` uses
+/// the same ones.
+#[derive(Clone, Copy)]
+pub(super) struct Grease {
+    cipher_suite: u16,
+    group: u16,
+    first_extension: u16,
+    last_extension: u16,
+}
+
+impl Grease {
+    pub(super) fn random(source: &dyn rand::RandomSource) -> Result<Self, rand::GetRandomFailed> {
+        let mut seed = [0u8; 4];
+        source.fill(&mut seed)?;
+
+        let first_extension = grease_value(seed[2]);
+        let mut last_extension = grease_value(seed[3]);
+        // Extensions can't be repeated.
+        if last_extension == first_extension {
+            last_extension ^= 0x1010;
+        }
+
+        Ok(Self {
+            cipher_suite: grease_value(seed[0]),
+            group: grease_value(seed[1]),
+            first_extension,
+            last_extension,
+        })
+    }
+
+    pub(super) fn cipher_suite(&self) -> CipherSuite {
+        CipherSuite::Unknown(self.cipher_suite)
+    }
+
+    pub(super) fn group(&self) -> NamedGroup {
+        NamedGroup::Unknown(self.group)
+    }
+
+    /// The extension to send before all others, which is empty.
+    pub(super) fn first_extension(&self) -> ClientExtension {
+        ClientExtension::Unknown(UnknownExtension {
+            typ: ExtensionType::Unknown(self.first_extension),
+            payload: Payload::empty(),
+        })
+    }
+
+    /// The extension to send after all others but the session ticket
+    /// and PSK ones, which holds a single zero byte.
+    pub(super) fn last_extension(&self) -> ClientExtension {
+        ClientExtension::Unknown(UnknownExtension {
+            typ: ExtensionType::Unknown(self.last_extension),
+            payload: Payload::new(vec![0]),
+        })
+    }
+
+    pub(super) fn is_extension(&self, typ: ExtensionType) -> bool {
+        typ == ExtensionType::Unknown(self.first_extension)
+            || typ == ExtensionType::Unknown(self.last_extension)
+    }
+}
+
+/// All GREASE values have the form `0x?A?A`, with the same nibble
+/// in both `?`s.  Choose one using the top four bits of `seed`.
+fn grease_value(seed: u8) -> u16 {
+    let byte = u16::from(seed & 0xf0) | 0x0a;
+    (byte << 8) | byte
+}
+
 pub(super) enum ClientAuthDetails {
     /// Send an empty `Certificate` and no `CertificateVerify`.
     Empty { auth_context_tls13: Option<Vec<u8>> },
@@ -114,4 +193,27 @@ impl ClientAuthDetails {
         debug!("Client auth requested but no cert/sigscheme available");
         Self::Empty { auth_context_tls13 }
     }
+
+    /// Send our raw public key rather than the certificate chain, once
+    /// that certificate type has been agreed with the server.
+    pub(super) fn into_raw_public_key(self) -> Self {
+        match self {
+            Self::Verify {
+                certkey,
+                signer,
+                auth_context_tls13,
+            } => match certkey.to_raw_public_key() {
+                Some(certkey) => Self::Verify {
+                    certkey: Arc::new(certkey),
+                    signer,
+                    auth_context_tls13,
+                },
+                None => {
+                    debug!("Client certificate has no usable public key");
+                    Self::Empty { auth_context_tls13 }
+                }
+            },
+            empty => empty,
+        }
+    }
 }
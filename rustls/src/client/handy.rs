@@ -2,9 +2,18 @@ use crate::client;
 use crate::error::Error;
 use crate::key;
 use crate::limited_cache;
-use crate::msgs::enums::SignatureScheme;
+use crate::msgs::codec::{Codec, Reader};
+use crate::msgs::enums::{CipherSuite, SignatureScheme};
+use crate::msgs::persist;
 use crate::sign;
+use crate::suites::ALL_CIPHER_SUITES;
+use crate::ticketer::TimeBase;
 
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 /// An implementer of `StoresClientSessions` which does nothing.
@@ -23,17 +32,23 @@ impl client::StoresClientSessions for NoClientSessionStorage {
 /// An implementer of `StoresClientSessions` that stores everything
 /// in memory.  It enforces a limit on the number of entries
 /// to bound memory usage.
+///
+/// Ticket pools are kept separately, with the same limit on
+/// the number of servers.
 pub struct ClientSessionMemoryCache {
     cache: Mutex<limited_cache::LimitedCache<Vec<u8>, Vec<u8>>>,
+    tickets: Mutex<limited_cache::LimitedCache<Vec<u8>, VecDeque<Vec<u8>>>>,
 }
 
 impl ClientSessionMemoryCache {
     /// Make a new ClientSessionMemoryCache.  `size` is the
     /// maximum number of stored sessions.
+    ///
+    /// Panics if `size` is less than 2.
     pub fn new(size: usize) -> Arc<Self> {
-        debug_assert!(size > 0);
         Arc::new(Self {
             cache: Mutex::new(limited_cache::LimitedCache::new(size)),
+            tickets: Mutex::new(limited_cache::LimitedCache::new(size)),
         })
     }
 }
@@ -54,6 +69,150 @@ impl client::StoresClientSessions for ClientSessionMemoryCache {
             .get(key)
             .cloned()
     }
+
+    fn put_ticket(&self, key: Vec<u8>, value: Vec<u8>, max: usize) -> bool {
+        if max == 0 {
+            return false;
+        }
+
+        let mut tickets = self.tickets.lock().unwrap();
+        match tickets.get_mut(&key) {
+            Some(pool) => {
+                while pool.len() >= max {
+                    pool.pop_front();
+                }
+                pool.push_back(value);
+            }
+            None => tickets.insert(key, VecDeque::from(vec![value])),
+        }
+        true
+    }
+
+    fn take_ticket(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.tickets
+            .lock()
+            .unwrap()
+            .get_mut(key)?
+            .pop_front()
+    }
+}
+
+/// An implementer of `StoresClientSessions` that stores everything
+/// in files in a directory, so that sessions survive a process
+/// restart.
+///
+/// Each key (which identifies the server name) is stored in its own
+/// file, named after a hash of the key.  Files are written by
+/// writing a temporary file and then renaming it into place, so
+/// concurrent readers -- including other processes using the same
+/// directory -- never see a partially-written file.
+///
+/// Files which are corrupt or truncated, or which contain a session
+/// that has passed its ticket lifetime, are treated as absent and
+/// deleted.
+///
+/// The files contain enough key material to break all security
+/// of the corresponding sessions, so `dir` should only be accessible
+/// to the current user.
+pub struct FileClientSessionStore {
+    dir: PathBuf,
+    max_value_size: usize,
+}
+
+/// Distinguishes temporary files written by concurrent `put`s.
+static TEMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl FileClientSessionStore {
+    /// Make a new FileClientSessionStore, storing files in `dir`.
+    /// `dir` is created if it does not exist.
+    ///
+    /// `max_value_size` is the maximum number of bytes stored for
+    /// each server; larger values are not stored.
+    pub fn new(dir: impl Into<PathBuf>, max_value_size: usize) -> io::Result<Arc<Self>> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Arc::new(Self {
+            dir,
+            max_value_size,
+        }))
+    }
+
+    fn file_name(key: &[u8]) -> String {
+        ring::digest::digest(&ring::digest::SHA256, key)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    fn write_atomically(&self, name: &str, contents: &[u8]) -> io::Result<()> {
+        let temp_path = self.dir.join(format!(
+            ".{}.{}.{}.tmp",
+            name,
+            std::process::id(),
+            TEMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        fs::write(&temp_path, contents)
+            .and_then(|_| fs::rename(&temp_path, self.dir.join(name)))
+            .map_err(|err| {
+                let _ = fs::remove_file(&temp_path);
+                err
+            })
+    }
+
+    /// Decode the contents of a file, returning the stored value
+    /// if it is well-formed and (for sessions) has not expired.
+    fn decode_file(key: &[u8], contents: &[u8]) -> Option<Vec<u8>> {
+        let mut rd = Reader::init(contents);
+        let len = u32::read(&mut rd)? as usize;
+        let value = rd.take(len)?;
+        if rd.any_left() {
+            return None;
+        }
+
+        if persist::ClientSessionKey::is_session_key(key) {
+            let suite = CipherSuite::read_bytes(value.get(..2)?)?;
+            let session = persist::ClientSessionValue::read(
+                &mut Reader::init(&value[2..]),
+                suite,
+                ALL_CIPHER_SUITES,
+            )?;
+            if persist::Retrieved::new(session, TimeBase::now().ok()?).has_expired() {
+                return None;
+            }
+        }
+
+        Some(value.to_vec())
+    }
+}
+
+impl client::StoresClientSessions for FileClientSessionStore {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        if value.len() > self.max_value_size || value.len() > u32::MAX as usize {
+            return false;
+        }
+
+        let mut contents = Vec::with_capacity(4 + value.len());
+        (value.len() as u32).encode(&mut contents);
+        contents.extend_from_slice(&value);
+
+        self.write_atomically(&Self::file_name(&key), &contents)
+            .is_ok()
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let path = self.dir.join(Self::file_name(key));
+        let contents = fs::read(&path).ok()?;
+
+        match Self::decode_file(key, &contents) {
+            Some(value) => Some(value),
+            None => {
+                let _ = fs::remove_file(&path);
+                None
+            }
+        }
+    }
 }
 
 pub(super) struct FailResolveClientCert {}
@@ -99,6 +258,26 @@ impl client::ResolvesClientCert for AlwaysResolvesClientCert {
     }
 }
 
+pub(super) struct ResolvesClientCertOnRequest<F>(pub(super) F);
+
+impl<F> client::ResolvesClientCert for ResolvesClientCertOnRequest<F>
+where
+    F: Fn(&[&[u8]], &[SignatureScheme]) -> Option<Arc<sign::CertifiedKey>> + Send + Sync,
+{
+    fn resolve(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        (self.0)(acceptable_issuers, sigschemes)
+    }
+
+    // We can't know without asking, and asking is what we're putting off.
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -158,4 +337,144 @@ mod test {
 
         assert!(count < 5);
     }
+
+    fn temp_store_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustls-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn session_key() -> Vec<u8> {
+        use std::convert::TryFrom;
+        let name = client::ServerName::try_from("example.com").unwrap();
+        persist::ClientSessionKey::session_for_server_name(&name).get_encoding()
+    }
+
+    fn session_value(lifetime_secs: u32, age_secs: u32) -> Vec<u8> {
+        let mut value = persist::Tls13ClientSessionValue::new(
+            crate::tls13::TLS13_AES_128_GCM_SHA256
+                .tls13()
+                .unwrap(),
+            vec![0x01, 0x02],
+            vec![0x03, 0x04],
+            vec![],
+            TimeBase::now().unwrap(),
+            lifetime_secs,
+            0,
+            0,
+        );
+        value.common.rewind_epoch(age_secs);
+        value.get_encoding()
+    }
+
+    #[test]
+    fn test_fileclientsessionstore_persists_put() {
+        let dir = temp_store_dir("persists");
+        let c = FileClientSessionStore::new(&dir, 1024).unwrap();
+        assert!(c.put(vec![0x01], vec![0x02]));
+        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+        assert_eq!(c.get(&[0x02]), None);
+        drop(c);
+
+        let c = FileClientSessionStore::new(&dir, 1024).unwrap();
+        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fileclientsessionstore_overwrites_put() {
+        let dir = temp_store_dir("overwrites");
+        let c = FileClientSessionStore::new(&dir, 1024).unwrap();
+        assert!(c.put(vec![0x01], vec![0x02]));
+        assert!(c.put(vec![0x01], vec![0x04, 0x05]));
+        assert_eq!(c.get(&[0x01]), Some(vec![0x04, 0x05]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fileclientsessionstore_enforces_size_cap() {
+        let dir = temp_store_dir("size-cap");
+        let c = FileClientSessionStore::new(&dir, 4).unwrap();
+        assert!(c.put(vec![0x01], vec![0x00; 4]));
+        assert!(!c.put(vec![0x01], vec![0x00; 5]));
+        assert_eq!(c.get(&[0x01]), Some(vec![0x00; 4]));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fileclientsessionstore_ignores_corrupt_files() {
+        let dir = temp_store_dir("corrupt");
+        let c = FileClientSessionStore::new(&dir, 1024).unwrap();
+        let path = dir.join(FileClientSessionStore::file_name(&[0x01]));
+
+        assert!(c.put(vec![0x01], vec![0x02, 0x03, 0x04]));
+        let contents = fs::read(&path).unwrap();
+
+        for len in 0..contents.len() {
+            fs::write(&path, &contents[..len]).unwrap();
+            assert_eq!(c.get(&[0x01]), None);
+            assert!(!path.exists());
+        }
+
+        fs::write(&path, b"\xff\xff\xff\xffgarbage").unwrap();
+        assert_eq!(c.get(&[0x01]), None);
+
+        let key = session_key();
+        assert!(c.put(key.clone(), vec![0xff; 16]));
+        assert_eq!(c.get(&key), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fileclientsessionstore_expires_sessions() {
+        let dir = temp_store_dir("expiry");
+        let c = FileClientSessionStore::new(&dir, 1024).unwrap();
+        let key = session_key();
+
+        let fresh = session_value(60, 0);
+        assert!(c.put(key.clone(), fresh.clone()));
+        assert_eq!(c.get(&key), Some(fresh));
+
+        assert!(c.put(key.clone(), session_value(60, 120)));
+        assert_eq!(c.get(&key), None);
+        assert!(!dir
+            .join(FileClientSessionStore::file_name(&key))
+            .exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_fileclientsessionstore_concurrent_open() {
+        let dir = temp_store_dir("concurrent");
+        let values: Vec<Vec<u8>> = (1..32u8)
+            .map(|i| vec![i; i as usize * 8])
+            .collect();
+
+        let finished = Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+        let writer = {
+            let c = FileClientSessionStore::new(&dir, 1024).unwrap();
+            let values = values.clone();
+            let finished = Arc::clone(&finished);
+            std::thread::spawn(move || {
+                for _ in 0..8 {
+                    for value in &values {
+                        assert!(c.put(vec![0x01], value.clone()));
+                    }
+                }
+                finished.store(true, Ordering::SeqCst);
+            })
+        };
+
+        let c = FileClientSessionStore::new(&dir, 1024).unwrap();
+        while !finished.load(Ordering::SeqCst) {
+            if let Some(value) = c.get(&[0x01]) {
+                assert!(values.contains(&value));
+            }
+        }
+        writer.join().unwrap();
+
+        assert_eq!(c.get(&[0x01]).as_ref(), values.last());
+        fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -2,7 +2,7 @@
 use crate::bs_debug;
 use crate::check::inappropriate_handshake_message;
 use crate::conn::{CommonState, ConnectionRandoms, State};
-use crate::error::Error;
+use crate::error::{CertificateError, Error};
 use crate::hash_hs::HandshakeHashBuffer;
 use crate::kx;
 #[cfg(feature = "logging")]
@@ -14,28 +14,33 @@ use crate::msgs::codec::{Codec, Reader};
 use crate::msgs::enums::{
     AlertDescription, CipherSuite, Compression, ContentType, ProtocolVersion,
 };
-use crate::msgs::enums::{ECPointFormat, PSKKeyExchangeMode};
-use crate::msgs::enums::{ExtensionType, HandshakeType};
+use crate::msgs::enums::{CertificateType, ECPointFormat, PSKKeyExchangeMode};
+use crate::msgs::enums::{ExtensionType, HandshakeType, MaxFragmentLength, SignatureScheme};
+use crate::msgs::fragmenter::{max_record_size_limit, MIN_RECORD_SIZE_LIMIT};
 use crate::msgs::handshake::{CertificateStatusRequest, ClientSessionTicket, SCTList};
 use crate::msgs::handshake::{ClientExtension, HasServerExtensions};
 use crate::msgs::handshake::{ClientHelloPayload, HandshakeMessagePayload, HandshakePayload};
 use crate::msgs::handshake::{ConvertProtocolNameList, ProtocolNameList};
 use crate::msgs::handshake::{ECPointFormatList, SupportedPointFormats};
 use crate::msgs::handshake::{HelloRetryRequest, KeyShareEntry};
-use crate::msgs::handshake::{Random, SessionID};
+use crate::msgs::handshake::{Random, SessionID, UnknownExtension};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
+use crate::rand::RandomSource;
 use crate::ticketer::TimeBase;
 use crate::tls13::key_schedule::KeyScheduleEarly;
+use crate::verify::{self, OcspStatus};
 use crate::SupportedCipherSuite;
 
 #[cfg(feature = "tls12")]
 use super::tls12;
 use crate::client::client_conn::ClientConnectionData;
-use crate::client::common::ClientHelloDetails;
+use crate::client::common::{ClientHelloDetails, Grease, ServerCertDetails};
 use crate::client::{tls13, ClientConfig, ServerName};
 
+use std::cmp;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 pub(super) type NextState = Box<dyn State<ClientConnectionData>>;
 pub(super) type NextStateOrError = Result<NextState, Error>;
@@ -46,6 +51,36 @@ fn find_session(
     config: &ClientConfig,
     #[cfg(feature = "quic")] cx: &mut ClientContext<'_>,
 ) -> Option<persist::Retrieved<persist::ClientSessionValue>> {
+    if let Some(max) = config.max_tickets_per_server {
+        if config.supports_version(ProtocolVersion::TLSv1_3) {
+            let key = persist::ClientSessionKey::tickets_for_server_name(server_name);
+            let key_buf = key.get_encoding();
+
+            // nb. bounded, in case the store does not remove taken tickets.
+            for _ in 0..max {
+                let value = match config
+                    .session_storage
+                    .take_ticket(&key_buf)
+                {
+                    Some(value) => value,
+                    None => break,
+                };
+
+                let found = decode_session(
+                    &value,
+                    config,
+                    #[cfg(feature = "quic")]
+                    cx,
+                );
+                if found.is_some() {
+                    return found;
+                }
+                debug!("Discarding unusable ticket for {:?}", server_name);
+            }
+            debug!("No tickets left for {:?}", server_name);
+        }
+    }
+
     let key = persist::ClientSessionKey::session_for_server_name(server_name);
     let key_buf = key.get_encoding();
 
@@ -57,8 +92,21 @@ fn find_session(
             None
         })?;
 
+    decode_session(
+        &value,
+        config,
+        #[cfg(feature = "quic")]
+        cx,
+    )
+}
+
+fn decode_session(
+    value: &[u8],
+    config: &ClientConfig,
+    #[cfg(feature = "quic")] cx: &mut ClientContext<'_>,
+) -> Option<persist::Retrieved<persist::ClientSessionValue>> {
     #[allow(unused_mut)]
-    let mut reader = Reader::init(&value[2..]);
+    let mut reader = Reader::init(value.get(2..)?);
     #[allow(clippy::bind_instead_of_map)] // https://github.com/rust-lang/rust-clippy/issues/8082
     CipherSuite::read_bytes(&value[..2])
         .and_then(|suite| {
@@ -98,12 +146,17 @@ pub(super) fn start_handshake(
     let support_tls13 = config.supports_version(ProtocolVersion::TLSv1_3);
 
     let mut session_id: Option<SessionID> = None;
-    let mut resuming_session = find_session(
-        &server_name,
-        &config,
-        #[cfg(feature = "quic")]
-        cx,
-    );
+    let mut resuming_session = if cx.data.allow_resumption {
+        find_session(
+            &server_name,
+            &config,
+            #[cfg(feature = "quic")]
+            cx,
+        )
+    } else {
+        debug!("Resumption disabled for this connection");
+        None
+    };
 
     let key_share = if support_tls13 {
         Some(tls13::initial_key_share(&config, &server_name)?)
@@ -118,7 +171,7 @@ pub(super) fn start_handshake(
             // we're  doing an abbreviated handshake.  See section 3.4 in
             // RFC5077.
             if !inner.ticket().is_empty() {
-                inner.session_id = SessionID::random()?;
+                inner.session_id = SessionID::random_from(config.random())?;
             }
             session_id = Some(inner.session_id);
         }
@@ -131,13 +184,19 @@ pub(super) fn start_handshake(
     // https://tools.ietf.org/html/rfc8446#appendix-D.4
     // https://tools.ietf.org/html/draft-ietf-quic-tls-34#section-8.4
     if session_id.is_none() && !cx.common.is_quic() {
-        session_id = Some(SessionID::random()?);
+        session_id = Some(SessionID::random_from(config.random())?);
     }
 
-    let random = Random::new()?;
-    let hello_details = ClientHelloDetails::new();
+    let random = Random::new_from(config.random())?;
+    let mut hello_details = ClientHelloDetails::new();
+    if config.enable_grease {
+        hello_details.grease = Some(Grease::random(config.random())?);
+    }
+    if config.extension_order.is_shuffled() {
+        hello_details.extension_shuffle_seed = Some(config.random().random_vec(16)?);
+    }
     let sent_tls13_fake_ccs = false;
-    let may_send_sct_list = config.verifier.request_scts();
+    let may_send_sct_list = config.verifier.request_scts() || config.ct_policy.is_some();
     Ok(emit_client_hello_for_retry(
         config,
         cx,
@@ -209,40 +268,27 @@ fn emit_client_hello_for_retry(
         (Vec::new(), ProtocolVersion::Unknown(0))
     };
 
-    let support_tls12 = config.supports_version(ProtocolVersion::TLSv1_2) && !cx.common.is_quic();
     let support_tls13 = config.supports_version(ProtocolVersion::TLSv1_3);
 
-    let mut supported_versions = Vec::new();
-    if support_tls13 {
-        supported_versions.push(ProtocolVersion::TLSv1_3);
-    }
+    let StaticHello {
+        leading_exts: mut exts,
+        trailing_exts,
+        mut cipher_suites,
+    } = match &cx.data.hello_template {
+        Some(template) => template.hello.clone(),
+        None => StaticHello::new(&config, cx.common.is_quic()),
+    };
 
-    if support_tls12 {
-        supported_versions.push(ProtocolVersion::TLSv1_2);
+    if let Some(grease) = &hello.grease {
+        cipher_suites.insert(0, grease.cipher_suite());
+        for ext in exts.iter_mut() {
+            if let ClientExtension::NamedGroups(groups) = ext {
+                groups.insert(0, grease.group());
+            }
+        }
+        exts.insert(0, grease.first_extension());
     }
 
-    // should be unreachable thanks to config builder
-    assert!(!supported_versions.is_empty());
-
-    let mut exts = vec![
-        ClientExtension::SupportedVersions(supported_versions),
-        ClientExtension::ECPointFormats(ECPointFormatList::supported()),
-        ClientExtension::NamedGroups(
-            config
-                .kx_groups
-                .iter()
-                .map(|skxg| skxg.name)
-                .collect(),
-        ),
-        ClientExtension::SignatureAlgorithms(
-            config
-                .verifier
-                .supported_verify_schemes(),
-        ),
-        ClientExtension::ExtendedMasterSecretRequest,
-        ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()),
-    ];
-
     if let (Some(sni_name), true) = (server_name.for_sni(), config.enable_sni) {
         exts.push(ClientExtension::make_sni(sni_name));
     }
@@ -261,26 +307,15 @@ fn emit_client_hello_for_retry(
         exts.push(ClientExtension::Cookie(cookie.clone()));
     }
 
-    if support_tls13 && config.enable_tickets {
-        // We could support PSK_KE here too. Such connections don't
-        // have forward secrecy, and are similar to TLS1.2 resumption.
-        let psk_modes = vec![PSKKeyExchangeMode::PSK_DHE_KE];
-        exts.push(ClientExtension::PresharedKeyModes(psk_modes));
-    }
-
-    if !config.alpn_protocols.is_empty() {
-        exts.push(ClientExtension::Protocols(ProtocolNameList::from_slices(
-            &config
-                .alpn_protocols
-                .iter()
-                .map(|proto| &proto[..])
-                .collect::<Vec<_>>(),
-        )));
-    }
+    exts.extend(trailing_exts);
 
     // Extra extensions must be placed before the PSK extension
     exts.extend(extra_exts.iter().cloned());
 
+    if let Some(grease) = &hello.grease {
+        exts.push(grease.last_extension());
+    }
+
     let fill_in_binder = if support_tls13
         && config.enable_tickets
         && resume_version == ProtocolVersion::TLSv1_3
@@ -323,20 +358,30 @@ fn emit_client_hello_for_retry(
         None
     };
 
-    // Note what extensions we sent.
+    // Only one PSK extension can be sent, so external PSKs are only
+    // offered when we're not resuming.
+    let external_psks = match fill_in_binder {
+        None if support_tls13 => tls13::offerable_external_psks(&config, suite),
+        _ => Vec::new(),
+    };
+    if !external_psks.is_empty() {
+        tls13::prepare_external_psks(&external_psks, &mut exts);
+    }
+
+    config
+        .extension_order
+        .apply(&mut exts, hello.extension_shuffle_seed.as_deref());
+
+    // Note what extensions we sent.  GREASE ones are left out, as the
+    // server must not answer them.
+    let grease = hello.grease;
     hello.sent_extensions = exts
         .iter()
         .map(ClientExtension::get_type)
+        .filter(|typ| !grease.map_or(false, |grease| grease.is_extension(*typ)))
         .collect();
 
     let session_id = session_id.unwrap_or_else(SessionID::empty);
-    let mut cipher_suites: Vec<_> = config
-        .cipher_suites
-        .iter()
-        .map(|cs| cs.suite())
-        .collect();
-    // We don't do renegotiation at all, in fact.
-    cipher_suites.push(CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV);
 
     let mut chp = HandshakeMessagePayload {
         typ: HandshakeType::ClientHello,
@@ -357,6 +402,11 @@ fn emit_client_hello_for_retry(
         None
     };
 
+    if !external_psks.is_empty() {
+        tls13::fill_in_external_psk_binders(&external_psks, &transcript_buffer, &mut chp);
+    }
+    hello.offered_external_psks = external_psks;
+
     let ch = Message {
         // "This value MUST be set to 0x0303 for all records generated
         //  by a TLS 1.3 implementation other than an initial ClientHello
@@ -420,6 +470,250 @@ fn emit_client_hello_for_retry(
     }
 }
 
+/// The parts of a `ClientHello` which depend only on the config, and
+/// so can be built once for many connections by a
+/// [`ClientHelloTemplate`](crate::client::ClientHelloTemplate).
+#[derive(Clone)]
+pub(super) struct StaticHello {
+    /// Extensions sent before the per-connection ones, like SNI.
+    leading_exts: Vec<ClientExtension>,
+    /// Extensions sent after them, but before any extra extensions
+    /// and the PSK or ticket.
+    trailing_exts: Vec<ClientExtension>,
+    cipher_suites: Vec<CipherSuite>,
+}
+
+impl StaticHello {
+    pub(super) fn new(config: &ClientConfig, quic: bool) -> Self {
+        let support_tls12 = config.supports_version(ProtocolVersion::TLSv1_2) && !quic;
+        let support_tls13 = config.supports_version(ProtocolVersion::TLSv1_3);
+
+        let mut supported_versions = Vec::new();
+        if support_tls13 {
+            supported_versions.push(ProtocolVersion::TLSv1_3);
+        }
+
+        if support_tls12 {
+            supported_versions.push(ProtocolVersion::TLSv1_2);
+        }
+
+        // should be unreachable thanks to config builder
+        assert!(!supported_versions.is_empty());
+
+        let leading_exts = vec![
+            ClientExtension::SupportedVersions(supported_versions),
+            ClientExtension::ECPointFormats(ECPointFormatList::supported()),
+            ClientExtension::NamedGroups(
+                config
+                    .kx_groups
+                    .iter()
+                    .map(|skxg| skxg.name)
+                    .collect(),
+            ),
+            ClientExtension::SignatureAlgorithms(offered_signature_schemes(config)),
+            ClientExtension::ExtendedMasterSecretRequest,
+            ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()),
+        ];
+
+        let mut trailing_exts = Vec::new();
+        if let Some(mfl) = config.max_fragment_length {
+            trailing_exts.push(ClientExtension::MaxFragmentLength(mfl));
+        }
+
+        if let Some(limit) = config.record_size_limit {
+            let limit = cmp::min(limit, max_record_size_limit(support_tls13));
+            trailing_exts.push(ClientExtension::RecordSizeLimit(limit));
+        }
+
+        if support_tls13 && config.enable_post_handshake_auth && !quic {
+            trailing_exts.push(ClientExtension::PostHandshakeAuth);
+        }
+
+        // Raw public keys are preferred where offered, but we can always
+        // fall back to X.509 if the server doesn't support them.
+        if support_tls13 && config.send_raw_public_key {
+            trailing_exts.push(ClientExtension::ClientCertificateTypes(vec![
+                CertificateType::RawPublicKey,
+                CertificateType::X509,
+            ]));
+        }
+
+        if support_tls13 && config.raw_public_key_verifier.is_some() {
+            trailing_exts.push(ClientExtension::ServerCertificateTypes(vec![
+                CertificateType::RawPublicKey,
+                CertificateType::X509,
+            ]));
+        }
+
+        if support_tls13 && !config.cert_decompressors.is_empty() {
+            trailing_exts.push(ClientExtension::CertificateCompressionAlgorithms(
+                config
+                    .cert_decompressors
+                    .iter()
+                    .map(|codec| codec.algorithm())
+                    .collect(),
+            ));
+        }
+
+        if support_tls13 && config.enable_delegated_credentials {
+            let schemes = offered_delegated_credential_schemes(config);
+            if !schemes.is_empty() {
+                trailing_exts.push(ClientExtension::DelegatedCredential(schemes));
+            }
+        }
+
+        if support_tls13 && (config.enable_tickets || !config.external_psks.is_empty()) {
+            // We could support PSK_KE here too. Such connections don't
+            // have forward secrecy, and are similar to TLS1.2 resumption.
+            let psk_modes = vec![PSKKeyExchangeMode::PSK_DHE_KE];
+            trailing_exts.push(ClientExtension::PresharedKeyModes(psk_modes));
+        }
+
+        if !config.alpn_protocols.is_empty() {
+            trailing_exts.push(ClientExtension::Protocols(ProtocolNameList::from_slices(
+                &config
+                    .alpn_protocols
+                    .iter()
+                    .map(|proto| &proto[..])
+                    .collect::<Vec<_>>(),
+            )));
+        }
+
+        let mut cipher_suites: Vec<_> = config
+            .cipher_suites
+            .iter()
+            .map(|cs| cs.suite())
+            .collect();
+        // We don't do renegotiation at all, in fact.
+        cipher_suites.push(CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV);
+
+        Self {
+            leading_exts,
+            trailing_exts,
+            cipher_suites,
+        }
+    }
+
+    /// Replace the extensions no connection alters by their encodings,
+    /// so that cloning each of them takes one allocation rather than
+    /// one per part.  `NamedGroups` is left alone, as GREASE adds to it.
+    pub(super) fn into_encoded(self) -> Self {
+        fn encode(exts: Vec<ClientExtension>) -> Vec<ClientExtension> {
+            exts.into_iter()
+                .map(|ext| match ext {
+                    ClientExtension::NamedGroups(_) => ext,
+                    _ => {
+                        // Drop the type and length; `Unknown` writes its own.
+                        let body = ext.get_encoding().split_off(4);
+                        ClientExtension::Unknown(UnknownExtension {
+                            typ: ext.get_type(),
+                            payload: Payload::new(body),
+                        })
+                    }
+                })
+                .collect()
+        }
+
+        Self {
+            leading_exts: encode(self.leading_exts),
+            trailing_exts: encode(self.trailing_exts),
+            cipher_suites: self.cipher_suites,
+        }
+    }
+}
+
+/// The signature schemes to offer in `signature_algorithms`: those
+/// configured, in their configured order, that our verifier supports.
+fn offered_signature_schemes(config: &ClientConfig) -> Vec<SignatureScheme> {
+    let supported = config
+        .verifier
+        .supported_verify_schemes();
+    match &config.signature_schemes {
+        Some(schemes) => schemes
+            .iter()
+            .filter(|scheme| supported.contains(scheme))
+            .cloned()
+            .collect(),
+        None => supported,
+    }
+}
+
+/// Check the server signed the handshake with a scheme we offered.
+pub(super) fn check_offered_signature_scheme(
+    config: &ClientConfig,
+    common: &mut CommonState,
+    scheme: SignatureScheme,
+) -> Result<(), Error> {
+    if offered_signature_schemes(config).contains(&scheme) {
+        return Ok(());
+    }
+
+    common.send_fatal_alert(AlertDescription::IllegalParameter);
+    Err(Error::PeerMisbehavedError(format!(
+        "server signed with unoffered signature scheme {:?}",
+        scheme
+    )))
+}
+
+/// The signature schemes to offer in `delegated_credential`: those
+/// offered in `signature_algorithms` which a credential's key may use.
+pub(super) fn offered_delegated_credential_schemes(config: &ClientConfig) -> Vec<SignatureScheme> {
+    offered_signature_schemes(config)
+        .into_iter()
+        .filter(|scheme| verify::DELEGATED_CREDENTIAL_SCHEMES.contains(scheme))
+        .collect()
+}
+
+pub(super) fn process_max_fragment_length(
+    common: &mut CommonState,
+    config: &ClientConfig,
+    mfl: Option<MaxFragmentLength>,
+) -> Result<(), Error> {
+    let mfl = match mfl {
+        Some(mfl) => mfl,
+        None => return Ok(()),
+    };
+
+    // The server can only echo what we asked for.
+    let max_len = match mfl.fragment_len() {
+        Some(max_len) if Some(mfl) == config.max_fragment_length => max_len,
+        _ => return Err(common.illegal_param("server sent non-offered max_fragment_length")),
+    };
+
+    debug!("Max fragment length is {}", max_len);
+    common.limit_fragment_len(max_len);
+    Ok(())
+}
+
+/// Apply the server's `record_size_limit` to what we send, and ours to
+/// what it sends.  The server only sends one if we did.
+pub(super) fn process_record_size_limit(
+    common: &mut CommonState,
+    config: &ClientConfig,
+    limit: Option<u16>,
+    mfl: Option<MaxFragmentLength>,
+) -> Result<(), Error> {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+
+    if limit < MIN_RECORD_SIZE_LIMIT {
+        return Err(common.illegal_param("server sent record_size_limit below 64"));
+    }
+
+    // RFC8449: "A client MUST treat receipt of both max_fragment_length
+    // and record_size_limit as a fatal error"
+    if mfl.is_some() {
+        return Err(common.illegal_param("server sent max_fragment_length and record_size_limit"));
+    }
+
+    debug!("Server's record size limit is {}", limit);
+    common.limit_outgoing_record_size(limit);
+    common.set_incoming_record_limit(config.record_size_limit);
+    Ok(())
+}
+
 pub(super) fn process_alpn_protocol(
     common: &mut CommonState,
     config: &ClientConfig,
@@ -545,6 +839,17 @@ impl State<ClientConnectionData> for ExpectServerHello {
         // Extract ALPN protocol
         if !cx.common.is_tls13() {
             process_alpn_protocol(cx.common, &self.config, server_hello.get_alpn_protocol())?;
+            process_record_size_limit(
+                cx.common,
+                &self.config,
+                server_hello.get_record_size_limit(),
+                server_hello.get_max_fragment_length(),
+            )?;
+            process_max_fragment_length(
+                cx.common,
+                &self.config,
+                server_hello.get_max_fragment_length(),
+            )?;
         }
 
         // If ECPointFormats extension is supplied by the server, it must contain
@@ -663,6 +968,7 @@ impl ExpectServerHelloOrHelloRetryRequest {
         trace!("Got HRR {:?}", hrr);
 
         cx.common.check_aligned_handshake()?;
+        cx.common.observe_hello_retry_request();
 
         let cookie = hrr.get_cookie();
         let req_group = hrr.get_requested_key_share_group();
@@ -749,7 +1055,7 @@ impl ExpectServerHelloOrHelloRetryRequest {
 
         // Early data is not allowed after HelloRetryrequest
         if cx.data.early_data.is_enabled() {
-            cx.data.early_data.rejected();
+            cx.data.reject_early_data(cx.common);
         }
 
         let may_send_sct_list = self
@@ -811,6 +1117,70 @@ impl State<ClientConnectionData> for ExpectServerHelloOrHelloRetryRequest {
     }
 }
 
+/// Checks any OCSP response the server stapled, if the config has an
+/// `ocsp_verifier`.
+pub(super) fn check_ocsp_response(
+    config: &ClientConfig,
+    server_cert: &ServerCertDetails,
+) -> Result<(), Error> {
+    let verifier = match &config.ocsp_verifier {
+        Some(verifier) if !server_cert.ocsp_response.is_empty() => verifier,
+        _ => return Ok(()),
+    };
+
+    let (end_entity, issuer) = match server_cert.cert_chain.as_slice() {
+        [end_entity, issuer, ..] => (end_entity, issuer),
+        _ => {
+            return Err(Error::InvalidCertificateData(
+                "no issuer certificate to check OCSP response".into(),
+            ))
+        }
+    };
+
+    match verifier.verify(&server_cert.ocsp_response, end_entity, issuer)? {
+        OcspStatus::Revoked => Err(Error::CertificateRevoked),
+        OcspStatus::Good | OcspStatus::Unknown => Ok(()),
+    }
+}
+
+/// Checks the SCTs the server sent against the config's `ct_policy`,
+/// if it has one.
+pub(super) fn check_scts(
+    config: &ClientConfig,
+    server_cert: &ServerCertDetails,
+    now: SystemTime,
+) -> Result<(), Error> {
+    let policy = match &config.ct_policy {
+        Some(policy) => policy,
+        None => return Ok(()),
+    };
+
+    let end_entity = server_cert
+        .cert_chain
+        .first()
+        .ok_or(Error::NoCertificatesPresented)?;
+    policy.verify(end_entity, now, &mut server_cert.scts())
+}
+
+/// Runs the config's `server_cert_policy`, if any, on a verified
+/// server certificate.
+pub(super) fn check_server_cert_policy(
+    config: &ClientConfig,
+    server_cert: &ServerCertDetails,
+    server_name: &ServerName,
+) -> Result<(), Error> {
+    let policy = match &config.server_cert_policy {
+        Some(policy) => policy,
+        None => return Ok(()),
+    };
+
+    let end_entity = server_cert
+        .cert_chain
+        .first()
+        .ok_or(Error::NoCertificatesPresented)?;
+    policy.check(end_entity, server_name)
+}
+
 pub(super) fn send_cert_error_alert(common: &mut CommonState, err: Error) -> Error {
     match err {
         Error::InvalidCertificateEncoding => {
@@ -819,6 +1189,21 @@ pub(super) fn send_cert_error_alert(common: &mut CommonState, err: Error) -> Err
         Error::PeerMisbehavedError(_) => {
             common.send_fatal_alert(AlertDescription::IllegalParameter);
         }
+        Error::CertificateRevoked => {
+            common.send_fatal_alert(AlertDescription::CertificateRevoked);
+        }
+        Error::InvalidCertificate(CertificateError::Expired { .. })
+        | Error::InvalidCertificate(CertificateError::NotValidYet { .. }) => {
+            common.send_fatal_alert(AlertDescription::CertificateExpired);
+        }
+        Error::InvalidCertificate(CertificateError::UnknownIssuer) => {
+            common.send_fatal_alert(AlertDescription::UnknownCA);
+        }
+        // RFC9345 asks for this alert for any invalid delegated credential.
+        Error::InvalidCertificate(CertificateError::DelegatedCredentialExpired { .. })
+        | Error::InvalidCertificate(CertificateError::BadDelegatedCredential) => {
+            common.send_fatal_alert(AlertDescription::IllegalParameter);
+        }
         _ => {
             common.send_fatal_alert(AlertDescription::BadCertificate);
         }
@@ -73,6 +73,15 @@ mod server_hello {
 
             // Doing EMS?
             self.using_ems = server_hello.ems_support_acked();
+            if self.using_ems {
+                cx.common.observe_ems();
+            } else if self.config.require_ems {
+                cx.common
+                    .send_fatal_alert(AlertDescription::HandshakeFailure);
+                return Err(Error::PeerIncompatibleError(
+                    "server did not agree to extended master secret".to_string(),
+                ));
+            }
 
             // Might the server send a ticket?
             let must_issue_new_ticket = if server_hello
@@ -111,6 +120,7 @@ mod server_hello {
             if let Some(ref resuming) = self.resuming_session {
                 if resuming.session_id == server_hello.session_id {
                     debug!("Server agreed to resume");
+                    cx.common.observe_resumption();
 
                     // Is the server telling lies about the ciphersuite?
                     if resuming.suite() != suite {
@@ -204,17 +214,15 @@ struct ExpectCertificate {
 }
 
 impl State<ClientConnectionData> for ExpectCertificate {
-    fn handle(
-        mut self: Box<Self>,
-        _cx: &mut ClientContext<'_>,
-        m: Message,
-    ) -> hs::NextStateOrError {
+    fn handle(mut self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
         self.transcript.add_message(&m);
         let server_cert_chain = require_handshake_msg_move!(
             m,
             HandshakeType::Certificate,
             HandshakePayload::Certificate
         )?;
+        cx.common
+            .check_cert_chain_len(server_cert_chain.len(), self.config.max_peer_cert_chain_len)?;
 
         if self.may_send_cert_status {
             Ok(Box::new(ExpectCertificateStatusOrServerKx {
@@ -726,6 +734,12 @@ impl State<ClientConnectionData> for ExpectServerDone {
                 now,
             )
             .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+        hs::check_ocsp_response(&st.config, &st.server_cert)
+            .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+        hs::check_scts(&st.config, &st.server_cert, now)
+            .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+        hs::check_server_cert_policy(&st.config, &st.server_cert, &st.server_name)
+            .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
 
         // 3.
         // Build up the contents of the signed message.
@@ -738,6 +752,7 @@ impl State<ClientConnectionData> for ExpectServerDone {
 
             // Check the signature is compatible with the ciphersuite.
             let sig = &st.server_kx.kx_sig;
+            hs::check_offered_signature_scheme(&st.config, cx.common, sig.scheme)?;
             if !SupportedCipherSuite::from(suite).usable_for_signature_algorithm(sig.scheme.sign())
             {
                 let error_message = format!(
@@ -798,6 +813,8 @@ impl State<ClientConnectionData> for ExpectServerDone {
             st.randoms,
             suite,
         )?;
+        cx.common
+            .observe_key_exchange(group.name);
 
         st.config.key_log.log(
             "CLIENT_RANDOM",
@@ -1,4 +1,5 @@
 use crate::check::inappropriate_handshake_message;
+use crate::compress;
 use crate::conn::{CommonState, ConnectionRandoms, State};
 use crate::error::Error;
 use crate::hash_hs::{HandshakeHash, HandshakeHashBuffer};
@@ -8,24 +9,27 @@ use crate::log::{debug, trace, warn};
 use crate::msgs::base::{Payload, PayloadU8};
 use crate::msgs::ccs::ChangeCipherSpecPayload;
 use crate::msgs::codec::Codec;
-use crate::msgs::enums::KeyUpdateRequest;
 use crate::msgs::enums::{AlertDescription, NamedGroup, ProtocolVersion};
+use crate::msgs::enums::{CertificateType, KeyUpdateRequest};
 use crate::msgs::enums::{ContentType, ExtensionType, HandshakeType, SignatureScheme};
+use crate::msgs::handshake::CertificateRequestPayloadTLS13;
 use crate::msgs::handshake::ClientExtension;
+use crate::msgs::handshake::DelegatedCredentialPayload;
 use crate::msgs::handshake::DigitallySignedStruct;
 use crate::msgs::handshake::EncryptedExtensions;
 use crate::msgs::handshake::NewSessionTicketPayloadTLS13;
 use crate::msgs::handshake::{CertificateEntry, CertificatePayloadTLS13};
 use crate::msgs::handshake::{HandshakeMessagePayload, HandshakePayload};
 use crate::msgs::handshake::{HasServerExtensions, ServerHelloPayload};
-use crate::msgs::handshake::{PresharedKeyIdentity, PresharedKeyOffer};
+use crate::msgs::handshake::{PresharedKeyBinder, PresharedKeyIdentity, PresharedKeyOffer};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
 use crate::tls13::key_schedule::{
     KeyScheduleEarly, KeyScheduleHandshake, KeySchedulePreHandshake, KeyScheduleTraffic,
 };
-use crate::tls13::Tls13CipherSuite;
+use crate::tls13::{ExternalPsk, Tls13CipherSuite};
 use crate::verify;
+use crate::SupportedCipherSuite;
 #[cfg(feature = "quic")]
 use crate::{conn::Protocol, msgs::base::PayloadU16, quic};
 use crate::{sign, KeyLog};
@@ -34,7 +38,7 @@ use super::client_conn::ClientConnectionData;
 use super::hs::ClientContext;
 use crate::client::common::ServerCertDetails;
 use crate::client::common::{ClientAuthDetails, ClientHelloDetails};
-use crate::client::{hs, ClientConfig, ServerName, StoresClientSessions};
+use crate::client::{hs, ClientConfig, ResolvesClientCert, ServerName, StoresClientSessions};
 
 use crate::ticketer::TimeBase;
 use ring::constant_time;
@@ -88,6 +92,7 @@ pub(super) fn handle_server_hello(
             .illegal_param("wrong group for key share"));
     }
 
+    let mut external_psk = false;
     let key_schedule_pre_handshake = if let (Some(selected_psk), Some(early_key_schedule)) =
         (server_hello.get_psk_index(), early_key_schedule)
     {
@@ -116,6 +121,7 @@ pub(super) fn handle_server_hello(
             }
 
             debug!("Resuming using PSK");
+            cx.common.observe_resumption();
             // The key schedule has been initialized and set in fill_in_psk_binder()
         } else {
             return Err(Error::PeerMisbehavedError(
@@ -123,10 +129,27 @@ pub(super) fn handle_server_hello(
             ));
         }
         KeySchedulePreHandshake::from(early_key_schedule)
+    } else if let Some(selected_psk) = server_hello.get_psk_index() {
+        let psk = hello
+            .offered_external_psks
+            .get(selected_psk as usize)
+            .ok_or_else(|| {
+                Error::PeerMisbehavedError("server selected unoffered psk".to_string())
+            })?;
+
+        if psk.hash_algorithm() != suite.hash_algorithm() {
+            return Err(cx
+                .common
+                .illegal_param("server chose suite unsuitable for psk"));
+        }
+
+        debug!("Authenticating using external PSK");
+        external_psk = true;
+        KeySchedulePreHandshake::from(KeyScheduleEarly::new(suite.hkdf_algorithm, psk.secret()))
     } else {
         debug!("Not resuming");
         // Discard the early data key schedule.
-        cx.data.early_data.rejected();
+        cx.data.reject_early_data(cx.common);
         cx.common.early_traffic = false;
         resuming_session.take();
         KeySchedulePreHandshake::new(suite.hkdf_algorithm)
@@ -135,6 +158,8 @@ pub(super) fn handle_server_hello(
     let key_schedule = our_key_share.complete(&their_key_share.payload.0, |secret| {
         Ok(key_schedule_pre_handshake.into_handshake(secret))
     })?;
+    cx.common
+        .observe_key_exchange(their_key_share.group);
 
     // Remember what KX group the server liked for next time.
     save_kx_hint(&config, &server_name, their_key_share.group);
@@ -173,6 +198,7 @@ pub(super) fn handle_server_hello(
     Ok(Box::new(ExpectEncryptedExtensions {
         config,
         resuming_session,
+        external_psk,
         server_name,
         randoms,
         suite,
@@ -256,6 +282,68 @@ pub(super) fn fill_in_psk_binder(
     key_schedule
 }
 
+/// The external PSKs we can offer: those with the hash function of a
+/// TLS1.3 suite we support or, after a HelloRetryRequest, of the suite
+/// the server chose.
+pub(super) fn offerable_external_psks(
+    config: &ClientConfig,
+    suite: Option<SupportedCipherSuite>,
+) -> Vec<ExternalPsk> {
+    config
+        .external_psks
+        .iter()
+        .filter(|psk| match suite {
+            Some(suite) => suite.hash_algorithm() == psk.hash_algorithm(),
+            None => config
+                .cipher_suites
+                .iter()
+                .filter_map(SupportedCipherSuite::tls13)
+                .any(|suite| suite.hash_algorithm() == psk.hash_algorithm()),
+        })
+        .cloned()
+        .collect()
+}
+
+/// Offer `psks` in a `pre_shared_key` extension, which must go last.
+///
+/// As for resumption, the binders are left empty until the rest of the
+/// message is known, and filled in by `fill_in_external_psk_binders`.
+pub(super) fn prepare_external_psks(psks: &[ExternalPsk], exts: &mut Vec<ClientExtension>) {
+    let psk_ext = PresharedKeyOffer {
+        identities: psks
+            .iter()
+            .map(|psk| PresharedKeyIdentity::new(psk.identity().to_vec(), 0))
+            .collect(),
+        binders: psks
+            .iter()
+            .map(|psk| PresharedKeyBinder::new(vec![0u8; psk.hash_algorithm().output_len]))
+            .collect(),
+    };
+    exts.push(ClientExtension::PresharedKey(psk_ext));
+}
+
+pub(super) fn fill_in_external_psk_binders(
+    psks: &[ExternalPsk],
+    transcript: &HandshakeHashBuffer,
+    hmp: &mut HandshakeMessagePayload,
+) {
+    let binder_plaintext = hmp.get_encoding_for_binder_signing();
+    let binders = psks
+        .iter()
+        .map(|psk| {
+            let handshake_hash = transcript.get_hash_given(psk.hash_algorithm(), &binder_plaintext);
+            KeyScheduleEarly::new(psk.hkdf_algorithm, psk.secret())
+                .external_psk_binder_key_and_sign_verify_data(&handshake_hash)
+                .as_ref()
+                .to_vec()
+        })
+        .collect();
+
+    if let HandshakePayload::ClientHello(ref mut ch) = hmp.payload {
+        ch.set_psk_binders(binders);
+    }
+}
+
 pub(super) fn prepare_resumption(
     config: &ClientConfig,
     cx: &mut ClientContext<'_>,
@@ -374,6 +462,7 @@ fn validate_encrypted_extensions(
 struct ExpectEncryptedExtensions {
     config: Arc<ClientConfig>,
     resuming_session: Option<persist::Tls13ClientSessionValue>,
+    external_psk: bool,
     server_name: ServerName,
     randoms: ConnectionRandoms,
     suite: &'static Tls13CipherSuite,
@@ -394,13 +483,23 @@ impl State<ClientConnectionData> for ExpectEncryptedExtensions {
 
         validate_encrypted_extensions(cx.common, &self.hello, exts)?;
         hs::process_alpn_protocol(cx.common, &self.config, exts.get_alpn_protocol())?;
+        hs::process_record_size_limit(
+            cx.common,
+            &self.config,
+            exts.get_record_size_limit(),
+            exts.get_max_fragment_length(),
+        )?;
+        hs::process_max_fragment_length(cx.common, &self.config, exts.get_max_fragment_length())?;
 
         #[cfg(feature = "quic")]
         {
             // QUIC transport parameters
             if cx.common.is_quic() {
                 match exts.get_quic_params_extension() {
-                    Some(params) => cx.common.quic.params = Some(params),
+                    Some((typ, params)) => cx
+                        .common
+                        .quic
+                        .received_params(typ, params),
                     None => {
                         return Err(cx
                             .common
@@ -415,8 +514,9 @@ impl State<ClientConnectionData> for ExpectEncryptedExtensions {
             if was_early_traffic {
                 if exts.early_data_extension_offered() {
                     cx.data.early_data.accepted();
+                    cx.common.observe_early_data();
                 } else {
-                    cx.data.early_data.rejected();
+                    cx.data.reject_early_data(cx.common);
                     cx.common.early_traffic = false;
                 }
             }
@@ -452,11 +552,50 @@ impl State<ClientConnectionData> for ExpectEncryptedExtensions {
                 cert_verified,
                 sig_verified,
             }))
+        } else if exts.early_data_extension_offered() {
+            let msg = "server sent early data extension without resumption".to_string();
+            Err(Error::PeerMisbehavedError(msg))
+        } else if self.external_psk {
+            // The server proved it knows the PSK by using it in the key schedule,
+            // which its Finished message will confirm.
+            let cert_verified = verify::ServerCertVerified::assertion();
+            let sig_verified = verify::HandshakeSignatureValid::assertion();
+            Ok(Box::new(ExpectFinished {
+                config: self.config,
+                server_name: self.server_name,
+                randoms: self.randoms,
+                suite: self.suite,
+                transcript: self.transcript,
+                key_schedule: self.key_schedule,
+                client_auth: None,
+                cert_verified,
+                sig_verified,
+            }))
         } else {
-            if exts.early_data_extension_offered() {
-                let msg = "server sent early data extension without resumption".to_string();
-                return Err(Error::PeerMisbehavedError(msg));
-            }
+            // We only offer raw public keys when configured to, so
+            // `validate_encrypted_extensions` ensures these are ours.
+            let raw_public_key_verifier = match exts.get_server_certificate_type() {
+                None | Some(CertificateType::X509) => None,
+                Some(CertificateType::RawPublicKey) => self
+                    .config
+                    .raw_public_key_verifier
+                    .clone(),
+                Some(_) => {
+                    return Err(cx
+                        .common
+                        .illegal_param("server chose unoffered server certificate type"));
+                }
+            };
+            let send_raw_public_key = match exts.get_client_certificate_type() {
+                None | Some(CertificateType::X509) => false,
+                Some(CertificateType::RawPublicKey) => true,
+                Some(_) => {
+                    return Err(cx
+                        .common
+                        .illegal_param("server chose unoffered client certificate type"));
+                }
+            };
+
             Ok(Box::new(ExpectCertificateOrCertReq {
                 config: self.config,
                 server_name: self.server_name,
@@ -465,6 +604,8 @@ impl State<ClientConnectionData> for ExpectEncryptedExtensions {
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
                 may_send_sct_list: self.hello.server_may_send_sct_list(),
+                raw_public_key_verifier,
+                send_raw_public_key,
             }))
         }
     }
@@ -478,6 +619,8 @@ struct ExpectCertificateOrCertReq {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     may_send_sct_list: bool,
+    raw_public_key_verifier: Option<Arc<dyn verify::RawPublicKeyVerifier>>,
+    send_raw_public_key: bool,
 }
 
 impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
@@ -486,6 +629,10 @@ impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
             MessagePayload::Handshake(HandshakeMessagePayload {
                 payload: HandshakePayload::CertificateTLS13(..),
                 ..
+            })
+            | MessagePayload::Handshake(HandshakeMessagePayload {
+                payload: HandshakePayload::CompressedCertificate(..),
+                ..
             }) => Box::new(ExpectCertificate {
                 config: self.config,
                 server_name: self.server_name,
@@ -494,6 +641,7 @@ impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
                 may_send_sct_list: self.may_send_sct_list,
+                raw_public_key_verifier: self.raw_public_key_verifier,
                 client_auth: None,
             })
             .handle(cx, m),
@@ -508,6 +656,8 @@ impl State<ClientConnectionData> for ExpectCertificateOrCertReq {
                 transcript: self.transcript,
                 key_schedule: self.key_schedule,
                 may_send_sct_list: self.may_send_sct_list,
+                raw_public_key_verifier: self.raw_public_key_verifier,
+                send_raw_public_key: self.send_raw_public_key,
             })
             .handle(cx, m),
             payload => Err(inappropriate_handshake_message(
@@ -533,6 +683,8 @@ struct ExpectCertificateRequest {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     may_send_sct_list: bool,
+    raw_public_key_verifier: Option<Arc<dyn verify::RawPublicKeyVerifier>>,
+    send_raw_public_key: bool,
 }
 
 impl State<ClientConnectionData> for ExpectCertificateRequest {
@@ -574,7 +726,7 @@ impl State<ClientConnectionData> for ExpectCertificateRequest {
             ));
         }
 
-        let client_auth = ClientAuthDetails::resolve(
+        let mut client_auth = ClientAuthDetails::resolve(
             self.config
                 .client_auth_cert_resolver
                 .as_ref(),
@@ -582,6 +734,9 @@ impl State<ClientConnectionData> for ExpectCertificateRequest {
             &compat_sigschemes,
             Some(certreq.context.0.clone()),
         );
+        if self.send_raw_public_key {
+            client_auth = client_auth.into_raw_public_key();
+        }
 
         Ok(Box::new(ExpectCertificate {
             config: self.config,
@@ -591,6 +746,7 @@ impl State<ClientConnectionData> for ExpectCertificateRequest {
             transcript: self.transcript,
             key_schedule: self.key_schedule,
             may_send_sct_list: self.may_send_sct_list,
+            raw_public_key_verifier: self.raw_public_key_verifier,
             client_auth: Some(client_auth),
         }))
     }
@@ -604,16 +760,33 @@ struct ExpectCertificate {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     may_send_sct_list: bool,
+    raw_public_key_verifier: Option<Arc<dyn verify::RawPublicKeyVerifier>>,
     client_auth: Option<ClientAuthDetails>,
 }
 
 impl State<ClientConnectionData> for ExpectCertificate {
     fn handle(mut self: Box<Self>, cx: &mut ClientContext<'_>, m: Message) -> hs::NextStateOrError {
-        let cert_chain = require_handshake_msg!(
-            m,
-            HandshakeType::Certificate,
-            HandshakePayload::CertificateTLS13
-        )?;
+        // A compressed certificate goes into the transcript as it was sent.
+        let decompressed;
+        let cert_chain = match m.payload {
+            MessagePayload::Handshake(HandshakeMessagePayload {
+                payload: HandshakePayload::CompressedCertificate(ref compressed),
+                ..
+            }) => {
+                decompressed = compress::decompress_certificate(
+                    &self.config.cert_decompressors,
+                    compressed,
+                    self.config.max_handshake_message_size,
+                    cx.common,
+                )?;
+                &decompressed
+            }
+            _ => require_handshake_msg!(
+                m,
+                HandshakeType::Certificate,
+                HandshakePayload::CertificateTLS13
+            )?,
+        };
         self.transcript.add_message(&m);
 
         // This is only non-empty for client auth.
@@ -624,6 +797,11 @@ impl State<ClientConnectionData> for ExpectCertificate {
             return Err(Error::corrupt_message(ContentType::Handshake));
         }
 
+        cx.common.check_cert_chain_len(
+            cert_chain.entries.len(),
+            self.config.max_peer_cert_chain_len,
+        )?;
+
         if cert_chain.any_entry_has_duplicate_extension()
             || cert_chain.any_entry_has_unknown_extension()
         {
@@ -635,6 +813,39 @@ impl State<ClientConnectionData> for ExpectCertificate {
             ));
         }
 
+        // A raw public key stands alone.
+        if self.raw_public_key_verifier.is_some() && cert_chain.entries.len() != 1 {
+            cx.common
+                .send_fatal_alert(AlertDescription::DecodeError);
+            return Err(Error::PeerMisbehavedError(
+                "server did not send exactly one raw public key".to_string(),
+            ));
+        }
+
+        // We only take a delegated credential if we offered to.  It
+        // stands in for a certificate's key, so can't come with a raw
+        // public key.
+        let delegated_credential = cert_chain
+            .get_end_entity_delegated_credential()
+            .cloned();
+        if let Some(dc) = &delegated_credential {
+            if !self.config.enable_delegated_credentials || self.raw_public_key_verifier.is_some() {
+                cx.common
+                    .send_fatal_alert(AlertDescription::UnsupportedExtension);
+                return Err(Error::PeerMisbehavedError(
+                    "server sent unsolicited delegated credential".to_string(),
+                ));
+            }
+
+            if !hs::offered_delegated_credential_schemes(&self.config)
+                .contains(&dc.cred.dc_cert_verify_algorithm)
+            {
+                return Err(cx
+                    .common
+                    .illegal_param("server sent delegated credential for unoffered scheme"));
+            }
+        }
+
         let server_cert = ServerCertDetails::new(
             cert_chain.convert(),
             cert_chain.get_end_entity_ocsp(),
@@ -661,6 +872,8 @@ impl State<ClientConnectionData> for ExpectCertificate {
             transcript: self.transcript,
             key_schedule: self.key_schedule,
             server_cert,
+            delegated_credential,
+            raw_public_key_verifier: self.raw_public_key_verifier,
             client_auth: self.client_auth,
         }))
     }
@@ -675,6 +888,8 @@ struct ExpectCertificateVerify {
     transcript: HandshakeHash,
     key_schedule: KeyScheduleHandshake,
     server_cert: ServerCertDetails,
+    delegated_credential: Option<DelegatedCredentialPayload>,
+    raw_public_key_verifier: Option<Arc<dyn verify::RawPublicKeyVerifier>>,
     client_auth: Option<ClientAuthDetails>,
 }
 
@@ -688,37 +903,68 @@ impl State<ClientConnectionData> for ExpectCertificateVerify {
 
         trace!("Server cert is {:?}", self.server_cert.cert_chain);
 
-        // 1. Verify the certificate chain.
-        let (end_entity, intermediates) = self
-            .server_cert
-            .cert_chain
-            .split_first()
-            .ok_or(Error::NoCertificatesPresented)?;
-        let now = std::time::SystemTime::now();
-        let cert_verified = self
-            .config
-            .verifier
-            .verify_server_cert(
-                end_entity,
-                intermediates,
-                &self.server_name,
-                &mut self.server_cert.scts(),
-                &self.server_cert.ocsp_response,
-                now,
-            )
-            .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
-
-        // 2. Verify their signature on the handshake.
+        hs::check_offered_signature_scheme(&self.config, cx.common, cert_verify.scheme)?;
+
         let handshake_hash = self.transcript.get_current_hash();
-        let sig_verified = self
-            .config
-            .verifier
-            .verify_tls13_signature(
-                &verify::construct_tls13_server_verify_message(&handshake_hash),
-                &self.server_cert.cert_chain[0],
-                cert_verify,
-            )
-            .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+        let message = verify::construct_tls13_server_verify_message(&handshake_hash);
+
+        let (cert_verified, sig_verified) = match &self.raw_public_key_verifier {
+            Some(raw_public_key_verifier) => {
+                // The server sent just its public key: there's no chain, and the
+                // ServerCertVerifier has no say in the matter.
+                let spki = &self.server_cert.cert_chain[0].0;
+                raw_public_key_verifier
+                    .verify_raw_public_key(spki)
+                    .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+                let sig_verified = raw_public_key_verifier
+                    .verify_tls13_signature(&message, spki, cert_verify)
+                    .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+                (verify::ServerCertVerified::assertion(), sig_verified)
+            }
+            None => {
+                // 1. Verify the certificate chain.
+                let (end_entity, intermediates) = self
+                    .server_cert
+                    .cert_chain
+                    .split_first()
+                    .ok_or(Error::NoCertificatesPresented)?;
+                let now = std::time::SystemTime::now();
+                let cert_verified = self
+                    .config
+                    .verifier
+                    .verify_server_cert(
+                        end_entity,
+                        intermediates,
+                        &self.server_name,
+                        &mut self.server_cert.scts(),
+                        &self.server_cert.ocsp_response,
+                        now,
+                    )
+                    .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+                hs::check_ocsp_response(&self.config, &self.server_cert)
+                    .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+                hs::check_scts(&self.config, &self.server_cert, now)
+                    .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+                hs::check_server_cert_policy(&self.config, &self.server_cert, &self.server_name)
+                    .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+
+                // 2. Verify their signature on the handshake, made by
+                // their delegated credential's key if they sent one.
+                let sig_verified = match &self.delegated_credential {
+                    Some(dc) => {
+                        verify::verify_delegated_credential(end_entity, dc, now).and_then(|_| {
+                            verify::verify_delegated_tls13_signature(&message, dc, cert_verify)
+                        })
+                    }
+                    None => self
+                        .config
+                        .verifier
+                        .verify_tls13_signature(&message, end_entity, cert_verify),
+                }
+                .map_err(|err| hs::send_cert_error_alert(cx.common, err))?;
+                (cert_verified, sig_verified)
+            }
+        };
 
         cx.common.peer_certificates = Some(self.server_cert.cert_chain);
         self.transcript.add_message(&m);
@@ -927,8 +1173,16 @@ impl State<ClientConnectionData> for ExpectFinished {
 
         cx.common.start_traffic();
 
+        let post_handshake_auth = if st.config.enable_post_handshake_auth && !cx.common.is_quic() {
+            Some(Arc::clone(&st.config.client_auth_cert_resolver))
+        } else {
+            None
+        };
+
         let st = ExpectTraffic {
             session_storage: Arc::clone(&st.config.session_storage),
+            max_tickets_per_server: st.config.max_tickets_per_server,
+            post_handshake_auth,
             server_name: st.server_name,
             suite: st.suite,
             transcript: st.transcript,
@@ -957,6 +1211,9 @@ impl State<ClientConnectionData> for ExpectFinished {
 // and application data.
 struct ExpectTraffic {
     session_storage: Arc<dyn StoresClientSessions>,
+    max_tickets_per_server: Option<usize>,
+    /// How to answer post-handshake `CertificateRequest`s, if we offered to.
+    post_handshake_auth: Option<Arc<dyn ResolvesClientCert>>,
     server_name: ServerName,
     suite: &'static Tls13CipherSuite,
     transcript: HandshakeHash,
@@ -1020,7 +1277,6 @@ impl ExpectTraffic {
             }
         }
 
-        let key = persist::ClientSessionKey::session_for_server_name(&self.server_name);
         #[allow(unused_mut)]
         let mut ticket = value.get_encoding();
 
@@ -1031,9 +1287,18 @@ impl ExpectTraffic {
             PayloadU16::encode_slice(quic_params, &mut ticket);
         }
 
-        let worked = self
-            .session_storage
-            .put(key.get_encoding(), ticket);
+        let worked = match self.max_tickets_per_server {
+            Some(max) => {
+                let key = persist::ClientSessionKey::tickets_for_server_name(&self.server_name);
+                self.session_storage
+                    .put_ticket(key.get_encoding(), ticket, max)
+            }
+            None => {
+                let key = persist::ClientSessionKey::session_for_server_name(&self.server_name);
+                self.session_storage
+                    .put(key.get_encoding(), ticket)
+            }
+        };
 
         if worked {
             debug!("Ticket saved");
@@ -1043,6 +1308,86 @@ impl ExpectTraffic {
         Ok(())
     }
 
+    fn handle_certificate_request(
+        &mut self,
+        common: &mut CommonState,
+        m: &Message,
+        certreq: &CertificateRequestPayloadTLS13,
+    ) -> Result<(), Error> {
+        let resolver = match &self.post_handshake_auth {
+            Some(resolver) => resolver,
+            None => {
+                common.send_fatal_alert(AlertDescription::UnexpectedMessage);
+                return Err(inappropriate_handshake_message(
+                    &m.payload,
+                    &[ContentType::ApplicationData, ContentType::Handshake],
+                    &[HandshakeType::NewSessionTicket, HandshakeType::KeyUpdate],
+                ));
+            }
+        };
+        debug!("Got post-handshake CertificateRequest {:?}", certreq);
+
+        // Must be non-empty after the handshake, so requests can be told apart.
+        if certreq.context.0.is_empty() {
+            warn!("Server sent empty post-handshake certreq context");
+            common.send_fatal_alert(AlertDescription::IllegalParameter);
+            return Err(Error::PeerMisbehavedError(
+                "server sent empty post-handshake certreq context".to_string(),
+            ));
+        }
+
+        let tls13_sign_schemes = sign::supported_sign_tls13();
+        let no_sigschemes = Vec::new();
+        let compat_sigschemes = certreq
+            .get_sigalgs_extension()
+            .unwrap_or(&no_sigschemes)
+            .iter()
+            .cloned()
+            .filter(|scheme| tls13_sign_schemes.contains(scheme))
+            .collect::<Vec<SignatureScheme>>();
+
+        if compat_sigschemes.is_empty() {
+            common.send_fatal_alert(AlertDescription::HandshakeFailure);
+            return Err(Error::PeerIncompatibleError(
+                "server sent bad certreq schemes".to_string(),
+            ));
+        }
+
+        let client_auth = ClientAuthDetails::resolve(
+            resolver.as_ref(),
+            certreq.get_authorities_extension(),
+            &compat_sigschemes,
+            Some(certreq.context.0.clone()),
+        );
+
+        // The handshake context is the main handshake plus this request;
+        // it doesn't carry over to later requests.
+        let mut transcript = self.transcript.clone();
+        transcript.add_message(m);
+
+        match client_auth {
+            ClientAuthDetails::Empty {
+                auth_context_tls13: auth_context,
+            } => {
+                emit_certificate_tls13(&mut transcript, None, auth_context, common);
+            }
+            ClientAuthDetails::Verify {
+                certkey,
+                signer,
+                auth_context_tls13: auth_context,
+            } => {
+                emit_certificate_tls13(&mut transcript, Some(&certkey), auth_context, common);
+                emit_certverify_tls13(&mut transcript, signer.as_ref(), common)?;
+            }
+        }
+
+        let verify_data = self
+            .key_schedule
+            .sign_client_post_handshake_finish(&transcript.get_current_hash());
+        emit_finished_tls13(&mut transcript, verify_data, common);
+        Ok(())
+    }
+
     fn handle_key_update(
         &mut self,
         common: &mut CommonState,
@@ -1085,6 +1430,19 @@ impl ExpectTraffic {
 
         Ok(())
     }
+
+    /// Send `key_update`, and switch to new write-side keys for
+    /// everything after it.
+    fn write_key_update(&mut self, common: &mut CommonState, key_update: Message) {
+        common.send_msg_encrypt(key_update.into());
+
+        let write_key = self
+            .key_schedule
+            .next_client_application_traffic_secret();
+        common
+            .record_layer
+            .set_message_encrypter(self.suite.derive_encrypter(&write_key));
+    }
 }
 
 impl State<ClientConnectionData> for ExpectTraffic {
@@ -1101,6 +1459,10 @@ impl State<ClientConnectionData> for ExpectTraffic {
                 payload: HandshakePayload::KeyUpdate(ref key_update),
                 ..
             }) => self.handle_key_update(cx.common, key_update)?,
+            MessagePayload::Handshake(HandshakeMessagePayload {
+                payload: HandshakePayload::CertificateRequestTLS13(ref certreq),
+                ..
+            }) => self.handle_certificate_request(cx.common, &m, certreq)?,
             payload => {
                 return Err(inappropriate_handshake_message(
                     &payload,
@@ -1126,16 +1488,24 @@ impl State<ClientConnectionData> for ExpectTraffic {
     fn perhaps_write_key_update(&mut self, common: &mut CommonState) {
         if self.want_write_key_update {
             self.want_write_key_update = false;
-            common.send_msg_encrypt(Message::build_key_update_notify().into());
-
-            let write_key = self
-                .key_schedule
-                .next_client_application_traffic_secret();
-            common
-                .record_layer
-                .set_message_encrypter(self.suite.derive_encrypter(&write_key));
+            self.write_key_update(common, Message::build_key_update_notify());
         }
     }
+
+    fn send_key_update(
+        &mut self,
+        common: &mut CommonState,
+        request_peer_update: bool,
+    ) -> Result<(), Error> {
+        // This also answers any update the peer asked for.
+        self.want_write_key_update = false;
+        let key_update = match request_peer_update {
+            true => Message::build_key_update_request(),
+            false => Message::build_key_update_notify(),
+        };
+        self.write_key_update(common, key_update);
+        Ok(())
+    }
 }
 
 #[cfg(feature = "quic")]
@@ -0,0 +1,118 @@
+use crate::conn::CommonState;
+use crate::error::Error;
+#[cfg(feature = "logging")]
+use crate::log::warn;
+use crate::msgs::base::PayloadU24;
+use crate::msgs::codec::{self, Codec, Reader};
+use crate::msgs::enums::{AlertDescription, CertificateCompressionAlgorithm};
+use crate::msgs::handshake::{CertificatePayloadTLS13, CompressedCertificatePayload};
+
+use std::sync::Arc;
+
+/// Compresses certificates with one RFC8879 algorithm.
+///
+/// rustls doesn't carry any compression code itself.  Instead, put
+/// implementations of this trait wrapping your preferred brotli,
+/// zlib or zstd crate in `ServerConfig::cert_compressors`.
+pub trait CertCompressor: Send + Sync {
+    /// Which algorithm this is.
+    fn algorithm(&self) -> CertificateCompressionAlgorithm;
+
+    /// Compress `input`, or return `None` to send it uncompressed.
+    fn compress(&self, input: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Decompresses certificates compressed with one RFC8879 algorithm.
+///
+/// Only the algorithms in `ClientConfig::cert_decompressors` are
+/// advertised, and so accepted.
+pub trait CertDecompressor: Send + Sync {
+    /// Which algorithm this is.
+    fn algorithm(&self) -> CertificateCompressionAlgorithm;
+
+    /// Decompress `input` into `output`, which is exactly the size
+    /// the peer says the decompressed message is.
+    ///
+    /// Return `false` if `input` is not valid, or does not decompress
+    /// to exactly `output.len()` bytes.
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> bool;
+}
+
+/// Choose the first of `ours` which the peer `offered`.
+pub(crate) fn choose<'a>(
+    ours: &'a [Arc<dyn CertCompressor>],
+    offered: &[CertificateCompressionAlgorithm],
+) -> Option<&'a dyn CertCompressor> {
+    ours.iter()
+        .find(|codec| offered.contains(&codec.algorithm()))
+        .map(|codec| codec.as_ref())
+}
+
+/// Compress `cert` with `codec`, if it is willing.
+pub(crate) fn compress_certificate(
+    codec: &dyn CertCompressor,
+    cert: &CertificatePayloadTLS13,
+) -> Option<CompressedCertificatePayload> {
+    let uncompressed = cert.get_encoding();
+    let compressed = codec.compress(&uncompressed)?;
+
+    Some(CompressedCertificatePayload {
+        alg: codec.algorithm(),
+        uncompressed_len: codec::u24(uncompressed.len() as u32),
+        compressed: PayloadU24::new(compressed),
+    })
+}
+
+/// Undo [`compress_certificate`], using whichever of `ours` the
+/// peer chose.  An algorithm we don't have is refused like any
+/// other certificate we can't decompress.
+///
+/// The peer's claimed decompressed size is checked against
+/// `max_len` before anything is allocated.
+pub(crate) fn decompress_certificate(
+    ours: &[Arc<dyn CertDecompressor>],
+    compressed: &CompressedCertificatePayload,
+    max_len: usize,
+    common: &mut CommonState,
+) -> Result<CertificatePayloadTLS13, Error> {
+    let codec = match ours
+        .iter()
+        .find(|codec| codec.algorithm() == compressed.alg)
+    {
+        Some(codec) => codec,
+        None => {
+            return Err(bad_certificate(
+                common,
+                "certificate compressed with unoffered algorithm",
+            ))
+        }
+    };
+
+    let len = compressed.uncompressed_len.0 as usize;
+    if len == 0 || len > max_len {
+        warn!("compressed certificate would be {} bytes", len);
+        return Err(bad_certificate(
+            common,
+            "compressed certificate is too large",
+        ));
+    }
+
+    let mut uncompressed = vec![0u8; len];
+    if !codec.decompress(&compressed.compressed.0, &mut uncompressed) {
+        return Err(bad_certificate(common, "certificate decompression failed"));
+    }
+
+    let mut rd = Reader::init(&uncompressed);
+    match CertificatePayloadTLS13::read(&mut rd) {
+        Some(cert) if !rd.any_left() => Ok(cert),
+        _ => Err(bad_certificate(
+            common,
+            "decompressed certificate is invalid",
+        )),
+    }
+}
+
+fn bad_certificate(common: &mut CommonState, why: &str) -> Error {
+    common.send_fatal_alert(AlertDescription::BadCertificate);
+    Error::PeerMisbehavedError(why.to_string())
+}
@@ -1,12 +1,16 @@
 use crate::error::Error;
+use crate::handshake_observer::{HandshakeObserver, ObservedHandshake};
 use crate::key;
 #[cfg(feature = "logging")]
 use crate::log::{debug, error, trace, warn};
 use crate::msgs::alert::AlertMessagePayload;
 use crate::msgs::base::Payload;
+use crate::msgs::codec;
 use crate::msgs::deframer::MessageDeframer;
 use crate::msgs::enums::HandshakeType;
-use crate::msgs::enums::{AlertDescription, AlertLevel, ContentType, ProtocolVersion};
+use crate::msgs::enums::{AlertDescription, AlertLevel, ContentType, NamedGroup, ProtocolVersion};
+#[cfg(feature = "quic")]
+use crate::msgs::enums::ExtensionType;
 use crate::msgs::fragmenter::MessageFragmenter;
 use crate::msgs::handshake::Random;
 use crate::msgs::hsjoiner::HandshakeJoiner;
@@ -20,12 +24,18 @@ use crate::suites::SupportedCipherSuite;
 #[cfg(feature = "tls12")]
 use crate::tls12::ConnectionSecrets;
 use crate::vecbuf::ChunkVecBuffer;
+use crate::x509;
+
+use ring::digest;
 
+use std::cmp;
 use std::collections::VecDeque;
 use std::convert::TryFrom;
 use std::io;
 use std::mem;
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 /// A client or server connection.
 pub enum Connection {
@@ -72,6 +82,17 @@ impl Connection {
         }
     }
 
+    /// Processes packets read by [`Connection::read_tls`], until enough
+    /// plaintext is waiting to be read.
+    ///
+    /// See [`ConnectionCommon::process_new_packets_limited()`] for more information.
+    pub fn process_new_packets_limited(&mut self, max_plaintext: usize) -> Result<IoState, Error> {
+        match self {
+            Connection::Client(conn) => conn.process_new_packets_limited(max_plaintext),
+            Connection::Server(conn) => conn.process_new_packets_limited(max_plaintext),
+        }
+    }
+
     /// Derives key material from the agreed connection secrets.
     ///
     /// See [`ConnectionCommon::export_keying_material()`] for more information.
@@ -87,6 +108,26 @@ impl Connection {
         }
     }
 
+    /// Computes a channel binding for this connection.
+    ///
+    /// See [`ConnectionCommon::channel_binding()`] for more information.
+    pub fn channel_binding(&self, typ: ChannelBindingType) -> Option<Vec<u8>> {
+        match self {
+            Connection::Client(conn) => conn.channel_binding(typ),
+            Connection::Server(conn) => conn.channel_binding(typ),
+        }
+    }
+
+    /// Abandons the handshake because it has taken too long.
+    ///
+    /// See [`ConnectionCommon::time_out_handshake()`] for more information.
+    pub fn time_out_handshake(&mut self) {
+        match self {
+            Connection::Client(conn) => conn.time_out_handshake(),
+            Connection::Server(conn) => conn.time_out_handshake(),
+        }
+    }
+
     /// This function uses `io` to complete any outstanding IO for this connection.
     ///
     /// See [`ConnectionCommon::complete_io()`] for more information.
@@ -111,6 +152,13 @@ impl crate::quic::QuicExt for Connection {
         }
     }
 
+    fn quic_transport_parameters_extension(&self) -> Option<(ExtensionType, &[u8])> {
+        match self {
+            Connection::Client(conn) => conn.quic_transport_parameters_extension(),
+            Connection::Server(conn) => conn.quic_transport_parameters_extension(),
+        }
+    }
+
     fn zero_rtt_keys(&self) -> Option<quic::DirectionalKeys> {
         match self {
             Connection::Client(conn) => conn.zero_rtt_keys(),
@@ -132,6 +180,13 @@ impl crate::quic::QuicExt for Connection {
         }
     }
 
+    fn next_1rtt_keys(&mut self) -> Option<quic::PacketKeySet> {
+        match self {
+            Connection::Client(conn) => conn.next_1rtt_keys(),
+            Connection::Server(conn) => conn.next_1rtt_keys(),
+        }
+    }
+
     fn alert(&self) -> Option<AlertDescription> {
         match self {
             Connection::Client(conn) => conn.alert(),
@@ -195,6 +250,62 @@ impl IoState {
     }
 }
 
+/// The next thing a connection needs from its caller, as returned
+/// by [`CommonState::io_state_hint`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoStateHint {
+    /// TLS data is waiting to be written with [`CommonState::write_tls`].
+    /// This takes priority over reading.
+    WantWrite,
+    /// More TLS data should be read with [`Connection::read_tls`].
+    WantRead,
+    /// Nothing is needed until the caller reads the plaintext that has
+    /// been received, processes records held back by
+    /// [`Connection::process_new_packets_limited`], or writes more.
+    Idle,
+    /// There is nothing to write, and the peer will send nothing more:
+    /// it sent a `close_notify` alert, the transport reached EOF, or we
+    /// sent a fatal alert.  Received plaintext may still be waiting.
+    Closed,
+}
+
+/// What was agreed with the peer during a handshake, as returned by
+/// `handshake_details` once it is complete.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandshakeDetails {
+    /// The protocol version.
+    pub protocol_version: ProtocolVersion,
+    /// The cipher suite.
+    pub cipher_suite: SupportedCipherSuite,
+    /// The group used for key exchange.  This is `None` for TLS1.2
+    /// resumptions, which reuse the previous key exchange.
+    pub key_exchange_group: Option<NamedGroup>,
+    /// The protocol agreed using ALPN, if any.
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// For servers, the hostname the client asked for using SNI, if any.
+    /// This is always `None` for clients.
+    pub sni_hostname: Option<String>,
+    /// Whether a previous session was resumed.
+    pub resumed: bool,
+    /// Whether the server accepted early data.
+    pub early_data: bool,
+    /// How many certificates the peer presented.
+    pub peer_certificate_count: usize,
+}
+
+/// The kind of handshake a connection completed, as returned by
+/// [`CommonState::handshake_kind`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HandshakeKind {
+    /// A full handshake, which did not resume a previous session.
+    Full,
+    /// A full handshake, in which the server first asked the client to
+    /// retry with a different key share using a TLS1.3 `HelloRetryRequest`.
+    FullWithHelloRetryRequest,
+    /// A handshake which resumed a previous session.
+    Resumed,
+}
+
 /// A structure that implements [`std::io::Read`] for reading plaintext.
 pub struct Reader<'a> {
     received_plaintext: &'a mut ChunkVecBuffer,
@@ -294,17 +405,26 @@ pub trait PlaintextSink {
     fn flush(&mut self) -> io::Result<()>;
 }
 
-impl<T> PlaintextSink for ConnectionCommon<T> {
+impl<T: SideData> PlaintextSink for ConnectionCommon<T> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_plaintext_writable()?;
         Ok(self.send_some_plaintext(buf))
     }
 
     fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
-        let mut sz = 0;
-        for buf in bufs {
-            sz += self.send_some_plaintext(buf);
+        self.check_plaintext_writable()?;
+        if bufs.len() <= 1 {
+            let buf = bufs.first().map_or(&[][..], |b| &b[..]);
+            return Ok(self.send_some_plaintext(buf));
         }
-        Ok(sz)
+
+        // Join the slices so they share records, rather than
+        // sending at least one record per slice.
+        let joined = bufs.iter().fold(Vec::new(), |mut joined, buf| {
+            joined.extend_from_slice(buf);
+            joined
+        });
+        Ok(self.send_some_plaintext(&joined))
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -338,6 +458,34 @@ impl<'a> io::Write for Writer<'a> {
     /// TLS handshake completes, and sends it as soon
     /// as it can.  See [`CommonState::set_buffer_limit`] to control
     /// the size of this buffer.
+    ///
+    /// Plaintext is deferred like this:
+    ///
+    /// - by a client, until it has received the server's `Finished`
+    ///   and sent its own.  A client with [`ClientConfig::enable_early_data`]
+    ///   set instead sends plaintext as early data while the server
+    ///   allows it (see [`ClientConnection::early_data`]), unless
+    ///   earlier plaintext is already waiting.  If the server rejects
+    ///   the early data, that plaintext is sent again once the handshake
+    ///   completes.  Remember that early data can be replayed.
+    /// - by a server, until it has received the client's `Finished`.
+    ///   With [`ServerConfig::send_half_rtt_data`], and no client
+    ///   authentication, it is instead sent straight after the
+    ///   server's own `Finished`, as 0.5-RTT data.
+    ///
+    /// After that plaintext is sent immediately.  Writes fail, writing
+    /// nothing, only where the plaintext could never be sent:
+    ///
+    /// - once the connection has failed, with an error of kind
+    ///   [`io::ErrorKind::InvalidData`] wrapping the TLS error.
+    /// - once we have sent a `close_notify` or fatal alert, with
+    ///   an error of kind [`io::ErrorKind::BrokenPipe`].
+    /// - always for QUIC connections, which carry application data in
+    ///   QUIC streams, with an error of kind [`io::ErrorKind::Other`].
+    ///
+    /// [`ClientConfig::enable_early_data`]: crate::ClientConfig::enable_early_data
+    /// [`ClientConnection::early_data`]: crate::ClientConnection::early_data
+    /// [`ServerConfig::send_half_rtt_data`]: crate::ServerConfig::send_half_rtt_data
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         self.sink.write(buf)
     }
@@ -411,6 +559,25 @@ impl<Data> ConnectionCommon<Data> {
         }
     }
 
+    /// Limit the size of each received handshake message to
+    /// `max_message_size` bytes, and of all those making up the
+    /// handshake to `max_handshake_size` bytes.
+    pub(crate) fn set_handshake_limits(
+        &mut self,
+        max_message_size: usize,
+        max_handshake_size: usize,
+    ) {
+        self.handshake_joiner
+            .set_limits(max_message_size, max_handshake_size);
+    }
+
+    /// Refuse the first handshake message, the `ClientHello` for a
+    /// server, if it is split across more than `max_records` records.
+    pub(crate) fn limit_first_handshake_message_records(&mut self, max_records: usize) {
+        self.handshake_joiner
+            .set_first_message_record_limit(max_records);
+    }
+
     /// Returns an object that allows reading plaintext.
     pub fn reader(&mut self) -> Reader {
         Reader {
@@ -425,9 +592,30 @@ impl<Data> ConnectionCommon<Data> {
         }
     }
 
-    /// Returns an object that allows writing plaintext.
-    pub fn writer(&mut self) -> Writer {
-        Writer::new(self)
+    /// Fail if plaintext written now could never be sent.
+    fn check_plaintext_writable(&self) -> io::Result<()> {
+        if let Err(err) = &self.state {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, err.clone()));
+        }
+
+        if self.common_state.has_sent_close_notify || self.common_state.sent_fatal_alert {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "cannot write plaintext after closing the connection",
+            ));
+        }
+
+        #[cfg(feature = "quic")]
+        {
+            if let Protocol::Quic = self.common_state.protocol {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "QUIC connections cannot write plaintext",
+                ));
+            }
+        }
+
+        Ok(())
     }
 
     /// This function uses `io` to complete any outstanding IO for
@@ -592,6 +780,14 @@ impl<Data> ConnectionCommon<Data> {
             false => msg.into_plain_message(),
         };
 
+        self.process_plain_msg(msg, state)
+    }
+
+    fn process_plain_msg(
+        &mut self,
+        msg: PlainMessage,
+        state: Box<dyn State<Data>>,
+    ) -> Result<Box<dyn State<Data>>, Error> {
         // For handshake messages, we need to join them before parsing
         // and processing.
         if self.handshake_joiner.want_message(&msg) {
@@ -639,9 +835,43 @@ impl<Data> ConnectionCommon<Data> {
     /// Success from this function comes with some sundry state data
     /// about the connection.
     ///
+    /// Every complete record buffered by [`read_tls`] is processed by
+    /// one call, so when several records arrive together there's no
+    /// need to call this once for each.  A record which has only partly
+    /// arrived is left buffered until [`read_tls`] reads the rest.
+    ///
     /// [`read_tls`]: Connection::read_tls
     /// [`process_new_packets`]: Connection::process_new_packets
     pub fn process_new_packets(&mut self) -> Result<IoState, Error> {
+        self.process_new_packets_limited(usize::MAX)
+    }
+
+    /// Like [`process_new_packets`], but stops once at least
+    /// `max_plaintext` bytes of received application data are waiting
+    /// to be read, leaving any further records buffered.
+    ///
+    /// Records are processed whole, so the last one may take the
+    /// plaintext past the budget by up to one record's worth.  Data
+    /// already waiting counts too: if it reaches the budget, nothing
+    /// is processed.
+    ///
+    /// Records held back are processed by the next call to this or
+    /// to [`process_new_packets`], as if they had just been read, so
+    /// read some plaintext and then call this again.  Until they are,
+    /// [`read_tls`] returns `Ok(0)` without reading anything and
+    /// [`CommonState::wants_read`] returns false, so the ciphertext
+    /// buffered is bounded as well as the plaintext.
+    ///
+    /// Every record past the budget is held back, whatever it holds.
+    /// Records must be decrypted in order, and TLS1.3 hides their type
+    /// until they are, so alerts (including `close_notify`), `KeyUpdate`
+    /// and other handshake messages that arrive after the data wait for
+    /// it to be read.  Likewise a budget of zero processes nothing at
+    /// all, not even the handshake.
+    ///
+    /// [`read_tls`]: Connection::read_tls
+    /// [`process_new_packets`]: Connection::process_new_packets
+    pub fn process_new_packets_limited(&mut self, max_plaintext: usize) -> Result<IoState, Error> {
         let mut state = match mem::replace(&mut self.state, Err(Error::HandshakeNotComplete)) {
             Ok(state) => state,
             Err(e) => {
@@ -654,7 +884,16 @@ impl<Data> ConnectionCommon<Data> {
             return Err(Error::CorruptMessage);
         }
 
-        while let Some(msg) = self.message_deframer.frames.pop_front() {
+        while self
+            .common_state
+            .plaintext_bytes_to_read()
+            < max_plaintext
+        {
+            let msg = match self.message_deframer.frames.pop_front() {
+                Some(msg) => msg,
+                None => break,
+            };
+
             match self.process_msg(msg, state) {
                 Ok(new) => state = new,
                 Err(e) => {
@@ -664,7 +903,48 @@ impl<Data> ConnectionCommon<Data> {
             }
         }
 
+        self.common_state.records_held_back = !self.message_deframer.frames.is_empty();
         self.state = Ok(state);
+        self.common_state
+            .update_handshake_observer();
+        Ok(self.common_state.current_io_state())
+    }
+
+    /// Processes `msg` as if it had just been received from the peer
+    /// and decrypted, without going through the record layer.  This
+    /// is for fuzzing and conformance testing, which need to give the
+    /// state machine messages that can't easily be sent over the wire.
+    ///
+    /// Handshake messages are split and joined as usual, so `msg` may
+    /// hold part of a handshake message, or several.  Unlike
+    /// [`ConnectionCommon::process_new_packets`], TLS1.3 middlebox
+    /// compatibility `ChangeCipherSpec` messages are not dropped, as
+    /// that happens in the record layer.
+    ///
+    /// The result, and the state of the connection afterwards, are
+    /// as for [`ConnectionCommon::process_new_packets`].  Any replies
+    /// are queued for [`CommonState::write_tls`].
+    #[cfg(feature = "message_api")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "message_api")))]
+    pub fn process_message(&mut self, msg: Message) -> Result<IoState, Error> {
+        let state = match mem::replace(&mut self.state, Err(Error::HandshakeNotComplete)) {
+            Ok(state) => state,
+            Err(e) => {
+                self.state = Err(e.clone());
+                return Err(e);
+            }
+        };
+
+        match self.process_plain_msg(PlainMessage::from(msg), state) {
+            Ok(new) => self.state = Ok(new),
+            Err(e) => {
+                self.state = Err(e.clone());
+                return Err(e);
+            }
+        }
+
+        self.common_state
+            .update_handshake_observer();
         Ok(self.common_state.current_io_state())
     }
 
@@ -679,15 +959,21 @@ impl<Data> ConnectionCommon<Data> {
                 .process_main_protocol(msg, state, &mut self.data)?;
         }
 
+        if !self.common_state.is_handshaking() {
+            self.handshake_joiner.handshake_complete();
+        }
+
         Ok(state)
     }
 
-    pub fn send_some_plaintext(&mut self, buf: &[u8]) -> usize {
+    fn perhaps_update_keys(&mut self) {
         if let Ok(st) = &mut self.state {
             st.perhaps_write_key_update(&mut self.common_state);
+            if self.common_state.wants_key_update() {
+                // Cannot fail once the handshake is complete.
+                let _ = st.send_key_update(&mut self.common_state, false);
+            }
         }
-        self.common_state
-            .send_some_plaintext(buf)
     }
 
     /// Read TLS content from `rd`.  This method does internal
@@ -704,8 +990,25 @@ impl<Data> ConnectionCommon<Data> {
     /// so.  This typically happens when a socket is cleanly closed,
     /// or a file is at EOF.
     ///
+    /// Each call reads up to the size of the largest possible record,
+    /// which may be many smaller records.  These are all split apart
+    /// before this returns, and the buffer is only compacted once
+    /// afterwards, so the cost of reading does not grow with the
+    /// number of records read at once.
+    ///
+    /// While records are held back by [`process_new_packets_limited`],
+    /// this also returns `Ok(0)`, but without reading from `rd`.  That
+    /// is not taken as EOF: [`CommonState::wants_read`] returns false
+    /// until they are processed, so callers which check it first never
+    /// see this.
+    ///
     /// [`process_new_packets`]: Connection::process_new_packets
+    /// [`process_new_packets_limited`]: Connection::process_new_packets_limited
     pub fn read_tls(&mut self, rd: &mut dyn io::Read) -> Result<usize, io::Error> {
+        if self.common_state.records_held_back {
+            return Ok(0);
+        }
+
         let res = self.message_deframer.read(rd);
         if let Ok(0) = res {
             self.common_state.has_seen_eof = true;
@@ -737,6 +1040,179 @@ impl<Data> ConnectionCommon<Data> {
             Err(e) => Err(e.clone()),
         }
     }
+
+    /// Computes a channel binding (RFC5056) of type `typ`, which an
+    /// application-layer authentication can include to show it
+    /// happened over this connection.
+    ///
+    /// This returns `None` until the handshake is complete, and when
+    /// `typ` isn't defined for the connection; see [`ChannelBindingType`].
+    pub fn channel_binding(&self, typ: ChannelBindingType) -> Option<Vec<u8>> {
+        if self.is_handshaking() {
+            return None;
+        }
+
+        match typ {
+            ChannelBindingType::TlsExporter => {
+                match self.protocol_version()? {
+                    ProtocolVersion::TLSv1_3 => {}
+                    ProtocolVersion::TLSv1_2 if self.common_state.using_ems => {}
+                    _ => return None,
+                }
+
+                let mut binding = vec![0u8; 32];
+                self.export_keying_material(&mut binding, b"EXPORTER-Channel-Binding", Some(b""))
+                    .ok()?;
+                Some(binding)
+            }
+            ChannelBindingType::TlsServerEndPoint => {
+                let cert = match self.side {
+                    Side::Client => self
+                        .peer_certificates
+                        .as_ref()?
+                        .first()?,
+                    Side::Server => self
+                        .common_state
+                        .sent_certificate
+                        .as_ref()?,
+                };
+                let hash = server_end_point_hash(x509::signature_algorithm(&cert.0)?)?;
+                Some(
+                    digest::digest(hash, &cert.0)
+                        .as_ref()
+                        .to_vec(),
+                )
+            }
+        }
+    }
+
+    pub(crate) fn request_post_handshake_auth(&mut self) -> Result<(), Error> {
+        match self.state.as_mut() {
+            Ok(st) => st.request_post_handshake_auth(&mut self.common_state),
+            Err(e) => Err(e.clone()),
+        }
+    }
+
+    /// Replaces the keys used to encrypt data sent on this connection,
+    /// and asks the peer to do the same, using a TLS1.3 `KeyUpdate`
+    /// message (RFC 8446 section 4.6.3).
+    ///
+    /// The `KeyUpdate` is queued to be written by the next `write_tls`,
+    /// and everything written after this call is encrypted with the new
+    /// keys.  The peer updates its own keys before it next sends data.
+    ///
+    /// Key updates from the peer are handled automatically, whether or
+    /// not this is ever called.
+    ///
+    /// This fails if the handshake is not yet complete, or if the
+    /// connection is not TLS1.3 over TCP.
+    pub fn refresh_traffic_keys(&mut self) -> Result<(), Error> {
+        if self.common_state.is_handshaking() {
+            return Err(Error::HandshakeNotComplete);
+        }
+
+        if !self.common_state.is_tls13() || self.common_state.is_quic() {
+            return Err(Error::General(
+                "key updates require TLS1.3 over TCP".to_string(),
+            ));
+        }
+
+        match self.state.as_mut() {
+            Ok(st) => st.send_key_update(&mut self.common_state, true),
+            Err(e) => Err(e.clone()),
+        }
+    }
+
+    /// Encrypts `plaintext` as one application data record, and writes
+    /// that record to the front of `out` rather than queueing it.
+    ///
+    /// On success, this returns the length of the record.  Nothing is
+    /// allocated to produce it when using rustls' own cipher suites, so
+    /// this suits callers which manage their own memory.  The record is
+    /// the same as the one [`CommonState::send_some_plaintext`] would
+    /// queue for [`CommonState::write_tls`], and the two may be mixed.
+    ///
+    /// If `out` is too short, [`Error::EncryptBufferTooSmall`] says how
+    /// long it needs to be, and nothing else changes: the call can be
+    /// repeated with a larger buffer.  This also fails:
+    ///
+    /// - if the handshake is not yet complete;
+    /// - once the connection has failed, or after a `close_notify` or
+    ///   fatal alert has been sent, like writes to [`ConnectionCommon::writer`];
+    /// - for QUIC, which encrypts its own packets;
+    /// - if `plaintext` is longer than the largest fragment the
+    ///   connection may send;
+    /// - if the connection is about to run out of record sequence
+    ///   numbers.  A `close_notify` alert is then queued, as it would
+    ///   be for plaintext sent any other way, whatever the length of `out`; or
+    /// - if TLS records are queued, which must be written first so the
+    ///   peer receives records in order.  This includes a `KeyUpdate`
+    ///   queued because of [`CommonState::set_key_update_threshold`].
+    pub fn encrypt_into(&mut self, plaintext: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+        if let Err(e) = &self.state {
+            return Err(e.clone());
+        }
+
+        if self.common_state.has_sent_close_notify || self.common_state.sent_fatal_alert {
+            return Err(Error::General(
+                "cannot write plaintext after closing the connection".to_string(),
+            ));
+        }
+
+        if self.common_state.may_send_application_data {
+            self.perhaps_update_keys();
+        }
+        self.common_state
+            .encrypt_into(plaintext, out)
+    }
+
+    /// Abandons the handshake because it has taken too long, for example
+    /// once [`CommonState::time_in_handshake`] passes a deadline while
+    /// the peer has sent nothing.
+    ///
+    /// A `user_canceled` alert then a `close_notify` alert are queued
+    /// for the peer, and from then on [`process_new_packets`] fails with
+    /// [`Error::HandshakeTimedOut`].
+    ///
+    /// This does nothing if the handshake is complete, or if the
+    /// connection has already failed.
+    ///
+    /// [`process_new_packets`]: ConnectionCommon::process_new_packets
+    pub fn time_out_handshake(&mut self) {
+        if !self.common_state.is_handshaking() || self.state.is_err() {
+            return;
+        }
+
+        self.common_state.send_user_canceled();
+        self.state = Err(Error::HandshakeTimedOut);
+    }
+}
+
+impl<Data: SideData> ConnectionCommon<Data> {
+    /// Returns an object that allows writing plaintext.
+    pub fn writer(&mut self) -> Writer {
+        Writer::new(self)
+    }
+
+    pub fn send_some_plaintext(&mut self, buf: &[u8]) -> usize {
+        self.perhaps_update_keys();
+
+        // Early data must not overtake plaintext already waiting.
+        let early = if self
+            .common_state
+            .sendable_plaintext
+            .is_empty()
+        {
+            self.data
+                .send_early_plaintext(&mut self.common_state, buf)
+        } else {
+            0
+        };
+        early
+            + self
+                .common_state
+                .send_some_plaintext(&buf[early..])
+    }
 }
 
 #[cfg(feature = "quic")]
@@ -765,8 +1241,11 @@ impl<Data> ConnectionCommon<Data> {
             return Err(Error::CorruptMessage);
         }
 
-        self.process_new_handshake_messages(state)
-            .map(|state| self.state = Ok(state))
+        let state = self.process_new_handshake_messages(state)?;
+        self.state = Ok(state);
+        self.common_state
+            .update_handshake_observer();
+        Ok(())
     }
 }
 
@@ -784,6 +1263,100 @@ impl<T> DerefMut for ConnectionCommon<T> {
     }
 }
 
+/// The kinds of channel binding which
+/// [`ConnectionCommon::channel_binding`] can compute.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelBindingType {
+    /// `tls-exporter` (RFC9266): 32 bytes of keying material exported
+    /// with the label `EXPORTER-Channel-Binding` and an empty context.
+    ///
+    /// This is defined for TLS1.3, and for TLS1.2 connections using the
+    /// extended master secret (RFC7627).
+    TlsExporter,
+
+    /// `tls-server-end-point` (RFC5929): a hash of the server's
+    /// end-entity certificate, by the hash function from the
+    /// certificate's signature algorithm, or SHA-256 if that is MD5 or
+    /// SHA-1.
+    ///
+    /// This is not defined for certificates signed without a single
+    /// such hash function, such as with Ed25519, nor for raw public keys.
+    /// rustls also doesn't support it for RSA-PSS signatures.  Servers
+    /// can't compute it for resumed handshakes, as they send no
+    /// certificate.
+    TlsServerEndPoint,
+}
+
+/// The hash function `tls-server-end-point` uses for a certificate
+/// signed with the algorithm `oid`.
+fn server_end_point_hash(oid: &[u8]) -> Option<&'static digest::Algorithm> {
+    const PKCS1: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01];
+    const ECDSA: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04];
+
+    if let Some(rest) = oid.strip_prefix(PKCS1) {
+        match rest {
+            // md5WithRSAEncryption, sha1WithRSAEncryption, sha256WithRSAEncryption
+            [0x04] | [0x05] | [0x0b] => Some(&digest::SHA256),
+            [0x0c] => Some(&digest::SHA384),
+            [0x0d] => Some(&digest::SHA512),
+            _ => None,
+        }
+    } else if let Some(rest) = oid.strip_prefix(ECDSA) {
+        match rest {
+            // ecdsa-with-SHA1, ecdsa-with-SHA256
+            [0x01] | [0x03, 0x02] => Some(&digest::SHA256),
+            [0x03, 0x03] => Some(&digest::SHA384),
+            [0x03, 0x04] => Some(&digest::SHA512),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+/// A function called with the level and description of each alert
+/// received from the peer, as in [`crate::ClientConfig::on_alert`].
+pub type AlertCallback = Arc<dyn Fn(AlertLevel, AlertDescription) + Send + Sync>;
+
+/// A function given each TLS record a connection produces, in place
+/// of [`CommonState::write_tls`]; see [`CommonState::set_record_callback`].
+pub type RecordCallback = Box<dyn FnMut(&OutgoingRecord<'_>) + Send + Sync>;
+
+/// A TLS record given to a [`RecordCallback`].
+#[derive(Debug)]
+pub struct OutgoingRecord<'a> {
+    /// The content type of the record's plaintext.
+    pub plaintext_type: ContentType,
+    /// The content type in the record's header.  This differs from
+    /// `plaintext_type` for TLS1.3 encrypted records, which all claim
+    /// to be application data.
+    pub wire_type: ContentType,
+    /// The protocol version in the record's header.
+    pub version: ProtocolVersion,
+    /// The record's contents, protected if it is encrypted.
+    pub payload: &'a [u8],
+}
+
+/// How a TLS1.2 connection responds to its peer asking to renegotiate,
+/// as chosen by [`crate::ClientConfig::renegotiation_response`].
+///
+/// rustls never renegotiates.  Clients are asked to with `HelloRequest`
+/// messages, and servers with a new `ClientHello`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenegotiationResponse {
+    /// Refuse with a `no_renegotiation` warning alert, and carry on
+    /// using the connection.
+    Decline,
+
+    /// Discard the request without replying.  A peer waiting for a
+    /// reply may then stall.
+    Ignore,
+
+    /// Refuse with a fatal `no_renegotiation` alert, which ends the
+    /// connection.
+    Abort,
+}
+
 /// Connection state common to both client and server connections.
 pub struct CommonState {
     pub negotiated_version: Option<ProtocolVersion>,
@@ -798,6 +1371,7 @@ pub struct CommonState {
     sent_fatal_alert: bool,
     /// If the peer has signaled end of stream.
     has_received_close_notify: bool,
+    has_sent_close_notify: bool,
     has_seen_eof: bool,
     received_middlebox_ccs: u8,
     pub peer_certificates: Option<Vec<key::Certificate>>,
@@ -805,11 +1379,30 @@ pub struct CommonState {
     received_plaintext: ChunkVecBuffer,
     sendable_plaintext: ChunkVecBuffer,
     pub sendable_tls: ChunkVecBuffer,
+    /// The plaintext content type of each record in `sendable_tls`,
+    /// oldest first, and perhaps of some already written.
+    queued_record_types: VecDeque<ContentType>,
     #[allow(dead_code)] // only read for QUIC
     /// Protocol whose key schedule should be used. Unused for TLS < 1.3.
     pub protocol: Protocol,
     #[cfg(feature = "quic")]
     pub quic: Quic,
+    observed_handshake: Option<ObservedHandshake>,
+    alert_callback: Option<AlertCallback>,
+    record_callback: Option<RecordCallback>,
+    renegotiation_response: RenegotiationResponse,
+    handshake_clock: Box<dyn Fn() -> Instant + Send + Sync>,
+    handshake_started: Instant,
+    handshake_finished: Option<Instant>,
+    kx_group: Option<NamedGroup>,
+    incoming_record_limit: Option<u16>,
+    hello_retry_requested: bool,
+    resumed: bool,
+    early_data_accepted: bool,
+    using_ems: bool,
+    sent_certificate: Option<key::Certificate>,
+    key_update_threshold: Option<u64>,
+    records_held_back: bool,
 }
 
 impl CommonState {
@@ -826,6 +1419,7 @@ impl CommonState {
             early_traffic: false,
             sent_fatal_alert: false,
             has_received_close_notify: false,
+            has_sent_close_notify: false,
             has_seen_eof: false,
             received_middlebox_ccs: 0,
             peer_certificates: None,
@@ -834,10 +1428,27 @@ impl CommonState {
             received_plaintext: ChunkVecBuffer::new(Some(0)),
             sendable_plaintext: ChunkVecBuffer::new(Some(DEFAULT_BUFFER_LIMIT)),
             sendable_tls: ChunkVecBuffer::new(Some(DEFAULT_BUFFER_LIMIT)),
+            queued_record_types: VecDeque::new(),
 
             protocol: Protocol::Tcp,
             #[cfg(feature = "quic")]
             quic: Quic::new(),
+            observed_handshake: None,
+            alert_callback: None,
+            record_callback: None,
+            renegotiation_response: RenegotiationResponse::Decline,
+            handshake_clock: Box::new(Instant::now),
+            handshake_started: Instant::now(),
+            handshake_finished: None,
+            kx_group: None,
+            incoming_record_limit: None,
+            hello_retry_requested: false,
+            resumed: false,
+            early_data_accepted: false,
+            using_ems: false,
+            sent_certificate: None,
+            key_update_threshold: None,
+            records_held_back: false,
         })
     }
 
@@ -847,6 +1458,25 @@ impl CommonState {
         !self.sendable_tls.is_empty()
     }
 
+    /// How many bytes of TLS data are queued to be written by
+    /// [`CommonState::write_tls`].  This is non-zero exactly when
+    /// [`CommonState::wants_write`] is true, and falls as `write_tls`
+    /// writes the data out.
+    ///
+    /// Plaintext written before the handshake completes isn't counted
+    /// until it has been encrypted.
+    pub fn tls_bytes_to_write(&self) -> usize {
+        self.sendable_tls.len()
+    }
+
+    /// How many bytes of decrypted plaintext are buffered, ready to be
+    /// read through [`ConnectionCommon::reader`] without calling
+    /// [`ConnectionCommon::read_tls`] again.  This falls as the
+    /// plaintext is read.
+    pub fn plaintext_bytes_to_read(&self) -> usize {
+        self.received_plaintext.len()
+    }
+
     /// Returns true if the connection is currently performing the TLS handshake.
     ///
     /// During this time plaintext written to the connection is buffered in memory. After
@@ -875,6 +1505,27 @@ impl CommonState {
         self.peer_certificates.as_deref()
     }
 
+    /// Returns true if the peer has sent a `close_notify` alert,
+    /// which was authenticated like any other record.
+    ///
+    /// This tells a clean close from the transport being cut off,
+    /// which may be a truncation attack: in the latter case this
+    /// stays false after [`Connection::read_tls`] reaches EOF.
+    pub fn received_close_notify(&self) -> bool {
+        self.has_received_close_notify
+    }
+
+    /// Returns true if the completed handshake resumed a previous
+    /// session, using a TLS1.2 session ID or ticket, or a TLS1.3 PSK
+    /// that the server accepted.
+    ///
+    /// This is false while the handshake is in progress, and after a
+    /// full handshake.  A client that offered a PSK which the server
+    /// declined has done a full handshake.
+    pub fn is_resumed(&self) -> bool {
+        !self.is_handshaking() && self.resumed
+    }
+
     /// Retrieves the protocol agreed with the peer via ALPN.
     ///
     /// A return value of `None` after handshake completion
@@ -891,6 +1542,15 @@ impl CommonState {
         self.suite
     }
 
+    /// Retrieves the group used for key exchange with the peer.
+    ///
+    /// This returns `None` until the key exchange is complete.  It
+    /// stays `None` for TLS1.2 resumptions, which have no key exchange.
+    /// After a HelloRetryRequest this is the group the server asked for.
+    pub fn negotiated_key_exchange_group(&self) -> Option<NamedGroup> {
+        self.kx_group
+    }
+
     /// Retrieves the protocol version agreed with the peer.
     ///
     /// This returns `None` until the version is agreed.
@@ -916,7 +1576,20 @@ impl CommonState {
                 Side::Server => HandshakeType::ClientHello,
             };
             if msg.is_handshake_type(reject_ty) {
-                self.send_warning_alert(AlertDescription::NoRenegotiation);
+                match self.renegotiation_response {
+                    RenegotiationResponse::Decline => {
+                        self.send_warning_alert(AlertDescription::NoRenegotiation);
+                    }
+                    RenegotiationResponse::Ignore => {
+                        debug!("Ignoring renegotiation request");
+                    }
+                    RenegotiationResponse::Abort => {
+                        self.send_fatal_alert(AlertDescription::NoRenegotiation);
+                        return Err(Error::PeerIncompatibleError(
+                            "peer tried to renegotiate".into(),
+                        ));
+                    }
+                }
                 return Ok(state);
             }
         }
@@ -945,6 +1618,12 @@ impl CommonState {
         self.send_plain(data, Limit::Yes)
     }
 
+    /// Puts plaintext sent as early data, which the server rejected,
+    /// back in front of any plaintext waiting for the handshake.
+    pub(crate) fn requeue_rejected_early_plaintext(&mut self, data: Vec<u8>) {
+        self.sendable_plaintext.prepend(data);
+    }
+
     pub fn send_early_plaintext(&mut self, data: &[u8]) -> usize {
         debug_assert!(self.early_traffic);
         debug_assert!(self.record_layer.is_encrypting());
@@ -977,6 +1656,21 @@ impl CommonState {
         Error::PeerMisbehavedError(why.to_string())
     }
 
+    /// Refuse a certificate chain of `len` certificates, end-entity
+    /// included, if that is more than `max`.  This happens before
+    /// the chain is verified.
+    pub(crate) fn check_cert_chain_len(&mut self, len: usize, max: usize) -> Result<(), Error> {
+        if len > max {
+            warn!("peer sent {} certificates, more than our limit of {}", len, max);
+            self.send_fatal_alert(AlertDescription::BadCertificate);
+            Err(Error::PeerMisbehavedError(
+                "peer certificate chain too long".to_string(),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn decrypt_incoming(
         &mut self,
         encr: OpaqueMessage,
@@ -1009,6 +1703,10 @@ impl CommonState {
                 Err(Error::DecryptError)
             }
             Err(e) => Err(e),
+            Ok(plain) if self.exceeds_incoming_record_limit(&plain) => {
+                self.send_fatal_alert(AlertDescription::RecordOverflow);
+                Err(Error::PeerSentOversizedRecord)
+            }
             Ok(plain) => Ok(Some(plain)),
         }
     }
@@ -1053,6 +1751,53 @@ impl CommonState {
         len
     }
 
+    fn encrypt_into(&mut self, plaintext: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+        if !self.may_send_application_data {
+            return Err(Error::HandshakeNotComplete);
+        }
+
+        if self.is_quic() {
+            return Err(Error::General(
+                "QUIC connections do not write TLS records".to_string(),
+            ));
+        }
+
+        if plaintext.len() > self.message_fragmenter.frag_len() {
+            return Err(Error::EncryptError);
+        }
+
+        // As in send_single_fragment, except that the plaintext is not
+        // then sent after the close_notify.
+        if self
+            .record_layer
+            .wants_close_before_encrypt()
+        {
+            self.send_close_notify();
+            return Err(Error::General(
+                "connection closed before its sequence numbers ran out".to_string(),
+            ));
+        }
+
+        if !self.sendable_tls.is_empty() {
+            return Err(Error::General(
+                "queued TLS records must be written first".to_string(),
+            ));
+        }
+
+        if self.record_layer.encrypt_exhausted() {
+            return Err(Error::EncryptError);
+        }
+
+        self.record_layer.encrypt_outgoing_into(
+            BorrowedPlainMessage {
+                typ: ContentType::ApplicationData,
+                version: ProtocolVersion::TLSv1_2,
+                payload: plaintext,
+            },
+            out,
+        )
+    }
+
     fn send_single_fragment(&mut self, m: BorrowedPlainMessage) {
         // Close connection once we start to run out of
         // sequence space.
@@ -1069,8 +1814,9 @@ impl CommonState {
             return;
         }
 
+        let typ = m.typ;
         let em = self.record_layer.encrypt_outgoing(m);
-        self.queue_tls_message(em);
+        self.queue_tls_message(typ, em);
     }
 
     /// Writes TLS messages to `wr`.
@@ -1084,6 +1830,75 @@ impl CommonState {
         self.sendable_tls.write_to(wr)
     }
 
+    /// Writes as many queued TLS messages to `wr` as it will accept.
+    ///
+    /// Like [`CommonState::write_tls`], records are given to
+    /// [`std::io::Write::write_vectored`] straight from the connection
+    /// buffer, without first being joined together.  But where that
+    /// makes one call, this keeps calling `wr` until the buffer is
+    /// empty, or `wr` accepts nothing more or fails.  Any bytes `wr` does
+    /// not accept stay queued for later, including the rest of a record
+    /// that was only partly written.
+    ///
+    /// On success, this returns `Ok(n)` where `n` is the total number
+    /// of bytes written to `wr`.  An error from `wr` is only returned
+    /// if nothing could be written; otherwise it is likely to recur on
+    /// the next call.
+    pub fn write_tls_vectored(&mut self, wr: &mut dyn io::Write) -> Result<usize, io::Error> {
+        self.sendable_tls.write_all_to(wr)
+    }
+
+    /// Gives each TLS record this connection produces to `callback`,
+    /// rather than queueing it for [`CommonState::write_tls`], for
+    /// transports which frame records themselves.
+    ///
+    /// `callback` gets the record's content type as it was before
+    /// encryption, what its header would say, and its contents.
+    /// Records which are already queued, like a client's `ClientHello`,
+    /// are given to `callback` straight away, with the same details.
+    ///
+    /// `callback` only sees records: it has no way to change them.
+    /// Each has already been accounted for in the connection's record
+    /// sequence numbers and handshake transcript, so a transport which
+    /// sends anything else can expect the peer to fail the connection.
+    ///
+    /// QUIC connections don't produce TLS records, so this makes no
+    /// difference to them.
+    ///
+    /// This fails if queued TLS data has already been partly written.
+    pub fn set_record_callback(&mut self, mut callback: RecordCallback) -> Result<(), Error> {
+        if self.sendable_tls.is_partly_read() {
+            return Err(Error::General("cannot take over partly written TLS data".into()));
+        }
+
+        self.forget_written_record_types();
+        while let Some(bytes) = self.sendable_tls.pop() {
+            let mut rd = codec::Reader::init(&bytes);
+            while rd.any_left() {
+                let m = OpaqueMessage::read(&mut rd);
+                // We only ever queue whole records.
+                debug_assert!(m.is_ok(), "queued TLS data is not a record");
+                let m = match m {
+                    Ok(m) => m,
+                    Err(_) => break,
+                };
+                let plaintext_type = self
+                    .queued_record_types
+                    .pop_front()
+                    .unwrap_or(m.typ);
+                callback(&OutgoingRecord {
+                    plaintext_type,
+                    wire_type: m.typ,
+                    version: m.version,
+                    payload: &m.payload.0,
+                });
+            }
+        }
+
+        self.record_callback = Some(callback);
+        Ok(())
+    }
+
     /// Encrypt and send some plaintext `data`.  `limit` controls
     /// whether the per-connection buffer limits apply.
     ///
@@ -1168,21 +1983,129 @@ impl CommonState {
         self.sendable_tls.set_limit(limit);
     }
 
+    /// Sets the most plaintext carried in each outgoing TLS record.
+    ///
+    /// Larger records have less overhead, so suit bulk transfers;
+    /// smaller ones let the peer start decrypting sooner.  Plaintext
+    /// written in several pieces is joined into records of up to this
+    /// size where possible, such as when buffered during the handshake
+    /// or written with `write_vectored`.
+    ///
+    /// `size` is clamped to the protocol maximum of 16KB, and records
+    /// are never larger than agreed with the peer using the
+    /// `max_fragment_length` or `record_size_limit` extensions.  This
+    /// replaces any `max_fragment_size` from the config, and applies to
+    /// records sent from now on.
+    pub fn set_max_plaintext_fragment_size(&mut self, size: usize) {
+        self.message_fragmenter
+            .set_max_fragment_len(size);
+    }
+
+    /// Sets how many records may be sent with one set of TLS1.3 traffic
+    /// keys before rustls replaces them, using a `KeyUpdate` message.
+    ///
+    /// Each cipher suite can only safely encrypt so many records with one
+    /// key (RFC 8446 section 5.5).  By default keys are replaced once half
+    /// of that is used; `records` above the limit itself is ignored.
+    /// This is checked before each write, so one large write may
+    /// somewhat overshoot it.
+    ///
+    /// This has no effect on TLS1.2 or QUIC connections.
+    pub fn set_key_update_threshold(&mut self, records: u64) {
+        self.key_update_threshold = Some(records);
+    }
+
+    /// How many records, and how many bytes of their plaintext, have
+    /// been sent with the current traffic keys.
+    ///
+    /// These count from zero each time the keys change, including at
+    /// every `KeyUpdate`, so can be compared with the threshold given
+    /// to [`CommonState::set_key_update_threshold`].
+    pub fn sent_with_current_keys(&self) -> (u64, u64) {
+        self.record_layer.write_counts()
+    }
+
+    /// How many records, and how many bytes of their plaintext, have
+    /// been received with the current traffic keys.
+    ///
+    /// Like [`CommonState::sent_with_current_keys`], these count from
+    /// zero each time the peer's keys change.
+    pub fn received_with_current_keys(&self) -> (u64, u64) {
+        self.record_layer.read_counts()
+    }
+
+    /// Whether we have sent enough records with our current keys that
+    /// we should replace them.
+    fn wants_key_update(&self) -> bool {
+        if self.is_handshaking() || self.is_quic() {
+            return false;
+        }
+
+        let limit = match self.suite.and_then(|suite| suite.tls13()) {
+            Some(suite) => suite.confidentiality_limit,
+            None => return false,
+        };
+        let threshold = match self.key_update_threshold {
+            Some(records) => cmp::min(records, limit),
+            None => limit / 2,
+        };
+
+        let (records, _) = self.record_layer.write_counts();
+        records >= threshold
+    }
+
     /// Send any buffered plaintext.  Plaintext is buffered if
     /// written during handshake.
+    ///
+    /// All of it is sent together, so small writes share records.
     fn flush_plaintext(&mut self) {
         if !self.may_send_application_data {
             return;
         }
 
+        let mut joined = match self.sendable_plaintext.pop() {
+            Some(buf) => buf,
+            None => return,
+        };
         while let Some(buf) = self.sendable_plaintext.pop() {
-            self.send_plain(&buf, Limit::No);
+            joined.extend_from_slice(&buf);
         }
+        self.send_plain(&joined, Limit::No);
     }
 
-    // Put m into sendable_tls for writing.
-    fn queue_tls_message(&mut self, m: OpaqueMessage) {
-        self.sendable_tls.append(m.encode());
+    // Put m into sendable_tls for writing, or give it to the record
+    // callback.  `typ` is m's content type before any encryption.
+    fn queue_tls_message(&mut self, typ: ContentType, m: OpaqueMessage) {
+        if let Some(callback) = &mut self.record_callback {
+            if let Some(observed) = &mut self.observed_handshake {
+                observed.queued(usize::from(OpaqueMessage::HEADER_SIZE) + m.payload.0.len());
+            }
+            callback(&OutgoingRecord {
+                plaintext_type: typ,
+                wire_type: m.typ,
+                version: m.version,
+                payload: &m.payload.0,
+            });
+            return;
+        }
+
+        let bytes = m.encode();
+        if let Some(observed) = &mut self.observed_handshake {
+            observed.queued(bytes.len());
+        }
+        self.forget_written_record_types();
+        self.queued_record_types.push_back(typ);
+        self.sendable_tls.append(bytes);
+    }
+
+    // Drop the types of records which have left `sendable_tls`.  Each
+    // record is queued as a chunk of its own, so those left are the
+    // last ones.
+    fn forget_written_record_types(&mut self) {
+        let queued = self.sendable_tls.chunk_count();
+        while self.queued_record_types.len() > queued {
+            self.queued_record_types.pop_front();
+        }
     }
 
     /// Send a raw TLS message, fragmenting it if needed.
@@ -1199,6 +2122,9 @@ impl CommonState {
                     );
                     let mut bytes = Vec::new();
                     m.payload.encode(&mut bytes);
+                    if let Some(observed) = &mut self.observed_handshake {
+                        observed.queued(bytes.len());
+                    }
                     self.quic
                         .hs_queue
                         .push_back((must_encrypt, bytes));
@@ -1211,13 +2137,159 @@ impl CommonState {
             self.message_fragmenter
                 .fragment(m.into(), &mut to_send);
             for mm in to_send {
-                self.queue_tls_message(mm.into_unencrypted_opaque());
+                self.queue_tls_message(mm.typ, mm.into_unencrypted_opaque());
             }
         } else {
             self.send_msg_encrypt(m.into());
         }
     }
 
+    /// Start reporting the progress of the handshake to `observer`, if any.
+    pub(crate) fn observe_handshake(&mut self, observer: Option<&Arc<dyn HandshakeObserver>>) {
+        self.observed_handshake = observer
+            .cloned()
+            .map(ObservedHandshake::start);
+    }
+
+    /// Start reporting received alerts to `callback`, if any.
+    pub(crate) fn set_alert_callback(&mut self, callback: Option<&AlertCallback>) {
+        self.alert_callback = callback.cloned();
+    }
+
+    pub(crate) fn set_renegotiation_response(&mut self, response: RenegotiationResponse) {
+        self.renegotiation_response = response;
+    }
+
+    /// Record when the handshake completed, and tell the handshake
+    /// observer, if any, about messages queued since the last call and
+    /// whether the handshake has now completed.
+    pub(crate) fn update_handshake_observer(&mut self) {
+        let handshaking = self.is_handshaking();
+        if !handshaking && self.handshake_finished.is_none() {
+            self.handshake_finished = Some((self.handshake_clock)());
+        }
+
+        let observed = match &mut self.observed_handshake {
+            Some(observed) => observed,
+            None => return,
+        };
+
+        observed.end_flight(handshaking);
+        if handshaking {
+            return;
+        }
+
+        if let (Some(observed), Some(version), Some(suite)) = (
+            self.observed_handshake.take(),
+            self.negotiated_version,
+            self.suite,
+        ) {
+            observed.complete(version, suite, self.resumed, self.early_data_accepted);
+        }
+    }
+
+    /// Measure [`CommonState::time_in_handshake`] using `clock` instead
+    /// of [`Instant::now`], starting from now.
+    ///
+    /// This is mostly useful for testing timeouts without waiting for
+    /// them.  Call it before the handshake starts.
+    pub fn set_handshake_clock(&mut self, clock: Box<dyn Fn() -> Instant + Send + Sync>) {
+        self.handshake_started = clock();
+        self.handshake_clock = clock;
+    }
+
+    /// How long the handshake has been going on: from when the
+    /// connection was made until now or, once the handshake has
+    /// completed, until then.
+    ///
+    /// Together with [`CommonState::io_state_hint`], which is
+    /// [`IoStateHint::WantRead`] while we wait for the peer, this lets
+    /// a caller decide when to give up on a stalled handshake using
+    /// [`ConnectionCommon::time_out_handshake`].
+    pub fn time_in_handshake(&self) -> Duration {
+        self.handshake_finished
+            .unwrap_or_else(|| (self.handshake_clock)())
+            .saturating_duration_since(self.handshake_started)
+    }
+
+    /// The kind of handshake that was completed, or `None` while the
+    /// handshake is still going on.
+    pub fn handshake_kind(&self) -> Option<HandshakeKind> {
+        if self.is_handshaking() {
+            None
+        } else if self.resumed {
+            Some(HandshakeKind::Resumed)
+        } else if self.hello_retry_requested {
+            Some(HandshakeKind::FullWithHelloRetryRequest)
+        } else {
+            Some(HandshakeKind::Full)
+        }
+    }
+
+    /// Record that the server sent a `HelloRetryRequest`.
+    pub(crate) fn observe_hello_retry_request(&mut self) {
+        self.hello_retry_requested = true;
+    }
+
+    /// Count a middlebox compatibility CCS received before any
+    /// version is negotiated, returning false if there was one already.
+    pub(crate) fn drop_early_middlebox_ccs(&mut self) -> bool {
+        if self.received_middlebox_ccs > 0 {
+            return false;
+        }
+        self.received_middlebox_ccs += 1;
+        true
+    }
+
+    /// Record that a previous session is being resumed.
+    pub(crate) fn observe_resumption(&mut self) {
+        self.resumed = true;
+    }
+
+    /// Record that the server accepted early data.
+    pub(crate) fn observe_early_data(&mut self) {
+        self.early_data_accepted = true;
+    }
+
+    /// Record that TLS1.2's extended master secret is in use.
+    pub(crate) fn observe_ems(&mut self) {
+        self.using_ems = true;
+    }
+
+    /// Record the end-entity certificate we sent to the peer.
+    pub(crate) fn observe_sent_certificate(&mut self, cert: Option<&key::Certificate>) {
+        self.sent_certificate = cert.cloned();
+    }
+
+    /// Record which group was used for key exchange.
+    pub(crate) fn observe_key_exchange(&mut self, group: NamedGroup) {
+        self.kx_group = Some(group);
+    }
+
+    /// A snapshot of what was agreed, once the handshake is complete.
+    pub(crate) fn handshake_details(
+        &self,
+        sni_hostname: Option<&str>,
+    ) -> Option<HandshakeDetails> {
+        if self.is_handshaking() {
+            return None;
+        }
+
+        Some(HandshakeDetails {
+            protocol_version: self.negotiated_version?,
+            cipher_suite: self.suite?,
+            key_exchange_group: self.kx_group,
+            alpn_protocol: self.alpn_protocol.clone(),
+            sni_hostname: sni_hostname.map(str::to_owned),
+            resumed: self.resumed,
+            early_data: self.early_data_accepted,
+            peer_certificate_count: self
+                .peer_certificates
+                .as_ref()
+                .map_or(0, Vec::len),
+        })
+    }
+
     pub fn take_received_plaintext(&mut self, bytes: Payload) {
         self.received_plaintext.append(bytes.0);
     }
@@ -1243,9 +2315,15 @@ impl CommonState {
     }
 
     fn process_alert(&mut self, alert: &AlertMessagePayload) -> Result<(), Error> {
-        // Reject unknown AlertLevels.
+        if let Some(callback) = &self.alert_callback {
+            callback(alert.level, alert.description);
+        }
+
+        // Reject unknown AlertLevels.  This includes a close_notify
+        // with an unknown level, which does not close the connection.
         if let AlertLevel::Unknown(_) = alert.level {
             self.send_fatal_alert(AlertDescription::IllegalParameter);
+            return Err(Error::corrupt_message(ContentType::Alert));
         }
 
         // If we get a CloseNotify, make a note to declare EOF to our
@@ -1284,6 +2362,24 @@ impl CommonState {
     pub fn send_close_notify(&mut self) {
         debug!("Sending warning alert {:?}", AlertDescription::CloseNotify);
         self.send_warning_alert_no_log(AlertDescription::CloseNotify);
+        self.has_sent_close_notify = true;
+    }
+
+    /// Queues a user_canceled warning alert then a close_notify alert,
+    /// as RFC8446 suggests for closing the connection without an error.
+    /// This lets the peer know that the application chose to abandon
+    /// the connection.
+    ///
+    /// This does nothing if a close_notify or fatal alert has already
+    /// been queued.
+    pub fn send_user_canceled(&mut self) {
+        if self.has_sent_close_notify || self.sent_fatal_alert {
+            return;
+        }
+
+        debug!("Sending warning alert {:?}", AlertDescription::UserCanceled);
+        self.send_warning_alert_no_log(AlertDescription::UserCanceled);
+        self.send_close_notify();
     }
 
     fn send_warning_alert_no_log(&mut self, desc: AlertDescription) {
@@ -1296,6 +2392,47 @@ impl CommonState {
             .set_max_fragment_size(new)
     }
 
+    /// Apply the limit negotiated with the `max_fragment_length` extension
+    /// to all records sent from now on.
+    pub(crate) fn limit_fragment_len(&mut self, max_len: usize) {
+        self.message_fragmenter
+            .limit_fragment_len(max_len);
+    }
+
+    /// Apply the limit the peer asked for with the `record_size_limit`
+    /// extension to all records sent from now on.
+    pub(crate) fn limit_outgoing_record_size(&mut self, limit: u16) {
+        self.limit_fragment_len(usize::from(limit) - self.record_size_overhead());
+    }
+
+    /// Refuse protected records bigger than `limit`, which we asked the
+    /// peer to respect with the `record_size_limit` extension.  `None`
+    /// stops doing so.
+    pub(crate) fn set_incoming_record_limit(&mut self, limit: Option<u16>) {
+        self.incoming_record_limit = limit;
+    }
+
+    pub(crate) fn take_incoming_record_limit(&mut self) -> Option<u16> {
+        self.incoming_record_limit.take()
+    }
+
+    /// RFC 8449 counts the inner content type of TLS1.3 records as part of
+    /// the record size.  Any padding has already been removed from `plain`,
+    /// so it is not counted.
+    fn exceeds_incoming_record_limit(&self, plain: &PlainMessage) -> bool {
+        match self.incoming_record_limit {
+            Some(limit) => plain.payload.0.len() + self.record_size_overhead() > usize::from(limit),
+            None => false,
+        }
+    }
+
+    fn record_size_overhead(&self) -> usize {
+        match self.is_tls13() {
+            true => 1,
+            false => 0,
+        }
+    }
+
     pub fn get_alpn_protocol(&self) -> Option<&[u8]> {
         self.alpn_protocol
             .as_ref()
@@ -1306,24 +2443,42 @@ impl CommonState {
     /// as possible.
     ///
     /// If there is pending plaintext data to read with [`Connection::reader`],
+    /// or records held back by [`Connection::process_new_packets_limited`],
     /// this returns false.  If your application respects this mechanism,
     /// only one full TLS message will be buffered by rustls.
     pub fn wants_read(&self) -> bool {
         // We want to read more data all the time, except when we have unprocessed plaintext.
         // This provides back-pressure to the TCP buffers. We also don't want to read more after
-        // the peer has sent us a close notification.
+        // the peer has sent us a close notification, or while records already read are
+        // held back.
         //
         // In the handshake case we don't have readable plaintext before the handshake has
         // completed, but also don't want to read if we still have sendable tls.
         self.received_plaintext.is_empty()
             && !self.has_received_close_notify
+            && !self.records_held_back
             && (self.may_send_application_data || self.sendable_tls.is_empty())
     }
 
+    /// Summarises [`CommonState::wants_write`], [`CommonState::wants_read`]
+    /// and whether the connection has closed into the single next action
+    /// the caller should take, without doing any IO.
+    pub fn io_state_hint(&self) -> IoStateHint {
+        if self.wants_write() {
+            IoStateHint::WantWrite
+        } else if self.has_received_close_notify || self.has_seen_eof || self.sent_fatal_alert {
+            IoStateHint::Closed
+        } else if self.wants_read() {
+            IoStateHint::WantRead
+        } else {
+            IoStateHint::Idle
+        }
+    }
+
     fn current_io_state(&self) -> IoState {
         IoState {
-            tls_bytes_to_write: self.sendable_tls.len(),
-            plaintext_bytes_to_read: self.received_plaintext.len(),
+            tls_bytes_to_write: self.tls_bytes_to_write(),
+            plaintext_bytes_to_read: self.plaintext_bytes_to_read(),
             peer_has_closed: self.has_received_close_notify,
         }
     }
@@ -1355,6 +2510,18 @@ pub trait State<Data>: Send + Sync {
     }
 
     fn perhaps_write_key_update(&mut self, _cx: &mut CommonState) {}
+
+    fn send_key_update(
+        &mut self,
+        _cx: &mut CommonState,
+        _request_peer_update: bool,
+    ) -> Result<(), Error> {
+        Err(Error::HandshakeNotComplete)
+    }
+
+    fn request_post_handshake_auth(&mut self, _cx: &mut CommonState) -> Result<(), Error> {
+        Err(Error::HandshakeNotComplete)
+    }
 }
 
 pub struct Context<'a, Data> {
@@ -1366,6 +2533,9 @@ pub struct Context<'a, Data> {
 pub struct Quic {
     /// QUIC transport parameters received from the peer during the handshake
     pub params: Option<Vec<u8>>,
+    /// The extension `params` arrived in, unless they were remembered
+    /// from a ticket rather than received in this handshake
+    pub params_extension: Option<ExtensionType>,
     pub alert: Option<AlertDescription>,
     pub hs_queue: VecDeque<(bool, Vec<u8>)>,
     pub early_secret: Option<ring::hkdf::Prk>,
@@ -1380,6 +2550,7 @@ impl Quic {
     fn new() -> Self {
         Self {
             params: None,
+            params_extension: None,
             alert: None,
             hs_queue: VecDeque::new(),
             early_secret: None,
@@ -1388,6 +2559,12 @@ impl Quic {
             returned_traffic_keys: false,
         }
     }
+
+    /// Keep the transport parameters the peer sent in extension `typ`.
+    pub(crate) fn received_params(&mut self, typ: ExtensionType, params: Vec<u8>) {
+        self.params = Some(params);
+        self.params_extension = Some(typ);
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -1397,6 +2574,211 @@ pub enum Side {
 }
 
 /// Data specific to the peer's side (client or server).
-pub trait SideData {}
+pub trait SideData {
+    /// Send as much of `data` as this side may before the handshake
+    /// completes, returning how much that was.
+    ///
+    /// This is not an external interface.
+    #[doc(hidden)]
+    fn send_early_plaintext(&mut self, _common: &mut CommonState, _data: &[u8]) -> usize {
+        0
+    }
+}
 
 const DEFAULT_BUFFER_LIMIT: usize = 64 * 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cipher::MessageEncrypter;
+    use crate::msgs::fragmenter::MAX_FRAGMENT_LEN;
+    use crate::tls13::TLS13_AES_128_GCM_SHA256_INTERNAL;
+    use ring::hkdf;
+
+    /// A TLS1.3 client which decrypts what the returned encrypter makes.
+    fn tls13_client() -> (CommonState, Box<dyn MessageEncrypter>) {
+        let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &[0u8; 32]);
+        let suite = TLS13_AES_128_GCM_SHA256_INTERNAL;
+
+        let mut common = CommonState::new(None, Side::Client).unwrap();
+        common.negotiated_version = Some(ProtocolVersion::TLSv1_3);
+        common
+            .record_layer
+            .set_message_decrypter(suite.derive_decrypter(&secret));
+        (common, suite.derive_encrypter(&secret))
+    }
+
+    fn app_data_record(encrypter: &dyn MessageEncrypter, seq: u64, len: usize) -> OpaqueMessage {
+        let msg = BorrowedPlainMessage {
+            typ: ContentType::ApplicationData,
+            version: ProtocolVersion::TLSv1_2,
+            payload: &vec![0u8; len],
+        };
+        encrypter.encrypt(msg, seq).unwrap()
+    }
+
+    #[test]
+    fn incoming_records_are_checked_against_record_size_limit() {
+        let (mut common, encrypter) = tls13_client();
+        common.set_incoming_record_limit(Some(100));
+
+        // The limit counts the inner content type.
+        let record = app_data_record(&*encrypter, 0, 99);
+        assert!(matches!(common.decrypt_incoming(record), Ok(Some(_))));
+        assert!(!common.wants_write());
+
+        let record = app_data_record(&*encrypter, 1, 100);
+        assert_eq!(
+            common.decrypt_incoming(record).err(),
+            Some(Error::PeerSentOversizedRecord)
+        );
+        assert!(common.sent_fatal_alert);
+    }
+
+    #[test]
+    fn incoming_record_size_limit_can_be_lifted() {
+        let (mut common, encrypter) = tls13_client();
+        common.set_incoming_record_limit(Some(100));
+        assert_eq!(common.take_incoming_record_limit(), Some(100));
+
+        let record = app_data_record(&*encrypter, 0, 1000);
+        assert!(matches!(common.decrypt_incoming(record), Ok(Some(_))));
+    }
+
+    /// A TLS1.3 connection which has finished its handshake, and sends
+    /// with keys from an all-zero secret.
+    fn tls13_sender() -> CommonState {
+        let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &[0u8; 32]);
+        let suite = TLS13_AES_128_GCM_SHA256_INTERNAL;
+
+        let mut common = CommonState::new(None, Side::Client).unwrap();
+        common.negotiated_version = Some(ProtocolVersion::TLSv1_3);
+        common
+            .record_layer
+            .set_message_encrypter(suite.derive_encrypter(&secret));
+        common.start_outgoing_traffic();
+        common
+    }
+
+    fn queued_tls(common: &mut CommonState) -> Vec<u8> {
+        let mut tls = Vec::new();
+        while common.wants_write() {
+            common.write_tls(&mut tls).unwrap();
+        }
+        tls
+    }
+
+    /// Checks `encrypter` writes the record `encrypt` makes.
+    fn assert_encrypt_into_matches(encrypter: &dyn MessageEncrypter) {
+        for (seq, len) in [(0, 0), (1, 5), (2, 1000)]
+            .iter()
+            .copied()
+        {
+            let payload = vec![0x5a; len];
+            let msg = || BorrowedPlainMessage {
+                typ: ContentType::ApplicationData,
+                version: ProtocolVersion::TLSv1_2,
+                payload: &payload,
+            };
+            let expected = encrypter
+                .encrypt(msg(), seq)
+                .unwrap()
+                .encode();
+
+            let mut out = vec![0u8; expected.len() + 10];
+            assert_eq!(
+                encrypter.encrypt_into(msg(), seq, &mut out),
+                Ok(expected.len())
+            );
+            assert_eq!(&out[..expected.len()], &expected[..]);
+
+            assert_eq!(
+                encrypter.encrypt_into(msg(), seq, &mut out[..expected.len() - 1]),
+                Err(Error::EncryptBufferTooSmall {
+                    required: expected.len()
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn encrypt_into_matches_encrypt() {
+        let secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &[0u8; 32]);
+        assert_encrypt_into_matches(&*TLS13_AES_128_GCM_SHA256_INTERNAL.derive_encrypter(&secret));
+    }
+
+    #[cfg(feature = "tls12")]
+    #[test]
+    fn tls12_encrypt_into_matches_encrypt() {
+        use crate::tls12::{AesGcm, ChaCha20Poly1305, Tls12AeadAlgorithm};
+        use ring::aead;
+
+        let key = |alg: &'static aead::Algorithm| {
+            aead::LessSafeKey::new(aead::UnboundKey::new(alg, &[1u8; 32][..alg.key_len()]).unwrap())
+        };
+        assert_encrypt_into_matches(&*AesGcm.encrypter(
+            key(&aead::AES_128_GCM),
+            &[2u8; 4],
+            &[3u8; 8],
+        ));
+        assert_encrypt_into_matches(&*ChaCha20Poly1305.encrypter(
+            key(&aead::CHACHA20_POLY1305),
+            &[2u8; 12],
+            &[],
+        ));
+    }
+
+    #[test]
+    fn encrypt_into_matches_queued_record() {
+        let mut queued = tls13_sender();
+        let mut direct = tls13_sender();
+
+        for data in [&b"hello"[..], &[0u8; 2000][..]].iter() {
+            assert_eq!(queued.send_some_plaintext(data), data.len());
+            let expected = queued_tls(&mut queued);
+
+            let mut out = [0u8; 4096];
+            let len = direct
+                .encrypt_into(data, &mut out)
+                .unwrap();
+            assert_eq!(&out[..len], &expected[..]);
+        }
+        assert_eq!(
+            direct.record_layer.write_counts(),
+            queued.record_layer.write_counts()
+        );
+    }
+
+    #[test]
+    fn encrypt_into_short_buffer_changes_nothing() {
+        let mut queued = tls13_sender();
+        queued.send_some_plaintext(b"hello");
+        let expected = queued_tls(&mut queued);
+
+        let mut direct = tls13_sender();
+        let mut out = vec![0u8; expected.len()];
+        assert_eq!(
+            direct.encrypt_into(b"hello", &mut out[..expected.len() - 1]),
+            Err(Error::EncryptBufferTooSmall {
+                required: expected.len()
+            })
+        );
+        assert_eq!(direct.record_layer.write_counts(), (0, 0));
+
+        assert_eq!(direct.encrypt_into(b"hello", &mut out), Ok(expected.len()));
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn encrypt_into_refuses_oversized_plaintext() {
+        let mut common = tls13_sender();
+        let mut out = vec![0u8; 20000];
+        assert_eq!(
+            common.encrypt_into(&[0u8; MAX_FRAGMENT_LEN + 1], &mut out),
+            Err(Error::EncryptError)
+        );
+        assert!(common
+            .encrypt_into(&[0u8; MAX_FRAGMENT_LEN], &mut out)
+            .is_ok());
+    }
+}
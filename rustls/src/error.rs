@@ -1,9 +1,11 @@
 use crate::msgs::enums::{AlertDescription, ContentType, HandshakeType};
 use crate::rand;
+use crate::x509;
 
 use std::error::Error as StdError;
 use std::fmt;
-use std::time::SystemTimeError;
+use std::sync::Arc;
+use std::time::{SystemTime, SystemTimeError, UNIX_EPOCH};
 
 /// Context around a corrupt TLS message payload that resulted in
 /// an error.
@@ -64,6 +66,13 @@ pub enum Error {
     /// This should never happen if the application is using valid record sizes.
     EncryptError,
 
+    /// A record couldn't be encrypted into the buffer given, which
+    /// must be at least `required` bytes long.
+    EncryptBufferTooSmall {
+        /// How long the encrypted record is.
+        required: usize,
+    },
+
     /// The peer doesn't support a protocol version/feature we require.
     /// The parameter gives a hint as to what version/feature it is.
     PeerIncompatibleError(String),
@@ -87,12 +96,40 @@ pub enum Error {
     /// We received a certificate which includes invalid data.
     InvalidCertificateData(String),
 
+    /// We received a certificate which failed verification, in one of
+    /// the ways described by [`CertificateError`].
+    InvalidCertificate(CertificateError),
+
     /// The presented SCT(s) were invalid.
     InvalidSct(sct::Error),
 
+    /// The peer did not present enough valid SCTs to satisfy the
+    /// configured Certificate Transparency policy.
+    TooFewValidScts {
+        /// How many SCTs the policy requires
+        required: usize,
+        /// How many valid SCTs were presented
+        valid: usize,
+    },
+
+    /// The peer's certificate chain may be valid, but none of its
+    /// certificates has a pinned public key.
+    NoPinMatched,
+
+    /// The peer's certificate has been revoked, according to the
+    /// OCSP response it stapled.
+    CertificateRevoked,
+
     /// A catch-all error for unlikely errors.
     General(String),
 
+    /// An error from outside rustls, such as one raised by a custom
+    /// certificate verifier.  It is the [`source`] of this error, so
+    /// isn't repeated when this is displayed.
+    ///
+    /// [`source`]: StdError::source
+    Other(OtherError),
+
     /// We failed to figure out what time it currently is.
     FailedToGetCurrentTime,
 
@@ -103,6 +140,12 @@ pub enum Error {
     /// is complete.
     HandshakeNotComplete,
 
+    /// The handshake was abandoned because it took too long, using
+    /// [`ConnectionCommon::time_out_handshake`].
+    ///
+    /// [`ConnectionCommon::time_out_handshake`]: crate::ConnectionCommon::time_out_handshake
+    HandshakeTimedOut,
+
     /// The peer sent an oversized record/fragment.
     PeerSentOversizedRecord,
 
@@ -171,25 +214,179 @@ impl fmt::Display for Error {
             Self::InvalidCertificateData(ref reason) => {
                 write!(f, "invalid peer certificate contents: {}", reason)
             }
+            Self::InvalidCertificate(ref err) => write!(f, "invalid peer certificate: {}", err),
             Self::CorruptMessage => write!(f, "received corrupt message"),
             Self::NoCertificatesPresented => write!(f, "peer sent no certificates"),
             Self::UnsupportedNameType => write!(f, "presented server name type wasn't supported"),
             Self::DecryptError => write!(f, "cannot decrypt peer's message"),
             Self::EncryptError => write!(f, "cannot encrypt message"),
+            Self::EncryptBufferTooSmall { required } => {
+                write!(f, "cannot encrypt into buffer: {} bytes needed", required)
+            }
             Self::PeerSentOversizedRecord => write!(f, "peer sent excess record size"),
             Self::HandshakeNotComplete => write!(f, "handshake not complete"),
+            Self::HandshakeTimedOut => write!(f, "handshake timed out"),
             Self::NoApplicationProtocol => write!(f, "peer doesn't support any known protocol"),
             Self::InvalidSct(ref err) => write!(f, "invalid certificate timestamp: {:?}", err),
+            Self::TooFewValidScts { required, valid } => write!(
+                f,
+                "too few valid certificate timestamps: required {}, got {}",
+                required, valid
+            ),
+            Self::NoPinMatched => write!(f, "peer certificate chain has no pinned public key"),
+            Self::CertificateRevoked => write!(f, "peer certificate has been revoked"),
             Self::FailedToGetCurrentTime => write!(f, "failed to get current time"),
             Self::FailedToGetRandomBytes => write!(f, "failed to get random bytes"),
             Self::BadMaxFragmentSize => {
                 write!(f, "the supplied max_fragment_size was too small or large")
             }
             Self::General(ref err) => write!(f, "unexpected error: {}", err),
+            Self::Other(ref err) => write!(f, "other error: {}", err.0),
+        }
+    }
+}
+
+/// Why a peer's certificate chain failed verification.
+#[derive(Debug, PartialEq, Clone)]
+pub enum CertificateError {
+    /// The end-entity certificate is not valid for the name the peer
+    /// was expected to have.
+    NotValidForName {
+        /// The DNS name or IP address the certificate was checked against.
+        name: String,
+    },
+
+    /// A certificate in the chain had expired.
+    Expired {
+        /// The time the chain was verified at.
+        now: SystemTime,
+        /// When the first presented certificate found to have expired
+        /// by `now` stopped being valid, if any could be found.
+        not_after: Option<SystemTime>,
+    },
+
+    /// A certificate in the chain was not valid yet.
+    NotValidYet {
+        /// The time the chain was verified at.
+        now: SystemTime,
+        /// When the first presented certificate found not to be valid
+        /// at `now` becomes valid, if any could be found.
+        not_before: Option<SystemTime>,
+    },
+
+    /// No chain to a trust anchor could be built from the presented
+    /// certificates.
+    UnknownIssuer,
+
+    /// A certificate's signature, made by its issuer, is wrong.
+    BadSignature,
+
+    /// A certificate's public key is of a type which can't verify the
+    /// signatures it is supposed to have made.
+    UnsupportedKeyType,
+
+    /// The chain is valid, but does not pass through any of the
+    /// intermediates the verifier was configured to require.
+    NoRequiredIntermediate,
+
+    /// The end-entity certificate's extendedKeyUsage extension does not
+    /// include a key purpose the verifier was configured to require.
+    MissingKeyPurpose {
+        /// The missing key purpose OID, DER-encoded without its tag and
+        /// length.
+        purpose: Vec<u8>,
+    },
+
+    /// The end-entity certificate came with an RFC9345 delegated
+    /// credential which had expired.
+    DelegatedCredentialExpired {
+        /// The time the credential was checked at.
+        now: SystemTime,
+        /// When the credential stopped being valid.
+        expiry: SystemTime,
+    },
+
+    /// The end-entity certificate came with an RFC9345 delegated
+    /// credential which it did not sign, which claims to be valid for
+    /// too long, or which it may not issue.
+    BadDelegatedCredential,
+}
+
+impl StdError for CertificateError {}
+
+impl fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::NotValidForName { ref name } => {
+                write!(f, "certificate not valid for name {:?}", name)
+            }
+            Self::Expired { now, not_after } => {
+                write!(
+                    f,
+                    "certificate expired: checked at UNIX time {}",
+                    unix_time(now)
+                )?;
+                if let Some(not_after) = not_after {
+                    write!(f, ", not valid after {}", unix_time(not_after))?;
+                }
+                Ok(())
+            }
+            Self::NotValidYet { now, not_before } => {
+                write!(
+                    f,
+                    "certificate not valid yet: checked at UNIX time {}",
+                    unix_time(now)
+                )?;
+                if let Some(not_before) = not_before {
+                    write!(f, ", not valid before {}", unix_time(not_before))?;
+                }
+                Ok(())
+            }
+            Self::UnknownIssuer => write!(f, "certificate issuer is not trusted"),
+            Self::BadSignature => write!(f, "certificate has a bad signature"),
+            Self::UnsupportedKeyType => write!(f, "certificate key type is not supported"),
+            Self::NoRequiredIntermediate => {
+                write!(f, "certificate is not issued under a required intermediate")
+            }
+            Self::MissingKeyPurpose { ref purpose } => {
+                write!(f, "certificate lacks required key purpose ")?;
+                write_oid(f, purpose)
+            }
+            Self::DelegatedCredentialExpired { now, expiry } => write!(
+                f,
+                "delegated credential expired: checked at UNIX time {}, not valid after {}",
+                unix_time(now),
+                unix_time(expiry)
+            ),
+            Self::BadDelegatedCredential => write!(f, "delegated credential is invalid"),
         }
     }
 }
 
+/// Write the DER-encoded OID contents `oid` in dotted decimal form, or
+/// in hex if it doesn't decode.
+fn write_oid(f: &mut fmt::Formatter, oid: &[u8]) -> fmt::Result {
+    match x509::oid_arcs(oid) {
+        Some(arcs) => {
+            write!(f, "{}", arcs[0])?;
+            arcs[1..]
+                .iter()
+                .try_for_each(|arc| write!(f, ".{}", arc))
+        }
+        None => oid
+            .iter()
+            .try_for_each(|byte| write!(f, "{:02x}", byte)),
+    }
+}
+
+/// Seconds since the UNIX epoch, with earlier times counting backwards.
+fn unix_time(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64),
+    }
+}
+
 impl From<SystemTimeError> for Error {
     #[inline]
     fn from(_: SystemTimeError) -> Self {
@@ -197,7 +394,43 @@ impl From<SystemTimeError> for Error {
     }
 }
 
-impl StdError for Error {}
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            Self::InvalidCertificate(ref err) => Some(err),
+            Self::Other(ref err) => Some(err.0.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// An error, from outside rustls, carried by [`Error::Other`].
+///
+/// This is shared, so that [`Error`] can still be cloned.  Two
+/// `OtherError`s are equal only if one is a clone of the other.
+#[derive(Debug, Clone)]
+pub struct OtherError(pub Arc<dyn StdError + Send + Sync>);
+
+impl OtherError {
+    /// Wrap `err`.
+    pub fn new(err: impl StdError + Send + Sync + 'static) -> Self {
+        Self(Arc::new(err))
+    }
+}
+
+impl PartialEq for OtherError {
+    fn eq(&self, other: &Self) -> bool {
+        // Compare data pointers only: vtable pointers for the same
+        // type needn't be unique.
+        Arc::as_ptr(&self.0) as *const u8 == Arc::as_ptr(&other.0) as *const u8
+    }
+}
+
+impl From<OtherError> for Error {
+    fn from(err: OtherError) -> Self {
+        Self::Other(err)
+    }
+}
 
 impl From<rand::GetRandomFailed> for Error {
     fn from(_: rand::GetRandomFailed) -> Self {
@@ -207,12 +440,15 @@ impl From<rand::GetRandomFailed> for Error {
 
 #[cfg(test)]
 mod tests {
-    use super::{CorruptMessagePayload, Error};
+    use super::{CertificateError, CorruptMessagePayload, Error, OtherError};
+    use std::error::Error as StdError;
+    use std::{fmt, io};
 
     #[test]
     fn smoke() {
         use crate::msgs::enums::{AlertDescription, ContentType, HandshakeType};
         use sct;
+        use std::time::{SystemTime, UNIX_EPOCH};
 
         let all = vec![
             Error::InappropriateMessage {
@@ -230,6 +466,7 @@ mod tests {
             }),
             Error::NoCertificatesPresented,
             Error::DecryptError,
+            Error::EncryptBufferTooSmall { required: 37 },
             Error::PeerIncompatibleError("no tls1.2".to_string()),
             Error::PeerMisbehavedError("inconsistent something".to_string()),
             Error::AlertReceived(AlertDescription::ExportRestriction),
@@ -237,11 +474,42 @@ mod tests {
             Error::InvalidCertificateSignatureType,
             Error::InvalidCertificateSignature,
             Error::InvalidCertificateData("Data".into()),
+            Error::InvalidCertificate(CertificateError::NotValidForName {
+                name: "example.com".into(),
+            }),
+            Error::InvalidCertificate(CertificateError::Expired {
+                now: SystemTime::now(),
+                not_after: Some(UNIX_EPOCH),
+            }),
+            Error::InvalidCertificate(CertificateError::NotValidYet {
+                now: UNIX_EPOCH,
+                not_before: None,
+            }),
+            Error::InvalidCertificate(CertificateError::UnknownIssuer),
+            Error::InvalidCertificate(CertificateError::BadSignature),
+            Error::InvalidCertificate(CertificateError::UnsupportedKeyType),
+            Error::InvalidCertificate(CertificateError::NoRequiredIntermediate),
+            Error::InvalidCertificate(CertificateError::MissingKeyPurpose {
+                purpose: vec![0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x01],
+            }),
+            Error::InvalidCertificate(CertificateError::DelegatedCredentialExpired {
+                now: SystemTime::now(),
+                expiry: UNIX_EPOCH,
+            }),
+            Error::InvalidCertificate(CertificateError::BadDelegatedCredential),
             Error::InvalidSct(sct::Error::MalformedSct),
+            Error::TooFewValidScts {
+                required: 2,
+                valid: 1,
+            },
+            Error::NoPinMatched,
+            Error::CertificateRevoked,
             Error::General("undocumented error".to_string()),
+            Error::Other(OtherError::new(io::Error::from(io::ErrorKind::Other))),
             Error::FailedToGetCurrentTime,
             Error::FailedToGetRandomBytes,
             Error::HandshakeNotComplete,
+            Error::HandshakeTimedOut,
             Error::PeerSentOversizedRecord,
             Error::NoApplicationProtocol,
             Error::BadMaxFragmentSize,
@@ -253,6 +521,115 @@ mod tests {
         }
     }
 
+    #[test]
+    fn certificate_error_display() {
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let err = Error::InvalidCertificate(CertificateError::Expired {
+            now: UNIX_EPOCH + Duration::from_secs(2000),
+            not_after: Some(UNIX_EPOCH + Duration::from_secs(1000)),
+        });
+        assert_eq!(
+            err.to_string(),
+            "invalid peer certificate: certificate expired: checked at UNIX time 2000, \
+             not valid after 1000"
+        );
+
+        let err = CertificateError::NotValidYet {
+            now: UNIX_EPOCH - Duration::from_secs(10),
+            not_before: None,
+        };
+        assert_eq!(
+            err.to_string(),
+            "certificate not valid yet: checked at UNIX time -10"
+        );
+
+        let err = CertificateError::MissingKeyPurpose {
+            purpose: vec![0x2b, 0x06, 0x01, 0x04, 0x01, 0x86, 0x8d, 0x1f, 0x01],
+        };
+        assert_eq!(
+            err.to_string(),
+            "certificate lacks required key purpose 1.3.6.1.4.1.99999.1"
+        );
+
+        let err = CertificateError::MissingKeyPurpose {
+            purpose: vec![0x2b, 0x86],
+        };
+        assert_eq!(
+            err.to_string(),
+            "certificate lacks required key purpose 2b86"
+        );
+    }
+
+    #[derive(Debug)]
+    struct PolicyError(io::Error);
+
+    impl fmt::Display for PolicyError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "certificate rejected by policy")
+        }
+    }
+
+    impl StdError for PolicyError {
+        fn source(&self) -> Option<&(dyn StdError + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    fn error_chain(err: &dyn StdError) -> Vec<String> {
+        let mut chain = vec![err.to_string()];
+        let mut err = err;
+        while let Some(source) = err.source() {
+            chain.push(source.to_string());
+            err = source;
+        }
+        chain
+    }
+
+    #[test]
+    fn other_error_is_the_source() {
+        let err = Error::from(OtherError::new(PolicyError(io::Error::new(
+            io::ErrorKind::Other,
+            "policy server unreachable",
+        ))));
+        assert_eq!(
+            error_chain(&err),
+            vec![
+                "other error: certificate rejected by policy",
+                "certificate rejected by policy",
+                "policy server unreachable",
+            ]
+        );
+        assert!(err
+            .source()
+            .unwrap()
+            .downcast_ref::<PolicyError>()
+            .is_some());
+    }
+
+    #[test]
+    fn other_errors_are_equal_to_their_clones() {
+        let err = Error::Other(OtherError::new(io::Error::from(io::ErrorKind::Other)));
+        assert_eq!(err, err.clone());
+        assert_ne!(
+            err,
+            Error::Other(OtherError::new(io::Error::from(io::ErrorKind::Other)))
+        );
+    }
+
+    #[test]
+    fn certificate_error_is_the_source() {
+        let err = Error::InvalidCertificate(CertificateError::UnknownIssuer);
+        assert_eq!(
+            error_chain(&err),
+            vec![
+                "invalid peer certificate: certificate issuer is not trusted",
+                "certificate issuer is not trusted",
+            ]
+        );
+        assert!(Error::CorruptMessage.source().is_none());
+    }
+
     #[test]
     fn rand_error_mapping() {
         use super::rand;
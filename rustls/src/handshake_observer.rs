@@ -0,0 +1,103 @@
+use crate::msgs::enums::ProtocolVersion;
+use crate::suites::SupportedCipherSuite;
+
+use std::sync::Arc;
+
+/// This trait represents the ability to watch a handshake progress,
+/// for example to measure how long each phase takes.
+///
+/// Install one in [`ClientConfig::handshake_observer`] or
+/// [`ServerConfig::handshake_observer`].  Callbacks are made
+/// synchronously, from within the call that caused them, so take
+/// timestamps there if you need them.
+///
+/// All methods have empty default implementations.
+///
+/// [`ClientConfig::handshake_observer`]: crate::ClientConfig::handshake_observer
+/// [`ServerConfig::handshake_observer`]: crate::ServerConfig::handshake_observer
+pub trait HandshakeObserver: Send + Sync {
+    /// Called once, when the connection is created.
+    fn on_handshake_start(&self) {}
+
+    /// Called when a flight of handshake messages totalling `bytes`
+    /// has been queued for sending.
+    ///
+    /// `bytes` counts whole TLS records, including headers and any
+    /// early or half-RTT data queued alongside the handshake messages.
+    fn on_flight_sent(&self, _bytes: usize) {}
+
+    /// Called once, when the handshake has completed successfully.
+    ///
+    /// This is never called if the handshake fails.
+    fn on_handshake_complete(&self, _stats: HandshakeStats) {}
+}
+
+/// A summary of a completed handshake, given to
+/// [`HandshakeObserver::on_handshake_complete`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HandshakeStats {
+    /// The negotiated protocol version.
+    pub version: ProtocolVersion,
+    /// The negotiated cipher suite.
+    pub suite: SupportedCipherSuite,
+    /// Whether a previous session was resumed.
+    pub resumed: bool,
+    /// Whether early data (0-RTT) was accepted.
+    pub early_data: bool,
+    /// How many flights this side sent, and then waited for the peer to
+    /// answer, before the handshake completed.
+    pub round_trips: usize,
+}
+
+/// The state kept for a connection whose handshake is being observed.
+pub(crate) struct ObservedHandshake {
+    observer: Arc<dyn HandshakeObserver>,
+    flight_bytes: usize,
+    round_trips: usize,
+}
+
+impl ObservedHandshake {
+    pub(crate) fn start(observer: Arc<dyn HandshakeObserver>) -> Self {
+        observer.on_handshake_start();
+        Self {
+            observer,
+            flight_bytes: 0,
+            round_trips: 0,
+        }
+    }
+
+    pub(crate) fn queued(&mut self, bytes: usize) {
+        self.flight_bytes += bytes;
+    }
+
+    /// Report anything queued since the last call as a flight.
+    pub(crate) fn end_flight(&mut self, handshaking: bool) {
+        if self.flight_bytes == 0 {
+            return;
+        }
+
+        self.observer
+            .on_flight_sent(self.flight_bytes);
+        self.flight_bytes = 0;
+        if handshaking {
+            self.round_trips += 1;
+        }
+    }
+
+    pub(crate) fn complete(
+        self,
+        version: ProtocolVersion,
+        suite: SupportedCipherSuite,
+        resumed: bool,
+        early_data: bool,
+    ) {
+        self.observer
+            .on_handshake_complete(HandshakeStats {
+                version,
+                suite,
+                resumed,
+                early_data,
+                round_trips: self.round_trips,
+            });
+    }
+}
@@ -37,6 +37,12 @@ impl HandshakeHashBuffer {
         }
     }
 
+    /// Replace what is buffered with a 'message_hash' message holding
+    /// `hash`, as the transcript is restarted after a `HelloRetryRequest`.
+    pub fn restart_for_hrr(&mut self, hash: &[u8]) {
+        self.buffer = HandshakeMessagePayload::build_handshake_hash(hash).get_encoding();
+    }
+
     /// Hash or buffer a byte slice.
     #[cfg(test)]
     fn update_raw(&mut self, buf: &[u8]) {
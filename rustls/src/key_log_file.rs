@@ -72,7 +72,12 @@ impl KeyLogFileInner {
 /// given by the `SSLKEYLOGFILE` environment variable, and writes
 /// keys into it.
 ///
-/// If `SSLKEYLOGFILE` is not set, this does nothing.
+/// Lines are appended in the NSS key log format, so the file can be
+/// given to Wireshark.  One `KeyLogFile` may be shared by any number
+/// of connections: each line is written whole.
+///
+/// If `SSLKEYLOGFILE` is not set, this does nothing, and no file
+/// is opened.
 ///
 /// If such a file cannot be opened, or cannot be written then
 /// this does nothing but logs errors at warning-level.
@@ -102,6 +107,10 @@ impl KeyLog for KeyLogFile {
             }
         }
     }
+
+    fn will_log(&self, _label: &str) -> bool {
+        self.0.lock().unwrap().file.is_some()
+    }
 }
 
 #[cfg(all(test, target_os = "linux"))]
@@ -129,6 +138,7 @@ mod test {
     fn test_env_var_is_not_set() {
         init();
         let mut inner = KeyLogFileInner::new(Err(env::VarError::NotPresent));
+        assert!(inner.file.is_none());
         assert!(inner
             .try_write("label", b"random", b"secret")
             .is_ok());
@@ -91,3 +91,18 @@ pub static SECP384R1: SupportedKxGroup = SupportedKxGroup {
 
 /// A list of all the key exchange groups supported by rustls.
 pub static ALL_KX_GROUPS: [&SupportedKxGroup; 3] = [&X25519, &SECP256R1, &SECP384R1];
+
+/// Keeps only those of `groups` named in `allowed`, failing if that
+/// leaves none.
+pub(crate) fn restrict_groups(
+    groups: &mut Vec<&'static SupportedKxGroup>,
+    allowed: &[NamedGroup],
+) -> Result<(), Error> {
+    groups.retain(|group| allowed.contains(&group.name));
+    if groups.is_empty() {
+        return Err(Error::General(
+            "none of the allowed kx groups are configured".into(),
+        ));
+    }
+    Ok(())
+}
@@ -255,6 +255,18 @@
 //! - `read_buf`: When building with Rust Nightly, adds support for the unstable
 //!   `std::io::ReadBuf` and related APIs. This reduces costs from initializing
 //!   buffers. Will do nothing on non-Nightly releases.
+//!
+//! - `test_support`: exposes the `pair` module, which runs a handshake
+//!   between a client and server in memory, for use in tests of code
+//!   built on rustls.
+//!
+//! - `pkcs12`: exposes the `pkcs12` module, for loading a certificate chain
+//!   and private key from a PKCS#12 (`.p12`/`.pfx`) bundle.
+//!
+//! - `message_api`: exposes the `message_api` module and
+//!   `ConnectionCommon::process_message`, for encoding individual handshake
+//!   messages and injecting them into a connection.  This is meant for
+//!   fuzzing and conformance testing, not for applications.
 
 // Require docs for public APIs, deny unsafe code, etc.
 #![forbid(unsafe_code, unused_must_use)]
@@ -315,10 +327,21 @@ mod log {
 pub mod msgs;
 pub mod anchors;
 pub mod cipher;
+pub mod compress;
 pub mod conn;
 pub mod error;
 pub mod hash_hs;
 pub mod limited_cache;
+#[cfg(feature = "message_api")]
+#[cfg_attr(docsrs, doc(cfg(feature = "message_api")))]
+pub mod message_api;
+#[cfg(feature = "test_support")]
+#[cfg_attr(docsrs, doc(cfg(feature = "test_support")))]
+pub mod pair;
+pub mod pem;
+#[cfg(feature = "pkcs12")]
+#[cfg_attr(docsrs, doc(cfg(feature = "pkcs12")))]
+pub mod pkcs12;
 pub mod rand;
 pub mod record_layer;
 pub mod stream;
@@ -334,6 +357,7 @@ pub mod x509;
 pub mod check;
 pub mod bs_debug;
 pub mod builder;
+pub mod handshake_observer;
 pub mod key;
 pub mod key_log;
 pub mod key_log_file;
@@ -358,20 +382,32 @@ pub mod internal {
 // The public interface is:
 pub use crate::anchors::{OwnedTrustAnchor, RootCertStore};
 pub use crate::builder::{
-    ConfigBuilder, ConfigSide, WantsCipherSuites, WantsKxGroups, WantsVerifier, WantsVersions,
+    ConfigBuilder, ConfigSide, WantsCipherSuites, WantsKxGroups, WantsKxGroupsForVersions,
+    WantsVerifier, WantsVersions,
 };
+pub use crate::compress::{CertCompressor, CertDecompressor};
 pub use crate::conn::{
-    CommonState, Connection, ConnectionCommon, IoState, Reader, SideData, Writer,
+    AlertCallback, ChannelBindingType, CommonState, Connection, ConnectionCommon,
+    HandshakeDetails, HandshakeKind, IoState, IoStateHint, OutgoingRecord, Reader,
+    RecordCallback, RenegotiationResponse, SideData, Writer,
 };
-pub use crate::error::{CorruptMessagePayload, Error};
+pub use crate::error::{CertificateError, CorruptMessagePayload, Error, OtherError};
+pub use crate::handshake_observer::{HandshakeObserver, HandshakeStats};
 pub use crate::key::{Certificate, PrivateKey};
 pub use crate::key_log::{KeyLog, NoKeyLog};
 pub use crate::key_log_file::KeyLogFile;
 pub use crate::kx::{SupportedKxGroup, ALL_KX_GROUPS};
+pub use crate::msgs::enums::CertificateCompressionAlgorithm;
 pub use crate::msgs::enums::CipherSuite;
+pub use crate::msgs::enums::ExtensionType;
+pub use crate::msgs::enums::MaxFragmentLength;
+pub use crate::msgs::enums::NamedGroup;
 pub use crate::msgs::enums::ProtocolVersion;
 pub use crate::msgs::enums::SignatureScheme;
 pub use crate::msgs::handshake::DistinguishedNames;
+pub use crate::pem::{read_pem_items, PemItem};
+#[cfg(feature = "pkcs12")]
+pub use crate::pkcs12::load_pkcs12;
 pub use crate::stream::{Stream, StreamOwned};
 pub use crate::suites::{
     BulkAlgorithm, SupportedCipherSuite, ALL_CIPHER_SUITES, DEFAULT_CIPHER_SUITES,
@@ -379,7 +415,8 @@ pub use crate::suites::{
 pub use crate::ticketer::Ticketer;
 #[cfg(feature = "tls12")]
 pub use crate::tls12::Tls12CipherSuite;
-pub use crate::tls13::Tls13CipherSuite;
+pub use crate::tls13::{ExternalPsk, Tls13CipherSuite};
+pub use crate::verify::ALL_SIGNATURE_SCHEMES;
 pub use crate::versions::{SupportedProtocolVersion, ALL_VERSIONS, DEFAULT_VERSIONS};
 
 /// Items for use in a client.
@@ -401,14 +438,18 @@ pub mod client {
     pub use client_conn::ResolvesClientCert;
     pub use client_conn::ServerName;
     pub use client_conn::StoresClientSessions;
-    pub use client_conn::{ClientConfig, ClientConnection, ClientConnectionData, WriteEarlyData};
-    pub use handy::{ClientSessionMemoryCache, NoClientSessionStorage};
+    pub use client_conn::{
+        ClientConfig, ClientConnection, ClientConnectionData, ClientHelloTemplate, EarlyDataError,
+        ExtensionOrder, WriteEarlyData,
+    };
+    pub use handy::{ClientSessionMemoryCache, FileClientSessionStore, NoClientSessionStorage};
 
+    pub use crate::verify::{CtPolicy, OcspStatus, OcspVerifier, ServerCertPolicy};
     #[cfg(feature = "dangerous_configuration")]
     #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
     pub use crate::verify::{
-        CertificateTransparencyPolicy, HandshakeSignatureValid, ServerCertVerified,
-        ServerCertVerifier, WebPkiVerifier,
+        CertificateTransparencyPolicy, HandshakeSignatureValid, RawPublicKeyVerifier,
+        ServerCertVerified, ServerCertVerifier, SpkiPinVerifier, WebPkiVerifier,
     };
     #[cfg(feature = "dangerous_configuration")]
     #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
@@ -432,20 +473,23 @@ pub mod server {
         AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, NoClientAuth,
     };
     pub use builder::WantsServerCert;
-    pub use handy::ResolvesServerCertUsingSni;
+    pub use handy::{ResolvesServerCertUsingAlpn, ResolvesServerCertUsingSni};
     pub use handy::{NoServerSessionStorage, ServerSessionMemoryCache};
     #[cfg(feature = "quic")]
     #[cfg_attr(docsrs, doc(cfg(feature = "quic")))]
     pub use server_conn::ServerQuicExt;
-    pub use server_conn::StoresServerSessions;
+    pub use server_conn::{ChecksEarlyDataReplay, StoresServerSessions};
     pub use server_conn::{
         Accepted, Acceptor, ReadEarlyData, ServerConfig, ServerConnection, ServerConnectionData,
     };
-    pub use server_conn::{ClientHello, ProducesTickets, ResolvesServerCert};
+    pub use server_conn::{AlpnSelector, ClientHello, ProducesTickets, ResolvesServerCert};
 
     #[cfg(feature = "dangerous_configuration")]
     #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
-    pub use crate::verify::{ClientCertVerified, ClientCertVerifier, DnsName};
+    pub use crate::verify::{ClientCertVerified, ClientCertVerifier, DnsName, RawPublicKeyVerifier};
+    #[cfg(feature = "dangerous_configuration")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+    pub use server_conn::danger::DangerousServerConfig;
 }
 
 pub use server::{ServerConfig, ServerConnection};
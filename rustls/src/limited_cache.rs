@@ -7,15 +7,34 @@ use std::hash::Hash;
 /// capacity, and evicts the oldest insertion to maintain this.
 ///
 /// The requested capacity may be rounded up by the underlying
-/// collections.  This implementation uses all the allocated
-/// storage.
+/// collections.
+///
+/// By default, entries are evicted in insertion order.  A cache made
+/// with [`LimitedCache::new_lru`] instead evicts the least recently
+/// used entry, where both `get` and `insert` of an existing key count
+/// as a use.
+///
+/// Removal and freshening are amortized O(1): rather than searching
+/// `oldest` for the affected key, each item carries a generation
+/// number, and entries in `oldest` whose generation no longer matches
+/// are skipped during eviction and periodically compacted away.
 ///
 /// This is inefficient: it stores keys twice.
 pub struct LimitedCache<K: Clone + Hash + Eq, V> {
-    map: HashMap<K, V>,
+    map: HashMap<K, (V, u64)>,
+
+    // first item is the oldest key.  items whose generation does
+    // not match the one in `map` are stale.
+    oldest: VecDeque<(K, u64)>,
+
+    // the number of live items at which we evict
+    capacity: usize,
 
-    // first item is the oldest key
-    oldest: VecDeque<K>,
+    // generation number for the next insertion or freshening
+    next_generation: u64,
+
+    // whether accesses move keys to the back of `oldest`
+    lru: bool,
 }
 
 impl<K, V> LimitedCache<K, V>
@@ -23,43 +42,129 @@ where
     K: Eq + Hash + Clone + std::fmt::Debug,
 {
     /// Create a new LimitedCache with the given rough capacity.
+    ///
+    /// Panics if `capacity_order_of_magnitude` is less than 2, since
+    /// such a cache could never retain an item.
     pub fn new(capacity_order_of_magnitude: usize) -> Self {
+        assert!(
+            capacity_order_of_magnitude >= 2,
+            "LimitedCache capacity must be at least 2"
+        );
+        let oldest = VecDeque::with_capacity(capacity_order_of_magnitude);
         Self {
             map: HashMap::with_capacity(capacity_order_of_magnitude),
-            oldest: VecDeque::with_capacity(capacity_order_of_magnitude),
+            capacity: oldest.capacity(),
+            oldest,
+            next_generation: 0,
+            lru: false,
+        }
+    }
+
+    /// Create a new LimitedCache with the given rough capacity, which
+    /// evicts the least recently used item rather than the oldest
+    /// insertion.
+    pub fn new_lru(capacity_order_of_magnitude: usize) -> Self {
+        Self {
+            lru: true,
+            ..Self::new(capacity_order_of_magnitude)
         }
     }
 
     pub fn insert(&mut self, k: K, v: V) {
+        let generation = self.take_generation();
+
         let inserted_new_item = match self.map.entry(k) {
             Entry::Occupied(mut old) => {
-                // nb. does not freshen entry in `oldest`
-                old.insert(v);
+                // nb. only freshens entry in `oldest` in LRU mode
+                if self.lru {
+                    self.oldest
+                        .push_back((old.key().clone(), generation));
+                    old.insert((v, generation));
+                } else {
+                    old.get_mut().0 = v;
+                }
                 false
             }
 
             entry @ Entry::Vacant(_) => {
                 self.oldest
-                    .push_back(entry.key().clone());
-                entry.or_insert(v);
+                    .push_back((entry.key().clone(), generation));
+                entry.or_insert((v, generation));
                 true
             }
         };
 
-        // ensure next insert() does not require a realloc
-        if inserted_new_item && self.oldest.capacity() == self.oldest.len() {
-            if let Some(oldest_key) = self.oldest.pop_front() {
-                self.map.remove(&oldest_key);
+        if inserted_new_item && self.map.len() >= self.capacity {
+            self.evict_oldest();
+        }
+
+        self.compact_if_needed();
+    }
+
+    /// Returns the value for `k`, first inserting the result of `f`
+    /// if there is no such value.
+    ///
+    /// `f` is only called if `k` is absent, and the insertion evicts
+    /// an old item in the same way as `insert`.
+    pub fn get_or_insert_with<F>(&mut self, k: K, f: F) -> &V
+    where
+        F: FnOnce() -> V,
+    {
+        let generation = self.take_generation();
+
+        let (key, inserted_new_item) = match self.map.entry(k) {
+            Entry::Occupied(mut old) => {
+                if self.lru {
+                    self.oldest
+                        .push_back((old.key().clone(), generation));
+                    old.get_mut().1 = generation;
+                }
+                (old.key().clone(), false)
+            }
+
+            entry @ Entry::Vacant(_) => {
+                let key = entry.key().clone();
+                self.oldest
+                    .push_back((key.clone(), generation));
+                entry.or_insert((f(), generation));
+                (key, true)
             }
+        };
+
+        if inserted_new_item && self.map.len() >= self.capacity {
+            self.evict_oldest();
+        }
+
+        self.compact_if_needed();
+
+        // nb. `key` is always present: it is the newest item and the
+        // capacity is at least 2, so it was not evicted above.
+        &self.map[&key].0
+    }
+
+    pub fn get<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        if self.lru {
+            self.freshen(k);
         }
+        self.map.get(k).map(|(v, _)| v)
     }
 
-    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&V>
+    /// Like `get`, but returns a mutable reference so the value can be
+    /// updated in place.  Like `insert` on an existing key, this does not
+    /// freshen the entry in the eviction order, unless in LRU mode.
+    pub fn get_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
     where
         K: Borrow<Q>,
         Q: Hash + Eq,
     {
-        self.map.get(k)
+        if self.lru {
+            self.freshen(k);
+        }
+        self.map.get_mut(k).map(|(v, _)| v)
     }
 
     pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
@@ -67,20 +172,105 @@ where
         K: Borrow<Q>,
         Q: Hash + Eq,
     {
-        if let Some(value) = self.map.remove(k) {
-            // O(N) search, followed by O(N) removal
-            if let Some(index) = self
-                .oldest
-                .iter()
-                .position(|item| item.borrow() == k)
-            {
-                self.oldest.remove(index);
+        // nb. the item's entry in `oldest` is now stale, and will be
+        // skipped or compacted away later.
+        self.map.remove(k).map(|(v, _)| v)
+    }
+
+    /// Returns the number of items currently stored.
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns true if no items are currently stored.
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns the effective capacity, after any rounding of the
+    /// requested capacity.
+    ///
+    /// An item is evicted whenever an insertion brings the number
+    /// of stored items up to this value, so at most `capacity() - 1`
+    /// items are retained.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Removes all items.
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.oldest.clear();
+    }
+
+    /// Iterates over the stored items in eviction order: the item
+    /// that would be evicted next comes first.
+    ///
+    /// Re-inserting the items in this order into an empty cache
+    /// reproduces the same eviction order.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> + '_ {
+        self.oldest
+            .iter()
+            .filter_map(move |(key, generation)| match self.map.get(key) {
+                Some((value, item_generation)) if item_generation == generation => {
+                    Some((key, value))
+                }
+                _ => None,
+            })
+    }
+
+    fn take_generation(&mut self) -> u64 {
+        let generation = self.next_generation;
+        self.next_generation += 1;
+        generation
+    }
+
+    /// Make `k` the newest item, if it exists.
+    fn freshen<Q: ?Sized>(&mut self, k: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq,
+    {
+        let key = match self.map.get_key_value(k) {
+            Some((key, _)) => key.clone(),
+            None => return,
+        };
+
+        let generation = self.take_generation();
+        if let Some((_, item_generation)) = self.map.get_mut(k) {
+            *item_generation = generation;
+        }
+        self.oldest.push_back((key, generation));
+        self.compact_if_needed();
+    }
+
+    /// Remove the oldest live item, discarding any stale entries
+    /// in front of it.
+    fn evict_oldest(&mut self) {
+        while let Some((key, generation)) = self.oldest.pop_front() {
+            if is_live(&self.map, &key, generation) {
+                self.map.remove(&key);
+                return;
             }
-            Some(value)
-        } else {
-            None
         }
     }
+
+    /// Drop stale entries from `oldest` once they outnumber live ones.
+    ///
+    /// This is O(N), but only happens after O(N) removals or freshenings.
+    fn compact_if_needed(&mut self) {
+        if self.oldest.len() <= self.capacity * 2 {
+            return;
+        }
+
+        let map = &self.map;
+        self.oldest
+            .retain(|(key, generation)| is_live(map, key, *generation));
+    }
+}
+
+fn is_live<K: Hash + Eq, V>(map: &HashMap<K, (V, u64)>, key: &K, generation: u64) -> bool {
+    matches!(map.get(key), Some((_, item_generation)) if *item_generation == generation)
 }
 
 #[cfg(test)]
@@ -95,6 +285,30 @@ mod test {
         assert_eq!(t.get("abc"), Some(&2));
     }
 
+    #[test]
+    fn test_get_mut_updates_existing_item() {
+        let mut t = Test::new(3);
+        t.insert("abc".into(), 1);
+        *t.get_mut("abc").unwrap() += 1;
+        assert_eq!(t.get("abc"), Some(&2));
+        assert_eq!(t.get_mut("def"), None);
+    }
+
+    #[test]
+    fn test_get_mut_does_not_freshen_item() {
+        let mut t = Test::new(3);
+        t.insert("abc".into(), 1);
+        t.insert("def".into(), 2);
+
+        *t.get_mut("abc").unwrap() = 10;
+
+        t.insert("ghi".into(), 3);
+
+        assert_eq!(t.get("abc"), None);
+        assert_eq!(t.get("def"), Some(&2));
+        assert_eq!(t.get("ghi"), Some(&3));
+    }
+
     #[test]
     fn test_evicts_oldest_item() {
         let mut t = Test::new(3);
@@ -162,6 +376,212 @@ mod test {
         assert_eq!(t.get("mno"), Some(&5));
     }
 
+    #[test]
+    fn test_lru_get_spares_oldest_item() {
+        let mut t = Test::new_lru(3);
+        t.insert("abc".into(), 1);
+        t.insert("def".into(), 2);
+
+        assert_eq!(t.get("abc"), Some(&1));
+
+        t.insert("ghi".into(), 3);
+
+        assert_eq!(t.get("abc"), Some(&1));
+        assert_eq!(t.get("def"), None);
+        assert_eq!(t.get("ghi"), Some(&3));
+    }
+
+    #[test]
+    fn test_lru_insert_spares_oldest_item() {
+        let mut t = Test::new_lru(3);
+        t.insert("abc".into(), 1);
+        t.insert("def".into(), 2);
+        t.insert("abc".into(), 10);
+        t.insert("ghi".into(), 3);
+
+        assert_eq!(t.get("abc"), Some(&10));
+        assert_eq!(t.get("def"), None);
+        assert_eq!(t.get("ghi"), Some(&3));
+    }
+
+    #[test]
+    fn test_lru_get_mut_spares_oldest_item() {
+        let mut t = Test::new_lru(3);
+        t.insert("abc".into(), 1);
+        t.insert("def".into(), 2);
+
+        *t.get_mut("abc").unwrap() += 1;
+
+        t.insert("ghi".into(), 3);
+
+        assert_eq!(t.get("abc"), Some(&2));
+        assert_eq!(t.get("def"), None);
+        assert_eq!(t.get("ghi"), Some(&3));
+    }
+
+    #[test]
+    fn test_lru_miss_does_not_affect_order() {
+        let mut t = Test::new_lru(3);
+        t.insert("abc".into(), 1);
+        t.insert("def".into(), 2);
+
+        assert_eq!(t.get("xyz"), None);
+
+        t.insert("ghi".into(), 3);
+
+        assert_eq!(t.get("abc"), None);
+        assert_eq!(t.get("def"), Some(&2));
+        assert_eq!(t.get("ghi"), Some(&3));
+    }
+
+    #[test]
+    fn test_len_tracks_inserts_and_removes() {
+        let mut t = Test::new(4);
+        assert!(t.is_empty());
+        assert_eq!(t.len(), 0);
+
+        t.insert("abc".into(), 1);
+        t.insert("abc".into(), 2);
+        assert!(!t.is_empty());
+        assert_eq!(t.len(), 1);
+
+        t.insert("def".into(), 3);
+        assert_eq!(t.len(), 2);
+
+        assert_eq!(t.remove("abc"), Some(2));
+        assert_eq!(t.len(), 1);
+        assert_eq!(t.remove("abc"), None);
+        assert_eq!(t.len(), 1);
+
+        assert_eq!(t.remove("def"), Some(3));
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn test_len_after_eviction() {
+        let mut t = Test::new(3);
+        assert_eq!(t.capacity(), 3);
+
+        t.insert("abc".into(), 1);
+        t.insert("def".into(), 2);
+        t.insert("ghi".into(), 3);
+        t.insert("jkl".into(), 4);
+        assert_eq!(t.len(), t.capacity() - 1);
+
+        // removing already-evicted items doesn't change anything
+        assert_eq!(t.remove("abc"), None);
+        assert_eq!(t.remove("def"), None);
+        assert_eq!(t.len(), 2);
+
+        assert_eq!(t.remove("ghi"), Some(3));
+        assert_eq!(t.len(), 1);
+
+        t.insert("mno".into(), 5);
+        t.insert("pqr".into(), 6);
+        assert_eq!(t.len(), 2);
+        assert_eq!(t.get("jkl"), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "LimitedCache capacity must be at least 2")]
+    fn test_new_rejects_capacity_below_two() {
+        Test::new(1);
+    }
+
+    #[test]
+    fn test_get_or_insert_with_calls_closure_once_for_new_item() {
+        let mut t = Test::new(3);
+        let mut calls = 0;
+
+        assert_eq!(
+            t.get_or_insert_with("abc".into(), || {
+                calls += 1;
+                1
+            }),
+            &1
+        );
+        assert_eq!(calls, 1);
+        assert_eq!(t.get("abc"), Some(&1));
+        assert_eq!(t.len(), 1);
+    }
+
+    #[test]
+    fn test_get_or_insert_with_does_not_call_closure_for_existing_item() {
+        let mut t = Test::new(3);
+        t.insert("abc".into(), 1);
+
+        assert_eq!(
+            t.get_or_insert_with("abc".into(), || panic!("closure called")),
+            &1
+        );
+        assert_eq!(t.len(), 1);
+    }
+
+    #[test]
+    fn test_get_or_insert_with_evicts_oldest_item() {
+        let mut t = Test::new(3);
+        t.insert("abc".into(), 1);
+        t.insert("def".into(), 2);
+
+        assert_eq!(t.get_or_insert_with("ghi".into(), || 3), &3);
+
+        assert_eq!(t.get("abc"), None);
+        assert_eq!(t.get("def"), Some(&2));
+        assert_eq!(t.get("ghi"), Some(&3));
+    }
+
+    #[test]
+    fn test_iter_yields_items_in_eviction_order() {
+        let mut t = Test::new_lru(4);
+        t.insert("abc".into(), 1);
+        t.insert("def".into(), 2);
+        t.insert("ghi".into(), 3);
+        t.get("abc");
+        t.remove("def");
+
+        let items: Vec<_> = t.iter().collect();
+        assert_eq!(
+            items,
+            vec![(&"ghi".to_string(), &3), (&"abc".to_string(), &1)]
+        );
+    }
+
+    #[test]
+    fn test_iter_round_trip_preserves_eviction_order() {
+        let mut t = Test::new(4);
+        t.insert("abc".into(), 1);
+        t.insert("def".into(), 2);
+        t.insert("ghi".into(), 3);
+        t.insert("jkl".into(), 4);
+        t.remove("ghi");
+
+        let saved: Vec<(String, usize)> = t
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+
+        t.clear();
+        assert!(t.is_empty());
+        assert_eq!(t.iter().count(), 0);
+
+        for (k, v) in saved.iter().cloned() {
+            t.insert(k, v);
+        }
+
+        let reloaded: Vec<(String, usize)> = t
+            .iter()
+            .map(|(k, v)| (k.clone(), *v))
+            .collect();
+        assert_eq!(saved, reloaded);
+
+        t.insert("mno".into(), 5);
+        t.insert("pqr".into(), 6);
+        assert_eq!(t.get("def"), None);
+        assert_eq!(t.get("jkl"), Some(&4));
+        assert_eq!(t.get("mno"), Some(&5));
+        assert_eq!(t.get("pqr"), Some(&6));
+    }
+
     #[test]
     fn test_inserts_many_items() {
         let mut t = Test::new(3);
@@ -172,4 +592,44 @@ mod test {
             t.insert("ghi".into(), 3);
         }
     }
+
+    #[test]
+    fn test_removes_from_middle_of_large_cache() {
+        const N: usize = 100_000;
+        let mut t = super::LimitedCache::<usize, usize>::new(N * 2);
+
+        for i in 0..N {
+            t.insert(i, i);
+        }
+
+        // with a linear scan per removal this takes several billion
+        // comparisons; it should instead be effectively instant.
+        let start = std::time::Instant::now();
+        for i in N / 4..(3 * N) / 4 {
+            assert_eq!(t.remove(&i), Some(i));
+        }
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+
+        assert_eq!(t.get(&0), Some(&0));
+        assert_eq!(t.get(&(N / 2)), None);
+        assert_eq!(t.get(&(N - 1)), Some(&(N - 1)));
+    }
+
+    #[test]
+    fn test_lru_survives_compaction() {
+        let mut t = Test::new_lru(3);
+        t.insert("abc".into(), 1);
+        t.insert("def".into(), 2);
+
+        // generate many stale entries, forcing compaction
+        for _ in 0..100 {
+            assert_eq!(t.get("abc"), Some(&1));
+        }
+
+        t.insert("ghi".into(), 3);
+
+        assert_eq!(t.get("abc"), Some(&1));
+        assert_eq!(t.get("def"), None);
+        assert_eq!(t.get("ghi"), Some(&3));
+    }
 }
@@ -0,0 +1,100 @@
+//! Encoding and decoding individual handshake messages, for fuzzing and
+//! conformance testing.
+//!
+//! Together with [`ConnectionCommon::process_message`], which gives one
+//! message to a connection's state machine without going through the
+//! record layer, this lets a test build arbitrary handshake messages,
+//! inject them, and see how a connection reacts.  The message types
+//! themselves are in [`crate::internal::msgs`].
+//!
+//! [`ConnectionCommon::process_message`]: crate::ConnectionCommon::process_message
+
+use crate::error::Error;
+use crate::msgs::codec::{Codec, Reader};
+use crate::msgs::enums::{ContentType, ProtocolVersion};
+use crate::msgs::handshake::HandshakeMessagePayload;
+
+/// Encode `payload` as it is sent: its type, its length, then its body.
+pub fn encode_handshake(payload: &HandshakeMessagePayload) -> Vec<u8> {
+    payload.get_encoding()
+}
+
+/// Decode the handshake message making up all of `bytes`.
+///
+/// Some messages are encoded differently in TLS1.3, like `Certificate`,
+/// so `version` gives the protocol version in use.
+///
+/// This fails if `bytes` is not a valid message, or there are bytes
+/// left over after it.
+pub fn decode_handshake(
+    bytes: &[u8],
+    version: ProtocolVersion,
+) -> Result<HandshakeMessagePayload, Error> {
+    let mut rd = Reader::init(bytes);
+    match HandshakeMessagePayload::read_version(&mut rd, version) {
+        Some(payload) if !rd.any_left() => Ok(payload),
+        _ => Err(Error::corrupt_message(ContentType::Handshake)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::msgs::base::PayloadU8;
+    use crate::msgs::enums::{HandshakeType, KeyUpdateRequest};
+    use crate::msgs::handshake::{CertificatePayloadTLS13, HandshakePayload};
+
+    #[test]
+    fn test_round_trip() {
+        let payloads = vec![
+            (
+                HandshakeMessagePayload {
+                    typ: HandshakeType::HelloRequest,
+                    payload: HandshakePayload::HelloRequest,
+                },
+                ProtocolVersion::TLSv1_2,
+            ),
+            (
+                HandshakeMessagePayload {
+                    typ: HandshakeType::KeyUpdate,
+                    payload: HandshakePayload::KeyUpdate(KeyUpdateRequest::UpdateRequested),
+                },
+                ProtocolVersion::TLSv1_3,
+            ),
+            (
+                HandshakeMessagePayload {
+                    typ: HandshakeType::Certificate,
+                    payload: HandshakePayload::CertificateTLS13(CertificatePayloadTLS13 {
+                        context: PayloadU8::new(vec![1, 2]),
+                        entries: Vec::new(),
+                    }),
+                },
+                ProtocolVersion::TLSv1_3,
+            ),
+        ];
+
+        for (payload, version) in payloads {
+            let bytes = encode_handshake(&payload);
+            let decoded = decode_handshake(&bytes, version).unwrap();
+            assert_eq!(decoded.typ, payload.typ);
+            assert_eq!(encode_handshake(&decoded), bytes);
+        }
+    }
+
+    #[test]
+    fn test_decode_rejects_bad_lengths() {
+        let bytes = encode_handshake(&HandshakeMessagePayload {
+            typ: HandshakeType::KeyUpdate,
+            payload: HandshakePayload::KeyUpdate(KeyUpdateRequest::UpdateNotRequested),
+        });
+        assert_eq!(bytes, vec![0x18, 0x00, 0x00, 0x01, 0x00]);
+
+        let truncated = decode_handshake(&bytes[..4], ProtocolVersion::TLSv1_3);
+        assert!(matches!(truncated, Err(Error::CorruptMessagePayload(_))));
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        let trailing = decode_handshake(&trailing, ProtocolVersion::TLSv1_3);
+        assert!(matches!(trailing, Err(Error::CorruptMessagePayload(_))));
+    }
+}
@@ -55,6 +55,11 @@ impl MessageDeframer {
     /// Read some bytes from `rd`, and add them to our internal
     /// buffer.  If this means our internal buffer contains
     /// full messages, decode them all.
+    ///
+    /// The messages are decoded in one pass over the buffer, and only
+    /// then is anything left over (a partial message, or bytes which
+    /// aren't TLS) moved to its start.  So reading many small messages
+    /// at once costs no more copying than reading one.
     pub fn read(&mut self, rd: &mut dyn io::Read) -> io::Result<usize> {
         // Try to do the largest reads possible.  Note that if
         // we get a message with a length field out of range here,
@@ -65,8 +70,9 @@ impl MessageDeframer {
 
         self.used += new_bytes;
 
+        let mut taken = 0;
         loop {
-            match self.try_deframe_one() {
+            match self.try_deframe_one(&mut taken) {
                 BufferContents::Invalid => {
                     self.desynced = true;
                     break;
@@ -75,6 +81,7 @@ impl MessageDeframer {
                 BufferContents::Partial => break,
             }
         }
+        self.buf_consume(taken);
 
         Ok(new_bytes)
     }
@@ -86,18 +93,19 @@ impl MessageDeframer {
         !self.frames.is_empty() || self.used > 0
     }
 
-    /// Does our `buf` contain a full message?  It does if it is big enough to
-    /// contain a header, and that header has a length which falls within `buf`.
-    /// If so, deframe it and place the message onto the frames output queue.
-    fn try_deframe_one(&mut self) -> BufferContents {
-        // Try to decode a message off the front of buf.
-        let mut rd = codec::Reader::init(&self.buf[..self.used]);
+    /// Does our `buf` contain a full message after the first `taken`
+    /// bytes?  It does if it is big enough to contain a header, and that
+    /// header has a length which falls within `buf`.  If so, deframe it,
+    /// place the message onto the frames output queue, and add its
+    /// length to `taken`.
+    fn try_deframe_one(&mut self, taken: &mut usize) -> BufferContents {
+        // Try to decode a message off the front of what's not yet taken.
+        let mut rd = codec::Reader::init(&self.buf[*taken..self.used]);
 
         match OpaqueMessage::read(&mut rd) {
             Ok(m) => {
-                let used = rd.used();
+                *taken += rd.used();
                 self.frames.push_back(m);
-                self.buf_consume(used);
                 BufferContents::Valid
             }
             Err(MessageError::TooShortForHeader) | Err(MessageError::TooShortForLength) => {
@@ -345,6 +353,32 @@ mod tests {
         assert!(!d.desynced);
     }
 
+    #[test]
+    fn test_many_in_one_read_with_trailing_partial() {
+        let mut bytes = Vec::new();
+        for _ in 0..10 {
+            bytes.extend_from_slice(SECOND_MESSAGE);
+        }
+        bytes.extend_from_slice(&FIRST_MESSAGE[..10]);
+
+        let mut d = MessageDeframer::new();
+        assert_len(bytes.len(), input_bytes(&mut d, &bytes));
+        assert_eq!(d.frames.len(), 10);
+        for _ in 0..10 {
+            pop_second(&mut d);
+        }
+        assert!(d.has_pending());
+
+        assert_len(
+            FIRST_MESSAGE.len() - 10,
+            input_bytes(&mut d, &FIRST_MESSAGE[10..]),
+        );
+        assert_eq!(d.frames.len(), 1);
+        pop_first(&mut d);
+        assert!(!d.has_pending());
+        assert!(!d.desynced);
+    }
+
     #[test]
     fn test_incremental_with_nonfatal_read_error() {
         let mut d = MessageDeframer::new();
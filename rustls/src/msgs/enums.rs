@@ -125,6 +125,7 @@ enum_builder! {
         CertificateURL => 0x15,
         CertificateStatus => 0x16,
         KeyUpdate => 0x18,
+        CompressedCertificate => 0x19,
         MessageHash => 0xfe
     }
 }
@@ -222,8 +223,13 @@ enum_builder! {
         Heartbeat => 0x000f,
         ALProtocolNegotiation => 0x0010,
         SCT => 0x0012,
+        ClientCertificateType => 0x0013,
+        ServerCertificateType => 0x0014,
         Padding => 0x0015,
         ExtendedMasterSecret => 0x0017,
+        CompressCertificate => 0x001b,
+        RecordSizeLimit => 0x001c,
+        DelegatedCredential => 0x0022,
         SessionTicket => 0x0023,
         PreSharedKey => 0x0029,
         EarlyData => 0x002a,
@@ -798,3 +804,57 @@ enum_builder! {
         OCSP => 0x01
     }
 }
+
+enum_builder! {
+    /// The `CertificateType` TLS protocol enum.  Values in this enum are taken
+    /// from the various RFCs covering TLS, and are listed by IANA.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U8
+    EnumName: CertificateType;
+    EnumVal{
+        X509 => 0x00,
+        OpenPGP => 0x01,
+        RawPublicKey => 0x02
+    }
+}
+
+enum_builder! {
+    /// The `CertificateCompressionAlgorithm` TLS protocol enum.  Values in this enum are taken
+    /// from the various RFCs covering TLS, and are listed by IANA.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U16
+    EnumName: CertificateCompressionAlgorithm;
+    EnumVal{
+        Zlib => 0x0001,
+        Brotli => 0x0002,
+        Zstd => 0x0003
+    }
+}
+
+enum_builder! {
+    /// The `MaxFragmentLength` TLS protocol enum.  Values in this enum are taken
+    /// from the various RFCs covering TLS, and are listed by IANA.
+    /// The `Unknown` item is used when processing unrecognised ordinals.
+    @U8
+    EnumName: MaxFragmentLength;
+    EnumVal{
+        Bytes512 => 0x01,
+        Bytes1024 => 0x02,
+        Bytes2048 => 0x03,
+        Bytes4096 => 0x04
+    }
+}
+
+impl MaxFragmentLength {
+    /// The largest record payload allowed by this value, or `None` if
+    /// it is not one defined by RFC6066.
+    pub fn fragment_len(&self) -> Option<usize> {
+        match *self {
+            Self::Bytes512 => Some(512),
+            Self::Bytes1024 => Some(1024),
+            Self::Bytes2048 => Some(2048),
+            Self::Bytes4096 => Some(4096),
+            Self::Unknown(_) => None,
+        }
+    }
+}
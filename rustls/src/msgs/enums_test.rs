@@ -91,6 +91,12 @@ fn test_enums() {
         KeyUpdateRequest::UpdateRequested,
     );
     test_enum8::<CertificateStatusType>(CertificateStatusType::OCSP, CertificateStatusType::OCSP);
+    test_enum8::<CertificateType>(CertificateType::X509, CertificateType::RawPublicKey);
+    test_enum16::<CertificateCompressionAlgorithm>(
+        CertificateCompressionAlgorithm::Zlib,
+        CertificateCompressionAlgorithm::Zstd,
+    );
+    test_enum8::<MaxFragmentLength>(MaxFragmentLength::Bytes512, MaxFragmentLength::Bytes4096);
 }
 
 #[test]
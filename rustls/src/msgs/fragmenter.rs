@@ -2,14 +2,27 @@ use crate::msgs::base::Payload;
 use crate::msgs::enums::{ContentType, ProtocolVersion};
 use crate::msgs::message::{BorrowedPlainMessage, PlainMessage};
 use crate::Error;
+use std::cmp;
 use std::collections::VecDeque;
 
 pub const MAX_FRAGMENT_LEN: usize = 16384;
 pub const PACKET_OVERHEAD: usize = 1 + 2 + 2;
 pub const MAX_FRAGMENT_SIZE: usize = MAX_FRAGMENT_LEN + PACKET_OVERHEAD;
 
+/// The smallest `record_size_limit` a peer may ask for (RFC 8449 section 4).
+pub const MIN_RECORD_SIZE_LIMIT: u16 = 64;
+
+/// The largest `record_size_limit` that may be sent, which for TLS1.3
+/// counts the inner content type byte (RFC 8449 section 4).
+pub fn max_record_size_limit(tls13: bool) -> u16 {
+    MAX_FRAGMENT_LEN as u16 + u16::from(tls13)
+}
+
 pub struct MessageFragmenter {
     max_frag: usize,
+    /// The most the peer agreed to receive, which wins over
+    /// `max_frag` if smaller.
+    peer_max_frag: usize,
 }
 
 impl MessageFragmenter {
@@ -19,7 +32,10 @@ impl MessageFragmenter {
     /// this includes overhead. A `max_fragment_size` of 10 will produce TLS fragments
     /// up to 10 bytes.
     pub fn new(max_fragment_size: Option<usize>) -> Result<Self, Error> {
-        let mut new = Self { max_frag: 0 };
+        let mut new = Self {
+            max_frag: 0,
+            peer_max_frag: MAX_FRAGMENT_LEN,
+        };
         new.set_max_fragment_size(max_fragment_size)?;
         Ok(new)
     }
@@ -30,12 +46,12 @@ impl MessageFragmenter {
     /// Payloads are copied.
     pub fn fragment(&self, msg: PlainMessage, out: &mut VecDeque<PlainMessage>) {
         // Non-fragment path
-        if msg.payload.0.len() <= self.max_frag {
+        if msg.payload.0.len() <= self.frag_len() {
             out.push_back(msg);
             return;
         }
 
-        for chunk in msg.payload.0.chunks(self.max_frag) {
+        for chunk in msg.payload.0.chunks(self.frag_len()) {
             out.push_back(PlainMessage {
                 typ: msg.typ,
                 version: msg.version,
@@ -53,7 +69,7 @@ impl MessageFragmenter {
         payload: &'a [u8],
         out: &mut VecDeque<BorrowedPlainMessage<'a>>,
     ) {
-        for chunk in payload.chunks(self.max_frag) {
+        for chunk in payload.chunks(self.frag_len()) {
             let cm = BorrowedPlainMessage {
                 typ,
                 version,
@@ -71,11 +87,29 @@ impl MessageFragmenter {
         };
         Ok(())
     }
+
+    /// Produce fragments with up to `len` bytes of payload, clamped
+    /// to what the protocol allows.  This excludes overhead, unlike
+    /// `set_max_fragment_size`.
+    pub fn set_max_fragment_len(&mut self, len: usize) {
+        self.max_frag = len.clamp(1, MAX_FRAGMENT_LEN);
+    }
+
+    /// Never produce fragments with more than `max_len` bytes of payload,
+    /// whatever the configured maximum fragment size.
+    pub fn limit_fragment_len(&mut self, max_len: usize) {
+        self.peer_max_frag = cmp::min(self.peer_max_frag, max_len);
+    }
+
+    /// The largest fragment payload this will produce.
+    pub fn frag_len(&self) -> usize {
+        cmp::min(self.max_frag, self.peer_max_frag)
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{MessageFragmenter, PACKET_OVERHEAD};
+    use super::{MessageFragmenter, MAX_FRAGMENT_LEN, PACKET_OVERHEAD};
     use crate::msgs::base::Payload;
     use crate::msgs::enums::{ContentType, ProtocolVersion};
     use crate::msgs::message::PlainMessage;
@@ -165,4 +199,74 @@ mod tests {
         );
         assert_eq!(q.len(), 0);
     }
+
+    #[test]
+    fn limit_fragment_len() {
+        let m = PlainMessage {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: Payload::new(b"\x01\x02\x03\x04\x05\x06\x07\x08".to_vec()),
+        };
+
+        let mut frag = MessageFragmenter::new(Some(32)).unwrap();
+        frag.limit_fragment_len(5);
+        // A larger limit does not undo a smaller one.
+        frag.limit_fragment_len(1024);
+        let mut q = VecDeque::new();
+        frag.fragment(m, &mut q);
+        msg_eq(
+            q.pop_front(),
+            PACKET_OVERHEAD + 5,
+            &ContentType::Handshake,
+            &ProtocolVersion::TLSv1_2,
+            b"\x01\x02\x03\x04\x05",
+        );
+        msg_eq(
+            q.pop_front(),
+            PACKET_OVERHEAD + 3,
+            &ContentType::Handshake,
+            &ProtocolVersion::TLSv1_2,
+            b"\x06\x07\x08",
+        );
+        assert_eq!(q.len(), 0);
+
+        // Nor does a larger maximum fragment size.
+        frag.set_max_fragment_len(MAX_FRAGMENT_LEN);
+        let data = [0u8; 8];
+        let mut q = VecDeque::new();
+        frag.fragment_borrow(
+            ContentType::ApplicationData,
+            ProtocolVersion::TLSv1_2,
+            &data,
+            &mut q,
+        );
+        assert_eq!(q.len(), 2);
+    }
+
+    #[test]
+    fn set_max_fragment_len_is_clamped() {
+        let data = [0u8; MAX_FRAGMENT_LEN + 1];
+
+        let mut frag = MessageFragmenter::new(None).unwrap();
+        frag.set_max_fragment_len(0);
+        let mut q = VecDeque::new();
+        frag.fragment_borrow(
+            ContentType::ApplicationData,
+            ProtocolVersion::TLSv1_2,
+            &data[..3],
+            &mut q,
+        );
+        assert_eq!(q.len(), 3);
+
+        frag.set_max_fragment_len(usize::MAX);
+        let mut q = VecDeque::new();
+        frag.fragment_borrow(
+            ContentType::ApplicationData,
+            ProtocolVersion::TLSv1_2,
+            &data,
+            &mut q,
+        );
+        assert_eq!(q.len(), 2);
+        assert_eq!(q[0].payload.len(), MAX_FRAGMENT_LEN);
+    }
 }
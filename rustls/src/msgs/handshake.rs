@@ -6,9 +6,10 @@ use crate::key;
 use crate::msgs::base::{Payload, PayloadU16, PayloadU24, PayloadU8};
 use crate::msgs::codec;
 use crate::msgs::codec::{Codec, Reader};
-use crate::msgs::enums::{CertificateStatusType, ClientCertificateType};
+use crate::msgs::enums::{CertificateCompressionAlgorithm, CertificateStatusType};
+use crate::msgs::enums::{CertificateType, ClientCertificateType};
 use crate::msgs::enums::{CipherSuite, Compression, ECPointFormat, ExtensionType};
-use crate::msgs::enums::{HandshakeType, ProtocolVersion};
+use crate::msgs::enums::{HandshakeType, MaxFragmentLength, ProtocolVersion};
 use crate::msgs::enums::{HashAlgorithm, ServerNameType, SignatureAlgorithm};
 use crate::msgs::enums::{KeyUpdateRequest, NamedGroup, SignatureScheme};
 use crate::msgs::enums::ECCurveType;
@@ -76,8 +77,12 @@ impl Codec for Random {
 
 impl Random {
     pub fn new() -> Result<Self, rand::GetRandomFailed> {
+        Self::new_from(&rand::SystemRandomSource)
+    }
+
+    pub fn new_from(source: &dyn rand::RandomSource) -> Result<Self, rand::GetRandomFailed> {
         let mut data = [0u8; 32];
-        rand::fill_random(&mut data)?;
+        source.fill(&mut data)?;
         Ok(Self(data))
     }
 
@@ -146,8 +151,12 @@ impl Codec for SessionID {
 
 impl SessionID {
     pub fn random() -> Result<Self, rand::GetRandomFailed> {
+        Self::random_from(&rand::SystemRandomSource)
+    }
+
+    pub fn random_from(source: &dyn rand::RandomSource) -> Result<Self, rand::GetRandomFailed> {
         let mut data = [0u8; 32];
-        rand::fill_random(&mut data)?;
+        source.fill(&mut data)?;
         Ok(Self { data, len: 32 })
     }
 
@@ -200,6 +209,8 @@ declare_u16_vec!(NamedGroups, NamedGroup);
 
 declare_u16_vec!(SupportedSignatureSchemes, SignatureScheme);
 
+declare_u8_vec!(CertificateTypes, CertificateType);
+
 pub trait DecomposedSignatureScheme {
     fn sign(&self) -> SignatureAlgorithm;
     fn make(alg: SignatureAlgorithm, hash: HashAlgorithm) -> SignatureScheme;
@@ -307,6 +318,7 @@ declare_u16_vec!(ServerNameRequest, ServerName);
 pub trait ConvertServerNameList {
     fn has_duplicate_names_for_type(&self) -> bool;
     fn get_single_hostname(&self) -> Option<webpki::DnsNameRef>;
+    fn get_single_raw_hostname(&self) -> Option<&[u8]>;
 }
 
 impl ConvertServerNameList for ServerNameRequest {
@@ -336,6 +348,15 @@ impl ConvertServerNameList for ServerNameRequest {
             .filter_map(only_dns_hostnames)
             .next()
     }
+
+    /// The same hostname as `get_single_hostname`, but exactly as it was sent.
+    fn get_single_raw_hostname(&self) -> Option<&[u8]> {
+        self.iter()
+            .find_map(|name| match &name.payload {
+                ServerNamePayload::HostName((raw, _)) => Some(raw.0.as_slice()),
+                ServerNamePayload::Unknown(_) => None,
+            })
+    }
 }
 
 pub type ProtocolNameList = VecU16OfPayloadU8;
@@ -543,6 +564,7 @@ pub type SCTList = VecU16OfPayloadU16;
 declare_u8_vec!(PSKKeyExchangeModes, PSKKeyExchangeMode);
 declare_u16_vec!(KeyShareEntries, KeyShareEntry);
 declare_u8_vec!(ProtocolVersions, ProtocolVersion);
+declare_u8_vec!(CertificateCompressionAlgorithms, CertificateCompressionAlgorithm);
 
 #[derive(Clone, Debug)]
 pub enum ClientExtension {
@@ -565,6 +587,13 @@ pub enum ClientExtension {
     EarlyData,
     RenegotiationInfo(PayloadU8),
     SignatureAlgorithmsCert(SupportedSignatureSchemes),
+    PostHandshakeAuth,
+    ClientCertificateTypes(CertificateTypes),
+    ServerCertificateTypes(CertificateTypes),
+    MaxFragmentLength(MaxFragmentLength),
+    RecordSizeLimit(u16),
+    CertificateCompressionAlgorithms(CertificateCompressionAlgorithms),
+    DelegatedCredential(SupportedSignatureSchemes),
     Unknown(UnknownExtension),
 }
 
@@ -590,6 +619,13 @@ impl ClientExtension {
             Self::EarlyData => ExtensionType::EarlyData,
             ClientExtension::RenegotiationInfo(_) => ExtensionType::RenegotiationInfo,
             Self::SignatureAlgorithmsCert(_) => ExtensionType::SignatureAlgorithmsCert,
+            Self::PostHandshakeAuth => ExtensionType::PostHandshakeAuth,
+            Self::ClientCertificateTypes(_) => ExtensionType::ClientCertificateType,
+            Self::ServerCertificateTypes(_) => ExtensionType::ServerCertificateType,
+            Self::MaxFragmentLength(_) => ExtensionType::MaxFragmentLength,
+            Self::RecordSizeLimit(_) => ExtensionType::RecordSizeLimit,
+            Self::CertificateCompressionAlgorithms(_) => ExtensionType::CompressCertificate,
+            Self::DelegatedCredential(_) => ExtensionType::DelegatedCredential,
             Self::Unknown(ref r) => r.typ,
         }
     }
@@ -608,7 +644,8 @@ impl Codec for ClientExtension {
             Self::SessionTicket(ClientSessionTicket::Request)
             | Self::ExtendedMasterSecretRequest
             | Self::SignedCertificateTimestampRequest
-            | Self::EarlyData => {}
+            | Self::EarlyData
+            | Self::PostHandshakeAuth => {}
             Self::SessionTicket(ClientSessionTicket::Offer(ref r)) => r.encode(&mut sub),
             Self::Protocols(ref r) => r.encode(&mut sub),
             Self::SupportedVersions(ref r) => r.encode(&mut sub),
@@ -621,6 +658,13 @@ impl Codec for ClientExtension {
             | Self::TransportParametersDraft(ref r) => sub.extend_from_slice(r),
             Self::RenegotiationInfo(ref r) => r.encode(&mut sub),
             Self::SignatureAlgorithmsCert(ref r) => r.encode(&mut sub),
+            Self::ClientCertificateTypes(ref r) | Self::ServerCertificateTypes(ref r) => {
+                r.encode(&mut sub)
+            }
+            Self::MaxFragmentLength(ref r) => r.encode(&mut sub),
+            Self::RecordSizeLimit(ref r) => r.encode(&mut sub),
+            Self::CertificateCompressionAlgorithms(ref r) => r.encode(&mut sub),
+            Self::DelegatedCredential(ref r) => r.encode(&mut sub),
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -683,6 +727,25 @@ impl Codec for ClientExtension {
                 ClientExtension::SignatureAlgorithmsCert(schemes)
             }
             ExtensionType::EarlyData if !sub.any_left() => Self::EarlyData,
+            ExtensionType::PostHandshakeAuth if !sub.any_left() => Self::PostHandshakeAuth,
+            ExtensionType::ClientCertificateType => {
+                Self::ClientCertificateTypes(CertificateTypes::read(&mut sub)?)
+            }
+            ExtensionType::ServerCertificateType => {
+                Self::ServerCertificateTypes(CertificateTypes::read(&mut sub)?)
+            }
+            ExtensionType::MaxFragmentLength => {
+                Self::MaxFragmentLength(MaxFragmentLength::read(&mut sub)?)
+            }
+            ExtensionType::RecordSizeLimit => Self::RecordSizeLimit(u16::read(&mut sub)?),
+            ExtensionType::CompressCertificate => {
+                let algs = CertificateCompressionAlgorithms::read(&mut sub)?;
+                Self::CertificateCompressionAlgorithms(algs)
+            }
+            ExtensionType::DelegatedCredential => {
+                let schemes = SupportedSignatureSchemes::read(&mut sub)?;
+                Self::DelegatedCredential(schemes)
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -743,6 +806,10 @@ pub enum ServerExtension {
     TransportParameters(Vec<u8>),
     TransportParametersDraft(Vec<u8>),
     EarlyData,
+    ClientCertificateType(CertificateType),
+    ServerCertificateType(CertificateType),
+    MaxFragmentLength(MaxFragmentLength),
+    RecordSizeLimit(u16),
     Unknown(UnknownExtension),
 }
 
@@ -763,6 +830,10 @@ impl ServerExtension {
             Self::TransportParameters(_) => ExtensionType::TransportParameters,
             Self::TransportParametersDraft(_) => ExtensionType::TransportParametersDraft,
             Self::EarlyData => ExtensionType::EarlyData,
+            Self::ClientCertificateType(_) => ExtensionType::ClientCertificateType,
+            Self::ServerCertificateType(_) => ExtensionType::ServerCertificateType,
+            Self::MaxFragmentLength(_) => ExtensionType::MaxFragmentLength,
+            Self::RecordSizeLimit(_) => ExtensionType::RecordSizeLimit,
             Self::Unknown(ref r) => r.typ,
         }
     }
@@ -789,6 +860,11 @@ impl Codec for ServerExtension {
             Self::TransportParameters(ref r) | Self::TransportParametersDraft(ref r) => {
                 sub.extend_from_slice(r)
             }
+            Self::ClientCertificateType(ref r) | Self::ServerCertificateType(ref r) => {
+                r.encode(&mut sub)
+            }
+            Self::MaxFragmentLength(ref r) => r.encode(&mut sub),
+            Self::RecordSizeLimit(ref r) => r.encode(&mut sub),
             Self::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -827,6 +903,16 @@ impl Codec for ServerExtension {
                 Self::TransportParametersDraft(sub.rest().to_vec())
             }
             ExtensionType::EarlyData => Self::EarlyData,
+            ExtensionType::ClientCertificateType => {
+                Self::ClientCertificateType(CertificateType::read(&mut sub)?)
+            }
+            ExtensionType::ServerCertificateType => {
+                Self::ServerCertificateType(CertificateType::read(&mut sub)?)
+            }
+            ExtensionType::MaxFragmentLength => {
+                Self::MaxFragmentLength(MaxFragmentLength::read(&mut sub)?)
+            }
+            ExtensionType::RecordSizeLimit => Self::RecordSizeLimit(u16::read(&mut sub)?),
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -963,13 +1049,15 @@ impl ClientHelloPayload {
         }
     }
 
-    pub fn get_quic_params_extension(&self) -> Option<Vec<u8>> {
+    pub fn get_quic_params_extension(&self) -> Option<(ExtensionType, Vec<u8>)> {
         let ext = self
             .find_extension(ExtensionType::TransportParameters)
             .or_else(|| self.find_extension(ExtensionType::TransportParametersDraft))?;
         match *ext {
             ClientExtension::TransportParameters(ref bytes)
-            | ClientExtension::TransportParametersDraft(ref bytes) => Some(bytes.to_vec()),
+            | ClientExtension::TransportParametersDraft(ref bytes) => {
+                Some((ext.get_type(), bytes.to_vec()))
+            }
             _ => None,
         }
     }
@@ -994,6 +1082,14 @@ impl ClientHelloPayload {
         }
     }
 
+    pub fn get_cookie_extension(&self) -> Option<&PayloadU16> {
+        let ext = self.find_extension(ExtensionType::Cookie)?;
+        match *ext {
+            ClientExtension::Cookie(ref ck) => Some(ck),
+            _ => None,
+        }
+    }
+
     pub fn has_keyshare_extension_with_duplicates(&self) -> bool {
         if let Some(entries) = self.get_keyshare_extension() {
             let mut seen = collections::HashSet::new();
@@ -1045,6 +1141,16 @@ impl ClientHelloPayload {
         }
     }
 
+    pub fn set_psk_binders(&mut self, binders: Vec<Vec<u8>>) {
+        let last_extension = self.extensions.last_mut();
+        if let Some(ClientExtension::PresharedKey(ref mut offer)) = last_extension {
+            offer.binders = binders
+                .into_iter()
+                .map(PresharedKeyBinder::new)
+                .collect();
+        }
+    }
+
     pub fn ems_support_offered(&self) -> bool {
         self.find_extension(ExtensionType::ExtendedMasterSecret)
             .is_some()
@@ -1054,6 +1160,59 @@ impl ClientHelloPayload {
         self.find_extension(ExtensionType::EarlyData)
             .is_some()
     }
+
+    pub fn post_handshake_auth_offered(&self) -> bool {
+        self.find_extension(ExtensionType::PostHandshakeAuth)
+            .is_some()
+    }
+
+    pub fn get_client_certificate_types(&self) -> Option<&CertificateTypes> {
+        let ext = self.find_extension(ExtensionType::ClientCertificateType)?;
+        match *ext {
+            ClientExtension::ClientCertificateTypes(ref types) => Some(types),
+            _ => None,
+        }
+    }
+
+    pub fn get_server_certificate_types(&self) -> Option<&CertificateTypes> {
+        let ext = self.find_extension(ExtensionType::ServerCertificateType)?;
+        match *ext {
+            ClientExtension::ServerCertificateTypes(ref types) => Some(types),
+            _ => None,
+        }
+    }
+
+    pub fn get_max_fragment_length(&self) -> Option<MaxFragmentLength> {
+        let ext = self.find_extension(ExtensionType::MaxFragmentLength)?;
+        match *ext {
+            ClientExtension::MaxFragmentLength(mfl) => Some(mfl),
+            _ => None,
+        }
+    }
+
+    pub fn get_record_size_limit(&self) -> Option<u16> {
+        let ext = self.find_extension(ExtensionType::RecordSizeLimit)?;
+        match *ext {
+            ClientExtension::RecordSizeLimit(limit) => Some(limit),
+            _ => None,
+        }
+    }
+
+    pub fn get_cert_compression_extension(&self) -> Option<&CertificateCompressionAlgorithms> {
+        let ext = self.find_extension(ExtensionType::CompressCertificate)?;
+        match *ext {
+            ClientExtension::CertificateCompressionAlgorithms(ref algs) => Some(algs),
+            _ => None,
+        }
+    }
+
+    pub fn get_delegated_credential_extension(&self) -> Option<&SupportedSignatureSchemes> {
+        let ext = self.find_extension(ExtensionType::DelegatedCredential)?;
+        match *ext {
+            ClientExtension::DelegatedCredential(ref schemes) => Some(schemes),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1329,6 +1488,7 @@ impl Codec for CertificatePayload {
 pub enum CertificateExtension {
     CertificateStatus(CertificateStatus),
     SignedCertificateTimestamp(SCTList),
+    DelegatedCredential(DelegatedCredentialPayload),
     Unknown(UnknownExtension),
 }
 
@@ -1337,6 +1497,7 @@ impl CertificateExtension {
         match *self {
             CertificateExtension::CertificateStatus(_) => ExtensionType::StatusRequest,
             CertificateExtension::SignedCertificateTimestamp(_) => ExtensionType::SCT,
+            CertificateExtension::DelegatedCredential(_) => ExtensionType::DelegatedCredential,
             CertificateExtension::Unknown(ref r) => r.typ,
         }
     }
@@ -1359,6 +1520,13 @@ impl CertificateExtension {
             _ => None,
         }
     }
+
+    pub fn get_delegated_credential(&self) -> Option<&DelegatedCredentialPayload> {
+        match *self {
+            CertificateExtension::DelegatedCredential(ref dc) => Some(dc),
+            _ => None,
+        }
+    }
 }
 
 impl Codec for CertificateExtension {
@@ -1369,6 +1537,7 @@ impl Codec for CertificateExtension {
         match *self {
             CertificateExtension::CertificateStatus(ref r) => r.encode(&mut sub),
             CertificateExtension::SignedCertificateTimestamp(ref r) => r.encode(&mut sub),
+            CertificateExtension::DelegatedCredential(ref r) => r.encode(&mut sub),
             CertificateExtension::Unknown(ref r) => r.encode(&mut sub),
         }
 
@@ -1390,6 +1559,10 @@ impl Codec for CertificateExtension {
                 let scts = SCTList::read(&mut sub)?;
                 Self::SignedCertificateTimestamp(scts)
             }
+            ExtensionType::DelegatedCredential => {
+                let dc = DelegatedCredentialPayload::read(&mut sub)?;
+                Self::DelegatedCredential(dc)
+            }
             _ => Self::Unknown(UnknownExtension::read(typ, &mut sub)),
         };
 
@@ -1448,7 +1621,9 @@ impl CertificateEntry {
 
     pub fn has_unknown_extension(&self) -> bool {
         self.exts.iter().any(|ext| {
-            ext.get_type() != ExtensionType::StatusRequest && ext.get_type() != ExtensionType::SCT
+            ext.get_type() != ExtensionType::StatusRequest
+                && ext.get_type() != ExtensionType::SCT
+                && ext.get_type() != ExtensionType::DelegatedCredential
         })
     }
 
@@ -1465,6 +1640,13 @@ impl CertificateEntry {
             .find(|ext| ext.get_type() == ExtensionType::SCT)
             .and_then(CertificateExtension::get_sct_list)
     }
+
+    pub fn get_delegated_credential(&self) -> Option<&DelegatedCredentialPayload> {
+        self.exts
+            .iter()
+            .find(|ext| ext.get_type() == ExtensionType::DelegatedCredential)
+            .and_then(CertificateExtension::get_delegated_credential)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -1533,6 +1715,12 @@ impl CertificatePayloadTLS13 {
             .unwrap_or_default()
     }
 
+    pub fn get_end_entity_delegated_credential(&self) -> Option<&DelegatedCredentialPayload> {
+        self.entries
+            .first()
+            .and_then(CertificateEntry::get_delegated_credential)
+    }
+
     pub fn get_end_entity_scts(&self) -> Option<SCTList> {
         self.entries
             .first()
@@ -1549,6 +1737,82 @@ impl CertificatePayloadTLS13 {
     }
 }
 
+/// RFC8879's `CompressedCertificate` message, which stands in for
+/// a TLS1.3 `Certificate` message.
+#[derive(Debug, Clone)]
+pub struct CompressedCertificatePayload {
+    pub alg: CertificateCompressionAlgorithm,
+    pub uncompressed_len: codec::u24,
+    pub compressed: PayloadU24,
+}
+
+impl Codec for CompressedCertificatePayload {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.alg.encode(bytes);
+        self.uncompressed_len.encode(bytes);
+        self.compressed.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            alg: CertificateCompressionAlgorithm::read(r)?,
+            uncompressed_len: codec::u24::read(r)?,
+            compressed: PayloadU24::read(r)?,
+        })
+    }
+}
+
+/// RFC9345's `Credential`: a public key the end-entity certificate's
+/// key delegates to, until `valid_time` seconds after the
+/// certificate's `notBefore`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credential {
+    pub valid_time: u32,
+    pub dc_cert_verify_algorithm: SignatureScheme,
+    pub public_key: PayloadU24,
+}
+
+impl Codec for Credential {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.valid_time.encode(bytes);
+        self.dc_cert_verify_algorithm.encode(bytes);
+        self.public_key.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            valid_time: u32::read(r)?,
+            dc_cert_verify_algorithm: SignatureScheme::read(r)?,
+            public_key: PayloadU24::read(r)?,
+        })
+    }
+}
+
+/// RFC9345's `DelegatedCredential`, carried in the end-entity
+/// certificate's entry of a TLS1.3 `Certificate` message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DelegatedCredentialPayload {
+    pub cred: Credential,
+    pub algorithm: SignatureScheme,
+    pub signature: PayloadU16,
+}
+
+impl Codec for DelegatedCredentialPayload {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.cred.encode(bytes);
+        self.algorithm.encode(bytes);
+        self.signature.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            cred: Credential::read(r)?,
+            algorithm: SignatureScheme::read(r)?,
+            signature: PayloadU16::read(r)?,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub enum KeyExchangeAlgorithm {
     BulkOnly,
@@ -1762,13 +2026,15 @@ pub trait HasServerExtensions {
         }
     }
 
-    fn get_quic_params_extension(&self) -> Option<Vec<u8>> {
+    fn get_quic_params_extension(&self) -> Option<(ExtensionType, Vec<u8>)> {
         let ext = self
             .find_extension(ExtensionType::TransportParameters)
             .or_else(|| self.find_extension(ExtensionType::TransportParametersDraft))?;
         match *ext {
             ServerExtension::TransportParameters(ref bytes)
-            | ServerExtension::TransportParametersDraft(ref bytes) => Some(bytes.to_vec()),
+            | ServerExtension::TransportParametersDraft(ref bytes) => {
+                Some((ext.get_type(), bytes.to_vec()))
+            }
             _ => None,
         }
     }
@@ -1777,6 +2043,38 @@ pub trait HasServerExtensions {
         self.find_extension(ExtensionType::EarlyData)
             .is_some()
     }
+
+    fn get_client_certificate_type(&self) -> Option<CertificateType> {
+        let ext = self.find_extension(ExtensionType::ClientCertificateType)?;
+        match *ext {
+            ServerExtension::ClientCertificateType(typ) => Some(typ),
+            _ => None,
+        }
+    }
+
+    fn get_server_certificate_type(&self) -> Option<CertificateType> {
+        let ext = self.find_extension(ExtensionType::ServerCertificateType)?;
+        match *ext {
+            ServerExtension::ServerCertificateType(typ) => Some(typ),
+            _ => None,
+        }
+    }
+
+    fn get_max_fragment_length(&self) -> Option<MaxFragmentLength> {
+        let ext = self.find_extension(ExtensionType::MaxFragmentLength)?;
+        match *ext {
+            ServerExtension::MaxFragmentLength(mfl) => Some(mfl),
+            _ => None,
+        }
+    }
+
+    fn get_record_size_limit(&self) -> Option<u16> {
+        let ext = self.find_extension(ExtensionType::RecordSizeLimit)?;
+        match *ext {
+            ServerExtension::RecordSizeLimit(limit) => Some(limit),
+            _ => None,
+        }
+    }
 }
 
 impl HasServerExtensions for EncryptedExtensions {
@@ -2132,6 +2430,7 @@ pub enum HandshakePayload {
     HelloRetryRequest(HelloRetryRequest),
     Certificate(CertificatePayload),
     CertificateTLS13(CertificatePayloadTLS13),
+    CompressedCertificate(CompressedCertificatePayload),
     ServerKeyExchange(ServerKeyExchangePayload),
     CertificateRequest(CertificateRequestPayload),
     CertificateRequestTLS13(CertificateRequestPayloadTLS13),
@@ -2159,6 +2458,7 @@ impl HandshakePayload {
             HelloRetryRequest(ref x) => x.encode(bytes),
             Certificate(ref x) => x.encode(bytes),
             CertificateTLS13(ref x) => x.encode(bytes),
+            CompressedCertificate(ref x) => x.encode(bytes),
             ServerKeyExchange(ref x) => x.encode(bytes),
             ClientKeyExchange(ref x) => x.encode(bytes),
             CertificateRequest(ref x) => x.encode(bytes),
@@ -2238,6 +2538,10 @@ impl HandshakeMessagePayload {
             HandshakeType::Certificate => {
                 HandshakePayload::Certificate(CertificatePayload::read(&mut sub)?)
             }
+            HandshakeType::CompressedCertificate => {
+                let p = CompressedCertificatePayload::read(&mut sub)?;
+                HandshakePayload::CompressedCertificate(p)
+            }
             HandshakeType::ServerKeyExchange => {
                 let p = ServerKeyExchangePayload::read(&mut sub)?;
                 HandshakePayload::ServerKeyExchange(p)
@@ -2310,6 +2614,13 @@ impl HandshakeMessagePayload {
         }
     }
 
+    pub fn build_key_update_request() -> Self {
+        Self {
+            typ: HandshakeType::KeyUpdate,
+            payload: HandshakePayload::KeyUpdate(KeyUpdateRequest::UpdateRequested),
+        }
+    }
+
     pub fn get_encoding_for_binder_signing(&self) -> Vec<u8> {
         let mut ret = self.get_encoding();
 
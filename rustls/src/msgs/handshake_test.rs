@@ -1,5 +1,5 @@
 use super::base::{Payload, PayloadU16, PayloadU24, PayloadU8};
-use super::codec::{put_u16, Codec, Reader};
+use super::codec::{self, put_u16, Codec, Reader};
 use super::enums::*;
 use super::handshake::*;
 use crate::key::Certificate;
@@ -386,6 +386,19 @@ fn get_sample_clienthellopayload() -> ClientHelloPayload {
             ClientExtension::CertificateStatusRequest(CertificateStatusRequest::build_ocsp()),
             ClientExtension::SignedCertificateTimestampRequest,
             ClientExtension::TransportParameters(vec![1, 2, 3]),
+            ClientExtension::PostHandshakeAuth,
+            ClientExtension::ClientCertificateTypes(vec![CertificateType::RawPublicKey]),
+            ClientExtension::ServerCertificateTypes(vec![
+                CertificateType::RawPublicKey,
+                CertificateType::X509,
+            ]),
+            ClientExtension::MaxFragmentLength(MaxFragmentLength::Bytes1024),
+            ClientExtension::RecordSizeLimit(1024),
+            ClientExtension::CertificateCompressionAlgorithms(vec![
+                CertificateCompressionAlgorithm::Brotli,
+                CertificateCompressionAlgorithm::Zlib,
+            ]),
+            ClientExtension::DelegatedCredential(vec![SignatureScheme::ECDSA_NISTP256_SHA256]),
             ClientExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![1, 2, 3]),
@@ -569,6 +582,52 @@ fn client_get_psk_modes() {
     });
 }
 
+#[test]
+fn client_get_client_certificate_types() {
+    test_client_extension_getter(ExtensionType::ClientCertificateType, |chp| {
+        chp.get_client_certificate_types()
+            .is_some()
+    });
+}
+
+#[test]
+fn client_get_server_certificate_types() {
+    test_client_extension_getter(ExtensionType::ServerCertificateType, |chp| {
+        chp.get_server_certificate_types()
+            .is_some()
+    });
+}
+
+#[test]
+fn client_get_max_fragment_length() {
+    test_client_extension_getter(ExtensionType::MaxFragmentLength, |chp| {
+        chp.get_max_fragment_length().is_some()
+    });
+}
+
+#[test]
+fn client_get_record_size_limit() {
+    test_client_extension_getter(ExtensionType::RecordSizeLimit, |chp| {
+        chp.get_record_size_limit().is_some()
+    });
+}
+
+#[test]
+fn client_get_cert_compression_extension() {
+    test_client_extension_getter(ExtensionType::CompressCertificate, |chp| {
+        chp.get_cert_compression_extension()
+            .is_some()
+    });
+}
+
+#[test]
+fn client_get_delegated_credential_extension() {
+    test_client_extension_getter(ExtensionType::DelegatedCredential, |chp| {
+        chp.get_delegated_credential_extension()
+            .is_some()
+    });
+}
+
 #[test]
 fn test_truncated_helloretry_extension_is_detected() {
     let hrr = get_sample_helloretryrequest();
@@ -715,6 +774,36 @@ fn server_get_supported_versions() {
     });
 }
 
+#[test]
+fn server_get_client_certificate_type() {
+    test_server_extension_getter(ExtensionType::ClientCertificateType, |shp| {
+        shp.get_client_certificate_type()
+            .is_some()
+    });
+}
+
+#[test]
+fn server_get_server_certificate_type() {
+    test_server_extension_getter(ExtensionType::ServerCertificateType, |shp| {
+        shp.get_server_certificate_type()
+            .is_some()
+    });
+}
+
+#[test]
+fn server_get_max_fragment_length() {
+    test_server_extension_getter(ExtensionType::MaxFragmentLength, |shp| {
+        shp.get_max_fragment_length().is_some()
+    });
+}
+
+#[test]
+fn server_get_record_size_limit() {
+    test_server_extension_getter(ExtensionType::RecordSizeLimit, |shp| {
+        shp.get_record_size_limit().is_some()
+    });
+}
+
 fn test_cert_extension_getter(typ: ExtensionType, getter: fn(&CertificateEntry) -> bool) {
     let mut ce = get_sample_certificatepayloadtls13()
         .entries
@@ -746,6 +835,13 @@ fn certentry_get_scts() {
     test_cert_extension_getter(ExtensionType::SCT, |ce| ce.get_scts().is_some());
 }
 
+#[test]
+fn certentry_get_delegated_credential() {
+    test_cert_extension_getter(ExtensionType::DelegatedCredential, |ce| {
+        ce.get_delegated_credential().is_some()
+    });
+}
+
 fn get_sample_serverhellopayload() -> ServerHelloPayload {
     ServerHelloPayload {
         legacy_version: ProtocolVersion::TLSv1_2,
@@ -766,6 +862,10 @@ fn get_sample_serverhellopayload() -> ServerHelloPayload {
             ServerExtension::SignedCertificateTimestamp(vec![PayloadU16(vec![0])]),
             ServerExtension::SupportedVersions(ProtocolVersion::TLSv1_2),
             ServerExtension::TransportParameters(vec![1, 2, 3]),
+            ServerExtension::ClientCertificateType(CertificateType::X509),
+            ServerExtension::ServerCertificateType(CertificateType::RawPublicKey),
+            ServerExtension::MaxFragmentLength(MaxFragmentLength::Bytes1024),
+            ServerExtension::RecordSizeLimit(1024),
             ServerExtension::Unknown(UnknownExtension {
                 typ: ExtensionType::Unknown(12345),
                 payload: Payload(vec![1, 2, 3]),
@@ -811,6 +911,15 @@ fn get_sample_certificatepayloadtls13() -> CertificatePayloadTLS13 {
                     ocsp_response: PayloadU24(vec![1, 2, 3]),
                 }),
                 CertificateExtension::SignedCertificateTimestamp(vec![PayloadU16(vec![0])]),
+                CertificateExtension::DelegatedCredential(DelegatedCredentialPayload {
+                    cred: Credential {
+                        valid_time: 3600,
+                        dc_cert_verify_algorithm: SignatureScheme::ECDSA_NISTP256_SHA256,
+                        public_key: PayloadU24(vec![4, 5, 6]),
+                    },
+                    algorithm: SignatureScheme::ED25519,
+                    signature: PayloadU16(vec![7, 8, 9]),
+                }),
                 CertificateExtension::Unknown(UnknownExtension {
                     typ: ExtensionType::Unknown(12345),
                     payload: Payload(vec![1, 2, 3]),
@@ -1042,6 +1151,14 @@ fn get_all_tls13_handshake_payloads() -> Vec<HandshakeMessagePayload> {
             typ: HandshakeType::Certificate,
             payload: HandshakePayload::CertificateTLS13(get_sample_certificatepayloadtls13()),
         },
+        HandshakeMessagePayload {
+            typ: HandshakeType::CompressedCertificate,
+            payload: HandshakePayload::CompressedCertificate(CompressedCertificatePayload {
+                alg: CertificateCompressionAlgorithm::Zlib,
+                uncompressed_len: codec::u24(1234),
+                compressed: PayloadU24(vec![1, 2, 3]),
+            }),
+        },
         HandshakeMessagePayload {
             typ: HandshakeType::ServerKeyExchange,
             payload: HandshakePayload::ServerKeyExchange(
@@ -8,9 +8,18 @@ use crate::msgs::message::{Message, MessagePayload, PlainMessage};
 const HEADER_SIZE: usize = 1 + 3;
 
 /// TLS allows for handshake messages of up to 16MB.  We
-/// restrict that to 64KB to limit potential for denial-of-
-/// service.
-const MAX_HANDSHAKE_SIZE: u32 = 0xffff;
+/// restrict that to 64KB by default to limit potential for
+/// denial-of-service.
+pub(crate) const DEFAULT_MAX_HANDSHAKE_MESSAGE_SIZE: usize = 0xffff;
+
+/// By default, the handshake messages received over a whole
+/// handshake may total up to 256KB.
+pub(crate) const DEFAULT_MAX_HANDSHAKE_SIZE: usize = 0x40000;
+
+/// By default, a server accepts a `ClientHello` split over
+/// up to 32 records.  Clients only split it when it is larger
+/// than a record, so a few records are plenty for them.
+pub(crate) const DEFAULT_MAX_CLIENT_HELLO_RECORDS: usize = 32;
 
 /// This works to reconstruct TLS handshake messages
 /// from individual TLS messages.  It's guaranteed that
@@ -22,6 +31,24 @@ pub struct HandshakeJoiner {
 
     /// The message payload we're currently accumulating.
     buf: Vec<u8>,
+
+    /// The largest handshake message we accept, excluding its header.
+    max_message_size: usize,
+
+    /// The most handshake message bytes we accept before the
+    /// handshake completes, or `None` once it has.
+    max_handshake_size: Option<usize>,
+
+    /// Handshake message bytes, including headers, joined so far.
+    handshake_size: usize,
+
+    /// The most records the first handshake message may span, if
+    /// that is limited.
+    max_first_message_records: Option<usize>,
+
+    /// Records taken while the first handshake message is
+    /// incomplete, or `None` once it has been joined.
+    first_message_records: Option<usize>,
 }
 
 impl Default for HandshakeJoiner {
@@ -47,9 +74,39 @@ impl HandshakeJoiner {
         Self {
             frames: VecDeque::new(),
             buf: Vec::new(),
+            max_message_size: DEFAULT_MAX_HANDSHAKE_MESSAGE_SIZE,
+            max_handshake_size: Some(DEFAULT_MAX_HANDSHAKE_SIZE),
+            handshake_size: 0,
+            max_first_message_records: None,
+            first_message_records: Some(0),
         }
     }
 
+    /// Replace the limits on the size of a single handshake message,
+    /// and on the total size of the messages making up the handshake.
+    pub fn set_limits(&mut self, max_message_size: usize, max_handshake_size: usize) {
+        self.max_message_size = max_message_size;
+        if self.max_handshake_size.is_some() {
+            self.max_handshake_size = Some(max_handshake_size);
+        }
+    }
+
+    /// Limit the number of records the first handshake message
+    /// may be split across.  Taking a record beyond that fails
+    /// like a corrupt message does.
+    pub fn set_first_message_record_limit(&mut self, max_records: usize) {
+        self.max_first_message_records = Some(max_records);
+    }
+
+    /// Stop limiting the total size of handshake messages.
+    ///
+    /// Post-handshake messages, like new session tickets, can
+    /// arrive at any time over the life of a connection, so
+    /// only the limit on each message's size applies to them.
+    pub fn handshake_complete(&mut self) {
+        self.max_handshake_size = None;
+    }
+
     /// Do we want to process this message?
     pub fn want_message(&self, msg: &PlainMessage) -> bool {
         msg.typ == ContentType::Handshake
@@ -68,6 +125,16 @@ impl HandshakeJoiner {
     /// You cannot recover from this situation.  Otherwise returns
     /// a count of how many messages we queued.
     pub fn take_message(&mut self, msg: PlainMessage) -> Option<usize> {
+        let max_records = self
+            .max_first_message_records
+            .unwrap_or(usize::MAX);
+        if let Some(records) = &mut self.first_message_records {
+            *records += 1;
+            if *records > max_records {
+                return None;
+            }
+        }
+
         // The vast majority of the time `self.buf` will be empty since most
         // handshake messages arrive in a single fragment. Avoid allocating and
         // copying in that common case.
@@ -105,10 +172,24 @@ impl HandshakeJoiner {
         }
 
         let (header, rest) = self.buf.split_at(HEADER_SIZE);
-        match codec::u24::decode(&header[1..]) {
-            Some(len) if len.0 > MAX_HANDSHAKE_SIZE => BufferState::MessageTooLarge,
-            Some(len) if rest.get(..len.into()).is_some() => BufferState::OneMessage,
-            _ => BufferState::NeedsMoreData,
+        let len = match codec::u24::decode(&header[1..]) {
+            Some(len) => usize::from(len),
+            None => return BufferState::NeedsMoreData,
+        };
+
+        if len > self.max_message_size {
+            return BufferState::MessageTooLarge;
+        }
+
+        if let Some(max) = self.max_handshake_size {
+            if self.handshake_size + HEADER_SIZE + len > max {
+                return BufferState::MessageTooLarge;
+            }
+        }
+
+        match rest.get(..len) {
+            Some(_) => BufferState::OneMessage,
+            None => BufferState::NeedsMoreData,
         }
     }
 
@@ -132,6 +213,8 @@ impl HandshakeJoiner {
             self.frames.push_back(m);
             rd.used()
         };
+        self.handshake_size += used;
+        self.first_message_records = None;
         self.buf = self.buf.split_off(used);
         true
     }
@@ -289,4 +372,84 @@ mod tests {
         assert_eq!(hj.take_message(msg), None);
         assert!(!hj.is_empty());
     }
+
+    fn hello_requests(count: usize) -> PlainMessage {
+        PlainMessage {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: Payload::new(b"\x00\x00\x00\x00".repeat(count)),
+        }
+    }
+
+    #[test]
+    fn rejects_message_over_configured_size() {
+        let mut hj = HandshakeJoiner::new();
+        hj.set_limits(15, 0x1000);
+
+        // Finished of 16 bytes is refused from its header alone.
+        let msg = PlainMessage {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: Payload::new(b"\x14\x00\x00\x10".to_vec()),
+        };
+        assert_eq!(hj.take_message(msg), None);
+
+        let mut hj = HandshakeJoiner::new();
+        hj.set_limits(16, 0x1000);
+        let msg = PlainMessage {
+            typ: ContentType::Handshake,
+            version: ProtocolVersion::TLSv1_2,
+            payload: Payload::new(b"\x14\x00\x00\x10".to_vec()),
+        };
+        assert_eq!(hj.take_message(msg), Some(0));
+    }
+
+    #[test]
+    fn rejects_handshake_over_configured_size() {
+        let mut hj = HandshakeJoiner::new();
+        hj.set_limits(0xffff, 12);
+
+        assert_eq!(hj.take_message(hello_requests(3)), Some(3));
+        assert_eq!(hj.take_message(hello_requests(1)), None);
+    }
+
+    #[test]
+    fn rejects_first_message_over_record_limit() {
+        let fragments = |hj: &mut HandshakeJoiner| {
+            let mut results = Vec::new();
+            for byte in b"\x00\x00\x00\x00" {
+                let msg = PlainMessage {
+                    typ: ContentType::Handshake,
+                    version: ProtocolVersion::TLSv1_2,
+                    payload: Payload::new(vec![*byte]),
+                };
+                results.push(hj.take_message(msg));
+            }
+            results
+        };
+
+        let mut hj = HandshakeJoiner::new();
+        hj.set_first_message_record_limit(4);
+        assert_eq!(fragments(&mut hj), vec![Some(0), Some(0), Some(0), Some(1)]);
+        // later messages are not limited
+        assert_eq!(fragments(&mut hj), vec![Some(0), Some(0), Some(0), Some(1)]);
+
+        let mut hj = HandshakeJoiner::new();
+        hj.set_first_message_record_limit(3);
+        assert_eq!(fragments(&mut hj)[3], None);
+    }
+
+    #[test]
+    fn handshake_size_not_limited_after_handshake() {
+        let mut hj = HandshakeJoiner::new();
+        hj.set_limits(0xffff, 12);
+
+        assert_eq!(hj.take_message(hello_requests(3)), Some(3));
+        hj.handshake_complete();
+        assert_eq!(hj.take_message(hello_requests(3)), Some(3));
+
+        // and configuring the limits again does not reinstate it
+        hj.set_limits(0xffff, 12);
+        assert_eq!(hj.take_message(hello_requests(3)), Some(3));
+    }
 }
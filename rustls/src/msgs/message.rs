@@ -138,6 +138,20 @@ impl OpaqueMessage {
         buf
     }
 
+    /// Write the header of a record of type `typ` and `version`, with
+    /// `len` bytes of payload, into `out`, which must be `HEADER_SIZE`
+    /// bytes long.
+    pub(crate) fn encode_header(
+        typ: ContentType,
+        version: ProtocolVersion,
+        len: usize,
+        out: &mut [u8],
+    ) {
+        out[0] = typ.get_u8();
+        out[1..3].copy_from_slice(&version.get_u16().to_be_bytes());
+        out[3..5].copy_from_slice(&(len as u16).to_be_bytes());
+    }
+
     /// Force conversion into a plaintext message.
     ///
     /// This should only be used for messages that are known to be in plaintext. Otherwise, the
@@ -156,7 +170,7 @@ impl OpaqueMessage {
     const MAX_PAYLOAD: u16 = 16384 + 2048;
 
     /// Content type, version and size.
-    const HEADER_SIZE: u16 = 1 + 2 + 2;
+    pub(crate) const HEADER_SIZE: u16 = 1 + 2 + 2;
 
     /// Maximum on-wire message size.
     pub const MAX_WIRE_SIZE: usize = (Self::MAX_PAYLOAD + Self::HEADER_SIZE) as usize;
@@ -244,6 +258,13 @@ impl Message {
             payload: MessagePayload::Handshake(HandshakeMessagePayload::build_key_update_notify()),
         }
     }
+
+    pub fn build_key_update_request() -> Self {
+        Self {
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::Handshake(HandshakeMessagePayload::build_key_update_request()),
+        }
+    }
 }
 
 /// Parses a plaintext message into a well-typed [`Message`].
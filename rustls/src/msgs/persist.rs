@@ -2,7 +2,7 @@ use crate::client::ServerName;
 use crate::key;
 use crate::msgs::base::{PayloadU16, PayloadU8};
 use crate::msgs::codec::{Codec, Reader};
-use crate::msgs::enums::{CipherSuite, ProtocolVersion};
+use crate::msgs::enums::{CipherSuite, NamedGroup, ProtocolVersion};
 use crate::msgs::handshake::CertificatePayload;
 use crate::msgs::handshake::SessionID;
 use crate::suites::SupportedCipherSuite;
@@ -41,7 +41,17 @@ impl Codec for ClientSessionKey {
 impl ClientSessionKey {
     pub fn session_for_server_name(server_name: &ServerName) -> Self {
         Self {
-            kind: b"session",
+            kind: SESSION_KIND,
+            name: server_name.encode(),
+        }
+    }
+
+    /// Key for the pool of TLS1.3 tickets kept when
+    /// `ClientConfig::max_tickets_per_server` is set.  Each value in
+    /// the pool is a `ClientSessionValue`.
+    pub fn tickets_for_server_name(server_name: &ServerName) -> Self {
+        Self {
+            kind: b"tickets",
             name: server_name.encode(),
         }
     }
@@ -52,8 +62,16 @@ impl ClientSessionKey {
             name: server_name.encode(),
         }
     }
+
+    /// Returns true if `encoded` is the encoding of a key made by
+    /// `session_for_server_name`, whose value is a `ClientSessionValue`.
+    pub fn is_session_key(encoded: &[u8]) -> bool {
+        encoded.starts_with(SESSION_KIND)
+    }
 }
 
+static SESSION_KIND: &[u8] = b"session";
+
 #[derive(Debug)]
 pub enum ClientSessionValue {
     Tls13(Tls13ClientSessionValue),
@@ -538,3 +556,56 @@ impl ServerSessionValue {
         self.freshness.unwrap_or_default()
     }
 }
+
+/// What a server puts in the cookie of a stateless `HelloRetryRequest`,
+/// to carry on with the handshake when the client answers it.
+#[derive(Debug)]
+pub struct HelloRetryCookie {
+    pub cipher_suite: CipherSuite,
+    pub group: NamedGroup,
+    /// The transcript hash of the first `ClientHello`.
+    pub client_hello_hash: PayloadU8,
+    /// When the `HelloRetryRequest` was sent, in seconds since the UNIX epoch.
+    pub creation_time_sec: u64,
+}
+
+impl Codec for HelloRetryCookie {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        self.cipher_suite.encode(bytes);
+        self.group.encode(bytes);
+        self.client_hello_hash.encode(bytes);
+        self.creation_time_sec.encode(bytes);
+    }
+
+    fn read(r: &mut Reader) -> Option<Self> {
+        Some(Self {
+            cipher_suite: CipherSuite::read(r)?,
+            group: NamedGroup::read(r)?,
+            client_hello_hash: PayloadU8::read(r)?,
+            creation_time_sec: u64::read(r)?,
+        })
+    }
+}
+
+impl HelloRetryCookie {
+    pub fn new(
+        cipher_suite: CipherSuite,
+        group: NamedGroup,
+        client_hello_hash: &[u8],
+        creation_time: TimeBase,
+    ) -> Self {
+        Self {
+            cipher_suite,
+            group,
+            client_hello_hash: PayloadU8::new(client_hello_hash.to_vec()),
+            creation_time_sec: creation_time.as_secs(),
+        }
+    }
+
+    /// Whether the cookie was made at most `lifetime_secs` before `now`.
+    pub fn is_fresh(&self, now: TimeBase, lifetime_secs: u64) -> bool {
+        now.as_secs()
+            .saturating_sub(self.creation_time_sec)
+            <= lifetime_secs
+    }
+}
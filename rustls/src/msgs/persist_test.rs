@@ -76,3 +76,39 @@ fn serversessionvalue_with_cert() {
     let ssv = ServerSessionValue::read(&mut rd).unwrap();
     assert_eq!(ssv.get_encoding(), bytes);
 }
+
+#[test]
+fn helloretrycookie_round_trips() {
+    let now = TimeBase::now().unwrap();
+    let cookie = HelloRetryCookie::new(
+        CipherSuite::TLS13_AES_128_GCM_SHA256,
+        NamedGroup::X25519,
+        &[1, 2, 3],
+        now,
+    );
+    let bytes = cookie.get_encoding();
+    assert_eq!(bytes[..8], [0x13, 0x01, 0x00, 0x1d, 0x03, 0x01, 0x02, 0x03]);
+    assert_eq!(bytes[8..], now.as_secs().to_be_bytes());
+
+    let mut rd = Reader::init(&bytes);
+    let cookie = HelloRetryCookie::read(&mut rd).unwrap();
+    assert_eq!(cookie.get_encoding(), bytes);
+}
+
+#[test]
+fn helloretrycookie_expires() {
+    let now = TimeBase::now().unwrap();
+    let mut cookie = HelloRetryCookie::new(
+        CipherSuite::TLS13_AES_128_GCM_SHA256,
+        NamedGroup::X25519,
+        &[1, 2, 3],
+        now,
+    );
+    assert!(cookie.is_fresh(now, 60));
+
+    cookie.creation_time_sec -= 60;
+    assert!(cookie.is_fresh(now, 60));
+
+    cookie.creation_time_sec -= 1;
+    assert!(!cookie.is_fresh(now, 60));
+}
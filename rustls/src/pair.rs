@@ -0,0 +1,106 @@
+//! Support for testing code that uses rustls, by connecting a client
+//! and a server to each other in memory.
+
+use crate::client::{ClientConfig, ClientConnection, ServerName};
+use crate::conn::{ConnectionCommon, SideData};
+use crate::error::Error;
+use crate::server::{ServerConfig, ServerConnection};
+
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+/// A client and a server connection which have completed a
+/// handshake with each other, without doing any IO.
+///
+/// Send application data by writing to the `writer()` of one
+/// connection, then call [`CompletedHandshake::transfer`] and read
+/// it from the `reader()` of the other.
+///
+/// ```no_run
+/// # use std::convert::TryInto;
+/// # use std::io::{Read, Write};
+/// # use std::sync::Arc;
+/// # use rustls::pair::CompletedHandshake;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let client_config: Arc<rustls::ClientConfig> = panic!();
+/// # let server_config: Arc<rustls::ServerConfig> = panic!();
+/// let server_name = "example.com".try_into()?;
+/// let mut pair = CompletedHandshake::new(client_config, server_name, server_config)?;
+/// pair.client.writer().write_all(b"hello")?;
+/// pair.transfer()?;
+/// let mut buf = [0u8; 5];
+/// pair.server.reader().read_exact(&mut buf)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct CompletedHandshake {
+    /// The client end of the connection.
+    pub client: ClientConnection,
+    /// The server end of the connection.
+    pub server: ServerConnection,
+}
+
+impl CompletedHandshake {
+    /// Makes a client for `server_name` using `client_config`, and a
+    /// server using `server_config`, and runs the handshake between
+    /// them to completion.
+    ///
+    /// Everything the server sends once its side of the handshake is
+    /// done, such as TLS1.3 tickets, has been processed by the client
+    /// too.  So another `CompletedHandshake` made with the same configs
+    /// can resume the session.
+    ///
+    /// The first error from either connection is returned.
+    pub fn new(
+        client_config: Arc<ClientConfig>,
+        server_name: ServerName,
+        server_config: Arc<ServerConfig>,
+    ) -> Result<Self, Error> {
+        let mut pair = Self {
+            client: ClientConnection::new(client_config, server_name)?,
+            server: ServerConnection::new(server_config)?,
+        };
+
+        pair.transfer()?;
+        if pair.client.is_handshaking() || pair.server.is_handshaking() {
+            return Err(Error::General("in-memory handshake stalled".into()));
+        }
+
+        Ok(pair)
+    }
+
+    /// Moves TLS data between the client and server, in both
+    /// directions, and processes it, until neither has anything
+    /// more to send.
+    pub fn transfer(&mut self) -> Result<(), Error> {
+        loop {
+            let to_server = transfer_one_way(&mut self.client, &mut self.server)?;
+            let to_client = transfer_one_way(&mut self.server, &mut self.client)?;
+            if to_server == 0 && to_client == 0 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Moves everything `from` wants to write into `to`, returning the
+/// number of bytes moved.
+fn transfer_one_way(
+    from: &mut (impl DerefMut + Deref<Target = ConnectionCommon<impl SideData>>),
+    to: &mut (impl DerefMut + Deref<Target = ConnectionCommon<impl SideData>>),
+) -> Result<usize, Error> {
+    let mut buf = Vec::new();
+    while from.wants_write() {
+        from.write_tls(&mut buf)
+            .map_err(|err| Error::General(format!("cannot write TLS data: {}", err)))?;
+    }
+
+    let mut rd = &buf[..];
+    while !rd.is_empty() {
+        to.read_tls(&mut rd)
+            .map_err(|err| Error::General(format!("cannot read TLS data: {}", err)))?;
+        to.process_new_packets()?;
+    }
+
+    Ok(buf.len())
+}
@@ -0,0 +1,62 @@
+//! Loading certificate chains and private keys from PKCS#12
+//! (`.p12`/`.pfx`) bundles.
+
+use crate::error::Error;
+use crate::key;
+
+/// Extract the certificate chain and private key from the DER-encoded
+/// PKCS#12 bundle `der`, which is protected by `password`.
+///
+/// The certificates are returned in the order they appear in the
+/// bundle; tools like `openssl pkcs12 -export` put the end-entity
+/// certificate first, as [`CertifiedKey::new`] expects.  The key is
+/// PKCS#8-encoded, so it can be passed to [`sign::any_supported_type`].
+///
+/// Bundles made without a password are opened with an empty `password`.
+/// The bundle must contain at least one certificate and exactly one
+/// private key.
+///
+/// Only the legacy encryption schemes in common use, based on SHA-1
+/// with triple DES or RC2, are supported.
+///
+/// [`CertifiedKey::new`]: crate::sign::CertifiedKey::new
+/// [`sign::any_supported_type`]: crate::sign::any_supported_type
+pub fn load_pkcs12(
+    der: &[u8],
+    password: &str,
+) -> Result<(Vec<key::Certificate>, key::PrivateKey), Error> {
+    let pfx = p12::PFX::parse(der)
+        .map_err(|_| Error::General("PKCS#12 bundle is malformed".to_string()))?;
+
+    if !pfx.verify_mac(password) {
+        return Err(Error::General(
+            "PKCS#12 bundle password is incorrect".to_string(),
+        ));
+    }
+
+    let cannot_decrypt = |_| Error::General("PKCS#12 bundle cannot be decrypted".to_string());
+    let certs = pfx
+        .cert_x509_bags(password)
+        .map_err(cannot_decrypt)?;
+    let mut keys = pfx
+        .key_bags(password)
+        .map_err(cannot_decrypt)?;
+
+    if certs.is_empty() {
+        return Err(Error::General(
+            "PKCS#12 bundle contains no certificates".to_string(),
+        ));
+    }
+
+    if keys.len() != 1 {
+        return Err(Error::General(
+            "PKCS#12 bundle does not contain exactly one private key".to_string(),
+        ));
+    }
+
+    let certs = certs
+        .into_iter()
+        .map(key::Certificate)
+        .collect();
+    Ok((certs, key::PrivateKey(keys.remove(0))))
+}
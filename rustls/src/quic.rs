@@ -3,7 +3,7 @@ use crate::cipher::{Iv, IvLen};
 pub use crate::client::ClientQuicExt;
 use crate::conn::CommonState;
 use crate::error::Error;
-use crate::msgs::enums::AlertDescription;
+use crate::msgs::enums::{AlertDescription, ExtensionType};
 pub use crate::server::ServerQuicExt;
 use crate::suites::BulkAlgorithm;
 use crate::tls13::key_schedule::hkdf_expand;
@@ -71,6 +71,17 @@ pub trait QuicExt {
     /// to fail.
     fn quic_transport_parameters(&self) -> Option<&[u8]>;
 
+    /// Return the transport parameters the peer sent in this handshake,
+    /// with the extension they came in: [`ExtensionType::TransportParameters`]
+    /// for QUIC v1, or [`ExtensionType::TransportParametersDraft`] for
+    /// the drafts.
+    ///
+    /// This is `None` until the peer's `ClientHello` or
+    /// `EncryptedExtensions` arrives, even where
+    /// [`QuicExt::quic_transport_parameters`] already has parameters
+    /// remembered from a ticket.
+    fn quic_transport_parameters_extension(&self) -> Option<(ExtensionType, &[u8])>;
+
     /// Compute the keys for encrypting/decrypting 0-RTT packets, if available
     fn zero_rtt_keys(&self) -> Option<DirectionalKeys>;
 
@@ -84,6 +95,18 @@ pub trait QuicExt {
     /// When this returns `Some(_)`, the new keys must be used for future handshake data.
     fn write_hs(&mut self, buf: &mut Vec<u8>) -> Option<KeyChange>;
 
+    /// Derive the packet keys for the next 1-RTT key phase, as
+    /// described in [RFC 9001 section 6].
+    ///
+    /// This is `None` until [`QuicExt::write_hs`] has returned
+    /// [`KeyChange::OneRtt`].  Each call moves on a generation, so call
+    /// it once per key update, whichever side started it.  The
+    /// connection keeps count separately from the [`Secrets`] in
+    /// [`KeyChange::OneRtt`]: use one or the other, not both.
+    ///
+    /// [RFC 9001 section 6]: https://www.rfc-editor.org/rfc/rfc9001.html#section-6
+    fn next_1rtt_keys(&mut self) -> Option<PacketKeySet>;
+
     /// Emit the TLS description code of a fatal alert, if one has arisen.
     ///
     /// Check after `read_hs` returns `Err(_)`.
@@ -414,14 +437,14 @@ pub fn write_hs(this: &mut CommonState, buf: &mut Vec<u8>) -> Option<KeyChange>
         });
     }
 
-    if let Some(mut secrets) = this.quic.traffic_secrets.take() {
+    if let Some(secrets) = &mut this.quic.traffic_secrets {
         if !this.quic.returned_traffic_keys {
             this.quic.returned_traffic_keys = true;
-            let keys = Keys::new(&secrets);
+            let keys = Keys::new(secrets);
             secrets.update();
             return Some(KeyChange::OneRtt {
                 keys,
-                next: secrets,
+                next: secrets.clone(),
             });
         }
     }
@@ -429,6 +452,17 @@ pub fn write_hs(this: &mut CommonState, buf: &mut Vec<u8>) -> Option<KeyChange>
     None
 }
 
+pub(crate) fn next_1rtt_keys(this: &mut CommonState) -> Option<PacketKeySet> {
+    if !this.quic.returned_traffic_keys {
+        return None;
+    }
+
+    this.quic
+        .traffic_secrets
+        .as_mut()
+        .map(Secrets::next_packet_keys)
+}
+
 /// Key material for use in QUIC packet spaces
 ///
 /// QUIC uses 4 different sets of keys (and progressive key updates for long-running connections):
@@ -440,8 +474,9 @@ pub fn write_hs(this: &mut CommonState, buf: &mut Vec<u8>) -> Option<KeyChange>
 /// * 1-RTT keys: these are returned from [`QuicExt::write_hs()`] after the handshake is done
 ///
 /// Once the 1-RTT keys have been exchanged, either side may initiate a key update. Progressive
-/// update keys can be obtained from the [`Secrets`] returned in [`KeyChange::OneRtt`]. Note that
-/// only packet keys are updated by key updates; header protection keys remain the same.
+/// update keys can be obtained from the [`Secrets`] returned in [`KeyChange::OneRtt`], or from
+/// [`QuicExt::next_1rtt_keys()`]. Note that only packet keys are updated by key updates; header
+/// protection keys remain the same.
 #[allow(clippy::large_enum_variant)]
 pub enum KeyChange {
     /// Keys for the handshake space
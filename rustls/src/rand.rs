@@ -4,6 +4,11 @@ use crate::msgs::codec;
 /// they panic on error.
 use ring::rand::{SecureRandom, SystemRandom};
 
+#[cfg(feature = "dangerous_configuration")]
+use ring::hmac;
+#[cfg(feature = "dangerous_configuration")]
+use std::sync::Mutex;
+
 /// Fill the whole slice with random material.
 pub fn fill_random(bytes: &mut [u8]) -> Result<(), GetRandomFailed> {
     SystemRandom::new()
@@ -26,5 +31,79 @@ pub fn random_u32() -> Result<u32, GetRandomFailed> {
     codec::decode_u32(&buf).ok_or(GetRandomFailed)
 }
 
+/// Where a connection gets its random material from.
+///
+/// This is the system RNG unless a config says otherwise, with
+/// `DangerousClientConfig::set_nonce_source` or
+/// `DangerousServerConfig::set_nonce_source`.  Ephemeral keys and
+/// signatures never come from here.
+pub trait RandomSource: Send + Sync {
+    /// Fill the whole of `bytes` with random material.
+    fn fill(&self, bytes: &mut [u8]) -> Result<(), GetRandomFailed>;
+
+    /// Make a Vec<u8> of the given size containing random material.
+    fn random_vec(&self, len: usize) -> Result<Vec<u8>, GetRandomFailed> {
+        let mut v = vec![0; len];
+        self.fill(&mut v)?;
+        Ok(v)
+    }
+
+    /// Return a uniformly random u32.
+    fn random_u32(&self) -> Result<u32, GetRandomFailed> {
+        let mut buf = [0u8; 4];
+        self.fill(&mut buf)?;
+        codec::decode_u32(&buf).ok_or(GetRandomFailed)
+    }
+}
+
+/// The system RNG, as used by [`fill_random`].
+pub struct SystemRandomSource;
+
+impl RandomSource for SystemRandomSource {
+    fn fill(&self, bytes: &mut [u8]) -> Result<(), GetRandomFailed> {
+        fill_random(bytes)
+    }
+}
+
+/// A deterministic source of nonces, for tests.  The same seed always
+/// produces the same sequence of bytes, which is HMAC-SHA256 of a
+/// counter keyed with it.  Handshakes using it still differ in their
+/// key shares and signatures.
+///
+/// **This is not random at all** and must never be used outside tests.
+#[cfg(feature = "dangerous_configuration")]
+#[cfg_attr(docsrs, doc(cfg(feature = "dangerous_configuration")))]
+pub struct SeededNonceSource {
+    key: hmac::Key,
+    counter: Mutex<u64>,
+}
+
+#[cfg(feature = "dangerous_configuration")]
+impl SeededNonceSource {
+    /// Make a source which produces a sequence decided by `seed`.
+    pub fn new(seed: &[u8]) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, seed),
+            counter: Mutex::new(0),
+        }
+    }
+}
+
+#[cfg(feature = "dangerous_configuration")]
+impl RandomSource for SeededNonceSource {
+    fn fill(&self, bytes: &mut [u8]) -> Result<(), GetRandomFailed> {
+        let mut counter = self
+            .counter
+            .lock()
+            .map_err(|_| GetRandomFailed)?;
+        for chunk in bytes.chunks_mut(32) {
+            let block = hmac::sign(&self.key, &counter.to_be_bytes());
+            chunk.copy_from_slice(&block.as_ref()[..chunk.len()]);
+            *counter += 1;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub struct GetRandomFailed;
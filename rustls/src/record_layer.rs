@@ -22,6 +22,9 @@ pub struct RecordLayer {
     message_decrypter: Box<dyn MessageDecrypter>,
     write_seq: u64,
     read_seq: u64,
+    /// Plaintext bytes encrypted or decrypted with the current keys.
+    write_bytes: u64,
+    read_bytes: u64,
     encrypt_state: DirectionState,
     decrypt_state: DirectionState,
 
@@ -38,6 +41,8 @@ impl RecordLayer {
             message_decrypter: <dyn MessageDecrypter>::invalid(),
             write_seq: 0,
             read_seq: 0,
+            write_bytes: 0,
+            read_bytes: 0,
             encrypt_state: DirectionState::Invalid,
             decrypt_state: DirectionState::Invalid,
             trial_decryption_len: None,
@@ -70,6 +75,7 @@ impl RecordLayer {
     pub fn prepare_message_encrypter(&mut self, cipher: Box<dyn MessageEncrypter>) {
         self.message_encrypter = cipher;
         self.write_seq = 0;
+        self.write_bytes = 0;
         self.encrypt_state = DirectionState::Prepared;
     }
 
@@ -78,6 +84,7 @@ impl RecordLayer {
     pub fn prepare_message_decrypter(&mut self, cipher: Box<dyn MessageDecrypter>) {
         self.message_decrypter = cipher;
         self.read_seq = 0;
+        self.read_bytes = 0;
         self.decrypt_state = DirectionState::Prepared;
     }
 
@@ -145,6 +152,18 @@ impl RecordLayer {
         self.write_seq == SEQ_SOFT_LIMIT
     }
 
+    /// How many records, and how many bytes of their plaintext,
+    /// have been encrypted with the current key.
+    pub fn write_counts(&self) -> (u64, u64) {
+        (self.write_seq, self.write_bytes)
+    }
+
+    /// How many records, and how many bytes of their plaintext,
+    /// have been decrypted with the current key.
+    pub fn read_counts(&self) -> (u64, u64) {
+        (self.read_seq, self.read_bytes)
+    }
+
     /// Return true if we outright refuse to do anything with the
     /// encryption key.
     pub fn encrypt_exhausted(&self) -> bool {
@@ -163,6 +182,7 @@ impl RecordLayer {
             .message_decrypter
             .decrypt(encr, seq)?;
         self.read_seq += 1;
+        self.read_bytes += msg.payload.0.len() as u64;
         Ok(msg)
     }
 
@@ -175,8 +195,31 @@ impl RecordLayer {
         assert!(!self.encrypt_exhausted());
         let seq = self.write_seq;
         self.write_seq += 1;
+        self.write_bytes += plain.payload.len() as u64;
         self.message_encrypter
             .encrypt(plain, seq)
             .unwrap()
     }
+
+    /// Encrypt a TLS message into `out`, like `encrypt_outgoing`.
+    ///
+    /// On success, returns the length of the record written to the
+    /// front of `out`.  If `out` is too short, nothing is written and
+    /// the sequence number is not used, so calling again with a
+    /// larger buffer gives the same record.
+    pub fn encrypt_outgoing_into(
+        &mut self,
+        plain: BorrowedPlainMessage,
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        debug_assert!(self.encrypt_state == DirectionState::Active);
+        assert!(!self.encrypt_exhausted());
+        let len = plain.payload.len() as u64;
+        let written = self
+            .message_encrypter
+            .encrypt_into(plain, self.write_seq, out)?;
+        self.write_seq += 1;
+        self.write_bytes += len;
+        Ok(written)
+    }
 }
@@ -1,7 +1,10 @@
 use crate::builder::{ConfigBuilder, WantsVerifier};
+use crate::conn::RenegotiationResponse;
 use crate::error::Error;
 use crate::key;
 use crate::kx::SupportedKxGroup;
+use crate::msgs::enums::SignatureScheme;
+use crate::msgs::hsjoiner;
 use crate::server::handy;
 use crate::server::{ResolvesServerCert, ServerConfig};
 use crate::suites::SupportedCipherSuite;
@@ -23,6 +26,7 @@ impl ConfigBuilder<ServerConfig, WantsVerifier> {
                 cipher_suites: self.state.cipher_suites,
                 kx_groups: self.state.kx_groups,
                 versions: self.state.versions,
+                signature_schemes: self.state.signature_schemes,
                 verifier: client_cert_verifier,
             },
             side: PhantomData::default(),
@@ -43,6 +47,7 @@ pub struct WantsServerCert {
     cipher_suites: Vec<SupportedCipherSuite>,
     kx_groups: Vec<&'static SupportedKxGroup>,
     versions: versions::EnabledVersions,
+    signature_schemes: Option<Vec<SignatureScheme>>,
     verifier: Arc<dyn verify::ClientCertVerifier>,
 }
 
@@ -98,15 +103,37 @@ impl ConfigBuilder<ServerConfig, WantsServerCert> {
             cipher_suites: self.state.cipher_suites,
             kx_groups: self.state.kx_groups,
             verifier: self.state.verifier,
+            signature_schemes: self.state.signature_schemes,
+            raw_public_key_verifier: None,
+            send_raw_public_key: false,
+            nonce_source: None,
             cert_resolver,
+            external_psks: Vec::new(),
+            sni_normalizer: None,
             ignore_client_order: false,
+            require_preferred_kx_group: false,
+            require_ems: false,
             max_fragment_size: None,
+            record_size_limit: None,
+            max_handshake_message_size: hsjoiner::DEFAULT_MAX_HANDSHAKE_MESSAGE_SIZE,
+            max_handshake_size: hsjoiner::DEFAULT_MAX_HANDSHAKE_SIZE,
+            max_client_hello_records: hsjoiner::DEFAULT_MAX_CLIENT_HELLO_RECORDS,
+            cert_compressors: Vec::new(),
+            max_peer_cert_chain_len: 10,
             session_storage: handy::ServerSessionMemoryCache::new(256),
             ticketer: Arc::new(handy::NeverProducesTickets {}),
+            send_tls13_tickets: 1,
+            hello_retry_cookies: None,
             alpn_protocols: Vec::new(),
+            alpn_selector: None,
+            alpn_mismatch_is_fatal: true,
             versions: self.state.versions,
             key_log: Arc::new(NoKeyLog {}),
+            handshake_observer: None,
+            on_alert: None,
+            renegotiation_response: RenegotiationResponse::Decline,
             max_early_data_size: 0,
+            early_data_anti_replay: None,
             send_half_rtt_data: false,
         }
     }
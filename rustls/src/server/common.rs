@@ -38,4 +38,9 @@ impl<'a> ActiveCertifiedKey<'a> {
     pub(super) fn get_sct_list(&self) -> Option<&[u8]> {
         self.sct_list
     }
+
+    #[inline]
+    pub(super) fn get_delegated_credential(&self) -> Option<&sign::DelegatedCredential> {
+        self.key.delegated_credential.as_ref()
+    }
 }
@@ -1,12 +1,18 @@
 use crate::error::Error;
 use crate::key;
 use crate::limited_cache;
+use crate::msgs::enums::{CipherSuite, SignatureAlgorithm, SignatureScheme};
 use crate::server;
 use crate::server::ClientHello;
 use crate::sign;
+use crate::suites::ALL_CIPHER_SUITES;
+use crate::verify::ALL_SIGNATURE_SCHEMES;
+
+use ring::rand::SystemRandom;
 
 use std::collections;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 /// Something which never stores sessions.
 pub struct NoServerSessionStorage {}
@@ -29,40 +35,91 @@ impl server::StoresServerSessions for NoServerSessionStorage {
 /// An implementer of `StoresServerSessions` that stores everything
 /// in memory.  If enforces a limit on the number of stored sessions
 /// to bound memory usage.
+///
+/// Optionally, stored sessions can also expire after a fixed time
+/// to live: see [`ServerSessionMemoryCache::with_ttl`].
 pub struct ServerSessionMemoryCache {
-    cache: Mutex<limited_cache::LimitedCache<Vec<u8>, Vec<u8>>>,
+    cache: Mutex<limited_cache::LimitedCache<Vec<u8>, StoredSession>>,
+    ttl: Option<Duration>,
+    clock: Box<dyn Fn() -> Instant + Send + Sync>,
+}
+
+struct StoredSession {
+    value: Vec<u8>,
+    expires_at: Option<Instant>,
+}
+
+impl StoredSession {
+    fn has_expired(&self, now: Instant) -> bool {
+        matches!(self.expires_at, Some(expires_at) if now >= expires_at)
+    }
 }
 
 impl ServerSessionMemoryCache {
     /// Make a new ServerSessionMemoryCache.  `size` is the maximum
     /// number of stored sessions, and may be rounded-up for
     /// efficiency.
+    ///
+    /// Panics if `size` is less than 2.
     pub fn new(size: usize) -> Arc<Self> {
+        Self::new_inner(size, None, Box::new(Instant::now))
+    }
+
+    /// Make a new ServerSessionMemoryCache whose sessions expire
+    /// `ttl` after they are stored.
+    ///
+    /// `size` is the maximum number of stored sessions, as for
+    /// [`ServerSessionMemoryCache::new`]; this limit applies
+    /// independently of `ttl`.  Expired sessions are never returned,
+    /// and are removed when next looked up.
+    pub fn with_ttl(size: usize, ttl: Duration) -> Arc<Self> {
+        Self::new_inner(size, Some(ttl), Box::new(Instant::now))
+    }
+
+    fn new_inner(
+        size: usize,
+        ttl: Option<Duration>,
+        clock: Box<dyn Fn() -> Instant + Send + Sync>,
+    ) -> Arc<Self> {
         Arc::new(Self {
             cache: Mutex::new(limited_cache::LimitedCache::new(size)),
+            ttl,
+            clock,
         })
     }
 }
 
 impl server::StoresServerSessions for ServerSessionMemoryCache {
     fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        let expires_at = self.ttl.map(|ttl| (self.clock)() + ttl);
         self.cache
             .lock()
             .unwrap()
-            .insert(key, value);
+            .insert(key, StoredSession { value, expires_at });
         true
     }
 
     fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.cache
-            .lock()
-            .unwrap()
-            .get(key)
-            .cloned()
+        let now = (self.clock)();
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(key) {
+            Some(stored) if !stored.has_expired(now) => Some(stored.value.clone()),
+            Some(_) => {
+                cache.remove(key);
+                None
+            }
+            None => None,
+        }
     }
 
     fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.cache.lock().unwrap().remove(key)
+        let now = (self.clock)();
+        self.cache
+            .lock()
+            .unwrap()
+            .remove(key)
+            .filter(|stored| !stored.has_expired(now))
+            .map(|stored| stored.value)
     }
 
     fn can_cache(&self) -> bool {
@@ -137,8 +194,16 @@ impl server::ResolvesServerCert for AlwaysResolvesChain {
 
 /// Something that resolves do different cert chains/keys based
 /// on client-supplied server name (via SNI).
+///
+/// A name can have several certificates, with keys of different
+/// types.  Of those with a key which can sign with one of the client's
+/// [`ClientHello::signature_schemes`], and which one of the client's
+/// [`ClientHello::cipher_suites`] can be used with, an ECDSA one is
+/// preferred, then whichever was added first.  Where none can, the
+/// first one added is used, and the handshake fails as it would if
+/// that were the only one.
 pub struct ResolvesServerCertUsingSni {
-    by_name: collections::HashMap<String, Arc<sign::CertifiedKey>>,
+    by_name: collections::HashMap<String, Vec<Arc<sign::CertifiedKey>>>,
 }
 
 impl ResolvesServerCertUsingSni {
@@ -151,26 +216,84 @@ impl ResolvesServerCertUsingSni {
 
     /// Add a new `sign::CertifiedKey` to be used for the given SNI `name`.
     ///
+    /// This replaces any certificate already added for `name` whose
+    /// key can make exactly the same kinds of signature.  So, for
+    /// example, ECDSA keys on different curves are all kept.
+    ///
     /// This function fails if `name` is not a valid DNS name, or if
     /// it's not valid for the supplied certificate, or if the certificate
     /// chain is syntactically faulty.
     pub fn add(&mut self, name: &str, ck: sign::CertifiedKey) -> Result<(), Error> {
-        let checked_name = webpki::DnsNameRef::try_from_ascii_str(name)
-            .map_err(|_| Error::General("Bad DNS name".into()))?
-            .to_owned();
-
-        ck.cross_check_end_entity_cert(Some(checked_name.as_ref()))?;
-        let as_str: &str = checked_name.as_ref().into();
-        self.by_name
-            .insert(as_str.to_string(), Arc::new(ck));
+        let name = check_name(name, &ck)?;
+        let keys = self.by_name.entry(name).or_default();
+        let schemes = signing_schemes(ck.key.as_ref());
+        keys.retain(|existing| signing_schemes(existing.key.as_ref()) != schemes);
+        keys.push(Arc::new(ck));
         Ok(())
     }
 }
 
+/// Whether `key` can sign with any of `schemes`.
+fn can_sign_with(key: &dyn sign::SigningKey, schemes: &[SignatureScheme]) -> bool {
+    key.choose_scheme(schemes, Box::new(SystemRandom::new()))
+        .is_some()
+}
+
+/// The schemes rustls knows which `key` can sign with.
+fn signing_schemes(key: &dyn sign::SigningKey) -> Vec<SignatureScheme> {
+    ALL_SIGNATURE_SCHEMES
+        .iter()
+        .copied()
+        .filter(|scheme| can_sign_with(key, &[*scheme]))
+        .collect()
+}
+
+/// Whether any of `suites` that rustls supports can be used with a
+/// key of type `algorithm`.  That is all TLS1.3 suites, but only
+/// TLS1.2 suites for that type of key.
+fn suites_allow(suites: &[CipherSuite], algorithm: SignatureAlgorithm) -> bool {
+    ALL_CIPHER_SUITES
+        .iter()
+        .filter(|supported| suites.contains(&supported.suite()))
+        .any(|supported| supported.usable_for_signature_algorithm(algorithm))
+}
+
+/// Choose which of `keys`, all for the same name, to use for
+/// `client_hello`.
+fn choose_certified_key(
+    keys: &[Arc<sign::CertifiedKey>],
+    client_hello: &ClientHello,
+) -> Option<Arc<sign::CertifiedKey>> {
+    let usable = |ck: &&Arc<sign::CertifiedKey>| {
+        can_sign_with(ck.key.as_ref(), client_hello.signature_schemes())
+            && suites_allow(client_hello.cipher_suites(), ck.key.algorithm())
+    };
+
+    keys.iter()
+        .filter(|ck| ck.key.algorithm() == SignatureAlgorithm::ECDSA)
+        .find(usable)
+        .or_else(|| keys.iter().find(usable))
+        .or_else(|| keys.first())
+        .map(Arc::clone)
+}
+
+/// Check `ck` is valid for `name`, returning `name` in the form
+/// `ClientHello::server_name` gives it.
+fn check_name(name: &str, ck: &sign::CertifiedKey) -> Result<String, Error> {
+    let checked_name = webpki::DnsNameRef::try_from_ascii_str(name)
+        .map_err(|_| Error::General("Bad DNS name".into()))?
+        .to_owned();
+
+    ck.cross_check_end_entity_cert(Some(checked_name.as_ref()))?;
+    let as_str: &str = checked_name.as_ref().into();
+    Ok(as_str.to_string())
+}
+
 impl server::ResolvesServerCert for ResolvesServerCertUsingSni {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
         if let Some(name) = client_hello.server_name() {
-            self.by_name.get(name).map(Arc::clone)
+            let keys = self.by_name.get(name)?;
+            choose_certified_key(keys, &client_hello)
         } else {
             // This kind of resolver requires SNI
             None
@@ -178,9 +301,104 @@ impl server::ResolvesServerCert for ResolvesServerCertUsingSni {
     }
 }
 
+/// Something that resolves to different cert chains/keys based on
+/// the ALPN protocol chosen for the connection, and perhaps the
+/// client's server name too.  Connections with no protocol chosen, or
+/// one with no certificate here, are left to another resolver, such
+/// as a [`ResolvesServerCertUsingSni`].
+///
+/// The protocol is the one the handshake negotiates, so it follows
+/// `ServerConfig::alpn_protocols` (or `ServerConfig::alpn_selector`)
+/// rather than the client's order.  See [`ClientHello::alpn_protocol`].
+///
+/// The certificate used is the first one of these that applies:
+///
+/// 1. one added with [`ResolvesServerCertUsingAlpn::add_for_name`]
+///    for the chosen protocol and the client's SNI name.
+/// 2. one added with [`ResolvesServerCertUsingAlpn::add`] for the
+///    chosen protocol.
+/// 3. whatever the fallback resolver gives.
+pub struct ResolvesServerCertUsingAlpn {
+    by_name: Vec<(String, Vec<u8>, Arc<sign::CertifiedKey>)>,
+    any_name: Vec<(Vec<u8>, Arc<sign::CertifiedKey>)>,
+    fallback: Arc<dyn server::ResolvesServerCert>,
+}
+
+impl ResolvesServerCertUsingAlpn {
+    /// Create a new resolver which knows no protocols, so leaves
+    /// everything to `fallback`.
+    pub fn new(fallback: Arc<dyn server::ResolvesServerCert>) -> Self {
+        Self {
+            by_name: Vec::new(),
+            any_name: Vec::new(),
+            fallback,
+        }
+    }
+
+    /// Add a new `sign::CertifiedKey` to be used for connections that
+    /// choose ALPN `protocol`, whichever server name they ask for.
+    ///
+    /// This function fails if the certificate chain is syntactically
+    /// faulty.
+    pub fn add(&mut self, protocol: &[u8], ck: sign::CertifiedKey) -> Result<(), Error> {
+        ck.cross_check_end_entity_cert(None)?;
+        self.any_name
+            .push((protocol.to_vec(), Arc::new(ck)));
+        Ok(())
+    }
+
+    /// Add a new `sign::CertifiedKey` to be used for connections that
+    /// choose ALPN `protocol` and whose client asks for SNI `name`.
+    ///
+    /// This function fails if `name` is not a valid DNS name, or if
+    /// it's not valid for the supplied certificate, or if the certificate
+    /// chain is syntactically faulty.
+    pub fn add_for_name(
+        &mut self,
+        name: &str,
+        protocol: &[u8],
+        ck: sign::CertifiedKey,
+    ) -> Result<(), Error> {
+        let name = check_name(name, &ck)?;
+        self.by_name
+            .push((name, protocol.to_vec(), Arc::new(ck)));
+        Ok(())
+    }
+}
+
+impl server::ResolvesServerCert for ResolvesServerCertUsingAlpn {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        let found = client_hello
+            .alpn_protocol()
+            .and_then(|chosen| {
+                let name = client_hello.server_name();
+                let for_name = self
+                    .by_name
+                    .iter()
+                    .filter(|(for_name, _, _)| Some(for_name.as_str()) == name)
+                    .map(|(_, protocol, ck)| (protocol, ck));
+                let for_any_name = self
+                    .any_name
+                    .iter()
+                    .map(|(protocol, ck)| (protocol, ck));
+
+                for_name
+                    .chain(for_any_name)
+                    .find(|(protocol, _)| protocol.as_slice() == chosen)
+                    .map(|(_, ck)| Arc::clone(ck))
+            });
+
+        match found {
+            Some(ck) => Some(ck),
+            None => self.fallback.resolve(client_hello),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::msgs::handshake::DecomposedSignatureScheme;
     use crate::server::ProducesTickets;
     use crate::server::ResolvesServerCert;
     use crate::server::StoresServerSessions;
@@ -248,6 +466,81 @@ mod test {
         assert!(count < 5);
     }
 
+    struct TestClock(Arc<Mutex<Instant>>);
+
+    impl TestClock {
+        fn new() -> Self {
+            Self(Arc::new(Mutex::new(Instant::now())))
+        }
+
+        fn boxed(&self) -> Box<dyn Fn() -> Instant + Send + Sync> {
+            let now = Arc::clone(&self.0);
+            Box::new(move || *now.lock().unwrap())
+        }
+
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    #[test]
+    fn test_serversessionmemorycache_with_ttl_expires_get() {
+        let clock = TestClock::new();
+        let c =
+            ServerSessionMemoryCache::new_inner(4, Some(Duration::from_secs(10)), clock.boxed());
+        assert!(c.put(vec![0x01], vec![0x02]));
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(c.get(&[0x01]), Some(vec![0x02]));
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(c.get(&[0x01]), None);
+        assert_eq!(c.cache.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_serversessionmemorycache_with_ttl_expires_take() {
+        let clock = TestClock::new();
+        let c =
+            ServerSessionMemoryCache::new_inner(4, Some(Duration::from_secs(10)), clock.boxed());
+        assert!(c.put(vec![0x01], vec![0x02]));
+        assert!(c.put(vec![0x03], vec![0x04]));
+
+        assert_eq!(c.take(&[0x01]), Some(vec![0x02]));
+
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(c.take(&[0x03]), None);
+        assert_eq!(c.cache.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_serversessionmemorycache_with_ttl_refreshes_on_put() {
+        let clock = TestClock::new();
+        let c =
+            ServerSessionMemoryCache::new_inner(4, Some(Duration::from_secs(10)), clock.boxed());
+        assert!(c.put(vec![0x01], vec![0x02]));
+
+        clock.advance(Duration::from_secs(5));
+        assert!(c.put(vec![0x01], vec![0x04]));
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(c.get(&[0x01]), Some(vec![0x04]));
+    }
+
+    #[test]
+    fn test_serversessionmemorycache_with_ttl_still_limits_size() {
+        let clock = TestClock::new();
+        let c =
+            ServerSessionMemoryCache::new_inner(3, Some(Duration::from_secs(10)), clock.boxed());
+        assert!(c.put(vec![0x01], vec![0x02]));
+        assert!(c.put(vec![0x03], vec![0x04]));
+        assert!(c.put(vec![0x05], vec![0x06]));
+
+        assert_eq!(c.get(&[0x01]), None);
+        assert_eq!(c.get(&[0x03]), Some(vec![0x04]));
+        assert_eq!(c.get(&[0x05]), Some(vec![0x06]));
+    }
+
     #[test]
     fn test_neverproducestickets_does_nothing() {
         let npt = NeverProducesTickets {};
@@ -261,7 +554,7 @@ mod test {
     fn test_resolvesservercertusingsni_requires_sni() {
         let rscsni = ResolvesServerCertUsingSni::new();
         assert!(rscsni
-            .resolve(ClientHello::new(&None, &[], None, &[]))
+            .resolve(ClientHello::new(&None, &[], &[], None, None, &[], None))
             .is_none());
     }
 
@@ -271,8 +564,149 @@ mod test {
         let name = webpki::DnsNameRef::try_from_ascii_str("hello.com")
             .unwrap()
             .to_owned();
-        assert!(rscsni
-            .resolve(ClientHello::new(&Some(name), &[], None, &[]))
-            .is_none());
+        let sni = Some(name);
+        let hello = ClientHello::new(&sni, &[], &[], None, None, &[], None);
+        assert!(rscsni.resolve(hello).is_none());
+    }
+
+    /// A key which can only sign with one scheme.  It never needs to
+    /// actually sign anything.
+    struct SingleSchemeKey(SignatureScheme);
+
+    impl sign::SigningKey for SingleSchemeKey {
+        fn choose_scheme(
+            &self,
+            offered: &[SignatureScheme],
+            _rng: Box<dyn ring::rand::SecureRandom + Send + Sync>,
+        ) -> Option<Box<dyn sign::Signer>> {
+            if !offered.contains(&self.0) {
+                return None;
+            }
+            Some(Box::new(SingleSchemeSigner(self.0)))
+        }
+
+        fn algorithm(&self) -> SignatureAlgorithm {
+            self.0.sign()
+        }
+    }
+
+    struct SingleSchemeSigner(SignatureScheme);
+
+    impl sign::Signer for SingleSchemeSigner {
+        fn sign(&self, _message: &[u8]) -> Result<Vec<u8>, Error> {
+            Err(Error::General("not a real key".into()))
+        }
+
+        fn scheme(&self) -> SignatureScheme {
+            self.0
+        }
+    }
+
+    /// Adds a key for `localhost` for each of `schemes` to a new resolver.
+    fn sni_resolver_with_keys(schemes: &[SignatureScheme]) -> ResolvesServerCertUsingSni {
+        let chain =
+            rustls_pemfile::certs(&mut &include_bytes!("../../../test-ca/ecdsa/end.fullchain")[..])
+                .unwrap()
+                .into_iter()
+                .map(key::Certificate)
+                .collect::<Vec<_>>();
+
+        let mut rscsni = ResolvesServerCertUsingSni::new();
+        for scheme in schemes {
+            let key = Arc::new(SingleSchemeKey(*scheme));
+            rscsni
+                .add("localhost", sign::CertifiedKey::new(chain.clone(), key))
+                .unwrap();
+        }
+        rscsni
+    }
+
+    /// The scheme of the key `rscsni` gives a client offering `schemes`
+    /// and `suites`.
+    fn resolved_scheme(
+        rscsni: &ResolvesServerCertUsingSni,
+        schemes: &[SignatureScheme],
+        suites: &[CipherSuite],
+    ) -> Option<SignatureScheme> {
+        let name = webpki::DnsNameRef::try_from_ascii_str("localhost")
+            .unwrap()
+            .to_owned();
+        let ck = rscsni.resolve(ClientHello::new(
+            &Some(name),
+            schemes,
+            schemes,
+            None,
+            None,
+            suites,
+            None,
+        ))?;
+        ck.key
+            .choose_scheme(ALL_SIGNATURE_SCHEMES, Box::new(SystemRandom::new()))
+            .map(|signer| signer.scheme())
+    }
+
+    #[test]
+    fn test_resolvesservercertusingsni_keeps_keys_for_each_curve() {
+        let rscsni = sni_resolver_with_keys(&[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+        ]);
+
+        for scheme in &[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+        ] {
+            assert_eq!(
+                resolved_scheme(
+                    &rscsni,
+                    &[*scheme],
+                    &[CipherSuite::TLS13_AES_128_GCM_SHA256]
+                ),
+                Some(*scheme)
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolvesservercertusingsni_replaces_key_of_same_kind() {
+        let mut rscsni = sni_resolver_with_keys(&[SignatureScheme::ECDSA_NISTP256_SHA256]);
+        let chain = rscsni.by_name["localhost"][0]
+            .cert
+            .clone();
+        let key = Arc::new(SingleSchemeKey(SignatureScheme::ECDSA_NISTP256_SHA256));
+        rscsni
+            .add("localhost", sign::CertifiedKey::new(chain, key))
+            .unwrap();
+        assert_eq!(rscsni.by_name["localhost"].len(), 1);
+    }
+
+    #[cfg(feature = "tls12")]
+    #[test]
+    fn test_resolvesservercertusingsni_needs_usable_cipher_suite() {
+        let rscsni = sni_resolver_with_keys(&[
+            SignatureScheme::RSA_PSS_SHA256,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+        ]);
+        let schemes = [
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::RSA_PSS_SHA256,
+        ];
+
+        assert_eq!(
+            resolved_scheme(
+                &rscsni,
+                &schemes,
+                &[CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256]
+            ),
+            Some(SignatureScheme::RSA_PSS_SHA256)
+        );
+        assert_eq!(
+            resolved_scheme(
+                &rscsni,
+                &schemes,
+                &[CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256]
+            ),
+            Some(SignatureScheme::ECDSA_NISTP256_SHA256)
+        );
     }
 }
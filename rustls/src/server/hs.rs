@@ -3,10 +3,12 @@ use crate::error::Error;
 use crate::hash_hs::{HandshakeHash, HandshakeHashBuffer};
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace};
+use crate::msgs::codec::{self, Codec};
 #[cfg(feature = "tls12")]
 use crate::msgs::enums::CipherSuite;
-use crate::msgs::enums::{AlertDescription, Compression, ExtensionType};
+use crate::msgs::enums::{AlertDescription, Compression, ExtensionType, PSKKeyExchangeMode};
 use crate::msgs::enums::{HandshakeType, ProtocolVersion, SignatureScheme};
+use crate::msgs::fragmenter::{max_record_size_limit, MIN_RECORD_SIZE_LIMIT};
 #[cfg(feature = "tls12")]
 use crate::msgs::handshake::SessionID;
 use crate::msgs::handshake::{ClientHelloPayload, Random, ServerExtension};
@@ -15,6 +17,7 @@ use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
 use crate::server::{ClientHello, ServerConfig};
 use crate::suites;
+use crate::tls13::ExternalPsk;
 use crate::SupportedCipherSuite;
 
 use super::server_conn::ServerConnectionData;
@@ -23,6 +26,7 @@ use super::tls12;
 use crate::server::common::ActiveCertifiedKey;
 use crate::server::tls13;
 
+use std::cmp;
 use std::sync::Arc;
 
 pub(super) type NextState = Box<dyn State<ServerConnectionData>>;
@@ -62,6 +66,28 @@ pub(super) fn can_resume(
         && &resumedata.sni == sni
 }
 
+/// The signature schemes from the client's `signature_algorithms`
+/// extension, as sent.
+pub(super) fn offered_sig_schemes(hello: &ClientHelloPayload) -> &[SignatureScheme] {
+    hello
+        .get_sigalgs_extension()
+        .map(|schemes| schemes.as_slice())
+        .unwrap_or(&[])
+}
+
+/// The encoding of `hello` as a handshake message, header included.
+///
+/// This is what `HandshakeHash::add_message` feeds to the transcript
+/// for the `ClientHello`.
+pub(super) fn client_hello_encoding(hello: &ClientHelloPayload) -> Vec<u8> {
+    let body = hello.get_encoding();
+    let mut bytes = Vec::with_capacity(4 + body.len());
+    HandshakeType::ClientHello.encode(&mut bytes);
+    codec::u24(body.len() as u32).encode(&mut bytes);
+    bytes.extend_from_slice(&body);
+    bytes
+}
+
 #[derive(Default)]
 pub(super) struct ExtensionProcessing {
     // extensions to reply with
@@ -85,34 +111,11 @@ impl ExtensionProcessing {
         resumedata: Option<&persist::ServerSessionValue>,
         extra_exts: Vec<ServerExtension>,
     ) -> Result<(), Error> {
-        // ALPN
-        let our_protocols = &config.alpn_protocols;
-        let maybe_their_protocols = hello.get_alpn_extension();
-        if let Some(their_protocols) = maybe_their_protocols {
-            let their_protocols = their_protocols.to_slices();
-
-            if their_protocols
-                .iter()
-                .any(|protocol| protocol.is_empty())
-            {
-                return Err(Error::PeerMisbehavedError(
-                    "client offered empty ALPN protocol".to_string(),
-                ));
-            }
-
-            cx.common.alpn_protocol = our_protocols
-                .iter()
-                .find(|protocol| their_protocols.contains(&protocol.as_slice()))
-                .cloned();
-            if let Some(ref selected_protocol) = cx.common.alpn_protocol {
-                debug!("Chosen ALPN protocol {:?}", selected_protocol);
-                self.exts
-                    .push(ServerExtension::make_alpn(&[selected_protocol]));
-            } else if !our_protocols.is_empty() {
-                cx.common
-                    .send_fatal_alert(AlertDescription::NoApplicationProtocol);
-                return Err(Error::NoApplicationProtocol);
-            }
+        // ALPN: the protocol was chosen before the certificate, by
+        // `choose_alpn_protocol`.
+        if let Some(ref selected_protocol) = cx.common.alpn_protocol {
+            self.exts
+                .push(ServerExtension::make_alpn(&[selected_protocol]));
         }
 
         #[cfg(feature = "quic")]
@@ -126,7 +129,7 @@ impl ExtensionProcessing {
                 // successful establishment of connections between peers that can't understand
                 // each other.
                 if cx.common.alpn_protocol.is_none()
-                    && (!our_protocols.is_empty() || maybe_their_protocols.is_some())
+                    && (!config.alpn_protocols.is_empty() || hello.get_alpn_extension().is_some())
                 {
                     cx.common
                         .send_fatal_alert(AlertDescription::NoApplicationProtocol);
@@ -134,7 +137,10 @@ impl ExtensionProcessing {
                 }
 
                 match hello.get_quic_params_extension() {
-                    Some(params) => cx.common.quic.params = Some(params),
+                    Some((typ, params)) => cx
+                        .common
+                        .quic
+                        .received_params(typ, params),
                     None => {
                         return Err(cx
                             .common
@@ -187,6 +193,34 @@ impl ExtensionProcessing {
             sct_list.take();
         }
 
+        let their_record_size_limit = hello.get_record_size_limit();
+        if their_record_size_limit.map_or(false, |limit| limit < MIN_RECORD_SIZE_LIMIT) {
+            return Err(cx
+                .common
+                .illegal_param("client sent record_size_limit below 64"));
+        }
+
+        // The two directions' record_size_limits are independent.  RFC8449
+        // says to ignore max_fragment_length when using them.
+        if let (Some(theirs), Some(ours)) = (their_record_size_limit, config.record_size_limit) {
+            let ours = cmp::min(ours, max_record_size_limit(cx.common.is_tls13()));
+            self.exts
+                .push(ServerExtension::RecordSizeLimit(ours));
+            cx.common
+                .limit_outgoing_record_size(theirs);
+            cx.common
+                .set_incoming_record_limit(Some(ours));
+        } else if let Some(mfl) = hello.get_max_fragment_length() {
+            // Agree to whatever record size limit the client asked for.
+            let max_len = mfl.fragment_len().ok_or_else(|| {
+                cx.common
+                    .illegal_param("client sent unknown max_fragment_length")
+            })?;
+            self.exts
+                .push(ServerExtension::MaxFragmentLength(mfl));
+            cx.common.limit_fragment_len(max_len);
+        }
+
         self.exts.extend(extra_exts);
 
         Ok(())
@@ -234,6 +268,76 @@ impl ExtensionProcessing {
     }
 }
 
+/// Chooses the ALPN protocol for this connection, recording it in
+/// `cx.common.alpn_protocol`.
+///
+/// This happens before a certificate is resolved, so that resolvers
+/// can see the protocol through [`ClientHello::alpn_protocol`].
+fn choose_alpn_protocol(
+    config: &ServerConfig,
+    cx: &mut ServerContext<'_>,
+    hello: &ClientHelloPayload,
+    sig_schemes: &[SignatureScheme],
+) -> Result<(), Error> {
+    cx.common.alpn_protocol = None;
+    let our_protocols = &config.alpn_protocols;
+    let maybe_their_protocols = hello.get_alpn_extension();
+    let their_protocols = match maybe_their_protocols {
+        Some(their_protocols) => their_protocols.to_slices(),
+        None => return Ok(()),
+    };
+
+    if their_protocols
+        .iter()
+        .any(|protocol| protocol.is_empty())
+    {
+        return Err(Error::PeerMisbehavedError(
+            "client offered empty ALPN protocol".to_string(),
+        ));
+    }
+
+    cx.common.alpn_protocol = match &config.alpn_selector {
+        Some(selector) => {
+            let client_hello = ClientHello::new(
+                &cx.data.sni,
+                sig_schemes,
+                offered_sig_schemes(hello),
+                maybe_their_protocols,
+                None,
+                &hello.cipher_suites,
+                Some(hello),
+            );
+
+            let selected = selector(&client_hello, &their_protocols);
+            if let Some(ref protocol) = selected {
+                if !their_protocols.contains(&protocol.as_slice()) {
+                    cx.common
+                        .send_fatal_alert(AlertDescription::InternalError);
+                    return Err(Error::General(
+                        "ALPN selector chose a protocol the client did not offer".to_string(),
+                    ));
+                }
+            }
+            selected
+        }
+        None => our_protocols
+            .iter()
+            .find(|protocol| their_protocols.contains(&protocol.as_slice()))
+            .cloned(),
+    };
+    if let Some(ref selected_protocol) = cx.common.alpn_protocol {
+        debug!("Chosen ALPN protocol {:?}", selected_protocol);
+    } else if !our_protocols.is_empty() {
+        if config.alpn_mismatch_is_fatal {
+            cx.common
+                .send_fatal_alert(AlertDescription::NoApplicationProtocol);
+            return Err(Error::NoApplicationProtocol);
+        }
+        debug!("No ALPN protocol agreed; continuing without one");
+    }
+    Ok(())
+}
+
 pub(super) struct ExpectClientHello {
     pub(super) config: Arc<ServerConfig>,
     pub(super) extra_exts: Vec<ServerExtension>,
@@ -315,13 +419,31 @@ impl ExpectClientHello {
 
         cx.common.negotiated_version = Some(version);
 
+        choose_alpn_protocol(&self.config, cx, client_hello, &sig_schemes)?;
+
+        // A TLS1.3 handshake using an external PSK is authenticated by
+        // that, so needs no certificate.
+        let external_psk = match version {
+            ProtocolVersion::TLSv1_3 => find_external_psk(&self.config, client_hello),
+            _ => None,
+        };
+
         // Choose a certificate.
-        let certkey = {
+        let certified_key = if external_psk.is_some() {
+            None
+        } else {
+            let sni = match &self.config.sni_normalizer {
+                Some(normalizer) => normalize_sni(normalizer.as_ref(), client_hello, cx.common)?,
+                None => cx.data.sni.clone(),
+            };
             let client_hello = ClientHello::new(
-                &cx.data.sni,
+                &sni,
                 &sig_schemes,
+                offered_sig_schemes(client_hello),
                 client_hello.get_alpn_extension(),
+                cx.common.alpn_protocol.as_deref(),
                 &client_hello.cipher_suites,
+                Some(client_hello),
             );
 
             let certkey = self
@@ -329,21 +451,33 @@ impl ExpectClientHello {
                 .cert_resolver
                 .resolve(client_hello);
 
-            certkey.ok_or_else(|| {
+            Some(certkey.ok_or_else(|| {
                 cx.common
                     .send_fatal_alert(AlertDescription::AccessDenied);
                 Error::General("no server certificate chain resolved".to_string())
-            })?
+            })?)
         };
-        let certkey = ActiveCertifiedKey::from_certified_key(&certkey);
+        let certkey = certified_key
+            .as_deref()
+            .map(ActiveCertifiedKey::from_certified_key);
 
         // Reduce our supported ciphersuites by the certificate.
         // (no-op for TLS1.3)
-        let suitable_suites =
-            suites::reduce_given_sigalg(&self.config.cipher_suites, certkey.get_key().algorithm());
+        let suitable_suites = match &certkey {
+            Some(certkey) => suites::reduce_given_sigalg(
+                &self.config.cipher_suites,
+                certkey.get_key().algorithm(),
+            ),
+            None => self.config.cipher_suites.clone(),
+        };
 
         // And version
-        let suitable_suites = suites::reduce_given_version(&suitable_suites, version);
+        let mut suitable_suites = suites::reduce_given_version(&suitable_suites, version);
+
+        // And the PSK's hash function.
+        if let Some(psk) = &external_psk {
+            suitable_suites.retain(|suite| suite.hash_algorithm() == psk.hash_algorithm());
+        }
 
         let suite = if self.config.ignore_client_order {
             suites::choose_ciphersuite_preferring_server(
@@ -363,8 +497,20 @@ impl ExpectClientHello {
 
         // Start handshake hash.
         let starting_hash = suite.hash_algorithm();
+        let mut done_retry = self.done_retry;
         let transcript = match self.transcript {
-            HandshakeHashOrBuffer::Buffer(inner) => inner.start_hash(starting_hash),
+            HandshakeHashOrBuffer::Buffer(mut inner) => {
+                if let Some(suite) = suite.tls13() {
+                    done_retry = tls13::follow_stateless_retry(
+                        &self.config,
+                        suite,
+                        client_hello,
+                        &mut inner,
+                        cx.common,
+                    )?;
+                }
+                inner.start_hash(starting_hash)
+            }
             HandshakeHashOrBuffer::Hash(inner) if inner.algorithm() == starting_hash => inner,
             _ => {
                 return Err(cx
@@ -374,16 +520,18 @@ impl ExpectClientHello {
         };
 
         // Save their Random.
-        let randoms = ConnectionRandoms::new(client_hello.random, Random::new()?);
+        let randoms =
+            ConnectionRandoms::new(client_hello.random, Random::new_from(self.config.random())?);
         match suite {
             SupportedCipherSuite::Tls13(suite) => tls13::CompleteClientHelloHandling {
                 config: self.config,
                 transcript,
                 suite,
                 randoms,
-                done_retry: self.done_retry,
+                done_retry,
                 send_ticket: self.send_ticket,
                 extra_exts: self.extra_exts,
+                external_psk,
             }
             .handle_client_hello(cx, certkey, m, client_hello, sig_schemes),
             #[cfg(feature = "tls12")]
@@ -399,7 +547,9 @@ impl ExpectClientHello {
             }
             .handle_client_hello(
                 cx,
-                certkey,
+                certkey.ok_or_else(|| {
+                    Error::General("no server certificate chain resolved".to_string())
+                })?,
                 m,
                 client_hello,
                 sig_schemes,
@@ -411,6 +561,19 @@ impl ExpectClientHello {
 
 impl State<ServerConnectionData> for ExpectClientHello {
     fn handle(self: Box<Self>, cx: &mut ServerContext<'_>, m: Message) -> NextStateOrError {
+        // A client answering a stateless HelloRetryRequest may send its
+        // middlebox compatibility CCS ahead of the second ClientHello.
+        if let MessagePayload::ChangeCipherSpec(_) = m.payload {
+            if self
+                .config
+                .hello_retry_cookies
+                .is_some()
+                && cx.common.drop_early_middlebox_ccs()
+            {
+                return Ok(self);
+            }
+        }
+
         let (client_hello, sig_schemes) = process_client_hello(
             &m,
             self.done_retry,
@@ -422,6 +585,79 @@ impl State<ServerConnectionData> for ExpectClientHello {
     }
 }
 
+/// Finds the first PSK offered in `client_hello` which is one of our
+/// external PSKs, and which we can use with one of the TLS1.3 suites
+/// it offers.
+///
+/// The binder is checked later, once the suite is chosen.
+fn find_external_psk(
+    config: &ServerConfig,
+    client_hello: &ClientHelloPayload,
+) -> Option<ExternalPsk> {
+    if config.external_psks.is_empty()
+        || !client_hello.psk_mode_offered(PSKKeyExchangeMode::PSK_DHE_KE)
+    {
+        return None;
+    }
+
+    let usable = |psk: &ExternalPsk| {
+        config
+            .cipher_suites
+            .iter()
+            .filter(|suite| {
+                client_hello
+                    .cipher_suites
+                    .contains(&suite.suite())
+            })
+            .filter_map(SupportedCipherSuite::tls13)
+            .any(|suite| suite.hash_algorithm() == psk.hash_algorithm())
+    };
+
+    client_hello
+        .get_psk()?
+        .identities
+        .iter()
+        .find_map(|offered| {
+            config
+                .external_psks
+                .iter()
+                .find(|psk| psk.identity() == &offered.identity.0[..] && usable(psk))
+        })
+        .cloned()
+}
+
+/// Rewrites the SNI hostname in `client_hello` with `normalizer`, failing
+/// the handshake if it is rejected.
+fn normalize_sni(
+    normalizer: &(dyn Fn(&str) -> Option<String> + Send + Sync),
+    client_hello: &ClientHelloPayload,
+    common: &mut CommonState,
+) -> Result<Option<webpki::DnsName>, Error> {
+    let raw = match client_hello
+        .get_sni_extension()
+        .and_then(|sni| sni.get_single_raw_hostname())
+    {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+
+    let normalized = std::str::from_utf8(raw)
+        .ok()
+        .and_then(normalizer)
+        .and_then(|name| {
+            webpki::DnsNameRef::try_from_ascii_str(&name)
+                .ok()
+                .map(webpki::DnsName::from)
+        });
+    match normalized {
+        Some(name) => Ok(Some(name)),
+        None => {
+            common.send_fatal_alert(AlertDescription::UnrecognisedName);
+            Err(Error::General("SNI hostname was rejected".to_string()))
+        }
+    }
+}
+
 /// Configuration-independent validation of a `ClientHello` message.
 ///
 /// This represents the first part of the `ClientHello` handling, where we do all validation that
@@ -1,23 +1,33 @@
 use crate::builder::{ConfigBuilder, WantsCipherSuites};
-use crate::conn::{CommonState, ConnectionCommon, Side, State};
+use crate::compress::CertCompressor;
+use crate::conn::{AlertCallback, CommonState, ConnectionCommon, HandshakeDetails};
+use crate::conn::{RenegotiationResponse, Side, State};
 use crate::error::Error;
-use crate::kx::SupportedKxGroup;
+use crate::key;
+use crate::kx::{self, SupportedKxGroup};
 #[cfg(feature = "logging")]
 use crate::log::trace;
 use crate::msgs::base::{Payload, PayloadU8};
 #[cfg(feature = "quic")]
 use crate::msgs::enums::AlertDescription;
+#[cfg(feature = "quic")]
+use crate::msgs::enums::ExtensionType;
+use crate::msgs::enums::NamedGroup;
 use crate::msgs::enums::ProtocolVersion;
 use crate::msgs::enums::SignatureScheme;
+use crate::msgs::fragmenter::{max_record_size_limit, MIN_RECORD_SIZE_LIMIT};
 use crate::msgs::handshake::{ClientHelloPayload, ServerExtension};
+use crate::msgs::hsjoiner;
 use crate::msgs::message::Message;
+use crate::rand::{RandomSource, SystemRandomSource};
 use crate::suites::SupportedCipherSuite;
+use crate::tls13::ExternalPsk;
 use crate::vecbuf::ChunkVecBuffer;
 use crate::verify;
-use crate::KeyLog;
 #[cfg(feature = "quic")]
 use crate::{conn::Protocol, quic};
 use crate::{sign, CipherSuite};
+use crate::{HandshakeObserver, KeyLog};
 
 use super::hs;
 
@@ -97,6 +107,22 @@ pub trait ProducesTickets: Send + Sync {
     fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>>;
 }
 
+/// A trait for detecting replayed TLS1.3 early data.
+///
+/// Early data is not protected against replay by the handshake: an
+/// attacker can resend a recorded `ClientHello` and its early data to
+/// any server that will accept the same ticket.  Tickets from
+/// [`ServerConfig::session_storage`] are normally only usable once, but a
+/// store shared between servers may not guarantee that.
+pub trait ChecksEarlyDataReplay: Send + Sync {
+    /// Returns true if this is the first time the ticket `ticket_id`
+    /// has been used for early data, and records that it has now been.
+    ///
+    /// Return false to reject the early data.  The handshake then
+    /// continues as normal without it.
+    fn single_use_ticket_check(&self, ticket_id: &[u8]) -> bool;
+}
+
 /// How to choose a certificate chain and signing key for use
 /// in server authentication.
 pub trait ResolvesServerCert: Send + Sync {
@@ -107,12 +133,20 @@ pub trait ResolvesServerCert: Send + Sync {
     fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>>;
 }
 
+/// A function that chooses an ALPN protocol for a connection.
+///
+/// See [`ServerConfig::alpn_selector`].
+pub type AlpnSelector = dyn Fn(&ClientHello, &[&[u8]]) -> Option<Vec<u8>> + Send + Sync;
+
 /// A struct representing the received Client Hello
 pub struct ClientHello<'a> {
     server_name: &'a Option<webpki::DnsName>,
     signature_schemes: &'a [SignatureScheme],
+    offered_signature_schemes: &'a [SignatureScheme],
     alpn: Option<&'a Vec<PayloadU8>>,
+    alpn_protocol: Option<&'a [u8]>,
     cipher_suites: &'a [CipherSuite],
+    payload: Option<&'a ClientHelloPayload>,
 }
 
 impl<'a> ClientHello<'a> {
@@ -120,19 +154,26 @@ impl<'a> ClientHello<'a> {
     pub(super) fn new(
         server_name: &'a Option<webpki::DnsName>,
         signature_schemes: &'a [SignatureScheme],
+        offered_signature_schemes: &'a [SignatureScheme],
         alpn: Option<&'a Vec<PayloadU8>>,
+        alpn_protocol: Option<&'a [u8]>,
         cipher_suites: &'a [CipherSuite],
+        payload: Option<&'a ClientHelloPayload>,
     ) -> Self {
         trace!("sni {:?}", server_name);
         trace!("sig schemes {:?}", signature_schemes);
+        trace!("offered sig schemes {:?}", offered_signature_schemes);
         trace!("alpn protocols {:?}", alpn);
         trace!("cipher suites {:?}", cipher_suites);
 
         ClientHello {
             server_name,
             signature_schemes,
+            offered_signature_schemes,
             alpn,
+            alpn_protocol,
             cipher_suites,
+            payload,
         }
     }
 
@@ -147,11 +188,28 @@ impl<'a> ClientHello<'a> {
 
     /// Get the compatible signature schemes.
     ///
+    /// This is the client's `signature_algorithms` extension reduced to
+    /// the schemes usable with the cipher suites we have in common, which
+    /// is what a certificate resolver usually wants.  It is therefore
+    /// *not* the list the client sent: for example, if we only share
+    /// `TLS_ECDHE_RSA_*` suites with the client, any ECDSA schemes it
+    /// offered are left out.  Use [`ClientHello::offered_signature_schemes`]
+    /// for the list as it appeared on the wire.
+    ///
     /// Returns standard-specified default if the client omitted this extension.
     pub fn signature_schemes(&self) -> &[SignatureScheme] {
         self.signature_schemes
     }
 
+    /// Get the signature schemes exactly as the client offered them.
+    ///
+    /// Unlike [`ClientHello::signature_schemes`], this is not filtered
+    /// and keeps the client's order.  It is empty if the client omitted
+    /// the `signature_algorithms` extension.
+    pub fn offered_signature_schemes(&self) -> &[SignatureScheme] {
+        self.offered_signature_schemes
+    }
+
     /// Get the alpn.
     ///
     /// Returns `None` if the client did not include an ALPN extension
@@ -163,10 +221,39 @@ impl<'a> ClientHello<'a> {
         })
     }
 
-    /// Get cipher suites.
+    /// Get the ALPN protocol the server has chosen from those the
+    /// client offered.
+    ///
+    /// The protocol is chosen before the certificate, so this is set
+    /// for [`ResolvesServerCert::resolve`] and agrees with what the
+    /// handshake goes on to negotiate.  It is `None` if no protocol
+    /// was agreed, and also wherever the choice has not been made yet:
+    /// in [`ServerConfig::alpn_selector`] and [`Accepted::client_hello`].
+    pub fn alpn_protocol(&self) -> Option<&'a [u8]> {
+        self.alpn_protocol
+    }
+
+    /// Get the cipher suites exactly as the client offered them,
+    /// in the client's order.
     pub fn cipher_suites(&self) -> &[CipherSuite] {
         self.cipher_suites
     }
+
+    /// Get the whole `ClientHello` handshake message, starting with
+    /// its four byte handshake header.
+    ///
+    /// These are exactly the bytes added to the handshake transcript
+    /// hash, so can be used to inspect anything the other accessors
+    /// don't cover, or to bind something to this particular handshake.
+    ///
+    /// The message is encoded afresh by each call, so that handshakes
+    /// which never look at it don't pay for it: call this once and
+    /// keep the result if you need it more than once.
+    pub fn raw(&self) -> Vec<u8> {
+        self.payload
+            .map(hs::client_hello_encoding)
+            .unwrap_or_default()
+    }
 }
 
 /// Common configuration for a set of server sessions.
@@ -178,10 +265,27 @@ impl<'a> ClientHello<'a> {
 ///
 /// # Defaults
 ///
+/// * [`ServerConfig::require_preferred_kx_group`]: any key share in a supported group is used.
+/// * [`ServerConfig::require_ems`]: TLS1.2 clients are not required to use the extended master secret.
 /// * [`ServerConfig::max_fragment_size`]: the default is `None`: TLS packets are not fragmented to a specific size.
+/// * [`ServerConfig::record_size_limit`]: the default is `None`: no record size limit is negotiated.
+/// * [`ServerConfig::max_handshake_message_size`]: handshake messages of up to 64KB are accepted.
+/// * [`ServerConfig::max_handshake_size`]: the client's handshake messages may total up to 256KB.
+/// * [`ServerConfig::max_client_hello_records`]: the `ClientHello` may be split over up to 32 records.
+/// * [`ServerConfig::cert_compressors`]: the default is empty -- certificates are sent uncompressed.
+/// * [`ServerConfig::max_peer_cert_chain_len`]: the client may present up to 10 certificates.
 /// * [`ServerConfig::session_storage`]: the default stores 256 sessions in memory.
+/// * [`ServerConfig::send_tls13_tickets`]: one ticket is sent after each TLS1.3 handshake.
+/// * [`ServerConfig::hello_retry_cookies`]: `HelloRetryRequest` state is kept in the connection.
+/// * [`ServerConfig::sni_normalizer`]: the SNI hostname is only folded to lowercase.
+/// * [`ServerConfig::external_psks`]: the default is empty -- no external PSKs are accepted.
 /// * [`ServerConfig::alpn_protocols`]: the default is empty -- no ALPN protocol is negotiated.
+/// * [`ServerConfig::alpn_mismatch_is_fatal`]: failing to agree an ALPN protocol fails the handshake.
 /// * [`ServerConfig::key_log`]: key material is not logged.
+/// * [`ServerConfig::handshake_observer`]: handshakes are not observed.
+/// * [`ServerConfig::on_alert`]: received alerts are not reported.
+/// * [`ServerConfig::renegotiation_response`]: TLS1.2 renegotiation is declined with a warning alert.
+/// * [`ServerConfig::early_data_anti_replay`]: no extra replay protection for early data.
 #[derive(Clone)]
 pub struct ServerConfig {
     /// List of ciphersuites, in preference order.
@@ -198,6 +302,22 @@ pub struct ServerConfig {
     /// which is supported by the client.
     pub ignore_client_order: bool,
 
+    /// Whether a TLS1.3 client must use the first of our key exchange
+    /// groups which it supports.  If its key shares are all for other
+    /// groups, it is asked for one in that group with a
+    /// `HelloRetryRequest`, even if one of them would do.
+    ///
+    /// The default is false: any key share in a group we support is
+    /// used, saving a round trip.
+    pub require_preferred_kx_group: bool,
+
+    /// Whether to refuse TLS1.2 clients which don't offer the extended
+    /// master secret (RFC7627), with a `handshake_failure` alert.
+    /// Sessions without it are open to the "triple handshake" attack.
+    ///
+    /// This has no effect on TLS1.3.  The default is false.
+    pub require_ems: bool,
+
     /// The maximum size of TLS message we'll emit.  If None, we don't limit TLS
     /// message lengths except to the 2**16 limit specified in the standard.
     ///
@@ -207,19 +327,149 @@ pub struct ServerConfig {
     /// Setting this value to the TCP MSS may improve latency for stream-y workloads.
     pub max_fragment_size: Option<usize>,
 
+    /// The largest record we'll accept from clients which send their own
+    /// RFC8449 `record_size_limit`, told to them in reply.  This counts
+    /// the record's plaintext and, in TLS1.3, its inner content type byte.
+    ///
+    /// We respect the client's limit when sending, and refuse protected
+    /// records from the client larger than ours, other than early data,
+    /// with a `record_overflow` alert.  As RFC8449 requires, such clients'
+    /// `max_fragment_length` requests are ignored.
+    ///
+    /// The default is `None`: the extension is ignored.  Values below 64,
+    /// or above TLS1.3's maximum of 2^14+1, are reported as errors from
+    /// ServerConnection::new.  TLS1.2 connections use at most 2^14.
+    pub record_size_limit: Option<u16>,
+
+    /// The largest handshake message we'll accept from the client,
+    /// excluding its four byte header.  Larger messages are refused as
+    /// soon as their header arrives, with a `decode_error` alert.
+    ///
+    /// The default is 65535 bytes.
+    pub max_handshake_message_size: usize,
+
+    /// The most handshake message bytes we'll accept from the client
+    /// before the handshake completes, counting headers.  Once this is
+    /// exceeded the handshake fails with a `decode_error` alert.
+    ///
+    /// The default is 256KB.
+    pub max_handshake_size: usize,
+
+    /// The most records the client's first `ClientHello` may be split
+    /// across.  A `ClientHello` arriving in more, which only makes
+    /// sense as an attempt to waste our time, fails the handshake with
+    /// a `decode_error` alert.  For QUIC, each call to `read_hs` counts
+    /// as a record.
+    ///
+    /// An [`Acceptor`] reads the `ClientHello` before it has a config,
+    /// so always applies the default.
+    ///
+    /// The default is 32.
+    pub max_client_hello_records: usize,
+
+    /// Algorithms we may compress our TLS1.3 certificate with, in
+    /// preference order.  The first one the client offers is used,
+    /// unless it declines to compress the certificate.
+    ///
+    /// The default is empty: certificates are sent uncompressed.
+    pub cert_compressors: Vec<Arc<dyn CertCompressor>>,
+
+    /// The most certificates, end-entity included, we'll accept in
+    /// the client's certificate chain.  A longer chain is refused with a
+    /// `bad_certificate` alert, without being verified.
+    ///
+    /// The default is 10.
+    pub max_peer_cert_chain_len: usize,
+
     /// How to store client sessions.
     pub session_storage: Arc<dyn StoresServerSessions + Send + Sync>,
 
     /// How to produce tickets.
     pub ticketer: Arc<dyn ProducesTickets>,
 
+    /// How many TLS1.3 tickets to send after each handshake, so
+    /// that clients can use a different ticket for each resumption.
+    ///
+    /// If this is zero, no tickets are sent and TLS1.3 clients cannot
+    /// resume.  Values above 8 are treated as 8.
+    pub send_tls13_tickets: usize,
+
+    /// If set, TLS1.3 `HelloRetryRequest`s are sent statelessly: what
+    /// the server needs to carry on is put in the request's cookie,
+    /// encrypted and authenticated with this, and recovered from the
+    /// cookie the client echoes in its second `ClientHello`.  The
+    /// connection keeps nothing in the meantime, and the second
+    /// `ClientHello` may be given to a new `ServerConnection`.
+    ///
+    /// To spread a handshake over several servers, they must share
+    /// the keys of this `ProducesTickets`; a [`Ticketer`](crate::Ticketer)
+    /// is only good for one process.  A cookie which cannot be
+    /// decrypted, or which is more than a minute old, fails the
+    /// handshake with an `illegal_parameter` alert; so does a second
+    /// `ClientHello` whose key share isn't for the group the
+    /// `HelloRetryRequest` asked for.
+    pub hello_retry_cookies: Option<Arc<dyn ProducesTickets>>,
+
     /// How to choose a server cert and key.
     pub cert_resolver: Arc<dyn ResolvesServerCert>,
 
+    /// TLS1.3 PSKs provisioned out of band, by which clients offering
+    /// them can authenticate us in place of a certificate.
+    ///
+    /// When a client offers one of these, `cert_resolver` is not
+    /// consulted, and only cipher suites with the PSK's hash function
+    /// are chosen.  No client certificate is asked for, and no tickets
+    /// are issued.  Otherwise the handshake continues with certificates,
+    /// so a server without any (whose `cert_resolver` resolves nothing)
+    /// refuses clients which don't offer a PSK it knows.
+    ///
+    /// The default is empty.
+    pub external_psks: Vec<ExternalPsk>,
+
+    /// Rewrites the SNI hostname, exactly as the client sent it, before
+    /// `cert_resolver` sees it.  This can fold case, remove a trailing
+    /// dot, or convert IDNs; returning `None` (or a name that isn't
+    /// valid) rejects the name, and the handshake fails with an
+    /// `unrecognized_name` alert.
+    ///
+    /// The normalizer does not change [`ServerConnection::sni_hostname`],
+    /// which reports the client's name folded to lowercase.  The default
+    /// is `None`: the resolver gets that same lowercase name.
+    pub sni_normalizer: Option<Arc<dyn Fn(&str) -> Option<String> + Send + Sync>>,
+
     /// Protocol names we support, most preferred first.
     /// If empty we don't do ALPN at all.
     pub alpn_protocols: Vec<Vec<u8>>,
 
+    /// Overrides how an ALPN protocol is chosen.
+    ///
+    /// If set, this is called with the `ClientHello` and the
+    /// protocols offered by the client, in the client's order,
+    /// instead of choosing from `alpn_protocols`.  It returns the
+    /// protocol to agree, which must be one the client offered,
+    /// or `None` to decline.
+    ///
+    /// Declining is treated like finding no overlap with
+    /// `alpn_protocols`: if that list is non-empty the handshake
+    /// fails with a `no_application_protocol` alert, unless
+    /// `alpn_mismatch_is_fatal` is false; otherwise it continues
+    /// without ALPN.  The default is `None`.
+    pub alpn_selector: Option<Arc<AlpnSelector>>,
+
+    /// Whether failing to agree an ALPN protocol with a client that
+    /// offered some, while `alpn_protocols` is non-empty, fails the
+    /// handshake with a `no_application_protocol` alert, as RFC7301
+    /// requires.
+    ///
+    /// If false, the handshake completes with no protocol agreed, and
+    /// [`ServerConnection::alpn_protocol`] returns `None`.  This is
+    /// ignored for QUIC, which always requires a protocol to be agreed.
+    ///
+    /// The default is true.
+    ///
+    /// [`ServerConnection::alpn_protocol`]: crate::CommonState::alpn_protocol
+    pub alpn_mismatch_is_fatal: bool,
+
     /// Supported protocol versions, in no particular order.
     /// The default is all supported versions.
     pub(super) versions: crate::versions::EnabledVersions,
@@ -227,10 +477,39 @@ pub struct ServerConfig {
     /// How to verify client certificates.
     pub(super) verifier: Arc<dyn verify::ClientCertVerifier>,
 
+    /// The signature schemes to ask client certificates to use, if not
+    /// all those `verifier` supports.
+    pub(super) signature_schemes: Option<Vec<SignatureScheme>>,
+
+    /// How to verify a client's raw public key, if we offer to accept
+    /// one in place of a certificate chain (RFC7250).
+    pub(super) raw_public_key_verifier: Option<Arc<dyn verify::RawPublicKeyVerifier>>,
+
+    /// Whether to offer to authenticate with a raw public key, rather
+    /// than the certificate chain chosen by `cert_resolver`.
+    pub(super) send_raw_public_key: bool,
+
+    /// Where to get the server random and other nonces from, if not
+    /// the system RNG.
+    pub(super) nonce_source: Option<Arc<dyn RandomSource>>,
+
     /// How to output key material for debugging.  The default
     /// does nothing.
     pub key_log: Arc<dyn KeyLog>,
 
+    /// Where to report the progress of each handshake, for example to
+    /// measure its duration.  The default is `None`: nothing is reported.
+    pub handshake_observer: Option<Arc<dyn HandshakeObserver>>,
+
+    /// Called with each alert received from the client, warnings
+    /// included, before rustls acts on it.  A fatal alert still ends
+    /// the connection.
+    pub on_alert: Option<AlertCallback>,
+
+    /// What to do when a TLS1.2 client sends a new `ClientHello` after
+    /// the handshake, asking to renegotiate.
+    pub renegotiation_response: RenegotiationResponse,
+
     /// Amount of early data to accept for sessions created by
     /// this config.  Specify 0 to disable early data.  The
     /// default is 0.
@@ -244,10 +523,21 @@ pub struct ServerConfig {
     /// expansion in the latter case.
     pub max_early_data_size: u32,
 
+    /// Consulted before accepting early data, to reject replays.
+    /// The default is `None`: early data is accepted whenever
+    /// `max_early_data_size` and the resumed session allow it.
+    pub early_data_anti_replay: Option<Arc<dyn ChecksEarlyDataReplay>>,
+
     /// Whether the server should send "0.5RTT" data.  This means the server
     /// sends data after its first flight of handshake messages, without
     /// waiting for the client to complete the handshake.
     ///
+    /// Data written before then is queued and sent straight after the
+    /// server's `Finished`; data written later, while the server is still
+    /// awaiting the client's `Finished`, is sent immediately.  A rustls
+    /// client makes this data readable as soon as it has processed the
+    /// server's `Finished`.
+    ///
     /// This can improve TTFB latency for either server-speaks-first protocols,
     /// or client-speaks-first protocols when paired with "0RTT" data.  This
     /// comes at the cost of a subtle weakening of the normal handshake
@@ -288,6 +578,92 @@ impl ServerConfig {
                 .iter()
                 .any(|cs| cs.version().version == v)
     }
+
+    pub(super) fn random(&self) -> &dyn RandomSource {
+        self.nonce_source
+            .as_deref()
+            .unwrap_or(&SystemRandomSource)
+    }
+
+    /// The signature schemes to list when asking for a client certificate:
+    /// those configured, in their configured order, that `verifier` supports.
+    pub(super) fn client_auth_signature_schemes(&self) -> Vec<SignatureScheme> {
+        let supported = self.verifier.supported_verify_schemes();
+        match &self.signature_schemes {
+            Some(schemes) => schemes
+                .iter()
+                .filter(|scheme| supported.contains(scheme))
+                .cloned()
+                .collect(),
+            None => supported,
+        }
+    }
+
+    fn check_record_size_limit(&self) -> Result<(), Error> {
+        match self.record_size_limit {
+            Some(limit) if limit < MIN_RECORD_SIZE_LIMIT || limit > max_record_size_limit(true) => {
+                Err(Error::BadMaxFragmentSize)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Access configuration options whose use is dangerous and requires
+    /// extra care.
+    #[cfg(feature = "dangerous_configuration")]
+    pub fn dangerous(&mut self) -> danger::DangerousServerConfig {
+        danger::DangerousServerConfig { cfg: self }
+    }
+}
+
+/// Container for unsafe APIs
+#[cfg(feature = "dangerous_configuration")]
+pub(super) mod danger {
+    use std::sync::Arc;
+
+    use super::verify::RawPublicKeyVerifier;
+    use super::ServerConfig;
+    use crate::rand::RandomSource;
+
+    /// Accessor for dangerous configuration options.
+    pub struct DangerousServerConfig<'a> {
+        /// The underlying ServerConfig
+        pub cfg: &'a mut ServerConfig,
+    }
+
+    impl<'a> DangerousServerConfig<'a> {
+        /// Offers to accept a raw public key (RFC7250) from clients in place
+        /// of a certificate chain.
+        ///
+        /// A client that agrees is always asked to authenticate, and must
+        /// present a key that `verifier` accepts; the `ClientCertVerifier`
+        /// is not used for it.  Clients that don't support raw public keys
+        /// are handled by the `ClientCertVerifier` as usual.  This only
+        /// applies to TLS1.3.
+        pub fn set_raw_public_key_verifier(&mut self, verifier: Arc<dyn RawPublicKeyVerifier>) {
+            self.cfg.raw_public_key_verifier = Some(verifier);
+        }
+
+        /// Offers to authenticate to clients with a raw public key (RFC7250).
+        ///
+        /// To clients that agree, the `SubjectPublicKeyInfo` of the end-entity
+        /// certificate chosen by `cert_resolver` is sent in place of the chain.
+        /// This only applies to TLS1.3.
+        pub fn set_send_raw_public_key(&mut self, send: bool) {
+            self.cfg.send_raw_public_key = send;
+        }
+
+        /// Takes the nonces for connections from `source`, rather than
+        /// the system RNG.  Never use this outside tests.
+        ///
+        /// `source` supplies the server random, TLS1.2 session IDs, TLS1.3
+        /// ticket nonces and identifiers, and post-handshake
+        /// authentication contexts.  Key shares and signatures are always
+        /// made with the system RNG, so handshakes are not reproducible.
+        pub fn set_nonce_source(&mut self, source: Arc<dyn RandomSource>) {
+            self.cfg.nonce_source = Some(source);
+        }
+    }
 }
 
 /// Allows reading of early data in resumed TLS1.3 connections.
@@ -331,18 +707,52 @@ impl ServerConnection {
         Self::from_config(config, vec![])
     }
 
+    /// Make a new ServerConnection that never resumes a session.
+    ///
+    /// This behaves like [`ServerConnection::new`], except that session IDs,
+    /// tickets and PSKs offered by the client are ignored, and a full
+    /// handshake is always performed.  The session storage and ticketer in
+    /// `config` remain in use for sessions issued on this connection.
+    pub fn new_without_resumption(config: Arc<ServerConfig>) -> Result<Self, Error> {
+        let mut new = Self::from_config(config, vec![])?;
+        new.inner.data.resumption_disabled = true;
+        Ok(new)
+    }
+
+    /// Make a new ServerConnection which only uses those of the key
+    /// exchange groups of `config` named in `kx_groups`.  A TLS1.3 client
+    /// whose key shares are all for other groups is sent a
+    /// `HelloRetryRequest` for one of these; if the client supports
+    /// none of them, the handshake fails.
+    ///
+    /// This fails if `config` has none of `kx_groups`.  Otherwise it
+    /// behaves like [`ServerConnection::new`].
+    pub fn new_with_kx_groups(
+        config: Arc<ServerConfig>,
+        kx_groups: &[NamedGroup],
+    ) -> Result<Self, Error> {
+        let mut config = (*config).clone();
+        kx::restrict_groups(&mut config.kx_groups, kx_groups)?;
+        Self::new(Arc::new(config))
+    }
+
     fn from_config(
         config: Arc<ServerConfig>,
         extra_exts: Vec<ServerExtension>,
     ) -> Result<Self, Error> {
-        let common = CommonState::new(config.max_fragment_size, Side::Server)?;
-        Ok(Self {
-            inner: ConnectionCommon::new(
-                Box::new(hs::ExpectClientHello::new(config, extra_exts)),
-                ServerConnectionData::default(),
-                common,
-            ),
-        })
+        config.check_record_size_limit()?;
+        let mut common = CommonState::new(config.max_fragment_size, Side::Server)?;
+        common.observe_handshake(config.handshake_observer.as_ref());
+        common.set_alert_callback(config.on_alert.as_ref());
+        common.set_renegotiation_response(config.renegotiation_response);
+        let mut inner = ConnectionCommon::new(
+            Box::new(hs::ExpectClientHello::new(config.clone(), extra_exts)),
+            ServerConnectionData::default(),
+            common,
+        );
+        inner.set_handshake_limits(config.max_handshake_message_size, config.max_handshake_size);
+        inner.limit_first_handshake_message_records(config.max_client_hello_records);
+        Ok(Self { inner })
     }
 
     /// Retrieves the SNI hostname, if any, used to select the certificate and
@@ -378,6 +788,33 @@ impl ServerConnection {
             .map(|x| &x[..])
     }
 
+    /// The identity from the client's TLS1.3 `pre_shared_key` extension
+    /// that we accepted, such as the ticket we issued in an earlier
+    /// session.
+    ///
+    /// This is `None` for full handshakes, and for TLS1.2 resumptions,
+    /// which don't use that extension.  It is known once the client's
+    /// `ClientHello` has been processed.
+    pub fn selected_psk_identity(&self) -> Option<&[u8]> {
+        self.inner
+            .data
+            .selected_psk_identity
+            .as_deref()
+    }
+
+    /// The certificate chain `ServerConfig::cert_resolver` chose for
+    /// this connection and we sent, end-entity certificate first.
+    ///
+    /// This is `None` until our `Certificate` message is sent, and for
+    /// resumptions and TLS1.3 PSK handshakes, which have none.  If we
+    /// sent a raw public key (RFC7250) this is the chain it came from.
+    pub fn sent_certificate_chain(&self) -> Option<&[key::Certificate]> {
+        self.inner
+            .data
+            .sent_certificate_chain
+            .as_deref()
+    }
+
     /// Set the resumption data to embed in future resumption tickets supplied to the client.
     ///
     /// Defaults to the empty byte string. Must be less than 2^15 bytes to allow room for other
@@ -426,6 +863,46 @@ impl ServerConnection {
             None
         }
     }
+
+    /// Returns true if this connection accepted the client's early data.
+    ///
+    /// Bytes read from [`ServerConnection::early_data`] are early data,
+    /// and may have been replayed; those read from
+    /// [`ConnectionCommon::reader`] never are.
+    pub fn is_early_data_accepted(&self) -> bool {
+        self.inner
+            .data
+            .early_data
+            .was_accepted()
+    }
+
+    /// Asks the client for a certificate after the handshake has completed,
+    /// using TLS1.3 post-handshake authentication (RFC 8446 section 4.6.2).
+    ///
+    /// This queues a `CertificateRequest` to be written by the next
+    /// `write_tls`.  The client answers when it next processes data, and
+    /// once its certificate has been verified by the configured
+    /// `ClientCertVerifier` it is available through `peer_certificates`.
+    /// If the client declines and client authentication is mandatory, the
+    /// connection fails with [`Error::NoCertificatesPresented`].
+    ///
+    /// This fails if the handshake is not yet complete, if the connection is
+    /// not TLS1.3 over TCP, if the client did not offer the
+    /// `post_handshake_auth` extension, if the server is not configured for
+    /// client authentication, or if an earlier request is still outstanding.
+    pub fn request_post_handshake_auth(&mut self) -> Result<(), Error> {
+        if self.is_handshaking() {
+            return Err(Error::HandshakeNotComplete);
+        }
+
+        if self.protocol_version() != Some(ProtocolVersion::TLSv1_3) || self.is_quic() {
+            return Err(Error::PeerIncompatibleError(
+                "post-handshake authentication requires TLS1.3 over TCP".to_string(),
+            ));
+        }
+
+        self.inner.request_post_handshake_auth()
+    }
 }
 
 impl fmt::Debug for ServerConnection {
@@ -468,9 +945,9 @@ impl Acceptor {
     pub fn new() -> Result<Self, Error> {
         let common = CommonState::new(None, Side::Server)?;
         let state = Box::new(Accepting);
-        Ok(Self {
-            inner: Some(ConnectionCommon::new(state, Default::default(), common)),
-        })
+        let mut inner = ConnectionCommon::new(state, Default::default(), common);
+        inner.limit_first_handshake_message_records(hsjoiner::DEFAULT_MAX_CLIENT_HELLO_RECORDS);
+        Ok(Self { inner: Some(inner) })
     }
 
     /// Returns true if the caller should call [`Connection::read_tls()`] as soon as possible.
@@ -532,7 +1009,7 @@ impl Acceptor {
         // `ALL_CIPHER_SUITES` here.
         let supported_cipher_suites = &crate::ALL_CIPHER_SUITES;
 
-        let (_, sig_schemes) = hs::process_client_hello(
+        let (client_hello, sig_schemes) = hs::process_client_hello(
             &message,
             false,
             supported_cipher_suites,
@@ -564,8 +1041,11 @@ impl Accepted {
         ClientHello::new(
             &self.connection.data.sni,
             &self.sig_schemes,
+            hs::offered_sig_schemes(payload),
             payload.get_alpn_extension(),
+            None,
             &payload.cipher_suites,
+            Some(payload),
         )
     }
 
@@ -575,9 +1055,21 @@ impl Accepted {
     /// [`sign::CertifiedKey`] that should be used for the session. Returns an error if
     /// configuration-dependent validation of the received `ClientHello` message fails.
     pub fn into_connection(mut self, config: Arc<ServerConfig>) -> Result<ServerConnection, Error> {
+        config.check_record_size_limit()?;
         self.connection
             .common_state
             .set_max_fragment_size(config.max_fragment_size)?;
+        self.connection
+            .common_state
+            .observe_handshake(config.handshake_observer.as_ref());
+        self.connection
+            .common_state
+            .set_alert_callback(config.on_alert.as_ref());
+        self.connection
+            .common_state
+            .set_renegotiation_response(config.renegotiation_response);
+        self.connection
+            .set_handshake_limits(config.max_handshake_message_size, config.max_handshake_size);
         let state = hs::ExpectClientHello::new(config, Vec::new());
         let mut cx = hs::ServerContext {
             common: &mut self.connection.common_state,
@@ -592,6 +1084,9 @@ impl Accepted {
         )?;
 
         self.connection.replace_state(new);
+        self.connection
+            .common_state
+            .update_handshake_observer();
         Ok(ServerConnection {
             inner: self.connection,
         })
@@ -702,8 +1197,12 @@ fn test_read_buf_in_new_state() {
 pub struct ServerConnectionData {
     pub(super) sni: Option<webpki::DnsName>,
     pub(super) received_resumption_data: Option<Vec<u8>>,
+    pub(super) selected_psk_identity: Option<Vec<u8>>,
+    pub(super) sent_certificate_chain: Option<Vec<key::Certificate>>,
     pub(super) resumption_data: Vec<u8>,
     pub(super) early_data: EarlyDataState,
+    pub(super) post_handshake_auth_offered: bool,
+    pub(super) resumption_disabled: bool,
 }
 
 impl ServerConnectionData {
@@ -714,6 +1213,15 @@ impl ServerConnectionData {
 
 impl crate::conn::SideData for ServerConnectionData {}
 
+impl ConnectionCommon<ServerConnectionData> {
+    /// Returns what was agreed with the client, including the SNI
+    /// hostname it sent, or `None` until the handshake is complete.
+    pub fn handshake_details(&self) -> Option<HandshakeDetails> {
+        self.common_state
+            .handshake_details(self.data.get_sni_str())
+    }
+}
+
 #[cfg(feature = "quic")]
 impl quic::QuicExt for ServerConnection {
     fn quic_transport_parameters(&self) -> Option<&[u8]> {
@@ -725,6 +1233,11 @@ impl quic::QuicExt for ServerConnection {
             .map(|v| v.as_ref())
     }
 
+    fn quic_transport_parameters_extension(&self) -> Option<(ExtensionType, &[u8])> {
+        let quic = &self.inner.common_state.quic;
+        Some((quic.params_extension?, quic.params.as_deref()?))
+    }
+
     fn zero_rtt_keys(&self) -> Option<quic::DirectionalKeys> {
         Some(quic::DirectionalKeys::new(
             self.inner
@@ -747,6 +1260,10 @@ impl quic::QuicExt for ServerConnection {
         quic::write_hs(&mut self.inner.common_state, buf)
     }
 
+    fn next_1rtt_keys(&mut self) -> Option<quic::PacketKeySet> {
+        quic::next_1rtt_keys(&mut self.inner.common_state)
+    }
+
     fn alert(&self) -> Option<AlertDescription> {
         self.inner.common_state.quic.alert
     }
@@ -787,3 +1304,43 @@ pub trait ServerQuicExt {
 
 #[cfg(feature = "quic")]
 impl ServerQuicExt for ServerConnection {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn client_hello_preserves_offered_lists() {
+        let sni = None;
+        let suites = [
+            CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+            CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV,
+            CipherSuite::TLS13_AES_128_GCM_SHA256,
+            CipherSuite::Unknown(0x1234),
+        ];
+        let offered_schemes = [
+            SignatureScheme::RSA_PKCS1_SHA1,
+            SignatureScheme::ED25519,
+            SignatureScheme::Unknown(0xfefe),
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+        ];
+        let compatible_schemes = [SignatureScheme::ECDSA_NISTP256_SHA256];
+
+        let hello = ClientHello::new(
+            &sni,
+            &compatible_schemes,
+            &offered_schemes,
+            None,
+            None,
+            &suites,
+            None,
+        );
+
+        assert_eq!(hello.cipher_suites(), &suites);
+        assert_eq!(hello.offered_signature_schemes(), &offered_schemes);
+        assert_eq!(hello.signature_schemes(), &compatible_schemes);
+        assert_eq!(hello.server_name(), None);
+        assert!(hello.alpn().is_none());
+        assert!(hello.alpn_protocol().is_none());
+    }
+}
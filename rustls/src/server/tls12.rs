@@ -67,6 +67,12 @@ mod client_hello {
 
             if client_hello.ems_support_offered() {
                 self.using_ems = true;
+                cx.common.observe_ems();
+            } else if self.config.require_ems {
+                return Err(hs::incompatible(
+                    cx.common,
+                    "client did not offer extended master secret",
+                ));
             }
 
             let groups_ext = client_hello
@@ -136,6 +142,12 @@ mod client_hello {
                 .and_then(|x| persist::ServerSessionValue::read_bytes(&x))
                 .filter(|resumedata| {
                     hs::can_resume(self.suite.into(), &cx.data.sni, self.using_ems, resumedata)
+                })
+                .filter(|_| {
+                    if cx.data.resumption_disabled {
+                        debug!("Not resuming, resumption disabled for this connection");
+                    }
+                    !cx.data.resumption_disabled
                 });
 
             if let Some(data) = resume_data {
@@ -174,7 +186,7 @@ mod client_hello {
             if !self.config.session_storage.can_cache() {
                 self.session_id = SessionID::empty();
             } else if self.session_id.is_empty() && !ticket_received {
-                self.session_id = SessionID::random()?;
+                self.session_id = SessionID::random_from(self.config.random())?;
             }
 
             self.send_ticket = emit_server_hello(
@@ -192,6 +204,7 @@ mod client_hello {
                 self.extra_exts,
             )?;
             emit_certificate(&mut self.transcript, cx.common, server_key.get_cert());
+            cx.data.sent_certificate_chain = Some(server_key.get_cert().to_vec());
             if let Some(ocsp_response) = ocsp_response {
                 emit_cert_status(&mut self.transcript, cx.common, ocsp_response);
             }
@@ -240,6 +253,7 @@ mod client_hello {
             resumedata: persist::ServerSessionValue,
         ) -> hs::NextStateOrError {
             debug!("Resuming connection");
+            cx.common.observe_resumption();
 
             if resumedata.extended_ms && !self.using_ems {
                 return Err(cx
@@ -366,6 +380,7 @@ mod client_hello {
 
         transcript.add_message(&c);
         common.send_msg(c, false);
+        common.observe_sent_certificate(cert_chain.first());
     }
 
     fn emit_cert_status(transcript: &mut HandshakeHash, common: &mut CommonState, ocsp: &[u8]) {
@@ -434,7 +449,7 @@ mod client_hello {
             return Ok(false);
         }
 
-        let verify_schemes = client_auth.supported_verify_schemes();
+        let verify_schemes = config.client_auth_signature_schemes();
 
         let names = client_auth
             .client_auth_root_subjects()
@@ -502,6 +517,8 @@ impl State<ServerConnectionData> for ExpectCertificate {
             HandshakeType::Certificate,
             HandshakePayload::Certificate
         )?;
+        cx.common
+            .check_cert_chain_len(cert_chain.len(), self.config.max_peer_cert_chain_len)?;
 
         // If we can't determine if the auth is mandatory, abort
         let mandatory = self
@@ -585,6 +602,7 @@ impl State<ServerConnectionData> for ExpectClientKx {
         // resulting premaster secret.
         let peer_kx_params =
             tls12::decode_ecdh_params::<ClientECDHParams>(cx.common, &client_kx.0)?;
+        let group = self.server_kx.group();
         let secrets = ConnectionSecrets::from_key_exchange(
             self.server_kx,
             &peer_kx_params.public.0,
@@ -592,6 +610,7 @@ impl State<ServerConnectionData> for ExpectClientKx {
             self.randoms,
             self.suite,
         )?;
+        cx.common.observe_key_exchange(group);
 
         self.config.key_log.log(
             "CLIENT_RANDOM",
@@ -5,19 +5,21 @@ use crate::hash_hs::HandshakeHash;
 use crate::key::Certificate;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace, warn};
+use crate::msgs::base::PayloadU8;
 use crate::msgs::codec::Codec;
 use crate::msgs::enums::{AlertDescription, KeyUpdateRequest};
 use crate::msgs::enums::{ContentType, HandshakeType, ProtocolVersion};
 use crate::msgs::handshake::HandshakeMessagePayload;
 use crate::msgs::handshake::HandshakePayload;
+use crate::msgs::handshake::{CertReqExtension, CertificateRequestPayloadTLS13};
 use crate::msgs::handshake::{NewSessionTicketExtension, NewSessionTicketPayloadTLS13};
 use crate::msgs::message::{Message, MessagePayload};
 use crate::msgs::persist;
-use crate::rand;
+use crate::rand::RandomSource;
 use crate::server::ServerConfig;
 use crate::ticketer;
 use crate::tls13::key_schedule::{KeyScheduleTraffic, KeyScheduleTrafficWithClientFinishedPending};
-use crate::tls13::Tls13CipherSuite;
+use crate::tls13::{ExternalPsk, Tls13CipherSuite};
 use crate::verify;
 #[cfg(feature = "quic")]
 use crate::{check::inappropriate_message, conn::Protocol};
@@ -25,25 +27,27 @@ use crate::{check::inappropriate_message, conn::Protocol};
 use super::hs::{self, HandshakeHashOrBuffer, ServerContext};
 use super::server_conn::ServerConnectionData;
 
+use std::cmp;
 use std::sync::Arc;
 
 use ring::constant_time;
 
-pub(super) use client_hello::CompleteClientHelloHandling;
+pub(super) use client_hello::{follow_stateless_retry, CompleteClientHelloHandling};
 
 mod client_hello {
+    use crate::compress::{self, CertCompressor};
+    use crate::hash_hs::HandshakeHashBuffer;
     use crate::kx;
-    use crate::msgs::base::{Payload, PayloadU8};
+    use crate::msgs::base::{Payload, PayloadU16, PayloadU8};
     use crate::msgs::ccs::ChangeCipherSpecPayload;
-    use crate::msgs::enums::{Compression, PSKKeyExchangeMode};
+    use crate::msgs::enums::{CertificateType, Compression, PSKKeyExchangeMode};
     use crate::msgs::enums::{NamedGroup, SignatureScheme};
-    use crate::msgs::handshake::CertReqExtension;
     use crate::msgs::handshake::CertificateEntry;
     use crate::msgs::handshake::CertificateExtension;
     use crate::msgs::handshake::CertificatePayloadTLS13;
-    use crate::msgs::handshake::CertificateRequestPayloadTLS13;
     use crate::msgs::handshake::CertificateStatus;
     use crate::msgs::handshake::ClientHelloPayload;
+    use crate::msgs::handshake::DelegatedCredentialPayload;
     use crate::msgs::handshake::DigitallySignedStruct;
     use crate::msgs::handshake::HelloRetryExtension;
     use crate::msgs::handshake::HelloRetryRequest;
@@ -59,6 +63,7 @@ mod client_hello {
     use crate::tls13::key_schedule::{
         KeyScheduleEarly, KeyScheduleHandshake, KeySchedulePreHandshake,
     };
+    use crate::x509;
 
     use super::*;
 
@@ -77,6 +82,7 @@ mod client_hello {
         pub(in crate::server) done_retry: bool,
         pub(in crate::server) send_ticket: bool,
         pub(in crate::server) extra_exts: Vec<ServerExtension>,
+        pub(in crate::server) external_psk: Option<ExternalPsk>,
     }
 
     fn max_early_data_size(configured: u32) -> usize {
@@ -100,6 +106,7 @@ mod client_hello {
             suite: &'static Tls13CipherSuite,
             client_hello: &Message,
             psk: &[u8],
+            external: bool,
             binder: &[u8],
         ) -> bool {
             let binder_plaintext = match client_hello.payload {
@@ -112,8 +119,11 @@ mod client_hello {
                 .get_hash_given(&binder_plaintext);
 
             let key_schedule = KeyScheduleEarly::new(suite.hkdf_algorithm, psk);
-            let real_binder =
-                key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash);
+            let real_binder = if external {
+                key_schedule.external_psk_binder_key_and_sign_verify_data(&handshake_hash)
+            } else {
+                key_schedule.resumption_psk_binder_key_and_sign_verify_data(&handshake_hash)
+            };
 
             constant_time::verify_slices_are_equal(real_binder.as_ref(), binder).is_ok()
         }
@@ -138,7 +148,7 @@ mod client_hello {
         pub(in crate::server) fn handle_client_hello(
             mut self,
             cx: &mut ServerContext<'_>,
-            server_key: ActiveCertifiedKey,
+            server_key: Option<ActiveCertifiedKey>,
             chm: &Message,
             client_hello: &ClientHelloPayload,
             mut sigschemes_ext: Vec<SignatureScheme>,
@@ -175,6 +185,11 @@ mod client_hello {
                     .illegal_param("client sent EarlyData in second ClientHello"));
             }
 
+            // Remember this for any later `request_post_handshake_auth`.
+            cx.data.post_handshake_auth_offered = client_hello.post_handshake_auth_offered();
+
+            let time_now = ticketer::TimeBase::now()?;
+
             // choose a share that we support
             let chosen_share = self
                 .config
@@ -184,6 +199,18 @@ mod client_hello {
                     shares_ext
                         .iter()
                         .find(|share| share.group == group.name)
+                })
+                .filter(|share| {
+                    // A retried ClientHello's share is taken as it is.
+                    !self.config.require_preferred_kx_group
+                        || self.done_retry
+                        || self
+                            .config
+                            .kx_groups
+                            .iter()
+                            .find(|group| groups_ext.contains(&group.name))
+                            .map(|group| group.name)
+                            == Some(share.group)
                 });
 
             let chosen_share = match chosen_share {
@@ -207,26 +234,49 @@ mod client_hello {
                                 .illegal_param("did not follow retry request"));
                         }
 
+                        let cookie = self
+                            .config
+                            .hello_retry_cookies
+                            .as_ref()
+                            .and_then(|cookies| {
+                                let state = persist::HelloRetryCookie::new(
+                                    self.suite.common.suite,
+                                    group.name,
+                                    self.transcript
+                                        .get_current_hash()
+                                        .as_ref(),
+                                    time_now,
+                                );
+                                cookies.encrypt(&state.get_encoding())
+                            });
+                        let stateless = cookie.is_some();
+
                         emit_hello_retry_request(
                             &mut self.transcript,
                             self.suite,
                             cx.common,
                             group.name,
+                            cookie,
                         );
                         emit_fake_ccs(cx.common);
 
                         let skip_early_data = max_early_data_size(self.config.max_early_data_size);
 
-                        let next = Box::new(hs::ExpectClientHello {
-                            config: self.config,
-                            transcript: HandshakeHashOrBuffer::Hash(self.transcript),
-                            #[cfg(feature = "tls12")]
-                            session_id: SessionID::empty(),
-                            #[cfg(feature = "tls12")]
-                            using_ems: false,
-                            done_retry: true,
-                            send_ticket: self.send_ticket,
-                            extra_exts: self.extra_exts,
+                        let next = Box::new(match stateless {
+                            // Start again, as a new connection would: the
+                            // second ClientHello's cookie says how to go on.
+                            true => hs::ExpectClientHello::new(self.config, self.extra_exts),
+                            false => hs::ExpectClientHello {
+                                config: self.config,
+                                transcript: HandshakeHashOrBuffer::Hash(self.transcript),
+                                #[cfg(feature = "tls12")]
+                                session_id: SessionID::empty(),
+                                #[cfg(feature = "tls12")]
+                                using_ems: false,
+                                done_retry: true,
+                                send_ticket: self.send_ticket,
+                                extra_exts: self.extra_exts,
+                            },
                         });
 
                         return if early_data_requested {
@@ -248,7 +298,7 @@ mod client_hello {
 
             let mut chosen_psk_index = None;
             let mut resumedata = None;
-            let time_now = ticketer::TimeBase::now()?;
+            let mut external_psk = None;
 
             if let Some(psk_offer) = client_hello.get_psk() {
                 if !client_hello.check_psk_ext_is_last() {
@@ -268,6 +318,37 @@ mod client_hello {
                 }
 
                 for (i, psk_id) in psk_offer.identities.iter().enumerate() {
+                    if let Some(psk) = self
+                        .external_psk
+                        .as_ref()
+                        .filter(|psk| psk.identity() == &psk_id.identity.0[..])
+                    {
+                        if !self.check_binder(
+                            self.suite,
+                            chm,
+                            psk.secret(),
+                            true,
+                            &psk_offer.binders[i].0,
+                        ) {
+                            cx.common
+                                .send_fatal_alert(AlertDescription::DecryptError);
+                            return Err(Error::PeerMisbehavedError(
+                                "client sent wrong binder".to_string(),
+                            ));
+                        }
+
+                        chosen_psk_index = Some(i);
+                        external_psk = self.external_psk.take();
+                        break;
+                    }
+
+                    // Don't even look the ticket up: with stateful
+                    // resumption that would consume it.
+                    if cx.data.resumption_disabled {
+                        debug!("Not resuming, resumption disabled for this connection");
+                        continue;
+                    }
+
                     let resume = match self
                         .attempt_tls13_ticket_decryption(&psk_id.identity.0)
                         .map(|resumedata| {
@@ -284,6 +365,7 @@ mod client_hello {
                         self.suite,
                         chm,
                         &resume.master_secret.0,
+                        false,
                         &psk_offer.binders[i].0,
                     ) {
                         cx.common
@@ -305,15 +387,91 @@ mod client_hello {
                 chosen_psk_index = None;
                 resumedata = None;
             } else {
-                self.send_ticket = true;
+                // Sessions authenticated by an external PSK aren't resumable:
+                // the client should keep using the PSK instead.
+                self.send_ticket = external_psk.is_none();
             }
 
             if let Some(ref resume) = resumedata {
+                cx.common.observe_resumption();
                 cx.data.received_resumption_data = Some(resume.application_data.0.clone());
                 cx.common.peer_certificates = resume.client_cert_chain.clone();
             }
 
-            let full_handshake = resumedata.is_none();
+            cx.data.selected_psk_identity = chosen_psk_index
+                .zip(client_hello.get_psk())
+                .map(|(i, psk_offer)| {
+                    psk_offer.identities[i]
+                        .identity
+                        .0
+                        .clone()
+                });
+
+            let full_handshake = chosen_psk_index.is_none();
+            let server_key = match server_key {
+                Some(server_key) if full_handshake => Some(server_key),
+                None if full_handshake => {
+                    cx.common
+                        .send_fatal_alert(AlertDescription::AccessDenied);
+                    return Err(Error::General(
+                        "no server certificate chain resolved".to_string(),
+                    ));
+                }
+                _ => None,
+            };
+
+            // Agree certificate types (RFC7250).  These are irrelevant
+            // when resuming, as no certificates are exchanged.
+            let mut server_raw_public_key = None;
+            let mut client_raw_public_key_verifier = None;
+            if let Some(server_key) = &server_key {
+                let own_spki = server_key
+                    .get_cert()
+                    .first()
+                    .filter(|_| self.config.send_raw_public_key)
+                    .and_then(|cert| x509::subject_public_key_info(&cert.0));
+
+                if let Some(offered) = client_hello.get_server_certificate_types() {
+                    let typ = choose_certificate_type(offered, own_spki.is_some())
+                        .ok_or_else(|| unsupported_certificate(cx.common, "server"))?;
+                    if typ == CertificateType::RawPublicKey {
+                        server_raw_public_key = own_spki.map(|spki| Certificate(spki.to_vec()));
+                    }
+                    self.extra_exts
+                        .push(ServerExtension::ServerCertificateType(typ));
+                }
+
+                // The client's certificate type is only worth mentioning if
+                // we're going to ask for a certificate.
+                if let Some(offered) = client_hello.get_client_certificate_types() {
+                    let offer_client_auth = self.config.verifier.offer_client_auth();
+                    let accept_raw_public_key = self
+                        .config
+                        .raw_public_key_verifier
+                        .is_some();
+                    match choose_certificate_type(offered, accept_raw_public_key) {
+                        Some(CertificateType::RawPublicKey) => {
+                            client_raw_public_key_verifier = self
+                                .config
+                                .raw_public_key_verifier
+                                .clone();
+                            self.extra_exts
+                                .push(ServerExtension::ClientCertificateType(
+                                    CertificateType::RawPublicKey,
+                                ));
+                        }
+                        Some(typ) if offer_client_auth => {
+                            self.extra_exts
+                                .push(ServerExtension::ClientCertificateType(typ));
+                        }
+                        None if offer_client_auth => {
+                            return Err(unsupported_certificate(cx.common, "client"));
+                        }
+                        _ => {}
+                    }
+                }
+            }
+
             self.transcript.add_message(chm);
             let key_schedule = emit_server_hello(
                 &mut self.transcript,
@@ -325,15 +483,27 @@ mod client_hello {
                 chosen_psk_index,
                 resumedata
                     .as_ref()
-                    .map(|x| &x.master_secret.0[..]),
+                    .map(|x| &x.master_secret.0[..])
+                    .or_else(|| {
+                        external_psk
+                            .as_ref()
+                            .map(ExternalPsk::secret)
+                    }),
                 &self.config,
             )?;
             if !self.done_retry {
                 emit_fake_ccs(cx.common);
             }
 
-            let (mut ocsp_response, mut sct_list) =
-                (server_key.get_ocsp(), server_key.get_sct_list());
+            let (mut ocsp_response, mut sct_list) = match (&server_key, &server_raw_public_key) {
+                // Neither can be attached to a raw public key.
+                (Some(server_key), None) => (server_key.get_ocsp(), server_key.get_sct_list()),
+                _ => (None, None),
+            };
+            let ticket_id = match (chosen_psk_index, client_hello.get_psk()) {
+                (Some(i), Some(psk_offer)) => Some(&psk_offer.identities[i].identity.0[..]),
+                _ => None,
+            };
             let doing_early_data = emit_encrypted_extensions(
                 &mut self.transcript,
                 self.suite,
@@ -342,25 +512,71 @@ mod client_hello {
                 &mut sct_list,
                 client_hello,
                 resumedata.as_ref(),
+                ticket_id,
                 self.extra_exts,
                 &self.config,
             )?;
 
-            let doing_client_auth = if full_handshake {
-                let client_auth =
-                    emit_certificate_req_tls13(&mut self.transcript, cx, &self.config)?;
+            let doing_client_auth = if let Some(server_key) = &server_key {
+                let client_auth = emit_certificate_req_tls13(
+                    &mut self.transcript,
+                    cx,
+                    &self.config,
+                    client_raw_public_key_verifier.as_deref(),
+                )?;
+                let cert_chain = match &server_raw_public_key {
+                    Some(spki) => std::slice::from_ref(spki),
+                    None => server_key.get_cert(),
+                };
+                let cert_compression = match client_hello.get_cert_compression_extension() {
+                    Some(offered) => compress::choose(&self.config.cert_compressors, offered),
+                    None => None,
+                };
+
+                // A delegated credential (RFC9345) stands in for the
+                // certificate's key if the client takes both its schemes,
+                // until it expires.  A raw public key has no certificate
+                // to delegate from.
+                let now = std::time::SystemTime::now();
+                let delegated_credential = server_key
+                    .get_delegated_credential()
+                    .filter(|_| server_raw_public_key.is_none())
+                    .filter(|dc| dc.is_valid_at(&server_key.get_cert()[0], now))
+                    .filter(|dc| {
+                        let cred = &dc.payload().cred;
+                        client_hello
+                            .get_delegated_credential_extension()
+                            .map_or(false, |offered| {
+                                offered.contains(&cred.dc_cert_verify_algorithm)
+                            })
+                            && sigschemes_ext.contains(&dc.payload().algorithm)
+                    });
+                let (signing_key, signing_schemes) = match delegated_credential {
+                    Some(dc) => {
+                        let cred = &dc.payload().cred;
+                        (
+                            dc.key(),
+                            std::slice::from_ref(&cred.dc_cert_verify_algorithm),
+                        )
+                    }
+                    None => (server_key.get_key(), &sigschemes_ext[..]),
+                };
+
                 emit_certificate_tls13(
                     &mut self.transcript,
                     cx.common,
-                    server_key.get_cert(),
+                    cert_chain,
                     ocsp_response,
                     sct_list,
+                    delegated_credential.map(sign::DelegatedCredential::payload),
+                    cert_compression,
                 );
+                cx.data.sent_certificate_chain = Some(server_key.get_cert().to_vec());
                 emit_certificate_verify_tls13(
                     &mut self.transcript,
                     cx.common,
-                    server_key.get_key(),
-                    &sigschemes_ext,
+                    signing_key,
+                    signing_schemes,
                 )?;
                 client_auth
             } else {
@@ -394,6 +610,7 @@ mod client_hello {
                     cx.data
                         .early_data
                         .accept(self.config.max_early_data_size as usize);
+                    cx.common.observe_early_data();
                 }
             }
 
@@ -421,17 +638,23 @@ mod client_hello {
                     suite: self.suite,
                     key_schedule: key_schedule_traffic,
                     send_ticket: self.send_ticket,
+                    raw_public_key_verifier: client_raw_public_key_verifier,
                 }))
             } else if doing_early_data == EarlyDataDecision::Accepted && !cx.common.is_quic() {
                 // Not used for QUIC: RFC 9001 §8.3: Clients MUST NOT send the EndOfEarlyData
                 // message. A server MUST treat receipt of a CRYPTO frame in a 0-RTT packet as a
                 // connection error of type PROTOCOL_VIOLATION.
+                //
+                // Early data is protected by keys from an earlier session,
+                // so our record size limit can't apply to it.
+                let incoming_record_limit = cx.common.take_incoming_record_limit();
                 Ok(Box::new(ExpectEarlyData {
                     config: self.config,
                     transcript: self.transcript,
                     suite: self.suite,
                     key_schedule: key_schedule_traffic,
                     send_ticket: self.send_ticket,
+                    incoming_record_limit,
                 }))
             } else {
                 Ok(Box::new(ExpectFinished {
@@ -445,6 +668,26 @@ mod client_hello {
         }
     }
 
+    /// Choose one of the certificate types the client `offered`, preferring
+    /// a raw public key if `raw_public_key` says we can use one.
+    fn choose_certificate_type(
+        offered: &[CertificateType],
+        raw_public_key: bool,
+    ) -> Option<CertificateType> {
+        if raw_public_key && offered.contains(&CertificateType::RawPublicKey) {
+            Some(CertificateType::RawPublicKey)
+        } else if offered.contains(&CertificateType::X509) {
+            Some(CertificateType::X509)
+        } else {
+            None
+        }
+    }
+
+    fn unsupported_certificate(common: &mut CommonState, whose: &str) -> Error {
+        common.send_fatal_alert(AlertDescription::UnsupportedCertificate);
+        Error::PeerIncompatibleError(format!("no usable {} certificate type offered", whose))
+    }
+
     fn emit_server_hello(
         transcript: &mut HandshakeHash,
         randoms: &ConnectionRandoms,
@@ -515,6 +758,8 @@ mod client_hello {
         let key_schedule = kx.complete(&share.payload.0, |secret| {
             Ok(key_schedule_pre_handshake.into_handshake(secret))
         })?;
+        cx.common
+            .observe_key_exchange(share.group);
 
         let handshake_hash = transcript.get_current_hash();
         let (key_schedule, _client_key, server_key) = key_schedule.derive_handshake_secrets(
@@ -559,12 +804,60 @@ mod client_hello {
         common.send_msg(m, false);
     }
 
-    fn emit_hello_retry_request(
-        transcript: &mut HandshakeHash,
+    /// If `client_hello` answers a `HelloRetryRequest` sent statelessly,
+    /// restores `transcript` to how it was when that was sent, from the
+    /// cookie.  Returns whether it did.
+    pub(in crate::server) fn follow_stateless_retry(
+        config: &ServerConfig,
         suite: &'static Tls13CipherSuite,
+        client_hello: &ClientHelloPayload,
+        transcript: &mut HandshakeHashBuffer,
         common: &mut CommonState,
+    ) -> Result<bool, Error> {
+        let (cookies, cookie) = match (
+            &config.hello_retry_cookies,
+            client_hello.get_cookie_extension(),
+        ) {
+            (Some(cookies), Some(cookie)) => (cookies, cookie),
+            _ => return Ok(false),
+        };
+
+        let state = match cookies
+            .decrypt(&cookie.0)
+            .and_then(|plain| persist::HelloRetryCookie::read_bytes(&plain))
+        {
+            Some(state) => state,
+            None => return Err(common.illegal_param("invalid HelloRetryRequest cookie")),
+        };
+        if !state.is_fresh(ticketer::TimeBase::now()?, HELLO_RETRY_COOKIE_LIFETIME_SECS) {
+            return Err(common.illegal_param("expired HelloRetryRequest cookie"));
+        }
+        if state.cipher_suite != suite.common.suite {
+            return Err(common.illegal_param("cipher suite differed on retry"));
+        }
+        match client_hello
+            .get_keyshare_extension()
+            .map(Vec::as_slice)
+        {
+            Some([share]) if share.group == state.group => {}
+            _ => return Err(common.illegal_param("key share differed from HelloRetryRequest")),
+        }
+
+        transcript.restart_for_hrr(&state.client_hello_hash.0);
+        transcript.add_message(&hello_retry_request(
+            suite,
+            state.group,
+            Some(cookie.0.clone()),
+        ));
+        common.observe_hello_retry_request();
+        Ok(true)
+    }
+
+    fn hello_retry_request(
+        suite: &'static Tls13CipherSuite,
         group: NamedGroup,
-    ) {
+        cookie: Option<Vec<u8>>,
+    ) -> Message {
         let mut req = HelloRetryRequest {
             legacy_version: ProtocolVersion::TLSv1_2,
             session_id: SessionID::empty(),
@@ -578,25 +871,40 @@ mod client_hello {
             .push(HelloRetryExtension::SupportedVersions(
                 ProtocolVersion::TLSv1_3,
             ));
+        if let Some(cookie) = cookie {
+            req.extensions
+                .push(HelloRetryExtension::Cookie(PayloadU16::new(cookie)));
+        }
 
-        let m = Message {
+        Message {
             version: ProtocolVersion::TLSv1_2,
             payload: MessagePayload::Handshake(HandshakeMessagePayload {
                 typ: HandshakeType::HelloRetryRequest,
                 payload: HandshakePayload::HelloRetryRequest(req),
             }),
-        };
+        }
+    }
 
+    fn emit_hello_retry_request(
+        transcript: &mut HandshakeHash,
+        suite: &'static Tls13CipherSuite,
+        common: &mut CommonState,
+        group: NamedGroup,
+        cookie: Option<Vec<u8>>,
+    ) {
+        let m = hello_retry_request(suite, group, cookie);
         trace!("Requesting retry {:?}", m);
         transcript.rollup_for_hrr();
         transcript.add_message(&m);
         common.send_msg(m, false);
+        common.observe_hello_retry_request();
     }
 
     fn decide_if_early_data_allowed(
         cx: &mut ServerContext<'_>,
         client_hello: &ClientHelloPayload,
         resumedata: Option<&persist::ServerSessionValue>,
+        ticket_id: Option<&[u8]>,
         suite: &'static Tls13CipherSuite,
         config: &ServerConfig,
     ) -> EarlyDataDecision {
@@ -640,7 +948,13 @@ mod client_hello {
             && resume.cipher_suite == suite.common.suite
             && resume.alpn.as_ref().map(|x| &x.0) == cx.common.alpn_protocol.as_ref();
 
-        if early_data_configured && early_data_possible && !cx.data.early_data.was_rejected() {
+        /* Replay protection (RFC8446, 8) is asked last, so a ticket is not
+         * recorded as used for early data we would refuse anyway. */
+        if early_data_configured
+            && early_data_possible
+            && !cx.data.early_data.was_rejected()
+            && ticket_id.map_or(false, |id| passes_anti_replay(config, id))
+        {
             EarlyDataDecision::Accepted
         } else {
             #[cfg(feature = "quic")]
@@ -653,6 +967,13 @@ mod client_hello {
         }
     }
 
+    fn passes_anti_replay(config: &ServerConfig, ticket_id: &[u8]) -> bool {
+        match &config.early_data_anti_replay {
+            Some(anti_replay) => anti_replay.single_use_ticket_check(ticket_id),
+            None => true,
+        }
+    }
+
     fn emit_encrypted_extensions(
         transcript: &mut HandshakeHash,
         suite: &'static Tls13CipherSuite,
@@ -661,6 +982,7 @@ mod client_hello {
         sct_list: &mut Option<&[u8]>,
         hello: &ClientHelloPayload,
         resumedata: Option<&persist::ServerSessionValue>,
+        ticket_id: Option<&[u8]>,
         extra_exts: Vec<ServerExtension>,
         config: &ServerConfig,
     ) -> Result<EarlyDataDecision, Error> {
@@ -675,7 +997,8 @@ mod client_hello {
             extra_exts,
         )?;
 
-        let early_data = decide_if_early_data_allowed(cx, hello, resumedata, suite, config);
+        let early_data =
+            decide_if_early_data_allowed(cx, hello, resumedata, ticket_id, suite, config);
         if early_data == EarlyDataDecision::Accepted {
             ep.exts.push(ServerExtension::EarlyData);
         }
@@ -698,43 +1021,28 @@ mod client_hello {
         transcript: &mut HandshakeHash,
         cx: &mut ServerContext<'_>,
         config: &ServerConfig,
+        raw_public_key_verifier: Option<&dyn verify::RawPublicKeyVerifier>,
     ) -> Result<bool, Error> {
-        if !config.verifier.offer_client_auth() {
-            return Ok(false);
-        }
-
-        let mut cr = CertificateRequestPayloadTLS13 {
-            context: PayloadU8::empty(),
-            extensions: Vec::new(),
-        };
-
-        let schemes = config
-            .verifier
-            .supported_verify_schemes();
-        cr.extensions
-            .push(CertReqExtension::SignatureAlgorithms(schemes.to_vec()));
-
-        let names = config
-            .verifier
-            .client_auth_root_subjects()
-            .ok_or_else(|| {
-                debug!("could not determine root subjects based on SNI");
-                cx.common
-                    .send_fatal_alert(AlertDescription::AccessDenied);
-                Error::General("client rejected by client_auth_root_subjects".into())
-            })?;
-
-        if !names.is_empty() {
-            cr.extensions
-                .push(CertReqExtension::AuthorityNames(names));
-        }
-
-        let m = Message {
-            version: ProtocolVersion::TLSv1_3,
-            payload: MessagePayload::Handshake(HandshakeMessagePayload {
-                typ: HandshakeType::CertificateRequest,
-                payload: HandshakePayload::CertificateRequestTLS13(cr),
-            }),
+        let m = match raw_public_key_verifier {
+            // There are no trust anchors to name for a raw public key.
+            Some(verifier) => Message {
+                version: ProtocolVersion::TLSv1_3,
+                payload: MessagePayload::Handshake(HandshakeMessagePayload {
+                    typ: HandshakeType::CertificateRequest,
+                    payload: HandshakePayload::CertificateRequestTLS13(
+                        CertificateRequestPayloadTLS13 {
+                            context: PayloadU8::empty(),
+                            extensions: vec![CertReqExtension::SignatureAlgorithms(
+                                verifier.supported_verify_schemes(),
+                            )],
+                        },
+                    ),
+                }),
+            },
+            None if config.verifier.offer_client_auth() => {
+                certificate_req_tls13(cx.common, config, PayloadU8::empty())?
+            }
+            None => return Ok(false),
         };
 
         trace!("Sending CertificateRequest {:?}", m);
@@ -749,6 +1057,8 @@ mod client_hello {
         cert_chain: &[Certificate],
         ocsp_response: Option<&[u8]>,
         sct_list: Option<&[u8]>,
+        delegated_credential: Option<&DelegatedCredentialPayload>,
+        compression: Option<&dyn CertCompressor>,
     ) {
         let mut cert_entries = vec![];
         for cert in cert_chain {
@@ -776,20 +1086,35 @@ mod client_hello {
                     .exts
                     .push(CertificateExtension::make_sct(sct_list.to_owned()));
             }
+
+            if let Some(dc) = delegated_credential {
+                end_entity_cert
+                    .exts
+                    .push(CertificateExtension::DelegatedCredential(dc.clone()));
+            }
         }
 
         let cert_body = CertificatePayloadTLS13::new(cert_entries);
+        let compressed =
+            compression.and_then(|codec| compress::compress_certificate(codec, &cert_body));
         let c = Message {
             version: ProtocolVersion::TLSv1_3,
-            payload: MessagePayload::Handshake(HandshakeMessagePayload {
-                typ: HandshakeType::Certificate,
-                payload: HandshakePayload::CertificateTLS13(cert_body),
+            payload: MessagePayload::Handshake(match compressed {
+                Some(compressed) => HandshakeMessagePayload {
+                    typ: HandshakeType::CompressedCertificate,
+                    payload: HandshakePayload::CompressedCertificate(compressed),
+                },
+                None => HandshakeMessagePayload {
+                    typ: HandshakeType::Certificate,
+                    payload: HandshakePayload::CertificateTLS13(cert_body),
+                },
             }),
         };
 
         trace!("sending certificate {:?}", c);
         transcript.add_message(&c);
         common.send_msg(c, true);
+        common.observe_sent_certificate(cert_chain.first());
     }
 
     fn emit_certificate_verify_tls13(
@@ -870,6 +1195,45 @@ mod client_hello {
     }
 }
 
+/// Build a `CertificateRequest` for the client, either during the handshake
+/// (with an empty `context`) or for post-handshake authentication.
+fn certificate_req_tls13(
+    common: &mut CommonState,
+    config: &ServerConfig,
+    context: PayloadU8,
+) -> Result<Message, Error> {
+    let mut cr = CertificateRequestPayloadTLS13 {
+        context,
+        extensions: Vec::new(),
+    };
+
+    let schemes = config.client_auth_signature_schemes();
+    cr.extensions
+        .push(CertReqExtension::SignatureAlgorithms(schemes));
+
+    let names = config
+        .verifier
+        .client_auth_root_subjects()
+        .ok_or_else(|| {
+            debug!("could not determine root subjects based on SNI");
+            common.send_fatal_alert(AlertDescription::AccessDenied);
+            Error::General("client rejected by client_auth_root_subjects".into())
+        })?;
+
+    if !names.is_empty() {
+        cr.extensions
+            .push(CertReqExtension::AuthorityNames(names));
+    }
+
+    Ok(Message {
+        version: ProtocolVersion::TLSv1_3,
+        payload: MessagePayload::Handshake(HandshakeMessagePayload {
+            typ: HandshakeType::CertificateRequest,
+            payload: HandshakePayload::CertificateRequestTLS13(cr),
+        }),
+    })
+}
+
 struct ExpectAndSkipRejectedEarlyData {
     skip_data_left: usize,
     next: Box<hs::ExpectClientHello>,
@@ -898,6 +1262,8 @@ struct ExpectCertificate {
     suite: &'static Tls13CipherSuite,
     key_schedule: KeyScheduleTrafficWithClientFinishedPending,
     send_ticket: bool,
+    /// Set if the client agreed to send a raw public key.
+    raw_public_key_verifier: Option<Arc<dyn verify::RawPublicKeyVerifier>>,
 }
 
 impl State<ServerConnectionData> for ExpectCertificate {
@@ -917,8 +1283,46 @@ impl State<ServerConnectionData> for ExpectCertificate {
             ));
         }
 
+        cx.common
+            .check_cert_chain_len(certp.entries.len(), self.config.max_peer_cert_chain_len)?;
         let client_cert = certp.convert();
 
+        if let Some(raw_public_key_verifier) = self.raw_public_key_verifier.take() {
+            // Having agreed on a raw public key, the client must send one.
+            let spki = match client_cert.as_slice() {
+                [spki] => spki,
+                [] => {
+                    cx.common
+                        .send_fatal_alert(AlertDescription::CertificateRequired);
+                    return Err(Error::NoCertificatesPresented);
+                }
+                _ => {
+                    cx.common
+                        .send_fatal_alert(AlertDescription::DecodeError);
+                    return Err(Error::PeerMisbehavedError(
+                        "client did not send exactly one raw public key".to_string(),
+                    ));
+                }
+            };
+
+            raw_public_key_verifier
+                .verify_raw_public_key(&spki.0)
+                .map_err(|err| {
+                    hs::incompatible(cx.common, "raw public key invalid");
+                    err
+                })?;
+
+            return Ok(Box::new(ExpectCertificateVerify {
+                config: self.config,
+                suite: self.suite,
+                transcript: self.transcript,
+                key_schedule: self.key_schedule,
+                client_cert,
+                send_ticket: self.send_ticket,
+                raw_public_key_verifier: Some(raw_public_key_verifier),
+            }));
+        }
+
         let mandatory = self
             .config
             .verifier
@@ -967,6 +1371,7 @@ impl State<ServerConnectionData> for ExpectCertificate {
             key_schedule: self.key_schedule,
             client_cert,
             send_ticket: self.send_ticket,
+            raw_public_key_verifier: None,
         }))
     }
 }
@@ -978,6 +1383,7 @@ struct ExpectCertificateVerify {
     key_schedule: KeyScheduleTrafficWithClientFinishedPending,
     client_cert: Vec<Certificate>,
     send_ticket: bool,
+    raw_public_key_verifier: Option<Arc<dyn verify::RawPublicKeyVerifier>>,
 }
 
 impl State<ServerConnectionData> for ExpectCertificateVerify {
@@ -993,9 +1399,13 @@ impl State<ServerConnectionData> for ExpectCertificateVerify {
             let certs = &self.client_cert;
             let msg = verify::construct_tls13_client_verify_message(&handshake_hash);
 
-            self.config
-                .verifier
-                .verify_tls13_signature(&msg, &certs[0], sig)
+            match &self.raw_public_key_verifier {
+                Some(verifier) => verifier.verify_tls13_signature(&msg, &certs[0].0, sig),
+                None => self
+                    .config
+                    .verifier
+                    .verify_tls13_signature(&msg, &certs[0], sig),
+            }
         };
 
         if let Err(e) = rc {
@@ -1027,6 +1437,7 @@ struct ExpectEarlyData {
     suite: &'static Tls13CipherSuite,
     key_schedule: KeyScheduleTrafficWithClientFinishedPending,
     send_ticket: bool,
+    incoming_record_limit: Option<u16>,
 }
 
 impl State<ServerConnectionData> for ExpectEarlyData {
@@ -1058,6 +1469,8 @@ impl State<ServerConnectionData> for ExpectEarlyData {
                         self.suite
                             .derive_decrypter(self.key_schedule.client_key()),
                     );
+                cx.common
+                    .set_incoming_record_limit(self.incoming_record_limit);
 
                 self.transcript.add_message(&m);
                 Ok(Box::new(ExpectFinished {
@@ -1106,6 +1519,13 @@ fn get_server_session_value(
     )
 }
 
+/// How long the client has to answer a stateless `HelloRetryRequest`.
+const HELLO_RETRY_COOKIE_LIFETIME_SECS: u64 = 60;
+
+/// The most tickets sent after one handshake, whatever
+/// `ServerConfig::send_tls13_tickets` says.
+const MAX_TLS13_TICKETS: usize = 8;
+
 struct ExpectFinished {
     config: Arc<ServerConfig>,
     transcript: HandshakeHash,
@@ -1122,9 +1542,9 @@ impl ExpectFinished {
         key_schedule: &KeyScheduleTraffic,
         config: &ServerConfig,
     ) -> Result<(), Error> {
-        let nonce = rand::random_vec(32)?;
+        let nonce = config.random().random_vec(32)?;
         let now = ticketer::TimeBase::now()?;
-        let age_add = rand::random_u32()?;
+        let age_add = config.random().random_u32()?;
         let plain =
             get_server_session_value(transcript, suite, key_schedule, cx, &nonce, now, age_add)
                 .get_encoding();
@@ -1137,7 +1557,7 @@ impl ExpectFinished {
             };
             (ticket, config.ticketer.lifetime())
         } else {
-            let id = rand::random_vec(32)?;
+            let id = config.random().random_vec(32)?;
             let stored = config
                 .session_storage
                 .put(id.clone(), plain);
@@ -1205,19 +1625,29 @@ impl State<ServerConnectionData> for ExpectFinished {
 
         cx.common.check_aligned_handshake()?;
 
+        // Post-handshake authentication continues this transcript, without
+        // any tickets we send below.
+        let auth_transcript = if cx.data.post_handshake_auth_offered {
+            Some(self.transcript.clone())
+        } else {
+            None
+        };
+
         // Install keying to read future messages.
         cx.common
             .record_layer
             .set_message_decrypter(self.suite.derive_decrypter(&client_key));
 
         if self.send_ticket {
-            Self::emit_ticket(
-                &mut self.transcript,
-                self.suite,
-                cx,
-                &key_schedule_traffic,
-                &self.config,
-            )?;
+            for _ in 0..cmp::min(self.config.send_tls13_tickets, MAX_TLS13_TICKETS) {
+                Self::emit_ticket(
+                    &mut self.transcript,
+                    self.suite,
+                    cx,
+                    &key_schedule_traffic,
+                    &self.config,
+                )?;
+            }
         }
 
         // Application data may now flow, even if we have client auth enabled.
@@ -1234,23 +1664,184 @@ impl State<ServerConnectionData> for ExpectFinished {
         }
 
         Ok(Box::new(ExpectTraffic {
+            config: self.config,
             suite: self.suite,
             key_schedule: key_schedule_traffic,
             want_write_key_update: false,
+            auth_transcript,
+            client_auth: None,
             _fin_verified: fin,
         }))
     }
 }
 
+/// Progress of a post-handshake client authentication.
+///
+/// Each transcript starts from the one at the end of the handshake and
+/// includes our `CertificateRequest`.
+enum PostHandshakeAuth {
+    ExpectCertificate {
+        context: Vec<u8>,
+        transcript: HandshakeHash,
+    },
+    ExpectCertificateVerify {
+        transcript: HandshakeHash,
+        client_cert: Vec<Certificate>,
+    },
+    ExpectFinished {
+        transcript: HandshakeHash,
+        client_cert: Option<Vec<Certificate>>,
+    },
+}
+
 // --- Process traffic ---
 struct ExpectTraffic {
+    config: Arc<ServerConfig>,
     suite: &'static Tls13CipherSuite,
     key_schedule: KeyScheduleTraffic,
     want_write_key_update: bool,
+    /// The handshake transcript, kept if the client offered post-handshake auth.
+    auth_transcript: Option<HandshakeHash>,
+    client_auth: Option<PostHandshakeAuth>,
     _fin_verified: verify::FinishedMessageVerified,
 }
 
 impl ExpectTraffic {
+    fn handle_client_auth(&mut self, cx: &mut ServerContext<'_>, m: &Message) -> Result<(), Error> {
+        let next = match self.client_auth.take() {
+            Some(PostHandshakeAuth::ExpectCertificate {
+                context,
+                mut transcript,
+            }) => {
+                let certp = require_handshake_msg!(
+                    m,
+                    HandshakeType::Certificate,
+                    HandshakePayload::CertificateTLS13
+                )?;
+                transcript.add_message(m);
+
+                if certp.context.0 != context {
+                    return Err(cx
+                        .common
+                        .illegal_param("client sent wrong certificate request context"));
+                }
+
+                // We don't send any CertificateRequest extensions, so any extensions
+                // here are illegal.
+                if certp.any_entry_has_extension() {
+                    return Err(Error::PeerMisbehavedError(
+                        "client sent unsolicited cert extension".to_string(),
+                    ));
+                }
+
+                cx.common.check_cert_chain_len(
+                    certp.entries.len(),
+                    self.config.max_peer_cert_chain_len,
+                )?;
+                let client_cert = certp.convert();
+
+                let mandatory = self
+                    .config
+                    .verifier
+                    .client_auth_mandatory()
+                    .ok_or_else(|| {
+                        debug!("could not determine if client auth is mandatory based on SNI");
+                        cx.common
+                            .send_fatal_alert(AlertDescription::AccessDenied);
+                        Error::General("client rejected by client_auth_mandatory".into())
+                    })?;
+
+                match client_cert.split_first() {
+                    None if mandatory => {
+                        cx.common
+                            .send_fatal_alert(AlertDescription::CertificateRequired);
+                        return Err(Error::NoCertificatesPresented);
+                    }
+                    None => {
+                        debug!("post-handshake client auth requested but no certificate supplied");
+                        PostHandshakeAuth::ExpectFinished {
+                            transcript,
+                            client_cert: None,
+                        }
+                    }
+                    Some((end_entity, intermediates)) => {
+                        let now = std::time::SystemTime::now();
+                        self.config
+                            .verifier
+                            .verify_client_cert(end_entity, intermediates, now)
+                            .map_err(|err| {
+                                hs::incompatible(cx.common, "certificate invalid");
+                                err
+                            })?;
+
+                        PostHandshakeAuth::ExpectCertificateVerify {
+                            transcript,
+                            client_cert,
+                        }
+                    }
+                }
+            }
+            Some(PostHandshakeAuth::ExpectCertificateVerify {
+                mut transcript,
+                client_cert,
+            }) => {
+                let sig = require_handshake_msg!(
+                    m,
+                    HandshakeType::CertificateVerify,
+                    HandshakePayload::CertificateVerify
+                )?;
+                let handshake_hash = transcript.get_current_hash();
+                let msg = verify::construct_tls13_client_verify_message(&handshake_hash);
+
+                if let Err(e) = self
+                    .config
+                    .verifier
+                    .verify_tls13_signature(&msg, &client_cert[0], sig)
+                {
+                    cx.common
+                        .send_fatal_alert(AlertDescription::AccessDenied);
+                    return Err(e);
+                }
+
+                trace!("post-handshake client CertificateVerify OK");
+                transcript.add_message(m);
+                PostHandshakeAuth::ExpectFinished {
+                    transcript,
+                    client_cert: Some(client_cert),
+                }
+            }
+            Some(PostHandshakeAuth::ExpectFinished {
+                transcript,
+                client_cert,
+            }) => {
+                let finished =
+                    require_handshake_msg!(m, HandshakeType::Finished, HandshakePayload::Finished)?;
+
+                let handshake_hash = transcript.get_current_hash();
+                let expect_verify_data = self
+                    .key_schedule
+                    .sign_client_post_handshake_finish(&handshake_hash);
+
+                constant_time::verify_slices_are_equal(expect_verify_data.as_ref(), &finished.0)
+                    .map_err(|_| {
+                        cx.common
+                            .send_fatal_alert(AlertDescription::DecryptError);
+                        warn!("post-handshake Finished wrong");
+                        Error::DecryptError
+                    })?;
+
+                if let Some(client_cert) = client_cert {
+                    cx.common.peer_certificates = Some(client_cert);
+                }
+                return Ok(());
+            }
+            None => unreachable!(),
+        };
+
+        self.client_auth = Some(next);
+        Ok(())
+    }
+
     fn handle_key_update(
         &mut self,
         common: &mut CommonState,
@@ -1292,10 +1883,32 @@ impl ExpectTraffic {
 
         Ok(())
     }
+
+    /// Send `key_update`, and switch to new write-side keys for
+    /// everything after it.
+    fn write_key_update(&mut self, common: &mut CommonState, key_update: Message) {
+        common.send_msg_encrypt(key_update.into());
+
+        let write_key = self
+            .key_schedule
+            .next_server_application_traffic_secret();
+        common
+            .record_layer
+            .set_message_encrypter(self.suite.derive_encrypter(&write_key));
+    }
 }
 
 impl State<ServerConnectionData> for ExpectTraffic {
     fn handle(mut self: Box<Self>, cx: &mut ServerContext, m: Message) -> hs::NextStateOrError {
+        if self.client_auth.is_some()
+            && (m.is_handshake_type(HandshakeType::Certificate)
+                || m.is_handshake_type(HandshakeType::CertificateVerify)
+                || m.is_handshake_type(HandshakeType::Finished))
+        {
+            self.handle_client_auth(cx, &m)?;
+            return Ok(self);
+        }
+
         match m.payload {
             MessagePayload::ApplicationData(payload) => cx
                 .common
@@ -1329,15 +1942,60 @@ impl State<ServerConnectionData> for ExpectTraffic {
     fn perhaps_write_key_update(&mut self, common: &mut CommonState) {
         if self.want_write_key_update {
             self.want_write_key_update = false;
-            common.send_msg_encrypt(Message::build_key_update_notify().into());
+            self.write_key_update(common, Message::build_key_update_notify());
+        }
+    }
 
-            let write_key = self
-                .key_schedule
-                .next_server_application_traffic_secret();
-            common
-                .record_layer
-                .set_message_encrypter(self.suite.derive_encrypter(&write_key));
+    fn send_key_update(
+        &mut self,
+        common: &mut CommonState,
+        request_peer_update: bool,
+    ) -> Result<(), Error> {
+        // This also answers any update the peer asked for.
+        self.want_write_key_update = false;
+        let key_update = match request_peer_update {
+            true => Message::build_key_update_request(),
+            false => Message::build_key_update_notify(),
+        };
+        self.write_key_update(common, key_update);
+        Ok(())
+    }
+
+    fn request_post_handshake_auth(&mut self, common: &mut CommonState) -> Result<(), Error> {
+        let transcript = match &self.auth_transcript {
+            Some(transcript) => transcript,
+            None => {
+                return Err(Error::PeerIncompatibleError(
+                    "client did not offer post-handshake authentication".to_string(),
+                ));
+            }
+        };
+
+        if !self.config.verifier.offer_client_auth() {
+            return Err(Error::General(
+                "client authentication is not configured".to_string(),
+            ));
+        }
+
+        if self.client_auth.is_some() {
+            return Err(Error::General(
+                "post-handshake authentication already in progress".to_string(),
+            ));
         }
+
+        let context = self.config.random().random_vec(32)?;
+        let m = certificate_req_tls13(common, &self.config, PayloadU8::new(context.clone()))?;
+
+        let mut transcript = transcript.clone();
+        trace!("Sending post-handshake CertificateRequest {:?}", m);
+        transcript.add_message(&m);
+        common.send_msg(m, true);
+
+        self.client_auth = Some(PostHandshakeAuth::ExpectCertificate {
+            context,
+            transcript,
+        });
+        Ok(())
     }
 }
 
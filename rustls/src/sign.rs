@@ -1,7 +1,11 @@
 use crate::error::Error;
 use crate::key;
+use crate::msgs::base::{PayloadU16, PayloadU24};
+use crate::msgs::codec::{Codec, Reader};
 use crate::msgs::enums::{SignatureAlgorithm, SignatureScheme};
-use crate::x509::{wrap_in_asn1_len, wrap_in_sequence};
+use crate::msgs::handshake::{Credential, DelegatedCredentialPayload, DigitallySignedStruct};
+use crate::verify;
+use crate::x509::{self, wrap_in_asn1_len, wrap_in_sequence};
 
 use ring::io::der;
 use ring::signature::{self, EcdsaKeyPair, Ed25519KeyPair, RsaKeyPair};
@@ -12,6 +16,7 @@ use std::convert::TryFrom;
 use std::error::Error as StdError;
 use std::fmt;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 /// An abstract signing key.
 pub trait SigningKey: Send + Sync {
@@ -56,6 +61,11 @@ pub struct CertifiedKey {
     /// certificate is included on those logs.  This must be
     /// a `SignedCertificateTimestampList` encoding; see RFC6962.
     pub sct_list: Option<Vec<u8>>,
+
+    /// An optional delegated credential for the end-entity
+    /// certificate.  TLS1.3 clients which accept it are
+    /// authenticated to with its key instead of `key`.
+    pub delegated_credential: Option<DelegatedCredential>,
 }
 
 impl CertifiedKey {
@@ -69,6 +79,7 @@ impl CertifiedKey {
             key,
             ocsp: None,
             sct_list: None,
+            delegated_credential: None,
         }
     }
 
@@ -77,6 +88,20 @@ impl CertifiedKey {
         self.cert.get(0).ok_or(SignError(()))
     }
 
+    /// Make a copy of this key whose "chain" is just the end-entity
+    /// certificate's `SubjectPublicKeyInfo`, for sending when a raw public
+    /// key (RFC 7250) has been negotiated.
+    ///
+    /// Returns `None` if the end-entity certificate can't be parsed.
+    pub(crate) fn to_raw_public_key(&self) -> Option<Self> {
+        let end_entity_cert = self.end_entity_cert().ok()?;
+        let spki = x509::subject_public_key_info(&end_entity_cert.0)?;
+        Some(Self::new(
+            vec![key::Certificate(spki.to_vec())],
+            Arc::clone(&self.key),
+        ))
+    }
+
     /// Check the certificate chain for validity:
     /// - it should be non-empty list
     /// - the first certificate should be parsable as a x509v3,
@@ -126,8 +151,185 @@ impl CertifiedKey {
 
         Ok(())
     }
+
+    /// Check the certificate chain and key belong together:
+    /// - the chain should be non-empty,
+    /// - every certificate should be parsable as a x509v3,
+    /// - each certificate should be issued by the one after it,
+    /// - `key` should be the private key for the end-entity
+    ///   certificate's public key.
+    ///
+    /// Like `cross_check_end_entity_cert`, this is meant to catch
+    /// misconfiguration, for example when a [`ResolvesServerCert`]
+    /// implementation loads its keys at startup.  It does not check the
+    /// chain leads to any trust anchor.
+    ///
+    /// [`ResolvesServerCert`]: crate::server::ResolvesServerCert
+    pub fn validate(&self) -> Result<(), Error> {
+        let end_entity_cert = self
+            .end_entity_cert()
+            .map_err(|SignError(())| {
+                Error::General("No end-entity certificate in certificate chain".to_string())
+            })?;
+
+        for (i, cert) in self.cert.iter().enumerate() {
+            if webpki::EndEntityCert::try_from(cert.as_ref()).is_err() {
+                return Err(Error::General(format!(
+                    "Certificate {} in certificate chain is syntactically invalid",
+                    i
+                )));
+            }
+        }
+
+        for (i, pair) in self.cert.windows(2).enumerate() {
+            let issuer = x509::issuer_and_subject(&pair[0].0).map(|(issuer, _)| issuer);
+            let subject = x509::issuer_and_subject(&pair[1].0).map(|(_, subject)| subject);
+            if issuer.is_none() || issuer != subject {
+                return Err(Error::General(format!(
+                    "Certificate {} in certificate chain is not issued by the next one",
+                    i
+                )));
+            }
+        }
+
+        let key_mismatch =
+            || Error::General("Private key does not match end-entity certificate".to_string());
+        let signer = self
+            .key
+            .choose_scheme(
+                &verify::WebPkiVerifier::verification_schemes(),
+                Box::new(rand::SystemRandom::new()),
+            )
+            .ok_or_else(key_mismatch)?;
+        let sig = signer.sign(KEY_CHECK_MESSAGE)?;
+        verify::verify_signed_struct(
+            KEY_CHECK_MESSAGE,
+            end_entity_cert,
+            &DigitallySignedStruct::new(signer.scheme(), sig),
+        )
+        .map_err(|_| key_mismatch())?;
+
+        Ok(())
+    }
+}
+
+/// A delegated credential (RFC9345): a short-lived key, and the
+/// credential by which an end-entity certificate's key vouches for it.
+///
+/// The certificate's own key need only be available to issue the
+/// credential, which can be done elsewhere ahead of time.
+#[derive(Clone)]
+pub struct DelegatedCredential {
+    payload: DelegatedCredentialPayload,
+    key: Arc<dyn SigningKey>,
+}
+
+impl DelegatedCredential {
+    /// Use `credential`, an encoded RFC9345 `DelegatedCredential`, with
+    /// `key`, the private key it delegates to.
+    ///
+    /// This fails if `credential` can't be decoded, or `key` can't sign
+    /// with the scheme `credential` names.  It doesn't check that
+    /// `credential` is for `key`, or for the certificate it is used with.
+    pub fn new(credential: &[u8], key: Arc<dyn SigningKey>) -> Result<Self, Error> {
+        let mut rd = Reader::init(credential);
+        let payload = DelegatedCredentialPayload::read(&mut rd)
+            .filter(|_| !rd.any_left())
+            .ok_or_else(|| Error::General("Delegated credential is invalid".to_string()))?;
+        if key
+            .choose_scheme(
+                &[payload.cred.dc_cert_verify_algorithm],
+                Box::new(rand::SystemRandom::new()),
+            )
+            .is_none()
+        {
+            return Err(Error::General(
+                "Delegated credential's key does not support its scheme".to_string(),
+            ));
+        }
+
+        Ok(Self { payload, key })
+    }
+
+    /// Issue a credential by which `end_entity`, whose private key is
+    /// `issuer`, delegates to the key whose DER-encoded
+    /// `SubjectPublicKeyInfo` is `public_key`.  That key is to sign
+    /// handshakes with `scheme`, until `expiry`.
+    ///
+    /// Returns the encoded credential, for [`DelegatedCredential::new`].
+    /// RFC9345 forbids clients from accepting credentials valid for more
+    /// than seven days, but that is not checked here.
+    ///
+    /// This fails if `end_entity` can't be parsed, if `expiry` is before
+    /// its `notBefore` or too long after, or if `issuer` has no TLS1.3
+    /// signature scheme.
+    pub fn issue(
+        end_entity: &key::Certificate,
+        issuer: &dyn SigningKey,
+        public_key: &[u8],
+        scheme: SignatureScheme,
+        expiry: SystemTime,
+    ) -> Result<Vec<u8>, Error> {
+        let (not_before, _) = x509::validity(&end_entity.0).ok_or_else(|| {
+            Error::General("End-entity certificate is syntactically invalid".to_string())
+        })?;
+        let valid_time = expiry
+            .duration_since(not_before)
+            .ok()
+            .and_then(|valid_time| u32::try_from(valid_time.as_secs()).ok())
+            .ok_or_else(|| {
+                Error::General(
+                    "Delegated credential expires before the certificate's \
+                     notBefore, or too long after"
+                        .to_string(),
+                )
+            })?;
+
+        let cred = Credential {
+            valid_time,
+            dc_cert_verify_algorithm: scheme,
+            public_key: PayloadU24::new(public_key.to_vec()),
+        };
+        let signer = issuer
+            .choose_scheme(supported_sign_tls13(), Box::new(rand::SystemRandom::new()))
+            .ok_or_else(|| {
+                Error::General("Delegated credential issuer has no TLS1.3 scheme".to_string())
+            })?;
+        let algorithm = signer.scheme();
+        let message = verify::construct_delegated_credential_message(end_entity, &cred, algorithm);
+        let signature = signer.sign(&message)?;
+
+        Ok(DelegatedCredentialPayload {
+            cred,
+            algorithm,
+            signature: PayloadU16::new(signature),
+        }
+        .get_encoding())
+    }
+
+    /// The credential, as sent in the end-entity certificate's entry
+    /// of a TLS1.3 `Certificate` message.
+    pub(crate) fn payload(&self) -> &DelegatedCredentialPayload {
+        &self.payload
+    }
+
+    /// The key the credential delegates to.
+    pub(crate) fn key(&self) -> &dyn SigningKey {
+        &*self.key
+    }
+
+    /// Whether the credential, issued by `end_entity`, has yet to
+    /// expire at `now`.
+    pub(crate) fn is_valid_at(&self, end_entity: &key::Certificate, now: SystemTime) -> bool {
+        let valid_time = Duration::from_secs(self.payload.cred.valid_time.into());
+        x509::validity(&end_entity.0).map_or(false, |(not_before, _)| now < not_before + valid_time)
+    }
 }
 
+/// What `CertifiedKey::validate` signs to check the key matches
+/// the certificate.
+const KEY_CHECK_MESSAGE: &[u8] = b"rustls certified key check";
+
 /// Parse `der` as any supported key encoding/type, returning
 /// the first which works.
 pub fn any_supported_type(der: &key::PrivateKey) -> Result<Arc<dyn SigningKey>, SignError> {
@@ -544,3 +746,118 @@ fn can_load_rsa2048_pkcs1() {
     assert!(any_eddsa_type(&key).is_err());
     assert!(any_ecdsa_type(&key).is_err());
 }
+
+#[cfg(test)]
+fn delegated_credential_parts() -> (key::Certificate, Arc<dyn SigningKey>, Arc<dyn SigningKey>) {
+    let read_key = |mut pem: &[u8]| {
+        let der = rustls_pemfile::pkcs8_private_keys(&mut pem).unwrap()[0].clone();
+        any_supported_type(&key::PrivateKey(der)).unwrap()
+    };
+    let pem = include_bytes!("../../test-ca/ecdsa/end-dc.cert");
+    let end_entity = key::Certificate(rustls_pemfile::certs(&mut &pem[..]).unwrap()[0].clone());
+
+    (
+        end_entity,
+        read_key(&include_bytes!("../../test-ca/ecdsa/end.key")[..]),
+        read_key(&include_bytes!("../../test-ca/ecdsa/delegated.key")[..]),
+    )
+}
+
+#[test]
+fn can_issue_delegated_credential() {
+    let (end_entity, issuer, key) = delegated_credential_parts();
+    let spki = include_bytes!("../../test-ca/ecdsa/delegated.spki");
+    let (not_before, _) = x509::validity(&end_entity.0).unwrap();
+    let expiry = not_before + Duration::from_secs(3600);
+
+    let credential = DelegatedCredential::issue(
+        &end_entity,
+        &*issuer,
+        spki,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        expiry,
+    )
+    .unwrap();
+    let dc = DelegatedCredential::new(&credential, key).unwrap();
+    let payload = dc.payload();
+    assert_eq!(payload.cred.valid_time, 3600);
+    assert_eq!(
+        payload.cred.dc_cert_verify_algorithm,
+        SignatureScheme::ECDSA_NISTP256_SHA256
+    );
+    assert_eq!(payload.cred.public_key.0, &spki[..]);
+
+    let message = verify::construct_delegated_credential_message(
+        &end_entity,
+        &payload.cred,
+        payload.algorithm,
+    );
+    let signature = DigitallySignedStruct::new(payload.algorithm, payload.signature.0.clone());
+    assert!(verify::verify_signed_struct(&message, &end_entity, &signature).is_ok());
+}
+
+#[test]
+fn delegated_credential_rejects_bad_parts() {
+    let (end_entity, issuer, key) = delegated_credential_parts();
+    let spki = include_bytes!("../../test-ca/ecdsa/delegated.spki");
+    let (not_before, _) = x509::validity(&end_entity.0).unwrap();
+    let issue = |expiry| {
+        DelegatedCredential::issue(
+            &end_entity,
+            &*issuer,
+            spki,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            expiry,
+        )
+    };
+
+    assert!(issue(not_before - Duration::from_secs(1)).is_err());
+    assert!(issue(not_before + Duration::from_secs(1 << 32)).is_err());
+
+    let credential = issue(not_before + Duration::from_secs(3600)).unwrap();
+    assert!(
+        DelegatedCredential::new(&credential[..credential.len() - 1], Arc::clone(&key)).is_err()
+    );
+    let mut trailing = credential.clone();
+    trailing.push(0);
+    assert!(DelegatedCredential::new(&trailing, Arc::clone(&key)).is_err());
+
+    // An Ed25519 key can't make the credential's ECDSA signatures.
+    let ed25519 = key::PrivateKey(include_bytes!("testdata/eddsakey.der").to_vec());
+    let ed25519 = any_supported_type(&ed25519).unwrap();
+    assert!(DelegatedCredential::new(&credential, ed25519).is_err());
+}
+
+#[test]
+fn delegated_credential_expires() {
+    let (end_entity, issuer, key) = delegated_credential_parts();
+    let spki = include_bytes!("../../test-ca/ecdsa/delegated.spki");
+    let (not_before, _) = x509::validity(&end_entity.0).unwrap();
+    let expiry = not_before + Duration::from_secs(3600);
+
+    let credential = DelegatedCredential::issue(
+        &end_entity,
+        &*issuer,
+        spki,
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        expiry,
+    )
+    .unwrap();
+    let dc = DelegatedCredential::new(&credential, key).unwrap();
+    assert!(dc.is_valid_at(&end_entity, expiry - Duration::from_secs(1)));
+    assert!(!dc.is_valid_at(&end_entity, expiry));
+
+    // Clients refuse expired credentials too.
+    assert!(verify::verify_delegated_credential(
+        &end_entity,
+        dc.payload(),
+        expiry - Duration::from_secs(1)
+    )
+    .is_ok());
+    assert!(matches!(
+        verify::verify_delegated_credential(&end_entity, dc.payload(), expiry),
+        Err(Error::InvalidCertificate(
+            crate::CertificateError::DelegatedCredentialExpired { .. }
+        ))
+    ));
+}
@@ -269,10 +269,32 @@ impl Ticketer {
     ///
     /// The encryption mechanism used in Chacha20Poly1305.
     pub fn new() -> Result<Arc<dyn ProducesTickets>, Error> {
-        Ok(Arc::new(TicketSwitcher::new(6 * 60 * 60, generate_inner)?))
+        Self::with_rotation_interval(6 * 60 * 60)
+    }
+
+    /// Make a Ticketer like [`Ticketer::new`], but which switches to
+    /// a fresh key every `interval` seconds.
+    ///
+    /// New tickets are always encrypted with the current key.  The key
+    /// it replaces is kept for decryption until the next switch, so
+    /// tickets are accepted for up to twice `interval`, and that is the
+    /// lifetime sent to clients.
+    ///
+    /// `interval` must be between 1 second and 3.5 days, so that the
+    /// ticket lifetime stays within the seven days allowed by RFC8446.
+    pub fn with_rotation_interval(interval: u32) -> Result<Arc<dyn ProducesTickets>, Error> {
+        if interval == 0 || interval > MAX_TICKET_LIFETIME / 2 {
+            return Err(Error::General(
+                "ticket key rotation interval out of range".into(),
+            ));
+        }
+        Ok(Arc::new(TicketSwitcher::new(interval, generate_inner)?))
     }
 }
 
+/// RFC8446 section 4.6.1.
+const MAX_TICKET_LIFETIME: u32 = 7 * 24 * 60 * 60;
+
 #[test]
 fn basic_pairwise_test() {
     let t = Ticketer::new().unwrap();
@@ -305,6 +327,40 @@ fn ticketswitcher_switching_test() {
     assert_eq!(t.decrypt(&cipher3).unwrap(), b"ticket 3");
 }
 
+#[test]
+fn ticketer_rotation_interval_test() {
+    let t = Ticketer::with_rotation_interval(60).unwrap();
+    assert_eq!(t.lifetime(), 120);
+    let cipher = t.encrypt(b"hello world").unwrap();
+    assert_eq!(t.decrypt(&cipher).unwrap(), b"hello world");
+
+    assert!(Ticketer::with_rotation_interval(0).is_err());
+    assert!(Ticketer::with_rotation_interval(MAX_TICKET_LIFETIME / 2).is_ok());
+    assert!(Ticketer::with_rotation_interval(MAX_TICKET_LIFETIME / 2 + 1).is_err());
+}
+
+#[test]
+fn ticketswitcher_encrypts_with_current_key_test() {
+    let t = TicketSwitcher::new(1, generate_inner).unwrap();
+    let now = TimeBase::now().unwrap();
+    let cipher1 = t.encrypt(b"ticket 1").unwrap();
+    {
+        // Trigger new ticketer
+        t.maybe_roll(TimeBase(now.0 + std::time::Duration::from_secs(10)));
+    }
+    let cipher2 = t.encrypt(b"ticket 2").unwrap();
+
+    let state = t.state.lock().unwrap();
+    let previous = state.previous.as_ref().unwrap();
+    assert_eq!(previous.decrypt(&cipher1).unwrap(), b"ticket 1");
+    assert!(previous.decrypt(&cipher2).is_none());
+    assert!(state
+        .current
+        .decrypt(&cipher1)
+        .is_none());
+    assert_eq!(state.current.decrypt(&cipher2).unwrap(), b"ticket 2");
+}
+
 #[cfg(test)]
 fn fail_generator() -> Result<Box<dyn ProducesTickets>, rand::GetRandomFailed> {
     Err(rand::GetRandomFailed)
@@ -1,4 +1,4 @@
-use crate::cipher::{make_nonce, Iv, MessageDecrypter, MessageEncrypter};
+use crate::cipher::{make_nonce, record_payload, Iv, MessageDecrypter, MessageEncrypter};
 use crate::error::Error;
 use crate::msgs::base::Payload;
 use crate::msgs::codec;
@@ -141,27 +141,53 @@ impl MessageDecrypter for GcmMessageDecrypter {
     }
 }
 
-impl MessageEncrypter for GcmMessageEncrypter {
-    fn encrypt(&self, msg: BorrowedPlainMessage, seq: u64) -> Result<OpaqueMessage, Error> {
+impl GcmMessageEncrypter {
+    /// How long the record payload encrypting `msg` is.
+    fn payload_len(&self, msg: &BorrowedPlainMessage) -> usize {
+        GCM_EXPLICIT_NONCE_LEN + msg.payload.len() + self.enc_key.algorithm().tag_len()
+    }
+
+    /// Encrypt `msg` into `payload`, which is `payload_len` long.
+    fn seal(&self, msg: BorrowedPlainMessage, seq: u64, payload: &mut [u8]) -> Result<(), Error> {
         let nonce = make_nonce(&self.iv, seq);
         let aad = make_tls12_aad(seq, msg.typ, msg.version, msg.payload.len());
 
-        let total_len = msg.payload.len() + self.enc_key.algorithm().tag_len();
-        let mut payload = Vec::with_capacity(GCM_EXPLICIT_NONCE_LEN + total_len);
-        payload.extend_from_slice(&nonce.as_ref()[4..]);
-        payload.extend_from_slice(msg.payload);
+        let (explicit, rest) = payload.split_at_mut(GCM_EXPLICIT_NONCE_LEN);
+        let (content, tag) = rest.split_at_mut(msg.payload.len());
+        explicit.copy_from_slice(&nonce.as_ref()[4..]);
+        content.copy_from_slice(msg.payload);
 
         self.enc_key
-            .seal_in_place_separate_tag(nonce, aad, &mut payload[GCM_EXPLICIT_NONCE_LEN..])
-            .map(|tag| payload.extend(tag.as_ref()))
-            .map_err(|_| Error::General("encrypt failed".to_string()))?;
+            .seal_in_place_separate_tag(nonce, aad, content)
+            .map(|sealed| tag.copy_from_slice(sealed.as_ref()))
+            .map_err(|_| Error::General("encrypt failed".to_string()))
+    }
+}
+
+impl MessageEncrypter for GcmMessageEncrypter {
+    fn encrypt(&self, msg: BorrowedPlainMessage, seq: u64) -> Result<OpaqueMessage, Error> {
+        let (typ, version) = (msg.typ, msg.version);
+        let mut payload = vec![0; self.payload_len(&msg)];
+        self.seal(msg, seq, &mut payload)?;
 
         Ok(OpaqueMessage {
-            typ: msg.typ,
-            version: msg.version,
+            typ,
+            version,
             payload: Payload::new(payload),
         })
     }
+
+    fn encrypt_into(
+        &self,
+        msg: BorrowedPlainMessage,
+        seq: u64,
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        let payload = record_payload(out, msg.typ, msg.version, self.payload_len(&msg))?;
+        let len = payload.len();
+        self.seal(msg, seq, payload)?;
+        Ok(usize::from(OpaqueMessage::HEADER_SIZE) + len)
+    }
 }
 
 /// The RFC7905/RFC7539 ChaCha20Poly1305 construction.
@@ -213,23 +239,49 @@ impl MessageDecrypter for ChaCha20Poly1305MessageDecrypter {
     }
 }
 
-impl MessageEncrypter for ChaCha20Poly1305MessageEncrypter {
-    fn encrypt(&self, msg: BorrowedPlainMessage, seq: u64) -> Result<OpaqueMessage, Error> {
+impl ChaCha20Poly1305MessageEncrypter {
+    /// How long the record payload encrypting `msg` is.
+    fn payload_len(&self, msg: &BorrowedPlainMessage) -> usize {
+        msg.payload.len() + self.enc_key.algorithm().tag_len()
+    }
+
+    /// Encrypt `msg` into `payload`, which is `payload_len` long.
+    fn seal(&self, msg: BorrowedPlainMessage, seq: u64, payload: &mut [u8]) -> Result<(), Error> {
         let nonce = make_nonce(&self.enc_offset, seq);
         let aad = make_tls12_aad(seq, msg.typ, msg.version, msg.payload.len());
 
-        let total_len = msg.payload.len() + self.enc_key.algorithm().tag_len();
-        let mut buf = Vec::with_capacity(total_len);
-        buf.extend_from_slice(msg.payload);
+        let (content, tag) = payload.split_at_mut(msg.payload.len());
+        content.copy_from_slice(msg.payload);
 
         self.enc_key
-            .seal_in_place_append_tag(nonce, aad, &mut buf)
-            .map_err(|_| Error::General("encrypt failed".to_string()))?;
+            .seal_in_place_separate_tag(nonce, aad, content)
+            .map(|sealed| tag.copy_from_slice(sealed.as_ref()))
+            .map_err(|_| Error::General("encrypt failed".to_string()))
+    }
+}
+
+impl MessageEncrypter for ChaCha20Poly1305MessageEncrypter {
+    fn encrypt(&self, msg: BorrowedPlainMessage, seq: u64) -> Result<OpaqueMessage, Error> {
+        let (typ, version) = (msg.typ, msg.version);
+        let mut buf = vec![0; self.payload_len(&msg)];
+        self.seal(msg, seq, &mut buf)?;
 
         Ok(OpaqueMessage {
-            typ: msg.typ,
-            version: msg.version,
+            typ,
+            version,
             payload: Payload::new(buf),
         })
     }
+
+    fn encrypt_into(
+        &self,
+        msg: BorrowedPlainMessage,
+        seq: u64,
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        let payload = record_payload(out, msg.typ, msg.version, self.payload_len(&msg))?;
+        let len = payload.len();
+        self.seal(msg, seq, payload)?;
+        Ok(usize::from(OpaqueMessage::HEADER_SIZE) + len)
+    }
 }
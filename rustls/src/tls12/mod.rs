@@ -461,4 +461,43 @@ mod tests {
     fn client_ecdhe_invalid() {
         assert!(decode_ecdh_params_::<ClientECDHParams>(&[34]).is_none());
     }
+
+    #[test]
+    fn export_keying_material_known_answer() {
+        let secrets = ConnectionSecrets {
+            randoms: ConnectionRandoms {
+                client: [0x11; 32],
+                server: [0x22; 32],
+            },
+            suite: TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256
+                .tls12()
+                .unwrap(),
+            master_secret: {
+                let mut ms = [0u8; 48];
+                for (i, b) in ms.iter_mut().enumerate() {
+                    *b = i as u8;
+                }
+                ms
+            },
+        };
+
+        /* These test vectors generated with OpenSSL. */
+        let expected_with_context = [
+            0x2a, 0xc1, 0x35, 0x96, 0xca, 0x40, 0xc4, 0xac, 0xd8, 0xf6, 0x34, 0x9b, 0x43, 0x40,
+            0x0f, 0x3c, 0x53, 0x93, 0xd7, 0x11, 0x59, 0x45, 0x41, 0xb2, 0x75, 0xf8, 0xf8, 0x00,
+            0x35, 0xfe, 0x71, 0xa2,
+        ];
+        let expected_without_context = [
+            0xf2, 0xcb, 0xaa, 0xfc, 0xb4, 0x64, 0x94, 0x92, 0x4f, 0xc6, 0xff, 0x3d, 0x9c, 0x87,
+            0x12, 0x3c, 0x9c, 0xd1, 0x2f, 0x6b, 0x68, 0x55, 0x16, 0x5f, 0xab, 0x86, 0x5c, 0x76,
+            0x71, 0x14, 0xd8, 0x89,
+        ];
+
+        let mut output = [0u8; 32];
+        secrets.export_keying_material(&mut output, b"label", Some(b"context"));
+        assert_eq!(output, expected_with_context);
+
+        secrets.export_keying_material(&mut output, b"label", None);
+        assert_eq!(output, expected_without_context);
+    }
 }
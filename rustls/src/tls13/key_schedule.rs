@@ -15,6 +15,7 @@ use ring::{
 #[derive(Debug, Clone, Copy, PartialEq)]
 enum SecretKind {
     ResumptionPskBinderKey,
+    ExternalPskBinderKey,
     ClientEarlyTrafficSecret,
     ClientHandshakeTrafficSecret,
     ServerHandshakeTrafficSecret,
@@ -30,6 +31,7 @@ impl SecretKind {
         use self::SecretKind::*;
         match self {
             ResumptionPskBinderKey => b"res binder",
+            ExternalPskBinderKey => b"ext binder",
             ClientEarlyTrafficSecret => b"c e traffic",
             ClientHandshakeTrafficSecret => b"c hs traffic",
             ServerHandshakeTrafficSecret => b"s hs traffic",
@@ -107,6 +109,14 @@ impl KeyScheduleEarly {
         self.ks
             .sign_verify_data_raw(&resumption_psk_binder_key, hs_hash.as_ref())
     }
+
+    pub fn external_psk_binder_key_and_sign_verify_data(&self, hs_hash: &Digest) -> hmac::Tag {
+        let external_psk_binder_key = self
+            .ks
+            .derive_for_empty_hash(SecretKind::ExternalPskBinderKey);
+        self.ks
+            .sign_verify_data_raw(&external_psk_binder_key, hs_hash.as_ref())
+    }
 }
 
 /// Pre-handshake key schedule
@@ -337,6 +347,13 @@ impl KeyScheduleTraffic {
         secret
     }
 
+    /// Sign the client's `Finished` in a post-handshake authentication,
+    /// which is keyed from the current client application traffic secret.
+    pub fn sign_client_post_handshake_finish(&self, hs_hash: &Digest) -> hmac::Tag {
+        self.ks
+            .sign_finish(&self.current_client_traffic_secret, hs_hash)
+    }
+
     pub fn resumption_master_secret_and_derive_ticket_psk(
         &self,
         hs_hash: &Digest,
@@ -592,6 +609,41 @@ mod test {
     use crate::KeyLog;
     use ring::{aead, hkdf};
 
+    #[test]
+    fn test_export_keying_material_vectors() {
+        /* The exporter master secret from the simple 1-RTT handshake in RFC8448;
+         * expected outputs generated with OpenSSL. */
+        let exporter_secret = [
+            0xfe, 0x22, 0xf8, 0x81, 0x17, 0x6e, 0xda, 0x18, 0xeb, 0x8f, 0x44, 0x52, 0x9e, 0x67,
+            0x92, 0xc5, 0x0c, 0x9a, 0x3f, 0x89, 0x45, 0x2f, 0x68, 0xd8, 0xae, 0x31, 0x1b, 0x43,
+            0x09, 0xd3, 0xcf, 0x50,
+        ];
+
+        let expected_with_context = [
+            0xce, 0x26, 0xb1, 0x4a, 0x53, 0x67, 0x2b, 0xf3, 0xc0, 0xbc, 0x3d, 0xfe, 0x54, 0x0c,
+            0xc9, 0xaa, 0xa8, 0x47, 0x5f, 0x17, 0xcf, 0x85, 0x72, 0xd0, 0x60, 0x73, 0x58, 0xd9,
+            0x66, 0x22, 0xd4, 0x8e,
+        ];
+
+        let expected_without_context = [
+            0xe6, 0x56, 0x32, 0xed, 0x2c, 0x7f, 0xc3, 0x65, 0xc1, 0x1f, 0x9d, 0x98, 0x92, 0x3a,
+            0x2d, 0xb3, 0x5f, 0xc1, 0x0a, 0xf9, 0x37, 0xdf, 0x91, 0x2f, 0x1a, 0x4a, 0x9a, 0x26,
+            0x45, 0x43, 0x6a, 0xb3,
+        ];
+
+        let ks = KeySchedule::new(hkdf::HKDF_SHA256, &[0u8; 32]);
+        let exporter_secret = hkdf::Prk::new_less_safe(hkdf::HKDF_SHA256, &exporter_secret);
+
+        let mut output = [0u8; 32];
+        ks.export_keying_material(&exporter_secret, &mut output, b"label", Some(b"context"))
+            .unwrap();
+        assert_eq!(output, expected_with_context);
+
+        ks.export_keying_material(&exporter_secret, &mut output, b"label", None)
+            .unwrap();
+        assert_eq!(output, expected_without_context);
+    }
+
     #[test]
     fn test_vectors() {
         /* These test vectors generated with OpenSSL. */
@@ -1,7 +1,6 @@
-use crate::cipher::{make_nonce, Iv, MessageDecrypter, MessageEncrypter};
+use crate::cipher::{make_nonce, record_payload, Iv, MessageDecrypter, MessageEncrypter};
 use crate::error::Error;
 use crate::msgs::base::Payload;
-use crate::msgs::codec::Codec;
 use crate::msgs::enums::{CipherSuite, ContentType, ProtocolVersion};
 use crate::msgs::fragmenter::MAX_FRAGMENT_LEN;
 use crate::msgs::message::{BorrowedPlainMessage, OpaqueMessage, PlainMessage};
@@ -25,7 +24,6 @@ pub static TLS13_CHACHA20_POLY1305_SHA256_INTERNAL: &Tls13CipherSuite = &Tls13Ci
         aead_algorithm: &ring::aead::CHACHA20_POLY1305,
     },
     hkdf_algorithm: ring::hkdf::HKDF_SHA256,
-    #[cfg(feature = "quic")]
     confidentiality_limit: u64::MAX,
     #[cfg(feature = "quic")]
     integrity_limit: 1 << 36,
@@ -40,7 +38,6 @@ pub static TLS13_AES_256_GCM_SHA384: SupportedCipherSuite =
             aead_algorithm: &ring::aead::AES_256_GCM,
         },
         hkdf_algorithm: ring::hkdf::HKDF_SHA384,
-        #[cfg(feature = "quic")]
         confidentiality_limit: 1 << 23,
         #[cfg(feature = "quic")]
         integrity_limit: 1 << 52,
@@ -57,7 +54,6 @@ pub static TLS13_AES_128_GCM_SHA256_INTERNAL: &Tls13CipherSuite = &Tls13CipherSu
         aead_algorithm: &ring::aead::AES_128_GCM,
     },
     hkdf_algorithm: ring::hkdf::HKDF_SHA256,
-    #[cfg(feature = "quic")]
     confidentiality_limit: 1 << 23,
     #[cfg(feature = "quic")]
     integrity_limit: 1 << 52,
@@ -68,7 +64,8 @@ pub struct Tls13CipherSuite {
     /// Common cipher suite fields.
     pub common: CipherSuiteCommon,
     pub hkdf_algorithm: ring::hkdf::Algorithm,
-    #[cfg(feature = "quic")]
+    /// How many records may be encrypted with one key, following
+    /// RFC 8446 section 5.5 and RFC 9001 section 6.6.
     pub confidentiality_limit: u64,
     #[cfg(feature = "quic")]
     pub integrity_limit: u64,
@@ -131,6 +128,82 @@ impl fmt::Debug for Tls13CipherSuite {
     }
 }
 
+/// A TLS1.3 pre-shared key provisioned out of band (RFC8446 section
+/// 2.2), rather than one from an earlier session.
+///
+/// A handshake using one is authenticated by the key alone, so no
+/// certificates are sent.  Such handshakes have forward secrecy, as
+/// rustls only uses PSKs alongside a key exchange.
+#[derive(Clone)]
+pub struct ExternalPsk {
+    identity: Vec<u8>,
+    secret: Vec<u8>,
+    pub(crate) hkdf_algorithm: hkdf::Algorithm,
+}
+
+impl ExternalPsk {
+    /// Make a PSK named `identity`, with the key `secret`, for use with
+    /// the cipher suites whose hash function is `hash`.
+    ///
+    /// This fails if `identity` is empty or longer than 65535 bytes,
+    /// if `secret` is empty, or if `hash` isn't SHA-256 or SHA-384.
+    pub fn new(
+        identity: Vec<u8>,
+        secret: Vec<u8>,
+        hash: &'static ring::digest::Algorithm,
+    ) -> Result<Self, Error> {
+        if identity.is_empty() || identity.len() > 0xffff {
+            return Err(Error::General(
+                "external PSK identity must be 1 to 65535 bytes".into(),
+            ));
+        }
+        if secret.is_empty() {
+            return Err(Error::General("external PSK secret is empty".into()));
+        }
+
+        let hkdf_algorithm = if *hash == ring::digest::SHA256 {
+            hkdf::HKDF_SHA256
+        } else if *hash == ring::digest::SHA384 {
+            hkdf::HKDF_SHA384
+        } else {
+            return Err(Error::General(
+                "external PSKs must use SHA-256 or SHA-384".into(),
+            ));
+        };
+
+        Ok(Self {
+            identity,
+            secret,
+            hkdf_algorithm,
+        })
+    }
+
+    /// The name by which the client and server know this PSK.
+    pub fn identity(&self) -> &[u8] {
+        &self.identity
+    }
+
+    /// The hash function of the cipher suites this PSK can be used with.
+    pub fn hash_algorithm(&self) -> &'static ring::digest::Algorithm {
+        self.hkdf_algorithm
+            .hmac_algorithm()
+            .digest_algorithm()
+    }
+
+    pub(crate) fn secret(&self) -> &[u8] {
+        &self.secret
+    }
+}
+
+impl fmt::Debug for ExternalPsk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ExternalPsk")
+            .field("identity", &self.identity)
+            .field("hash_algorithm", self.hash_algorithm())
+            .finish()
+    }
+}
+
 struct Tls13MessageEncrypter {
     enc_key: aead::LessSafeKey,
     iv: Iv,
@@ -164,19 +237,37 @@ fn make_tls13_aad(len: usize) -> ring::aead::Aad<[u8; TLS13_AAD_SIZE]> {
 // https://datatracker.ietf.org/doc/html/rfc8446#section-5.2
 const TLS13_AAD_SIZE: usize = 1 + 2 + 2;
 
-impl MessageEncrypter for Tls13MessageEncrypter {
-    fn encrypt(&self, msg: BorrowedPlainMessage, seq: u64) -> Result<OpaqueMessage, Error> {
-        let total_len = msg.payload.len() + 1 + self.enc_key.algorithm().tag_len();
-        let mut payload = Vec::with_capacity(total_len);
-        payload.extend_from_slice(msg.payload);
-        msg.typ.encode(&mut payload);
+/// TLS1.3 allows at most 256 bytes of expansion on a record
+/// (RFC 8446 section 5.2), rather than TLS1.2's 2048.
+const TLS13_MAX_CIPHERTEXT_LEN: usize = MAX_FRAGMENT_LEN + 256;
 
+impl Tls13MessageEncrypter {
+    /// How long the record payload encrypting `msg` is.
+    fn payload_len(&self, msg: &BorrowedPlainMessage) -> usize {
+        msg.payload.len() + 1 + self.enc_key.algorithm().tag_len()
+    }
+
+    /// Encrypt `msg` into `payload`, which is `payload_len` long.
+    fn seal(&self, msg: BorrowedPlainMessage, seq: u64, payload: &mut [u8]) -> Result<(), Error> {
         let nonce = make_nonce(&self.iv, seq);
-        let aad = make_tls13_aad(total_len);
+        let aad = make_tls13_aad(payload.len());
+
+        let (inner, tag) = payload.split_at_mut(msg.payload.len() + 1);
+        let (content, typ) = inner.split_at_mut(msg.payload.len());
+        content.copy_from_slice(msg.payload);
+        typ[0] = msg.typ.get_u8();
 
         self.enc_key
-            .seal_in_place_append_tag(nonce, aad, &mut payload)
-            .map_err(|_| Error::General("encrypt failed".to_string()))?;
+            .seal_in_place_separate_tag(nonce, aad, inner)
+            .map(|sealed| tag.copy_from_slice(sealed.as_ref()))
+            .map_err(|_| Error::General("encrypt failed".to_string()))
+    }
+}
+
+impl MessageEncrypter for Tls13MessageEncrypter {
+    fn encrypt(&self, msg: BorrowedPlainMessage, seq: u64) -> Result<OpaqueMessage, Error> {
+        let mut payload = vec![0; self.payload_len(&msg)];
+        self.seal(msg, seq, &mut payload)?;
 
         Ok(OpaqueMessage {
             typ: ContentType::ApplicationData,
@@ -184,11 +275,32 @@ impl MessageEncrypter for Tls13MessageEncrypter {
             payload: Payload::new(payload),
         })
     }
+
+    fn encrypt_into(
+        &self,
+        msg: BorrowedPlainMessage,
+        seq: u64,
+        out: &mut [u8],
+    ) -> Result<usize, Error> {
+        let payload = record_payload(
+            out,
+            ContentType::ApplicationData,
+            ProtocolVersion::TLSv1_2,
+            self.payload_len(&msg),
+        )?;
+        let len = payload.len();
+        self.seal(msg, seq, payload)?;
+        Ok(usize::from(OpaqueMessage::HEADER_SIZE) + len)
+    }
 }
 
 impl MessageDecrypter for Tls13MessageDecrypter {
     fn decrypt(&self, mut msg: OpaqueMessage, seq: u64) -> Result<PlainMessage, Error> {
         let payload = &mut msg.payload.0;
+        if payload.len() > TLS13_MAX_CIPHERTEXT_LEN {
+            return Err(Error::PeerSentOversizedRecord);
+        }
+
         if payload.len() < self.dec_key.algorithm().tag_len() {
             return Err(Error::DecryptError);
         }
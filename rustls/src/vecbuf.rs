@@ -9,6 +9,9 @@ use std::io::Read;
 /// more complexity when reading out.
 pub struct ChunkVecBuffer {
     chunks: VecDeque<Vec<u8>>,
+    /// How many bytes at the start of the first chunk have
+    /// already been read out.
+    consumed: usize,
     limit: Option<usize>,
 }
 
@@ -16,6 +19,7 @@ impl ChunkVecBuffer {
     pub fn new(limit: Option<usize>) -> Self {
         Self {
             chunks: VecDeque::new(),
+            consumed: 0,
             limit,
         }
     }
@@ -36,13 +40,23 @@ impl ChunkVecBuffer {
         self.chunks.is_empty()
     }
 
+    /// If the first chunk has been partly read out
+    pub fn is_partly_read(&self) -> bool {
+        self.consumed != 0
+    }
+
+    /// How many chunks we're storing, including any partly read
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
     /// How many bytes we're storing
     pub fn len(&self) -> usize {
         let mut len = 0;
         for ch in &self.chunks {
             len += ch.len();
         }
-        len
+        len - self.consumed
     }
 
     /// For a proposed append of `len` bytes, how many
@@ -76,10 +90,22 @@ impl ChunkVecBuffer {
         len
     }
 
-    /// Take one of the chunks from this object.  This
-    /// function panics if the object `is_empty`.
+    /// Take the given `bytes` and put them in front of everything else.
+    /// Must not be used once the first chunk has been partly read.
+    pub fn prepend(&mut self, bytes: Vec<u8>) {
+        debug_assert!(!self.is_partly_read());
+        if !bytes.is_empty() {
+            self.chunks.push_front(bytes);
+        }
+    }
+
+    /// Take the first chunk from this object, less any part of it
+    /// already read.  Returns `None` if the object `is_empty`.
     pub fn pop(&mut self) -> Option<Vec<u8>> {
-        self.chunks.pop_front()
+        let mut chunk = self.chunks.pop_front()?;
+        chunk.drain(..self.consumed);
+        self.consumed = 0;
+        Some(chunk)
     }
 
     /// Read data out of this object, writing it into `buf`
@@ -88,9 +114,7 @@ impl ChunkVecBuffer {
         let mut offs = 0;
 
         while offs < buf.len() && !self.is_empty() {
-            let used = self.chunks[0]
-                .as_slice()
-                .read(&mut buf[offs..])?;
+            let used = (&self.chunks[0][self.consumed..]).read(&mut buf[offs..])?;
 
             self.consume(used);
             offs += used;
@@ -103,7 +127,7 @@ impl ChunkVecBuffer {
     /// Read data out of this object, writing it into `buf`.
     pub fn read_buf(&mut self, buf: &mut io::ReadBuf<'_>) -> io::Result<()> {
         while !self.is_empty() && buf.remaining() > 0 {
-            let chunk = self.chunks[0].as_slice();
+            let chunk = &self.chunks[0][self.consumed..];
             let used = std::cmp::min(chunk.len(), buf.remaining());
             buf.append(&chunk[..used]);
             self.consume(used);
@@ -112,15 +136,19 @@ impl ChunkVecBuffer {
         Ok(())
     }
 
+    /// Discard `used` bytes from the front.  A partly used chunk
+    /// is kept whole, rather than copying out what remains.
     fn consume(&mut self, mut used: usize) {
-        while let Some(mut buf) = self.chunks.pop_front() {
-            if used < buf.len() {
-                self.chunks
-                    .push_front(buf.split_off(used));
+        while let Some(buf) = self.chunks.front() {
+            let remaining = buf.len() - self.consumed;
+            if used < remaining {
+                self.consumed += used;
                 break;
-            } else {
-                used -= buf.len();
             }
+
+            used -= remaining;
+            self.consumed = 0;
+            self.chunks.pop_front();
         }
     }
 
@@ -134,11 +162,32 @@ impl ChunkVecBuffer {
         for (iov, chunk) in bufs.iter_mut().zip(self.chunks.iter()) {
             *iov = io::IoSlice::new(chunk);
         }
+        bufs[0] = io::IoSlice::new(&self.chunks[0][self.consumed..]);
         let len = cmp::min(bufs.len(), self.chunks.len());
         let used = wr.write_vectored(&bufs[..len])?;
         self.consume(used);
         Ok(used)
     }
+
+    /// Like `write_to`, but keep passing data to `wr` until we're
+    /// empty or it stops accepting any.
+    ///
+    /// An error from `wr` is only returned if nothing was written.
+    pub fn write_all_to(&mut self, wr: &mut dyn io::Write) -> io::Result<usize> {
+        let mut written = 0;
+
+        while !self.is_empty() {
+            match self.write_to(wr) {
+                Ok(0) => break,
+                Ok(used) => written += used,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) if written == 0 => return Err(e),
+                Err(_) => break,
+            }
+        }
+
+        Ok(written)
+    }
 }
 
 #[cfg(test)]
@@ -158,6 +207,47 @@ mod test {
         assert_eq!(buf.to_vec(), b"helloworldhe".to_vec());
     }
 
+    #[test]
+    fn partial_reads_and_writes() {
+        let mut cvb = ChunkVecBuffer::new(None);
+        cvb.append(b"hello".to_vec());
+        cvb.append(b"world".to_vec());
+
+        let mut buf = [0u8; 3];
+        assert_eq!(cvb.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf, b"hel");
+        assert_eq!(cvb.len(), 7);
+
+        let mut out = [0u8; 4];
+        assert_eq!(cvb.write_to(&mut &mut out[..]).unwrap(), 4);
+        assert_eq!(&out, b"lowo");
+        assert_eq!(cvb.len(), 3);
+
+        assert_eq!(cvb.pop(), Some(b"rld".to_vec()));
+        assert!(cvb.is_empty());
+    }
+
+    #[test]
+    fn write_all_to_stops_when_full() {
+        let mut cvb = ChunkVecBuffer::new(None);
+        cvb.append(b"hello".to_vec());
+        cvb.append(b"world".to_vec());
+
+        // Stops once `out` is full.
+        let mut out = [0u8; 8];
+        assert_eq!(
+            cvb.write_all_to(&mut &mut out[..])
+                .unwrap(),
+            8
+        );
+        assert_eq!(&out, b"hellowor");
+
+        let mut out = Vec::new();
+        assert_eq!(cvb.write_all_to(&mut out).unwrap(), 2);
+        assert_eq!(out, b"ld".to_vec());
+        assert!(cvb.is_empty());
+    }
+
     #[cfg(read_buf)]
     #[test]
     fn read_buf() {
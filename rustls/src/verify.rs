@@ -1,17 +1,23 @@
 use crate::anchors::{OwnedTrustAnchor, RootCertStore};
 use crate::client::ServerName;
-use crate::error::Error;
+use crate::error::{CertificateError, Error};
 use crate::key::Certificate;
 #[cfg(feature = "logging")]
 use crate::log::{debug, trace, warn};
+use crate::msgs::codec::Codec;
 use crate::msgs::enums::SignatureScheme;
-use crate::msgs::handshake::{DigitallySignedStruct, DistinguishedNames};
+use crate::msgs::handshake::{
+    Credential, DelegatedCredentialPayload, DigitallySignedStruct, DistinguishedNames,
+};
+use crate::x509;
 
 use ring::digest::Digest;
 
 use std::convert::TryFrom;
+use std::iter;
+use std::net::IpAddr;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 type SignatureAlgorithms = &'static [&'static webpki::SignatureAlgorithm];
 
@@ -285,11 +291,63 @@ pub trait ClientCertVerifier: Send + Sync {
     }
 }
 
+/// Something that can verify a peer's raw public key (RFC 7250).
+///
+/// Once a raw public key certificate type has been negotiated, the peer's
+/// `Certificate` message carries a DER-encoded `SubjectPublicKeyInfo` rather
+/// than a certificate chain.  The `ServerCertVerifier` or `ClientCertVerifier`
+/// is not consulted at all in that case: there is no chain to build, so this
+/// alone decides whether the key belongs to an acceptable peer -- for example
+/// by comparing it against a set of pinned keys.
+///
+/// Certificate types are only negotiated in TLS1.3.
+#[allow(unreachable_pub)]
+pub trait RawPublicKeyVerifier: Send + Sync {
+    /// Verify the DER-encoded `SubjectPublicKeyInfo` presented by the peer.
+    ///
+    /// Return `Ok(())` if and only if `spki` is acceptable.  Otherwise, return
+    /// an error -- rustls will send an alert and abort the connection.
+    fn verify_raw_public_key(&self, spki: &[u8]) -> Result<(), Error>;
+
+    /// Verify a TLS1.3 signature allegedly made with the private key
+    /// corresponding to `spki`.
+    ///
+    /// `spki` is the same key that was previously accepted by a call to
+    /// `verify_raw_public_key`.
+    ///
+    /// This trait method has a default implementation that uses ring to verify
+    /// the signature, for the schemes listed by the default
+    /// `supported_verify_schemes`.
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        spki: &[u8],
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_raw_public_key(message, spki, dss)
+    }
+
+    /// Return the list of SignatureSchemes that this verifier will handle,
+    /// in `verify_tls13_signature` calls.
+    ///
+    /// This should be in priority order, with the most preferred first.
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        vec![
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ED25519,
+            SignatureScheme::RSA_PSS_SHA512,
+            SignatureScheme::RSA_PSS_SHA384,
+            SignatureScheme::RSA_PSS_SHA256,
+        ]
+    }
+}
+
 impl ServerCertVerifier for WebPkiVerifier {
     /// Will verify the certificate is valid in the following ways:
     /// - Signed by a  trusted `RootCertStore` CA
     /// - Not Expired
-    /// - Valid for DNS entry
+    /// - Valid for DNS entry or IP address
     fn verify_server_cert(
         &self,
         end_entity: &Certificate,
@@ -302,8 +360,6 @@ impl ServerCertVerifier for WebPkiVerifier {
         let (cert, chain, trustroots) = prepare(end_entity, intermediates, &self.roots)?;
         let webpki_now = webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)?;
 
-        let ServerName::DnsName(dns_name) = server_name;
-
         let cert = cert
             .verify_is_valid_tls_server_cert(
                 SUPPORTED_SIG_ALGS,
@@ -311,8 +367,10 @@ impl ServerCertVerifier for WebPkiVerifier {
                 &chain,
                 webpki_now,
             )
-            .map_err(pki_error)
+            .map_err(|err| chain_error(err, end_entity, intermediates, now))
             .map(|_| cert)?;
+        self.check_required_intermediates(end_entity, intermediates, webpki_now)?;
+        check_key_purposes(end_entity, &self.required_key_purposes)?;
 
         if let Some(policy) = &self.ct_policy {
             policy.verify(end_entity, now, scts)?;
@@ -322,17 +380,80 @@ impl ServerCertVerifier for WebPkiVerifier {
             trace!("Unvalidated OCSP response: {:?}", ocsp_response.to_vec());
         }
 
-        cert.verify_is_valid_for_dns_name(dns_name.0.as_ref())
-            .map_err(pki_error)
-            .map(|_| ServerCertVerified::assertion())
+        match server_name {
+            ServerName::DnsName(dns_name) => cert
+                .verify_is_valid_for_dns_name(dns_name.0.as_ref())
+                .map_err(|err| match err {
+                    webpki::Error::CertNotValidForName => not_valid_for_name(dns_name.as_ref()),
+                    err => pki_error(err),
+                })
+                .map(|_| ServerCertVerified::assertion()),
+            ServerName::IpAddress(addr) => {
+                // webpki only matches DNS names, so look for the address ourselves.
+                let ip = match addr {
+                    IpAddr::V4(ip) => ip.octets().to_vec(),
+                    IpAddr::V6(ip) => ip.octets().to_vec(),
+                };
+                match x509::subject_alt_ip_addresses(&end_entity.0) {
+                    Some(addresses) if addresses.contains(&ip.as_slice()) => {
+                        Ok(ServerCertVerified::assertion())
+                    }
+                    Some(_) => Err(not_valid_for_name(&addr.to_string())),
+                    None => Err(Error::InvalidCertificateEncoding),
+                }
+            }
+        }
     }
 }
 
+/// The revocation status of a certificate, as given by an OCSP response.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OcspStatus {
+    /// The certificate is not revoked.
+    Good,
+
+    /// The certificate has been revoked.
+    Revoked,
+
+    /// The responder does not know the certificate.
+    Unknown,
+}
+
+/// Something that can check an OCSP response (RFC6960) stapled by the
+/// server, to learn whether its certificate has been revoked.
+pub trait OcspVerifier: Send + Sync {
+    /// Check `response_der`, a DER-encoded OCSP response stapled for the
+    /// certificate `end_entity`, which was issued by `issuer`.
+    ///
+    /// This is called after the certificate chain has been verified.
+    /// An error should be returned if the response is malformed, stale,
+    /// or not signed on behalf of `issuer`; the handshake then fails.
+    fn verify(
+        &self,
+        response_der: &[u8],
+        end_entity: &Certificate,
+        issuer: &Certificate,
+    ) -> Result<OcspStatus, Error>;
+}
+
+/// Application policy run on a server certificate once it has been
+/// verified, which can still reject it.
+pub trait ServerCertPolicy: Send + Sync {
+    /// Check `end_entity`, the server's certificate, which has already
+    /// passed the `ServerCertVerifier` for `server_name`.
+    ///
+    /// Returning an error fails the handshake, with an alert chosen
+    /// as for an error from the `ServerCertVerifier`.
+    fn check(&self, end_entity: &Certificate, server_name: &ServerName) -> Result<(), Error>;
+}
+
 /// Default `ServerCertVerifier`, see the trait impl for more information.
 #[allow(unreachable_pub)]
 pub struct WebPkiVerifier {
     roots: RootCertStore,
     ct_policy: Option<CertificateTransparencyPolicy>,
+    required_intermediates: Vec<Certificate>,
+    required_key_purposes: Vec<Vec<u8>>,
 }
 
 #[allow(unreachable_pub)]
@@ -345,23 +466,299 @@ impl WebPkiVerifier {
     /// Transparency. Currently CT log enforcement is opportunistic; see
     /// <https://github.com/rustls/rustls/issues/479>.
     pub fn new(roots: RootCertStore, ct_policy: Option<CertificateTransparencyPolicy>) -> Self {
-        Self { roots, ct_policy }
+        Self::new_with_required_key_purposes(roots, ct_policy, Vec::new())
+    }
+
+    /// Constructs a new `WebPkiVerifier` which also requires the server's
+    /// certificate to list each of `purposes` in its extendedKeyUsage
+    /// extension.
+    ///
+    /// Each purpose is an OID, DER-encoded without its tag and length.
+    /// They are checked once the chain has been verified, and a missing
+    /// one fails verification with [`CertificateError::MissingKeyPurpose`].
+    /// The certificate must still be usable for TLS servers as well: webpki
+    /// rejects it if it has the extension without `id-kp-serverAuth`.
+    pub fn new_with_required_key_purposes(
+        roots: RootCertStore,
+        ct_policy: Option<CertificateTransparencyPolicy>,
+        purposes: Vec<Vec<u8>>,
+    ) -> Self {
+        Self {
+            roots,
+            ct_policy,
+            required_intermediates: Vec::new(),
+            required_key_purposes: purposes,
+        }
+    }
+
+    /// Also require the server's certificate to be issued under one of
+    /// `intermediates`: some valid path from it to one of the roots must
+    /// pass through one of them.
+    ///
+    /// An otherwise valid chain which reaches a root by another path is
+    /// rejected with [`CertificateError::NoRequiredIntermediate`].  Each
+    /// intermediate is matched as a whole certificate, so a cross-signed
+    /// certificate for the same CA, leading to a different root, does not
+    /// count.  The whole path through the intermediate is verified, so it
+    /// must itself be valid, and issued under the roots.  The server need
+    /// not send the intermediates themselves.
+    ///
+    /// This fails if any of `intermediates` can't be parsed.
+    pub fn with_required_intermediates(
+        mut self,
+        intermediates: &[Certificate],
+    ) -> Result<Self, Error> {
+        for intermediate in intermediates {
+            webpki::EndEntityCert::try_from(intermediate.0.as_ref()).map_err(pki_error)?;
+            self.required_intermediates
+                .push(intermediate.clone());
+        }
+        Ok(self)
     }
 
     /// Returns the signature verification methods supported by
     /// webpki.
+    ///
+    /// These are [`ALL_SIGNATURE_SCHEMES`], in the same order.
     pub fn verification_schemes() -> Vec<SignatureScheme> {
-        vec![
-            SignatureScheme::ECDSA_NISTP384_SHA384,
-            SignatureScheme::ECDSA_NISTP256_SHA256,
-            SignatureScheme::ED25519,
-            SignatureScheme::RSA_PSS_SHA512,
-            SignatureScheme::RSA_PSS_SHA384,
-            SignatureScheme::RSA_PSS_SHA256,
-            SignatureScheme::RSA_PKCS1_SHA512,
-            SignatureScheme::RSA_PKCS1_SHA384,
-            SignatureScheme::RSA_PKCS1_SHA256,
-        ]
+        ALL_SIGNATURE_SCHEMES.to_vec()
+    }
+
+    /// Check that `end_entity`, which has already been verified against
+    /// the roots, has a verified path through a required intermediate.
+    fn check_required_intermediates(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        now: webpki::Time,
+    ) -> Result<(), Error> {
+        if self.required_intermediates.is_empty() {
+            return Ok(());
+        }
+
+        let mut candidates: Vec<&Certificate> = Vec::new();
+        for cert in intermediates
+            .iter()
+            .chain(&self.required_intermediates)
+        {
+            if !candidates.contains(&cert) {
+                candidates.push(cert);
+            }
+        }
+
+        let through_required = |path: &[&Certificate]| {
+            path.iter().any(|cert| {
+                self.required_intermediates
+                    .contains(cert)
+            })
+        };
+        if self.has_verified_path(end_entity, &candidates, now, &through_required) {
+            Ok(())
+        } else {
+            Err(Error::InvalidCertificate(
+                CertificateError::NoRequiredIntermediate,
+            ))
+        }
+    }
+
+    /// Whether `end_entity` has a path to one of the roots through some
+    /// of `candidates` which webpki accepts, and for which `wanted`
+    /// returns true given the intermediates on it, in order.
+    ///
+    /// webpki is only ever given the intermediates on the path being
+    /// tried, and these are chosen so that it can build no other path
+    /// from them: no two have the same subject, and none has a root's
+    /// subject.  So `wanted` is asked about exactly the path verified.
+    fn has_verified_path(
+        &self,
+        end_entity: &Certificate,
+        candidates: &[&Certificate],
+        now: webpki::Time,
+        wanted: &dyn Fn(&[&Certificate]) -> bool,
+    ) -> bool {
+        let cert = match webpki::EndEntityCert::try_from(end_entity.0.as_ref()) {
+            Ok(cert) => cert,
+            Err(_) => return false,
+        };
+        let trustroots: Vec<webpki::TrustAnchor> = self
+            .roots
+            .roots
+            .iter()
+            .map(OwnedTrustAnchor::to_trust_anchor)
+            .collect();
+
+        let search = PathSearch {
+            cert: &cert,
+            candidates,
+            trustroots: &trustroots,
+            now,
+            wanted,
+        };
+        search.extend(end_entity, &mut Vec::new())
+    }
+}
+
+/// The most intermediates `WebPkiVerifier::has_verified_path` puts
+/// on a path.
+const MAX_PATH_INTERMEDIATES: usize = 6;
+
+struct PathSearch<'a> {
+    cert: &'a webpki::EndEntityCert<'a>,
+    candidates: &'a [&'a Certificate],
+    trustroots: &'a [webpki::TrustAnchor<'a>],
+    now: webpki::Time,
+    wanted: &'a dyn Fn(&[&Certificate]) -> bool,
+}
+
+impl<'a> PathSearch<'a> {
+    /// Try each path which continues `path` from `issued`, the end
+    /// entity certificate or the last intermediate on `path`.
+    fn extend(&self, issued: &Certificate, path: &mut Vec<&'a Certificate>) -> bool {
+        let issuer = match x509::issuer_and_subject(&issued.0) {
+            Some((issuer, _)) => issuer,
+            None => return false,
+        };
+
+        if self
+            .trustroots
+            .iter()
+            .any(|root| root.subject == issuer)
+        {
+            return (self.wanted)(path) && self.verify(path);
+        }
+
+        if path.len() == MAX_PATH_INTERMEDIATES {
+            return false;
+        }
+
+        for &candidate in self.candidates {
+            let subject = match x509::issuer_and_subject(&candidate.0) {
+                Some((_, subject)) => subject,
+                None => continue,
+            };
+            let on_path = path
+                .iter()
+                .any(|cert| subject_of(cert) == Some(subject));
+            if subject != issuer || on_path {
+                continue;
+            }
+
+            path.push(candidate);
+            if self.extend(candidate, path) {
+                return true;
+            }
+            path.pop();
+        }
+        false
+    }
+
+    fn verify(&self, path: &[&Certificate]) -> bool {
+        let chain: Vec<&[u8]> = path
+            .iter()
+            .map(|cert| cert.0.as_ref())
+            .collect();
+        self.cert
+            .verify_is_valid_tls_server_cert(
+                SUPPORTED_SIG_ALGS,
+                &webpki::TlsServerTrustAnchors(self.trustroots),
+                &chain,
+                self.now,
+            )
+            .is_ok()
+    }
+}
+
+fn subject_of(cert: &Certificate) -> Option<&[u8]> {
+    x509::issuer_and_subject(&cert.0).map(|(_, subject)| subject)
+}
+
+/// A `ServerCertVerifier` which pins public keys: it accepts a server
+/// only if a certificate with a `SubjectPublicKeyInfo` whose SHA-256
+/// hash is one of the pins vouches for it.
+///
+/// If it has trust anchors, the chain must first pass the checks made by
+/// [`WebPkiVerifier`], and then the server's certificate or one of the
+/// intermediates on a verified path from it to those anchors must be
+/// pinned.  Otherwise only the server's own certificate can match a pin,
+/// as the server could send any intermediates alongside it, and nothing
+/// else is checked -- not even that the certificate is valid for the
+/// server's name.
+#[allow(unreachable_pub)]
+pub struct SpkiPinVerifier {
+    pins: Vec<[u8; 32]>,
+    chain_verifier: Option<WebPkiVerifier>,
+}
+
+#[allow(unreachable_pub)]
+impl SpkiPinVerifier {
+    /// Constructs a new `SpkiPinVerifier`.
+    ///
+    /// `pins` are SHA-256 hashes of DER-encoded `SubjectPublicKeyInfo`s,
+    /// as returned by [`SpkiPinVerifier::pin_for`].
+    ///
+    /// `roots`, if given, is the set of trust anchors that the chain is
+    /// also verified against.
+    pub fn new(pins: Vec<[u8; 32]>, roots: Option<RootCertStore>) -> Self {
+        Self {
+            pins,
+            chain_verifier: roots.map(|roots| WebPkiVerifier::new(roots, None)),
+        }
+    }
+
+    /// Returns the pin for the public key in `cert`, or `None` if it
+    /// can't be found.
+    pub fn pin_for(cert: &Certificate) -> Option<[u8; 32]> {
+        let spki = x509::subject_public_key_info(&cert.0)?;
+        let mut pin = [0u8; 32];
+        pin.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, spki).as_ref());
+        Some(pin)
+    }
+}
+
+impl ServerCertVerifier for SpkiPinVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let is_pinned = |cert: &Certificate| match Self::pin_for(cert) {
+            Some(pin) => self.pins.contains(&pin),
+            None => false,
+        };
+
+        let pinned = match &self.chain_verifier {
+            Some(chain_verifier) => {
+                chain_verifier.verify_server_cert(
+                    end_entity,
+                    intermediates,
+                    server_name,
+                    scts,
+                    ocsp_response,
+                    now,
+                )?;
+
+                let webpki_now =
+                    webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)?;
+                let candidates: Vec<&Certificate> = intermediates.iter().collect();
+                is_pinned(end_entity)
+                    || chain_verifier.has_verified_path(
+                        end_entity,
+                        &candidates,
+                        webpki_now,
+                        &|path: &[&Certificate]| path.iter().any(|cert| is_pinned(cert)),
+                    )
+            }
+            None => is_pinned(end_entity),
+        };
+        if !pinned {
+            return Err(Error::NoPinMatched);
+        }
+
+        Ok(ServerCertVerified::assertion())
     }
 }
 
@@ -441,6 +838,61 @@ impl CertificateTransparencyPolicy {
     }
 }
 
+/// A Certificate Transparency policy which the client enforces on every
+/// server certificate, whatever its [`ServerCertVerifier`].
+///
+/// Unlike [`CertificateTransparencyPolicy`], this is not opportunistic: the
+/// server must send valid SCTs from at least `min_scts` distinct logs in the list.
+#[derive(Clone)]
+pub struct CtPolicy {
+    logs: &'static [&'static sct::Log<'static>],
+    min_scts: usize,
+}
+
+impl CtPolicy {
+    /// Create a new policy trusting `logs`, and requiring valid SCTs
+    /// from at least `min_scts` of them.
+    pub fn new(logs: &'static [&'static sct::Log<'static>], min_scts: usize) -> Self {
+        Self { logs, min_scts }
+    }
+
+    pub(crate) fn verify(
+        &self,
+        cert: &Certificate,
+        now: SystemTime,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+    ) -> Result<(), Error> {
+        let now = unix_time_millis(now)?;
+        let mut valid_logs = Vec::new();
+        for sct in scts {
+            #[cfg_attr(not(feature = "logging"), allow(unused_variables))]
+            match sct::verify_sct(&cert.0, sct, now, self.logs) {
+                Ok(index) => {
+                    if !valid_logs.contains(&index) {
+                        valid_logs.push(index);
+                    }
+                }
+                Err(e) if e.should_be_fatal() => return Err(Error::InvalidSct(e)),
+                Err(e) => debug!("SCT ignored because {:?}", e),
+            }
+        }
+
+        if valid_logs.len() < self.min_scts {
+            warn!(
+                "Only {} of {} required SCTs are valid",
+                valid_logs.len(),
+                self.min_scts
+            );
+            return Err(Error::TooFewValidScts {
+                required: self.min_scts,
+                valid: valid_logs.len(),
+            });
+        }
+
+        Ok(())
+    }
+}
+
 type CertChainAndRoots<'a, 'b> = (
     webpki::EndEntityCert<'a>,
     Vec<&'a [u8]>,
@@ -473,6 +925,7 @@ fn prepare<'a, 'b>(
 /// certificate, without any name checking.
 pub struct AllowAnyAuthenticatedClient {
     roots: RootCertStore,
+    required_key_purposes: Vec<Vec<u8>>,
 }
 
 impl AllowAnyAuthenticatedClient {
@@ -480,7 +933,23 @@ impl AllowAnyAuthenticatedClient {
     ///
     /// `roots` is the list of trust anchors to use for certificate validation.
     pub fn new(roots: RootCertStore) -> Arc<dyn ClientCertVerifier> {
-        Arc::new(Self { roots })
+        Self::new_with_required_key_purposes(roots, Vec::new())
+    }
+
+    /// Construct a new `AllowAnyAuthenticatedClient` which also requires
+    /// the client's certificate to list each of `purposes` in its
+    /// extendedKeyUsage extension.
+    ///
+    /// These are checked as by [`WebPkiVerifier::new_with_required_key_purposes`],
+    /// and the certificate must likewise still allow `id-kp-clientAuth`.
+    pub fn new_with_required_key_purposes(
+        roots: RootCertStore,
+        purposes: Vec<Vec<u8>>,
+    ) -> Arc<dyn ClientCertVerifier> {
+        Arc::new(Self {
+            roots,
+            required_key_purposes: purposes,
+        })
     }
 }
 
@@ -500,15 +969,16 @@ impl ClientCertVerifier for AllowAnyAuthenticatedClient {
         now: SystemTime,
     ) -> Result<ClientCertVerified, Error> {
         let (cert, chain, trustroots) = prepare(end_entity, intermediates, &self.roots)?;
-        let now = webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)?;
+        let webpki_now = webpki::Time::try_from(now).map_err(|_| Error::FailedToGetCurrentTime)?;
         cert.verify_is_valid_tls_client_cert(
             SUPPORTED_SIG_ALGS,
             &webpki::TlsClientTrustAnchors(&trustroots),
             &chain,
-            now,
+            webpki_now,
         )
-        .map_err(pki_error)
-        .map(|_| ClientCertVerified::assertion())
+        .map_err(|err| chain_error(err, end_entity, intermediates, now))?;
+        check_key_purposes(end_entity, &self.required_key_purposes)
+            .map(|_| ClientCertVerified::assertion())
     }
 }
 
@@ -527,8 +997,23 @@ impl AllowAnyAnonymousOrAuthenticatedClient {
     ///
     /// `roots` is the list of trust anchors to use for certificate validation.
     pub fn new(roots: RootCertStore) -> Arc<dyn ClientCertVerifier> {
+        Self::new_with_required_key_purposes(roots, Vec::new())
+    }
+
+    /// Construct a new `AllowAnyAnonymousOrAuthenticatedClient` which
+    /// requires the certificate of a client that presents one to list
+    /// each of `purposes`.
+    ///
+    /// See [`AllowAnyAuthenticatedClient::new_with_required_key_purposes`].
+    pub fn new_with_required_key_purposes(
+        roots: RootCertStore,
+        purposes: Vec<Vec<u8>>,
+    ) -> Arc<dyn ClientCertVerifier> {
         Arc::new(Self {
-            inner: AllowAnyAuthenticatedClient { roots },
+            inner: AllowAnyAuthenticatedClient {
+                roots,
+                required_key_purposes: purposes,
+            },
         })
     }
 }
@@ -557,6 +1042,68 @@ impl ClientCertVerifier for AllowAnyAnonymousOrAuthenticatedClient {
     }
 }
 
+/// Map an `error` from verifying the chain starting `end_entity` at
+/// time `now`, being precise about the failures `CertificateError`
+/// distinguishes.
+fn chain_error(
+    error: webpki::Error,
+    end_entity: &Certificate,
+    intermediates: &[Certificate],
+    now: SystemTime,
+) -> Error {
+    use webpki::Error::*;
+    // webpki doesn't say which certificate was out of date, so find one.
+    let validities = iter::once(end_entity)
+        .chain(intermediates)
+        .filter_map(|cert| x509::validity(&cert.0));
+
+    let error = match error {
+        CertExpired => CertificateError::Expired {
+            now,
+            not_after: validities
+                .map(|(_, not_after)| not_after)
+                .find(|not_after| *not_after < now),
+        },
+        CertNotValidYet => CertificateError::NotValidYet {
+            now,
+            not_before: validities
+                .map(|(not_before, _)| not_before)
+                .find(|not_before| *not_before > now),
+        },
+        UnknownIssuer => CertificateError::UnknownIssuer,
+        InvalidSignatureForPublicKey => CertificateError::BadSignature,
+        UnsupportedSignatureAlgorithmForPublicKey => CertificateError::UnsupportedKeyType,
+        e => return pki_error(e),
+    };
+    Error::InvalidCertificate(error)
+}
+
+fn not_valid_for_name(name: &str) -> Error {
+    Error::InvalidCertificate(CertificateError::NotValidForName { name: name.into() })
+}
+
+/// Check that `end_entity` lists every one of `required` in its
+/// extendedKeyUsage extension.
+fn check_key_purposes(end_entity: &Certificate, required: &[Vec<u8>]) -> Result<(), Error> {
+    if required.is_empty() {
+        return Ok(());
+    }
+
+    let purposes =
+        x509::extended_key_usages(&end_entity.0).ok_or(Error::InvalidCertificateEncoding)?;
+    match required
+        .iter()
+        .find(|purpose| !purposes.contains(&purpose.as_slice()))
+    {
+        Some(missing) => Err(Error::InvalidCertificate(
+            CertificateError::MissingKeyPurpose {
+                purpose: missing.clone(),
+            },
+        )),
+        None => Ok(()),
+    }
+}
+
 fn pki_error(error: webpki::Error) -> Error {
     use webpki::Error::*;
     match error {
@@ -636,6 +1183,31 @@ fn convert_scheme(scheme: SignatureScheme) -> Result<SignatureAlgorithms, Error>
     }
 }
 
+/// A list of all the signature schemes rustls can verify, most preferred
+/// first.
+///
+/// Like [`ALL_CIPHER_SUITES`](crate::ALL_CIPHER_SUITES) and
+/// [`ALL_KX_GROUPS`](crate::ALL_KX_GROUPS), this describes what the
+/// cryptography rustls is built with -- *ring* and webpki -- can do, so
+/// it is the widest choice that can be passed to
+/// [`ConfigBuilder::with_signature_schemes`](crate::ConfigBuilder::with_signature_schemes).
+pub static ALL_SIGNATURE_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ED25519,
+    SignatureScheme::RSA_PSS_SHA512,
+    SignatureScheme::RSA_PSS_SHA384,
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::RSA_PKCS1_SHA512,
+    SignatureScheme::RSA_PKCS1_SHA384,
+    SignatureScheme::RSA_PKCS1_SHA256,
+];
+
+/// Whether signatures using `scheme` can be verified, in TLS1.2 at least.
+pub(crate) fn supports_signature_scheme(scheme: SignatureScheme) -> bool {
+    convert_scheme(scheme).is_ok()
+}
+
 fn verify_sig_using_any_alg(
     cert: &webpki::EndEntityCert,
     algs: SignatureAlgorithms,
@@ -654,7 +1226,7 @@ fn verify_sig_using_any_alg(
     Err(webpki::Error::UnsupportedSignatureAlgorithmForPublicKey)
 }
 
-fn verify_signed_struct(
+pub(crate) fn verify_signed_struct(
     message: &[u8],
     cert: &Certificate,
     dss: &DigitallySignedStruct,
@@ -717,6 +1289,96 @@ fn construct_tls13_verify_message(
     msg
 }
 
+/// Constructs the message a delegated credential's signature covers,
+/// as specified in section 4 of RFC9345.
+pub(crate) fn construct_delegated_credential_message(
+    end_entity: &Certificate,
+    cred: &Credential,
+    algorithm: SignatureScheme,
+) -> Vec<u8> {
+    let mut msg = Vec::new();
+    msg.resize(64, 0x20u8);
+    msg.extend_from_slice(b"TLS, server delegated credentials\x00");
+    msg.extend_from_slice(&end_entity.0);
+    cred.encode(&mut msg);
+    algorithm.encode(&mut msg);
+    msg
+}
+
+/// The schemes a delegated credential's key may sign the handshake
+/// with.  Only the key's `SubjectPublicKeyInfo` is known, so these are
+/// the TLS1.3 schemes we can verify with one of those.
+pub(crate) static DELEGATED_CREDENTIAL_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ECDSA_NISTP384_SHA384,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+    SignatureScheme::ED25519,
+    SignatureScheme::RSA_PSS_SHA512,
+    SignatureScheme::RSA_PSS_SHA384,
+    SignatureScheme::RSA_PSS_SHA256,
+];
+
+/// The longest a delegated credential may have left to run.
+const MAX_DELEGATED_CREDENTIAL_VALIDITY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Checks the delegated credential `dc` which came with `end_entity`,
+/// as section 4.1.3 of RFC9345 requires.  `end_entity` must have the
+/// DelegationUsage extension and have signed `dc`, and `dc` must be
+/// valid at `now` and for no more than seven days after.
+///
+/// `end_entity` itself must be verified separately.
+pub(crate) fn verify_delegated_credential(
+    end_entity: &Certificate,
+    dc: &DelegatedCredentialPayload,
+    now: SystemTime,
+) -> Result<(), Error> {
+    let bad = || Error::InvalidCertificate(CertificateError::BadDelegatedCredential);
+
+    if !x509::has_delegation_usage(&end_entity.0).ok_or(Error::InvalidCertificateEncoding)? {
+        warn!("delegated credential from certificate without DelegationUsage");
+        return Err(bad());
+    }
+
+    let (not_before, _) = x509::validity(&end_entity.0).ok_or(Error::InvalidCertificateEncoding)?;
+    let expiry = not_before + Duration::from_secs(dc.cred.valid_time.into());
+    if now >= expiry {
+        return Err(Error::InvalidCertificate(
+            CertificateError::DelegatedCredentialExpired { now, expiry },
+        ));
+    }
+    if expiry
+        .duration_since(now)
+        .unwrap_or_default()
+        > MAX_DELEGATED_CREDENTIAL_VALIDITY
+    {
+        warn!("delegated credential is valid for more than seven days");
+        return Err(bad());
+    }
+
+    let message = construct_delegated_credential_message(end_entity, &dc.cred, dc.algorithm);
+    let dss = DigitallySignedStruct::new(dc.algorithm, dc.signature.0.clone());
+    verify_tls13(&message, end_entity, &dss)
+        .map(|_| ())
+        .map_err(|_| bad())
+}
+
+/// Verify the server's signature `dss` on the handshake, which must be
+/// made by the key the delegated credential `dc` delegates to.
+pub(crate) fn verify_delegated_tls13_signature(
+    message: &[u8],
+    dc: &DelegatedCredentialPayload,
+    dss: &DigitallySignedStruct,
+) -> Result<HandshakeSignatureValid, Error> {
+    if dss.scheme != dc.cred.dc_cert_verify_algorithm {
+        let error_msg = format!(
+            "received sig scheme {:?} for delegated credential for {:?}",
+            dss.scheme, dc.cred.dc_cert_verify_algorithm
+        );
+        return Err(Error::PeerMisbehavedError(error_msg));
+    }
+
+    verify_tls13_raw_public_key(message, &dc.cred.public_key.0, dss)
+}
+
 fn verify_tls13(
     msg: &[u8],
     cert: &Certificate,
@@ -731,6 +1393,54 @@ fn verify_tls13(
         .map(|_| HandshakeSignatureValid::assertion())
 }
 
+// DER-encoded AlgorithmIdentifier contents, as found in a SubjectPublicKeyInfo.
+static RSA_ENCRYPTION: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01, 0x05, 0x00,
+];
+static EC_PUBLIC_KEY_P256: &[u8] = &[
+    0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce,
+    0x3d, 0x03, 0x01, 0x07,
+];
+static EC_PUBLIC_KEY_P384: &[u8] = &[
+    0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x05, 0x2b, 0x81, 0x04, 0x00,
+    0x22,
+];
+static ED25519_PUBLIC_KEY: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x70];
+
+fn verify_tls13_raw_public_key(
+    msg: &[u8],
+    spki: &[u8],
+    dss: &DigitallySignedStruct,
+) -> Result<HandshakeSignatureValid, Error> {
+    use crate::msgs::enums::SignatureScheme::*;
+    use ring::signature;
+
+    let (key_algorithm, alg): (&[u8], &'static dyn signature::VerificationAlgorithm) =
+        match dss.scheme {
+            ECDSA_NISTP256_SHA256 => (EC_PUBLIC_KEY_P256, &signature::ECDSA_P256_SHA256_ASN1),
+            ECDSA_NISTP384_SHA384 => (EC_PUBLIC_KEY_P384, &signature::ECDSA_P384_SHA384_ASN1),
+            ED25519 => (ED25519_PUBLIC_KEY, &signature::ED25519),
+            RSA_PSS_SHA256 => (RSA_ENCRYPTION, &signature::RSA_PSS_2048_8192_SHA256),
+            RSA_PSS_SHA384 => (RSA_ENCRYPTION, &signature::RSA_PSS_2048_8192_SHA384),
+            RSA_PSS_SHA512 => (RSA_ENCRYPTION, &signature::RSA_PSS_2048_8192_SHA512),
+            _ => {
+                let error_msg = format!("received unsupported sig scheme {:?}", dss.scheme);
+                return Err(Error::PeerMisbehavedError(error_msg));
+            }
+        };
+
+    let (algorithm, key) = x509::split_subject_public_key_info(spki)
+        .ok_or(Error::InvalidCertificateEncoding)?;
+    if algorithm != key_algorithm {
+        return Err(Error::InvalidCertificateSignatureType);
+    }
+
+    signature::UnparsedPublicKey::new(alg, key)
+        .verify(msg, &dss.sig.0)
+        .map_err(|_| Error::InvalidCertificateSignature)
+        .map(|_| HandshakeSignatureValid::assertion())
+}
+
 fn unix_time_millis(now: SystemTime) -> Result<u64, Error> {
     now.duration_since(std::time::UNIX_EPOCH)
         .map(|dur| dur.as_secs())
@@ -764,4 +1474,316 @@ mod tests {
             "ServerCertVerified(())"
         );
     }
+
+    /// `openssl dgst -sha1 -sign test-ca/rsa/end.key` of `SHA1_MESSAGE`.
+    static SHA1_SIGNATURE: &[u8] = &[
+        0x33, 0x74, 0xe1, 0xa2, 0x2b, 0xf1, 0xc9, 0x8d, 0x53, 0x0d, 0xa4, 0x82, 0x9b, 0xb3, 0x32,
+        0x19, 0x88, 0xbf, 0xc6, 0xb6, 0xab, 0x0d, 0x21, 0x95, 0x8c, 0xf8, 0xad, 0x30, 0xa1, 0xfb,
+        0x75, 0x1e, 0xdb, 0x55, 0x1e, 0x27, 0x6e, 0xd9, 0xb7, 0xea, 0x2a, 0x2d, 0x33, 0xc2, 0x51,
+        0x91, 0x5b, 0x7a, 0xda, 0xca, 0xef, 0x44, 0xfc, 0x9c, 0x75, 0x8d, 0x64, 0x06, 0x92, 0x4b,
+        0x38, 0x10, 0x68, 0x81, 0xd1, 0xc6, 0x0f, 0x66, 0xdd, 0x76, 0x11, 0x03, 0xb1, 0xaa, 0xca,
+        0xd1, 0x7d, 0xd6, 0xb5, 0xe6, 0x6e, 0xb6, 0x08, 0xf1, 0x3d, 0xe5, 0xdc, 0xe5, 0xee, 0xf3,
+        0x29, 0x4c, 0x70, 0xf4, 0xf2, 0x9a, 0x19, 0x8e, 0x7d, 0x40, 0xb0, 0xf2, 0x7a, 0xc4, 0x50,
+        0xc3, 0x38, 0x36, 0x7e, 0x97, 0xd0, 0x6e, 0xbd, 0x9f, 0x8b, 0x28, 0xe9, 0xdb, 0x67, 0x27,
+        0xb4, 0x36, 0x7c, 0xe8, 0xda, 0xa2, 0xad, 0xaf, 0x1f, 0xef, 0x82, 0x83, 0xe6, 0x79, 0x17,
+        0xbe, 0x27, 0xd9, 0xee, 0x95, 0xcf, 0x32, 0x35, 0xc9, 0xbc, 0x43, 0x96, 0x52, 0xad, 0x34,
+        0xdf, 0x32, 0x32, 0x31, 0xad, 0xd2, 0x70, 0x46, 0x06, 0x7c, 0x51, 0xbd, 0xa7, 0xf9, 0xe4,
+        0xf3, 0x3d, 0x8e, 0x96, 0xa7, 0x76, 0x5b, 0xb0, 0xde, 0xba, 0xb0, 0xa9, 0xab, 0x06, 0x1f,
+        0x0c, 0xa0, 0x2b, 0x16, 0x54, 0x8e, 0xf7, 0xfb, 0xc5, 0x10, 0xc3, 0x59, 0xa3, 0x4c, 0x24,
+        0x8d, 0x35, 0x67, 0x6b, 0x70, 0xed, 0xa1, 0x03, 0x13, 0x22, 0x93, 0x4d, 0xe4, 0xce, 0x61,
+        0x2a, 0x13, 0x36, 0xd8, 0xcd, 0x34, 0x29, 0x24, 0x7e, 0x51, 0x7c, 0x64, 0xd1, 0x02, 0xc3,
+        0x7b, 0xb3, 0xa7, 0xee, 0x73, 0x93, 0xd6, 0xff, 0x89, 0xdf, 0xab, 0x1a, 0xdc, 0x88, 0xf7,
+        0xb1, 0x96, 0x15, 0xa1, 0x46, 0x64, 0x0f, 0xaf, 0xe4, 0x42, 0x7e, 0x99, 0xf6, 0xe3, 0xa4,
+        0x51,
+    ];
+    static SHA1_MESSAGE: &[u8] = b"legacy signed message";
+
+    fn rsa_end_cert() -> Certificate {
+        let pem = include_bytes!("../../test-ca/rsa/end.cert");
+        let certs = rustls_pemfile::certs(&mut &pem[..]).unwrap();
+        Certificate(certs[0].clone())
+    }
+
+    fn sha1_dss(sig: &[u8]) -> DigitallySignedStruct {
+        DigitallySignedStruct::new(SignatureScheme::RSA_PKCS1_SHA1, sig.to_vec())
+    }
+
+    #[test]
+    fn sha1_is_not_verified_by_default() {
+        let cert = rsa_end_cert();
+        assert!(matches!(
+            verify_signed_struct(SHA1_MESSAGE, &cert, &sha1_dss(SHA1_SIGNATURE)),
+            Err(Error::PeerMisbehavedError(_))
+        ));
+        assert!(matches!(
+            verify_tls13(SHA1_MESSAGE, &cert, &sha1_dss(SHA1_SIGNATURE)),
+            Err(Error::PeerMisbehavedError(_))
+        ));
+    }
+
+    /// A time at which the whole RSA test chain is valid.
+    fn rsa_chain_valid_time() -> SystemTime {
+        // 2028-01-01T00:00:00Z
+        SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_830_297_600)
+    }
+
+    static RSA_CA: &[u8] = include_bytes!("../../test-ca/rsa/ca.cert");
+    static ECDSA_CA: &[u8] = include_bytes!("../../test-ca/ecdsa/ca.cert");
+
+    /// Verify the RSA test chain for `server_name` at `now`, trusting the
+    /// CA in `ca_pem`.
+    fn verify_rsa_chain(
+        mut ca_pem: &[u8],
+        server_name: &str,
+        now: SystemTime,
+    ) -> Result<(), Error> {
+        let mut root_store = RootCertStore::empty();
+        root_store.add_parsable_certificates(&rustls_pemfile::certs(&mut ca_pem).unwrap());
+
+        let end_entity = rsa_end_cert();
+        let pem = include_bytes!("../../test-ca/rsa/inter.cert");
+        let intermediate = Certificate(rustls_pemfile::certs(&mut &pem[..]).unwrap()[0].clone());
+
+        WebPkiVerifier::new(root_store, None)
+            .verify_server_cert(
+                &end_entity,
+                &[intermediate],
+                &ServerName::try_from(server_name).unwrap(),
+                &mut iter::empty(),
+                &[],
+                now,
+            )
+            .map(|_| ())
+    }
+
+    #[test]
+    fn valid_chain_is_verified() {
+        assert_eq!(
+            verify_rsa_chain(RSA_CA, "testserver.com", rsa_chain_valid_time()),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn name_mismatch_reports_the_name() {
+        for &name in &["example.com", "192.0.2.1"] {
+            assert_eq!(
+                verify_rsa_chain(RSA_CA, name, rsa_chain_valid_time()),
+                Err(Error::InvalidCertificate(
+                    CertificateError::NotValidForName { name: name.into() }
+                ))
+            );
+        }
+    }
+
+    #[test]
+    fn expiry_reports_the_times() {
+        let (not_before, not_after) = x509::validity(&rsa_end_cert().0).unwrap();
+
+        let now = not_after + std::time::Duration::from_secs(1);
+        assert_eq!(
+            verify_rsa_chain(RSA_CA, "testserver.com", now),
+            Err(Error::InvalidCertificate(CertificateError::Expired {
+                now,
+                not_after: Some(not_after),
+            }))
+        );
+
+        let now = not_before - std::time::Duration::from_secs(1);
+        assert_eq!(
+            verify_rsa_chain(RSA_CA, "testserver.com", now),
+            Err(Error::InvalidCertificate(CertificateError::NotValidYet {
+                now,
+                not_before: Some(not_before),
+            }))
+        );
+    }
+
+    #[test]
+    fn untrusted_chain_is_unknown_issuer() {
+        assert_eq!(
+            verify_rsa_chain(ECDSA_CA, "testserver.com", rsa_chain_valid_time()),
+            Err(Error::InvalidCertificate(CertificateError::UnknownIssuer))
+        );
+    }
+
+    /// 1.3.6.1.4.1.99999.1, which the `*-purpose` test certificates assert.
+    static TEST_KEY_PURPOSE: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x86, 0x8d, 0x1f, 0x01];
+
+    /// Verify `end_entity` from the RSA test chain, requiring it to
+    /// assert `purposes`.
+    fn verify_key_purposes(mut end_entity: &[u8], purposes: &[&[u8]]) -> Result<(), Error> {
+        let mut root_store = RootCertStore::empty();
+        root_store.add_parsable_certificates(&rustls_pemfile::certs(&mut &RSA_CA[..]).unwrap());
+
+        let end_entity = Certificate(rustls_pemfile::certs(&mut end_entity).unwrap()[0].clone());
+        let pem = include_bytes!("../../test-ca/rsa/inter.cert");
+        let intermediate = Certificate(rustls_pemfile::certs(&mut &pem[..]).unwrap()[0].clone());
+
+        let purposes = purposes
+            .iter()
+            .map(|purpose| purpose.to_vec())
+            .collect();
+        WebPkiVerifier::new_with_required_key_purposes(root_store, None, purposes)
+            .verify_server_cert(
+                &end_entity,
+                &[intermediate],
+                &ServerName::try_from("testserver.com").unwrap(),
+                &mut iter::empty(),
+                &[],
+                rsa_chain_valid_time(),
+            )
+            .map(|_| ())
+    }
+
+    #[test]
+    fn required_key_purpose_is_accepted() {
+        let end_entity = include_bytes!("../../test-ca/rsa/end-purpose.cert");
+        assert_eq!(verify_key_purposes(end_entity, &[]), Ok(()));
+        assert_eq!(verify_key_purposes(end_entity, &[TEST_KEY_PURPOSE]), Ok(()));
+    }
+
+    #[test]
+    fn missing_key_purpose_is_rejected() {
+        let missing = Err(Error::InvalidCertificate(
+            CertificateError::MissingKeyPurpose {
+                purpose: TEST_KEY_PURPOSE.to_vec(),
+            },
+        ));
+
+        // No extendedKeyUsage extension at all.
+        let end_entity = include_bytes!("../../test-ca/rsa/end.cert");
+        assert_eq!(verify_key_purposes(end_entity, &[]), Ok(()));
+        assert_eq!(
+            verify_key_purposes(end_entity, &[TEST_KEY_PURPOSE]),
+            missing
+        );
+
+        // One purpose present, another not.
+        let end_entity = include_bytes!("../../test-ca/rsa/end-purpose.cert");
+        let client_auth = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x02][..];
+        assert_eq!(
+            verify_key_purposes(end_entity, &[TEST_KEY_PURPOSE, client_auth]),
+            Err(Error::InvalidCertificate(
+                CertificateError::MissingKeyPurpose {
+                    purpose: client_auth.to_vec(),
+                }
+            ))
+        );
+    }
+
+    fn pem_cert(mut pem: &[u8]) -> Certificate {
+        Certificate(rustls_pemfile::certs(&mut pem).unwrap()[0].clone())
+    }
+
+    /// Verify rsa/end-inter3.cert, trusting both CAs, with the given
+    /// intermediates sent and `required` intermediates required.
+    fn verify_inter3_chain(intermediates: &[&[u8]], required: &[&[u8]]) -> Result<(), Error> {
+        let mut root_store = RootCertStore::empty();
+        root_store.add_parsable_certificates(&rustls_pemfile::certs(&mut &RSA_CA[..]).unwrap());
+        root_store.add_parsable_certificates(&rustls_pemfile::certs(&mut &ECDSA_CA[..]).unwrap());
+
+        let end_entity = pem_cert(include_bytes!("../../test-ca/rsa/end-inter3.cert"));
+        let intermediates = intermediates
+            .iter()
+            .map(|pem| pem_cert(pem))
+            .collect::<Vec<_>>();
+        let required = required
+            .iter()
+            .map(|pem| pem_cert(pem))
+            .collect::<Vec<_>>();
+
+        WebPkiVerifier::new(root_store, None)
+            .with_required_intermediates(&required)?
+            .verify_server_cert(
+                &end_entity,
+                &intermediates,
+                &ServerName::try_from("testserver.com").unwrap(),
+                &mut iter::empty(),
+                &[],
+                rsa_chain_valid_time(),
+            )
+            .map(|_| ())
+    }
+
+    static RSA_INTER: &[u8] = include_bytes!("../../test-ca/rsa/inter.cert");
+    static RSA_INTER3: &[u8] = include_bytes!("../../test-ca/rsa/inter3.cert");
+    static RSA_INTER3_CROSS: &[u8] = include_bytes!("../../test-ca/rsa/inter3-cross.cert");
+
+    #[test]
+    fn path_through_required_intermediate_is_accepted() {
+        assert_eq!(verify_inter3_chain(&[RSA_INTER3, RSA_INTER], &[]), Ok(()));
+        assert_eq!(
+            verify_inter3_chain(&[RSA_INTER3, RSA_INTER], &[RSA_INTER]),
+            Ok(())
+        );
+        assert_eq!(
+            verify_inter3_chain(&[RSA_INTER3, RSA_INTER], &[RSA_INTER3]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn path_avoiding_required_intermediate_is_rejected() {
+        let rejected = Err(Error::InvalidCertificate(
+            CertificateError::NoRequiredIntermediate,
+        ));
+
+        // Valid on its own, through the cross-signed inter3 to the ECDSA CA.
+        assert_eq!(verify_inter3_chain(&[RSA_INTER3_CROSS], &[]), Ok(()));
+        assert_eq!(
+            verify_inter3_chain(&[RSA_INTER3_CROSS], &[RSA_INTER]),
+            rejected
+        );
+
+        // The cross-signed certificate has inter3's name and key, but is
+        // not the certificate required, and doesn't lead to the RSA CA.
+        assert_eq!(
+            verify_inter3_chain(&[RSA_INTER3_CROSS], &[RSA_INTER3]),
+            rejected
+        );
+        assert_eq!(
+            verify_inter3_chain(&[RSA_INTER3_CROSS, RSA_INTER], &[RSA_INTER3]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn chain_errors_are_mapped_precisely() {
+        let end_entity = rsa_end_cert();
+        let now = rsa_chain_valid_time();
+        for (error, expected) in vec![
+            (
+                webpki::Error::InvalidSignatureForPublicKey,
+                CertificateError::BadSignature,
+            ),
+            (
+                webpki::Error::UnsupportedSignatureAlgorithmForPublicKey,
+                CertificateError::UnsupportedKeyType,
+            ),
+            (
+                webpki::Error::UnknownIssuer,
+                CertificateError::UnknownIssuer,
+            ),
+        ] {
+            assert_eq!(
+                chain_error(error, &end_entity, &[], now),
+                Error::InvalidCertificate(expected)
+            );
+        }
+
+        // Nothing presented is out of date, so no time can be given.
+        assert_eq!(
+            chain_error(webpki::Error::CertExpired, &end_entity, &[], now),
+            Error::InvalidCertificate(CertificateError::Expired {
+                now,
+                not_after: None
+            })
+        );
+
+        assert_eq!(
+            chain_error(webpki::Error::BadDer, &end_entity, &[], now),
+            Error::InvalidCertificateEncoding
+        );
+    }
 }
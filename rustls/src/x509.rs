@@ -2,6 +2,8 @@
 
 use ring::io::der;
 
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 pub fn wrap_in_asn1_len(bytes: &mut Vec<u8>) {
     let len = bytes.len();
 
@@ -25,6 +27,377 @@ pub fn wrap_in_sequence(bytes: &mut Vec<u8>) {
     bytes.insert(0, der::Tag::Sequence as u8);
 }
 
+/// Split the DER element with the given `tag` off the front of `input`.
+///
+/// Returns the element's contents and the remainder of `input`.
+fn read_asn1<'a>(input: &'a [u8], tag: der::Tag) -> Option<(&'a [u8], &'a [u8])> {
+    match read_any_asn1(input)? {
+        (actual, contents, rest) if actual == tag as u8 => Some((contents, rest)),
+        _ => None,
+    }
+}
+
+/// Split the DER element off the front of `input`, whatever its tag.
+///
+/// Returns the element's tag, its contents and the remainder of `input`.
+fn read_any_asn1(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, mut rest) = rest.split_first()?;
+    let len = match first {
+        0x00..=0x7f => first as usize,
+        0x81..=0x83 => {
+            let mut len = 0usize;
+            for _ in 0..(first & 0x7f) {
+                let (&byte, tail) = rest.split_first()?;
+                len = (len << 8) | byte as usize;
+                rest = tail;
+            }
+
+            // Reject non-minimal encodings.
+            if len < 0x80 || len >> (((first & 0x7f) as usize - 1) * 8) == 0 {
+                return None;
+            }
+            len
+        }
+        _ => return None,
+    };
+
+    if rest.len() < len {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(len);
+    Some((tag, contents, rest))
+}
+
+/// Find the DER-encoded `SubjectPublicKeyInfo` inside the DER-encoded
+/// X.509 certificate `cert`.
+///
+/// This does no validation of the certificate beyond what is needed to
+/// walk its `TBSCertificate` structure.
+pub fn subject_public_key_info(cert: &[u8]) -> Option<&[u8]> {
+    split_tbs_at_spki(cert).map(|(spki, _)| spki)
+}
+
+/// Split the `TBSCertificate` of `cert` into its `SubjectPublicKeyInfo`
+/// and the fields that follow it.
+fn split_tbs_at_spki(cert: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (cert, _) = read_asn1(cert, der::Tag::Sequence)?;
+    let (mut tbs, _) = read_asn1(cert, der::Tag::Sequence)?;
+
+    // version is optional; serialNumber, signature, issuer, validity and
+    // subject precede the subjectPublicKeyInfo.
+    if let Some((_, rest)) = read_asn1(tbs, der::Tag::ContextSpecificConstructed0) {
+        tbs = rest;
+    }
+    for tag in &[
+        der::Tag::Integer,
+        der::Tag::Sequence,
+        der::Tag::Sequence,
+        der::Tag::Sequence,
+        der::Tag::Sequence,
+    ] {
+        tbs = read_asn1(tbs, *tag)?.1;
+    }
+
+    let (_, rest) = read_asn1(tbs, der::Tag::Sequence)?;
+    Some((&tbs[..tbs.len() - rest.len()], rest))
+}
+
+/// Find the DER-encoded issuer and subject `Name`s inside the DER-encoded
+/// X.509 certificate `cert`, without their tags and lengths.
+///
+/// As with `subject_public_key_info`, the certificate isn't validated.
+pub fn issuer_and_subject(cert: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (cert, _) = read_asn1(cert, der::Tag::Sequence)?;
+    let (mut tbs, _) = read_asn1(cert, der::Tag::Sequence)?;
+    if let Some((_, rest)) = read_asn1(tbs, der::Tag::ContextSpecificConstructed0) {
+        tbs = rest;
+    }
+
+    // serialNumber and signature come before the issuer, and
+    // validity separates the issuer from the subject.
+    let (_, tbs) = read_asn1(tbs, der::Tag::Integer)?;
+    let (_, tbs) = read_asn1(tbs, der::Tag::Sequence)?;
+    let (issuer, tbs) = read_asn1(tbs, der::Tag::Sequence)?;
+    let (_, tbs) = read_asn1(tbs, der::Tag::Sequence)?;
+    let (subject, _) = read_asn1(tbs, der::Tag::Sequence)?;
+    Some((issuer, subject))
+}
+
+/// Find the signature algorithm OID of the DER-encoded X.509 certificate
+/// `cert`, without its tag and length.  This is the algorithm the issuer
+/// signed `cert` with.
+///
+/// As with `subject_public_key_info`, the certificate isn't validated.
+pub fn signature_algorithm(cert: &[u8]) -> Option<&[u8]> {
+    let (cert, _) = read_asn1(cert, der::Tag::Sequence)?;
+    let (_, cert) = read_asn1(cert, der::Tag::Sequence)?;
+    let (algorithm, _) = read_asn1(cert, der::Tag::Sequence)?;
+    let (oid, _) = read_asn1(algorithm, der::Tag::OID)?;
+    Some(oid)
+}
+
+/// Find the `notBefore` and `notAfter` times of the DER-encoded X.509
+/// certificate `cert`.
+///
+/// As with `subject_public_key_info`, the certificate isn't validated.
+/// Times before the UNIX epoch are not supported.
+pub fn validity(cert: &[u8]) -> Option<(SystemTime, SystemTime)> {
+    let (cert, _) = read_asn1(cert, der::Tag::Sequence)?;
+    let (mut tbs, _) = read_asn1(cert, der::Tag::Sequence)?;
+    if let Some((_, rest)) = read_asn1(tbs, der::Tag::ContextSpecificConstructed0) {
+        tbs = rest;
+    }
+
+    // serialNumber, signature and issuer come before the validity.
+    let (_, tbs) = read_asn1(tbs, der::Tag::Integer)?;
+    let (_, tbs) = read_asn1(tbs, der::Tag::Sequence)?;
+    let (_, tbs) = read_asn1(tbs, der::Tag::Sequence)?;
+    let (validity, _) = read_asn1(tbs, der::Tag::Sequence)?;
+    let (not_before, validity) = read_time(validity)?;
+    let (not_after, validity) = read_time(validity)?;
+    if !validity.is_empty() {
+        return None;
+    }
+
+    Some((not_before, not_after))
+}
+
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+
+/// Split a `Time` off the front of `input`.  RFC5280 requires these
+/// to be in UTC and to include seconds.
+fn read_time(input: &[u8]) -> Option<(SystemTime, &[u8])> {
+    let (tag, time, rest) = read_any_asn1(input)?;
+    let (year, time) = match tag {
+        UTC_TIME => {
+            // RFC5280 section 4.1.2.5.1: YY of 50 or more is 19YY.
+            let year = read_digits(time, 2)?;
+            let year = if year >= 50 { 1900 + year } else { 2000 + year };
+            (year, &time[2..])
+        }
+        GENERALIZED_TIME => (read_digits(time, 4)?, &time[4..]),
+        _ => return None,
+    };
+
+    // MMDDHHMMSSZ
+    if time.len() != 11 || time[10] != b'Z' {
+        return None;
+    }
+    let month = read_digits(&time[0..], 2)?;
+    let day = read_digits(&time[2..], 2)?;
+    let hour = read_digits(&time[4..], 2)?;
+    let minute = read_digits(&time[6..], 2)?;
+    let second = read_digits(&time[8..], 2)?;
+    if year < 1970
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 59
+    {
+        return None;
+    }
+
+    let days = days_since_epoch(year, month, day);
+    let seconds = ((days * 24 + hour) * 60 + minute) * 60 + second;
+    Some((UNIX_EPOCH + Duration::from_secs(seconds), rest))
+}
+
+/// Parse the first `count` bytes of `input` as decimal digits.
+fn read_digits(input: &[u8], count: usize) -> Option<u64> {
+    input
+        .get(..count)?
+        .iter()
+        .try_fold(0u64, |value, &digit| match digit {
+            b'0'..=b'9' => Some(value * 10 + u64::from(digit - b'0')),
+            _ => None,
+        })
+}
+
+/// The number of days from 1970-01-01 to the given date, which must not
+/// be earlier.
+fn days_since_epoch(year: u64, month: u64, day: u64) -> u64 {
+    // Count years from March, so that leap days fall at the end.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let year_of_era = year % 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    // 719468 is the number of days from 0000-03-01 to 1970-01-01.
+    era * 146097 + day_of_era - 719468
+}
+
+/// `id-ce-subjectAltName`, without its tag and length.
+const SUBJECT_ALT_NAME_OID: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// `id-ce-extKeyUsage`, without its tag and length.
+const EXTENDED_KEY_USAGE_OID: &[u8] = &[0x55, 0x1d, 0x25];
+
+/// RFC9345's `id-pe-delegationUsage`, 1.3.6.1.4.1.44363.44, without its
+/// tag and length.
+const DELEGATION_USAGE_OID: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x82, 0xda, 0x4b, 0x2c];
+
+/// The tag of `iPAddress [7] IMPLICIT OCTET STRING` in a `GeneralName`.
+const GENERAL_NAME_IP_ADDRESS: u8 = 0x87;
+
+/// Find the extensions of the DER-encoded X.509 certificate `cert`,
+/// as the OID and contents of the `extnValue` of each.
+///
+/// As with `subject_public_key_info`, the certificate isn't validated.
+fn extensions(cert: &[u8]) -> Option<Vec<(&[u8], &[u8])>> {
+    let (_, mut tbs) = split_tbs_at_spki(cert)?;
+
+    // issuerUniqueID [1] and subjectUniqueID [2] may come before the
+    // extensions [3].
+    let mut extensions = None;
+    while !tbs.is_empty() {
+        let (tag, contents, rest) = read_any_asn1(tbs)?;
+        if tag == der::Tag::ContextSpecificConstructed3 as u8 {
+            extensions = Some(contents);
+        }
+        tbs = rest;
+    }
+
+    let mut found = Vec::new();
+    let mut extensions = match extensions {
+        Some(extensions) => read_asn1(extensions, der::Tag::Sequence)?.0,
+        None => return Some(found),
+    };
+
+    while !extensions.is_empty() {
+        let (extension, rest) = read_asn1(extensions, der::Tag::Sequence)?;
+        extensions = rest;
+
+        let (oid, mut extension) = read_asn1(extension, der::Tag::OID)?;
+        if let Some((_, rest)) = read_asn1(extension, der::Tag::Boolean) {
+            extension = rest;
+        }
+
+        let (value, _) = read_asn1(extension, der::Tag::OctetString)?;
+        found.push((oid, value));
+    }
+
+    Some(found)
+}
+
+/// Find the `iPAddress` entries in the subjectAltName extension of the
+/// DER-encoded X.509 certificate `cert`.  Each is an IPv4 or IPv6 address
+/// in network byte order.
+///
+/// As with `subject_public_key_info`, the certificate isn't validated.
+pub fn subject_alt_ip_addresses(cert: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut addresses = Vec::new();
+    for (oid, value) in extensions(cert)? {
+        if oid != SUBJECT_ALT_NAME_OID {
+            continue;
+        }
+
+        let (mut names, _) = read_asn1(value, der::Tag::Sequence)?;
+        while !names.is_empty() {
+            let (tag, name, rest) = read_any_asn1(names)?;
+            if tag == GENERAL_NAME_IP_ADDRESS {
+                addresses.push(name);
+            }
+            names = rest;
+        }
+    }
+
+    Some(addresses)
+}
+
+/// Find the key purpose OIDs, without their tags and lengths, in the
+/// extendedKeyUsage extension of the DER-encoded X.509 certificate
+/// `cert`.  There are none if it has no such extension.
+///
+/// As with `subject_public_key_info`, the certificate isn't validated.
+pub fn extended_key_usages(cert: &[u8]) -> Option<Vec<&[u8]>> {
+    let mut purposes = Vec::new();
+    for (oid, value) in extensions(cert)? {
+        if oid != EXTENDED_KEY_USAGE_OID {
+            continue;
+        }
+
+        let (mut oids, _) = read_asn1(value, der::Tag::Sequence)?;
+        while !oids.is_empty() {
+            let (purpose, rest) = read_asn1(oids, der::Tag::OID)?;
+            purposes.push(purpose);
+            oids = rest;
+        }
+    }
+
+    Some(purposes)
+}
+
+/// Decode the arcs of the OID whose DER encoding, without its tag and
+/// length, is `oid`: 1.3.6.1 from `[0x2b, 0x06, 0x01]`.
+pub fn oid_arcs(oid: &[u8]) -> Option<Vec<u64>> {
+    if oid.last()? & 0x80 != 0 {
+        return None;
+    }
+
+    // Each subidentifier is base 128, with the top bit set on all but
+    // its last byte.
+    let mut subidentifiers = Vec::new();
+    let mut subidentifier = 0u64;
+    for &byte in oid {
+        if subidentifier.leading_zeros() < 7 {
+            return None;
+        }
+        subidentifier = (subidentifier << 7) | u64::from(byte & 0x7f);
+        if byte & 0x80 == 0 {
+            subidentifiers.push(subidentifier);
+            subidentifier = 0;
+        }
+    }
+
+    // The first subidentifier packs in the first two arcs.
+    let (first, second) = match subidentifiers[0] {
+        x if x < 40 => (0, x),
+        x if x < 80 => (1, x - 40),
+        x => (2, x - 80),
+    };
+    let mut arcs = vec![first, second];
+    arcs.extend_from_slice(&subidentifiers[1..]);
+    Some(arcs)
+}
+
+/// Whether the DER-encoded X.509 certificate `cert` has the
+/// DelegationUsage extension, and so may issue RFC9345 delegated
+/// credentials.
+///
+/// As with `subject_public_key_info`, the certificate isn't validated.
+pub fn has_delegation_usage(cert: &[u8]) -> Option<bool> {
+    Some(
+        extensions(cert)?
+            .iter()
+            .any(|(oid, _)| *oid == DELEGATION_USAGE_OID),
+    )
+}
+
+/// Split the DER-encoded `SubjectPublicKeyInfo` `spki` into the contents
+/// of its `AlgorithmIdentifier` and the public key itself.
+pub fn split_subject_public_key_info(spki: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (spki, rest) = read_asn1(spki, der::Tag::Sequence)?;
+    if !rest.is_empty() {
+        return None;
+    }
+
+    let (algorithm, spki) = read_asn1(spki, der::Tag::Sequence)?;
+    let (key, spki) = read_asn1(spki, der::Tag::BitString)?;
+    if !spki.is_empty() {
+        return None;
+    }
+
+    // Public keys are always a whole number of octets.
+    match key.split_first() {
+        Some((0, key)) => Some((algorithm, key)),
+        _ => None,
+    }
+}
+
 #[test]
 fn test_empty() {
     let mut val = Vec::new();
@@ -91,3 +464,144 @@ fn test_ludicrous() {
     );
     assert_eq!(val.len(), 0x1000000 + 6);
 }
+
+#[test]
+fn test_subject_public_key_info() {
+    let cert = include_bytes!("../../test-ca/ecdsa/ca.der");
+    let spki = subject_public_key_info(cert).unwrap();
+    let (algorithm, key) = split_subject_public_key_info(spki).unwrap();
+
+    // id-ecPublicKey, secp384r1
+    assert_eq!(
+        algorithm,
+        &[
+            0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x05, 0x2b, 0x81, 0x04,
+            0x00, 0x22
+        ]
+    );
+    assert_eq!(key.len(), 97);
+    assert_eq!(key[0], 0x04);
+}
+
+#[test]
+fn test_split_subject_public_key_info_rejects_junk() {
+    let cert = include_bytes!("../../test-ca/ecdsa/ca.der");
+    let mut spki = subject_public_key_info(cert)
+        .unwrap()
+        .to_vec();
+    spki.push(0x00);
+    assert_eq!(split_subject_public_key_info(&spki), None);
+    assert_eq!(split_subject_public_key_info(&spki[..spki.len() - 2]), None);
+    assert_eq!(subject_public_key_info(&spki), None);
+}
+
+#[test]
+fn test_subject_alt_ip_addresses_without_subject_alt_name() {
+    let cert = include_bytes!("../../test-ca/ecdsa/ca.der");
+    assert_eq!(subject_alt_ip_addresses(cert), Some(vec![]));
+    assert_eq!(subject_alt_ip_addresses(&cert[..cert.len() - 1]), None);
+}
+
+#[test]
+fn test_extended_key_usages() {
+    let pem = include_bytes!("../../test-ca/rsa/client-purpose.cert");
+    let cert = rustls_pemfile::certs(&mut &pem[..]).unwrap();
+
+    // id-kp-clientAuth, then 1.3.6.1.4.1.99999.1
+    assert_eq!(
+        extended_key_usages(&cert[0]),
+        Some(vec![
+            &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x02][..],
+            &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x86, 0x8d, 0x1f, 0x01][..],
+        ])
+    );
+
+    let cert = include_bytes!("../../test-ca/ecdsa/ca.der");
+    assert_eq!(extended_key_usages(cert), Some(vec![]));
+    assert_eq!(extended_key_usages(&cert[..cert.len() - 1]), None);
+}
+
+#[test]
+fn test_oid_arcs() {
+    assert_eq!(
+        oid_arcs(&[0x2b, 0x06, 0x01, 0x04, 0x01, 0x86, 0x8d, 0x1f, 0x01]),
+        Some(vec![1, 3, 6, 1, 4, 1, 99999, 1])
+    );
+    assert_eq!(oid_arcs(&[0x88, 0x37]), Some(vec![2, 999]));
+    assert_eq!(oid_arcs(&[]), None);
+    assert_eq!(oid_arcs(&[0x2b, 0x86]), None);
+    // Too big for a u64.
+    let mut huge = vec![0xff; 10];
+    huge.push(0x7f);
+    assert_eq!(oid_arcs(&huge), None);
+}
+
+#[test]
+fn test_has_delegation_usage() {
+    let pem = include_bytes!("../../test-ca/ecdsa/end-dc.cert");
+    let cert = rustls_pemfile::certs(&mut &pem[..]).unwrap();
+    assert_eq!(has_delegation_usage(&cert[0]), Some(true));
+
+    let pem = include_bytes!("../../test-ca/ecdsa/end.cert");
+    let cert = rustls_pemfile::certs(&mut &pem[..]).unwrap();
+    assert_eq!(has_delegation_usage(&cert[0]), Some(false));
+    assert_eq!(has_delegation_usage(&cert[0][..cert[0].len() - 1]), None);
+}
+
+#[test]
+fn test_issuer_and_subject_of_self_signed_cert() {
+    let cert = include_bytes!("../../test-ca/ecdsa/ca.der");
+    let (issuer, subject) = issuer_and_subject(cert).unwrap();
+    assert_eq!(issuer, subject);
+    assert!(!issuer.is_empty());
+    assert_eq!(issuer_and_subject(&cert[..cert.len() - 1]), None);
+}
+
+#[test]
+fn test_signature_algorithm() {
+    // ecdsa-with-SHA256
+    let cert = include_bytes!("../../test-ca/ecdsa/ca.der");
+    assert_eq!(
+        signature_algorithm(cert),
+        Some(&[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02][..])
+    );
+
+    // A SubjectPublicKeyInfo isn't mistaken for a certificate.
+    let spki = subject_public_key_info(cert).unwrap();
+    assert_eq!(signature_algorithm(spki), None);
+}
+
+#[test]
+fn test_validity() {
+    // notBefore 190609171512Z, notAfter 290606171512Z
+    let cert = include_bytes!("../../test-ca/ecdsa/ca.der");
+    assert_eq!(
+        validity(cert),
+        Some((
+            UNIX_EPOCH + Duration::from_secs(1_560_100_512),
+            UNIX_EPOCH + Duration::from_secs(1_875_460_512)
+        ))
+    );
+    assert_eq!(validity(&cert[..cert.len() - 1]), None);
+}
+
+#[test]
+fn test_read_time() {
+    let generalized = b"\x18\x0f20500101000000Z";
+    assert_eq!(
+        read_time(generalized),
+        Some((UNIX_EPOCH + Duration::from_secs(2_524_608_000), &[][..]))
+    );
+
+    // two-digit years from 50 are in the twentieth century
+    let utc = b"\x17\x0d700101000001Z";
+    assert_eq!(
+        read_time(utc),
+        Some((UNIX_EPOCH + Duration::from_secs(1), &[][..]))
+    );
+
+    assert_eq!(read_time(b"\x17\x0d691231235959Z"), None);
+    assert_eq!(read_time(b"\x17\x0d701301000000Z"), None);
+    assert_eq!(read_time(b"\x17\x0d7001010000000"), None);
+    assert_eq!(read_time(b"\x18\x0d700101000000Z"), None);
+}
@@ -7,22 +7,28 @@ use std::fmt;
 use std::io::{self, IoSlice, Read, Write};
 use std::mem;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use log;
 
-use rustls::client::ResolvesClientCert;
+use rustls::client::{EarlyDataError, OcspStatus, ResolvesClientCert};
 #[cfg(feature = "quic")]
 use rustls::quic::{self, ClientQuicExt, QuicExt, ServerQuicExt};
-use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, ClientHello, ResolvesServerCert};
-use rustls::{sign, ConnectionCommon, Error, KeyLog, SideData};
-use rustls::{CipherSuite, ProtocolVersion, SignatureScheme};
+use rustls::server::{
+    AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+    ResolvesServerCert,
+};
+use rustls::{sign, CertificateError, ChannelBindingType, ConnectionCommon, Error, IoStateHint};
+use rustls::{CipherSuite, MaxFragmentLength, NamedGroup, ProtocolVersion, SignatureScheme};
 use rustls::{ClientConfig, ClientConnection};
+use rustls::{HandshakeDetails, HandshakeKind, HandshakeObserver, HandshakeStats};
+use rustls::{KeyLog, SideData};
 use rustls::{ServerConfig, ServerConnection};
 use rustls::{Stream, StreamOwned};
-use rustls::{SupportedCipherSuite, ALL_CIPHER_SUITES};
+use rustls::{SupportedCipherSuite, ALL_CIPHER_SUITES, ALL_SIGNATURE_SCHEMES};
 
 mod common;
 use crate::common::*;
@@ -35,7 +41,23 @@ fn alpn_test_error(
 ) {
     let mut server_config = make_server_config(KeyType::Rsa);
     server_config.alpn_protocols = server_protos;
+    alpn_test_with_config_error(server_config, client_protos, agreed, expected_error)
+}
+
+fn alpn_test_with_config(
+    server_config: ServerConfig,
+    client_protos: Vec<Vec<u8>>,
+    agreed: Option<&[u8]>,
+) {
+    alpn_test_with_config_error(server_config, client_protos, agreed, None)
+}
 
+fn alpn_test_with_config_error(
+    server_config: ServerConfig,
+    client_protos: Vec<Vec<u8>>,
+    agreed: Option<&[u8]>,
+    expected_error: Option<ErrorFromPeer>,
+) {
     let server_config = Arc::new(server_config);
 
     for version in rustls::ALL_VERSIONS {
@@ -93,6 +115,134 @@ fn alpn() {
     );
 }
 
+#[test]
+fn alpn_mismatch_is_fatal_by_default() {
+    let server_config = make_server_config(KeyType::Rsa);
+    assert!(server_config.alpn_mismatch_is_fatal);
+
+    for version in rustls::ALL_VERSIONS {
+        let mut server_config = server_config.clone();
+        server_config.alpn_protocols = vec![b"server-proto".to_vec()];
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.alpn_protocols = vec![b"client-proto".to_vec()];
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Server(Error::NoApplicationProtocol),
+                ErrorFromPeer::Client(Error::AlertReceived(
+                    AlertDescription::NoApplicationProtocol
+                )),
+            ])
+        );
+    }
+}
+
+#[test]
+fn alpn_mismatch_can_be_soft_failure() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.alpn_protocols = vec![b"server-proto".to_vec()];
+    server_config.alpn_mismatch_is_fatal = false;
+
+    alpn_test_with_config(server_config.clone(), vec![b"client-proto".to_vec()], None);
+
+    // agreeing a protocol is unaffected
+    alpn_test_with_config(
+        server_config.clone(),
+        vec![b"client-proto".to_vec(), b"server-proto".to_vec()],
+        Some(b"server-proto"),
+    );
+
+    // and so is a selector declining
+    server_config.alpn_selector = Some(Arc::new(|_: &ClientHello, _: &[&[u8]]| None));
+    alpn_test_with_config(server_config, vec![b"server-proto".to_vec()], None);
+}
+
+fn alpn_selector_test(
+    server_protos: Vec<Vec<u8>>,
+    chosen: Option<&'static [u8]>,
+    agreed: Option<&[u8]>,
+    expected_error: Option<ErrorFromPeer>,
+) {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.alpn_protocols = server_protos;
+    server_config.alpn_selector = Some(Arc::new(move |hello: &ClientHello, offered: &[&[u8]]| {
+        assert_eq!(hello.server_name(), Some("localhost"));
+        assert_eq!(offered, &[&b"first"[..], &b"second"[..]]);
+        chosen.map(|proto| proto.to_vec())
+    }));
+
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.alpn_protocols = vec![b"first".to_vec(), b"second".to_vec()];
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+
+        let error = do_handshake_until_error(&mut client, &mut server);
+        assert_eq!(client.alpn_protocol(), agreed);
+        assert_eq!(server.alpn_protocol(), agreed);
+        assert_eq!(error.err(), expected_error);
+    }
+}
+
+#[test]
+fn alpn_selector() {
+    // selector overrides server preference
+    alpn_selector_test(
+        vec![b"first".to_vec(), b"second".to_vec()],
+        Some(b"second"),
+        Some(b"second"),
+        None,
+    );
+
+    // selector may choose without a static list
+    alpn_selector_test(vec![], Some(b"first"), Some(b"first"), None);
+
+    // declining without a static list continues without ALPN
+    alpn_selector_test(vec![], None, None, None);
+
+    // declining with a static list is fatal
+    alpn_selector_test(
+        vec![b"first".to_vec()],
+        None,
+        None,
+        Some(ErrorFromPeer::Server(Error::NoApplicationProtocol)),
+    );
+
+    // choosing something the client did not offer is fatal
+    alpn_selector_test(
+        vec![],
+        Some(b"third"),
+        None,
+        Some(ErrorFromPeer::Server(Error::General(
+            "ALPN selector chose a protocol the client did not offer".to_string(),
+        ))),
+    );
+}
+
+#[test]
+fn alpn_selector_not_called_without_client_alpn() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.alpn_protocols = vec![b"proto".to_vec()];
+    server_config.alpn_selector = Some(Arc::new(|_: &ClientHello, _: &[&[u8]]| {
+        panic!("selector called")
+    }));
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.alpn_protocol(), None);
+        assert_eq!(server.alpn_protocol(), None);
+    }
+}
+
 fn version_test(
     client_versions: &[&'static rustls::SupportedProtocolVersion],
     server_versions: &[&'static rustls::SupportedProtocolVersion],
@@ -257,330 +407,487 @@ fn config_builder_for_server_rejects_incompatible_cipher_suites() {
     );
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn buffered_client_data_sent() {
-    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+fn config_builder_per_version_cipher_suites_are_used_per_version() {
+    use rustls::cipher_suite::{
+        TLS13_AES_256_GCM_SHA384, TLS13_CHACHA20_POLY1305_SHA256,
+        TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    };
 
-    for version in rustls::ALL_VERSIONS {
+    let server_config = ServerConfig::builder()
+        .with_cipher_suites_per_version(&[
+            (
+                &rustls::version::TLS13,
+                &[TLS13_AES_256_GCM_SHA384, TLS13_CHACHA20_POLY1305_SHA256],
+            ),
+            // TLS1.3 suites aren't usable with TLS1.2, so are ignored here
+            (
+                &rustls::version::TLS12,
+                &[
+                    TLS13_AES_256_GCM_SHA384,
+                    TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                ],
+            ),
+        ])
+        .unwrap()
+        .with_safe_default_kx_groups()
+        .unwrap();
+    let server_config = Arc::new(finish_server_config(KeyType::Rsa, server_config));
+
+    for (version, expected) in &[
+        (&rustls::version::TLS13, TLS13_AES_256_GCM_SHA384),
+        (
+            &rustls::version::TLS12,
+            TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+        ),
+    ] {
         let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
         let (mut client, mut server) =
             make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-
-        assert_eq!(5, client.writer().write(b"hello").unwrap());
-
         do_handshake(&mut client, &mut server);
-        transfer(&mut client, &mut server);
-        server.process_new_packets().unwrap();
-
-        check_read(&mut server.reader(), b"hello");
+        assert_eq!(client.negotiated_cipher_suite(), Some(*expected));
+        assert_eq!(server.negotiated_cipher_suite(), Some(*expected));
     }
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn buffered_server_data_sent() {
-    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+fn config_builder_per_version_cipher_suites_enable_only_listed_versions() {
+    let server_config = ServerConfig::builder()
+        .with_cipher_suites_per_version(&[(
+            &rustls::version::TLS13,
+            &[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384],
+        )])
+        .unwrap()
+        .with_safe_default_kx_groups()
+        .unwrap();
+    let server_config = finish_server_config(KeyType::Rsa, server_config);
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
-        let (mut client, mut server) =
-            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    assert!(matches!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::PeerIncompatibleError(_)))
+    ));
+}
 
-        assert_eq!(5, server.writer().write(b"hello").unwrap());
+#[cfg(feature = "tls12")]
+#[test]
+fn config_builder_rejects_bad_per_version_cipher_suites() {
+    assert_eq!(
+        ServerConfig::builder()
+            .with_cipher_suites_per_version(&[
+                (
+                    &rustls::version::TLS13,
+                    &[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384]
+                ),
+                (
+                    &rustls::version::TLS12,
+                    &[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384]
+                ),
+            ])
+            .err(),
+        Some(Error::General(
+            "no usable cipher suites configured for TLSv1_2".into()
+        ))
+    );
 
-        do_handshake(&mut client, &mut server);
-        transfer(&mut server, &mut client);
-        client.process_new_packets().unwrap();
+    assert_eq!(
+        ClientConfig::builder()
+            .with_cipher_suites_per_version(&[
+                (
+                    &rustls::version::TLS13,
+                    &[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384]
+                ),
+                (
+                    &rustls::version::TLS13,
+                    &[rustls::cipher_suite::TLS13_AES_128_GCM_SHA256]
+                ),
+            ])
+            .err(),
+        Some(Error::General(
+            "cipher suites configured more than once for TLSv1_3".into()
+        ))
+    );
 
-        check_read(&mut client.reader(), b"hello");
-    }
+    assert_eq!(
+        ClientConfig::builder()
+            .with_cipher_suites_per_version(&[])
+            .err(),
+        Some(Error::General("no protocol versions configured".into()))
+    );
+
+    assert_eq!(
+        ClientConfig::builder()
+            .with_cipher_suites_per_version(&[(
+                &rustls::version::TLS13,
+                &[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384]
+            )])
+            .unwrap()
+            .with_kx_groups(&[])
+            .err(),
+        Some(Error::General("no kx groups configured".into()))
+    );
 }
 
 #[test]
-fn buffered_both_data_sent() {
-    let server_config = Arc::new(make_server_config(KeyType::Rsa));
-
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
-        let (mut client, mut server) =
-            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+fn config_builder_rejects_bad_signature_schemes() {
+    assert_eq!(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_signature_schemes(&[])
+            .err(),
+        Some(Error::General("no signature schemes configured".into()))
+    );
 
+    for &scheme in &[SignatureScheme::ED448, SignatureScheme::RSA_PKCS1_SHA1] {
         assert_eq!(
-            12,
-            server
-                .writer()
-                .write(b"from-server!")
-                .unwrap()
-        );
-        assert_eq!(
-            12,
-            client
-                .writer()
-                .write(b"from-client!")
-                .unwrap()
+            ServerConfig::builder()
+                .with_safe_defaults()
+                .with_signature_schemes(&[SignatureScheme::ED25519, scheme])
+                .err(),
+            Some(Error::General(format!(
+                "unsupported signature scheme {:?}",
+                scheme
+            )))
         );
-
-        do_handshake(&mut client, &mut server);
-
-        transfer(&mut server, &mut client);
-        client.process_new_packets().unwrap();
-        transfer(&mut client, &mut server);
-        server.process_new_packets().unwrap();
-
-        check_read(&mut client.reader(), b"from-server!");
-        check_read(&mut server.reader(), b"from-client!");
     }
+
+    assert_eq!(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_signature_schemes(&[
+                SignatureScheme::ED25519,
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::ED25519,
+            ])
+            .err(),
+        Some(Error::General(
+            "signature scheme ED25519 configured more than once".into()
+        ))
+    );
 }
 
 #[test]
-fn client_can_get_server_cert() {
-    for kt in ALL_KEY_TYPES.iter() {
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions(*kt, &[version]);
-            let (mut client, mut server) =
-                make_pair_for_configs(client_config, make_server_config(*kt));
-            do_handshake(&mut client, &mut server);
+fn supported_algorithms_are_self_consistent() {
+    assert!(!ALL_CIPHER_SUITES.is_empty());
+    assert!(!rustls::ALL_KX_GROUPS.is_empty());
+    assert!(!ALL_SIGNATURE_SCHEMES.is_empty());
+
+    for (i, suite) in ALL_CIPHER_SUITES.iter().enumerate() {
+        assert!(rustls::ALL_VERSIONS.contains(&suite.version()));
+        assert!(!ALL_CIPHER_SUITES[..i]
+            .iter()
+            .any(|earlier| earlier.suite() == suite.suite()));
 
-            let certs = client.peer_certificates();
-            assert_eq!(certs, Some(kt.get_chain().as_slice()));
+        #[cfg(feature = "tls12")]
+        if let SupportedCipherSuite::Tls12(tls12) = suite {
+            for scheme in tls12.sign {
+                assert!(ALL_SIGNATURE_SCHEMES.contains(scheme));
+            }
         }
     }
-}
 
-#[test]
-fn client_can_get_server_cert_after_resumption() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let server_config = make_server_config(*kt);
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions(*kt, &[version]);
-            let (mut client, mut server) =
-                make_pair_for_configs(client_config.clone(), server_config.clone());
-            do_handshake(&mut client, &mut server);
+    for version in rustls::ALL_VERSIONS {
+        assert!(ALL_CIPHER_SUITES
+            .iter()
+            .any(|suite| suite.version() == *version));
+    }
 
-            let original_certs = client.peer_certificates();
+    for (i, group) in rustls::ALL_KX_GROUPS.iter().enumerate() {
+        assert!(!rustls::ALL_KX_GROUPS[..i]
+            .iter()
+            .any(|earlier| earlier.name == group.name));
+    }
 
-            let (mut client, mut server) =
-                make_pair_for_configs(client_config.clone(), server_config.clone());
-            do_handshake(&mut client, &mut server);
+    // Everything reported can be configured at once.
+    ServerConfig::builder()
+        .with_cipher_suites(ALL_CIPHER_SUITES)
+        .with_kx_groups(&rustls::ALL_KX_GROUPS)
+        .with_protocol_versions(rustls::ALL_VERSIONS)
+        .unwrap()
+        .with_signature_schemes(ALL_SIGNATURE_SCHEMES)
+        .unwrap();
+}
 
-            let resumed_certs = client.peer_certificates();
+static CONFIGURED_SIGNATURE_SCHEMES: &[SignatureScheme] = &[
+    SignatureScheme::ED25519,
+    SignatureScheme::RSA_PSS_SHA256,
+    SignatureScheme::ECDSA_NISTP256_SHA256,
+];
 
-            assert_eq!(original_certs, resumed_certs);
-        }
+#[test]
+fn client_offers_configured_signature_schemes_in_order() {
+    for version in rustls::ALL_VERSIONS {
+        let builder = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .with_signature_schemes(CONFIGURED_SIGNATURE_SCHEMES)
+            .unwrap();
+        let client_config = finish_client_config(KeyType::Rsa, builder);
+
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+            expected_sigalgs: Some(CONFIGURED_SIGNATURE_SCHEMES.to_vec()),
+            ..Default::default()
+        });
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Server(Error::General(
+                    "no server certificate chain resolved".into()
+                )),
+                ErrorFromPeer::Client(Error::AlertReceived(AlertDescription::AccessDenied)),
+            ])
+        );
     }
 }
 
-#[test]
-fn server_can_get_client_cert() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
+/// A signing key which signs with `scheme`, whatever the peer offered.
+struct IgnoresOfferedSchemes {
+    key: Arc<dyn sign::SigningKey>,
+    scheme: SignatureScheme,
+}
 
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
-            let (mut client, mut server) =
-                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-            do_handshake(&mut client, &mut server);
+impl sign::SigningKey for IgnoresOfferedSchemes {
+    fn choose_scheme(
+        &self,
+        _offered: &[SignatureScheme],
+        rng: Box<dyn ring::rand::SecureRandom + Send + Sync>,
+    ) -> Option<Box<dyn sign::Signer>> {
+        self.key
+            .choose_scheme(&[self.scheme], rng)
+    }
 
-            let certs = server.peer_certificates();
-            assert_eq!(certs, Some(kt.get_client_chain().as_slice()));
-        }
+    fn algorithm(&self) -> rustls::internal::msgs::enums::SignatureAlgorithm {
+        self.key.algorithm()
     }
 }
 
 #[test]
-fn server_can_get_client_cert_after_resumption() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
+fn client_rejects_signature_with_unoffered_scheme() {
+    let kt = KeyType::Ecdsa;
+    let key = sign::any_supported_type(&kt.get_key()).unwrap();
+    let certified_key = sign::CertifiedKey::new(
+        kt.get_chain(),
+        Arc::new(IgnoresOfferedSchemes {
+            key,
+            scheme: SignatureScheme::ECDSA_NISTP256_SHA256,
+        }),
+    );
+    let server_config = Arc::new(make_server_config_with_certified_key(certified_key));
 
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
-            let client_config = Arc::new(client_config);
-            let (mut client, mut server) =
-                make_pair_for_arc_configs(&client_config, &server_config);
-            do_handshake(&mut client, &mut server);
-            let original_certs = server.peer_certificates();
+    for version in rustls::ALL_VERSIONS {
+        let builder = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .with_signature_schemes(&[
+                SignatureScheme::ED25519,
+                SignatureScheme::ECDSA_NISTP384_SHA384,
+            ])
+            .unwrap();
+        let client_config = finish_client_config(kt, builder);
 
-            let (mut client, mut server) =
-                make_pair_for_arc_configs(&client_config, &server_config);
-            do_handshake(&mut client, &mut server);
-            let resumed_certs = server.peer_certificates();
-            assert_eq!(original_certs, resumed_certs);
-        }
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Client(Error::PeerMisbehavedError(
+                    "server signed with unoffered signature scheme ECDSA_NISTP256_SHA256".into()
+                )),
+                ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::IllegalParameter)),
+            ])
+        );
     }
 }
 
-/// Test that the server handles combination of `offer_client_auth()` returning true
-/// and `client_auth_mandatory` returning `Some(false)`. This exercises both the
-/// client's and server's ability to "recover" from the server asking for a client
-/// certificate and not being given one. This also covers the implementation
-/// of `AllowAnyAnonymousOrAuthenticatedClient`.
-#[test]
-fn server_allow_any_anonymous_or_authenticated_client() {
-    let kt = KeyType::Rsa;
-    for client_cert_chain in [None, Some(kt.get_client_chain())].iter() {
-        let client_auth_roots = get_client_root_store(kt);
-        let client_auth = AllowAnyAnonymousOrAuthenticatedClient::new(client_auth_roots);
-
-        let server_config = ServerConfig::builder()
-            .with_safe_defaults()
-            .with_client_cert_verifier(client_auth)
-            .with_single_cert(kt.get_chain(), kt.get_key())
-            .unwrap();
-        let server_config = Arc::new(server_config);
-
-        for version in rustls::ALL_VERSIONS {
-            let client_config = if client_cert_chain.is_some() {
-                make_client_config_with_versions_with_auth(kt, &[version])
-            } else {
-                make_client_config_with_versions(kt, &[version])
-            };
-            let (mut client, mut server) =
-                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-            do_handshake(&mut client, &mut server);
+struct ClientCheckSigschemes {
+    queried: AtomicBool,
+}
 
-            let certs = server.peer_certificates();
-            assert_eq!(certs, client_cert_chain.as_deref());
-        }
+impl ResolvesClientCert for ClientCheckSigschemes {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        assert_eq!(sigschemes, CONFIGURED_SIGNATURE_SCHEMES);
+        self.queried
+            .store(true, Ordering::SeqCst);
+        None
     }
-}
 
-fn check_read_and_close(reader: &mut dyn io::Read, expect: &[u8]) {
-    check_read(reader, expect);
-    assert!(matches!(reader.read(&mut [0u8; 5]), Ok(0)));
+    fn has_certs(&self) -> bool {
+        true
+    }
 }
 
 #[test]
-fn server_close_notify() {
+fn server_requests_configured_signature_schemes_in_order() {
     let kt = KeyType::Rsa;
-    let server_config = Arc::new(make_server_config_with_mandatory_client_auth(kt));
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_signature_schemes(CONFIGURED_SIGNATURE_SCHEMES)
+        .unwrap()
+        .with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(
+            get_client_root_store(kt),
+        ))
+        .with_single_cert(kt.get_chain(), kt.get_key())
+        .unwrap();
+    let server_config = Arc::new(server_config);
 
     for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions_with_auth(kt, &[version]);
+        let resolver = Arc::new(ClientCheckSigschemes {
+            queried: AtomicBool::new(false),
+        });
+        let mut client_config = make_client_config_with_versions(kt, &[version]);
+        client_config.client_auth_cert_resolver = resolver.clone();
+
         let (mut client, mut server) =
             make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-        do_handshake(&mut client, &mut server);
-
-        // check that alerts don't overtake appdata
-        assert_eq!(
-            12,
-            server
-                .writer()
-                .write(b"from-server!")
-                .unwrap()
-        );
         assert_eq!(
-            12,
-            client
-                .writer()
-                .write(b"from-client!")
-                .unwrap()
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Server(Error::NoCertificatesPresented))
         );
-        server.send_close_notify();
+        assert!(resolver.queried.load(Ordering::SeqCst));
+    }
+}
 
-        transfer(&mut server, &mut client);
-        let io_state = client.process_new_packets().unwrap();
-        assert!(io_state.peer_has_closed());
-        check_read_and_close(&mut client.reader(), b"from-server!");
+/// Chooses the first of its keys able to make the client's most
+/// preferred signature scheme.
+struct ResolvesByClientPreference {
+    keys: Vec<Arc<sign::CertifiedKey>>,
+}
 
-        transfer(&mut client, &mut server);
-        server.process_new_packets().unwrap();
-        check_read(&mut server.reader(), b"from-client!");
+impl ResolvesServerCert for ResolvesByClientPreference {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        client_hello
+            .signature_schemes()
+            .iter()
+            .find_map(|scheme| {
+                self.keys.iter().find(|certkey| {
+                    certkey
+                        .key
+                        .choose_scheme(&[*scheme], Box::new(ring::rand::SystemRandom::new()))
+                        .is_some()
+                })
+            })
+            .cloned()
     }
 }
 
 #[test]
-fn client_close_notify() {
-    let kt = KeyType::Rsa;
-    let server_config = Arc::new(make_server_config_with_mandatory_client_auth(kt));
+fn server_certificate_follows_configured_signature_scheme_preference() {
+    let key_types = [KeyType::Ecdsa, KeyType::Ed25519];
+    let mut roots = rustls::RootCertStore::empty();
+    let keys = key_types
+        .iter()
+        .map(|kt| {
+            let chain = kt.get_chain();
+            roots
+                .add(chain.last().unwrap())
+                .unwrap();
+            let key = sign::any_supported_type(&kt.get_key()).unwrap();
+            Arc::new(sign::CertifiedKey::new(chain, key))
+        })
+        .collect::<Vec<_>>();
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_resolver = Arc::new(ResolvesByClientPreference { keys });
+    let server_config = Arc::new(server_config);
+
+    let preferences = [
+        (SignatureScheme::ED25519, KeyType::Ed25519),
+        (SignatureScheme::ECDSA_NISTP256_SHA256, KeyType::Ecdsa),
+    ];
+    for &(preferred, expected) in &preferences {
+        for version in rustls::ALL_VERSIONS {
+            let mut schemes = vec![preferred];
+            schemes.extend(
+                CONFIGURED_SIGNATURE_SCHEMES
+                    .iter()
+                    .filter(|scheme| **scheme != preferred),
+            );
+            let client_config = ClientConfig::builder()
+                .with_safe_default_cipher_suites()
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[version])
+                .unwrap()
+                .with_signature_schemes(&schemes)
+                .unwrap()
+                .with_root_certificates(roots.clone())
+                .with_no_client_auth();
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+            assert_eq!(
+                client.peer_certificates().unwrap()[0],
+                expected.get_chain()[0]
+            );
+            assert_eq!(
+                server.sent_certificate_chain(),
+                Some(&expected.get_chain()[..])
+            );
+        }
+    }
+}
+
+#[test]
+fn buffered_client_data_sent() {
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
 
     for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions_with_auth(kt, &[version]);
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
         let (mut client, mut server) =
             make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-        do_handshake(&mut client, &mut server);
 
-        // check that alerts don't overtake appdata
-        assert_eq!(
-            12,
-            server
-                .writer()
-                .write(b"from-server!")
-                .unwrap()
-        );
-        assert_eq!(
-            12,
-            client
-                .writer()
-                .write(b"from-client!")
-                .unwrap()
-        );
-        client.send_close_notify();
+        assert_eq!(5, client.writer().write(b"hello").unwrap());
 
+        do_handshake(&mut client, &mut server);
         transfer(&mut client, &mut server);
-        let io_state = server.process_new_packets().unwrap();
-        assert!(io_state.peer_has_closed());
-        check_read_and_close(&mut server.reader(), b"from-client!");
+        server.process_new_packets().unwrap();
 
-        transfer(&mut server, &mut client);
-        client.process_new_packets().unwrap();
-        check_read(&mut client.reader(), b"from-server!");
+        check_read(&mut server.reader(), b"hello");
     }
 }
 
 #[test]
-fn server_closes_uncleanly() {
-    let kt = KeyType::Rsa;
-    let server_config = Arc::new(make_server_config(kt));
+fn buffered_server_data_sent() {
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
 
     for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
         let (mut client, mut server) =
             make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-        do_handshake(&mut client, &mut server);
 
-        // check that unclean EOF reporting does not overtake appdata
-        assert_eq!(
-            12,
-            server
-                .writer()
-                .write(b"from-server!")
-                .unwrap()
-        );
-        assert_eq!(
-            12,
-            client
-                .writer()
-                .write(b"from-client!")
-                .unwrap()
-        );
+        assert_eq!(5, server.writer().write(b"hello").unwrap());
 
+        do_handshake(&mut client, &mut server);
         transfer(&mut server, &mut client);
-        transfer_eof(&mut client);
-        let io_state = client.process_new_packets().unwrap();
-        assert!(!io_state.peer_has_closed());
-        check_read(&mut client.reader(), b"from-server!");
-
-        assert!(matches!(client.reader().read(&mut [0u8; 1]),
-                         Err(err) if err.kind() == io::ErrorKind::UnexpectedEof));
+        client.process_new_packets().unwrap();
 
-        // may still transmit pending frames
-        transfer(&mut client, &mut server);
-        server.process_new_packets().unwrap();
-        check_read(&mut server.reader(), b"from-client!");
+        check_read(&mut client.reader(), b"hello");
     }
 }
 
 #[test]
-fn client_closes_uncleanly() {
-    let kt = KeyType::Rsa;
-    let server_config = Arc::new(make_server_config(kt));
+fn buffered_both_data_sent() {
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
 
     for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
         let (mut client, mut server) =
             make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-        do_handshake(&mut client, &mut server);
 
-        // check that unclean EOF reporting does not overtake appdata
         assert_eq!(
             12,
             server
@@ -596,3329 +903,10366 @@ fn client_closes_uncleanly() {
                 .unwrap()
         );
 
-        transfer(&mut client, &mut server);
-        transfer_eof(&mut server);
-        let io_state = server.process_new_packets().unwrap();
-        assert!(!io_state.peer_has_closed());
-        check_read(&mut server.reader(), b"from-client!");
-
-        assert!(matches!(server.reader().read(&mut [0u8; 1]),
-                         Err(err) if err.kind() == io::ErrorKind::UnexpectedEof));
+        do_handshake(&mut client, &mut server);
 
-        // may still transmit pending frames
         transfer(&mut server, &mut client);
         client.process_new_packets().unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+
         check_read(&mut client.reader(), b"from-server!");
+        check_read(&mut server.reader(), b"from-client!");
     }
 }
 
-#[derive(Default)]
-struct ServerCheckCertResolve {
-    expected_sni: Option<String>,
-    expected_sigalgs: Option<Vec<SignatureScheme>>,
-    expected_alpn: Option<Vec<Vec<u8>>>,
-    expected_cipher_suites: Option<Vec<CipherSuite>>,
-}
+#[test]
+fn client_can_get_server_cert() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, make_server_config(*kt));
+            do_handshake(&mut client, &mut server);
 
-impl ResolvesServerCert for ServerCheckCertResolve {
-    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
-        if client_hello
-            .signature_schemes()
-            .is_empty()
-        {
-            panic!("no signature schemes shared by client");
+            let certs = client.peer_certificates();
+            assert_eq!(certs, Some(kt.get_chain().as_slice()));
         }
+    }
+}
 
-        if client_hello.cipher_suites().is_empty() {
-            panic!("no cipher suites shared by client");
-        }
-
-        if let Some(expected_sni) = &self.expected_sni {
-            let sni: &str = client_hello
-                .server_name()
-                .expect("sni unexpectedly absent");
-            assert_eq!(expected_sni, sni);
-        }
+#[test]
+fn client_can_get_server_cert_after_resumption() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = make_server_config(*kt);
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config.clone(), server_config.clone());
+            do_handshake(&mut client, &mut server);
 
-        if let Some(expected_sigalgs) = &self.expected_sigalgs {
-            assert_eq!(
-                expected_sigalgs,
-                client_hello.signature_schemes(),
-                "unexpected signature schemes"
-            );
-        }
+            let original_certs = client.peer_certificates();
 
-        if let Some(expected_alpn) = &self.expected_alpn {
-            let alpn = client_hello
-                .alpn()
-                .expect("alpn unexpectedly absent")
-                .collect::<Vec<_>>();
-            assert_eq!(alpn.len(), expected_alpn.len());
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config.clone(), server_config.clone());
+            do_handshake(&mut client, &mut server);
 
-            for (got, wanted) in alpn.iter().zip(expected_alpn.iter()) {
-                assert_eq!(got, &wanted.as_slice());
-            }
-        }
+            let resumed_certs = client.peer_certificates();
 
-        if let Some(expected_cipher_suites) = &self.expected_cipher_suites {
-            assert_eq!(
-                expected_cipher_suites,
-                client_hello.cipher_suites(),
-                "unexpected cipher suites"
-            );
+            assert_eq!(original_certs, resumed_certs);
         }
-
-        None
     }
 }
 
 #[test]
-fn server_cert_resolve_with_sni() {
+fn server_can_get_client_cert() {
     for kt in ALL_KEY_TYPES.iter() {
-        let client_config = make_client_config(*kt);
-        let mut server_config = make_server_config(*kt);
-
-        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
-            expected_sni: Some("the-value-from-sni".into()),
-            ..Default::default()
-        });
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
 
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("the-value-from-sni")).unwrap();
-        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
 
-        let err = do_handshake_until_error(&mut client, &mut server);
-        assert!(err.is_err());
+            let certs = server.peer_certificates();
+            assert_eq!(certs, Some(kt.get_client_chain().as_slice()));
+        }
     }
 }
 
 #[test]
-fn server_cert_resolve_with_alpn() {
+fn server_can_get_client_cert_after_resumption() {
     for kt in ALL_KEY_TYPES.iter() {
-        let mut client_config = make_client_config(*kt);
-        client_config.alpn_protocols = vec!["foo".into(), "bar".into()];
-
-        let mut server_config = make_server_config(*kt);
-        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
-            expected_alpn: Some(vec![b"foo".to_vec(), b"bar".to_vec()]),
-            ..Default::default()
-        });
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
 
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("sni-value")).unwrap();
-        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+            let client_config = Arc::new(client_config);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            let original_certs = server.peer_certificates();
 
-        let err = do_handshake_until_error(&mut client, &mut server);
-        assert!(err.is_err());
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&client_config, &server_config);
+            do_handshake(&mut client, &mut server);
+            let resumed_certs = server.peer_certificates();
+            assert_eq!(original_certs, resumed_certs);
+        }
     }
 }
 
+/// Test that the server handles combination of `offer_client_auth()` returning true
+/// and `client_auth_mandatory` returning `Some(false)`. This exercises both the
+/// client's and server's ability to "recover" from the server asking for a client
+/// certificate and not being given one. This also covers the implementation
+/// of `AllowAnyAnonymousOrAuthenticatedClient`.
 #[test]
-fn client_trims_terminating_dot() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let client_config = make_client_config(*kt);
-        let mut server_config = make_server_config(*kt);
+fn server_allow_any_anonymous_or_authenticated_client() {
+    let kt = KeyType::Rsa;
+    for client_cert_chain in [None, Some(kt.get_client_chain())].iter() {
+        let client_auth_roots = get_client_root_store(kt);
+        let client_auth = AllowAnyAnonymousOrAuthenticatedClient::new(client_auth_roots);
 
-        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
-            expected_sni: Some("some-host.com".into()),
-            ..Default::default()
-        });
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(client_auth)
+            .with_single_cert(kt.get_chain(), kt.get_key())
+            .unwrap();
+        let server_config = Arc::new(server_config);
 
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("some-host.com.")).unwrap();
-        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+        for version in rustls::ALL_VERSIONS {
+            let client_config = if client_cert_chain.is_some() {
+                make_client_config_with_versions_with_auth(kt, &[version])
+            } else {
+                make_client_config_with_versions(kt, &[version])
+            };
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
 
-        let err = do_handshake_until_error(&mut client, &mut server);
-        assert!(err.is_err());
+            let certs = server.peer_certificates();
+            assert_eq!(certs, client_cert_chain.as_deref());
+        }
     }
 }
 
-#[cfg(feature = "tls12")]
-fn check_sigalgs_reduced_by_ciphersuite(
-    kt: KeyType,
-    suite: CipherSuite,
-    expected_sigalgs: Vec<SignatureScheme>,
-) {
-    let client_config = finish_client_config(
-        kt,
-        ClientConfig::builder()
-            .with_cipher_suites(&[find_suite(suite)])
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap(),
+#[test]
+fn server_post_handshake_auth_requires_completed_handshake() {
+    let kt = KeyType::Rsa;
+    let (_, mut server) = make_pair_for_configs(
+        make_client_config(kt),
+        make_server_config_with_optional_client_auth(kt),
     );
 
-    let mut server_config = make_server_config(kt);
+    assert_eq!(
+        server.request_post_handshake_auth(),
+        Err(Error::HandshakeNotComplete)
+    );
+}
 
-    server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
-        expected_sigalgs: Some(expected_sigalgs),
-        expected_cipher_suites: Some(vec![suite, CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV]),
-        ..Default::default()
-    });
+#[test]
+fn server_post_handshake_auth_requires_client_support() {
+    let kt = KeyType::Rsa;
+    let server_config = Arc::new(make_server_config_with_optional_client_auth(kt));
 
-    let mut client = ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
-    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions_with_auth(kt, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
 
-    let err = do_handshake_until_error(&mut client, &mut server);
-    assert!(err.is_err());
-}
+        let err = server
+            .request_post_handshake_auth()
+            .unwrap_err();
+        assert!(matches!(err, Error::PeerIncompatibleError(_)));
+        assert!(!server.wants_write());
 
-#[cfg(feature = "tls12")]
-#[test]
-fn server_cert_resolve_reduces_sigalgs_for_rsa_ciphersuite() {
-    check_sigalgs_reduced_by_ciphersuite(
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
-        vec![
-            SignatureScheme::RSA_PSS_SHA512,
-            SignatureScheme::RSA_PSS_SHA384,
-            SignatureScheme::RSA_PSS_SHA256,
-            SignatureScheme::RSA_PKCS1_SHA512,
-            SignatureScheme::RSA_PKCS1_SHA384,
-            SignatureScheme::RSA_PKCS1_SHA256,
-        ],
-    );
+        // the connection is unaffected
+        server
+            .writer()
+            .write_all(b"hello")
+            .unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        check_read(&mut client.reader(), b"hello");
+    }
 }
 
-#[cfg(feature = "tls12")]
-#[test]
-fn server_cert_resolve_reduces_sigalgs_for_ecdsa_ciphersuite() {
-    check_sigalgs_reduced_by_ciphersuite(
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
-        vec![
-            SignatureScheme::ECDSA_NISTP384_SHA384,
-            SignatureScheme::ECDSA_NISTP256_SHA256,
-            SignatureScheme::ED25519,
-        ],
-    );
+/// Declines to authenticate during the handshake, but answers any later requests.
+struct ClientCertAfterHandshake {
+    certkey: Arc<sign::CertifiedKey>,
+    query_count: AtomicUsize,
 }
 
-struct ServerCheckNoSNI {}
+impl ClientCertAfterHandshake {
+    fn new(kt: KeyType) -> Self {
+        Self {
+            certkey: kt.get_certified_client_key(),
+            query_count: AtomicUsize::new(0),
+        }
+    }
+}
 
-impl ResolvesServerCert for ServerCheckNoSNI {
-    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
-        assert!(client_hello.server_name().is_none());
+impl ResolvesClientCert for ClientCertAfterHandshake {
+    fn resolve(
+        &self,
+        _acceptable_issuers: &[&[u8]],
+        _sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        match self
+            .query_count
+            .fetch_add(1, Ordering::SeqCst)
+        {
+            0 => None,
+            _ => Some(Arc::clone(&self.certkey)),
+        }
+    }
 
-        None
+    fn has_certs(&self) -> bool {
+        true
     }
 }
 
+fn make_pair_for_post_handshake_auth(
+    kt: KeyType,
+    resolver: Arc<dyn ResolvesClientCert>,
+) -> (ClientConnection, ServerConnection) {
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.client_auth_cert_resolver = resolver;
+    client_config.enable_post_handshake_auth = true;
+
+    let (mut client, mut server) = make_pair_for_configs(
+        client_config,
+        make_server_config_with_optional_client_auth(kt),
+    );
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.peer_certificates(), None);
+    (client, server)
+}
+
+fn do_post_handshake_auth(client: &mut ClientConnection, server: &mut ServerConnection) {
+    server
+        .request_post_handshake_auth()
+        .unwrap();
+    transfer(server, client);
+    client.process_new_packets().unwrap();
+    transfer(client, server);
+    server.process_new_packets().unwrap();
+}
+
 #[test]
-fn client_with_sni_disabled_does_not_send_sni() {
+fn post_handshake_auth_round_trip() {
     for kt in ALL_KEY_TYPES.iter() {
-        let mut server_config = make_server_config(*kt);
-        server_config.cert_resolver = Arc::new(ServerCheckNoSNI {});
-        let server_config = Arc::new(server_config);
+        let resolver = Arc::new(ClientCertAfterHandshake::new(*kt));
+        let (mut client, mut server) = make_pair_for_post_handshake_auth(*kt, resolver);
 
-        for version in rustls::ALL_VERSIONS {
-            let mut client_config = make_client_config_with_versions(*kt, &[version]);
-            client_config.enable_sni = false;
+        do_post_handshake_auth(&mut client, &mut server);
+        assert_eq!(server.peer_certificates(), Some(&kt.get_client_chain()[..]));
 
-            let mut client =
-                ClientConnection::new(Arc::new(client_config), dns_name("value-not-sent")).unwrap();
-            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+        // each request gets a fresh context and transcript
+        do_post_handshake_auth(&mut client, &mut server);
+        assert_eq!(server.peer_certificates(), Some(&kt.get_client_chain()[..]));
 
-            let err = do_handshake_until_error(&mut client, &mut server);
-            assert!(err.is_err());
-        }
-    }
-}
-
-#[test]
-fn client_checks_server_certificate_with_given_name() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config(*kt));
-
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions(*kt, &[version]);
-            let mut client = ClientConnection::new(
-                Arc::new(client_config),
-                dns_name("not-the-right-hostname.com"),
-            )
+        // the connection stays usable in both directions
+        client
+            .writer()
+            .write_all(b"from-client")
             .unwrap();
-            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-
-            let err = do_handshake_until_error(&mut client, &mut server);
-            assert_eq!(
-                err,
-                Err(ErrorFromPeer::Client(Error::InvalidCertificateData(
-                    "invalid peer certificate: CertNotValidForName".into(),
-                )))
-            );
-        }
-    }
-}
-
-struct ClientCheckCertResolve {
-    query_count: AtomicUsize,
-    expect_queries: usize,
-}
-
-impl ClientCheckCertResolve {
-    fn new(expect_queries: usize) -> Self {
-        ClientCheckCertResolve {
-            query_count: AtomicUsize::new(0),
-            expect_queries,
-        }
-    }
-}
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), b"from-client");
 
-impl Drop for ClientCheckCertResolve {
-    fn drop(&mut self) {
-        if !std::thread::panicking() {
-            let count = self.query_count.load(Ordering::SeqCst);
-            assert_eq!(count, self.expect_queries);
-        }
+        server
+            .writer()
+            .write_all(b"from-server")
+            .unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        check_read(&mut client.reader(), b"from-server");
     }
 }
 
-impl ResolvesClientCert for ClientCheckCertResolve {
-    fn resolve(
-        &self,
-        acceptable_issuers: &[&[u8]],
-        sigschemes: &[SignatureScheme],
-    ) -> Option<Arc<sign::CertifiedKey>> {
-        self.query_count
-            .fetch_add(1, Ordering::SeqCst);
-
-        if acceptable_issuers.is_empty() {
-            panic!("no issuers offered by server");
-        }
-
-        if sigschemes.is_empty() {
-            panic!("no signature schemes shared by server");
-        }
-
-        None
-    }
+#[test]
+fn post_handshake_auth_client_declines() {
+    let kt = KeyType::Rsa;
+    let (mut client, mut server) =
+        make_pair_for_post_handshake_auth(kt, Arc::new(ClientCheckCertResolve::new(2)));
 
-    fn has_certs(&self) -> bool {
-        true
-    }
+    // client auth is optional, so declining leaves the connection usable
+    do_post_handshake_auth(&mut client, &mut server);
+    assert_eq!(server.peer_certificates(), None);
 }
 
 #[test]
-fn client_cert_resolve() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
+fn post_handshake_auth_only_one_request_at_a_time() {
+    let kt = KeyType::Rsa;
+    let resolver = Arc::new(ClientCertAfterHandshake::new(kt));
+    let (_client, mut server) = make_pair_for_post_handshake_auth(kt, resolver);
 
-        for version in rustls::ALL_VERSIONS {
-            let mut client_config = make_client_config_with_versions(*kt, &[version]);
-            client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(1));
+    server
+        .request_post_handshake_auth()
+        .unwrap();
+    assert!(matches!(
+        server.request_post_handshake_auth(),
+        Err(Error::General(_))
+    ));
+}
 
-            let (mut client, mut server) =
-                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+#[cfg(feature = "tls12")]
+fn client_renegotiation_response(
+    response: rustls::RenegotiationResponse,
+) -> (ClientConnection, ServerConnection, Result<(), Error>) {
+    use rustls::internal::msgs::enums::HandshakeType;
+    use rustls::internal::msgs::handshake::{HandshakeMessagePayload, HandshakePayload};
+    use rustls::internal::msgs::message::{Message, MessagePayload};
+
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    client_config.renegotiation_response = response;
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
 
-            assert_eq!(
-                do_handshake_until_error(&mut client, &mut server),
-                Err(ErrorFromPeer::Server(Error::NoCertificatesPresented))
-            );
-        }
-    }
+    // the server won't send this itself, as rustls never renegotiates
+    let hello_request = Message {
+        version: ProtocolVersion::TLSv1_2,
+        payload: MessagePayload::Handshake(HandshakeMessagePayload {
+            typ: HandshakeType::HelloRequest,
+            payload: HandshakePayload::HelloRequest,
+        }),
+    };
+    server
+        .common_state
+        .send_msg(hello_request, true);
+    transfer(&mut server, &mut client);
+    let result = client.process_new_packets().map(|_| ());
+    (client, server, result)
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn client_auth_works() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
+fn client_declines_renegotiation_by_default() {
+    let default_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    assert_eq!(
+        default_config.renegotiation_response,
+        rustls::RenegotiationResponse::Decline
+    );
 
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
-            let (mut client, mut server) =
-                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
-            do_handshake(&mut client, &mut server);
-        }
-    }
-}
+    let (mut client, mut server, result) =
+        client_renegotiation_response(rustls::RenegotiationResponse::Decline);
+    assert_eq!(result, Ok(()));
+    assert!(client.wants_write());
 
-#[test]
-fn client_error_is_sticky() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
+    // the warning alert doesn't stop the connection
+    transfer(&mut client, &mut server);
+    assert!(server.process_new_packets().is_ok());
     client
-        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
-        .unwrap();
-    let mut err = client.process_new_packets();
-    assert!(err.is_err());
-    err = client.process_new_packets();
-    assert!(err.is_err());
-}
-
-#[test]
-fn server_error_is_sticky() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    server
-        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
+        .writer()
+        .write_all(b"still here")
         .unwrap();
-    let mut err = server.process_new_packets();
-    assert!(err.is_err());
-    err = server.process_new_packets();
-    assert!(err.is_err());
-}
-
-#[test]
-fn server_flush_does_nothing() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    assert!(matches!(server.writer().flush(), Ok(())));
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"still here");
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn client_flush_does_nothing() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    assert!(matches!(client.writer().flush(), Ok(())));
+fn client_can_ignore_renegotiation() {
+    let (client, _server, result) =
+        client_renegotiation_response(rustls::RenegotiationResponse::Ignore);
+    assert_eq!(result, Ok(()));
+    assert!(!client.wants_write());
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn server_is_send_and_sync() {
-    let (_, server) = make_pair(KeyType::Rsa);
-    &server as &dyn Send;
-    &server as &dyn Sync;
-}
+fn client_can_abort_on_renegotiation() {
+    use rustls::internal::msgs::enums::AlertDescription;
 
-#[test]
-fn client_is_send_and_sync() {
-    let (client, _) = make_pair(KeyType::Rsa);
-    &client as &dyn Send;
-    &client as &dyn Sync;
+    let (mut client, mut server, result) =
+        client_renegotiation_response(rustls::RenegotiationResponse::Abort);
+    assert_eq!(
+        result,
+        Err(Error::PeerIncompatibleError(
+            "peer tried to renegotiate".into()
+        ))
+    );
+    transfer(&mut client, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::AlertReceived(AlertDescription::NoRenegotiation))
+    );
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn server_respects_buffer_limit_pre_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn server_can_abort_on_renegotiation() {
+    use rustls::internal::msgs::enums::{AlertDescription, Compression, HandshakeType};
+    use rustls::internal::msgs::handshake::{
+        ClientHelloPayload, HandshakeMessagePayload, HandshakePayload, Random, SessionID,
+    };
+    use rustls::internal::msgs::message::{Message, MessagePayload};
 
-    server.set_buffer_limit(Some(32));
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.renegotiation_response = rustls::RenegotiationResponse::Abort;
+    let (mut client, mut server) = make_pair_for_configs(
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]),
+        server_config,
+    );
+    do_handshake(&mut client, &mut server);
 
+    let client_hello = Message {
+        version: ProtocolVersion::TLSv1_2,
+        payload: MessagePayload::Handshake(HandshakeMessagePayload {
+            typ: HandshakeType::ClientHello,
+            payload: HandshakePayload::ClientHello(ClientHelloPayload {
+                client_version: ProtocolVersion::TLSv1_2,
+                random: Random::from([0; 32]),
+                session_id: SessionID::empty(),
+                cipher_suites: vec![CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256],
+                compression_methods: vec![Compression::Null],
+                extensions: vec![],
+            }),
+        }),
+    };
+    client
+        .common_state
+        .send_msg(client_hello, true);
+    transfer(&mut client, &mut server);
     assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
+        server.process_new_packets().err(),
+        Some(Error::PeerIncompatibleError(
+            "peer tried to renegotiate".into()
+        ))
     );
+    transfer(&mut server, &mut client);
     assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        12
+        client.process_new_packets().err(),
+        Some(Error::AlertReceived(AlertDescription::NoRenegotiation))
     );
+}
 
+#[test]
+fn post_handshake_certificate_request_rejected_unless_enabled() {
+    use rustls::internal::msgs::base::PayloadU8;
+    use rustls::internal::msgs::enums::{AlertDescription, HandshakeType};
+    use rustls::internal::msgs::handshake::{
+        CertReqExtension, CertificateRequestPayloadTLS13, HandshakeMessagePayload, HandshakePayload,
+    };
+    use rustls::internal::msgs::message::{Message, MessagePayload};
+
+    let kt = KeyType::Rsa;
+    let client_config = make_client_config_with_versions_with_auth(kt, &[&rustls::version::TLS13]);
+    let (mut client, mut server) = make_pair_for_configs(
+        client_config,
+        make_server_config_with_optional_client_auth(kt),
+    );
     do_handshake(&mut client, &mut server);
+
+    // the server won't send this itself, as the client didn't offer support
+    let certreq = Message {
+        version: ProtocolVersion::TLSv1_3,
+        payload: MessagePayload::Handshake(HandshakeMessagePayload {
+            typ: HandshakeType::CertificateRequest,
+            payload: HandshakePayload::CertificateRequestTLS13(CertificateRequestPayloadTLS13 {
+                context: PayloadU8::new(vec![1, 2, 3]),
+                extensions: vec![CertReqExtension::SignatureAlgorithms(vec![
+                    SignatureScheme::ECDSA_NISTP256_SHA256,
+                ])],
+            }),
+        }),
+    };
+    server
+        .common_state
+        .send_msg(certreq, true);
     transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
 
-    check_read(&mut client.reader(), b"01234567890123456789012345678901");
+    assert!(matches!(
+        client.process_new_packets(),
+        Err(Error::InappropriateHandshakeMessage { .. })
+    ));
+    transfer(&mut client, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::AlertReceived(AlertDescription::UnexpectedMessage))
+    );
 }
 
 #[test]
-fn server_respects_buffer_limit_pre_handshake_with_vectored_write() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn tls13_tampered_record_is_fatal() {
+    use rustls::internal::msgs::enums::AlertDescription;
 
-    server.set_buffer_limit(Some(32));
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
 
-    assert_eq!(
-        server
+    let mut records = vec![];
+    for data in [b"hello", b"world"].iter() {
+        client
             .writer()
-            .write_vectored(&[
-                IoSlice::new(b"01234567890123456789"),
-                IoSlice::new(b"01234567890123456789")
-            ])
-            .unwrap(),
-        32
-    );
+            .write_all(*data)
+            .unwrap();
+        let mut record = Vec::new();
+        client.write_tls(&mut record).unwrap();
+        records.push(record);
+    }
+    let last = records[0].len() - 1;
+    records[0][last] ^= 0x01;
 
-    do_handshake(&mut client, &mut server);
+    server
+        .read_tls(&mut &records[0][..])
+        .unwrap();
+    assert_eq!(server.process_new_packets(), Err(Error::DecryptError));
     transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+    assert_eq!(
+        client.process_new_packets(),
+        Err(Error::AlertReceived(AlertDescription::BadRecordMac))
+    );
 
-    check_read(&mut client.reader(), b"01234567890123456789012345678901");
+    // The following, untouched, record is not processed.
+    server
+        .read_tls(&mut &records[1][..])
+        .unwrap();
+    assert_eq!(server.process_new_packets(), Err(Error::DecryptError));
+    let err = server
+        .reader()
+        .read(&mut [0u8; 16])
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
 }
 
 #[test]
-fn server_respects_buffer_limit_post_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn tls13_oversized_ciphertext_is_fatal() {
+    use rustls::internal::msgs::enums::AlertDescription;
 
-    // this test will vary in behaviour depending on the default suites
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
     do_handshake(&mut client, &mut server);
-    server.set_buffer_limit(Some(48));
 
+    // This is within what TLS1.2 allows, but too large for TLS1.3.
+    let len = 16384 + 256 + 1;
+    let mut record = vec![0x17, 0x03, 0x03, (len >> 8) as u8, len as u8];
+    record.resize(5 + len, 0);
+
+    server
+        .read_tls(&mut &record[..])
+        .unwrap();
     assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
+        server.process_new_packets(),
+        Err(Error::PeerSentOversizedRecord)
     );
+    transfer(&mut server, &mut client);
     assert_eq!(
-        server
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        6
+        client.process_new_packets(),
+        Err(Error::AlertReceived(AlertDescription::RecordOverflow))
     );
+}
 
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+fn check_read_and_close(reader: &mut dyn io::Read, expect: &[u8]) {
+    check_read(reader, expect);
+    assert!(matches!(reader.read(&mut [0u8; 5]), Ok(0)));
+}
 
-    check_read(&mut client.reader(), b"01234567890123456789012345");
+#[test]
+fn server_close_notify() {
+    let kt = KeyType::Rsa;
+    let server_config = Arc::new(make_server_config_with_mandatory_client_auth(kt));
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions_with_auth(kt, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+
+        // check that alerts don't overtake appdata
+        assert_eq!(
+            12,
+            server
+                .writer()
+                .write(b"from-server!")
+                .unwrap()
+        );
+        assert_eq!(
+            12,
+            client
+                .writer()
+                .write(b"from-client!")
+                .unwrap()
+        );
+        server.send_close_notify();
+
+        transfer(&mut server, &mut client);
+        let io_state = client.process_new_packets().unwrap();
+        assert!(io_state.peer_has_closed());
+        check_read_and_close(&mut client.reader(), b"from-server!");
+
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), b"from-client!");
+    }
 }
 
 #[test]
-fn client_respects_buffer_limit_pre_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn client_close_notify() {
+    let kt = KeyType::Rsa;
+    let server_config = Arc::new(make_server_config_with_mandatory_client_auth(kt));
 
-    client.set_buffer_limit(Some(32));
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions_with_auth(kt, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
 
-    assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
-    );
-    assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        12
-    );
+        // check that alerts don't overtake appdata
+        assert_eq!(
+            12,
+            server
+                .writer()
+                .write(b"from-server!")
+                .unwrap()
+        );
+        assert_eq!(
+            12,
+            client
+                .writer()
+                .write(b"from-client!")
+                .unwrap()
+        );
+        client.send_close_notify();
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+        transfer(&mut client, &mut server);
+        let io_state = server.process_new_packets().unwrap();
+        assert!(io_state.peer_has_closed());
+        check_read_and_close(&mut server.reader(), b"from-client!");
 
-    check_read(&mut server.reader(), b"01234567890123456789012345678901");
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        check_read(&mut client.reader(), b"from-server!");
+    }
 }
 
 #[test]
-fn client_respects_buffer_limit_pre_handshake_with_vectored_write() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn writes_are_buffered_in_each_handshake_phase() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+
+        let mut client_wrote = Vec::new();
+        let mut server_wrote = Vec::new();
+        let mut phase = 0;
+        while client.is_handshaking() || server.is_handshaking() {
+            let data = format!("phase {} ", phase);
+            phase += 1;
+            assert_eq!(
+                data.len(),
+                client
+                    .writer()
+                    .write(data.as_bytes())
+                    .unwrap()
+            );
+            assert_eq!(
+                data.len(),
+                server
+                    .writer()
+                    .write(data.as_bytes())
+                    .unwrap()
+            );
+            client_wrote.extend_from_slice(data.as_bytes());
+            server_wrote.extend_from_slice(data.as_bytes());
+
+            // Plaintext only arrives once its sender's handshake is complete.
+            transfer(&mut client, &mut server);
+            let io_state = server.process_new_packets().unwrap();
+            if io_state.plaintext_bytes_to_read() > 0 {
+                assert!(!client.is_handshaking());
+            }
+            transfer(&mut server, &mut client);
+            let io_state = client.process_new_packets().unwrap();
+            if io_state.plaintext_bytes_to_read() > 0 {
+                assert!(!server.is_handshaking());
+            }
+        }
 
-    client.set_buffer_limit(Some(32));
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        check_read(&mut server.reader(), &client_wrote);
+        check_read(&mut client.reader(), &server_wrote);
+    }
+}
 
-    assert_eq!(
-        client
-            .writer()
-            .write_vectored(&[
-                IoSlice::new(b"01234567890123456789"),
-                IoSlice::new(b"01234567890123456789")
-            ])
-            .unwrap(),
-        32
-    );
+#[test]
+fn writes_fail_after_close_notify() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    client.send_close_notify();
+    let err = client
+        .writer()
+        .write(b"hello")
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
 
-    do_handshake(&mut client, &mut server);
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
 
-    check_read(&mut server.reader(), b"01234567890123456789012345678901");
+        client.send_close_notify();
+        let err = client
+            .writer()
+            .write(b"hello")
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        let err = client
+            .writer()
+            .write_vectored(&[IoSlice::new(b"hel"), IoSlice::new(b"lo")])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+        // The peer can still write to us.
+        assert_eq!(5, server.writer().write(b"hello").unwrap());
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        check_read(&mut client.reader(), b"hello");
+    }
 }
 
 #[test]
-fn client_respects_buffer_limit_post_handshake() {
+fn writes_fail_once_connection_has_failed() {
     let (mut client, mut server) = make_pair(KeyType::Rsa);
 
+    // A ClientHello header claiming 65536 bytes, more than the default allows.
+    let record = [0x16u8, 0x03, 0x03, 0x00, 0x04, 0x01, 0x01, 0x00, 0x00];
+    server
+        .read_tls(&mut &record[..])
+        .unwrap();
+    assert!(server.process_new_packets().is_err());
+
+    let err = server
+        .writer()
+        .write(b"hello")
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(5, client.writer().write(b"hello").unwrap());
+}
+
+#[test]
+fn tls_bytes_to_write_tracks_the_send_queue() {
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
     do_handshake(&mut client, &mut server);
-    client.set_buffer_limit(Some(48));
+    assert_eq!(client.tls_bytes_to_write(), 0);
 
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    // header, data, content type and tag
+    let record_len = 5 + 5 + 1 + 16;
+    assert_eq!(client.tls_bytes_to_write(), record_len);
+
+    client
+        .writer()
+        .write_all(b"world")
+        .unwrap();
+    assert_eq!(client.tls_bytes_to_write(), 2 * record_len);
+
+    let mut buf = [0u8; 10];
     assert_eq!(
         client
-            .writer()
-            .write(b"01234567890123456789")
-            .unwrap(),
-        20
-    );
-    assert_eq!(
-        client
-            .writer()
-            .write(b"01234567890123456789")
+            .write_tls(&mut &mut buf[..])
             .unwrap(),
-        6
+        10
     );
+    assert_eq!(client.tls_bytes_to_write(), 2 * record_len - 10);
+    assert!(client.wants_write());
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-
-    check_read(&mut server.reader(), b"01234567890123456789012345");
+    let mut buf = Vec::new();
+    assert_eq!(client.write_tls(&mut buf).unwrap(), 2 * record_len - 10);
+    assert_eq!(client.tls_bytes_to_write(), 0);
+    assert!(!client.wants_write());
 }
 
-struct OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    sess: &'a mut C,
-    pub reads: usize,
-    pub writevs: Vec<Vec<usize>>,
-    fail_ok: bool,
-    pub short_writes: bool,
-    pub last_error: Option<rustls::Error>,
-}
+#[test]
+fn plaintext_bytes_to_read_tracks_the_receive_buffer() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.plaintext_bytes_to_read(), 0);
 
-impl<'a, C, S> OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    fn new(sess: &'a mut C) -> OtherSession<'a, C, S> {
-        OtherSession {
-            sess,
-            reads: 0,
-            writevs: vec![],
-            fail_ok: false,
-            short_writes: false,
-            last_error: None,
-        }
-    }
+    server
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b" world")
+        .unwrap();
+    transfer(&mut server, &mut client);
+    // received, but not yet decrypted
+    assert_eq!(client.plaintext_bytes_to_read(), 0);
 
-    fn new_fails(sess: &'a mut C) -> OtherSession<'a, C, S> {
-        let mut os = OtherSession::new(sess);
-        os.fail_ok = true;
-        os
-    }
-}
+    let io_state = client.process_new_packets().unwrap();
+    assert_eq!(client.plaintext_bytes_to_read(), 11);
+    assert_eq!(io_state.plaintext_bytes_to_read(), 11);
 
-impl<'a, C, S> io::Read for OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    fn read(&mut self, mut b: &mut [u8]) -> io::Result<usize> {
-        self.reads += 1;
-        self.sess.write_tls(b.by_ref())
-    }
+    let mut buf = [0u8; 3];
+    client
+        .reader()
+        .read_exact(&mut buf)
+        .unwrap();
+    assert_eq!(client.plaintext_bytes_to_read(), 8);
+
+    check_read(&mut client.reader(), b"lo world");
+    assert_eq!(client.plaintext_bytes_to_read(), 0);
 }
 
-impl<'a, C, S> io::Write for OtherSession<'a, C, S>
-where
-    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
-    S: SideData,
-{
-    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
-        unreachable!()
-    }
+#[test]
+fn io_state_hint_through_handshake_and_close() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        assert_eq!(client.io_state_hint(), IoStateHint::WantWrite);
+        assert_eq!(server.io_state_hint(), IoStateHint::WantRead);
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
-    }
+        transfer(&mut client, &mut server);
+        assert_eq!(client.io_state_hint(), IoStateHint::WantRead);
+        server.process_new_packets().unwrap();
+        assert_eq!(server.io_state_hint(), IoStateHint::WantWrite);
 
-    fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
-        let mut total = 0;
-        let mut lengths = vec![];
-        for bytes in b {
-            let write_len = if self.short_writes {
-                if bytes.len() > 5 {
-                    bytes.len() / 2
-                } else {
-                    bytes.len()
-                }
-            } else {
-                bytes.len()
-            };
+        do_handshake(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        assert_eq!(client.io_state_hint(), IoStateHint::WantRead);
+        assert_eq!(server.io_state_hint(), IoStateHint::WantRead);
 
-            let l = self
-                .sess
-                .read_tls(&mut io::Cursor::new(&bytes[..write_len]))?;
-            lengths.push(l);
-            total += l;
-            if bytes.len() != l {
-                break;
-            }
-        }
+        server
+            .writer()
+            .write_all(b"hello")
+            .unwrap();
+        assert_eq!(server.io_state_hint(), IoStateHint::WantWrite);
+        transfer(&mut server, &mut client);
+        assert_eq!(server.io_state_hint(), IoStateHint::WantRead);
+        client.process_new_packets().unwrap();
+        assert_eq!(client.io_state_hint(), IoStateHint::Idle);
+        check_read(&mut client.reader(), b"hello");
+        assert_eq!(client.io_state_hint(), IoStateHint::WantRead);
 
-        let rc = self.sess.process_new_packets();
-        if !self.fail_ok {
-            rc.unwrap();
-        } else if rc.is_err() {
-            self.last_error = rc.err();
-        }
+        client.send_close_notify();
+        assert_eq!(client.io_state_hint(), IoStateHint::WantWrite);
+        transfer(&mut client, &mut server);
+        assert_eq!(client.io_state_hint(), IoStateHint::WantRead);
+        server.process_new_packets().unwrap();
+        assert_eq!(server.io_state_hint(), IoStateHint::Closed);
 
-        self.writevs.push(lengths);
-        Ok(total)
+        transfer_eof(&mut client);
+        assert_eq!(client.io_state_hint(), IoStateHint::Closed);
     }
 }
 
 #[test]
-fn server_read_returns_wouldblock_when_no_data() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    assert!(matches!(server.reader().read(&mut [0u8; 1]),
-                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
-}
+fn received_close_notify_after_clean_close() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
 
-#[test]
-fn client_read_returns_wouldblock_when_no_data() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    assert!(matches!(client.reader().read(&mut [0u8; 1]),
-                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+        server.send_close_notify();
+        transfer(&mut server, &mut client);
+        assert!(!client.received_close_notify());
+        client.process_new_packets().unwrap();
+        assert!(client.received_close_notify());
+        assert!(!server.received_close_notify());
+        assert!(matches!(client.reader().read(&mut [0u8; 1]), Ok(0)));
+    }
 }
 
 #[test]
-fn new_server_returns_initial_io_state() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    let io_state = server.process_new_packets().unwrap();
-    println!("IoState is Debug {:?}", io_state);
-    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
-    assert!(!io_state.peer_has_closed());
-    assert_eq!(io_state.tls_bytes_to_write(), 0);
-}
+fn received_close_notify_is_false_after_transport_eof() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
 
-#[test]
-fn new_client_returns_initial_io_state() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    let io_state = client.process_new_packets().unwrap();
-    println!("IoState is Debug {:?}", io_state);
-    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
-    assert!(!io_state.peer_has_closed());
-    assert!(io_state.tls_bytes_to_write() > 200);
+        transfer_eof(&mut client);
+        client.process_new_packets().unwrap();
+        assert!(!client.received_close_notify());
+        assert!(matches!(client.reader().read(&mut [0u8; 1]),
+                         Err(err) if err.kind() == io::ErrorKind::UnexpectedEof));
+    }
 }
 
 #[test]
-fn client_complete_io_for_handshake() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
+fn received_close_notify_is_false_for_unknown_alert_level() {
+    use rustls::internal::msgs::enums::ContentType;
 
-    assert!(client.is_handshaking());
-    let (rdlen, wrlen) = client
-        .complete_io(&mut OtherSession::new(&mut server))
+    let (_, mut server) = make_pair(KeyType::Rsa);
+
+    // A plaintext close_notify alert, with alert level 5.
+    let alert = [0x15u8, 0x03, 0x03, 0x00, 0x02, 0x05, 0x00];
+    server
+        .read_tls(&mut &alert[..])
         .unwrap();
-    assert!(rdlen > 0 && wrlen > 0);
-    assert!(!client.is_handshaking());
+    match server.process_new_packets() {
+        Err(Error::CorruptMessagePayload(c)) => assert_eq!(c.content_type(), ContentType::Alert),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(!server.received_close_notify());
 }
 
 #[test]
-fn client_complete_io_for_handshake_eof() {
+fn send_user_canceled_queues_alerts_in_order() {
     let (mut client, _) = make_pair(KeyType::Rsa);
-    let mut input = io::Cursor::new(Vec::new());
-
-    assert!(client.is_handshaking());
-    let err = client
-        .complete_io(&mut input)
-        .unwrap_err();
-    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
-}
-
-#[test]
-fn client_complete_io_for_write() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
-
-        do_handshake(&mut client, &mut server);
+    client.send_user_canceled();
+    client.send_user_canceled();
 
-        client
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        client
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
-            assert!(rdlen == 0 && wrlen > 0);
-            println!("{:?}", pipe.writevs);
-            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
-        }
-        check_read(
-            &mut server.reader(),
-            b"0123456789012345678901234567890123456789",
-        );
+    let mut buf = Vec::new();
+    while client.wants_write() {
+        client.write_tls(&mut buf).unwrap();
     }
-}
-
-#[test]
-fn client_complete_io_for_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
-
-        do_handshake(&mut client, &mut server);
 
-        server
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
-            assert!(rdlen > 0 && wrlen == 0);
-            assert_eq!(pipe.reads, 1);
-        }
-        check_read(&mut client.reader(), b"01234567890123456789");
+    // Before the handshake, the alerts follow the ClientHello in plaintext.
+    let mut records = Vec::new();
+    let mut rest = &buf[..];
+    while !rest.is_empty() {
+        let len = usize::from(u16::from_be_bytes([rest[3], rest[4]]));
+        records.push((rest[0], rest[5..5 + len].to_vec()));
+        rest = &rest[5 + len..];
     }
+
+    let alerts: Vec<Vec<u8>> = records
+        .into_iter()
+        .filter(|(typ, _)| *typ == 0x15)
+        .map(|(_, body)| body)
+        .collect();
+    // warning(1) user_canceled(90), then warning(1) close_notify(0).
+    assert_eq!(alerts, vec![vec![1, 90], vec![1, 0]]);
 }
 
 #[test]
-fn server_complete_io_for_handshake() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+fn send_user_canceled_closes_connection_cleanly() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
 
-        assert!(server.is_handshaking());
-        let (rdlen, wrlen) = server
-            .complete_io(&mut OtherSession::new(&mut client))
-            .unwrap();
-        assert!(rdlen > 0 && wrlen > 0);
-        assert!(!server.is_handshaking());
+        client.send_user_canceled();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        assert!(server.received_close_notify());
+        assert!(matches!(server.reader().read(&mut [0u8; 1]), Ok(0)));
     }
 }
 
 #[test]
-fn server_complete_io_for_handshake_eof() {
-    let (_, mut server) = make_pair(KeyType::Rsa);
-    let mut input = io::Cursor::new(Vec::new());
+fn send_user_canceled_does_nothing_after_close_notify() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
 
-    assert!(server.is_handshaking());
-    let err = server
-        .complete_io(&mut input)
-        .unwrap_err();
-    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
+    client.send_close_notify();
+    let before = client
+        .process_new_packets()
+        .unwrap()
+        .tls_bytes_to_write();
+    client.send_user_canceled();
+    let after = client
+        .process_new_packets()
+        .unwrap()
+        .tls_bytes_to_write();
+    assert_eq!(before, after);
 }
 
 #[test]
-fn server_complete_io_for_write() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+fn server_closes_uncleanly() {
+    let kt = KeyType::Rsa;
+    let server_config = Arc::new(make_server_config(kt));
 
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
         do_handshake(&mut client, &mut server);
 
-        server
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        server
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
-            assert!(rdlen == 0 && wrlen > 0);
-            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
-        }
-        check_read(
-            &mut client.reader(),
-            b"0123456789012345678901234567890123456789",
+        // check that unclean EOF reporting does not overtake appdata
+        assert_eq!(
+            12,
+            server
+                .writer()
+                .write(b"from-server!")
+                .unwrap()
+        );
+        assert_eq!(
+            12,
+            client
+                .writer()
+                .write(b"from-client!")
+                .unwrap()
         );
+
+        transfer(&mut server, &mut client);
+        transfer_eof(&mut client);
+        let io_state = client.process_new_packets().unwrap();
+        assert!(!io_state.peer_has_closed());
+        check_read(&mut client.reader(), b"from-server!");
+
+        assert!(matches!(client.reader().read(&mut [0u8; 1]),
+                         Err(err) if err.kind() == io::ErrorKind::UnexpectedEof));
+
+        // may still transmit pending frames
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), b"from-client!");
     }
 }
 
 #[test]
-fn server_complete_io_for_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
-
-        do_handshake(&mut client, &mut server);
+fn client_closes_uncleanly() {
+    let kt = KeyType::Rsa;
+    let server_config = Arc::new(make_server_config(kt));
 
-        client
-            .writer()
-            .write_all(b"01234567890123456789")
-            .unwrap();
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
-            assert!(rdlen > 0 && wrlen == 0);
-            assert_eq!(pipe.reads, 1);
-        }
-        check_read(&mut server.reader(), b"01234567890123456789");
-    }
-}
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
 
-#[test]
-fn client_stream_write() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+        // check that unclean EOF reporting does not overtake appdata
+        assert_eq!(
+            12,
+            server
+                .writer()
+                .write(b"from-server!")
+                .unwrap()
+        );
+        assert_eq!(
+            12,
+            client
+                .writer()
+                .write(b"from-client!")
+                .unwrap()
+        );
 
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let mut stream = Stream::new(&mut client, &mut pipe);
-            assert_eq!(stream.write(b"hello").unwrap(), 5);
-        }
-        check_read(&mut server.reader(), b"hello");
+        transfer(&mut client, &mut server);
+        transfer_eof(&mut server);
+        let io_state = server.process_new_packets().unwrap();
+        assert!(!io_state.peer_has_closed());
+        check_read(&mut server.reader(), b"from-client!");
+
+        assert!(matches!(server.reader().read(&mut [0u8; 1]),
+                         Err(err) if err.kind() == io::ErrorKind::UnexpectedEof));
+
+        // may still transmit pending frames
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        check_read(&mut client.reader(), b"from-server!");
     }
 }
 
-#[test]
-fn client_streamowned_write() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (client, mut server) = make_pair(*kt);
+#[derive(Default)]
+struct ServerCheckCertResolve {
+    expected_sni: Option<String>,
+    expected_sigalgs: Option<Vec<SignatureScheme>>,
+    expected_offered_sigalgs: Option<Vec<SignatureScheme>>,
+    expected_alpn: Option<Vec<Vec<u8>>>,
+    expected_cipher_suites: Option<Vec<CipherSuite>>,
+}
 
+impl ResolvesServerCert for ServerCheckCertResolve {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        if client_hello
+            .signature_schemes()
+            .is_empty()
         {
-            let pipe = OtherSession::new(&mut server);
-            let mut stream = StreamOwned::new(client, pipe);
-            assert_eq!(stream.write(b"hello").unwrap(), 5);
+            panic!("no signature schemes shared by client");
         }
-        check_read(&mut server.reader(), b"hello");
-    }
-}
 
-#[test]
-fn client_stream_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+        if client_hello.cipher_suites().is_empty() {
+            panic!("no cipher suites shared by client");
+        }
 
-        server
-            .writer()
-            .write_all(b"world")
-            .unwrap();
+        if let Some(expected_sni) = &self.expected_sni {
+            let sni: &str = client_hello
+                .server_name()
+                .expect("sni unexpectedly absent");
+            assert_eq!(expected_sni, sni);
+        }
 
-        {
-            let mut pipe = OtherSession::new(&mut server);
-            let mut stream = Stream::new(&mut client, &mut pipe);
-            check_read(&mut stream, b"world");
+        if let Some(expected_sigalgs) = &self.expected_sigalgs {
+            assert_eq!(
+                expected_sigalgs,
+                client_hello.signature_schemes(),
+                "unexpected signature schemes"
+            );
         }
-    }
-}
 
-#[test]
-fn client_streamowned_read() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (client, mut server) = make_pair(*kt);
+        if let Some(expected_offered_sigalgs) = &self.expected_offered_sigalgs {
+            assert_eq!(
+                expected_offered_sigalgs,
+                client_hello.offered_signature_schemes(),
+                "unexpected offered signature schemes"
+            );
+        }
 
-        server
-            .writer()
-            .write_all(b"world")
-            .unwrap();
+        if let Some(expected_alpn) = &self.expected_alpn {
+            let alpn = client_hello
+                .alpn()
+                .expect("alpn unexpectedly absent")
+                .collect::<Vec<_>>();
+            assert_eq!(alpn.len(), expected_alpn.len());
 
-        {
-            let pipe = OtherSession::new(&mut server);
-            let mut stream = StreamOwned::new(client, pipe);
-            check_read(&mut stream, b"world");
+            for (got, wanted) in alpn.iter().zip(expected_alpn.iter()) {
+                assert_eq!(got, &wanted.as_slice());
+            }
         }
-    }
-}
-
-#[test]
-fn server_stream_write() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
 
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let mut stream = Stream::new(&mut server, &mut pipe);
-            assert_eq!(stream.write(b"hello").unwrap(), 5);
+        if let Some(expected_cipher_suites) = &self.expected_cipher_suites {
+            assert_eq!(
+                expected_cipher_suites,
+                client_hello.cipher_suites(),
+                "unexpected cipher suites"
+            );
         }
-        check_read(&mut client.reader(), b"hello");
+
+        None
     }
 }
 
 #[test]
-fn server_streamowned_write() {
+fn server_cert_resolve_with_sni() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, server) = make_pair(*kt);
+        let client_config = make_client_config(*kt);
+        let mut server_config = make_server_config(*kt);
 
-        {
-            let pipe = OtherSession::new(&mut client);
-            let mut stream = StreamOwned::new(server, pipe);
-            assert_eq!(stream.write(b"hello").unwrap(), 5);
-        }
-        check_read(&mut client.reader(), b"hello");
+        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+            expected_sni: Some("the-value-from-sni".into()),
+            ..Default::default()
+        });
+
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("the-value-from-sni")).unwrap();
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+
+        let err = do_handshake_until_error(&mut client, &mut server);
+        assert!(err.is_err());
     }
 }
 
 #[test]
-fn server_stream_read() {
+fn server_cert_resolve_with_alpn() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, mut server) = make_pair(*kt);
+        let mut client_config = make_client_config(*kt);
+        client_config.alpn_protocols = vec!["foo".into(), "bar".into()];
 
-        client
-            .writer()
-            .write_all(b"world")
-            .unwrap();
+        let mut server_config = make_server_config(*kt);
+        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+            expected_alpn: Some(vec![b"foo".to_vec(), b"bar".to_vec()]),
+            ..Default::default()
+        });
 
-        {
-            let mut pipe = OtherSession::new(&mut client);
-            let mut stream = Stream::new(&mut server, &mut pipe);
-            check_read(&mut stream, b"world");
-        }
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("sni-value")).unwrap();
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+
+        let err = do_handshake_until_error(&mut client, &mut server);
+        assert!(err.is_err());
     }
 }
 
 #[test]
-fn server_streamowned_read() {
+fn client_trims_terminating_dot() {
     for kt in ALL_KEY_TYPES.iter() {
-        let (mut client, server) = make_pair(*kt);
-
-        client
-            .writer()
-            .write_all(b"world")
-            .unwrap();
+        let client_config = make_client_config(*kt);
+        let mut server_config = make_server_config(*kt);
 
-        {
-            let pipe = OtherSession::new(&mut client);
-            let mut stream = StreamOwned::new(server, pipe);
-            check_read(&mut stream, b"world");
-        }
-    }
-}
+        server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+            expected_sni: Some("some-host.com".into()),
+            ..Default::default()
+        });
 
-struct FailsWrites {
-    errkind: io::ErrorKind,
-    after: usize,
-}
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("some-host.com.")).unwrap();
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
 
-impl io::Read for FailsWrites {
-    fn read(&mut self, _b: &mut [u8]) -> io::Result<usize> {
-        Ok(0)
+        let err = do_handshake_until_error(&mut client, &mut server);
+        assert!(err.is_err());
     }
 }
 
-impl io::Write for FailsWrites {
-    fn write(&mut self, b: &[u8]) -> io::Result<usize> {
-        if self.after > 0 {
-            self.after -= 1;
-            Ok(b.len())
-        } else {
-            Err(io::Error::new(self.errkind, "oops"))
-        }
-    }
+#[cfg(feature = "tls12")]
+fn check_sigalgs_reduced_by_ciphersuite(
+    kt: KeyType,
+    suite: CipherSuite,
+    expected_sigalgs: Vec<SignatureScheme>,
+) {
+    let client_config = finish_client_config(
+        kt,
+        ClientConfig::builder()
+            .with_cipher_suites(&[find_suite(suite)])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
 
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
-    }
-}
+    let mut server_config = make_server_config(kt);
 
-#[test]
-fn stream_write_reports_underlying_io_error_before_plaintext_processed() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-    do_handshake(&mut client, &mut server);
+    // The client offers every scheme its verifier supports: only the
+    // reduced list shows up in `signature_schemes()`.
+    server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+        expected_sigalgs: Some(expected_sigalgs),
+        expected_offered_sigalgs: Some(ALL_SIGNATURE_SCHEMES.to_vec()),
+        expected_cipher_suites: Some(vec![suite, CipherSuite::TLS_EMPTY_RENEGOTIATION_INFO_SCSV]),
+        ..Default::default()
+    });
 
-    let mut pipe = FailsWrites {
-        errkind: io::ErrorKind::ConnectionAborted,
-        after: 0,
-    };
-    client
-        .writer()
-        .write_all(b"hello")
-        .unwrap();
-    let mut client_stream = Stream::new(&mut client, &mut pipe);
-    let rc = client_stream.write(b"world");
-    assert!(rc.is_err());
-    let err = rc.err().unwrap();
-    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    let mut client = ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
+    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+
+    let err = do_handshake_until_error(&mut client, &mut server);
+    assert!(err.is_err());
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn stream_write_swallows_underlying_io_error_after_plaintext_processed() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
-    do_handshake(&mut client, &mut server);
-
-    let mut pipe = FailsWrites {
-        errkind: io::ErrorKind::ConnectionAborted,
-        after: 1,
-    };
-    client
-        .writer()
-        .write_all(b"hello")
-        .unwrap();
-    let mut client_stream = Stream::new(&mut client, &mut pipe);
-    let rc = client_stream.write(b"world");
-    assert_eq!(format!("{:?}", rc), "Ok(5)");
+fn server_cert_resolve_reduces_sigalgs_for_rsa_ciphersuite() {
+    check_sigalgs_reduced_by_ciphersuite(
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        vec![
+            SignatureScheme::RSA_PSS_SHA512,
+            SignatureScheme::RSA_PSS_SHA384,
+            SignatureScheme::RSA_PSS_SHA256,
+            SignatureScheme::RSA_PKCS1_SHA512,
+            SignatureScheme::RSA_PKCS1_SHA384,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ],
+    );
 }
 
-fn make_disjoint_suite_configs() -> (ClientConfig, ServerConfig) {
-    let kt = KeyType::Rsa;
-    let server_config = finish_server_config(
-        kt,
-        ServerConfig::builder()
-            .with_cipher_suites(&[rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256])
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap(),
+#[cfg(feature = "tls12")]
+#[test]
+fn server_cert_resolve_reduces_sigalgs_for_ecdsa_ciphersuite() {
+    check_sigalgs_reduced_by_ciphersuite(
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+        vec![
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ED25519,
+        ],
     );
+}
 
-    let client_config = finish_client_config(
-        kt,
-        ClientConfig::builder()
-            .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384])
-            .with_safe_default_kx_groups()
-            .with_safe_default_protocol_versions()
-            .unwrap(),
-    );
+struct ServerCheckNoSNI {}
 
-    (client_config, server_config)
+impl ResolvesServerCert for ServerCheckNoSNI {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        assert!(client_hello.server_name().is_none());
+
+        None
+    }
 }
 
 #[test]
-fn client_stream_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+fn client_with_sni_disabled_does_not_send_sni() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut server_config = make_server_config(*kt);
+        server_config.cert_resolver = Arc::new(ServerCheckNoSNI {});
+        let server_config = Arc::new(server_config);
 
-    {
-        let mut pipe = OtherSession::new_fails(&mut server);
-        let mut client_stream = Stream::new(&mut client, &mut pipe);
-        let rc = client_stream.write(b"hello");
-        assert!(rc.is_err());
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.enable_sni = false;
+
+            let mut client =
+                ClientConnection::new(Arc::new(client_config), dns_name("value-not-sent")).unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert!(err.is_err());
+        }
+    }
+}
+
+struct ServerCheckRawClientHello {
+    expected: Vec<u8>,
+}
+
+impl ResolvesServerCert for ServerCheckRawClientHello {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        use rustls::internal::msgs::codec::{Codec, Reader};
+        use rustls::internal::msgs::handshake::{
+            ConvertProtocolNameList, ConvertServerNameList, HandshakeMessagePayload,
+        };
+
+        let raw = client_hello.raw();
+        assert_eq!(raw, self.expected);
+
+        let mut rd = Reader::init(&raw);
+        let hs = HandshakeMessagePayload::read(&mut rd).unwrap();
+        assert!(!rd.any_left());
+        let hello = match hs.payload {
+            HandshakePayload::ClientHello(hello) => hello,
+            _ => panic!("raw() is not a ClientHello"),
+        };
+
+        assert_eq!(hello.cipher_suites, client_hello.cipher_suites());
         assert_eq!(
-            format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+            hello
+                .get_sni_extension()
+                .and_then(|sni| sni.get_single_hostname())
+                .map(|name| <&str>::from(name).to_owned()),
+            client_hello
+                .server_name()
+                .map(str::to_owned)
         );
-        let rc = client_stream.write(b"hello");
-        assert!(rc.is_err());
         assert_eq!(
-            format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+            hello
+                .get_sigalgs_extension()
+                .unwrap()
+                .as_slice(),
+            client_hello.offered_signature_schemes()
+        );
+        assert_eq!(
+            hello
+                .get_alpn_extension()
+                .map(|protocols| protocols.to_slices()),
+            client_hello
+                .alpn()
+                .map(|protocols| protocols.collect::<Vec<_>>())
         );
+
+        None
     }
 }
 
 #[test]
-fn client_streamowned_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (client, mut server) = make_pair_for_configs(client_config, server_config);
+fn server_cert_resolver_sees_raw_client_hello() {
+    use rustls::internal::msgs::codec::Reader;
+    use rustls::internal::msgs::message::OpaqueMessage;
 
-    let pipe = OtherSession::new_fails(&mut server);
-    let mut client_stream = StreamOwned::new(client, pipe);
-    let rc = client_stream.write(b"hello");
-    assert!(rc.is_err());
-    assert_eq!(
-        format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
-    );
-    let rc = client_stream.write(b"hello");
-    assert!(rc.is_err());
-    assert_eq!(
-        format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
-    );
-}
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
 
-#[test]
-fn server_stream_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        let mut buf = Vec::new();
+        client.write_tls(&mut buf).unwrap();
+        let record = OpaqueMessage::read(&mut Reader::init(&buf)).unwrap();
 
-    client
-        .writer()
-        .write_all(b"world")
-        .unwrap();
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.cert_resolver = Arc::new(ServerCheckRawClientHello {
+            expected: record.payload.0,
+        });
+        let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
 
-    {
-        let mut pipe = OtherSession::new_fails(&mut client);
-        let mut server_stream = Stream::new(&mut server, &mut pipe);
-        let mut bytes = [0u8; 5];
-        let rc = server_stream.read(&mut bytes);
-        assert!(rc.is_err());
+        server
+            .read_tls(&mut buf.as_slice())
+            .unwrap();
         assert_eq!(
-            format!("{:?}", rc),
-            "Err(Custom { kind: InvalidData, error: PeerIncompatibleError(\"no ciphersuites in common\") })"
+            server.process_new_packets().err(),
+            Some(Error::General(
+                "no server certificate chain resolved".into()
+            ))
         );
     }
 }
 
 #[test]
-fn server_streamowned_handshake_error() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, server) = make_pair_for_configs(client_config, server_config);
+fn client_checks_server_certificate_with_given_name() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config(*kt));
 
-    client
-        .writer()
-        .write_all(b"world")
-        .unwrap();
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let mut client = ClientConnection::new(
+                Arc::new(client_config),
+                dns_name("not-the-right-hostname.com"),
+            )
+            .unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
 
-    let pipe = OtherSession::new_fails(&mut client);
-    let mut server_stream = StreamOwned::new(server, pipe);
-    let mut bytes = [0u8; 5];
-    let rc = server_stream.read(&mut bytes);
-    assert!(rc.is_err());
-    assert_eq!(
-        format!("{:?}", rc),
-        "Err(Custom { kind: InvalidData, error: PeerIncompatibleError(\"no ciphersuites in common\") })"
-    );
+            let err = do_handshake_until_error(&mut client, &mut server);
+            assert_eq!(
+                err,
+                Err(ErrorFromPeer::Client(Error::InvalidCertificate(
+                    CertificateError::NotValidForName {
+                        name: "not-the-right-hostname.com".into(),
+                    }
+                )))
+            );
+        }
+    }
 }
 
-#[test]
-fn server_config_is_clone() {
-    let _ = make_server_config(KeyType::Rsa);
+struct ClientCheckCertResolve {
+    query_count: AtomicUsize,
+    expect_queries: usize,
 }
 
-#[test]
-fn client_config_is_clone() {
-    let _ = make_client_config(KeyType::Rsa);
+impl ClientCheckCertResolve {
+    fn new(expect_queries: usize) -> Self {
+        ClientCheckCertResolve {
+            query_count: AtomicUsize::new(0),
+            expect_queries,
+        }
+    }
 }
 
-#[test]
-fn client_connection_is_debug() {
-    let (client, _) = make_pair(KeyType::Rsa);
-    println!("{:?}", client);
+impl Drop for ClientCheckCertResolve {
+    fn drop(&mut self) {
+        if !std::thread::panicking() {
+            let count = self.query_count.load(Ordering::SeqCst);
+            assert_eq!(count, self.expect_queries);
+        }
+    }
 }
 
-#[test]
-fn server_connection_is_debug() {
-    let (_, server) = make_pair(KeyType::Rsa);
-    println!("{:?}", server);
-}
+impl ResolvesClientCert for ClientCheckCertResolve {
+    fn resolve(
+        &self,
+        acceptable_issuers: &[&[u8]],
+        sigschemes: &[SignatureScheme],
+    ) -> Option<Arc<sign::CertifiedKey>> {
+        self.query_count
+            .fetch_add(1, Ordering::SeqCst);
 
-#[test]
-fn server_complete_io_for_handshake_ending_with_alert() {
-    let (client_config, server_config) = make_disjoint_suite_configs();
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        if acceptable_issuers.is_empty() {
+            panic!("no issuers offered by server");
+        }
 
-    assert!(server.is_handshaking());
+        if sigschemes.is_empty() {
+            panic!("no signature schemes shared by server");
+        }
 
-    let mut pipe = OtherSession::new_fails(&mut client);
-    let rc = server.complete_io(&mut pipe);
-    assert!(rc.is_err(), "server io failed due to handshake failure");
-    assert!(!server.wants_write(), "but server did send its alert");
-    assert_eq!(
-        format!("{:?}", pipe.last_error),
-        "Some(AlertReceived(HandshakeFailure))",
-        "which was received by client"
-    );
+        None
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
 }
 
 #[test]
-fn server_exposes_offered_sni() {
-    let kt = KeyType::Rsa;
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("second.testserver.com"))
-                .unwrap();
-        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
+fn client_cert_resolve() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
 
-        assert_eq!(None, server.sni_hostname());
-        do_handshake(&mut client, &mut server);
-        assert_eq!(Some("second.testserver.com"), server.sni_hostname());
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.client_auth_cert_resolver = Arc::new(ClientCheckCertResolve::new(1));
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+
+            assert_eq!(
+                do_handshake_until_error(&mut client, &mut server),
+                Err(ErrorFromPeer::Server(Error::NoCertificatesPresented))
+            );
+        }
     }
 }
 
 #[test]
-fn server_exposes_offered_sni_smashed_to_lowercase() {
-    // webpki actually does this for us in its DnsName type
-    let kt = KeyType::Rsa;
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("SECOND.TESTServer.com"))
-                .unwrap();
-        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
+fn client_auth_works() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
 
-        assert_eq!(None, server.sni_hostname());
-        do_handshake(&mut client, &mut server);
-        assert_eq!(Some("second.testserver.com"), server.sni_hostname());
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions_with_auth(*kt, &[version]);
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+        }
     }
 }
 
+fn make_client_config_with_cert_on_request<F>(
+    kt: KeyType,
+    version: &'static rustls::SupportedProtocolVersion,
+    resolve: F,
+) -> ClientConfig
+where
+    F: Fn(&[&[u8]], &[SignatureScheme]) -> Option<Arc<sign::CertifiedKey>> + Send + Sync + 'static,
+{
+    ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[version])
+        .unwrap()
+        .with_root_certificates(get_client_root_store(kt))
+        .with_client_cert_on_request(resolve)
+}
+
 #[test]
-fn server_exposes_offered_sni_even_if_resolver_fails() {
-    let kt = KeyType::Rsa;
-    let resolver = rustls::server::ResolvesServerCertUsingSni::new();
+fn client_cert_on_request_is_resolved_when_requested() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
 
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
+        for version in rustls::ALL_VERSIONS {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let resolver_calls = Arc::clone(&calls);
+            let client_kt = *kt;
+            let client_config = make_client_config_with_cert_on_request(
+                *kt,
+                version,
+                move |acceptable_issuers, sigschemes| {
+                    resolver_calls.fetch_add(1, Ordering::SeqCst);
+                    assert!(!acceptable_issuers.is_empty());
+                    assert!(!sigschemes.is_empty());
+                    Some(client_kt.get_certified_client_key())
+                },
+            );
 
-    for version in rustls::ALL_VERSIONS {
-        let client_config = make_client_config_with_versions(kt, &[version]);
-        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("thisdoesNOTexist.com"))
-                .unwrap();
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
 
-        assert_eq!(None, server.sni_hostname());
-        transfer(&mut client, &mut server);
-        assert_eq!(
-            server.process_new_packets(),
-            Err(Error::General(
-                "no server certificate chain resolved".to_string()
-            ))
-        );
-        assert_eq!(Some("thisdoesnotexist.com"), server.sni_hostname());
+            assert_eq!(calls.load(Ordering::SeqCst), 1);
+            assert_eq!(server.peer_certificates(), Some(&kt.get_client_chain()[..]));
+        }
     }
 }
 
 #[test]
-fn sni_resolver_works() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
-    resolver
-        .add(
-            "localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone()),
-        )
-        .unwrap();
+fn client_cert_on_request_is_not_resolved_unless_requested() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config(*kt));
 
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
+        for version in rustls::ALL_VERSIONS {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let resolver_calls = Arc::clone(&calls);
+            let client_config =
+                make_client_config_with_cert_on_request(*kt, version, move |_, _| {
+                    resolver_calls.fetch_add(1, Ordering::SeqCst);
+                    None
+                });
 
-    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client1 =
-        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("localhost")).unwrap();
-    let err = do_handshake_until_error(&mut client1, &mut server1);
-    assert_eq!(err, Ok(()));
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
 
-    let mut server2 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client2 =
-        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("notlocalhost")).unwrap();
-    let err = do_handshake_until_error(&mut client2, &mut server2);
-    assert_eq!(
-        err,
-        Err(ErrorFromPeer::Server(Error::General(
-            "no server certificate chain resolved".into()
-        )))
-    );
+            assert_eq!(calls.load(Ordering::SeqCst), 0);
+            assert_eq!(server.peer_certificates(), None);
+        }
+    }
 }
 
 #[test]
-fn sni_resolver_rejects_wrong_names() {
+fn client_cert_on_request_declined_sends_empty_certificate() {
     let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+    let optional = Arc::new(make_server_config_with_optional_client_auth(kt));
+    let mandatory = Arc::new(make_server_config_with_mandatory_client_auth(kt));
 
-    assert_eq!(
-        Ok(()),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
-    );
-    assert_eq!(
-        Err(Error::General(
-            "The server certificate is not valid for the given name".into()
-        )),
-        resolver.add(
-            "not-localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
-    );
-    assert_eq!(
-        Err(Error::General("Bad DNS name".into())),
-        resolver.add(
-            "not ascii 🦀",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
-    );
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_cert_on_request(
+            kt,
+            version,
+            |_, _| None,
+        ));
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &optional);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(server.peer_certificates(), None);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &mandatory);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Server(Error::NoCertificatesPresented))
+        );
+    }
 }
 
 #[test]
-fn sni_resolver_lower_cases_configured_names() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+fn client_cert_on_request_chooses_by_acceptable_issuers() {
+    fn issued_by_acceptable(kt: KeyType, acceptable_issuers: &[&[u8]]) -> bool {
+        get_client_root_store(kt)
+            .subjects()
+            .iter()
+            .any(|subject| acceptable_issuers.contains(&subject.0.as_slice()))
+    }
 
-    assert_eq!(
-        Ok(()),
-        resolver.add(
-            "LOCALHOST",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
-    );
+    for server_kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*server_kt));
 
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
+        for version in rustls::ALL_VERSIONS {
+            let client_config =
+                make_client_config_with_cert_on_request(*server_kt, version, |issuers, _| {
+                    ALL_KEY_TYPES
+                        .iter()
+                        .find(|kt| issued_by_acceptable(**kt, issuers))
+                        .map(|kt| kt.get_certified_client_key())
+                });
 
-    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client1 =
-        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("localhost")).unwrap();
-    let err = do_handshake_until_error(&mut client1, &mut server1);
-    assert_eq!(err, Ok(()));
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+
+            assert_eq!(
+                server.peer_certificates(),
+                Some(&server_kt.get_client_chain()[..])
+            );
+        }
+    }
 }
 
-#[test]
-fn sni_resolver_lower_cases_queried_names() {
-    // actually, the handshake parser does this, but the effect is the same.
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+/// Verifies client certificates with `inner`, but advertises `subjects`
+/// as the acceptable CAs.
+struct AdvertisesClientAuthRoots {
+    inner: Arc<dyn rustls::server::ClientCertVerifier>,
+    subjects: rustls::DistinguishedNames,
+}
 
-    assert_eq!(
-        Ok(()),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
-        )
-    );
+impl rustls::server::ClientCertVerifier for AdvertisesClientAuthRoots {
+    fn client_auth_root_subjects(&self) -> Option<rustls::DistinguishedNames> {
+        Some(self.subjects.clone())
+    }
 
-    let mut server_config = make_server_config(kt);
-    server_config.cert_resolver = Arc::new(resolver);
-    let server_config = Arc::new(server_config);
+    fn verify_client_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        intermediates: &[rustls::Certificate],
+        now: std::time::SystemTime,
+    ) -> Result<rustls::server::ClientCertVerified, Error> {
+        self.inner
+            .verify_client_cert(end_entity, intermediates, now)
+    }
+}
 
-    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
-    let mut client1 =
-        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("LOCALHOST")).unwrap();
-    let err = do_handshake_until_error(&mut client1, &mut server1);
-    assert_eq!(err, Ok(()));
+fn make_server_config_advertising_client_auth_roots(
+    kt: KeyType,
+    client_kts: &[KeyType],
+    subjects: rustls::DistinguishedNames,
+) -> ServerConfig {
+    let mut roots = rustls::RootCertStore::empty();
+    for client_kt in client_kts {
+        for cert in client_kt.get_chain() {
+            roots.add(&cert).unwrap();
+        }
+    }
+
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(Arc::new(AdvertisesClientAuthRoots {
+            inner: rustls::server::AllowAnyAuthenticatedClient::new(roots),
+            subjects,
+        }))
+        .with_single_cert(kt.get_chain(), kt.get_key())
+        .unwrap()
 }
 
 #[test]
-fn sni_resolver_rejects_bad_certs() {
-    let kt = KeyType::Rsa;
-    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
-    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
-    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+fn client_cert_resolver_sees_advertised_ca_names() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_config = Arc::new(make_server_config_with_mandatory_client_auth(*kt));
+        let expected = get_client_root_store(*kt)
+            .subjects()
+            .iter()
+            .map(|subject| subject.0.clone())
+            .collect::<Vec<_>>();
 
-    assert_eq!(
-        Err(Error::General(
-            "No end-entity certificate in certificate chain".into()
-        )),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(vec![], signing_key.clone())
-        )
-    );
+        for version in rustls::ALL_VERSIONS {
+            let seen = Arc::new(Mutex::new(Vec::new()));
+            let resolver_seen = Arc::clone(&seen);
+            let client_kt = *kt;
+            let client_config = make_client_config_with_cert_on_request(
+                *kt,
+                version,
+                move |acceptable_issuers, _| {
+                    *resolver_seen.lock().unwrap() = acceptable_issuers
+                        .iter()
+                        .map(|name| name.to_vec())
+                        .collect();
+                    Some(client_kt.get_certified_client_key())
+                },
+            );
 
-    let bad_chain = vec![rustls::Certificate(vec![0xa0])];
-    assert_eq!(
-        Err(Error::General(
-            "End-entity certificate in certificate chain is syntactically invalid".into()
-        )),
-        resolver.add(
-            "localhost",
-            sign::CertifiedKey::new(bad_chain, signing_key.clone())
-        )
-    );
-}
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
 
-fn do_exporter_test(client_config: ClientConfig, server_config: ServerConfig) {
-    let mut client_secret = [0u8; 64];
-    let mut server_secret = [0u8; 64];
+            assert_eq!(*seen.lock().unwrap(), expected);
+        }
+    }
+}
 
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+#[test]
+fn client_cert_resolver_selects_advertised_ca() {
+    let server_config = Arc::new(make_server_config_advertising_client_auth_roots(
+        KeyType::Rsa,
+        &ALL_KEY_TYPES,
+        get_client_root_store(KeyType::Ecdsa).subjects(),
+    ));
 
-    assert_eq!(
-        Err(Error::HandshakeNotComplete),
-        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
-    );
-    assert_eq!(
-        Err(Error::HandshakeNotComplete),
-        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
-    );
-    do_handshake(&mut client, &mut server);
+    for version in rustls::ALL_VERSIONS {
+        let client_config =
+            make_client_config_with_cert_on_request(KeyType::Rsa, version, |issuers, _| {
+                ALL_KEY_TYPES
+                    .iter()
+                    .find(|kt| {
+                        get_client_root_store(**kt)
+                            .subjects()
+                            .iter()
+                            .any(|name| issuers.contains(&name.0.as_slice()))
+                    })
+                    .map(|kt| kt.get_certified_client_key())
+            });
 
-    assert_eq!(
-        Ok(()),
-        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
-    );
-    assert_eq!(
-        Ok(()),
-        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
-    );
-    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
 
-    assert_eq!(
-        Ok(()),
-        client.export_keying_material(&mut client_secret, b"label", None)
-    );
-    assert_ne!(client_secret.to_vec(), server_secret.to_vec());
-    assert_eq!(
-        Ok(()),
-        server.export_keying_material(&mut server_secret, b"label", None)
-    );
-    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+        assert_eq!(
+            server.peer_certificates(),
+            Some(&KeyType::Ecdsa.get_client_chain()[..])
+        );
+    }
 }
 
-#[cfg(feature = "tls12")]
 #[test]
-fn test_tls12_exporter() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS12]);
-        let server_config = make_server_config(*kt);
+fn client_cert_resolver_sees_no_ca_names_when_none_advertised() {
+    let kt = KeyType::Rsa;
+    let server_config = Arc::new(make_server_config_advertising_client_auth_roots(
+        kt,
+        &[kt],
+        rustls::DistinguishedNames::new(),
+    ));
 
-        do_exporter_test(client_config, server_config);
-    }
-}
+    for version in rustls::ALL_VERSIONS {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let resolver_calls = Arc::clone(&calls);
+        let client_config =
+            make_client_config_with_cert_on_request(kt, version, move |acceptable_issuers, _| {
+                resolver_calls.fetch_add(1, Ordering::SeqCst);
+                assert!(acceptable_issuers.is_empty());
+                Some(kt.get_certified_client_key())
+            });
 
-#[test]
-fn test_tls13_exporter() {
-    for kt in ALL_KEY_TYPES.iter() {
-        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
-        let server_config = make_server_config(*kt);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
 
-        do_exporter_test(client_config, server_config);
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(server.peer_certificates(), Some(&kt.get_client_chain()[..]));
     }
 }
 
-fn do_suite_test(
-    client_config: ClientConfig,
-    server_config: ServerConfig,
-    expect_suite: SupportedCipherSuite,
-    expect_version: ProtocolVersion,
-) {
-    println!(
-        "do_suite_test {:?} {:?}",
-        expect_version,
-        expect_suite.suite()
+/// 1.3.6.1.4.1.99999.1, which the `*-purpose` test certificates assert.
+const TEST_KEY_PURPOSE: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x86, 0x8d, 0x1f, 0x01];
+
+fn make_server_config_requiring_client_key_purpose(purpose: &[u8]) -> ServerConfig {
+    let client_auth = AllowAnyAuthenticatedClient::new_with_required_key_purposes(
+        get_client_root_store(KeyType::Rsa),
+        vec![purpose.to_vec()],
     );
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-    assert_eq!(None, client.negotiated_cipher_suite());
-    assert_eq!(None, server.negotiated_cipher_suite());
-    assert_eq!(None, client.protocol_version());
-    assert_eq!(None, server.protocol_version());
-    assert!(client.is_handshaking());
-    assert!(server.is_handshaking());
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_auth)
+        .with_single_cert(KeyType::Rsa.get_chain(), KeyType::Rsa.get_key())
+        .unwrap()
+}
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
+#[test]
+fn client_cert_with_required_key_purpose_is_accepted() {
+    let chain = rustls_pemfile::certs(&mut io::BufReader::new(bytes_for(
+        "rsa",
+        "client-purpose.fullchain",
+    )))
+    .unwrap()
+    .into_iter()
+    .map(rustls::Certificate)
+    .collect::<Vec<_>>();
+    let certified_key = Arc::new(sign::CertifiedKey::new(
+        chain.clone(),
+        KeyType::Rsa
+            .get_certified_client_key()
+            .key
+            .clone(),
+    ));
+    let server_config = Arc::new(make_server_config_requiring_client_key_purpose(
+        TEST_KEY_PURPOSE,
+    ));
 
-    assert!(client.is_handshaking());
-    assert!(server.is_handshaking());
-    assert_eq!(None, client.protocol_version());
-    assert_eq!(Some(expect_version), server.protocol_version());
-    assert_eq!(None, client.negotiated_cipher_suite());
-    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+    for version in rustls::ALL_VERSIONS {
+        let certified_key = Arc::clone(&certified_key);
+        let client_config =
+            make_client_config_with_cert_on_request(KeyType::Rsa, version, move |_, _| {
+                Some(Arc::clone(&certified_key))
+            });
 
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(server.peer_certificates(), Some(&chain[..]));
+    }
+}
 
-    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
-    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+#[test]
+fn client_cert_without_required_key_purpose_is_rejected() {
+    let server_config = Arc::new(make_server_config_requiring_client_key_purpose(
+        TEST_KEY_PURPOSE,
+    ));
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions_with_auth(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        assert_eq!(
+            do_handshake_until_error(&mut client, &mut server),
+            Err(ErrorFromPeer::Server(Error::InvalidCertificate(
+                CertificateError::MissingKeyPurpose {
+                    purpose: TEST_KEY_PURPOSE.to_vec(),
+                }
+            )))
+        );
+    }
+}
 
-    assert!(!client.is_handshaking());
-    assert!(!server.is_handshaking());
-    assert_eq!(Some(expect_version), client.protocol_version());
-    assert_eq!(Some(expect_version), server.protocol_version());
-    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
-    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+/// The ECDSA end-entity certificate which may issue delegated
+/// credentials, with its key, and a credential for the key in
+/// ecdsa/delegated.key which expires at `expiry`.  The credential is
+/// signed by `issuer` if given, or else the certificate's key.
+fn certified_key_with_delegated_credential(
+    issuer: Option<&dyn sign::SigningKey>,
+    expiry: std::time::SystemTime,
+) -> sign::CertifiedKey {
+    let chain = rustls_pemfile::certs(&mut bytes_for("ecdsa", "end-dc.fullchain"))
+        .unwrap()
+        .into_iter()
+        .map(rustls::Certificate)
+        .collect::<Vec<_>>();
+    let key = sign::any_supported_type(&KeyType::Ecdsa.get_key()).unwrap();
+    let delegated_key = rustls::PrivateKey(
+        rustls_pemfile::pkcs8_private_keys(&mut bytes_for("ecdsa", "delegated.key")).unwrap()[0]
+            .clone(),
+    );
+
+    let credential = sign::DelegatedCredential::issue(
+        &chain[0],
+        issuer.unwrap_or(&*key),
+        bytes_for("ecdsa", "delegated.spki"),
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        expiry,
+    )
+    .unwrap();
+    let mut certified_key = sign::CertifiedKey::new(chain, key);
+    certified_key.delegated_credential = Some(
+        sign::DelegatedCredential::new(
+            &credential,
+            sign::any_supported_type(&delegated_key).unwrap(),
+        )
+        .unwrap(),
+    );
+    certified_key
 }
 
-fn find_suite(suite: CipherSuite) -> SupportedCipherSuite {
-    for scs in ALL_CIPHER_SUITES.iter().copied() {
-        if scs.suite() == suite {
-            return scs;
-        }
-    }
+fn make_server_config_with_certified_key(certified_key: sign::CertifiedKey) -> ServerConfig {
+    let mut server_config = make_server_config(KeyType::Ecdsa);
+    server_config.cert_resolver = Arc::new(ResolvesByClientPreference {
+        keys: vec![Arc::new(certified_key)],
+    });
+    server_config
+}
 
-    panic!("find_suite given unsupported suite");
+fn make_server_config_with_delegated_credential() -> ServerConfig {
+    let expiry = std::time::SystemTime::now() + Duration::from_secs(24 * 60 * 60);
+    make_server_config_with_certified_key(certified_key_with_delegated_credential(None, expiry))
 }
 
-static TEST_CIPHERSUITES: &[(&rustls::SupportedProtocolVersion, KeyType, CipherSuite)] = &[
-    (
-        &rustls::version::TLS13,
-        KeyType::Rsa,
-        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
-    ),
-    (
-        &rustls::version::TLS13,
-        KeyType::Rsa,
-        CipherSuite::TLS13_AES_256_GCM_SHA384,
-    ),
-    (
-        &rustls::version::TLS13,
-        KeyType::Rsa,
-        CipherSuite::TLS13_AES_128_GCM_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Ecdsa,
-        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
-    ),
-    #[cfg(feature = "tls12")]
-    (
-        &rustls::version::TLS12,
-        KeyType::Rsa,
-        CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
-    ),
-];
+fn make_client_config_accepting_delegated_credentials() -> ClientConfig {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Ecdsa, &[&rustls::version::TLS13]);
+    client_config.enable_delegated_credentials = true;
+    client_config
+}
 
 #[test]
-fn negotiated_ciphersuite_default() {
-    for kt in ALL_KEY_TYPES.iter() {
-        do_suite_test(
-            make_client_config(*kt),
-            make_server_config(*kt),
-            find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384),
-            ProtocolVersion::TLSv1_3,
-        );
+fn server_authenticates_with_certificate_key_without_delegated_credential_support() {
+    let server_config = Arc::new(make_server_config_with_delegated_credential());
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Ecdsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake(&mut client, &mut server);
     }
 }
 
 #[test]
-fn all_suites_covered() {
-    assert_eq!(ALL_CIPHER_SUITES.len(), TEST_CIPHERSUITES.len());
+fn client_accepts_valid_delegated_credential() {
+    let (mut client, mut server) = make_pair_for_configs(
+        make_client_config_accepting_delegated_credentials(),
+        make_server_config_with_delegated_credential(),
+    );
+    do_handshake(&mut client, &mut server);
 }
 
 #[test]
-fn negotiated_ciphersuite_client() {
-    for item in TEST_CIPHERSUITES.iter() {
-        let (version, kt, suite) = *item;
-        let scs = find_suite(suite);
-        let client_config = finish_client_config(
-            kt,
-            ClientConfig::builder()
-                .with_cipher_suites(&[scs])
-                .with_safe_default_kx_groups()
-                .with_protocol_versions(&[version])
-                .unwrap(),
-        );
+fn client_rejects_delegated_credential_signed_by_other_key() {
+    let expiry = std::time::SystemTime::now() + Duration::from_secs(24 * 60 * 60);
+    let other_key = KeyType::Ecdsa
+        .get_certified_client_key()
+        .key
+        .clone();
+    let (mut client, mut server) = make_pair_for_configs(
+        make_client_config_accepting_delegated_credentials(),
+        make_server_config_with_certified_key(certified_key_with_delegated_credential(
+            Some(&*other_key),
+            expiry,
+        )),
+    );
 
-        do_suite_test(client_config, make_server_config(kt), scs, version.version);
-    }
+    assert_eq!(
+        do_handshake_until_both_error(&mut client, &mut server),
+        Err(vec![
+            ErrorFromPeer::Client(Error::InvalidCertificate(
+                CertificateError::BadDelegatedCredential
+            )),
+            ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::IllegalParameter)),
+        ])
+    );
 }
 
-#[test]
-fn negotiated_ciphersuite_server() {
-    for item in TEST_CIPHERSUITES.iter() {
-        let (version, kt, suite) = *item;
-        let scs = find_suite(suite);
-        let server_config = finish_server_config(
-            kt,
-            ServerConfig::builder()
-                .with_cipher_suites(&[scs])
-                .with_safe_default_kx_groups()
-                .with_protocol_versions(&[version])
-                .unwrap(),
-        );
+/// A signing key which counts the signatures it makes.
+struct CountingSigningKey {
+    key: Arc<dyn sign::SigningKey>,
+    signatures: Arc<AtomicUsize>,
+}
 
-        do_suite_test(make_client_config(kt), server_config, scs, version.version);
+impl sign::SigningKey for CountingSigningKey {
+    fn choose_scheme(
+        &self,
+        offered: &[SignatureScheme],
+        rng: Box<dyn ring::rand::SecureRandom + Send + Sync>,
+    ) -> Option<Box<dyn sign::Signer>> {
+        let signer = self.key.choose_scheme(offered, rng)?;
+        Some(Box::new(CountingSigner {
+            signer,
+            signatures: Arc::clone(&self.signatures),
+        }))
     }
-}
 
-#[derive(Debug, PartialEq)]
-struct KeyLogItem {
-    label: String,
-    client_random: Vec<u8>,
-    secret: Vec<u8>,
+    fn algorithm(&self) -> rustls::internal::msgs::enums::SignatureAlgorithm {
+        self.key.algorithm()
+    }
 }
 
-struct KeyLogToVec {
-    label: &'static str,
-    items: Mutex<Vec<KeyLogItem>>,
+struct CountingSigner {
+    signer: Box<dyn sign::Signer>,
+    signatures: Arc<AtomicUsize>,
 }
 
-impl KeyLogToVec {
-    fn new(who: &'static str) -> Self {
-        KeyLogToVec {
-            label: who,
-            items: Mutex::new(vec![]),
-        }
+impl sign::Signer for CountingSigner {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, Error> {
+        self.signatures
+            .fetch_add(1, Ordering::SeqCst);
+        self.signer.sign(message)
     }
 
-    fn take(&self) -> Vec<KeyLogItem> {
-        std::mem::take(&mut self.items.lock().unwrap())
+    fn scheme(&self) -> SignatureScheme {
+        self.signer.scheme()
     }
 }
 
-impl KeyLog for KeyLogToVec {
-    fn log(&self, label: &str, client: &[u8], secret: &[u8]) {
-        let value = KeyLogItem {
-            label: label.into(),
-            client_random: client.into(),
-            secret: secret.into(),
-        };
-
-        println!("key log {:?}: {:?}", self.label, value);
-
-        self.items.lock().unwrap().push(value);
-    }
+/// A server config with a delegated credential expiring at `expiry`,
+/// and a count of the signatures made with the certificate's own key.
+fn make_server_config_counting_certificate_key_signatures(
+    expiry: std::time::SystemTime,
+) -> (ServerConfig, Arc<AtomicUsize>) {
+    let mut certified_key = certified_key_with_delegated_credential(None, expiry);
+    let signatures = Arc::new(AtomicUsize::new(0));
+    certified_key.key = Arc::new(CountingSigningKey {
+        key: certified_key.key,
+        signatures: Arc::clone(&signatures),
+    });
+    (
+        make_server_config_with_certified_key(certified_key),
+        signatures,
+    )
 }
 
-#[cfg(feature = "tls12")]
 #[test]
-fn key_log_for_tls12() {
-    let client_key_log = Arc::new(KeyLogToVec::new("client"));
-    let server_key_log = Arc::new(KeyLogToVec::new("server"));
-
-    let kt = KeyType::Rsa;
-    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS12]);
-    client_config.key_log = client_key_log.clone();
-    let client_config = Arc::new(client_config);
-
-    let mut server_config = make_server_config(kt);
-    server_config.key_log = server_key_log.clone();
+fn server_signs_with_delegated_credential_key() {
+    let expiry = std::time::SystemTime::now() + Duration::from_secs(24 * 60 * 60);
+    let (server_config, signatures) =
+        make_server_config_counting_certificate_key_signatures(expiry);
     let server_config = Arc::new(server_config);
 
-    // full handshake
+    let client_config = Arc::new(make_client_config_accepting_delegated_credentials());
     let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
     do_handshake(&mut client, &mut server);
+    assert_eq!(signatures.load(Ordering::SeqCst), 0);
 
-    let client_full_log = client_key_log.take();
-    let server_full_log = server_key_log.take();
-    assert_eq!(client_full_log, server_full_log);
-    assert_eq!(1, client_full_log.len());
-    assert_eq!("CLIENT_RANDOM", client_full_log[0].label);
-
-    // resumed
+    // Clients which don't accept delegated credentials get the certificate's key.
+    let client_config = Arc::new(make_client_config_with_versions(
+        KeyType::Ecdsa,
+        &[&rustls::version::TLS13],
+    ));
     let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
     do_handshake(&mut client, &mut server);
+    assert_eq!(signatures.load(Ordering::SeqCst), 1);
+}
 
-    let client_resume_log = client_key_log.take();
-    let server_resume_log = server_key_log.take();
-    assert_eq!(client_resume_log, server_resume_log);
-    assert_eq!(1, client_resume_log.len());
-    assert_eq!("CLIENT_RANDOM", client_resume_log[0].label);
-    assert_eq!(client_full_log[0].secret, client_resume_log[0].secret);
+#[test]
+fn server_does_not_send_expired_delegated_credential() {
+    let expiry = std::time::SystemTime::now() - Duration::from_secs(1);
+    let (server_config, signatures) =
+        make_server_config_counting_certificate_key_signatures(expiry);
+
+    let (mut client, mut server) = make_pair_for_configs(
+        make_client_config_accepting_delegated_credentials(),
+        server_config,
+    );
+    do_handshake(&mut client, &mut server);
+    assert_eq!(signatures.load(Ordering::SeqCst), 1);
 }
 
 #[test]
-fn key_log_for_tls13() {
-    let client_key_log = Arc::new(KeyLogToVec::new("client"));
-    let server_key_log = Arc::new(KeyLogToVec::new("server"));
+fn server_ignores_delegated_credential_for_other_schemes() {
+    // How many signatures the certificate's key makes for a client
+    // offering delegated credentials with `schemes`.
+    let certificate_key_signatures = |schemes: &[SignatureScheme]| {
+        let expiry = std::time::SystemTime::now() + Duration::from_secs(24 * 60 * 60);
+        let (server_config, signatures) =
+            make_server_config_counting_certificate_key_signatures(expiry);
+        let client_config =
+            make_client_config_with_versions(KeyType::Ecdsa, &[&rustls::version::TLS13]);
+        let (client, server) = make_pair_for_configs(client_config, server_config);
+        let (mut client, mut server) = (client.into(), server.into());
+
+        let offer_delegated_credentials = |msg: &mut Message| {
+            if let MessagePayload::Handshake(hs) = &mut msg.payload {
+                if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                    ch.extensions
+                        .push(ClientExtension::DelegatedCredential(schemes.to_vec()));
+                }
+            }
+            Altered::InPlace
+        };
+        transfer_altered(&mut client, offer_delegated_credentials, &mut server);
+        server.process_new_packets().unwrap();
+        signatures.load(Ordering::SeqCst)
+    };
 
-    let kt = KeyType::Rsa;
-    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-    client_config.key_log = client_key_log.clone();
-    let client_config = Arc::new(client_config);
+    assert_eq!(
+        certificate_key_signatures(&[
+            SignatureScheme::ED25519,
+            SignatureScheme::ECDSA_NISTP256_SHA256
+        ]),
+        0
+    );
+    assert_eq!(certificate_key_signatures(&[SignatureScheme::ED25519]), 1);
+}
 
-    let mut server_config = make_server_config(kt);
-    server_config.key_log = server_key_log.clone();
-    let server_config = Arc::new(server_config);
+#[test]
+fn client_error_is_sticky() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    client
+        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
+        .unwrap();
+    let mut err = client.process_new_packets();
+    assert!(err.is_err());
+    err = client.process_new_packets();
+    assert!(err.is_err());
+}
 
-    // full handshake
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+#[test]
+fn server_error_is_sticky() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    server
+        .read_tls(&mut b"\x16\x03\x03\x00\x08\x0f\x00\x00\x04junk".as_ref())
+        .unwrap();
+    let mut err = server.process_new_packets();
+    assert!(err.is_err());
+    err = server.process_new_packets();
+    assert!(err.is_err());
+}
 
-    let client_full_log = client_key_log.take();
-    let server_full_log = server_key_log.take();
+#[test]
+fn server_flush_does_nothing() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    assert!(matches!(server.writer().flush(), Ok(())));
+}
 
-    assert_eq!(5, client_full_log.len());
-    assert_eq!("CLIENT_HANDSHAKE_TRAFFIC_SECRET", client_full_log[0].label);
-    assert_eq!("SERVER_HANDSHAKE_TRAFFIC_SECRET", client_full_log[1].label);
-    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_full_log[2].label);
-    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_full_log[3].label);
-    assert_eq!("EXPORTER_SECRET", client_full_log[4].label);
+#[test]
+fn client_flush_does_nothing() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert!(matches!(client.writer().flush(), Ok(())));
+}
 
-    assert_eq!(client_full_log[0], server_full_log[0]);
-    assert_eq!(client_full_log[1], server_full_log[1]);
-    assert_eq!(client_full_log[2], server_full_log[2]);
-    assert_eq!(client_full_log[3], server_full_log[3]);
-    assert_eq!(client_full_log[4], server_full_log[4]);
+#[test]
+fn server_is_send_and_sync() {
+    let (_, server) = make_pair(KeyType::Rsa);
+    &server as &dyn Send;
+    &server as &dyn Sync;
+}
 
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+#[test]
+fn client_is_send_and_sync() {
+    let (client, _) = make_pair(KeyType::Rsa);
+    &client as &dyn Send;
+    &client as &dyn Sync;
+}
 
-    let client_resume_log = client_key_log.take();
-    let server_resume_log = server_key_log.take();
+#[test]
+fn server_respects_buffer_limit_pre_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    assert_eq!(5, client_resume_log.len());
-    assert_eq!(
-        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
-        client_resume_log[0].label
-    );
-    assert_eq!(
-        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
-        client_resume_log[1].label
-    );
-    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_resume_log[2].label);
-    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_resume_log[3].label);
-    assert_eq!("EXPORTER_SECRET", client_resume_log[4].label);
+    server.set_buffer_limit(Some(32));
 
-    assert_eq!(6, server_resume_log.len());
-    assert_eq!("CLIENT_EARLY_TRAFFIC_SECRET", server_resume_log[0].label);
     assert_eq!(
-        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
-        server_resume_log[1].label
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
     );
     assert_eq!(
-        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
-        server_resume_log[2].label
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        12
     );
-    assert_eq!("CLIENT_TRAFFIC_SECRET_0", server_resume_log[3].label);
-    assert_eq!("SERVER_TRAFFIC_SECRET_0", server_resume_log[4].label);
-    assert_eq!("EXPORTER_SECRET", server_resume_log[5].label);
 
-    assert_eq!(client_resume_log[0], server_resume_log[1]);
-    assert_eq!(client_resume_log[1], server_resume_log[2]);
-    assert_eq!(client_resume_log[2], server_resume_log[3]);
-    assert_eq!(client_resume_log[3], server_resume_log[4]);
-    assert_eq!(client_resume_log[4], server_resume_log[5]);
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    check_read(&mut client.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn vectored_write_for_server_appdata() {
+fn server_respects_buffer_limit_pre_handshake_with_vectored_write() {
     let (mut client, mut server) = make_pair(KeyType::Rsa);
-    do_handshake(&mut client, &mut server);
 
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert_eq!(84, wrlen);
-        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
-    }
-    check_read(
-        &mut client.reader(),
-        b"0123456789012345678901234567890123456789",
+    server.set_buffer_limit(Some(32));
+
+    assert_eq!(
+        server
+            .writer()
+            .write_vectored(&[
+                IoSlice::new(b"01234567890123456789"),
+                IoSlice::new(b"01234567890123456789")
+            ])
+            .unwrap(),
+        32
     );
-}
 
-#[test]
-fn vectored_write_for_client_appdata() {
-    let (mut client, mut server) = make_pair(KeyType::Rsa);
     do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
 
-    client
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    client
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert_eq!(84, wrlen);
-        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
-    }
-    check_read(
-        &mut server.reader(),
-        b"0123456789012345678901234567890123456789",
-    );
+    check_read(&mut client.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn vectored_write_for_server_handshake_with_half_rtt_data() {
-    let mut server_config = make_server_config(KeyType::Rsa);
-    server_config.send_half_rtt_data = true;
-    let (mut client, mut server) =
-        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
+fn server_respects_buffer_limit_post_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    server
-        .writer()
-        .write_all(b"0123456789")
-        .unwrap();
+    // this test will vary in behaviour depending on the default suites
+    do_handshake(&mut client, &mut server);
+    server.set_buffer_limit(Some(48));
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        // don't assert exact sizes here, to avoid a brittle test
-        assert!(wrlen > 4000); // its pretty big (contains cert chain)
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert_eq!(pipe.writevs[0].len(), 8); // at least a server hello/ccs/cert/serverkx/0.5rtt data
-    }
+    assert_eq!(
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
+    );
+    assert_eq!(
+        server
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        6
+    );
 
+    transfer(&mut server, &mut client);
     client.process_new_packets().unwrap();
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert_eq!(wrlen, 103);
-        assert_eq!(pipe.writevs, vec![vec![103]]);
-    }
 
-    assert!(!server.is_handshaking());
-    assert!(!client.is_handshaking());
-    check_read(&mut client.reader(), b"012345678901234567890123456789");
+    check_read(&mut client.reader(), b"01234567890123456789012345");
 }
 
-fn check_half_rtt_does_not_work(server_config: ServerConfig) {
-    let (mut client, mut server) =
-        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
+#[test]
+fn client_respects_buffer_limit_pre_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    server
-        .writer()
-        .write_all(b"0123456789")
-        .unwrap();
+    client.set_buffer_limit(Some(32));
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        // don't assert exact sizes here, to avoid a brittle test
-        assert!(wrlen > 4000); // its pretty big (contains cert chain)
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() >= 6); // at least a server hello/ccs/cert/serverkx data
-    }
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
+    );
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        12
+    );
 
-    // client second flight
-    client.process_new_packets().unwrap();
+    do_handshake(&mut client, &mut server);
     transfer(&mut client, &mut server);
-
-    // when client auth is enabled, we don't sent 0.5-rtt data, as we'd be sending
-    // it to an unauthenticated peer. so it happens here, in the server's second
-    // flight (42 and 32 are lengths of appdata sent above).
     server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert_eq!(wrlen, 177);
-        assert_eq!(pipe.writevs, vec![vec![103, 42, 32]]);
-    }
-
-    assert!(!server.is_handshaking());
-    assert!(!client.is_handshaking());
-    check_read(&mut client.reader(), b"012345678901234567890123456789");
-}
-
-#[test]
-fn vectored_write_for_server_handshake_no_half_rtt_with_client_auth() {
-    let mut server_config = make_server_config_with_mandatory_client_auth(KeyType::Rsa);
-    server_config.send_half_rtt_data = true; // ask even though it will be ignored
-    check_half_rtt_does_not_work(server_config);
-}
 
-#[test]
-fn vectored_write_for_server_handshake_no_half_rtt_by_default() {
-    let server_config = make_server_config(KeyType::Rsa);
-    assert_eq!(server_config.send_half_rtt_data, false);
-    check_half_rtt_does_not_work(server_config);
+    check_read(&mut server.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn vectored_write_for_client_handshake() {
+fn client_respects_buffer_limit_pre_handshake_with_vectored_write() {
     let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    client
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-    client
-        .writer()
-        .write_all(b"0123456789")
-        .unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        // don't assert exact sizes here, to avoid a brittle test
-        assert!(wrlen > 200); // just the client hello
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 1); // only a client hello
-    }
+    client.set_buffer_limit(Some(32));
 
-    transfer(&mut server, &mut client);
-    client.process_new_packets().unwrap();
+    assert_eq!(
+        client
+            .writer()
+            .write_vectored(&[
+                IoSlice::new(b"01234567890123456789"),
+                IoSlice::new(b"01234567890123456789")
+            ])
+            .unwrap(),
+        32
+    );
 
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert_eq!(wrlen, 154);
-        // CCS, finished, then two application datas
-        assert_eq!(pipe.writevs, vec![vec![6, 74, 42, 32]]);
-    }
+    do_handshake(&mut client, &mut server);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-    assert!(!server.is_handshaking());
-    assert!(!client.is_handshaking());
-    check_read(&mut server.reader(), b"012345678901234567890123456789");
+    check_read(&mut server.reader(), b"01234567890123456789012345678901");
 }
 
 #[test]
-fn vectored_write_with_slow_client() {
+fn client_respects_buffer_limit_post_handshake() {
     let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    client.set_buffer_limit(Some(32));
-
     do_handshake(&mut client, &mut server);
-    server
-        .writer()
-        .write_all(b"01234567890123456789")
-        .unwrap();
-
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        pipe.short_writes = true;
-        let wrlen = server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap()
-            + server.write_tls(&mut pipe).unwrap();
-        assert_eq!(42, wrlen);
-        assert_eq!(
-            pipe.writevs,
-            vec![vec![21], vec![10], vec![5], vec![3], vec![3]]
-        );
-    }
-    check_read(&mut client.reader(), b"01234567890123456789");
-}
+    client.set_buffer_limit(Some(48));
 
-struct ServerStorage {
-    storage: Arc<dyn rustls::server::StoresServerSessions>,
-    put_count: AtomicUsize,
-    get_count: AtomicUsize,
-    take_count: AtomicUsize,
-}
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        20
+    );
+    assert_eq!(
+        client
+            .writer()
+            .write(b"01234567890123456789")
+            .unwrap(),
+        6
+    );
 
-impl ServerStorage {
-    fn new() -> Self {
-        ServerStorage {
-            storage: rustls::server::ServerSessionMemoryCache::new(1024),
-            put_count: AtomicUsize::new(0),
-            get_count: AtomicUsize::new(0),
-            take_count: AtomicUsize::new(0),
-        }
-    }
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
 
-    fn puts(&self) -> usize {
-        self.put_count.load(Ordering::SeqCst)
-    }
-    fn gets(&self) -> usize {
-        self.get_count.load(Ordering::SeqCst)
-    }
-    fn takes(&self) -> usize {
-        self.take_count.load(Ordering::SeqCst)
-    }
+    check_read(&mut server.reader(), b"01234567890123456789012345");
 }
 
-impl fmt::Debug for ServerStorage {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "(put: {:?}, get: {:?}, take: {:?})",
-            self.put_count, self.get_count, self.take_count
-        )
-    }
+struct OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    sess: &'a mut C,
+    pub reads: usize,
+    pub writevs: Vec<Vec<usize>>,
+    fail_ok: bool,
+    pub short_writes: bool,
+    pub last_error: Option<rustls::Error>,
 }
 
-impl rustls::server::StoresServerSessions for ServerStorage {
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        self.put_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.put(key, value)
+impl<'a, C, S> OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn new(sess: &'a mut C) -> OtherSession<'a, C, S> {
+        OtherSession {
+            sess,
+            reads: 0,
+            writevs: vec![],
+            fail_ok: false,
+            short_writes: false,
+            last_error: None,
+        }
     }
 
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.get_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.get(key)
+    fn new_fails(sess: &'a mut C) -> OtherSession<'a, C, S> {
+        let mut os = OtherSession::new(sess);
+        os.fail_ok = true;
+        os
     }
+}
 
-    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.take_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.take(key)
+impl<'a, C, S> io::Read for OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn read(&mut self, mut b: &mut [u8]) -> io::Result<usize> {
+        self.reads += 1;
+        self.sess.write_tls(b.by_ref())
     }
+}
 
-    fn can_cache(&self) -> bool {
-        true
+impl<'a, C, S> io::Write for OtherSession<'a, C, S>
+where
+    C: DerefMut + Deref<Target = ConnectionCommon<S>>,
+    S: SideData,
+{
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        unreachable!()
     }
-}
 
-struct ClientStorage {
-    storage: Arc<dyn rustls::client::StoresClientSessions>,
-    put_count: AtomicUsize,
-    get_count: AtomicUsize,
-    last_put_key: Mutex<Option<Vec<u8>>>,
-}
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 
-impl ClientStorage {
-    fn new() -> Self {
-        ClientStorage {
-            storage: rustls::client::ClientSessionMemoryCache::new(1024),
-            put_count: AtomicUsize::new(0),
-            get_count: AtomicUsize::new(0),
-            last_put_key: Mutex::new(None),
+    fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
+        let mut total = 0;
+        let mut lengths = vec![];
+        for bytes in b {
+            let write_len = if self.short_writes {
+                if bytes.len() > 5 {
+                    bytes.len() / 2
+                } else {
+                    bytes.len()
+                }
+            } else {
+                bytes.len()
+            };
+
+            let l = self
+                .sess
+                .read_tls(&mut io::Cursor::new(&bytes[..write_len]))?;
+            lengths.push(l);
+            total += l;
+            if bytes.len() != l {
+                break;
+            }
         }
-    }
 
-    fn puts(&self) -> usize {
-        self.put_count.load(Ordering::SeqCst)
-    }
-    fn gets(&self) -> usize {
-        self.get_count.load(Ordering::SeqCst)
+        let rc = self.sess.process_new_packets();
+        if !self.fail_ok {
+            rc.unwrap();
+        } else if rc.is_err() {
+            self.last_error = rc.err();
+        }
+
+        self.writevs.push(lengths);
+        Ok(total)
     }
 }
 
-impl fmt::Debug for ClientStorage {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "(puts: {:?}, gets: {:?} )",
-            self.put_count, self.get_count
-        )
-    }
+#[test]
+fn server_read_returns_wouldblock_when_no_data() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    assert!(matches!(server.reader().read(&mut [0u8; 1]),
+                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
 }
 
-impl rustls::client::StoresClientSessions for ClientStorage {
-    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
-        self.put_count
-            .fetch_add(1, Ordering::SeqCst);
-        *self.last_put_key.lock().unwrap() = Some(key.clone());
-        self.storage.put(key, value)
-    }
+#[test]
+fn client_read_returns_wouldblock_when_no_data() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert!(matches!(client.reader().read(&mut [0u8; 1]),
+                     Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+}
 
-    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        self.get_count
-            .fetch_add(1, Ordering::SeqCst);
-        self.storage.get(key)
-    }
+#[test]
+fn new_server_returns_initial_io_state() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    let io_state = server.process_new_packets().unwrap();
+    println!("IoState is Debug {:?}", io_state);
+    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
+    assert!(!io_state.peer_has_closed());
+    assert_eq!(io_state.tls_bytes_to_write(), 0);
 }
 
 #[test]
-fn tls13_stateful_resumption() {
-    let kt = KeyType::Rsa;
-    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-    let client_config = Arc::new(client_config);
+fn new_client_returns_initial_io_state() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    let io_state = client.process_new_packets().unwrap();
+    println!("IoState is Debug {:?}", io_state);
+    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
+    assert!(!io_state.peer_has_closed());
+    assert!(io_state.tls_bytes_to_write() > 200);
+}
 
-    let mut server_config = make_server_config(kt);
-    let storage = Arc::new(ServerStorage::new());
-    server_config.session_storage = storage.clone();
-    let server_config = Arc::new(server_config);
+#[test]
+fn client_complete_io_for_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
 
-    // full handshake
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(storage.puts(), 1);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+    assert!(client.is_handshaking());
+    let (rdlen, wrlen) = client
+        .complete_io(&mut OtherSession::new(&mut server))
+        .unwrap();
+    assert!(rdlen > 0 && wrlen > 0);
+    assert!(!client.is_handshaking());
+}
 
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
-    assert!(resume_c2s > full_c2s);
-    assert!(resume_s2c < full_s2c);
-    assert_eq!(storage.puts(), 2);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 1);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+#[test]
+fn client_complete_io_for_handshake_eof() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    let mut input = io::Cursor::new(Vec::new());
 
-    // resumed again
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(resume_s2c, resume2_s2c);
-    assert_eq!(resume_c2s, resume2_c2s);
-    assert_eq!(storage.puts(), 3);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 2);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+    assert!(client.is_handshaking());
+    let err = client
+        .complete_io(&mut input)
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
 }
 
 #[test]
-fn tls13_stateless_resumption() {
-    let kt = KeyType::Rsa;
-    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-    let client_config = Arc::new(client_config);
+fn client_complete_io_for_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-    let mut server_config = make_server_config(kt);
-    server_config.ticketer = rustls::Ticketer::new().unwrap();
-    let storage = Arc::new(ServerStorage::new());
-    server_config.session_storage = storage.clone();
-    let server_config = Arc::new(server_config);
+        do_handshake(&mut client, &mut server);
 
-    // full handshake
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(storage.puts(), 0);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
         client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
-
-    // resumed
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
-    assert!(resume_c2s > full_c2s);
-    assert!(resume_s2c < full_s2c);
-    assert_eq!(storage.puts(), 0);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
-        client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
-
-    // resumed again
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
-    assert_eq!(resume_s2c, resume2_s2c);
-    assert_eq!(resume_c2s, resume2_c2s);
-    assert_eq!(storage.puts(), 0);
-    assert_eq!(storage.gets(), 0);
-    assert_eq!(storage.takes(), 0);
-    assert_eq!(
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
         client
-            .peer_certificates()
-            .map(|certs| certs.len()),
-        Some(3)
-    );
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
+            assert!(rdlen == 0 && wrlen > 0);
+            println!("{:?}", pipe.writevs);
+            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+        }
+        check_read(
+            &mut server.reader(),
+            b"0123456789012345678901234567890123456789",
+        );
+    }
 }
 
 #[test]
-fn early_data_not_available() {
-    let (mut client, _) = make_pair(KeyType::Rsa);
-    assert!(client.early_data().is_none());
-}
+fn client_complete_io_for_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-fn early_data_configs() -> (Arc<ClientConfig>, Arc<ServerConfig>) {
-    let kt = KeyType::Rsa;
-    let mut client_config = make_client_config(kt);
-    client_config.enable_early_data = true;
-    client_config.session_storage = Arc::new(ClientStorage::new());
+        do_handshake(&mut client, &mut server);
 
-    let mut server_config = make_server_config(kt);
-    server_config.max_early_data_size = 1234;
-    (Arc::new(client_config), Arc::new(server_config))
+        server
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let (rdlen, wrlen) = client.complete_io(&mut pipe).unwrap();
+            assert!(rdlen > 0 && wrlen == 0);
+            assert_eq!(pipe.reads, 1);
+        }
+        check_read(&mut client.reader(), b"01234567890123456789");
+    }
 }
 
 #[test]
-fn early_data_is_available_on_resumption() {
-    let (client_config, server_config) = early_data_configs();
+fn server_complete_io_for_handshake() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+        assert!(server.is_handshaking());
+        let (rdlen, wrlen) = server
+            .complete_io(&mut OtherSession::new(&mut client))
+            .unwrap();
+        assert!(rdlen > 0 && wrlen > 0);
+        assert!(!server.is_handshaking());
+    }
+}
 
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    assert!(client.early_data().is_some());
-    assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .bytes_left(),
-        1234
-    );
-    client
-        .early_data()
-        .unwrap()
-        .flush()
-        .unwrap();
-    assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .write(b"hello")
-            .unwrap(),
-        5
-    );
-    do_handshake(&mut client, &mut server);
+#[test]
+fn server_complete_io_for_handshake_eof() {
+    let (_, mut server) = make_pair(KeyType::Rsa);
+    let mut input = io::Cursor::new(Vec::new());
 
-    let mut received_early_data = [0u8; 5];
-    assert_eq!(
-        server
-            .early_data()
-            .expect("early_data didn't happen")
-            .read(&mut received_early_data)
-            .expect("early_data failed unexpectedly"),
-        5
-    );
-    assert_eq!(&received_early_data[..], b"hello");
+    assert!(server.is_handshaking());
+    let err = server
+        .complete_io(&mut input)
+        .unwrap_err();
+    assert_eq!(io::ErrorKind::UnexpectedEof, err.kind());
 }
 
 #[test]
-fn early_data_not_available_on_server_before_client_hello() {
-    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
-    assert!(server.early_data().is_none());
+fn server_complete_io_for_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        do_handshake(&mut client, &mut server);
+
+        server
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        server
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
+            assert!(rdlen == 0 && wrlen > 0);
+            assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+        }
+        check_read(
+            &mut client.reader(),
+            b"0123456789012345678901234567890123456789",
+        );
+    }
 }
 
 #[test]
-fn early_data_can_be_rejected_by_server() {
-    let (client_config, server_config) = early_data_configs();
+fn server_complete_io_for_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    do_handshake(&mut client, &mut server);
+        do_handshake(&mut client, &mut server);
 
-    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
-    assert!(client.early_data().is_some());
-    assert_eq!(
-        client
-            .early_data()
-            .unwrap()
-            .bytes_left(),
-        1234
-    );
-    client
-        .early_data()
-        .unwrap()
-        .flush()
-        .unwrap();
-    assert_eq!(
         client
-            .early_data()
-            .unwrap()
-            .write(b"hello")
-            .unwrap(),
-        5
-    );
-    server.reject_early_data();
-    do_handshake(&mut client, &mut server);
-
-    assert_eq!(client.is_early_data_accepted(), false);
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let (rdlen, wrlen) = server.complete_io(&mut pipe).unwrap();
+            assert!(rdlen > 0 && wrlen == 0);
+            assert_eq!(pipe.reads, 1);
+        }
+        check_read(&mut server.reader(), b"01234567890123456789");
+    }
 }
 
-#[cfg(feature = "quic")]
-mod test_quic {
-    use super::*;
-    use rustls::Connection;
+#[test]
+fn client_stream_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-    // Returns the sender's next secrets to use, or the receiver's error.
-    fn step(
-        send: &mut dyn QuicExt,
-        recv: &mut dyn QuicExt,
-    ) -> Result<Option<quic::KeyChange>, Error> {
-        let mut buf = Vec::new();
-        let change = loop {
-            let prev = buf.len();
-            if let Some(x) = send.write_hs(&mut buf) {
-                break Some(x);
-            }
-            if prev == buf.len() {
-                break None;
-            }
-        };
-        if let Err(e) = recv.read_hs(&buf) {
-            return Err(e);
-        } else {
-            assert_eq!(recv.alert(), None);
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let mut stream = Stream::new(&mut client, &mut pipe);
+            assert_eq!(stream.write(b"hello").unwrap(), 5);
         }
-
-        Ok(change)
+        check_read(&mut server.reader(), b"hello");
     }
+}
 
-    #[test]
-    fn test_quic_handshake() {
-        fn equal_packet_keys(x: &quic::PacketKey, y: &quic::PacketKey) -> bool {
-            // Check that these two sets of keys are equal.
-            let mut buf = vec![0; 32];
-            let (header, payload_tag) = buf.split_at_mut(8);
-            let (payload, tag_buf) = payload_tag.split_at_mut(8);
-            let tag = x
-                .encrypt_in_place(42, &*header, payload)
-                .unwrap();
-            tag_buf.copy_from_slice(tag.as_ref());
+#[test]
+fn client_streamowned_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (client, mut server) = make_pair(*kt);
 
-            let result = y.decrypt_in_place(42, &*header, payload_tag);
-            match result {
-                Ok(payload) => payload == &[0; 8],
-                Err(_) => false,
-            }
+        {
+            let pipe = OtherSession::new(&mut server);
+            let mut stream = StreamOwned::new(client, pipe);
+            assert_eq!(stream.write(b"hello").unwrap(), 5);
         }
+        check_read(&mut server.reader(), b"hello");
+    }
+}
 
-        fn compatible_keys(x: &quic::KeyChange, y: &quic::KeyChange) -> bool {
-            fn keys(kc: &quic::KeyChange) -> &quic::Keys {
-                match kc {
-                    quic::KeyChange::Handshake { keys } => keys,
-                    quic::KeyChange::OneRtt { keys, .. } => keys,
-                }
-            }
-
-            let (x, y) = (keys(x), keys(y));
-            equal_packet_keys(&x.local.packet, &y.remote.packet)
-                && equal_packet_keys(&x.remote.packet, &y.local.packet)
-        }
+#[test]
+fn client_stream_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-        let kt = KeyType::Rsa;
-        let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-        client_config.enable_early_data = true;
-        let client_config = Arc::new(client_config);
-        let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
-        server_config.max_early_data_size = 0xffffffff;
-        let server_config = Arc::new(server_config);
-        let client_params = &b"client params"[..];
-        let server_params = &b"server params"[..];
+        server
+            .writer()
+            .write_all(b"world")
+            .unwrap();
 
-        // full handshake
-        let mut client = Connection::from(
-            ClientConnection::new_quic(
-                Arc::clone(&client_config),
-                quic::Version::V1,
-                dns_name("localhost"),
-                client_params.into(),
-            )
-            .unwrap(),
-        );
+        {
+            let mut pipe = OtherSession::new(&mut server);
+            let mut stream = Stream::new(&mut client, &mut pipe);
+            check_read(&mut stream, b"world");
+        }
+    }
+}
 
-        let mut server = Connection::from(
-            ServerConnection::new_quic(
-                Arc::clone(&server_config),
-                quic::Version::V1,
-                server_params.into(),
-            )
-            .unwrap(),
-        );
+#[test]
+fn client_streamowned_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (client, mut server) = make_pair(*kt);
 
-        let client_initial = step(&mut client, &mut server).unwrap();
-        assert!(client_initial.is_none());
-        assert!(client.zero_rtt_keys().is_none());
-        assert_eq!(server.quic_transport_parameters(), Some(client_params));
-        let server_hs = step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        assert!(server.zero_rtt_keys().is_none());
-        let client_hs = step(&mut client, &mut server)
-            .unwrap()
-            .unwrap();
-        assert!(compatible_keys(&server_hs, &client_hs));
-        assert!(client.is_handshaking());
-        let server_1rtt = step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        assert!(!client.is_handshaking());
-        assert_eq!(client.quic_transport_parameters(), Some(server_params));
-        assert!(server.is_handshaking());
-        let client_1rtt = step(&mut client, &mut server)
-            .unwrap()
+        server
+            .writer()
+            .write_all(b"world")
             .unwrap();
-        assert!(!server.is_handshaking());
-        assert!(compatible_keys(&server_1rtt, &client_1rtt));
-        assert!(!compatible_keys(&server_hs, &server_1rtt));
-        assert!(step(&mut client, &mut server)
-            .unwrap()
-            .is_none());
-        assert!(step(&mut server, &mut client)
-            .unwrap()
-            .is_none());
 
-        // 0-RTT handshake
-        let mut client = ClientConnection::new_quic(
-            Arc::clone(&client_config),
-            quic::Version::V1,
-            dns_name("localhost"),
-            client_params.into(),
-        )
-        .unwrap();
-        assert!(client
-            .negotiated_cipher_suite()
-            .is_some());
+        {
+            let pipe = OtherSession::new(&mut server);
+            let mut stream = StreamOwned::new(client, pipe);
+            check_read(&mut stream, b"world");
+        }
+    }
+}
 
-        let mut server = ServerConnection::new_quic(
-            Arc::clone(&server_config),
-            quic::Version::V1,
-            server_params.into(),
-        )
-        .unwrap();
+#[test]
+fn server_stream_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
 
-        step(&mut client, &mut server).unwrap();
-        assert_eq!(client.quic_transport_parameters(), Some(server_params));
         {
-            let client_early = client.zero_rtt_keys().unwrap();
-            let server_early = server.zero_rtt_keys().unwrap();
-            assert!(equal_packet_keys(
-                &client_early.packet,
-                &server_early.packet
-            ));
+            let mut pipe = OtherSession::new(&mut client);
+            let mut stream = Stream::new(&mut server, &mut pipe);
+            assert_eq!(stream.write(b"hello").unwrap(), 5);
         }
-        step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        step(&mut client, &mut server)
-            .unwrap()
-            .unwrap();
-        step(&mut server, &mut client)
-            .unwrap()
-            .unwrap();
-        assert!(client.is_early_data_accepted());
+        check_read(&mut client.reader(), b"hello");
+    }
+}
+
+#[test]
+fn server_streamowned_write() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, server) = make_pair(*kt);
 
-        // 0-RTT rejection
         {
-            let client_config = (*client_config).clone();
-            let mut client = ClientConnection::new_quic(
-                Arc::new(client_config),
-                quic::Version::V1,
-                dns_name("localhost"),
-                client_params.into(),
-            )
-            .unwrap();
+            let pipe = OtherSession::new(&mut client);
+            let mut stream = StreamOwned::new(server, pipe);
+            assert_eq!(stream.write(b"hello").unwrap(), 5);
+        }
+        check_read(&mut client.reader(), b"hello");
+    }
+}
 
-            let mut server = ServerConnection::new_quic(
-                Arc::clone(&server_config),
-                quic::Version::V1,
-                server_params.into(),
-            )
+#[test]
+fn server_stream_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, mut server) = make_pair(*kt);
+
+        client
+            .writer()
+            .write_all(b"world")
             .unwrap();
 
-            step(&mut client, &mut server).unwrap();
-            assert_eq!(client.quic_transport_parameters(), Some(server_params));
-            assert!(client.zero_rtt_keys().is_some());
-            assert!(server.zero_rtt_keys().is_none());
-            step(&mut server, &mut client)
-                .unwrap()
-                .unwrap();
-            step(&mut client, &mut server)
-                .unwrap()
-                .unwrap();
-            step(&mut server, &mut client)
-                .unwrap()
-                .unwrap();
-            assert!(!client.is_early_data_accepted());
+        {
+            let mut pipe = OtherSession::new(&mut client);
+            let mut stream = Stream::new(&mut server, &mut pipe);
+            check_read(&mut stream, b"world");
         }
+    }
+}
 
-        // failed handshake
-        let mut client = ClientConnection::new_quic(
-            client_config,
-            quic::Version::V1,
-            dns_name("example.com"),
-            client_params.into(),
-        )
-        .unwrap();
-
-        let mut server =
-            ServerConnection::new_quic(server_config, quic::Version::V1, server_params.into())
-                .unwrap();
+#[test]
+fn server_streamowned_read() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let (mut client, server) = make_pair(*kt);
 
-        step(&mut client, &mut server).unwrap();
-        step(&mut server, &mut client)
-            .unwrap()
+        client
+            .writer()
+            .write_all(b"world")
             .unwrap();
-        assert!(step(&mut server, &mut client).is_err());
-        assert_eq!(
-            client.alert(),
-            Some(rustls::internal::msgs::enums::AlertDescription::BadCertificate)
-        );
 
-        // Key updates
-
-        let (mut client_secrets, mut server_secrets) = match (client_1rtt, server_1rtt) {
-            (quic::KeyChange::OneRtt { next: c, .. }, quic::KeyChange::OneRtt { next: s, .. }) => {
-                (c, s)
-            }
-            _ => unreachable!(),
-        };
+        {
+            let pipe = OtherSession::new(&mut client);
+            let mut stream = StreamOwned::new(server, pipe);
+            check_read(&mut stream, b"world");
+        }
+    }
+}
 
-        let mut client_next = client_secrets.next_packet_keys();
-        let mut server_next = server_secrets.next_packet_keys();
-        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
-        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+struct FailsWrites {
+    errkind: io::ErrorKind,
+    after: usize,
+}
 
-        client_next = client_secrets.next_packet_keys();
-        server_next = server_secrets.next_packet_keys();
-        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
-        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+impl io::Read for FailsWrites {
+    fn read(&mut self, _b: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
     }
+}
 
-    #[test]
-    fn test_quic_rejects_missing_alpn() {
-        let client_params = &b"client params"[..];
-        let server_params = &b"server params"[..];
-
-        for &kt in ALL_KEY_TYPES.iter() {
-            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-            let client_config = Arc::new(client_config);
+impl io::Write for FailsWrites {
+    fn write(&mut self, b: &[u8]) -> io::Result<usize> {
+        if self.after > 0 {
+            self.after -= 1;
+            Ok(b.len())
+        } else {
+            Err(io::Error::new(self.errkind, "oops"))
+        }
+    }
 
-            let mut server_config =
-                make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
-            server_config.alpn_protocols = vec!["foo".into()];
-            let server_config = Arc::new(server_config);
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
 
-            let mut client = ClientConnection::new_quic(
-                client_config,
-                quic::Version::V1,
-                dns_name("localhost"),
-                client_params.into(),
-            )
-            .unwrap();
-            let mut server =
-                ServerConnection::new_quic(server_config, quic::Version::V1, server_params.into())
-                    .unwrap();
+#[test]
+fn stream_write_reports_underlying_io_error_before_plaintext_processed() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
 
-            assert_eq!(
-                step(&mut client, &mut server)
-                    .err()
-                    .unwrap(),
-                Error::NoApplicationProtocol
-            );
+    let mut pipe = FailsWrites {
+        errkind: io::ErrorKind::ConnectionAborted,
+        after: 0,
+    };
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    let mut client_stream = Stream::new(&mut client, &mut pipe);
+    let rc = client_stream.write(b"world");
+    assert!(rc.is_err());
+    let err = rc.err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+}
 
-            assert_eq!(
-                server.alert(),
-                Some(rustls::internal::msgs::enums::AlertDescription::NoApplicationProtocol)
-            );
-        }
-    }
+#[test]
+fn stream_write_swallows_underlying_io_error_after_plaintext_processed() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
 
-    #[test]
-    fn test_quic_no_tls13_error() {
-        let mut client_config =
-            make_client_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
-        client_config.alpn_protocols = vec!["foo".into()];
-        let client_config = Arc::new(client_config);
+    let mut pipe = FailsWrites {
+        errkind: io::ErrorKind::ConnectionAborted,
+        after: 1,
+    };
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    let mut client_stream = Stream::new(&mut client, &mut pipe);
+    let rc = client_stream.write(b"world");
+    assert_eq!(format!("{:?}", rc), "Ok(5)");
+}
 
-        assert!(ClientConnection::new_quic(
-            client_config,
-            quic::Version::V1,
-            dns_name("localhost"),
-            b"client params".to_vec(),
-        )
-        .is_err());
+fn make_disjoint_suite_configs() -> (ClientConfig, ServerConfig) {
+    let kt = KeyType::Rsa;
+    let server_config = finish_server_config(
+        kt,
+        ServerConfig::builder()
+            .with_cipher_suites(&[rustls::cipher_suite::TLS13_CHACHA20_POLY1305_SHA256])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
 
-        let mut server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
-        server_config.alpn_protocols = vec!["foo".into()];
-        let server_config = Arc::new(server_config);
+    let client_config = finish_client_config(
+        kt,
+        ClientConfig::builder()
+            .with_cipher_suites(&[rustls::cipher_suite::TLS13_AES_256_GCM_SHA384])
+            .with_safe_default_kx_groups()
+            .with_safe_default_protocol_versions()
+            .unwrap(),
+    );
 
-        assert!(ServerConnection::new_quic(
-            server_config,
-            quic::Version::V1,
-            b"server params".to_vec(),
-        )
-        .is_err());
+    (client_config, server_config)
+}
+
+#[test]
+fn client_stream_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    {
+        let mut pipe = OtherSession::new_fails(&mut server);
+        let mut client_stream = Stream::new(&mut client, &mut pipe);
+        let rc = client_stream.write(b"hello");
+        assert!(rc.is_err());
+        assert_eq!(
+            format!("{:?}", rc),
+            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+        );
+        let rc = client_stream.write(b"hello");
+        assert!(rc.is_err());
+        assert_eq!(
+            format!("{:?}", rc),
+            "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+        );
     }
+}
 
-    #[test]
-    fn test_quic_invalid_early_data_size() {
-        let mut server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
-        server_config.alpn_protocols = vec!["foo".into()];
+#[test]
+fn client_streamowned_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (client, mut server) = make_pair_for_configs(client_config, server_config);
 
-        let cases = [
-            (None, true),
-            (Some(0u32), true),
-            (Some(5), false),
-            (Some(0xffff_ffff), true),
-        ];
+    let pipe = OtherSession::new_fails(&mut server);
+    let mut client_stream = StreamOwned::new(client, pipe);
+    let rc = client_stream.write(b"hello");
+    assert!(rc.is_err());
+    assert_eq!(
+        format!("{:?}", rc),
+        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+    );
+    let rc = client_stream.write(b"hello");
+    assert!(rc.is_err());
+    assert_eq!(
+        format!("{:?}", rc),
+        "Err(Custom { kind: InvalidData, error: AlertReceived(HandshakeFailure) })"
+    );
+}
 
-        for &(size, ok) in cases.iter() {
-            println!("early data size case: {:?}", size);
-            if let Some(new) = size {
-                server_config.max_early_data_size = new;
-            }
+#[test]
+fn server_stream_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-            let wrapped = Arc::new(server_config.clone());
-            assert_eq!(
-                ServerConnection::new_quic(wrapped, quic::Version::V1, b"server params".to_vec(),)
-                    .is_ok(),
-                ok
-            );
-        }
+    client
+        .writer()
+        .write_all(b"world")
+        .unwrap();
+
+    {
+        let mut pipe = OtherSession::new_fails(&mut client);
+        let mut server_stream = Stream::new(&mut server, &mut pipe);
+        let mut bytes = [0u8; 5];
+        let rc = server_stream.read(&mut bytes);
+        assert!(rc.is_err());
+        assert_eq!(
+            format!("{:?}", rc),
+            "Err(Custom { kind: InvalidData, error: PeerIncompatibleError(\"no ciphersuites in common\") })"
+        );
     }
+}
 
-    #[test]
-    fn test_quic_server_no_params_received() {
-        let server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
-        let server_config = Arc::new(server_config);
+#[test]
+fn server_streamowned_handshake_error() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, server) = make_pair_for_configs(client_config, server_config);
 
-        let mut server =
-            ServerConnection::new_quic(server_config, quic::Version::V1, b"server params".to_vec())
-                .unwrap();
+    client
+        .writer()
+        .write_all(b"world")
+        .unwrap();
 
-        use ring::rand::SecureRandom;
-        use rustls::internal::msgs::base::PayloadU16;
-        use rustls::internal::msgs::enums::{
-            CipherSuite, Compression, HandshakeType, NamedGroup, SignatureScheme,
-        };
-        use rustls::internal::msgs::handshake::{
-            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionID,
-        };
-        use rustls::internal::msgs::message::PlainMessage;
+    let pipe = OtherSession::new_fails(&mut client);
+    let mut server_stream = StreamOwned::new(server, pipe);
+    let mut bytes = [0u8; 5];
+    let rc = server_stream.read(&mut bytes);
+    assert!(rc.is_err());
+    assert_eq!(
+        format!("{:?}", rc),
+        "Err(Custom { kind: InvalidData, error: PeerIncompatibleError(\"no ciphersuites in common\") })"
+    );
+}
 
-        let rng = ring::rand::SystemRandom::new();
-        let mut random = [0; 32];
-        rng.fill(&mut random).unwrap();
-        let random = Random::from(random);
+#[test]
+fn server_config_is_clone() {
+    let _ = make_server_config(KeyType::Rsa);
+}
 
-        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
-            .unwrap()
-            .compute_public_key()
-            .unwrap();
+#[test]
+fn client_config_is_clone() {
+    let _ = make_client_config(KeyType::Rsa);
+}
 
-        let client_hello = Message {
-            version: ProtocolVersion::TLSv1_3,
-            payload: MessagePayload::Handshake(HandshakeMessagePayload {
-                typ: HandshakeType::ClientHello,
-                payload: HandshakePayload::ClientHello(ClientHelloPayload {
-                    client_version: ProtocolVersion::TLSv1_3,
-                    random,
-                    session_id: SessionID::random().unwrap(),
-                    cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
-                    compression_methods: vec![Compression::Null],
-                    extensions: vec![
-                        ClientExtension::SupportedVersions(vec![ProtocolVersion::TLSv1_3]),
-                        ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
-                        ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
-                        ClientExtension::KeyShare(vec![KeyShareEntry {
-                            group: NamedGroup::X25519,
-                            payload: PayloadU16::new(kx.as_ref().to_vec()),
-                        }]),
-                    ],
-                }),
-            }),
-        };
+#[test]
+fn client_connection_is_debug() {
+    let (client, _) = make_pair(KeyType::Rsa);
+    println!("{:?}", client);
+}
 
-        let buf = PlainMessage::from(client_hello)
-            .into_unencrypted_opaque()
-            .encode();
-        server
-            .read_tls(&mut buf.as_slice())
-            .unwrap();
-        assert_eq!(
-            server.process_new_packets().err(),
-            Some(Error::PeerMisbehavedError(
-                "QUIC transport parameters not found".into(),
-            )),
-        );
-    }
+#[test]
+fn server_connection_is_debug() {
+    let (_, server) = make_pair(KeyType::Rsa);
+    println!("{:?}", server);
+}
 
-    #[test]
-    fn test_quic_server_no_tls12() {
-        let mut server_config =
-            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
-        server_config.alpn_protocols = vec!["foo".into()];
-        let server_config = Arc::new(server_config);
+#[test]
+fn server_complete_io_for_handshake_ending_with_alert() {
+    let (client_config, server_config) = make_disjoint_suite_configs();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-        use ring::rand::SecureRandom;
-        use rustls::internal::msgs::base::PayloadU16;
-        use rustls::internal::msgs::enums::{
-            CipherSuite, Compression, HandshakeType, NamedGroup, SignatureScheme,
-        };
-        use rustls::internal::msgs::handshake::{
-            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionID,
-        };
-        use rustls::internal::msgs::message::PlainMessage;
+    assert!(server.is_handshaking());
 
-        let rng = ring::rand::SystemRandom::new();
-        let mut random = [0; 32];
-        rng.fill(&mut random).unwrap();
-        let random = Random::from(random);
+    let mut pipe = OtherSession::new_fails(&mut client);
+    let rc = server.complete_io(&mut pipe);
+    assert!(rc.is_err(), "server io failed due to handshake failure");
+    assert!(!server.wants_write(), "but server did send its alert");
+    assert_eq!(
+        format!("{:?}", pipe.last_error),
+        "Some(AlertReceived(HandshakeFailure))",
+        "which was received by client"
+    );
+}
 
-        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
-            .unwrap()
-            .compute_public_key()
-            .unwrap();
+#[test]
+fn server_exposes_offered_sni() {
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("second.testserver.com"))
+                .unwrap();
+        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
 
-        let mut server =
-            ServerConnection::new_quic(server_config, quic::Version::V1, b"server params".to_vec())
+        assert_eq!(None, server.sni_hostname());
+        do_handshake(&mut client, &mut server);
+        assert_eq!(Some("second.testserver.com"), server.sni_hostname());
+    }
+}
+
+#[test]
+fn server_exposes_offered_sni_smashed_to_lowercase() {
+    // webpki actually does this for us in its DnsName type
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("SECOND.TESTServer.com"))
                 .unwrap();
+        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
 
-        let client_hello = Message {
-            version: ProtocolVersion::TLSv1_2,
-            payload: MessagePayload::Handshake(HandshakeMessagePayload {
-                typ: HandshakeType::ClientHello,
-                payload: HandshakePayload::ClientHello(ClientHelloPayload {
-                    client_version: ProtocolVersion::TLSv1_2,
-                    random: random.clone(),
-                    session_id: SessionID::random().unwrap(),
-                    cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
-                    compression_methods: vec![Compression::Null],
-                    extensions: vec![
-                        ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
-                        ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
-                        ClientExtension::KeyShare(vec![KeyShareEntry {
-                            group: NamedGroup::X25519,
-                            payload: PayloadU16::new(kx.as_ref().to_vec()),
-                        }]),
-                    ],
-                }),
-            }),
-        };
+        assert_eq!(None, server.sni_hostname());
+        do_handshake(&mut client, &mut server);
+        assert_eq!(Some("second.testserver.com"), server.sni_hostname());
+    }
+}
 
-        let buf = PlainMessage::from(client_hello)
-            .into_unencrypted_opaque()
-            .encode();
-        server
-            .read_tls(&mut buf.as_slice())
-            .unwrap();
+#[test]
+fn server_exposes_offered_sni_even_if_resolver_fails() {
+    let kt = KeyType::Rsa;
+    let resolver = rustls::server::ResolvesServerCertUsingSni::new();
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("thisdoesNOTexist.com"))
+                .unwrap();
+
+        assert_eq!(None, server.sni_hostname());
+        transfer(&mut client, &mut server);
         assert_eq!(
-            server.process_new_packets().err(),
-            Some(Error::PeerIncompatibleError(
-                "Server requires TLS1.3, but client omitted versions ext".into(),
-            )),
+            server.process_new_packets(),
+            Err(Error::General(
+                "no server certificate chain resolved".to_string()
+            ))
         );
+        assert_eq!(Some("thisdoesnotexist.com"), server.sni_hostname());
     }
+}
 
-    #[test]
-    fn packet_key_api() {
-        use rustls::quic::{Keys, Version};
+#[test]
+fn client_sends_no_sni_for_ip_address() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            for ip in ["198.51.100.1", "2001:db8::1"].iter() {
+                let server_name = rustls::ServerName::try_from(*ip).unwrap();
+                assert!(matches!(server_name, rustls::ServerName::IpAddress(_)));
 
-        // Test vectors: https://www.rfc-editor.org/rfc/rfc9001.html#name-client-initial
-        const CONNECTION_ID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
-        const PACKET_NUMBER: u64 = 2;
-        const PLAIN_HEADER: &[u8] = &[
-            0xc3, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
-            0x00, 0x00, 0x44, 0x9e, 0x00, 0x00, 0x00, 0x02,
-        ];
+                let client_config = make_client_config_with_versions(*kt, &[version]);
+                let mut client =
+                    ClientConnection::new(Arc::new(client_config), server_name).unwrap();
+                let mut server = ServerConnection::new(Arc::new(make_server_config(*kt))).unwrap();
 
-        const PAYLOAD: &[u8] = &[
-            0x06, 0x00, 0x40, 0xf1, 0x01, 0x00, 0x00, 0xed, 0x03, 0x03, 0xeb, 0xf8, 0xfa, 0x56,
-            0xf1, 0x29, 0x39, 0xb9, 0x58, 0x4a, 0x38, 0x96, 0x47, 0x2e, 0xc4, 0x0b, 0xb8, 0x63,
-            0xcf, 0xd3, 0xe8, 0x68, 0x04, 0xfe, 0x3a, 0x47, 0xf0, 0x6a, 0x2b, 0x69, 0x48, 0x4c,
-            0x00, 0x00, 0x04, 0x13, 0x01, 0x13, 0x02, 0x01, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00,
-            0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
-            0x63, 0x6f, 0x6d, 0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x06,
-            0x00, 0x1d, 0x00, 0x17, 0x00, 0x18, 0x00, 0x10, 0x00, 0x07, 0x00, 0x05, 0x04, 0x61,
-            0x6c, 0x70, 0x6e, 0x00, 0x05, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33,
-            0x00, 0x26, 0x00, 0x24, 0x00, 0x1d, 0x00, 0x20, 0x93, 0x70, 0xb2, 0xc9, 0xca, 0xa4,
-            0x7f, 0xba, 0xba, 0xf4, 0x55, 0x9f, 0xed, 0xba, 0x75, 0x3d, 0xe1, 0x71, 0xfa, 0x71,
-            0xf5, 0x0f, 0x1c, 0xe1, 0x5d, 0x43, 0xe9, 0x94, 0xec, 0x74, 0xd7, 0x48, 0x00, 0x2b,
-            0x00, 0x03, 0x02, 0x03, 0x04, 0x00, 0x0d, 0x00, 0x10, 0x00, 0x0e, 0x04, 0x03, 0x05,
-            0x03, 0x06, 0x03, 0x02, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08, 0x06, 0x00, 0x2d, 0x00,
-            0x02, 0x01, 0x01, 0x00, 0x1c, 0x00, 0x02, 0x40, 0x01, 0x00, 0x39, 0x00, 0x32, 0x04,
-            0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x05, 0x04, 0x80, 0x00, 0xff,
-            0xff, 0x07, 0x04, 0x80, 0x00, 0xff, 0xff, 0x08, 0x01, 0x10, 0x01, 0x04, 0x80, 0x00,
-            0x75, 0x30, 0x09, 0x01, 0x10, 0x0f, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57,
-            0x08, 0x06, 0x04, 0x80, 0x00, 0xff, 0xff,
-        ];
+                do_handshake(&mut client, &mut server);
+                assert_eq!(None, server.sni_hostname());
+            }
+        }
+    }
+}
+
+#[test]
+fn client_checks_ip_address_against_certificate() {
+    let kt = KeyType::Rsa;
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(kt, &[version]);
+        let server_name = rustls::ServerName::try_from("198.51.100.2").unwrap();
+        let mut client = ClientConnection::new(Arc::new(client_config), server_name).unwrap();
+        let mut server = ServerConnection::new(Arc::new(make_server_config(kt))).unwrap();
 
-        let client_keys = Keys::initial(Version::V1, &CONNECTION_ID, true);
         assert_eq!(
-            client_keys
-                .local
-                .packet
-                .confidentiality_limit(),
-            2u64.pow(23)
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Client(Error::InvalidCertificate(
+                    CertificateError::NotValidForName {
+                        name: "198.51.100.2".into(),
+                    }
+                )),
+                ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::BadCertificate)),
+            ])
         );
+    }
+}
+
+#[test]
+fn client_sends_unknown_ca_for_untrusted_certificate() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Ecdsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+
         assert_eq!(
-            client_keys
-                .local
-                .packet
-                .integrity_limit(),
-            2u64.pow(52)
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Client(Error::InvalidCertificate(CertificateError::UnknownIssuer)),
+                ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::UnknownCA)),
+            ])
         );
-        assert_eq!(client_keys.local.packet.tag_len(), 16);
-
-        let mut buf = Vec::new();
-        buf.extend(PLAIN_HEADER);
-        buf.extend(PAYLOAD);
-        let header_len = PLAIN_HEADER.len();
-        let tag_len = client_keys.local.packet.tag_len();
-        let padding_len = 1200 - header_len - PAYLOAD.len() - tag_len;
-        buf.extend(std::iter::repeat(0).take(padding_len));
-        let (header, payload) = buf.split_at_mut(header_len);
-        let tag = client_keys
-            .local
-            .packet
-            .encrypt_in_place(PACKET_NUMBER, &*header, payload)
-            .unwrap();
+    }
+}
 
-        let sample_len = client_keys.local.header.sample_len();
-        let sample = &payload[..sample_len];
-        let (first, rest) = header.split_at_mut(1);
-        client_keys
-            .local
-            .header
-            .encrypt_in_place(sample, &mut first[0], &mut rest[17..21])
-            .unwrap();
+#[test]
+fn sni_resolver_works() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+    resolver
+        .add(
+            "localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone()),
+        )
+        .unwrap();
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client1 =
+        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("localhost")).unwrap();
+    let err = do_handshake_until_error(&mut client1, &mut server1);
+    assert_eq!(err, Ok(()));
+
+    let mut server2 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client2 =
+        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("notlocalhost")).unwrap();
+    let err = do_handshake_until_error(&mut client2, &mut server2);
+    assert_eq!(
+        err,
+        Err(ErrorFromPeer::Server(Error::General(
+            "no server certificate chain resolved".into()
+        )))
+    );
+}
+
+#[test]
+fn sni_resolver_rejects_wrong_names() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+
+    assert_eq!(
+        Ok(()),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+    assert_eq!(
+        Err(Error::General(
+            "The server certificate is not valid for the given name".into()
+        )),
+        resolver.add(
+            "not-localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+    assert_eq!(
+        Err(Error::General("Bad DNS name".into())),
+        resolver.add(
+            "not ascii 🦀",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+}
+
+#[test]
+fn sni_resolver_lower_cases_configured_names() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+
+    assert_eq!(
+        Ok(()),
+        resolver.add(
+            "LOCALHOST",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client1 =
+        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("localhost")).unwrap();
+    let err = do_handshake_until_error(&mut client1, &mut server1);
+    assert_eq!(err, Ok(()));
+}
+
+#[test]
+fn sni_resolver_lower_cases_queried_names() {
+    // actually, the handshake parser does this, but the effect is the same.
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+
+    assert_eq!(
+        Ok(()),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(kt.get_chain(), signing_key.clone())
+        )
+    );
+
+    let mut server_config = make_server_config(kt);
+    server_config.cert_resolver = Arc::new(resolver);
+    let server_config = Arc::new(server_config);
+
+    let mut server1 = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+    let mut client1 =
+        ClientConnection::new(Arc::new(make_client_config(kt)), dns_name("LOCALHOST")).unwrap();
+    let err = do_handshake_until_error(&mut client1, &mut server1);
+    assert_eq!(err, Ok(()));
+}
+
+#[test]
+fn sni_resolver_rejects_bad_certs() {
+    let kt = KeyType::Rsa;
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    let signing_key = sign::RsaSigningKey::new(&kt.get_key()).unwrap();
+    let signing_key: Arc<dyn sign::SigningKey> = Arc::new(signing_key);
+
+    assert_eq!(
+        Err(Error::General(
+            "No end-entity certificate in certificate chain".into()
+        )),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(vec![], signing_key.clone())
+        )
+    );
+
+    let bad_chain = vec![rustls::Certificate(vec![0xa0])];
+    assert_eq!(
+        Err(Error::General(
+            "End-entity certificate in certificate chain is syntactically invalid".into()
+        )),
+        resolver.add(
+            "localhost",
+            sign::CertifiedKey::new(bad_chain, signing_key.clone())
+        )
+    );
+}
+
+fn certified_key(kt: KeyType) -> sign::CertifiedKey {
+    sign::CertifiedKey::new(
+        kt.get_chain(),
+        sign::any_supported_type(&kt.get_key()).unwrap(),
+    )
+}
+
+/// Returns the type of key whose certificate `resolver` gives a client
+/// asking for `name` and offering `protocols`, or `None` if none.  The
+/// server prefers the protocols in `server_protocols`, in that order.
+fn resolved_key_type(
+    resolver: &Arc<dyn rustls::server::ResolvesServerCert>,
+    name: &'static str,
+    server_protocols: &[&[u8]],
+    protocols: &[&[u8]],
+) -> Option<KeyType> {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_resolver = Arc::clone(resolver);
+    server_config.alpn_protocols = server_protocols
+        .iter()
+        .map(|p| p.to_vec())
+        .collect();
+    let server_config = Arc::new(server_config);
+
+    ALL_KEY_TYPES
+        .iter()
+        .copied()
+        .find(|&kt| {
+            // The client only trusts certificates for `kt`.
+            let mut client_config = make_client_config(kt);
+            client_config.alpn_protocols = protocols
+                .iter()
+                .map(|p| p.to_vec())
+                .collect();
+            let mut client =
+                ClientConnection::new(Arc::new(client_config), dns_name(name)).unwrap();
+            let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+            do_handshake_until_error(&mut client, &mut server).is_ok()
+        })
+}
+
+/// Server ALPN preferences for use with `make_alpn_resolver`.
+const GRPC_FIRST: &[&[u8]] = &[b"grpc-exp", b"h2"];
+const H2_FIRST: &[&[u8]] = &[b"h2", b"grpc-exp"];
+
+/// An ALPN resolver falling back to RSA for localhost, with ECDSA
+/// for grpc-exp and Ed25519 for grpc-exp on testserver.com.
+fn make_alpn_resolver() -> Arc<dyn rustls::server::ResolvesServerCert> {
+    let mut sni_resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    sni_resolver
+        .add("localhost", certified_key(KeyType::Rsa))
+        .unwrap();
+
+    let mut resolver = rustls::server::ResolvesServerCertUsingAlpn::new(Arc::new(sni_resolver));
+    resolver
+        .add(b"grpc-exp", certified_key(KeyType::Ecdsa))
+        .unwrap();
+    resolver
+        .add_for_name(
+            "testserver.com",
+            b"grpc-exp",
+            certified_key(KeyType::Ed25519),
+        )
+        .unwrap();
+    Arc::new(resolver)
+}
+
+#[test]
+fn alpn_resolver_falls_back_for_other_protocols() {
+    let resolver = make_alpn_resolver();
+    assert_eq!(
+        resolved_key_type(&resolver, "localhost", GRPC_FIRST, &[]),
+        Some(KeyType::Rsa)
+    );
+    assert_eq!(
+        resolved_key_type(&resolver, "localhost", GRPC_FIRST, &[b"h2"]),
+        Some(KeyType::Rsa)
+    );
+    assert_eq!(
+        resolved_key_type(&resolver, "testserver.com", GRPC_FIRST, &[b"h2"]),
+        None
+    );
+}
+
+#[test]
+fn alpn_resolver_prefers_certificate_for_protocol() {
+    let resolver = make_alpn_resolver();
+    assert_eq!(
+        resolved_key_type(&resolver, "localhost", GRPC_FIRST, &[b"h2", b"grpc-exp"]),
+        Some(KeyType::Ecdsa)
+    );
+    assert_eq!(
+        resolved_key_type(&resolver, "localhost", H2_FIRST, &[b"grpc-exp", b"h2"]),
+        Some(KeyType::Rsa)
+    );
+    assert_eq!(
+        resolved_key_type(
+            &resolver,
+            "second.testserver.com",
+            GRPC_FIRST,
+            &[b"grpc-exp"]
+        ),
+        Some(KeyType::Ecdsa)
+    );
+}
+
+#[test]
+fn alpn_resolver_prefers_certificate_for_name_and_protocol() {
+    let resolver = make_alpn_resolver();
+    assert_eq!(
+        resolved_key_type(&resolver, "testserver.com", GRPC_FIRST, &[b"grpc-exp"]),
+        Some(KeyType::Ed25519)
+    );
+    assert_eq!(
+        resolved_key_type(&resolver, "TESTSERVER.COM", GRPC_FIRST, &[b"grpc-exp"]),
+        Some(KeyType::Ed25519)
+    );
+}
+
+#[test]
+fn alpn_resolver_follows_server_protocol_preference() {
+    let mut resolver = rustls::server::ResolvesServerCertUsingAlpn::new(make_alpn_resolver());
+    resolver
+        .add(b"first", certified_key(KeyType::Ecdsa))
+        .unwrap();
+    resolver
+        .add(b"second", certified_key(KeyType::Ed25519))
+        .unwrap();
+    let resolver: Arc<dyn rustls::server::ResolvesServerCert> = Arc::new(resolver);
+
+    assert_eq!(
+        resolved_key_type(
+            &resolver,
+            "localhost",
+            &[b"second", b"first"],
+            &[b"first", b"second"]
+        ),
+        Some(KeyType::Ed25519)
+    );
+    assert_eq!(
+        resolved_key_type(
+            &resolver,
+            "localhost",
+            &[b"first", b"second"],
+            &[b"second", b"first"]
+        ),
+        Some(KeyType::Ecdsa)
+    );
+    assert_eq!(
+        resolved_key_type(
+            &resolver,
+            "localhost",
+            &[b"h2", b"first"],
+            &[b"first", b"h2"]
+        ),
+        Some(KeyType::Rsa)
+    );
+}
+
+#[test]
+fn alpn_resolver_rejects_wrong_names() {
+    let mut resolver = rustls::server::ResolvesServerCertUsingAlpn::new(make_alpn_resolver());
+    assert_eq!(
+        Err(Error::General(
+            "The server certificate is not valid for the given name".into()
+        )),
+        resolver.add_for_name("not-localhost", b"h2", certified_key(KeyType::Rsa))
+    );
+    assert_eq!(
+        Err(Error::General("Bad DNS name".into())),
+        resolver.add_for_name("not ascii 🦀", b"h2", certified_key(KeyType::Rsa))
+    );
+}
+
+/// A client trusting all our test CAs, offering only `schemes`.
+fn make_client_config_with_signature_schemes(
+    version: &'static rustls::SupportedProtocolVersion,
+    schemes: &[SignatureScheme],
+) -> ClientConfig {
+    make_client_config_with_suites_and_signature_schemes(
+        rustls::DEFAULT_CIPHER_SUITES,
+        version,
+        schemes,
+    )
+}
+
+/// A client trusting all our test CAs, offering only `suites` and `schemes`.
+fn make_client_config_with_suites_and_signature_schemes(
+    suites: &[SupportedCipherSuite],
+    version: &'static rustls::SupportedProtocolVersion,
+    schemes: &[SignatureScheme],
+) -> ClientConfig {
+    let mut root_store = rustls::RootCertStore::empty();
+    for kt in ALL_KEY_TYPES.iter() {
+        for cert in kt.get_chain() {
+            root_store.add(&cert).unwrap();
+        }
+    }
+
+    ClientConfig::builder()
+        .with_cipher_suites(suites)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[version])
+        .unwrap()
+        .with_signature_schemes(schemes)
+        .unwrap()
+        .with_root_certificates(root_store)
+        .with_no_client_auth()
+}
+
+/// An SNI resolver with RSA then ECDSA certificates for localhost.
+fn make_dual_sni_resolver() -> Arc<dyn rustls::server::ResolvesServerCert> {
+    let mut resolver = rustls::server::ResolvesServerCertUsingSni::new();
+    resolver
+        .add("localhost", certified_key(KeyType::Rsa))
+        .unwrap();
+    resolver
+        .add("localhost", certified_key(KeyType::Ecdsa))
+        .unwrap();
+    Arc::new(resolver)
+}
+
+/// Returns the certificate a client offering `schemes` receives from a
+/// server using `make_dual_sni_resolver`.
+fn dual_sni_resolver_certificate(
+    version: &'static rustls::SupportedProtocolVersion,
+    schemes: &[SignatureScheme],
+) -> rustls::Certificate {
+    dual_sni_resolver_certificate_for_client(make_client_config_with_signature_schemes(
+        version, schemes,
+    ))
+}
+
+fn dual_sni_resolver_certificate_for_client(client_config: ClientConfig) -> rustls::Certificate {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_resolver = make_dual_sni_resolver();
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    client.peer_certificates().unwrap()[0].clone()
+}
+
+#[test]
+fn sni_resolver_chooses_ecdsa_for_ecdsa_only_client() {
+    for version in rustls::ALL_VERSIONS {
+        let cert = dual_sni_resolver_certificate(
+            version,
+            &[
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+                SignatureScheme::ECDSA_NISTP384_SHA384,
+            ],
+        );
+        assert_eq!(cert, KeyType::Ecdsa.get_chain()[0]);
+    }
+}
+
+#[test]
+fn sni_resolver_chooses_rsa_for_rsa_only_client() {
+    for version in rustls::ALL_VERSIONS {
+        let cert = dual_sni_resolver_certificate(
+            version,
+            &[
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::RSA_PKCS1_SHA256,
+            ],
+        );
+        assert_eq!(cert, KeyType::Rsa.get_chain()[0]);
+    }
+}
+
+#[test]
+fn sni_resolver_prefers_ecdsa_for_dual_capable_client() {
+    for version in rustls::ALL_VERSIONS {
+        let cert = dual_sni_resolver_certificate(
+            version,
+            &[
+                SignatureScheme::RSA_PSS_SHA256,
+                SignatureScheme::RSA_PKCS1_SHA256,
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+            ],
+        );
+        assert_eq!(cert, KeyType::Ecdsa.get_chain()[0]);
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn sni_resolver_chooses_rsa_for_client_with_only_rsa_suites() {
+    let client_config = make_client_config_with_suites_and_signature_schemes(
+        &[find_suite(
+            CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+        )],
+        &rustls::version::TLS12,
+        &[
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::RSA_PSS_SHA256,
+        ],
+    );
+    let cert = dual_sni_resolver_certificate_for_client(client_config);
+    assert_eq!(cert, KeyType::Rsa.get_chain()[0]);
+}
+
+#[test]
+fn sni_resolver_falls_back_to_first_certificate() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_resolver = make_dual_sni_resolver();
+    let client_config = make_client_config_with_signature_schemes(
+        &rustls::version::TLS13,
+        &[SignatureScheme::ED25519],
+    );
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::PeerIncompatibleError(
+            "no overlapping sigschemes".into()
+        )))
+    );
+}
+
+#[test]
+fn certified_key_validate_accepts_matching_key() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let signing_key = sign::any_supported_type(&kt.get_key()).unwrap();
+        let certified_key = sign::CertifiedKey::new(kt.get_chain(), signing_key);
+        assert_eq!(certified_key.validate(), Ok(()));
+    }
+}
+
+#[test]
+fn certified_key_validate_rejects_mismatched_key() {
+    let signing_key = sign::any_supported_type(&KeyType::Ecdsa.get_key()).unwrap();
+    let certified_key = sign::CertifiedKey::new(KeyType::Rsa.get_chain(), signing_key);
+    assert_eq!(
+        certified_key.validate(),
+        Err(Error::General(
+            "Private key does not match end-entity certificate".into()
+        ))
+    );
+}
+
+#[test]
+fn certified_key_validate_rejects_bad_chains() {
+    let kt = KeyType::Rsa;
+    let signing_key = sign::any_supported_type(&kt.get_key()).unwrap();
+
+    assert_eq!(
+        sign::CertifiedKey::new(vec![], signing_key.clone()).validate(),
+        Err(Error::General(
+            "No end-entity certificate in certificate chain".into()
+        ))
+    );
+
+    let mut chain = kt.get_chain();
+    chain[1] = rustls::Certificate(vec![0xa0]);
+    assert_eq!(
+        sign::CertifiedKey::new(chain, signing_key.clone()).validate(),
+        Err(Error::General(
+            "Certificate 1 in certificate chain is syntactically invalid".into()
+        ))
+    );
+
+    // The end-entity certificate is issued by the intermediate, not the root.
+    let mut chain = kt.get_chain();
+    chain.remove(1);
+    assert_eq!(
+        sign::CertifiedKey::new(chain, signing_key.clone()).validate(),
+        Err(Error::General(
+            "Certificate 0 in certificate chain is not issued by the next one".into()
+        ))
+    );
+}
+
+fn do_exporter_test(client_config: ClientConfig, server_config: ServerConfig) {
+    let mut client_secret = [0u8; 64];
+    let mut server_secret = [0u8; 64];
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    assert_eq!(
+        Err(Error::HandshakeNotComplete),
+        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
+    );
+    assert_eq!(
+        Err(Error::HandshakeNotComplete),
+        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
+    );
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(
+        Ok(()),
+        client.export_keying_material(&mut client_secret, b"label", Some(b"context"))
+    );
+    assert_eq!(
+        Ok(()),
+        server.export_keying_material(&mut server_secret, b"label", Some(b"context"))
+    );
+    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+
+    assert_eq!(
+        Ok(()),
+        client.export_keying_material(&mut client_secret, b"label", None)
+    );
+    assert_ne!(client_secret.to_vec(), server_secret.to_vec());
+    assert_eq!(
+        Ok(()),
+        server.export_keying_material(&mut server_secret, b"label", None)
+    );
+    assert_eq!(client_secret.to_vec(), server_secret.to_vec());
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn test_tls12_exporter() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS12]);
+        let server_config = make_server_config(*kt);
+
+        do_exporter_test(client_config, server_config);
+    }
+}
+
+#[test]
+fn test_tls13_exporter() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+        let server_config = make_server_config(*kt);
+
+        do_exporter_test(client_config, server_config);
+    }
+}
+
+#[test]
+fn channel_binding_is_none_until_handshake_complete() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    for &typ in &[
+        ChannelBindingType::TlsExporter,
+        ChannelBindingType::TlsServerEndPoint,
+    ] {
+        assert_eq!(client.channel_binding(typ), None);
+        assert_eq!(server.channel_binding(typ), None);
+    }
+
+    do_handshake(&mut client, &mut server);
+    for &typ in &[
+        ChannelBindingType::TlsExporter,
+        ChannelBindingType::TlsServerEndPoint,
+    ] {
+        assert!(client.channel_binding(typ).is_some());
+        assert_eq!(client.channel_binding(typ), server.channel_binding(typ));
+    }
+}
+
+#[test]
+fn test_tls_exporter_channel_binding() {
+    for version in rustls::ALL_VERSIONS {
+        for kt in ALL_KEY_TYPES.iter() {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let server_config = make_server_config(*kt);
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+            do_handshake(&mut client, &mut server);
+
+            let mut expected = [0u8; 32];
+            client
+                .export_keying_material(&mut expected, b"EXPORTER-Channel-Binding", Some(b""))
+                .unwrap();
+            assert_eq!(
+                client.channel_binding(ChannelBindingType::TlsExporter),
+                Some(expected.to_vec())
+            );
+            assert_eq!(
+                server.channel_binding(ChannelBindingType::TlsExporter),
+                Some(expected.to_vec())
+            );
+        }
+    }
+}
+
+#[test]
+fn test_tls_server_end_point_channel_binding() {
+    // SHA-256 of each end-entity certificate, from
+    // `openssl x509 -in test-ca/$kt/end.cert -outform der | openssl dgst -sha256`
+    fn expected(kt: KeyType) -> Option<Vec<u8>> {
+        match kt {
+            KeyType::Rsa => Some(vec![
+                0xec, 0x70, 0x92, 0xc6, 0x70, 0x45, 0x2e, 0xed, 0xc5, 0x54, 0xb5, 0xdc, 0x4e, 0xc0,
+                0x20, 0xaa, 0x1d, 0xf3, 0x0c, 0x5a, 0x2a, 0x2e, 0xdb, 0xc5, 0x0b, 0x5e, 0x9f, 0xaa,
+                0x99, 0x7c, 0xf3, 0x1c,
+            ]),
+            KeyType::Ecdsa => Some(vec![
+                0xc0, 0xf6, 0x00, 0x14, 0x85, 0x3b, 0xeb, 0xdd, 0x87, 0xa6, 0x5a, 0xe9, 0xc9, 0xa8,
+                0x92, 0xb7, 0x9d, 0xdc, 0x62, 0x10, 0x45, 0x66, 0x9f, 0x50, 0x3d, 0x99, 0x16, 0x4c,
+                0x12, 0x04, 0xc2, 0xa1,
+            ]),
+            // Ed25519 signatures have no separate hash function.
+            KeyType::Ed25519 => None,
+        }
+    }
+
+    for version in rustls::ALL_VERSIONS {
+        for kt in ALL_KEY_TYPES.iter() {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let server_config = make_server_config(*kt);
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+            do_handshake(&mut client, &mut server);
+
+            assert_eq!(
+                client.channel_binding(ChannelBindingType::TlsServerEndPoint),
+                expected(*kt)
+            );
+            assert_eq!(
+                server.channel_binding(ChannelBindingType::TlsServerEndPoint),
+                expected(*kt)
+            );
+        }
+    }
+}
+
+fn do_suite_test(
+    client_config: ClientConfig,
+    server_config: ServerConfig,
+    expect_suite: SupportedCipherSuite,
+    expect_version: ProtocolVersion,
+) {
+    println!(
+        "do_suite_test {:?} {:?}",
+        expect_version,
+        expect_suite.suite()
+    );
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    assert_eq!(None, client.negotiated_cipher_suite());
+    assert_eq!(None, server.negotiated_cipher_suite());
+    assert_eq!(None, client.protocol_version());
+    assert_eq!(None, server.protocol_version());
+    assert!(client.is_handshaking());
+    assert!(server.is_handshaking());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    assert!(client.is_handshaking());
+    assert!(server.is_handshaking());
+    assert_eq!(None, client.protocol_version());
+    assert_eq!(Some(expect_version), server.protocol_version());
+    assert_eq!(None, client.negotiated_cipher_suite());
+    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
+    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    assert!(!client.is_handshaking());
+    assert!(!server.is_handshaking());
+    assert_eq!(Some(expect_version), client.protocol_version());
+    assert_eq!(Some(expect_version), server.protocol_version());
+    assert_eq!(Some(expect_suite), client.negotiated_cipher_suite());
+    assert_eq!(Some(expect_suite), server.negotiated_cipher_suite());
+}
+
+fn find_suite(suite: CipherSuite) -> SupportedCipherSuite {
+    for scs in ALL_CIPHER_SUITES.iter().copied() {
+        if scs.suite() == suite {
+            return scs;
+        }
+    }
+
+    panic!("find_suite given unsupported suite");
+}
+
+static TEST_CIPHERSUITES: &[(&rustls::SupportedProtocolVersion, KeyType, CipherSuite)] = &[
+    (
+        &rustls::version::TLS13,
+        KeyType::Rsa,
+        CipherSuite::TLS13_CHACHA20_POLY1305_SHA256,
+    ),
+    (
+        &rustls::version::TLS13,
+        KeyType::Rsa,
+        CipherSuite::TLS13_AES_256_GCM_SHA384,
+    ),
+    (
+        &rustls::version::TLS13,
+        KeyType::Rsa,
+        CipherSuite::TLS13_AES_128_GCM_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Ecdsa,
+        CipherSuite::TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+    ),
+    #[cfg(feature = "tls12")]
+    (
+        &rustls::version::TLS12,
+        KeyType::Rsa,
+        CipherSuite::TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+    ),
+];
+
+#[test]
+fn negotiated_ciphersuite_default() {
+    for kt in ALL_KEY_TYPES.iter() {
+        do_suite_test(
+            make_client_config(*kt),
+            make_server_config(*kt),
+            find_suite(CipherSuite::TLS13_AES_256_GCM_SHA384),
+            ProtocolVersion::TLSv1_3,
+        );
+    }
+}
+
+#[test]
+fn all_suites_covered() {
+    assert_eq!(ALL_CIPHER_SUITES.len(), TEST_CIPHERSUITES.len());
+}
+
+#[test]
+fn negotiated_ciphersuite_client() {
+    for item in TEST_CIPHERSUITES.iter() {
+        let (version, kt, suite) = *item;
+        let scs = find_suite(suite);
+        let client_config = finish_client_config(
+            kt,
+            ClientConfig::builder()
+                .with_cipher_suites(&[scs])
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[version])
+                .unwrap(),
+        );
+
+        do_suite_test(client_config, make_server_config(kt), scs, version.version);
+    }
+}
+
+#[test]
+fn negotiated_ciphersuite_server() {
+    for item in TEST_CIPHERSUITES.iter() {
+        let (version, kt, suite) = *item;
+        let scs = find_suite(suite);
+        let server_config = finish_server_config(
+            kt,
+            ServerConfig::builder()
+                .with_cipher_suites(&[scs])
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(&[version])
+                .unwrap(),
+        );
+
+        do_suite_test(make_client_config(kt), server_config, scs, version.version);
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct KeyLogItem {
+    label: String,
+    client_random: Vec<u8>,
+    secret: Vec<u8>,
+}
+
+struct KeyLogToVec {
+    label: &'static str,
+    items: Mutex<Vec<KeyLogItem>>,
+}
+
+impl KeyLogToVec {
+    fn new(who: &'static str) -> Self {
+        KeyLogToVec {
+            label: who,
+            items: Mutex::new(vec![]),
+        }
+    }
+
+    fn take(&self) -> Vec<KeyLogItem> {
+        std::mem::take(&mut self.items.lock().unwrap())
+    }
+}
+
+impl KeyLog for KeyLogToVec {
+    fn log(&self, label: &str, client: &[u8], secret: &[u8]) {
+        let value = KeyLogItem {
+            label: label.into(),
+            client_random: client.into(),
+            secret: secret.into(),
+        };
+
+        println!("key log {:?}: {:?}", self.label, value);
+
+        self.items.lock().unwrap().push(value);
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn key_log_for_tls12() {
+    let client_key_log = Arc::new(KeyLogToVec::new("client"));
+    let server_key_log = Arc::new(KeyLogToVec::new("server"));
+
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS12]);
+    client_config.key_log = client_key_log.clone();
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.key_log = server_key_log.clone();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_full_log = client_key_log.take();
+    let server_full_log = server_key_log.take();
+    assert_eq!(client_full_log, server_full_log);
+    assert_eq!(1, client_full_log.len());
+    assert_eq!("CLIENT_RANDOM", client_full_log[0].label);
+
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_resume_log = client_key_log.take();
+    let server_resume_log = server_key_log.take();
+    assert_eq!(client_resume_log, server_resume_log);
+    assert_eq!(1, client_resume_log.len());
+    assert_eq!("CLIENT_RANDOM", client_resume_log[0].label);
+    assert_eq!(client_full_log[0].secret, client_resume_log[0].secret);
+}
+
+#[test]
+fn key_log_for_tls13() {
+    let client_key_log = Arc::new(KeyLogToVec::new("client"));
+    let server_key_log = Arc::new(KeyLogToVec::new("server"));
+
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.key_log = client_key_log.clone();
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.key_log = server_key_log.clone();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_full_log = client_key_log.take();
+    let server_full_log = server_key_log.take();
+
+    assert_eq!(5, client_full_log.len());
+    assert_eq!("CLIENT_HANDSHAKE_TRAFFIC_SECRET", client_full_log[0].label);
+    assert_eq!("SERVER_HANDSHAKE_TRAFFIC_SECRET", client_full_log[1].label);
+    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_full_log[2].label);
+    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_full_log[3].label);
+    assert_eq!("EXPORTER_SECRET", client_full_log[4].label);
+
+    assert_eq!(client_full_log[0], server_full_log[0]);
+    assert_eq!(client_full_log[1], server_full_log[1]);
+    assert_eq!(client_full_log[2], server_full_log[2]);
+    assert_eq!(client_full_log[3], server_full_log[3]);
+    assert_eq!(client_full_log[4], server_full_log[4]);
+
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let client_resume_log = client_key_log.take();
+    let server_resume_log = server_key_log.take();
+
+    assert_eq!(5, client_resume_log.len());
+    assert_eq!(
+        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+        client_resume_log[0].label
+    );
+    assert_eq!(
+        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+        client_resume_log[1].label
+    );
+    assert_eq!("CLIENT_TRAFFIC_SECRET_0", client_resume_log[2].label);
+    assert_eq!("SERVER_TRAFFIC_SECRET_0", client_resume_log[3].label);
+    assert_eq!("EXPORTER_SECRET", client_resume_log[4].label);
+
+    assert_eq!(6, server_resume_log.len());
+    assert_eq!("CLIENT_EARLY_TRAFFIC_SECRET", server_resume_log[0].label);
+    assert_eq!(
+        "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+        server_resume_log[1].label
+    );
+    assert_eq!(
+        "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+        server_resume_log[2].label
+    );
+    assert_eq!("CLIENT_TRAFFIC_SECRET_0", server_resume_log[3].label);
+    assert_eq!("SERVER_TRAFFIC_SECRET_0", server_resume_log[4].label);
+    assert_eq!("EXPORTER_SECRET", server_resume_log[5].label);
+
+    assert_eq!(client_resume_log[0], server_resume_log[1]);
+    assert_eq!(client_resume_log[1], server_resume_log[2]);
+    assert_eq!(client_resume_log[2], server_resume_log[3]);
+    assert_eq!(client_resume_log[3], server_resume_log[4]);
+    assert_eq!(client_resume_log[4], server_resume_log[5]);
+}
+
+#[derive(Debug, PartialEq)]
+enum HandshakeEvent {
+    Start,
+    FlightSent(usize),
+    Complete(HandshakeStats),
+}
+
+struct HandshakeEventsToVec {
+    events: Mutex<Vec<HandshakeEvent>>,
+}
+
+impl HandshakeEventsToVec {
+    fn new() -> Self {
+        HandshakeEventsToVec {
+            events: Mutex::new(vec![]),
+        }
+    }
+
+    fn take(&self) -> Vec<HandshakeEvent> {
+        std::mem::take(&mut self.events.lock().unwrap())
+    }
+}
+
+impl HandshakeObserver for HandshakeEventsToVec {
+    fn on_handshake_start(&self) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(HandshakeEvent::Start);
+    }
+
+    fn on_flight_sent(&self, bytes: usize) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(HandshakeEvent::FlightSent(bytes));
+    }
+
+    fn on_handshake_complete(&self, stats: HandshakeStats) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(HandshakeEvent::Complete(stats));
+    }
+}
+
+/// Check `events` are a start, then `flights` flights totalling `bytes`,
+/// then completion with `stats`.
+fn check_handshake_events(
+    events: &[HandshakeEvent],
+    flights: usize,
+    bytes: usize,
+    stats: HandshakeStats,
+) {
+    assert_eq!(flights + 2, events.len());
+    assert_eq!(HandshakeEvent::Start, events[0]);
+
+    let mut total = 0;
+    for event in &events[1..flights + 1] {
+        match event {
+            HandshakeEvent::FlightSent(n) if *n > 0 => total += n,
+            _ => panic!("unexpected event {:?}", event),
+        }
+    }
+    assert_eq!(bytes, total);
+
+    assert_eq!(HandshakeEvent::Complete(stats), events[flights + 1]);
+}
+
+#[test]
+fn handshake_observer_sees_full_handshake() {
+    for version in rustls::ALL_VERSIONS {
+        let client_events = Arc::new(HandshakeEventsToVec::new());
+        let server_events = Arc::new(HandshakeEventsToVec::new());
+
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.handshake_observer = Some(client_events.clone());
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.handshake_observer = Some(server_events.clone());
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        let (to_server, to_client) = do_handshake(&mut client, &mut server);
+
+        let stats = HandshakeStats {
+            version: version.version,
+            suite: client
+                .negotiated_cipher_suite()
+                .unwrap(),
+            resumed: false,
+            early_data: false,
+            round_trips: 1,
+        };
+
+        // TLS1.2 needs a second round trip before the client sees the
+        // server's Finished.
+        let client_round_trips = match version.version {
+            ProtocolVersion::TLSv1_3 => 1,
+            _ => 2,
+        };
+        check_handshake_events(
+            &client_events.take(),
+            2,
+            to_server,
+            HandshakeStats {
+                round_trips: client_round_trips,
+                ..stats
+            },
+        );
+        check_handshake_events(&server_events.take(), 2, to_client, stats);
+
+        // Nothing more is reported once the handshake is complete.
+        client
+            .writer()
+            .write_all(b"hello")
+            .unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        assert_eq!(client_events.take(), vec![]);
+        assert_eq!(server_events.take(), vec![]);
+    }
+}
+
+#[test]
+fn handshake_observer_sees_resumption() {
+    let client_events = Arc::new(HandshakeEventsToVec::new());
+    let server_events = Arc::new(HandshakeEventsToVec::new());
+
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.handshake_observer = Some(client_events.clone());
+    let client_config = Arc::new(client_config);
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.handshake_observer = Some(server_events.clone());
+    let server_config = Arc::new(server_config);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    client_events.take();
+    server_events.take();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (to_server, to_client) = do_handshake(&mut client, &mut server);
+
+    let stats = HandshakeStats {
+        version: ProtocolVersion::TLSv1_3,
+        suite: client
+            .negotiated_cipher_suite()
+            .unwrap(),
+        resumed: true,
+        early_data: false,
+        round_trips: 1,
+    };
+    check_handshake_events(&client_events.take(), 2, to_server, stats);
+    check_handshake_events(&server_events.take(), 2, to_client, stats);
+}
+
+#[test]
+fn handshake_details_match_config() {
+    let cases = [
+        (
+            &rustls::version::TLS12,
+            rustls::cipher_suite::TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+            &rustls::kx_group::SECP256R1,
+        ),
+        (
+            &rustls::version::TLS13,
+            rustls::cipher_suite::TLS13_AES_128_GCM_SHA256,
+            &rustls::kx_group::SECP384R1,
+        ),
+    ];
+
+    for (version, suite, kx_group) in cases.iter() {
+        let client_config = ClientConfig::builder()
+            .with_cipher_suites(&[*suite])
+            .with_kx_groups(&[*kx_group])
+            .with_protocol_versions(&[*version])
+            .unwrap();
+        let mut client_config = finish_client_config(KeyType::Rsa, client_config);
+        client_config.alpn_protocols = vec![b"proto".to_vec()];
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.alpn_protocols = vec![b"proto".to_vec()];
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert_eq!(client.handshake_details(), None);
+        assert_eq!(server.handshake_details(), None);
+
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        assert_eq!(server.handshake_details(), None);
+
+        do_handshake(&mut client, &mut server);
+
+        let details = HandshakeDetails {
+            protocol_version: version.version,
+            cipher_suite: *suite,
+            key_exchange_group: Some(kx_group.name),
+            alpn_protocol: Some(b"proto".to_vec()),
+            sni_hostname: None,
+            resumed: false,
+            early_data: false,
+            peer_certificate_count: KeyType::Rsa.get_chain().len(),
+        };
+        assert_eq!(client.handshake_details(), Some(details.clone()));
+        assert_eq!(
+            server.handshake_details(),
+            Some(HandshakeDetails {
+                sni_hostname: Some("localhost".to_string()),
+                peer_certificate_count: 0,
+                ..details
+            })
+        );
+    }
+}
+
+#[test]
+fn handshake_details_after_resumption() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(KeyType::Rsa, &[version]));
+        let server_config = Arc::new(make_server_config(KeyType::Rsa));
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+
+        let details = client.handshake_details().unwrap();
+        assert!(details.resumed);
+        assert!(!details.early_data);
+        assert_eq!(
+            details.cipher_suite,
+            client
+                .negotiated_cipher_suite()
+                .unwrap()
+        );
+        assert_eq!(
+            details.peer_certificate_count,
+            KeyType::Rsa.get_chain().len()
+        );
+
+        // TLS1.2 resumption skips key exchange.
+        let kx_group = match version.version {
+            ProtocolVersion::TLSv1_3 => Some(rustls::kx_group::X25519.name),
+            _ => None,
+        };
+        assert_eq!(details.key_exchange_group, kx_group);
+
+        let details = server.handshake_details().unwrap();
+        assert!(details.resumed);
+        assert_eq!(details.key_exchange_group, kx_group);
+        assert_eq!(details.peer_certificate_count, 0);
+    }
+}
+
+#[test]
+fn handshake_kind_is_known_once_handshake_completes() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(KeyType::Rsa, &[version]));
+        let server_config = Arc::new(make_server_config(KeyType::Rsa));
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        assert_eq!(client.handshake_kind(), None);
+        assert_eq!(server.handshake_kind(), None);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.handshake_kind(), Some(HandshakeKind::Full));
+        assert_eq!(server.handshake_kind(), Some(HandshakeKind::Full));
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.handshake_kind(), Some(HandshakeKind::Resumed));
+        assert_eq!(server.handshake_kind(), Some(HandshakeKind::Resumed));
+    }
+}
+
+#[test]
+fn handshake_kind_after_helloretryrequest() {
+    let client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client.handshake_kind(),
+        Some(HandshakeKind::FullWithHelloRetryRequest)
+    );
+    assert_eq!(
+        server.handshake_kind(),
+        Some(HandshakeKind::FullWithHelloRetryRequest)
+    );
+}
+
+#[test]
+fn client_connection_can_be_restricted_to_one_kx_group() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(KeyType::Rsa, &[version]));
+        let server_config = Arc::new(make_server_config(KeyType::Rsa));
+        let mut client = ClientConnection::new_with_kx_groups(
+            client_config,
+            dns_name("localhost"),
+            &[NamedGroup::secp384r1],
+        )
+        .unwrap();
+        let mut server = ServerConnection::new(server_config).unwrap();
+        do_handshake(&mut client, &mut server);
+        assert_eq!(
+            client.negotiated_key_exchange_group(),
+            Some(NamedGroup::secp384r1)
+        );
+        assert_eq!(client.handshake_kind(), Some(HandshakeKind::Full));
+    }
+}
+
+#[test]
+fn server_connection_restricted_to_one_kx_group_asks_for_retry() {
+    // The client's key share is for X25519.
+    let client_config = Arc::new(make_client_config(KeyType::Rsa));
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+    let mut client = ClientConnection::new(client_config, dns_name("localhost")).unwrap();
+    let mut server =
+        ServerConnection::new_with_kx_groups(server_config, &[NamedGroup::secp384r1]).unwrap();
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        server.negotiated_key_exchange_group(),
+        Some(NamedGroup::secp384r1)
+    );
+    assert_eq!(
+        server.handshake_kind(),
+        Some(HandshakeKind::FullWithHelloRetryRequest)
+    );
+}
+
+#[test]
+fn connections_restricted_to_different_kx_groups_fail() {
+    let client_config = Arc::new(make_client_config(KeyType::Rsa));
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+    let mut client = ClientConnection::new_with_kx_groups(
+        client_config,
+        dns_name("localhost"),
+        &[NamedGroup::X25519],
+    )
+    .unwrap();
+    let mut server =
+        ServerConnection::new_with_kx_groups(server_config, &[NamedGroup::secp384r1]).unwrap();
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::PeerIncompatibleError(
+            "no kx group overlap with client".into()
+        )))
+    );
+}
+
+#[test]
+fn connection_cannot_be_restricted_to_unconfigured_kx_groups() {
+    let server_config = Arc::new(make_server_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::X25519],
+    ));
+    assert_eq!(
+        ServerConnection::new_with_kx_groups(server_config, &[NamedGroup::secp384r1]).err(),
+        Some(Error::General(
+            "none of the allowed kx groups are configured".into()
+        ))
+    );
+
+    let client_config = Arc::new(make_client_config(KeyType::Rsa));
+    assert_eq!(
+        ClientConnection::new_with_kx_groups(client_config, dns_name("localhost"), &[]).err(),
+        Some(Error::General(
+            "none of the allowed kx groups are configured".into()
+        ))
+    );
+}
+
+fn preferred_kx_group_handshake(
+    client_groups: &[&'static rustls::SupportedKxGroup],
+    require_preferred_kx_group: bool,
+) -> (ClientConnection, ServerConnection) {
+    let client_config = make_client_config_with_kx_groups(KeyType::Rsa, client_groups);
+    let mut server_config = make_server_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::X25519, &rustls::kx_group::SECP384R1],
+    );
+    server_config.require_preferred_kx_group = require_preferred_kx_group;
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    (client, server)
+}
+
+#[test]
+fn server_can_require_its_preferred_kx_group() {
+    // The client's only key share is for its first group, which the
+    // server supports but likes less than X25519.
+    let client_groups = &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519];
+
+    let (client, server) = preferred_kx_group_handshake(client_groups, true);
+    assert_eq!(
+        server.handshake_kind(),
+        Some(HandshakeKind::FullWithHelloRetryRequest)
+    );
+    assert_eq!(
+        client.negotiated_key_exchange_group(),
+        Some(NamedGroup::X25519)
+    );
+    assert_eq!(
+        server.negotiated_key_exchange_group(),
+        Some(NamedGroup::X25519)
+    );
+
+    let (_, server) = preferred_kx_group_handshake(client_groups, false);
+    assert_eq!(server.handshake_kind(), Some(HandshakeKind::Full));
+    assert_eq!(
+        server.negotiated_key_exchange_group(),
+        Some(NamedGroup::secp384r1)
+    );
+}
+
+#[test]
+fn server_requiring_preferred_kx_group_takes_matching_key_share() {
+    let (_, server) = preferred_kx_group_handshake(
+        &[&rustls::kx_group::X25519, &rustls::kx_group::SECP384R1],
+        true,
+    );
+    assert_eq!(server.handshake_kind(), Some(HandshakeKind::Full));
+    assert_eq!(
+        server.negotiated_key_exchange_group(),
+        Some(NamedGroup::X25519)
+    );
+}
+
+#[test]
+fn server_requiring_preferred_kx_group_falls_back_to_supported_one() {
+    // X25519 is not offered at all, so the key share in the server's
+    // second choice is fine.
+    let (_, server) = preferred_kx_group_handshake(&[&rustls::kx_group::SECP384R1], true);
+    assert_eq!(server.handshake_kind(), Some(HandshakeKind::Full));
+    assert_eq!(
+        server.negotiated_key_exchange_group(),
+        Some(NamedGroup::secp384r1)
+    );
+}
+
+fn make_stateless_retry_configs() -> (Arc<ClientConfig>, Arc<ServerConfig>) {
+    let client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+    let mut server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    server_config.hello_retry_cookies = Some(rustls::Ticketer::new().unwrap());
+    (Arc::new(client_config), Arc::new(server_config))
+}
+
+/// Sends the client's first `ClientHello` to a server which answers it
+/// with a `HelloRetryRequest`, and has the client process that.
+fn retry_with_throwaway_server(client: &mut ClientConnection, server_config: &Arc<ServerConfig>) {
+    let mut server = ServerConnection::new(Arc::clone(server_config)).unwrap();
+    transfer(client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, client);
+    client.process_new_packets().unwrap();
+}
+
+#[test]
+fn stateless_helloretryrequest_is_followed_by_a_new_connection() {
+    let (client_config, server_config) = make_stateless_retry_configs();
+    let mut client = ClientConnection::new(client_config, dns_name("localhost")).unwrap();
+    retry_with_throwaway_server(&mut client, &server_config);
+
+    let mut server = ServerConnection::new(server_config).unwrap();
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client.handshake_kind(),
+        Some(HandshakeKind::FullWithHelloRetryRequest)
+    );
+    assert_eq!(
+        server.handshake_kind(),
+        Some(HandshakeKind::FullWithHelloRetryRequest)
+    );
+
+    server
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client.reader(), b"hello");
+}
+
+#[test]
+fn stateless_helloretryrequest_is_followed_by_the_same_connection() {
+    let (client_config, server_config) = make_stateless_retry_configs();
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        server.handshake_kind(),
+        Some(HandshakeKind::FullWithHelloRetryRequest)
+    );
+}
+
+#[test]
+fn stateless_helloretryrequest_refuses_tampered_cookie() {
+    fn tamper_with_cookie(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                for ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::Cookie(cookie) = ext {
+                        cookie.0[0] ^= 0x01;
+                    }
+                }
+            }
+        }
+        Altered::InPlace
+    }
+
+    let (client_config, server_config) = make_stateless_retry_configs();
+    let mut client = ClientConnection::new(client_config, dns_name("localhost")).unwrap();
+    retry_with_throwaway_server(&mut client, &server_config);
+
+    let mut client: rustls::Connection = client.into();
+    let mut server: rustls::Connection = ServerConnection::new(server_config)
+        .unwrap()
+        .into();
+    transfer_altered(&mut client, tamper_with_cookie, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerMisbehavedError(
+            "invalid HelloRetryRequest cookie".into()
+        ))
+    );
+}
+
+#[test]
+fn stateless_helloretryrequest_refuses_cookie_from_other_key() {
+    let (client_config, server_config) = make_stateless_retry_configs();
+    let mut client = ClientConnection::new(client_config, dns_name("localhost")).unwrap();
+    retry_with_throwaway_server(&mut client, &server_config);
+
+    let mut other_server_config = (*server_config).clone();
+    other_server_config.hello_retry_cookies = Some(rustls::Ticketer::new().unwrap());
+    let mut server = ServerConnection::new(Arc::new(other_server_config)).unwrap();
+    transfer(&mut client, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerMisbehavedError(
+            "invalid HelloRetryRequest cookie".into()
+        ))
+    );
+}
+
+#[test]
+fn stateless_helloretryrequest_refuses_key_share_for_other_group() {
+    fn change_key_share_group(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                for ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::KeyShare(shares) = ext {
+                        shares[0].group = NamedGroup::secp256r1;
+                    }
+                }
+            }
+        }
+        Altered::InPlace
+    }
+
+    let (client_config, server_config) = make_stateless_retry_configs();
+    let mut client = ClientConnection::new(client_config, dns_name("localhost")).unwrap();
+    retry_with_throwaway_server(&mut client, &server_config);
+
+    let mut client: rustls::Connection = client.into();
+    let mut server: rustls::Connection = ServerConnection::new(server_config)
+        .unwrap()
+        .into();
+    transfer_altered(&mut client, change_key_share_group, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerMisbehavedError(
+            "key share differed from HelloRetryRequest".into()
+        ))
+    );
+}
+
+struct TestClock(Arc<Mutex<Instant>>);
+
+impl TestClock {
+    fn new() -> Self {
+        Self(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    fn boxed(&self) -> Box<dyn Fn() -> Instant + Send + Sync> {
+        let now = self.0.clone();
+        Box::new(move || *now.lock().unwrap())
+    }
+
+    fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+/// A stand-in certificate compression algorithm for tests.  It
+/// remembers what it last compressed and sends a one-byte token in
+/// its place, so it only works when the client and server share one
+/// instance.  Decompressions are counted.
+struct RememberingCompression {
+    alg: rustls::CertificateCompressionAlgorithm,
+    remembered: Mutex<Vec<u8>>,
+    decompressions: AtomicUsize,
+    willing: bool,
+}
+
+impl RememberingCompression {
+    fn new(alg: rustls::CertificateCompressionAlgorithm) -> Arc<Self> {
+        Self::make(alg, true)
+    }
+
+    fn unwilling(alg: rustls::CertificateCompressionAlgorithm) -> Arc<Self> {
+        Self::make(alg, false)
+    }
+
+    fn make(alg: rustls::CertificateCompressionAlgorithm, willing: bool) -> Arc<Self> {
+        Arc::new(Self {
+            alg,
+            remembered: Mutex::new(Vec::new()),
+            decompressions: AtomicUsize::new(0),
+            willing,
+        })
+    }
+
+    fn decompressions(&self) -> usize {
+        self.decompressions
+            .load(Ordering::SeqCst)
+    }
+}
+
+impl rustls::CertCompressor for RememberingCompression {
+    fn algorithm(&self) -> rustls::CertificateCompressionAlgorithm {
+        self.alg
+    }
+
+    fn compress(&self, input: &[u8]) -> Option<Vec<u8>> {
+        if !self.willing {
+            return None;
+        }
+        *self.remembered.lock().unwrap() = input.to_vec();
+        Some(vec![0x5a])
+    }
+}
+
+impl rustls::CertDecompressor for RememberingCompression {
+    fn algorithm(&self) -> rustls::CertificateCompressionAlgorithm {
+        self.alg
+    }
+
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> bool {
+        self.decompressions
+            .fetch_add(1, Ordering::SeqCst);
+        let remembered = self.remembered.lock().unwrap();
+        if input != [0x5a] || output.len() != remembered.len() {
+            return false;
+        }
+        output.copy_from_slice(&remembered);
+        true
+    }
+}
+
+fn make_cert_compression_configs(
+    client_codecs: &[Arc<RememberingCompression>],
+    server_codecs: &[Arc<RememberingCompression>],
+) -> (ClientConfig, ServerConfig) {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.cert_decompressors = client_codecs
+        .iter()
+        .map(|codec| codec.clone() as Arc<dyn rustls::CertDecompressor>)
+        .collect();
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.cert_compressors = server_codecs
+        .iter()
+        .map(|codec| codec.clone() as Arc<dyn rustls::CertCompressor>)
+        .collect();
+    (client_config, server_config)
+}
+
+#[test]
+fn server_certificate_can_be_compressed() {
+    use rustls::CertificateCompressionAlgorithm;
+
+    for alg in &[
+        CertificateCompressionAlgorithm::Zlib,
+        CertificateCompressionAlgorithm::Brotli,
+        CertificateCompressionAlgorithm::Zstd,
+    ] {
+        let codec = RememberingCompression::new(*alg);
+        let (client_config, server_config) =
+            make_cert_compression_configs(&[codec.clone()], &[codec.clone()]);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(codec.decompressions(), 1);
+        assert_eq!(
+            client.peer_certificates(),
+            Some(&KeyType::Rsa.get_chain()[..])
+        );
+    }
+}
+
+#[test]
+fn server_compresses_certificate_with_its_preferred_algorithm() {
+    use rustls::CertificateCompressionAlgorithm;
+
+    let zlib = RememberingCompression::new(CertificateCompressionAlgorithm::Zlib);
+    let brotli = RememberingCompression::new(CertificateCompressionAlgorithm::Brotli);
+    let zstd = RememberingCompression::new(CertificateCompressionAlgorithm::Zstd);
+    let (client_config, server_config) = make_cert_compression_configs(
+        &[zlib.clone(), brotli.clone()],
+        &[zstd.clone(), brotli.clone(), zlib.clone()],
+    );
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(brotli.decompressions(), 1);
+    assert_eq!(zlib.decompressions() + zstd.decompressions(), 0);
+}
+
+#[test]
+fn server_certificate_is_sent_uncompressed_unless_agreed() {
+    use rustls::CertificateCompressionAlgorithm;
+
+    let zlib = RememberingCompression::new(CertificateCompressionAlgorithm::Zlib);
+    let brotli = RememberingCompression::new(CertificateCompressionAlgorithm::Brotli);
+    let unwilling = RememberingCompression::unwilling(CertificateCompressionAlgorithm::Zlib);
+    for (client_codecs, server_codecs) in &[
+        (vec![], vec![]),
+        (vec![], vec![zlib.clone()]),
+        (vec![zlib.clone()], vec![]),
+        (vec![zlib.clone()], vec![brotli.clone()]),
+        (vec![zlib.clone()], vec![unwilling]),
+    ] {
+        let (client_config, server_config) =
+            make_cert_compression_configs(client_codecs, server_codecs);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+    }
+
+    assert_eq!(zlib.decompressions() + brotli.decompressions(), 0);
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn cert_compression_is_not_offered_for_tls12_only() {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    client_config.cert_decompressors = vec![RememberingCompression::new(
+        rustls::CertificateCompressionAlgorithm::Zlib,
+    )];
+    let mut client = ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
+
+    let (client_hello, _) = sent_client_hello(&mut client);
+    assert!(client_hello
+        .get_cert_compression_extension()
+        .is_none());
+}
+
+#[test]
+fn client_rejects_compressed_certificate_over_message_size_limit() {
+    let codec = RememberingCompression::new(rustls::CertificateCompressionAlgorithm::Brotli);
+    let (mut client_config, server_config) =
+        make_cert_compression_configs(&[codec.clone()], &[codec.clone()]);
+    client_config.max_handshake_message_size = 4096;
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    let errors = do_handshake_until_both_error(&mut client, &mut server).unwrap_err();
+    assert_eq!(
+        errors,
+        vec![
+            ErrorFromPeer::Client(Error::PeerMisbehavedError(
+                "compressed certificate is too large".into()
+            )),
+            ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::BadCertificate)),
+        ]
+    );
+    assert_eq!(codec.decompressions(), 0);
+}
+
+#[test]
+fn client_rejects_certificate_which_fails_to_decompress() {
+    use rustls::CertificateCompressionAlgorithm;
+
+    let client_codec = RememberingCompression::new(CertificateCompressionAlgorithm::Zlib);
+    let server_codec = RememberingCompression::new(CertificateCompressionAlgorithm::Zlib);
+    let (client_config, server_config) =
+        make_cert_compression_configs(&[client_codec.clone()], &[server_codec]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    let errors = do_handshake_until_both_error(&mut client, &mut server).unwrap_err();
+    assert_eq!(
+        errors,
+        vec![
+            ErrorFromPeer::Client(Error::PeerMisbehavedError(
+                "certificate decompression failed".into()
+            )),
+            ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::BadCertificate)),
+        ]
+    );
+    assert_eq!(client_codec.decompressions(), 1);
+}
+
+/// RFC8879 zlib certificate compression, using flate2.
+struct ZlibCompression;
+
+impl rustls::CertCompressor for ZlibCompression {
+    fn algorithm(&self) -> rustls::CertificateCompressionAlgorithm {
+        rustls::CertificateCompressionAlgorithm::Zlib
+    }
+
+    fn compress(&self, input: &[u8]) -> Option<Vec<u8>> {
+        let mut encoder =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(input).ok()?;
+        encoder.finish().ok()
+    }
+}
+
+impl rustls::CertDecompressor for ZlibCompression {
+    fn algorithm(&self) -> rustls::CertificateCompressionAlgorithm {
+        rustls::CertificateCompressionAlgorithm::Zlib
+    }
+
+    fn decompress(&self, input: &[u8], output: &mut [u8]) -> bool {
+        // Reading one byte more than expected spots input which
+        // decompresses to too much.
+        let mut decompressed = Vec::with_capacity(output.len());
+        let read = flate2::read::ZlibDecoder::new(input)
+            .take(output.len() as u64 + 1)
+            .read_to_end(&mut decompressed);
+        if read.is_err() || decompressed.len() != output.len() {
+            return false;
+        }
+        output.copy_from_slice(&decompressed);
+        true
+    }
+}
+
+#[test]
+fn large_certificate_chain_round_trips_through_zlib() {
+    // The intermediates are repeated, which costs the client's verifier
+    // nothing but makes a chain of around 30KB.
+    let mut chain = KeyType::Rsa.get_chain();
+    let intermediates = chain[1..].to_vec();
+    for _ in 0..12 {
+        chain.extend_from_slice(&intermediates);
+    }
+    let chain_len: usize = chain
+        .iter()
+        .map(|cert| cert.0.len())
+        .sum();
+
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.cert_decompressors = vec![Arc::new(ZlibCompression)];
+    let mut server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(chain.clone(), KeyType::Rsa.get_key())
+        .unwrap();
+    server_config.cert_compressors = vec![Arc::new(ZlibCompression)];
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    let (_, to_client) = do_handshake(&mut client, &mut server);
+
+    assert!(to_client < chain_len / 2);
+    assert_eq!(client.peer_certificates(), Some(&chain[..]));
+}
+
+#[test]
+fn stalled_handshake_times_out() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, _) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        let clock = TestClock::new();
+        client.set_handshake_clock(clock.boxed());
+
+        // The ClientHello is lost, so the server never answers.
+        let mut lost = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut lost).unwrap();
+        }
+        assert_eq!(client.io_state_hint(), IoStateHint::WantRead);
+
+        clock.advance(Duration::from_secs(30));
+        assert!(client.is_handshaking());
+        assert_eq!(client.time_in_handshake(), Duration::from_secs(30));
+        assert_eq!(client.handshake_kind(), None);
+
+        client.time_out_handshake();
+        assert_eq!(
+            client.process_new_packets().err(),
+            Some(Error::HandshakeTimedOut)
+        );
+        assert_eq!(
+            client.process_new_packets().err(),
+            Some(Error::HandshakeTimedOut)
+        );
+
+        // The client tells the server that it gave up: warning(1)
+        // user_canceled(90), then warning(1) close_notify(0).
+        let mut buf = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut buf).unwrap();
+        }
+        assert_eq!(
+            buf,
+            vec![0x15, 0x03, 0x03, 0x00, 0x02, 1, 90, 0x15, 0x03, 0x03, 0x00, 0x02, 1, 0]
+        );
+    }
+}
+
+#[test]
+fn time_out_handshake_does_nothing_after_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    let clock = TestClock::new();
+    client.set_handshake_clock(clock.boxed());
+
+    clock.advance(Duration::from_secs(1));
+    do_handshake(&mut client, &mut server);
+    clock.advance(Duration::from_secs(10));
+    assert_eq!(client.time_in_handshake(), Duration::from_secs(1));
+
+    client.time_out_handshake();
+    assert!(!client.wants_write());
+    client.process_new_packets().unwrap();
+}
+
+#[test]
+fn is_resumed_is_false_after_full_handshake() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        assert!(!client.is_resumed());
+        assert!(!server.is_resumed());
+
+        do_handshake(&mut client, &mut server);
+        assert!(!client.is_resumed());
+        assert!(!server.is_resumed());
+    }
+}
+
+#[test]
+fn is_resumed_is_true_after_resumption() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(KeyType::Rsa, &[version]));
+        let server_config = Arc::new(make_server_config(KeyType::Rsa));
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(client.is_resumed());
+        assert!(server.is_resumed());
+    }
+}
+
+#[test]
+fn server_sends_no_certificate_chain_when_resuming() {
+    for (client_config, server_config) in resumption_test_configs() {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        assert_eq!(server.sent_certificate_chain(), None);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(
+            server.sent_certificate_chain(),
+            Some(&KeyType::Rsa.get_chain()[..])
+        );
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(server.is_resumed());
+        assert_eq!(server.sent_certificate_chain(), None);
+    }
+}
+
+fn resumption_test_configs() -> Vec<(Arc<ClientConfig>, Arc<ServerConfig>)> {
+    let mut configs = Vec::new();
+    for version in rustls::ALL_VERSIONS {
+        for &stateless in &[false, true] {
+            let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+            let mut server_config = make_server_config(KeyType::Rsa);
+            if stateless {
+                server_config.ticketer = rustls::Ticketer::new().unwrap();
+            }
+            configs.push((Arc::new(client_config), Arc::new(server_config)));
+        }
+    }
+    configs
+}
+
+fn store_session(client_config: &Arc<ClientConfig>, server_config: &Arc<ServerConfig>) {
+    let (mut client, mut server) = make_pair_for_arc_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+}
+
+#[test]
+fn client_without_resumption_does_full_handshake() {
+    for (client_config, server_config) in resumption_test_configs() {
+        store_session(&client_config, &server_config);
+
+        let mut client = ClientConnection::new_without_resumption(
+            Arc::clone(&client_config),
+            dns_name("localhost"),
+        )
+        .unwrap();
+        let mut server = ServerConnection::new(Arc::clone(&server_config)).unwrap();
+        do_handshake(&mut client, &mut server);
+        assert!(
+            !client
+                .handshake_details()
+                .unwrap()
+                .resumed
+        );
+        assert!(
+            !server
+                .handshake_details()
+                .unwrap()
+                .resumed
+        );
+        assert_eq!(
+            client
+                .peer_certificates()
+                .map(|certs| certs.len()),
+            Some(KeyType::Rsa.get_chain().len())
+        );
+
+        // The shared session store is still usable by other connections.
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(
+            client
+                .handshake_details()
+                .unwrap()
+                .resumed
+        );
+        assert!(
+            server
+                .handshake_details()
+                .unwrap()
+                .resumed
+        );
+    }
+}
+
+#[test]
+fn server_without_resumption_does_full_handshake() {
+    for (client_config, server_config) in resumption_test_configs() {
+        store_session(&client_config, &server_config);
+
+        let mut client =
+            ClientConnection::new(Arc::clone(&client_config), dns_name("localhost")).unwrap();
+        let mut server =
+            ServerConnection::new_without_resumption(Arc::clone(&server_config)).unwrap();
+        do_handshake(&mut client, &mut server);
+        assert!(
+            !client
+                .handshake_details()
+                .unwrap()
+                .resumed
+        );
+        assert!(
+            !server
+                .handshake_details()
+                .unwrap()
+                .resumed
+        );
+        assert_eq!(
+            client
+                .peer_certificates()
+                .map(|certs| certs.len()),
+            Some(KeyType::Rsa.get_chain().len())
+        );
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(
+            client
+                .handshake_details()
+                .unwrap()
+                .resumed
+        );
+        assert!(
+            server
+                .handshake_details()
+                .unwrap()
+                .resumed
+        );
+    }
+}
+
+#[test]
+fn server_without_resumption_does_not_consume_ticket() {
+    let storage = Arc::new(ServerStorage::new());
+    let client_config = Arc::new(make_client_config_with_versions(
+        KeyType::Rsa,
+        &[&rustls::version::TLS13],
+    ));
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.session_storage = storage.clone();
+    let server_config = Arc::new(server_config);
+    store_session(&client_config, &server_config);
+
+    let mut client =
+        ClientConnection::new(Arc::clone(&client_config), dns_name("localhost")).unwrap();
+    let mut server = ServerConnection::new_without_resumption(Arc::clone(&server_config)).unwrap();
+    do_handshake(&mut client, &mut server);
+    assert!(
+        !server
+            .handshake_details()
+            .unwrap()
+            .resumed
+    );
+    assert_eq!(storage.takes(), 0);
+}
+
+#[test]
+fn vectored_write_for_server_appdata() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert_eq!(84, wrlen);
+        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+    }
+    check_read(
+        &mut client.reader(),
+        b"0123456789012345678901234567890123456789",
+    );
+}
+
+#[test]
+fn vectored_write_for_client_appdata() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    client
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    client
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert_eq!(84, wrlen);
+        assert_eq!(pipe.writevs, vec![vec![42, 42]]);
+    }
+    check_read(
+        &mut server.reader(),
+        b"0123456789012345678901234567890123456789",
+    );
+}
+
+#[test]
+fn vectored_write_for_server_handshake_with_half_rtt_data() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.send_half_rtt_data = true;
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
+
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        // don't assert exact sizes here, to avoid a brittle test
+        assert!(wrlen > 4000); // its pretty big (contains cert chain)
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert_eq!(pipe.writevs[0].len(), 7); // at least a server hello/ccs/cert/serverkx/0.5rtt data
+    }
+
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert_eq!(wrlen, 103);
+        assert_eq!(pipe.writevs, vec![vec![103]]);
+    }
+
+    assert!(!server.is_handshaking());
+    assert!(!client.is_handshaking());
+    check_read(&mut client.reader(), b"012345678901234567890123456789");
+}
+
+#[test]
+fn client_reads_half_rtt_data_before_server_sees_its_finished() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.send_half_rtt_data = true;
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+
+    // server flight, then data written once that has been sent
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    server
+        .writer()
+        .write_all(b"half-rtt data")
+        .unwrap();
+    assert!(server.is_handshaking());
+    assert!(server.wants_write());
+    transfer(&mut server, &mut client);
+
+    let io_state = client.process_new_packets().unwrap();
+    assert_eq!(io_state.plaintext_bytes_to_read(), 13);
+    check_read(&mut client.reader(), b"half-rtt data");
+
+    // the client's Finished is yet to be delivered
+    assert!(client.wants_write());
+    assert!(server.is_handshaking());
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    assert!(!server.is_handshaking());
+}
+
+fn check_half_rtt_does_not_work(server_config: ServerConfig) {
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config_with_auth(KeyType::Rsa), server_config);
+
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        // don't assert exact sizes here, to avoid a brittle test
+        assert!(wrlen > 4000); // its pretty big (contains cert chain)
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() >= 6); // at least a server hello/ccs/cert/serverkx data
+    }
+
+    // client second flight
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+
+    // when client auth is enabled, we don't sent 0.5-rtt data, as we'd be sending
+    // it to an unauthenticated peer. so it happens here, in the server's second
+    // flight (52 is the length of one record holding the appdata sent above).
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert_eq!(wrlen, 155);
+        assert_eq!(pipe.writevs, vec![vec![103, 52]]);
+    }
+
+    assert!(!server.is_handshaking());
+    assert!(!client.is_handshaking());
+    check_read(&mut client.reader(), b"012345678901234567890123456789");
+}
+
+#[test]
+fn vectored_write_for_server_handshake_no_half_rtt_with_client_auth() {
+    let mut server_config = make_server_config_with_mandatory_client_auth(KeyType::Rsa);
+    server_config.send_half_rtt_data = true; // ask even though it will be ignored
+    check_half_rtt_does_not_work(server_config);
+}
+
+#[test]
+fn vectored_write_for_server_handshake_no_half_rtt_by_default() {
+    let server_config = make_server_config(KeyType::Rsa);
+    assert_eq!(server_config.send_half_rtt_data, false);
+    check_half_rtt_does_not_work(server_config);
+}
+
+#[test]
+fn vectored_write_for_client_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    client
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+    client
+        .writer()
+        .write_all(b"0123456789")
+        .unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        // don't assert exact sizes here, to avoid a brittle test
+        assert!(wrlen > 200); // just the client hello
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 1); // only a client hello
+    }
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert_eq!(wrlen, 132);
+        // CCS, finished, then both writes in one application data
+        assert_eq!(pipe.writevs, vec![vec![6, 74, 52]]);
+    }
+
+    assert!(!server.is_handshaking());
+    assert!(!client.is_handshaking());
+    check_read(&mut server.reader(), b"012345678901234567890123456789");
+}
+
+#[test]
+fn vectored_write_with_slow_client() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+
+    client.set_buffer_limit(Some(32));
+
+    do_handshake(&mut client, &mut server);
+    server
+        .writer()
+        .write_all(b"01234567890123456789")
+        .unwrap();
+
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        pipe.short_writes = true;
+        let wrlen = server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap()
+            + server.write_tls(&mut pipe).unwrap();
+        assert_eq!(42, wrlen);
+        assert_eq!(
+            pipe.writevs,
+            vec![vec![21], vec![10], vec![5], vec![3], vec![3]]
+        );
+    }
+    check_read(&mut client.reader(), b"01234567890123456789");
+}
+
+/// Takes up to the next budget's worth of bytes from each `write_vectored`
+/// call (`None` meaning `WouldBlock`), and records the slices it is offered.
+struct BudgetedWriter {
+    budgets: Vec<Option<usize>>,
+    slices: Vec<Vec<usize>>,
+    written: Vec<u8>,
+}
+
+impl BudgetedWriter {
+    fn new() -> Self {
+        BudgetedWriter {
+            budgets: vec![],
+            slices: vec![],
+            written: vec![],
+        }
+    }
+}
+
+impl io::Write for BudgetedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_vectored(&[IoSlice::new(buf)])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        self.slices.push(
+            bufs.iter()
+                .map(|buf| buf.len())
+                .collect(),
+        );
+
+        let budget = if self.budgets.is_empty() {
+            None
+        } else {
+            self.budgets.remove(0)
+        };
+        let mut budget = match budget {
+            Some(budget) => budget,
+            None => return Err(io::ErrorKind::WouldBlock.into()),
+        };
+
+        let start = self.written.len();
+        for buf in bufs {
+            let take = budget.min(buf.len());
+            self.written
+                .extend_from_slice(&buf[..take]);
+            budget -= take;
+        }
+        Ok(self.written.len() - start)
+    }
+}
+
+#[test]
+fn write_tls_vectored_keeps_unsent_bytes_queued() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    for _ in 0..3 {
+        server
+            .writer()
+            .write_all(b"01234567890123456789")
+            .unwrap();
+    }
+
+    let mut wr = BudgetedWriter::new();
+    wr.budgets = vec![None];
+    let err = server
+        .write_tls_vectored(&mut wr)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+    // The error is not reported once something is written.
+    wr.budgets = vec![Some(50), Some(40), None];
+    assert_eq!(
+        server
+            .write_tls_vectored(&mut wr)
+            .unwrap(),
+        90
+    );
+    assert!(server.wants_write());
+
+    wr.budgets = vec![Some(0)];
+    assert_eq!(
+        server
+            .write_tls_vectored(&mut wr)
+            .unwrap(),
+        0
+    );
+
+    wr.budgets = vec![Some(usize::MAX)];
+    assert_eq!(
+        server
+            .write_tls_vectored(&mut wr)
+            .unwrap(),
+        36
+    );
+    assert!(!server.wants_write());
+
+    // Partly written records are offered again from where they stopped.
+    assert_eq!(
+        wr.slices,
+        vec![
+            vec![42, 42, 42],
+            vec![42, 42, 42],
+            vec![34, 42],
+            vec![36],
+            vec![36],
+            vec![36]
+        ]
+    );
+
+    let mut rd = &wr.written[..];
+    while !rd.is_empty() {
+        client.read_tls(&mut rd).unwrap();
+    }
+    client.process_new_packets().unwrap();
+    check_read(
+        &mut client.reader(),
+        b"012345678901234567890123456789012345678901234567890123456789",
+    );
+}
+
+/// Records given to a record callback: their plaintext and header
+/// content types, and each record framed as it would be by `write_tls`.
+#[derive(Default)]
+struct CapturedRecords {
+    types: Vec<(
+        rustls::internal::msgs::enums::ContentType,
+        rustls::internal::msgs::enums::ContentType,
+    )>,
+    framed: Vec<Vec<u8>>,
+}
+
+fn capture_records(conn: &mut rustls::CommonState) -> Arc<Mutex<CapturedRecords>> {
+    use rustls::internal::msgs::codec::Codec;
+
+    let captured = Arc::new(Mutex::new(CapturedRecords::default()));
+    let sink = captured.clone();
+    conn.set_record_callback(Box::new(move |record: &rustls::OutgoingRecord<'_>| {
+        let mut sink = sink.lock().unwrap();
+        sink.types
+            .push((record.plaintext_type, record.wire_type));
+        let mut framed = Vec::new();
+        record.wire_type.encode(&mut framed);
+        record.version.encode(&mut framed);
+        (record.payload.len() as u16).encode(&mut framed);
+        framed.extend_from_slice(record.payload);
+        sink.framed.push(framed);
+    }))
+    .unwrap();
+    captured
+}
+
+/// Gives `to` the records captured from its peer since last time.
+fn deliver_records(
+    captured: &Mutex<CapturedRecords>,
+    to: &mut (impl DerefMut + Deref<Target = ConnectionCommon<impl SideData>>),
+) -> usize {
+    let framed = mem::take(&mut captured.lock().unwrap().framed);
+    for record in &framed {
+        to.read_tls(&mut &record[..]).unwrap();
+        to.process_new_packets().unwrap();
+    }
+    framed.len()
+}
+
+#[test]
+fn record_callback_sees_each_handshake_record() {
+    use rustls::internal::msgs::enums::ContentType;
+
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        let from_client = capture_records(&mut client);
+        let from_server = capture_records(&mut server);
+
+        while deliver_records(&from_client, &mut server)
+            + deliver_records(&from_server, &mut client)
+            > 0
+        {
+            assert!(!client.wants_write());
+            assert!(!server.wants_write());
+        }
+        assert!(!client.is_handshaking());
+        assert!(!server.is_handshaking());
+
+        client
+            .writer()
+            .write_all(b"hello")
+            .unwrap();
+        assert_eq!(deliver_records(&from_client, &mut server), 1);
+        check_read(&mut server.reader(), b"hello");
+
+        let client_types = from_client
+            .lock()
+            .unwrap()
+            .types
+            .clone();
+        let server_types = from_server
+            .lock()
+            .unwrap()
+            .types
+            .clone();
+        assert_eq!(
+            client_types.first(),
+            Some(&(ContentType::Handshake, ContentType::Handshake))
+        );
+        assert_eq!(
+            client_types.last(),
+            Some(&(ContentType::ApplicationData, ContentType::ApplicationData))
+        );
+
+        // TLS1.3 hides the type of encrypted records, but the
+        // callback still learns it.
+        let hidden = (ContentType::Handshake, ContentType::ApplicationData);
+        assert_eq!(
+            server_types.contains(&hidden),
+            version.version == ProtocolVersion::TLSv1_3
+        );
+    }
+}
+
+#[test]
+fn record_callback_sees_plaintext_type_of_queued_records() {
+    use rustls::internal::msgs::enums::ContentType;
+
+    let handshake = (ContentType::Handshake, ContentType::Handshake);
+    let hidden = (ContentType::Handshake, ContentType::ApplicationData);
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+
+    // The server's whole first flight is queued, most of it encrypted.
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    let from_server = capture_records(&mut server);
+    {
+        let types = &from_server.lock().unwrap().types;
+        assert_eq!(types.first(), Some(&handshake));
+        assert!(types.contains(&hidden));
+    }
+    deliver_records(&from_server, &mut client);
+
+    // The client's ClientHello was written out, but its Finished is queued.
+    let from_client = capture_records(&mut client);
+    {
+        let types = &from_client.lock().unwrap().types;
+        assert!(!types.contains(&handshake));
+        assert_eq!(types.last(), Some(&hidden));
+    }
+    deliver_records(&from_client, &mut server);
+    assert!(!client.is_handshaking());
+    assert!(!server.is_handshaking());
+}
+
+#[test]
+fn record_callback_refuses_partly_written_data() {
+    let mut client = ClientConnection::new(
+        Arc::new(make_client_config(KeyType::Rsa)),
+        dns_name("localhost"),
+    )
+    .unwrap();
+    let mut buf = [0u8; 3];
+    assert_eq!(
+        client
+            .write_tls(&mut &mut buf[..])
+            .unwrap(),
+        3
+    );
+
+    let err = client
+        .set_record_callback(Box::new(|_: &rustls::OutgoingRecord<'_>| {}))
+        .unwrap_err();
+    assert_eq!(
+        err,
+        Error::General("cannot take over partly written TLS data".into())
+    );
+    assert!(client.wants_write());
+}
+
+struct ServerStorage {
+    storage: Arc<dyn rustls::server::StoresServerSessions>,
+    put_count: AtomicUsize,
+    get_count: AtomicUsize,
+    take_count: AtomicUsize,
+}
+
+impl ServerStorage {
+    fn new() -> Self {
+        ServerStorage {
+            storage: rustls::server::ServerSessionMemoryCache::new(1024),
+            put_count: AtomicUsize::new(0),
+            get_count: AtomicUsize::new(0),
+            take_count: AtomicUsize::new(0),
+        }
+    }
+
+    fn puts(&self) -> usize {
+        self.put_count.load(Ordering::SeqCst)
+    }
+    fn gets(&self) -> usize {
+        self.get_count.load(Ordering::SeqCst)
+    }
+    fn takes(&self) -> usize {
+        self.take_count.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for ServerStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "(put: {:?}, get: {:?}, take: {:?})",
+            self.put_count, self.get_count, self.take_count
+        )
+    }
+}
+
+impl rustls::server::StoresServerSessions for ServerStorage {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.put_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.get(key)
+    }
+
+    fn take(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.take_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.take(key)
+    }
+
+    fn can_cache(&self) -> bool {
+        true
+    }
+}
+
+struct ClientStorage {
+    storage: Arc<dyn rustls::client::StoresClientSessions>,
+    put_count: AtomicUsize,
+    get_count: AtomicUsize,
+    last_put_key: Mutex<Option<Vec<u8>>>,
+}
+
+impl ClientStorage {
+    fn new() -> Self {
+        ClientStorage {
+            storage: rustls::client::ClientSessionMemoryCache::new(1024),
+            put_count: AtomicUsize::new(0),
+            get_count: AtomicUsize::new(0),
+            last_put_key: Mutex::new(None),
+        }
+    }
+
+    fn puts(&self) -> usize {
+        self.put_count.load(Ordering::SeqCst)
+    }
+    fn gets(&self) -> usize {
+        self.get_count.load(Ordering::SeqCst)
+    }
+}
+
+impl fmt::Debug for ClientStorage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "(puts: {:?}, gets: {:?} )",
+            self.put_count, self.get_count
+        )
+    }
+}
+
+impl rustls::client::StoresClientSessions for ClientStorage {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.put_count
+            .fetch_add(1, Ordering::SeqCst);
+        *self.last_put_key.lock().unwrap() = Some(key.clone());
+        self.storage.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.get_count
+            .fetch_add(1, Ordering::SeqCst);
+        self.storage.get(key)
+    }
+}
+
+#[test]
+fn tls13_stateful_resumption() {
+    let kt = KeyType::Rsa;
+    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    let storage = Arc::new(ServerStorage::new());
+    server_config.session_storage = storage.clone();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(storage.puts(), 1);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
+    assert!(resume_c2s > full_c2s);
+    assert!(resume_s2c < full_s2c);
+    assert_eq!(storage.puts(), 2);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 1);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+
+    // resumed again
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(resume_s2c, resume2_s2c);
+    assert_eq!(resume_c2s, resume2_c2s);
+    assert_eq!(storage.puts(), 3);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 2);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+}
+
+#[test]
+fn tls13_stateless_resumption() {
+    let kt = KeyType::Rsa;
+    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
+    let storage = Arc::new(ServerStorage::new());
+    server_config.session_storage = storage.clone();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (full_c2s, full_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(storage.puts(), 0);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+
+    // resumed
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume_c2s, resume_s2c) = do_handshake(&mut client, &mut server);
+    assert!(resume_c2s > full_c2s);
+    assert!(resume_s2c < full_s2c);
+    assert_eq!(storage.puts(), 0);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+
+    // resumed again
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let (resume2_c2s, resume2_s2c) = do_handshake(&mut client, &mut server);
+    assert_eq!(resume_s2c, resume2_s2c);
+    assert_eq!(resume_c2s, resume2_c2s);
+    assert_eq!(storage.puts(), 0);
+    assert_eq!(storage.gets(), 0);
+    assert_eq!(storage.takes(), 0);
+    assert_eq!(
+        client
+            .peer_certificates()
+            .map(|certs| certs.len()),
+        Some(3)
+    );
+}
+
+/// Keeps the tickets issued by `ticketer`.
+struct RecordingTicketer {
+    ticketer: Arc<dyn rustls::server::ProducesTickets>,
+    issued: Mutex<Vec<Vec<u8>>>,
+}
+
+impl rustls::server::ProducesTickets for RecordingTicketer {
+    fn enabled(&self) -> bool {
+        self.ticketer.enabled()
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.ticketer.lifetime()
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let ticket = self.ticketer.encrypt(plain)?;
+        self.issued
+            .lock()
+            .unwrap()
+            .push(ticket.clone());
+        Some(ticket)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.ticketer.decrypt(cipher)
+    }
+}
+
+#[test]
+fn tls13_selected_psk_identity_is_resumption_ticket() {
+    let kt = KeyType::Rsa;
+    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    let client_config = Arc::new(client_config);
+
+    let ticketer = Arc::new(RecordingTicketer {
+        ticketer: rustls::Ticketer::new().unwrap(),
+        issued: Mutex::new(Vec::new()),
+    });
+    let mut server_config = make_server_config(kt);
+    server_config.ticketer = ticketer.clone();
+    let server_config = Arc::new(server_config);
+
+    // full handshake
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(!server.is_resumed());
+    assert_eq!(server.selected_psk_identity(), None);
+    let ticket = ticketer
+        .issued
+        .lock()
+        .unwrap()
+        .pop()
+        .unwrap();
+
+    // resumed with that ticket
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(server.is_resumed());
+    assert_eq!(server.selected_psk_identity(), Some(&ticket[..]));
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn tls12_resumption_selects_no_psk_identity() {
+    let kt = KeyType::Rsa;
+    let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS12]);
+    let client_config = Arc::new(client_config);
+    let server_config = Arc::new(make_server_config(kt));
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(server.is_resumed());
+    assert_eq!(server.selected_psk_identity(), None);
+}
+
+struct ResolvesNoCert;
+
+impl ResolvesServerCert for ResolvesNoCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<sign::CertifiedKey>> {
+        None
+    }
+}
+
+fn make_external_psk(identity: &[u8], secret: &[u8]) -> rustls::ExternalPsk {
+    rustls::ExternalPsk::new(identity.to_vec(), secret.to_vec(), &ring::digest::SHA256).unwrap()
+}
+
+#[test]
+fn external_psk_rejects_bad_parameters() {
+    assert!(rustls::ExternalPsk::new(vec![], vec![1], &ring::digest::SHA256).is_err());
+    assert!(rustls::ExternalPsk::new(vec![0; 0x10000], vec![1], &ring::digest::SHA256).is_err());
+    assert!(rustls::ExternalPsk::new(vec![1], vec![], &ring::digest::SHA256).is_err());
+    assert!(rustls::ExternalPsk::new(vec![1], vec![1], &ring::digest::SHA512).is_err());
+
+    let psk = rustls::ExternalPsk::new(vec![1], vec![2], &ring::digest::SHA384).unwrap();
+    assert_eq!(psk.identity(), &[1]);
+    assert_eq!(psk.hash_algorithm(), &ring::digest::SHA384);
+}
+
+#[test]
+fn external_psk_handshake_needs_no_certificates() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+        client_config.external_psks = vec![make_external_psk(b"client1", b"secret")];
+
+        let mut server_config = make_server_config(*kt);
+        server_config.cert_resolver = Arc::new(ResolvesNoCert);
+        server_config.external_psks = vec![
+            make_external_psk(b"client0", b"other secret"),
+            make_external_psk(b"client1", b"secret"),
+        ];
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+        assert_eq!(server.selected_psk_identity(), Some(&b"client1"[..]));
+        assert!(!server.is_resumed());
+        assert!(client.peer_certificates().is_none());
+        assert!(server.peer_certificates().is_none());
+    }
+}
+
+#[test]
+fn external_psk_sessions_are_not_resumed() {
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.external_psks = vec![make_external_psk(b"client1", b"secret")];
+    let client_config = Arc::new(client_config);
+
+    let mut server_config = make_server_config(kt);
+    server_config.external_psks = vec![make_external_psk(b"client1", b"secret")];
+    let server_config = Arc::new(server_config);
+
+    for _ in 0..2 {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(!server.is_resumed());
+        assert_eq!(server.selected_psk_identity(), Some(&b"client1"[..]));
+    }
+}
+
+#[test]
+fn external_psk_handshake_after_hello_retry_request() {
+    let mut client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+    client_config.external_psks = vec![make_external_psk(b"client1", b"secret")];
+
+    let mut server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    server_config.cert_resolver = Arc::new(ResolvesNoCert);
+    server_config.external_psks = vec![make_external_psk(b"client1", b"secret")];
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client.negotiated_key_exchange_group(),
+        Some(NamedGroup::X25519)
+    );
+    assert_eq!(server.selected_psk_identity(), Some(&b"client1"[..]));
+}
+
+#[test]
+fn unknown_external_psk_falls_back_to_certificates() {
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.external_psks = vec![make_external_psk(b"client1", b"secret")];
+
+    let mut server_config = make_server_config(kt);
+    server_config.external_psks = vec![make_external_psk(b"client2", b"secret")];
+    let server_config = Arc::new(server_config);
+
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.selected_psk_identity(), None);
+    assert!(client.peer_certificates().is_some());
+
+    // without a certificate, there is nothing to fall back to
+    let mut server_config = (*server_config).clone();
+    server_config.cert_resolver = Arc::new(ResolvesNoCert);
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.external_psks = vec![make_external_psk(b"client1", b"secret")];
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert!(matches!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::General(_)))
+    ));
+}
+
+#[test]
+fn external_psk_with_wrong_secret_fails() {
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+    client_config.external_psks = vec![make_external_psk(b"client1", b"secret")];
+
+    let mut server_config = make_server_config(kt);
+    server_config.external_psks = vec![make_external_psk(b"client1", b"wrong secret")];
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert_eq!(
+        do_handshake_until_error(&mut client, &mut server),
+        Err(ErrorFromPeer::Server(Error::PeerMisbehavedError(
+            "client sent wrong binder".into()
+        )))
+    );
+}
+
+#[test]
+fn external_psk_is_only_offered_with_a_suite_of_its_hash() {
+    let kt = KeyType::Rsa;
+    let mut client_config =
+        make_client_config_with_suites(kt, &[rustls::cipher_suite::TLS13_AES_128_GCM_SHA256]);
+    client_config.external_psks = vec![rustls::ExternalPsk::new(
+        b"client1".to_vec(),
+        b"secret".to_vec(),
+        &ring::digest::SHA384,
+    )
+    .unwrap()];
+
+    let mut server_config = make_server_config(kt);
+    server_config.external_psks = client_config.external_psks.clone();
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(server.selected_psk_identity(), None);
+    assert!(client.peer_certificates().is_some());
+}
+
+struct TicketPoolStorage {
+    storage: Arc<dyn rustls::client::StoresClientSessions>,
+    stored: Mutex<Vec<Vec<u8>>>,
+    taken: Mutex<Vec<Vec<u8>>>,
+}
+
+impl TicketPoolStorage {
+    fn new() -> Self {
+        TicketPoolStorage {
+            storage: rustls::client::ClientSessionMemoryCache::new(1024),
+            stored: Mutex::new(Vec::new()),
+            taken: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn stored(&self) -> Vec<Vec<u8>> {
+        self.stored.lock().unwrap().clone()
+    }
+
+    fn taken(&self) -> Vec<Vec<u8>> {
+        self.taken.lock().unwrap().clone()
+    }
+}
+
+impl rustls::client::StoresClientSessions for TicketPoolStorage {
+    fn put(&self, key: Vec<u8>, value: Vec<u8>) -> bool {
+        self.storage.put(key, value)
+    }
+
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.storage.get(key)
+    }
+
+    fn put_ticket(&self, key: Vec<u8>, value: Vec<u8>, max: usize) -> bool {
+        self.stored
+            .lock()
+            .unwrap()
+            .push(value.clone());
+        self.storage.put_ticket(key, value, max)
+    }
+
+    fn take_ticket(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let value = self.storage.take_ticket(key)?;
+        self.taken
+            .lock()
+            .unwrap()
+            .push(value.clone());
+        Some(value)
+    }
+}
+
+fn make_ticket_pool_configs(
+    pool_size: usize,
+) -> (Arc<ClientConfig>, Arc<ServerConfig>, Arc<TicketPoolStorage>) {
+    let storage = Arc::new(TicketPoolStorage::new());
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.session_storage = storage.clone();
+    client_config.max_tickets_per_server = Some(pool_size);
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.ticketer = rustls::Ticketer::new().unwrap();
+
+    (Arc::new(client_config), Arc::new(server_config), storage)
+}
+
+/// Does `count` full handshakes side by side, so each is given its own
+/// ticket, then delivers all of the tickets to the client.
+fn fill_ticket_pool(
+    client_config: &Arc<ClientConfig>,
+    server_config: &Arc<ServerConfig>,
+    count: usize,
+) {
+    // Every ClientHello is sent before any ticket arrives.
+    let mut pairs = (0..count)
+        .map(|_| make_pair_for_arc_configs(client_config, server_config))
+        .collect::<Vec<_>>();
+    for (client, server) in pairs.iter_mut() {
+        do_handshake(client, server);
+        transfer(server, client);
+        client.process_new_packets().unwrap();
+    }
+}
+
+/// Does a handshake without delivering the tickets the server sends
+/// afterwards, so the client's ticket pool is only drained.
+fn handshake_is_resumed(
+    client_config: &Arc<ClientConfig>,
+    server_config: &Arc<ServerConfig>,
+) -> bool {
+    let (mut client, mut server) = make_pair_for_arc_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    let resumed = client
+        .handshake_details()
+        .unwrap()
+        .resumed;
+    assert_eq!(
+        resumed,
+        server
+            .handshake_details()
+            .unwrap()
+            .resumed
+    );
+    resumed
+}
+
+#[test]
+fn tls13_ticket_pool_uses_each_ticket_once() {
+    let (client_config, server_config, storage) = make_ticket_pool_configs(3);
+    fill_ticket_pool(&client_config, &server_config, 3);
+    assert_eq!(storage.stored().len(), 3);
+
+    for _ in 0..3 {
+        assert!(handshake_is_resumed(&client_config, &server_config));
+    }
+
+    let taken = storage.taken();
+    assert_eq!(taken, storage.stored());
+    for (i, ticket) in taken.iter().enumerate() {
+        assert!(!taken[..i].contains(ticket));
+    }
+
+    // The pool is now exhausted.
+    assert!(!handshake_is_resumed(&client_config, &server_config));
+    assert_eq!(storage.taken().len(), 3);
+}
+
+#[test]
+fn tls13_ticket_pool_keeps_newest_tickets() {
+    let (client_config, server_config, storage) = make_ticket_pool_configs(2);
+    fill_ticket_pool(&client_config, &server_config, 3);
+    assert_eq!(storage.stored().len(), 3);
+
+    assert!(handshake_is_resumed(&client_config, &server_config));
+    assert!(handshake_is_resumed(&client_config, &server_config));
+    assert!(!handshake_is_resumed(&client_config, &server_config));
+    assert_eq!(storage.taken(), storage.stored()[1..].to_vec());
+}
+
+#[test]
+fn tls13_ticket_pool_is_refilled_by_new_tickets() {
+    let (client_config, server_config, storage) = make_ticket_pool_configs(1);
+
+    for _ in 0..3 {
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+    }
+
+    assert_eq!(storage.stored().len(), 3);
+    assert_eq!(storage.taken(), storage.stored()[..2].to_vec());
+}
+
+#[test]
+fn server_sends_configured_number_of_tls13_tickets() {
+    for &(configured, expected) in &[
+        (0, 0),
+        (1, 1),
+        (2, 2),
+        (5, 5),
+        (8, 8),
+        (9, 8),
+        (usize::MAX, 8),
+    ] {
+        let (client_config, server_config, storage) = make_ticket_pool_configs(16);
+        let mut server_config = (*server_config).clone();
+        server_config.send_tls13_tickets = configured;
+        let server_config = Arc::new(server_config);
+
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        assert_eq!(storage.stored().len(), expected);
+
+        assert_eq!(
+            handshake_is_resumed(&client_config, &server_config),
+            expected > 0
+        );
+    }
+}
+
+#[test]
+fn early_data_not_available() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert!(client.early_data().is_none());
+}
+
+fn early_data_configs() -> (Arc<ClientConfig>, Arc<ServerConfig>) {
+    let kt = KeyType::Rsa;
+    let mut client_config = make_client_config(kt);
+    client_config.enable_early_data = true;
+    client_config.session_storage = Arc::new(ClientStorage::new());
+
+    let mut server_config = make_server_config(kt);
+    server_config.max_early_data_size = 1234;
+    (Arc::new(client_config), Arc::new(server_config))
+}
+
+#[test]
+fn early_data_is_available_on_resumption() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert!(client.early_data().is_some());
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .bytes_left(),
+        1234
+    );
+    client
+        .early_data()
+        .unwrap()
+        .flush()
+        .unwrap();
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+    do_handshake(&mut client, &mut server);
+
+    let mut received_early_data = [0u8; 5];
+    assert_eq!(
+        server
+            .early_data()
+            .expect("early_data didn't happen")
+            .read(&mut received_early_data)
+            .expect("early_data failed unexpectedly"),
+        5
+    );
+    assert_eq!(&received_early_data[..], b"hello");
+}
+
+#[test]
+fn client_writer_sends_early_data() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(5, client.writer().write(b"hello").unwrap());
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .bytes_left(),
+        1229
+    );
+    do_handshake(&mut client, &mut server);
+    assert!(client.is_early_data_accepted());
+
+    let mut early_data = Vec::new();
+    server
+        .early_data()
+        .unwrap()
+        .read_to_end(&mut early_data)
+        .unwrap();
+    assert_eq!(early_data, b"hello");
+
+    // Nothing more follows the handshake.
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    let mut buf = [0u8; 5];
+    let err = server
+        .reader()
+        .read(&mut buf)
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn client_writer_waits_for_handshake_beyond_early_data_limit() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let data = (0..2000)
+        .map(|i| i as u8)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        1000,
+        client
+            .writer()
+            .write(&data[..1000])
+            .unwrap()
+    );
+    assert_eq!(
+        1000,
+        client
+            .writer()
+            .write(&data[1000..])
+            .unwrap()
+    );
+    assert_eq!(
+        client.send_early_data(b"more"),
+        Err(EarlyDataError::LimitReached)
+    );
+    do_handshake(&mut client, &mut server);
+
+    let mut early_data = Vec::new();
+    server
+        .early_data()
+        .unwrap()
+        .read_to_end(&mut early_data)
+        .unwrap();
+    assert_eq!(early_data, &data[..1234]);
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), &data[1234..]);
+}
+
+#[test]
+fn client_writer_resends_rejected_early_data() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(client.send_early_data(b"early "), Ok(6));
+    assert_eq!(5, client.writer().write(b"hello").unwrap());
+    server.reject_early_data();
+    do_handshake(&mut client, &mut server);
+
+    // Only what was sent with `send_early_data` is the application's to resend.
+    assert!(!client.is_early_data_accepted());
+    assert_eq!(
+        client.send_early_data(b""),
+        Err(EarlyDataError::Rejected { resend_len: 6 })
+    );
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"hello");
+}
+
+#[test]
+fn early_data_bytes_left_tracks_writes() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    let mut sent = Vec::new();
+    let data = [0x55u8; 500];
+    for &(want_written, want_left) in &[(500, 734), (500, 234), (234, 0), (0, 0)] {
+        let mut early_data = client.early_data().unwrap();
+        assert_eq!(early_data.write(&data).unwrap(), want_written);
+        assert_eq!(early_data.bytes_left(), want_left);
+        sent.extend_from_slice(&data[..want_written]);
+    }
+
+    do_handshake(&mut client, &mut server);
+    let mut received = Vec::new();
+    server
+        .early_data()
+        .unwrap()
+        .read_to_end(&mut received)
+        .unwrap();
+    assert_eq!(received, sent);
+}
+
+#[test]
+fn early_data_bytes_left_ignores_unsent_writes() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    let mut client_hello = Vec::new();
+    client
+        .write_tls(&mut client_hello)
+        .unwrap();
+    client.set_buffer_limit(Some(100));
+
+    // The buffer limit, rather than the server, makes these writes short.
+    let data = [0x55u8; 500];
+    let mut early_data = client.early_data().unwrap();
+    assert_eq!(early_data.write(&data).unwrap(), 100);
+    assert_eq!(early_data.bytes_left(), 1134);
+    assert_eq!(early_data.write(&data).unwrap(), 0);
+    assert_eq!(early_data.bytes_left(), 1134);
+}
+
+#[test]
+fn early_data_not_available_on_server_before_client_hello() {
+    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
+    assert!(server.early_data().is_none());
+}
+
+#[test]
+fn early_data_can_be_rejected_by_server() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert!(client.early_data().is_some());
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .bytes_left(),
+        1234
+    );
+    client
+        .early_data()
+        .unwrap()
+        .flush()
+        .unwrap();
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b"hello")
+            .unwrap(),
+        5
+    );
+    server.reject_early_data();
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(client.is_early_data_accepted(), false);
+}
+
+#[test]
+fn send_early_data_not_available() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert_eq!(
+        client.send_early_data(b"hello"),
+        Err(EarlyDataError::Unavailable)
+    );
+
+    // A ticket from a server that doesn't allow early data is no help.
+    let (client_config, _) = early_data_configs();
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, _) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(
+        client.send_early_data(b"hello"),
+        Err(EarlyDataError::Unavailable)
+    );
+}
+
+#[test]
+fn send_early_data_accepted() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(client.send_early_data(b"hello"), Ok(5));
+    let big = vec![0u8; 2000];
+    assert_eq!(client.send_early_data(&big), Ok(1234 - 5));
+    assert_eq!(
+        client.send_early_data(b"more"),
+        Err(EarlyDataError::LimitReached)
+    );
+    do_handshake(&mut client, &mut server);
+
+    assert!(client.is_early_data_accepted());
+    assert_eq!(
+        client.send_early_data(b"more"),
+        Err(EarlyDataError::Finished)
+    );
+    let mut received_early_data = [0u8; 1234];
+    assert_eq!(
+        server
+            .early_data()
+            .expect("early_data didn't happen")
+            .read(&mut received_early_data)
+            .expect("early_data failed unexpectedly"),
+        1234
+    );
+    assert_eq!(&received_early_data[..5], b"hello");
+}
+
+#[test]
+fn send_early_data_rejected() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(client.send_early_data(b"hello"), Ok(5));
+    assert_eq!(
+        client
+            .early_data()
+            .unwrap()
+            .write(b" world")
+            .unwrap(),
+        6
+    );
+    server.reject_early_data();
+    do_handshake(&mut client, &mut server);
+
+    assert!(!client.is_early_data_accepted());
+    assert_eq!(
+        client.send_early_data(b""),
+        Err(EarlyDataError::Rejected { resend_len: 11 })
+    );
+
+    // The application resends it over the established connection.
+    client
+        .writer()
+        .write_all(b"hello world")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"hello world");
+}
+
+struct ReplayChecker {
+    allow: bool,
+    checked: Mutex<Vec<Vec<u8>>>,
+}
+
+impl ReplayChecker {
+    fn new(allow: bool) -> Arc<Self> {
+        Arc::new(Self {
+            allow,
+            checked: Mutex::new(vec![]),
+        })
+    }
+
+    fn checked(&self) -> usize {
+        self.checked.lock().unwrap().len()
+    }
+}
+
+impl rustls::server::ChecksEarlyDataReplay for ReplayChecker {
+    fn single_use_ticket_check(&self, ticket_id: &[u8]) -> bool {
+        self.checked
+            .lock()
+            .unwrap()
+            .push(ticket_id.to_vec());
+        self.allow
+    }
+}
+
+fn early_data_configs_with_anti_replay(
+    checker: Arc<ReplayChecker>,
+) -> (Arc<ClientConfig>, Arc<ServerConfig>) {
+    let (client_config, server_config) = early_data_configs();
+    let mut server_config = (*server_config).clone();
+    server_config.early_data_anti_replay = Some(checker);
+    (client_config, Arc::new(server_config))
+}
+
+#[test]
+fn server_accepts_early_data_passing_anti_replay() {
+    let checker = ReplayChecker::new(true);
+    let (client_config, server_config) = early_data_configs_with_anti_replay(checker.clone());
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    assert!(!server.is_early_data_accepted());
+    assert_eq!(checker.checked(), 0);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(client.send_early_data(b"hello"), Ok(5));
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(checker.checked(), 1);
+    assert!(server.is_early_data_accepted());
+    assert!(client.is_early_data_accepted());
+    check_read(&mut server.early_data().unwrap(), b"hello");
+}
+
+#[test]
+fn server_rejects_early_data_failing_anti_replay() {
+    let checker = ReplayChecker::new(false);
+    let (client_config, server_config) = early_data_configs_with_anti_replay(checker.clone());
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    assert_eq!(client.send_early_data(b"hello"), Ok(5));
+    do_handshake(&mut client, &mut server);
+
+    assert_eq!(checker.checked(), 1);
+    assert!(!server.is_early_data_accepted());
+    assert!(server.early_data().is_none());
+    assert_eq!(
+        client.send_early_data(b""),
+        Err(EarlyDataError::Rejected { resend_len: 5 })
+    );
+
+    // The connection itself was resumed and works.
+    assert!(
+        client
+            .handshake_details()
+            .unwrap()
+            .resumed
+    );
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"hello");
+}
+
+#[test]
+fn server_rejects_too_much_early_data() {
+    let (client_config, server_config) = early_data_configs();
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+
+    // The ticket allows 1234 bytes, but this server only takes 10.
+    let mut small_server_config = (*server_config).clone();
+    small_server_config.max_early_data_size = 10;
+    let small_server_config = Arc::new(small_server_config);
+
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &small_server_config);
+    assert_eq!(client.send_early_data(&[0u8; 20]), Ok(20));
+    transfer(&mut client, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerMisbehavedError(
+            "too much early_data received".into()
+        ))
+    );
+}
+
+#[cfg(feature = "quic")]
+mod test_quic {
+    use super::*;
+    use rustls::Connection;
+
+    // Returns the sender's next secrets to use, or the receiver's error.
+    fn step(
+        send: &mut dyn QuicExt,
+        recv: &mut dyn QuicExt,
+    ) -> Result<Option<quic::KeyChange>, Error> {
+        let mut buf = Vec::new();
+        let change = loop {
+            let prev = buf.len();
+            if let Some(x) = send.write_hs(&mut buf) {
+                break Some(x);
+            }
+            if prev == buf.len() {
+                break None;
+            }
+        };
+        if let Err(e) = recv.read_hs(&buf) {
+            return Err(e);
+        } else {
+            assert_eq!(recv.alert(), None);
+        }
+
+        Ok(change)
+    }
+
+    fn equal_packet_keys(x: &quic::PacketKey, y: &quic::PacketKey) -> bool {
+        // Check that these two sets of keys are equal.
+        let mut buf = vec![0; 32];
+        let (header, payload_tag) = buf.split_at_mut(8);
+        let (payload, tag_buf) = payload_tag.split_at_mut(8);
+        let tag = x
+            .encrypt_in_place(42, &*header, payload)
+            .unwrap();
+        tag_buf.copy_from_slice(tag.as_ref());
+
+        let result = y.decrypt_in_place(42, &*header, payload_tag);
+        match result {
+            Ok(payload) => payload == &[0; 8],
+            Err(_) => false,
+        }
+    }
+
+    #[test]
+    fn test_quic_handshake() {
+        fn compatible_keys(x: &quic::KeyChange, y: &quic::KeyChange) -> bool {
+            fn keys(kc: &quic::KeyChange) -> &quic::Keys {
+                match kc {
+                    quic::KeyChange::Handshake { keys } => keys,
+                    quic::KeyChange::OneRtt { keys, .. } => keys,
+                }
+            }
+
+            let (x, y) = (keys(x), keys(y));
+            equal_packet_keys(&x.local.packet, &y.remote.packet)
+                && equal_packet_keys(&x.remote.packet, &y.local.packet)
+        }
+
+        let kt = KeyType::Rsa;
+        let mut client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+        client_config.enable_early_data = true;
+        let client_config = Arc::new(client_config);
+        let mut server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+        server_config.max_early_data_size = 0xffffffff;
+        let server_config = Arc::new(server_config);
+        let client_params = &b"client params"[..];
+        let server_params = &b"server params"[..];
+
+        // full handshake
+        let mut client = Connection::from(
+            ClientConnection::new_quic(
+                Arc::clone(&client_config),
+                quic::Version::V1,
+                dns_name("localhost"),
+                client_params.into(),
+            )
+            .unwrap(),
+        );
+
+        let mut server = Connection::from(
+            ServerConnection::new_quic(
+                Arc::clone(&server_config),
+                quic::Version::V1,
+                server_params.into(),
+            )
+            .unwrap(),
+        );
+
+        let client_initial = step(&mut client, &mut server).unwrap();
+        assert!(client_initial.is_none());
+        assert!(client.zero_rtt_keys().is_none());
+        assert_eq!(server.quic_transport_parameters(), Some(client_params));
+        let server_hs = step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(server.zero_rtt_keys().is_none());
+        let client_hs = step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        assert!(compatible_keys(&server_hs, &client_hs));
+        assert!(client.is_handshaking());
+        let server_1rtt = step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(!client.is_handshaking());
+        assert_eq!(client.quic_transport_parameters(), Some(server_params));
+        assert!(server.is_handshaking());
+        let client_1rtt = step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        assert!(!server.is_handshaking());
+        assert!(compatible_keys(&server_1rtt, &client_1rtt));
+        assert!(!compatible_keys(&server_hs, &server_1rtt));
+        assert!(step(&mut client, &mut server)
+            .unwrap()
+            .is_none());
+        assert!(step(&mut server, &mut client)
+            .unwrap()
+            .is_none());
+
+        // 0-RTT handshake
+        let mut client = ClientConnection::new_quic(
+            Arc::clone(&client_config),
+            quic::Version::V1,
+            dns_name("localhost"),
+            client_params.into(),
+        )
+        .unwrap();
+        assert!(client
+            .negotiated_cipher_suite()
+            .is_some());
+
+        let mut server = ServerConnection::new_quic(
+            Arc::clone(&server_config),
+            quic::Version::V1,
+            server_params.into(),
+        )
+        .unwrap();
+
+        step(&mut client, &mut server).unwrap();
+        assert_eq!(client.quic_transport_parameters(), Some(server_params));
+        assert_eq!(client.quic_transport_parameters_extension(), None);
+        {
+            let client_early = client.zero_rtt_keys().unwrap();
+            let server_early = server.zero_rtt_keys().unwrap();
+            assert!(equal_packet_keys(
+                &client_early.packet,
+                &server_early.packet
+            ));
+        }
+        step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(client.is_early_data_accepted());
+
+        // 0-RTT rejection
+        {
+            let client_config = (*client_config).clone();
+            let mut client = ClientConnection::new_quic(
+                Arc::new(client_config),
+                quic::Version::V1,
+                dns_name("localhost"),
+                client_params.into(),
+            )
+            .unwrap();
+
+            let mut server = ServerConnection::new_quic(
+                Arc::clone(&server_config),
+                quic::Version::V1,
+                server_params.into(),
+            )
+            .unwrap();
+
+            step(&mut client, &mut server).unwrap();
+            assert_eq!(client.quic_transport_parameters(), Some(server_params));
+            assert!(client.zero_rtt_keys().is_some());
+            assert!(server.zero_rtt_keys().is_none());
+            step(&mut server, &mut client)
+                .unwrap()
+                .unwrap();
+            step(&mut client, &mut server)
+                .unwrap()
+                .unwrap();
+            step(&mut server, &mut client)
+                .unwrap()
+                .unwrap();
+            assert!(!client.is_early_data_accepted());
+        }
+
+        // failed handshake
+        let mut client = ClientConnection::new_quic(
+            client_config,
+            quic::Version::V1,
+            dns_name("example.com"),
+            client_params.into(),
+        )
+        .unwrap();
+
+        let mut server =
+            ServerConnection::new_quic(server_config, quic::Version::V1, server_params.into())
+                .unwrap();
+
+        step(&mut client, &mut server).unwrap();
+        step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(step(&mut server, &mut client).is_err());
+        assert_eq!(
+            client.alert(),
+            Some(rustls::internal::msgs::enums::AlertDescription::BadCertificate)
+        );
+
+        // Key updates
+
+        let (mut client_secrets, mut server_secrets) = match (client_1rtt, server_1rtt) {
+            (quic::KeyChange::OneRtt { next: c, .. }, quic::KeyChange::OneRtt { next: s, .. }) => {
+                (c, s)
+            }
+            _ => unreachable!(),
+        };
+
+        let mut client_next = client_secrets.next_packet_keys();
+        let mut server_next = server_secrets.next_packet_keys();
+        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
+        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+
+        client_next = client_secrets.next_packet_keys();
+        server_next = server_secrets.next_packet_keys();
+        assert!(equal_packet_keys(&client_next.local, &server_next.remote));
+        assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+    }
+
+    #[test]
+    fn test_quic_transport_parameters_extension() {
+        use rustls::ExtensionType;
+
+        let client_params = &b"client params"[..];
+        let server_params = &b"server params"[..];
+
+        for &(version, typ) in &[
+            (quic::Version::V1, ExtensionType::TransportParameters),
+            (
+                quic::Version::V1Draft,
+                ExtensionType::TransportParametersDraft,
+            ),
+        ] {
+            let client_config =
+                make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+            let server_config =
+                make_server_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+            let mut client = ClientConnection::new_quic(
+                Arc::new(client_config),
+                version,
+                dns_name("localhost"),
+                client_params.into(),
+            )
+            .unwrap();
+            let mut server =
+                ServerConnection::new_quic(Arc::new(server_config), version, server_params.into())
+                    .unwrap();
+            assert_eq!(client.quic_transport_parameters_extension(), None);
+            assert_eq!(server.quic_transport_parameters_extension(), None);
+
+            step(&mut client, &mut server).unwrap();
+            assert_eq!(
+                server.quic_transport_parameters_extension(),
+                Some((typ, client_params))
+            );
+            assert_eq!(client.quic_transport_parameters_extension(), None);
+
+            step(&mut server, &mut client).unwrap();
+            assert_eq!(
+                client.quic_transport_parameters_extension(),
+                Some((typ, server_params))
+            );
+        }
+    }
+
+    #[test]
+    fn test_quic_next_1rtt_keys() {
+        let client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+        let server_config =
+            make_server_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+        let mut client = ClientConnection::new_quic(
+            Arc::new(client_config),
+            quic::Version::V1,
+            dns_name("localhost"),
+            b"client params".to_vec(),
+        )
+        .unwrap();
+        let mut server = ServerConnection::new_quic(
+            Arc::new(server_config),
+            quic::Version::V1,
+            b"server params".to_vec(),
+        )
+        .unwrap();
+
+        step(&mut client, &mut server).unwrap();
+        step(&mut server, &mut client).unwrap();
+        step(&mut client, &mut server).unwrap();
+        assert!(client.next_1rtt_keys().is_none());
+        assert!(server.next_1rtt_keys().is_none());
+
+        let server_1rtt = step(&mut server, &mut client)
+            .unwrap()
+            .unwrap();
+        assert!(client.next_1rtt_keys().is_none());
+        let client_1rtt = step(&mut client, &mut server)
+            .unwrap()
+            .unwrap();
+        let (mut client_secrets, mut server_secrets) = match (client_1rtt, server_1rtt) {
+            (quic::KeyChange::OneRtt { next: c, .. }, quic::KeyChange::OneRtt { next: s, .. }) => {
+                (c, s)
+            }
+            _ => unreachable!(),
+        };
+
+        let mut previous: Option<quic::PacketKeySet> = None;
+        for _ in 0..3 {
+            let client_next = client.next_1rtt_keys().unwrap();
+            let server_next = server.next_1rtt_keys().unwrap();
+            assert!(equal_packet_keys(&client_next.local, &server_next.remote));
+            assert!(equal_packet_keys(&server_next.local, &client_next.remote));
+
+            // The same generation as the secrets handed out with the 1-RTT keys.
+            let expected = client_secrets.next_packet_keys();
+            assert!(equal_packet_keys(&client_next.local, &expected.local));
+            assert!(equal_packet_keys(&client_next.remote, &expected.remote));
+            let expected = server_secrets.next_packet_keys();
+            assert!(equal_packet_keys(&server_next.local, &expected.local));
+
+            if let Some(previous) = &previous {
+                assert!(!equal_packet_keys(&previous.local, &client_next.local));
+            }
+            previous = Some(client_next);
+        }
+    }
+
+    #[test]
+    fn test_quic_connections_refuse_plaintext() {
+        let client_config =
+            make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+        let mut client = ClientConnection::new_quic(
+            Arc::new(client_config),
+            quic::Version::V1,
+            dns_name("localhost"),
+            b"client params".to_vec(),
+        )
+        .unwrap();
+
+        let err = client
+            .writer()
+            .write(b"hello")
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_quic_rejects_missing_alpn() {
+        let client_params = &b"client params"[..];
+        let server_params = &b"server params"[..];
+
+        for &kt in ALL_KEY_TYPES.iter() {
+            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+            let client_config = Arc::new(client_config);
+
+            let mut server_config =
+                make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+            server_config.alpn_protocols = vec!["foo".into()];
+            let server_config = Arc::new(server_config);
+
+            let mut client = ClientConnection::new_quic(
+                client_config,
+                quic::Version::V1,
+                dns_name("localhost"),
+                client_params.into(),
+            )
+            .unwrap();
+            let mut server =
+                ServerConnection::new_quic(server_config, quic::Version::V1, server_params.into())
+                    .unwrap();
+
+            assert_eq!(
+                step(&mut client, &mut server)
+                    .err()
+                    .unwrap(),
+                Error::NoApplicationProtocol
+            );
+
+            assert_eq!(
+                server.alert(),
+                Some(rustls::internal::msgs::enums::AlertDescription::NoApplicationProtocol)
+            );
+        }
+    }
+
+    #[test]
+    fn test_quic_no_tls13_error() {
+        let mut client_config =
+            make_client_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
+        client_config.alpn_protocols = vec!["foo".into()];
+        let client_config = Arc::new(client_config);
+
+        assert!(ClientConnection::new_quic(
+            client_config,
+            quic::Version::V1,
+            dns_name("localhost"),
+            b"client params".to_vec(),
+        )
+        .is_err());
+
+        let mut server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS12]);
+        server_config.alpn_protocols = vec!["foo".into()];
+        let server_config = Arc::new(server_config);
+
+        assert!(ServerConnection::new_quic(
+            server_config,
+            quic::Version::V1,
+            b"server params".to_vec(),
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_quic_invalid_early_data_size() {
+        let mut server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
+        server_config.alpn_protocols = vec!["foo".into()];
+
+        let cases = [
+            (None, true),
+            (Some(0u32), true),
+            (Some(5), false),
+            (Some(0xffff_ffff), true),
+        ];
+
+        for &(size, ok) in cases.iter() {
+            println!("early data size case: {:?}", size);
+            if let Some(new) = size {
+                server_config.max_early_data_size = new;
+            }
+
+            let wrapped = Arc::new(server_config.clone());
+            assert_eq!(
+                ServerConnection::new_quic(wrapped, quic::Version::V1, b"server params".to_vec(),)
+                    .is_ok(),
+                ok
+            );
+        }
+    }
+
+    #[test]
+    fn test_quic_server_no_params_received() {
+        let server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
+        let server_config = Arc::new(server_config);
+
+        let mut server =
+            ServerConnection::new_quic(server_config, quic::Version::V1, b"server params".to_vec())
+                .unwrap();
+
+        use ring::rand::SecureRandom;
+        use rustls::internal::msgs::base::PayloadU16;
+        use rustls::internal::msgs::enums::{
+            CipherSuite, Compression, HandshakeType, NamedGroup, SignatureScheme,
+        };
+        use rustls::internal::msgs::handshake::{
+            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionID,
+        };
+        use rustls::internal::msgs::message::PlainMessage;
+
+        let rng = ring::rand::SystemRandom::new();
+        let mut random = [0; 32];
+        rng.fill(&mut random).unwrap();
+        let random = Random::from(random);
+
+        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
+            .unwrap()
+            .compute_public_key()
+            .unwrap();
+
+        let client_hello = Message {
+            version: ProtocolVersion::TLSv1_3,
+            payload: MessagePayload::Handshake(HandshakeMessagePayload {
+                typ: HandshakeType::ClientHello,
+                payload: HandshakePayload::ClientHello(ClientHelloPayload {
+                    client_version: ProtocolVersion::TLSv1_3,
+                    random,
+                    session_id: SessionID::random().unwrap(),
+                    cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
+                    compression_methods: vec![Compression::Null],
+                    extensions: vec![
+                        ClientExtension::SupportedVersions(vec![ProtocolVersion::TLSv1_3]),
+                        ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
+                        ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
+                        ClientExtension::KeyShare(vec![KeyShareEntry {
+                            group: NamedGroup::X25519,
+                            payload: PayloadU16::new(kx.as_ref().to_vec()),
+                        }]),
+                    ],
+                }),
+            }),
+        };
+
+        let buf = PlainMessage::from(client_hello)
+            .into_unencrypted_opaque()
+            .encode();
+        server
+            .read_tls(&mut buf.as_slice())
+            .unwrap();
+        assert_eq!(
+            server.process_new_packets().err(),
+            Some(Error::PeerMisbehavedError(
+                "QUIC transport parameters not found".into(),
+            )),
+        );
+    }
+
+    #[test]
+    fn test_quic_server_no_tls12() {
+        let mut server_config =
+            make_server_config_with_versions(KeyType::Ed25519, &[&rustls::version::TLS13]);
+        server_config.alpn_protocols = vec!["foo".into()];
+        let server_config = Arc::new(server_config);
+
+        use ring::rand::SecureRandom;
+        use rustls::internal::msgs::base::PayloadU16;
+        use rustls::internal::msgs::enums::{
+            CipherSuite, Compression, HandshakeType, NamedGroup, SignatureScheme,
+        };
+        use rustls::internal::msgs::handshake::{
+            ClientHelloPayload, HandshakeMessagePayload, KeyShareEntry, Random, SessionID,
+        };
+        use rustls::internal::msgs::message::PlainMessage;
+
+        let rng = ring::rand::SystemRandom::new();
+        let mut random = [0; 32];
+        rng.fill(&mut random).unwrap();
+        let random = Random::from(random);
+
+        let kx = ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, &rng)
+            .unwrap()
+            .compute_public_key()
+            .unwrap();
+
+        let mut server =
+            ServerConnection::new_quic(server_config, quic::Version::V1, b"server params".to_vec())
+                .unwrap();
+
+        let client_hello = Message {
+            version: ProtocolVersion::TLSv1_2,
+            payload: MessagePayload::Handshake(HandshakeMessagePayload {
+                typ: HandshakeType::ClientHello,
+                payload: HandshakePayload::ClientHello(ClientHelloPayload {
+                    client_version: ProtocolVersion::TLSv1_2,
+                    random: random.clone(),
+                    session_id: SessionID::random().unwrap(),
+                    cipher_suites: vec![CipherSuite::TLS13_AES_128_GCM_SHA256],
+                    compression_methods: vec![Compression::Null],
+                    extensions: vec![
+                        ClientExtension::NamedGroups(vec![NamedGroup::X25519]),
+                        ClientExtension::SignatureAlgorithms(vec![SignatureScheme::ED25519]),
+                        ClientExtension::KeyShare(vec![KeyShareEntry {
+                            group: NamedGroup::X25519,
+                            payload: PayloadU16::new(kx.as_ref().to_vec()),
+                        }]),
+                    ],
+                }),
+            }),
+        };
+
+        let buf = PlainMessage::from(client_hello)
+            .into_unencrypted_opaque()
+            .encode();
+        server
+            .read_tls(&mut buf.as_slice())
+            .unwrap();
+        assert_eq!(
+            server.process_new_packets().err(),
+            Some(Error::PeerIncompatibleError(
+                "Server requires TLS1.3, but client omitted versions ext".into(),
+            )),
+        );
+    }
+
+    #[test]
+    fn packet_key_api() {
+        use rustls::quic::{Keys, Version};
+
+        // Test vectors: https://www.rfc-editor.org/rfc/rfc9001.html#name-client-initial
+        const CONNECTION_ID: &[u8] = &[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08];
+        const PACKET_NUMBER: u64 = 2;
+        const PLAIN_HEADER: &[u8] = &[
+            0xc3, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
+            0x00, 0x00, 0x44, 0x9e, 0x00, 0x00, 0x00, 0x02,
+        ];
+
+        const PAYLOAD: &[u8] = &[
+            0x06, 0x00, 0x40, 0xf1, 0x01, 0x00, 0x00, 0xed, 0x03, 0x03, 0xeb, 0xf8, 0xfa, 0x56,
+            0xf1, 0x29, 0x39, 0xb9, 0x58, 0x4a, 0x38, 0x96, 0x47, 0x2e, 0xc4, 0x0b, 0xb8, 0x63,
+            0xcf, 0xd3, 0xe8, 0x68, 0x04, 0xfe, 0x3a, 0x47, 0xf0, 0x6a, 0x2b, 0x69, 0x48, 0x4c,
+            0x00, 0x00, 0x04, 0x13, 0x01, 0x13, 0x02, 0x01, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00,
+            0x10, 0x00, 0x0e, 0x00, 0x00, 0x0b, 0x65, 0x78, 0x61, 0x6d, 0x70, 0x6c, 0x65, 0x2e,
+            0x63, 0x6f, 0x6d, 0xff, 0x01, 0x00, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x08, 0x00, 0x06,
+            0x00, 0x1d, 0x00, 0x17, 0x00, 0x18, 0x00, 0x10, 0x00, 0x07, 0x00, 0x05, 0x04, 0x61,
+            0x6c, 0x70, 0x6e, 0x00, 0x05, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x33,
+            0x00, 0x26, 0x00, 0x24, 0x00, 0x1d, 0x00, 0x20, 0x93, 0x70, 0xb2, 0xc9, 0xca, 0xa4,
+            0x7f, 0xba, 0xba, 0xf4, 0x55, 0x9f, 0xed, 0xba, 0x75, 0x3d, 0xe1, 0x71, 0xfa, 0x71,
+            0xf5, 0x0f, 0x1c, 0xe1, 0x5d, 0x43, 0xe9, 0x94, 0xec, 0x74, 0xd7, 0x48, 0x00, 0x2b,
+            0x00, 0x03, 0x02, 0x03, 0x04, 0x00, 0x0d, 0x00, 0x10, 0x00, 0x0e, 0x04, 0x03, 0x05,
+            0x03, 0x06, 0x03, 0x02, 0x03, 0x08, 0x04, 0x08, 0x05, 0x08, 0x06, 0x00, 0x2d, 0x00,
+            0x02, 0x01, 0x01, 0x00, 0x1c, 0x00, 0x02, 0x40, 0x01, 0x00, 0x39, 0x00, 0x32, 0x04,
+            0x08, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x05, 0x04, 0x80, 0x00, 0xff,
+            0xff, 0x07, 0x04, 0x80, 0x00, 0xff, 0xff, 0x08, 0x01, 0x10, 0x01, 0x04, 0x80, 0x00,
+            0x75, 0x30, 0x09, 0x01, 0x10, 0x0f, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57,
+            0x08, 0x06, 0x04, 0x80, 0x00, 0xff, 0xff,
+        ];
+
+        let client_keys = Keys::initial(Version::V1, &CONNECTION_ID, true);
+        assert_eq!(
+            client_keys
+                .local
+                .packet
+                .confidentiality_limit(),
+            2u64.pow(23)
+        );
+        assert_eq!(
+            client_keys
+                .local
+                .packet
+                .integrity_limit(),
+            2u64.pow(52)
+        );
+        assert_eq!(client_keys.local.packet.tag_len(), 16);
+
+        let mut buf = Vec::new();
+        buf.extend(PLAIN_HEADER);
+        buf.extend(PAYLOAD);
+        let header_len = PLAIN_HEADER.len();
+        let tag_len = client_keys.local.packet.tag_len();
+        let padding_len = 1200 - header_len - PAYLOAD.len() - tag_len;
+        buf.extend(std::iter::repeat(0).take(padding_len));
+        let (header, payload) = buf.split_at_mut(header_len);
+        let tag = client_keys
+            .local
+            .packet
+            .encrypt_in_place(PACKET_NUMBER, &*header, payload)
+            .unwrap();
+
+        let sample_len = client_keys.local.header.sample_len();
+        let sample = &payload[..sample_len];
+        let (first, rest) = header.split_at_mut(1);
+        client_keys
+            .local
+            .header
+            .encrypt_in_place(sample, &mut first[0], &mut rest[17..21])
+            .unwrap();
         buf.extend_from_slice(tag.as_ref());
 
-        const PROTECTED: &[u8] = &[
-            0xc0, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
-            0x00, 0x00, 0x44, 0x9e, 0x7b, 0x9a, 0xec, 0x34, 0xd1, 0xb1, 0xc9, 0x8d, 0xd7, 0x68,
-            0x9f, 0xb8, 0xec, 0x11, 0xd2, 0x42, 0xb1, 0x23, 0xdc, 0x9b, 0xd8, 0xba, 0xb9, 0x36,
-            0xb4, 0x7d, 0x92, 0xec, 0x35, 0x6c, 0x0b, 0xab, 0x7d, 0xf5, 0x97, 0x6d, 0x27, 0xcd,
-            0x44, 0x9f, 0x63, 0x30, 0x00, 0x99, 0xf3, 0x99, 0x1c, 0x26, 0x0e, 0xc4, 0xc6, 0x0d,
-            0x17, 0xb3, 0x1f, 0x84, 0x29, 0x15, 0x7b, 0xb3, 0x5a, 0x12, 0x82, 0xa6, 0x43, 0xa8,
-            0xd2, 0x26, 0x2c, 0xad, 0x67, 0x50, 0x0c, 0xad, 0xb8, 0xe7, 0x37, 0x8c, 0x8e, 0xb7,
-            0x53, 0x9e, 0xc4, 0xd4, 0x90, 0x5f, 0xed, 0x1b, 0xee, 0x1f, 0xc8, 0xaa, 0xfb, 0xa1,
-            0x7c, 0x75, 0x0e, 0x2c, 0x7a, 0xce, 0x01, 0xe6, 0x00, 0x5f, 0x80, 0xfc, 0xb7, 0xdf,
-            0x62, 0x12, 0x30, 0xc8, 0x37, 0x11, 0xb3, 0x93, 0x43, 0xfa, 0x02, 0x8c, 0xea, 0x7f,
-            0x7f, 0xb5, 0xff, 0x89, 0xea, 0xc2, 0x30, 0x82, 0x49, 0xa0, 0x22, 0x52, 0x15, 0x5e,
-            0x23, 0x47, 0xb6, 0x3d, 0x58, 0xc5, 0x45, 0x7a, 0xfd, 0x84, 0xd0, 0x5d, 0xff, 0xfd,
-            0xb2, 0x03, 0x92, 0x84, 0x4a, 0xe8, 0x12, 0x15, 0x46, 0x82, 0xe9, 0xcf, 0x01, 0x2f,
-            0x90, 0x21, 0xa6, 0xf0, 0xbe, 0x17, 0xdd, 0xd0, 0xc2, 0x08, 0x4d, 0xce, 0x25, 0xff,
-            0x9b, 0x06, 0xcd, 0xe5, 0x35, 0xd0, 0xf9, 0x20, 0xa2, 0xdb, 0x1b, 0xf3, 0x62, 0xc2,
-            0x3e, 0x59, 0x6d, 0x11, 0xa4, 0xf5, 0xa6, 0xcf, 0x39, 0x48, 0x83, 0x8a, 0x3a, 0xec,
-            0x4e, 0x15, 0xda, 0xf8, 0x50, 0x0a, 0x6e, 0xf6, 0x9e, 0xc4, 0xe3, 0xfe, 0xb6, 0xb1,
-            0xd9, 0x8e, 0x61, 0x0a, 0xc8, 0xb7, 0xec, 0x3f, 0xaf, 0x6a, 0xd7, 0x60, 0xb7, 0xba,
-            0xd1, 0xdb, 0x4b, 0xa3, 0x48, 0x5e, 0x8a, 0x94, 0xdc, 0x25, 0x0a, 0xe3, 0xfd, 0xb4,
-            0x1e, 0xd1, 0x5f, 0xb6, 0xa8, 0xe5, 0xeb, 0xa0, 0xfc, 0x3d, 0xd6, 0x0b, 0xc8, 0xe3,
-            0x0c, 0x5c, 0x42, 0x87, 0xe5, 0x38, 0x05, 0xdb, 0x05, 0x9a, 0xe0, 0x64, 0x8d, 0xb2,
-            0xf6, 0x42, 0x64, 0xed, 0x5e, 0x39, 0xbe, 0x2e, 0x20, 0xd8, 0x2d, 0xf5, 0x66, 0xda,
-            0x8d, 0xd5, 0x99, 0x8c, 0xca, 0xbd, 0xae, 0x05, 0x30, 0x60, 0xae, 0x6c, 0x7b, 0x43,
-            0x78, 0xe8, 0x46, 0xd2, 0x9f, 0x37, 0xed, 0x7b, 0x4e, 0xa9, 0xec, 0x5d, 0x82, 0xe7,
-            0x96, 0x1b, 0x7f, 0x25, 0xa9, 0x32, 0x38, 0x51, 0xf6, 0x81, 0xd5, 0x82, 0x36, 0x3a,
-            0xa5, 0xf8, 0x99, 0x37, 0xf5, 0xa6, 0x72, 0x58, 0xbf, 0x63, 0xad, 0x6f, 0x1a, 0x0b,
-            0x1d, 0x96, 0xdb, 0xd4, 0xfa, 0xdd, 0xfc, 0xef, 0xc5, 0x26, 0x6b, 0xa6, 0x61, 0x17,
-            0x22, 0x39, 0x5c, 0x90, 0x65, 0x56, 0xbe, 0x52, 0xaf, 0xe3, 0xf5, 0x65, 0x63, 0x6a,
-            0xd1, 0xb1, 0x7d, 0x50, 0x8b, 0x73, 0xd8, 0x74, 0x3e, 0xeb, 0x52, 0x4b, 0xe2, 0x2b,
-            0x3d, 0xcb, 0xc2, 0xc7, 0x46, 0x8d, 0x54, 0x11, 0x9c, 0x74, 0x68, 0x44, 0x9a, 0x13,
-            0xd8, 0xe3, 0xb9, 0x58, 0x11, 0xa1, 0x98, 0xf3, 0x49, 0x1d, 0xe3, 0xe7, 0xfe, 0x94,
-            0x2b, 0x33, 0x04, 0x07, 0xab, 0xf8, 0x2a, 0x4e, 0xd7, 0xc1, 0xb3, 0x11, 0x66, 0x3a,
-            0xc6, 0x98, 0x90, 0xf4, 0x15, 0x70, 0x15, 0x85, 0x3d, 0x91, 0xe9, 0x23, 0x03, 0x7c,
-            0x22, 0x7a, 0x33, 0xcd, 0xd5, 0xec, 0x28, 0x1c, 0xa3, 0xf7, 0x9c, 0x44, 0x54, 0x6b,
-            0x9d, 0x90, 0xca, 0x00, 0xf0, 0x64, 0xc9, 0x9e, 0x3d, 0xd9, 0x79, 0x11, 0xd3, 0x9f,
-            0xe9, 0xc5, 0xd0, 0xb2, 0x3a, 0x22, 0x9a, 0x23, 0x4c, 0xb3, 0x61, 0x86, 0xc4, 0x81,
-            0x9e, 0x8b, 0x9c, 0x59, 0x27, 0x72, 0x66, 0x32, 0x29, 0x1d, 0x6a, 0x41, 0x82, 0x11,
-            0xcc, 0x29, 0x62, 0xe2, 0x0f, 0xe4, 0x7f, 0xeb, 0x3e, 0xdf, 0x33, 0x0f, 0x2c, 0x60,
-            0x3a, 0x9d, 0x48, 0xc0, 0xfc, 0xb5, 0x69, 0x9d, 0xbf, 0xe5, 0x89, 0x64, 0x25, 0xc5,
-            0xba, 0xc4, 0xae, 0xe8, 0x2e, 0x57, 0xa8, 0x5a, 0xaf, 0x4e, 0x25, 0x13, 0xe4, 0xf0,
-            0x57, 0x96, 0xb0, 0x7b, 0xa2, 0xee, 0x47, 0xd8, 0x05, 0x06, 0xf8, 0xd2, 0xc2, 0x5e,
-            0x50, 0xfd, 0x14, 0xde, 0x71, 0xe6, 0xc4, 0x18, 0x55, 0x93, 0x02, 0xf9, 0x39, 0xb0,
-            0xe1, 0xab, 0xd5, 0x76, 0xf2, 0x79, 0xc4, 0xb2, 0xe0, 0xfe, 0xb8, 0x5c, 0x1f, 0x28,
-            0xff, 0x18, 0xf5, 0x88, 0x91, 0xff, 0xef, 0x13, 0x2e, 0xef, 0x2f, 0xa0, 0x93, 0x46,
-            0xae, 0xe3, 0x3c, 0x28, 0xeb, 0x13, 0x0f, 0xf2, 0x8f, 0x5b, 0x76, 0x69, 0x53, 0x33,
-            0x41, 0x13, 0x21, 0x19, 0x96, 0xd2, 0x00, 0x11, 0xa1, 0x98, 0xe3, 0xfc, 0x43, 0x3f,
-            0x9f, 0x25, 0x41, 0x01, 0x0a, 0xe1, 0x7c, 0x1b, 0xf2, 0x02, 0x58, 0x0f, 0x60, 0x47,
-            0x47, 0x2f, 0xb3, 0x68, 0x57, 0xfe, 0x84, 0x3b, 0x19, 0xf5, 0x98, 0x40, 0x09, 0xdd,
-            0xc3, 0x24, 0x04, 0x4e, 0x84, 0x7a, 0x4f, 0x4a, 0x0a, 0xb3, 0x4f, 0x71, 0x95, 0x95,
-            0xde, 0x37, 0x25, 0x2d, 0x62, 0x35, 0x36, 0x5e, 0x9b, 0x84, 0x39, 0x2b, 0x06, 0x10,
-            0x85, 0x34, 0x9d, 0x73, 0x20, 0x3a, 0x4a, 0x13, 0xe9, 0x6f, 0x54, 0x32, 0xec, 0x0f,
-            0xd4, 0xa1, 0xee, 0x65, 0xac, 0xcd, 0xd5, 0xe3, 0x90, 0x4d, 0xf5, 0x4c, 0x1d, 0xa5,
-            0x10, 0xb0, 0xff, 0x20, 0xdc, 0xc0, 0xc7, 0x7f, 0xcb, 0x2c, 0x0e, 0x0e, 0xb6, 0x05,
-            0xcb, 0x05, 0x04, 0xdb, 0x87, 0x63, 0x2c, 0xf3, 0xd8, 0xb4, 0xda, 0xe6, 0xe7, 0x05,
-            0x76, 0x9d, 0x1d, 0xe3, 0x54, 0x27, 0x01, 0x23, 0xcb, 0x11, 0x45, 0x0e, 0xfc, 0x60,
-            0xac, 0x47, 0x68, 0x3d, 0x7b, 0x8d, 0x0f, 0x81, 0x13, 0x65, 0x56, 0x5f, 0xd9, 0x8c,
-            0x4c, 0x8e, 0xb9, 0x36, 0xbc, 0xab, 0x8d, 0x06, 0x9f, 0xc3, 0x3b, 0xd8, 0x01, 0xb0,
-            0x3a, 0xde, 0xa2, 0xe1, 0xfb, 0xc5, 0xaa, 0x46, 0x3d, 0x08, 0xca, 0x19, 0x89, 0x6d,
-            0x2b, 0xf5, 0x9a, 0x07, 0x1b, 0x85, 0x1e, 0x6c, 0x23, 0x90, 0x52, 0x17, 0x2f, 0x29,
-            0x6b, 0xfb, 0x5e, 0x72, 0x40, 0x47, 0x90, 0xa2, 0x18, 0x10, 0x14, 0xf3, 0xb9, 0x4a,
-            0x4e, 0x97, 0xd1, 0x17, 0xb4, 0x38, 0x13, 0x03, 0x68, 0xcc, 0x39, 0xdb, 0xb2, 0xd1,
-            0x98, 0x06, 0x5a, 0xe3, 0x98, 0x65, 0x47, 0x92, 0x6c, 0xd2, 0x16, 0x2f, 0x40, 0xa2,
-            0x9f, 0x0c, 0x3c, 0x87, 0x45, 0xc0, 0xf5, 0x0f, 0xba, 0x38, 0x52, 0xe5, 0x66, 0xd4,
-            0x45, 0x75, 0xc2, 0x9d, 0x39, 0xa0, 0x3f, 0x0c, 0xda, 0x72, 0x19, 0x84, 0xb6, 0xf4,
-            0x40, 0x59, 0x1f, 0x35, 0x5e, 0x12, 0xd4, 0x39, 0xff, 0x15, 0x0a, 0xab, 0x76, 0x13,
-            0x49, 0x9d, 0xbd, 0x49, 0xad, 0xab, 0xc8, 0x67, 0x6e, 0xef, 0x02, 0x3b, 0x15, 0xb6,
-            0x5b, 0xfc, 0x5c, 0xa0, 0x69, 0x48, 0x10, 0x9f, 0x23, 0xf3, 0x50, 0xdb, 0x82, 0x12,
-            0x35, 0x35, 0xeb, 0x8a, 0x74, 0x33, 0xbd, 0xab, 0xcb, 0x90, 0x92, 0x71, 0xa6, 0xec,
-            0xbc, 0xb5, 0x8b, 0x93, 0x6a, 0x88, 0xcd, 0x4e, 0x8f, 0x2e, 0x6f, 0xf5, 0x80, 0x01,
-            0x75, 0xf1, 0x13, 0x25, 0x3d, 0x8f, 0xa9, 0xca, 0x88, 0x85, 0xc2, 0xf5, 0x52, 0xe6,
-            0x57, 0xdc, 0x60, 0x3f, 0x25, 0x2e, 0x1a, 0x8e, 0x30, 0x8f, 0x76, 0xf0, 0xbe, 0x79,
-            0xe2, 0xfb, 0x8f, 0x5d, 0x5f, 0xbb, 0xe2, 0xe3, 0x0e, 0xca, 0xdd, 0x22, 0x07, 0x23,
-            0xc8, 0xc0, 0xae, 0xa8, 0x07, 0x8c, 0xdf, 0xcb, 0x38, 0x68, 0x26, 0x3f, 0xf8, 0xf0,
-            0x94, 0x00, 0x54, 0xda, 0x48, 0x78, 0x18, 0x93, 0xa7, 0xe4, 0x9a, 0xd5, 0xaf, 0xf4,
-            0xaf, 0x30, 0x0c, 0xd8, 0x04, 0xa6, 0xb6, 0x27, 0x9a, 0xb3, 0xff, 0x3a, 0xfb, 0x64,
-            0x49, 0x1c, 0x85, 0x19, 0x4a, 0xab, 0x76, 0x0d, 0x58, 0xa6, 0x06, 0x65, 0x4f, 0x9f,
-            0x44, 0x00, 0xe8, 0xb3, 0x85, 0x91, 0x35, 0x6f, 0xbf, 0x64, 0x25, 0xac, 0xa2, 0x6d,
-            0xc8, 0x52, 0x44, 0x25, 0x9f, 0xf2, 0xb1, 0x9c, 0x41, 0xb9, 0xf9, 0x6f, 0x3c, 0xa9,
-            0xec, 0x1d, 0xde, 0x43, 0x4d, 0xa7, 0xd2, 0xd3, 0x92, 0xb9, 0x05, 0xdd, 0xf3, 0xd1,
-            0xf9, 0xaf, 0x93, 0xd1, 0xaf, 0x59, 0x50, 0xbd, 0x49, 0x3f, 0x5a, 0xa7, 0x31, 0xb4,
-            0x05, 0x6d, 0xf3, 0x1b, 0xd2, 0x67, 0xb6, 0xb9, 0x0a, 0x07, 0x98, 0x31, 0xaa, 0xf5,
-            0x79, 0xbe, 0x0a, 0x39, 0x01, 0x31, 0x37, 0xaa, 0xc6, 0xd4, 0x04, 0xf5, 0x18, 0xcf,
-            0xd4, 0x68, 0x40, 0x64, 0x7e, 0x78, 0xbf, 0xe7, 0x06, 0xca, 0x4c, 0xf5, 0xe9, 0xc5,
-            0x45, 0x3e, 0x9f, 0x7c, 0xfd, 0x2b, 0x8b, 0x4c, 0x8d, 0x16, 0x9a, 0x44, 0xe5, 0x5c,
-            0x88, 0xd4, 0xa9, 0xa7, 0xf9, 0x47, 0x42, 0x41, 0xe2, 0x21, 0xaf, 0x44, 0x86, 0x00,
-            0x18, 0xab, 0x08, 0x56, 0x97, 0x2e, 0x19, 0x4c, 0xd9, 0x34,
-        ];
+        const PROTECTED: &[u8] = &[
+            0xc0, 0x00, 0x00, 0x00, 0x01, 0x08, 0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08,
+            0x00, 0x00, 0x44, 0x9e, 0x7b, 0x9a, 0xec, 0x34, 0xd1, 0xb1, 0xc9, 0x8d, 0xd7, 0x68,
+            0x9f, 0xb8, 0xec, 0x11, 0xd2, 0x42, 0xb1, 0x23, 0xdc, 0x9b, 0xd8, 0xba, 0xb9, 0x36,
+            0xb4, 0x7d, 0x92, 0xec, 0x35, 0x6c, 0x0b, 0xab, 0x7d, 0xf5, 0x97, 0x6d, 0x27, 0xcd,
+            0x44, 0x9f, 0x63, 0x30, 0x00, 0x99, 0xf3, 0x99, 0x1c, 0x26, 0x0e, 0xc4, 0xc6, 0x0d,
+            0x17, 0xb3, 0x1f, 0x84, 0x29, 0x15, 0x7b, 0xb3, 0x5a, 0x12, 0x82, 0xa6, 0x43, 0xa8,
+            0xd2, 0x26, 0x2c, 0xad, 0x67, 0x50, 0x0c, 0xad, 0xb8, 0xe7, 0x37, 0x8c, 0x8e, 0xb7,
+            0x53, 0x9e, 0xc4, 0xd4, 0x90, 0x5f, 0xed, 0x1b, 0xee, 0x1f, 0xc8, 0xaa, 0xfb, 0xa1,
+            0x7c, 0x75, 0x0e, 0x2c, 0x7a, 0xce, 0x01, 0xe6, 0x00, 0x5f, 0x80, 0xfc, 0xb7, 0xdf,
+            0x62, 0x12, 0x30, 0xc8, 0x37, 0x11, 0xb3, 0x93, 0x43, 0xfa, 0x02, 0x8c, 0xea, 0x7f,
+            0x7f, 0xb5, 0xff, 0x89, 0xea, 0xc2, 0x30, 0x82, 0x49, 0xa0, 0x22, 0x52, 0x15, 0x5e,
+            0x23, 0x47, 0xb6, 0x3d, 0x58, 0xc5, 0x45, 0x7a, 0xfd, 0x84, 0xd0, 0x5d, 0xff, 0xfd,
+            0xb2, 0x03, 0x92, 0x84, 0x4a, 0xe8, 0x12, 0x15, 0x46, 0x82, 0xe9, 0xcf, 0x01, 0x2f,
+            0x90, 0x21, 0xa6, 0xf0, 0xbe, 0x17, 0xdd, 0xd0, 0xc2, 0x08, 0x4d, 0xce, 0x25, 0xff,
+            0x9b, 0x06, 0xcd, 0xe5, 0x35, 0xd0, 0xf9, 0x20, 0xa2, 0xdb, 0x1b, 0xf3, 0x62, 0xc2,
+            0x3e, 0x59, 0x6d, 0x11, 0xa4, 0xf5, 0xa6, 0xcf, 0x39, 0x48, 0x83, 0x8a, 0x3a, 0xec,
+            0x4e, 0x15, 0xda, 0xf8, 0x50, 0x0a, 0x6e, 0xf6, 0x9e, 0xc4, 0xe3, 0xfe, 0xb6, 0xb1,
+            0xd9, 0x8e, 0x61, 0x0a, 0xc8, 0xb7, 0xec, 0x3f, 0xaf, 0x6a, 0xd7, 0x60, 0xb7, 0xba,
+            0xd1, 0xdb, 0x4b, 0xa3, 0x48, 0x5e, 0x8a, 0x94, 0xdc, 0x25, 0x0a, 0xe3, 0xfd, 0xb4,
+            0x1e, 0xd1, 0x5f, 0xb6, 0xa8, 0xe5, 0xeb, 0xa0, 0xfc, 0x3d, 0xd6, 0x0b, 0xc8, 0xe3,
+            0x0c, 0x5c, 0x42, 0x87, 0xe5, 0x38, 0x05, 0xdb, 0x05, 0x9a, 0xe0, 0x64, 0x8d, 0xb2,
+            0xf6, 0x42, 0x64, 0xed, 0x5e, 0x39, 0xbe, 0x2e, 0x20, 0xd8, 0x2d, 0xf5, 0x66, 0xda,
+            0x8d, 0xd5, 0x99, 0x8c, 0xca, 0xbd, 0xae, 0x05, 0x30, 0x60, 0xae, 0x6c, 0x7b, 0x43,
+            0x78, 0xe8, 0x46, 0xd2, 0x9f, 0x37, 0xed, 0x7b, 0x4e, 0xa9, 0xec, 0x5d, 0x82, 0xe7,
+            0x96, 0x1b, 0x7f, 0x25, 0xa9, 0x32, 0x38, 0x51, 0xf6, 0x81, 0xd5, 0x82, 0x36, 0x3a,
+            0xa5, 0xf8, 0x99, 0x37, 0xf5, 0xa6, 0x72, 0x58, 0xbf, 0x63, 0xad, 0x6f, 0x1a, 0x0b,
+            0x1d, 0x96, 0xdb, 0xd4, 0xfa, 0xdd, 0xfc, 0xef, 0xc5, 0x26, 0x6b, 0xa6, 0x61, 0x17,
+            0x22, 0x39, 0x5c, 0x90, 0x65, 0x56, 0xbe, 0x52, 0xaf, 0xe3, 0xf5, 0x65, 0x63, 0x6a,
+            0xd1, 0xb1, 0x7d, 0x50, 0x8b, 0x73, 0xd8, 0x74, 0x3e, 0xeb, 0x52, 0x4b, 0xe2, 0x2b,
+            0x3d, 0xcb, 0xc2, 0xc7, 0x46, 0x8d, 0x54, 0x11, 0x9c, 0x74, 0x68, 0x44, 0x9a, 0x13,
+            0xd8, 0xe3, 0xb9, 0x58, 0x11, 0xa1, 0x98, 0xf3, 0x49, 0x1d, 0xe3, 0xe7, 0xfe, 0x94,
+            0x2b, 0x33, 0x04, 0x07, 0xab, 0xf8, 0x2a, 0x4e, 0xd7, 0xc1, 0xb3, 0x11, 0x66, 0x3a,
+            0xc6, 0x98, 0x90, 0xf4, 0x15, 0x70, 0x15, 0x85, 0x3d, 0x91, 0xe9, 0x23, 0x03, 0x7c,
+            0x22, 0x7a, 0x33, 0xcd, 0xd5, 0xec, 0x28, 0x1c, 0xa3, 0xf7, 0x9c, 0x44, 0x54, 0x6b,
+            0x9d, 0x90, 0xca, 0x00, 0xf0, 0x64, 0xc9, 0x9e, 0x3d, 0xd9, 0x79, 0x11, 0xd3, 0x9f,
+            0xe9, 0xc5, 0xd0, 0xb2, 0x3a, 0x22, 0x9a, 0x23, 0x4c, 0xb3, 0x61, 0x86, 0xc4, 0x81,
+            0x9e, 0x8b, 0x9c, 0x59, 0x27, 0x72, 0x66, 0x32, 0x29, 0x1d, 0x6a, 0x41, 0x82, 0x11,
+            0xcc, 0x29, 0x62, 0xe2, 0x0f, 0xe4, 0x7f, 0xeb, 0x3e, 0xdf, 0x33, 0x0f, 0x2c, 0x60,
+            0x3a, 0x9d, 0x48, 0xc0, 0xfc, 0xb5, 0x69, 0x9d, 0xbf, 0xe5, 0x89, 0x64, 0x25, 0xc5,
+            0xba, 0xc4, 0xae, 0xe8, 0x2e, 0x57, 0xa8, 0x5a, 0xaf, 0x4e, 0x25, 0x13, 0xe4, 0xf0,
+            0x57, 0x96, 0xb0, 0x7b, 0xa2, 0xee, 0x47, 0xd8, 0x05, 0x06, 0xf8, 0xd2, 0xc2, 0x5e,
+            0x50, 0xfd, 0x14, 0xde, 0x71, 0xe6, 0xc4, 0x18, 0x55, 0x93, 0x02, 0xf9, 0x39, 0xb0,
+            0xe1, 0xab, 0xd5, 0x76, 0xf2, 0x79, 0xc4, 0xb2, 0xe0, 0xfe, 0xb8, 0x5c, 0x1f, 0x28,
+            0xff, 0x18, 0xf5, 0x88, 0x91, 0xff, 0xef, 0x13, 0x2e, 0xef, 0x2f, 0xa0, 0x93, 0x46,
+            0xae, 0xe3, 0x3c, 0x28, 0xeb, 0x13, 0x0f, 0xf2, 0x8f, 0x5b, 0x76, 0x69, 0x53, 0x33,
+            0x41, 0x13, 0x21, 0x19, 0x96, 0xd2, 0x00, 0x11, 0xa1, 0x98, 0xe3, 0xfc, 0x43, 0x3f,
+            0x9f, 0x25, 0x41, 0x01, 0x0a, 0xe1, 0x7c, 0x1b, 0xf2, 0x02, 0x58, 0x0f, 0x60, 0x47,
+            0x47, 0x2f, 0xb3, 0x68, 0x57, 0xfe, 0x84, 0x3b, 0x19, 0xf5, 0x98, 0x40, 0x09, 0xdd,
+            0xc3, 0x24, 0x04, 0x4e, 0x84, 0x7a, 0x4f, 0x4a, 0x0a, 0xb3, 0x4f, 0x71, 0x95, 0x95,
+            0xde, 0x37, 0x25, 0x2d, 0x62, 0x35, 0x36, 0x5e, 0x9b, 0x84, 0x39, 0x2b, 0x06, 0x10,
+            0x85, 0x34, 0x9d, 0x73, 0x20, 0x3a, 0x4a, 0x13, 0xe9, 0x6f, 0x54, 0x32, 0xec, 0x0f,
+            0xd4, 0xa1, 0xee, 0x65, 0xac, 0xcd, 0xd5, 0xe3, 0x90, 0x4d, 0xf5, 0x4c, 0x1d, 0xa5,
+            0x10, 0xb0, 0xff, 0x20, 0xdc, 0xc0, 0xc7, 0x7f, 0xcb, 0x2c, 0x0e, 0x0e, 0xb6, 0x05,
+            0xcb, 0x05, 0x04, 0xdb, 0x87, 0x63, 0x2c, 0xf3, 0xd8, 0xb4, 0xda, 0xe6, 0xe7, 0x05,
+            0x76, 0x9d, 0x1d, 0xe3, 0x54, 0x27, 0x01, 0x23, 0xcb, 0x11, 0x45, 0x0e, 0xfc, 0x60,
+            0xac, 0x47, 0x68, 0x3d, 0x7b, 0x8d, 0x0f, 0x81, 0x13, 0x65, 0x56, 0x5f, 0xd9, 0x8c,
+            0x4c, 0x8e, 0xb9, 0x36, 0xbc, 0xab, 0x8d, 0x06, 0x9f, 0xc3, 0x3b, 0xd8, 0x01, 0xb0,
+            0x3a, 0xde, 0xa2, 0xe1, 0xfb, 0xc5, 0xaa, 0x46, 0x3d, 0x08, 0xca, 0x19, 0x89, 0x6d,
+            0x2b, 0xf5, 0x9a, 0x07, 0x1b, 0x85, 0x1e, 0x6c, 0x23, 0x90, 0x52, 0x17, 0x2f, 0x29,
+            0x6b, 0xfb, 0x5e, 0x72, 0x40, 0x47, 0x90, 0xa2, 0x18, 0x10, 0x14, 0xf3, 0xb9, 0x4a,
+            0x4e, 0x97, 0xd1, 0x17, 0xb4, 0x38, 0x13, 0x03, 0x68, 0xcc, 0x39, 0xdb, 0xb2, 0xd1,
+            0x98, 0x06, 0x5a, 0xe3, 0x98, 0x65, 0x47, 0x92, 0x6c, 0xd2, 0x16, 0x2f, 0x40, 0xa2,
+            0x9f, 0x0c, 0x3c, 0x87, 0x45, 0xc0, 0xf5, 0x0f, 0xba, 0x38, 0x52, 0xe5, 0x66, 0xd4,
+            0x45, 0x75, 0xc2, 0x9d, 0x39, 0xa0, 0x3f, 0x0c, 0xda, 0x72, 0x19, 0x84, 0xb6, 0xf4,
+            0x40, 0x59, 0x1f, 0x35, 0x5e, 0x12, 0xd4, 0x39, 0xff, 0x15, 0x0a, 0xab, 0x76, 0x13,
+            0x49, 0x9d, 0xbd, 0x49, 0xad, 0xab, 0xc8, 0x67, 0x6e, 0xef, 0x02, 0x3b, 0x15, 0xb6,
+            0x5b, 0xfc, 0x5c, 0xa0, 0x69, 0x48, 0x10, 0x9f, 0x23, 0xf3, 0x50, 0xdb, 0x82, 0x12,
+            0x35, 0x35, 0xeb, 0x8a, 0x74, 0x33, 0xbd, 0xab, 0xcb, 0x90, 0x92, 0x71, 0xa6, 0xec,
+            0xbc, 0xb5, 0x8b, 0x93, 0x6a, 0x88, 0xcd, 0x4e, 0x8f, 0x2e, 0x6f, 0xf5, 0x80, 0x01,
+            0x75, 0xf1, 0x13, 0x25, 0x3d, 0x8f, 0xa9, 0xca, 0x88, 0x85, 0xc2, 0xf5, 0x52, 0xe6,
+            0x57, 0xdc, 0x60, 0x3f, 0x25, 0x2e, 0x1a, 0x8e, 0x30, 0x8f, 0x76, 0xf0, 0xbe, 0x79,
+            0xe2, 0xfb, 0x8f, 0x5d, 0x5f, 0xbb, 0xe2, 0xe3, 0x0e, 0xca, 0xdd, 0x22, 0x07, 0x23,
+            0xc8, 0xc0, 0xae, 0xa8, 0x07, 0x8c, 0xdf, 0xcb, 0x38, 0x68, 0x26, 0x3f, 0xf8, 0xf0,
+            0x94, 0x00, 0x54, 0xda, 0x48, 0x78, 0x18, 0x93, 0xa7, 0xe4, 0x9a, 0xd5, 0xaf, 0xf4,
+            0xaf, 0x30, 0x0c, 0xd8, 0x04, 0xa6, 0xb6, 0x27, 0x9a, 0xb3, 0xff, 0x3a, 0xfb, 0x64,
+            0x49, 0x1c, 0x85, 0x19, 0x4a, 0xab, 0x76, 0x0d, 0x58, 0xa6, 0x06, 0x65, 0x4f, 0x9f,
+            0x44, 0x00, 0xe8, 0xb3, 0x85, 0x91, 0x35, 0x6f, 0xbf, 0x64, 0x25, 0xac, 0xa2, 0x6d,
+            0xc8, 0x52, 0x44, 0x25, 0x9f, 0xf2, 0xb1, 0x9c, 0x41, 0xb9, 0xf9, 0x6f, 0x3c, 0xa9,
+            0xec, 0x1d, 0xde, 0x43, 0x4d, 0xa7, 0xd2, 0xd3, 0x92, 0xb9, 0x05, 0xdd, 0xf3, 0xd1,
+            0xf9, 0xaf, 0x93, 0xd1, 0xaf, 0x59, 0x50, 0xbd, 0x49, 0x3f, 0x5a, 0xa7, 0x31, 0xb4,
+            0x05, 0x6d, 0xf3, 0x1b, 0xd2, 0x67, 0xb6, 0xb9, 0x0a, 0x07, 0x98, 0x31, 0xaa, 0xf5,
+            0x79, 0xbe, 0x0a, 0x39, 0x01, 0x31, 0x37, 0xaa, 0xc6, 0xd4, 0x04, 0xf5, 0x18, 0xcf,
+            0xd4, 0x68, 0x40, 0x64, 0x7e, 0x78, 0xbf, 0xe7, 0x06, 0xca, 0x4c, 0xf5, 0xe9, 0xc5,
+            0x45, 0x3e, 0x9f, 0x7c, 0xfd, 0x2b, 0x8b, 0x4c, 0x8d, 0x16, 0x9a, 0x44, 0xe5, 0x5c,
+            0x88, 0xd4, 0xa9, 0xa7, 0xf9, 0x47, 0x42, 0x41, 0xe2, 0x21, 0xaf, 0x44, 0x86, 0x00,
+            0x18, 0xab, 0x08, 0x56, 0x97, 0x2e, 0x19, 0x4c, 0xd9, 0x34,
+        ];
+
+        assert_eq!(&buf, PROTECTED);
+
+        let (header, payload) = buf.split_at_mut(header_len);
+        let (first, rest) = header.split_at_mut(1);
+        let sample = &payload[..sample_len];
+
+        let server_keys = Keys::initial(Version::V1, &CONNECTION_ID, false);
+        server_keys
+            .remote
+            .header
+            .decrypt_in_place(sample, &mut first[0], &mut rest[17..21])
+            .unwrap();
+        let payload = server_keys
+            .remote
+            .packet
+            .decrypt_in_place(PACKET_NUMBER, &*header, payload)
+            .unwrap();
+
+        assert_eq!(&payload[..PAYLOAD.len()], PAYLOAD);
+        assert_eq!(payload.len(), buf.len() - header_len - tag_len);
+    }
+
+    #[test]
+    fn test_quic_exporter() {
+        for &kt in ALL_KEY_TYPES.iter() {
+            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
+            let server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+
+            do_exporter_test(client_config, server_config);
+        }
+    }
+} // mod test_quic
+
+#[test]
+fn test_client_does_not_offer_sha1() {
+    use rustls::internal::msgs::{
+        codec::Reader, enums::HandshakeType, handshake::HandshakePayload, message::MessagePayload,
+        message::OpaqueMessage,
+    };
+
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+            let (mut client, _) = make_pair_for_configs(client_config, make_server_config(*kt));
+
+            assert!(client.wants_write());
+            let mut buf = [0u8; 262144];
+            let sz = client
+                .write_tls(&mut buf.as_mut())
+                .unwrap();
+            let msg = OpaqueMessage::read(&mut Reader::init(&buf[..sz])).unwrap();
+            let msg = Message::try_from(msg.into_plain_message()).unwrap();
+            assert!(msg.is_handshake_type(HandshakeType::ClientHello));
+
+            let client_hello = match msg.payload {
+                MessagePayload::Handshake(hs) => match hs.payload {
+                    HandshakePayload::ClientHello(ch) => ch,
+                    _ => unreachable!(),
+                },
+                _ => unreachable!(),
+            };
+
+            let sigalgs = client_hello
+                .get_sigalgs_extension()
+                .unwrap();
+            assert!(
+                !sigalgs.contains(&SignatureScheme::RSA_PKCS1_SHA1),
+                "sha1 unexpectedly offered"
+            );
+        }
+    }
+}
+
+/// Returns the `ClientHello` `client` sends, and the length of its
+/// encoding.
+fn sent_client_hello(
+    client: &mut ClientConnection,
+) -> (rustls::internal::msgs::handshake::ClientHelloPayload, usize) {
+    use rustls::internal::msgs::{
+        codec::Reader, handshake::HandshakePayload, message::MessagePayload, message::OpaqueMessage,
+    };
+
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+    let msg = OpaqueMessage::read(&mut Reader::init(&buf)).unwrap();
+    let msg = Message::try_from(msg.into_plain_message()).unwrap();
+    match msg.payload {
+        MessagePayload::Handshake(hs) => match hs.payload {
+            HandshakePayload::ClientHello(ch) => (ch, buf.len()),
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    }
+}
+
+/// Returns the extension types of the `ClientHello` `client` sends, and
+/// the length of its encoding.
+fn client_hello_shape(
+    client: &mut ClientConnection,
+) -> (Vec<rustls::internal::msgs::enums::ExtensionType>, usize) {
+    let (ch, len) = sent_client_hello(client);
+    (
+        ch.extensions
+            .iter()
+            .map(|ext| ext.get_type())
+            .collect(),
+        len,
+    )
+}
+
+#[test]
+fn client_hello_template_matches_fresh_client_hello() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        client_config.max_fragment_length = Some(MaxFragmentLength::Len2048);
+        let client_config = Arc::new(client_config);
+        let template = Arc::new(rustls::client::ClientHelloTemplate::new(
+            client_config.clone(),
+        ));
+        assert!(Arc::ptr_eq(template.config(), &client_config));
+
+        let mut fresh = ClientConnection::new(client_config, dns_name("localhost")).unwrap();
+        let mut templated =
+            ClientConnection::from_template(template, dns_name("localhost")).unwrap();
+        assert_eq!(
+            client_hello_shape(&mut fresh),
+            client_hello_shape(&mut templated)
+        );
+    }
+}
+
+#[test]
+fn client_hello_template_connections_complete_handshakes() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = Arc::new(make_client_config_with_versions(*kt, &[version]));
+            let server_config = Arc::new(make_server_config(*kt));
+            let template = Arc::new(rustls::client::ClientHelloTemplate::new(client_config));
+
+            // the second connection resumes the first's session
+            for &expected_kind in &[HandshakeKind::Full, HandshakeKind::Resumed] {
+                let mut client =
+                    ClientConnection::from_template(template.clone(), dns_name("localhost"))
+                        .unwrap();
+                let mut server = ServerConnection::new(server_config.clone()).unwrap();
+                do_handshake(&mut client, &mut server);
+                assert_eq!(client.handshake_kind(), Some(expected_kind));
+                assert_eq!(client.protocol_version(), Some(version.version));
+
+                client
+                    .writer()
+                    .write_all(b"hello")
+                    .unwrap();
+                transfer(&mut client, &mut server);
+                server.process_new_packets().unwrap();
+                check_read(&mut server.reader(), b"hello");
+
+                // pick up any TLS1.3 tickets
+                transfer(&mut server, &mut client);
+                client.process_new_packets().unwrap();
+            }
+        }
+    }
+}
+
+#[test]
+fn client_hello_template_connection_survives_hello_retry_request() {
+    let client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    let template = Arc::new(rustls::client::ClientHelloTemplate::new(Arc::new(
+        client_config,
+    )));
+    let mut client = ClientConnection::from_template(template, dns_name("localhost")).unwrap();
+    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client.handshake_kind(),
+        Some(HandshakeKind::FullWithHelloRetryRequest)
+    );
+}
+
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// Returns the GREASE cipher suites, groups and extension types, in
+/// that order, that `client` offers in its `ClientHello`.
+fn sent_grease(client: &mut ClientConnection) -> (Vec<u16>, Vec<u16>, Vec<u16>) {
+    use rustls::internal::msgs::handshake::ClientExtension;
+
+    let (ch, _) = sent_client_hello(client);
+    let cipher_suites = ch
+        .cipher_suites
+        .iter()
+        .map(|cs| cs.get_u16())
+        .filter(|&v| is_grease(v))
+        .collect();
+    let groups = ch
+        .extensions
+        .iter()
+        .filter_map(|ext| match ext {
+            ClientExtension::NamedGroups(groups) => Some(groups),
+            _ => None,
+        })
+        .flatten()
+        .map(|group| group.get_u16())
+        .filter(|&v| is_grease(v))
+        .collect();
+    let extensions = ch
+        .extensions
+        .iter()
+        .map(|ext| ext.get_type().get_u16())
+        .filter(|&v| is_grease(v))
+        .collect();
+    (cipher_suites, groups, extensions)
+}
+
+#[test]
+fn client_sends_no_grease_by_default() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        assert!(!client_config.enable_grease);
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
+        assert_eq!(sent_grease(&mut client), (vec![], vec![], vec![]));
+    }
+}
+
+#[test]
+fn client_sends_grease_when_enabled() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.enable_grease = true;
+        let client_config = Arc::new(client_config);
+        let template = Arc::new(rustls::client::ClientHelloTemplate::new(
+            client_config.clone(),
+        ));
+
+        let fresh = ClientConnection::new(client_config.clone(), dns_name("localhost")).unwrap();
+        let templated = ClientConnection::from_template(template, dns_name("localhost")).unwrap();
+        for mut client in vec![fresh, templated] {
+            let (cipher_suites, groups, extensions) = sent_grease(&mut client);
+            assert_eq!(cipher_suites.len(), 1);
+            assert_eq!(groups.len(), 1);
+            assert_eq!(extensions.len(), 2);
+            assert_ne!(extensions[0], extensions[1]);
+        }
+
+        // GREASE values are sent first
+        let mut client = ClientConnection::new(client_config, dns_name("localhost")).unwrap();
+        let (ch, _) = sent_client_hello(&mut client);
+        assert!(is_grease(ch.cipher_suites[0].get_u16()));
+        assert!(is_grease(ch.extensions[0].get_type().get_u16()));
+        assert!(!ch.has_duplicate_extension());
+    }
+}
+
+#[test]
+fn handshakes_complete_with_grease() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.enable_grease = true;
+            let client_config = Arc::new(client_config);
+            let server_config = Arc::new(make_server_config(*kt));
+
+            for &expected_kind in &[HandshakeKind::Full, HandshakeKind::Resumed] {
+                let (mut client, mut server) =
+                    make_pair_for_arc_configs(&client_config, &server_config);
+                do_handshake(&mut client, &mut server);
+                assert_eq!(client.handshake_kind(), Some(expected_kind));
+                assert_eq!(client.protocol_version(), Some(version.version));
+                transfer(&mut server, &mut client);
+                client.process_new_packets().unwrap();
+            }
+        }
+    }
+}
+
+#[test]
+fn handshake_with_grease_survives_hello_retry_request() {
+    let mut client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+    client_config.enable_grease = true;
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(
+        client.handshake_kind(),
+        Some(HandshakeKind::FullWithHelloRetryRequest)
+    );
+}
+
+#[test]
+fn client_sends_extensions_in_custom_order() {
+    use rustls::client::ExtensionOrder;
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.alpn_protocols = vec![b"h2".to_vec()];
+        let mut client =
+            ClientConnection::new(Arc::new(client_config.clone()), dns_name("localhost")).unwrap();
+        let (usual, _) = client_hello_shape(&mut client);
+
+        let mut reversed = usual.clone();
+        reversed.reverse();
+        client_config.extension_order = ExtensionOrder::custom(reversed.clone()).unwrap();
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
+        assert_eq!(client_hello_shape(&mut client).0, reversed);
+    }
+}
+
+#[test]
+fn custom_extension_order_puts_unlisted_extensions_last() {
+    use rustls::client::ExtensionOrder;
+    use rustls::ExtensionType;
+
+    let order = vec![
+        ExtensionType::StatusRequest,
+        ExtensionType::SignatureAlgorithms,
+        ExtensionType::ExtendedMasterSecret,
+        ExtensionType::ECPointFormats,
+        ExtensionType::EllipticCurves,
+        ExtensionType::SupportedVersions,
+        ExtensionType::ALProtocolNegotiation,
+    ];
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let mut client =
+        ClientConnection::new(Arc::new(client_config.clone()), dns_name("localhost")).unwrap();
+    let (usual, _) = client_hello_shape(&mut client);
+
+    // no ALPN protocols are configured, so that extension isn't sent
+    let mut expected = order[..6].to_vec();
+    expected.extend(
+        usual
+            .iter()
+            .filter(|typ| !order.contains(typ)),
+    );
+
+    client_config.extension_order = ExtensionOrder::custom(order).unwrap();
+    let mut client = ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
+    assert_eq!(client_hello_shape(&mut client).0, expected);
+}
+
+#[test]
+fn invalid_custom_extension_orders_are_rejected() {
+    use rustls::client::ExtensionOrder;
+    use rustls::ExtensionType;
+
+    let valid = vec![
+        ExtensionType::SupportedVersions,
+        ExtensionType::ECPointFormats,
+        ExtensionType::EllipticCurves,
+        ExtensionType::SignatureAlgorithms,
+        ExtensionType::ExtendedMasterSecret,
+        ExtensionType::StatusRequest,
+    ];
+    assert!(ExtensionOrder::custom(valid.clone()).is_ok());
+
+    let mut missing = valid.clone();
+    missing.remove(3);
+    assert_eq!(
+        ExtensionOrder::custom(missing),
+        Err(Error::General(
+            "extension order must include SignatureAlgorithms".into()
+        ))
+    );
+
+    let mut repeated = valid.clone();
+    repeated.push(ExtensionType::ECPointFormats);
+    assert_eq!(
+        ExtensionOrder::custom(repeated),
+        Err(Error::General(
+            "extension order includes ECPointFormats more than once".into()
+        ))
+    );
+
+    let mut psk = valid;
+    psk.insert(0, ExtensionType::PreSharedKey);
+    assert!(ExtensionOrder::custom(psk).is_err());
+}
+
+#[test]
+fn handshakes_complete_with_reordered_extensions() {
+    use rustls::client::ExtensionOrder;
+    use rustls::ExtensionType;
+
+    let custom = ExtensionOrder::custom(vec![
+        ExtensionType::StatusRequest,
+        ExtensionType::KeyShare,
+        ExtensionType::SignatureAlgorithms,
+        ExtensionType::ExtendedMasterSecret,
+        ExtensionType::ECPointFormats,
+        ExtensionType::EllipticCurves,
+        ExtensionType::SupportedVersions,
+    ])
+    .unwrap();
+
+    for order in vec![ExtensionOrder::shuffled(), custom] {
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+            client_config.extension_order = order.clone();
+            client_config.enable_grease = true;
+            let client_config = Arc::new(client_config);
+            let server_config = Arc::new(make_server_config(KeyType::Rsa));
+
+            // resumption checks the PSK extension is still last
+            for &expected_kind in &[HandshakeKind::Full, HandshakeKind::Resumed] {
+                let (mut client, mut server) =
+                    make_pair_for_arc_configs(&client_config, &server_config);
+                do_handshake(&mut client, &mut server);
+                assert_eq!(client.handshake_kind(), Some(expected_kind));
+                transfer(&mut server, &mut client);
+                client.process_new_packets().unwrap();
+            }
+        }
+    }
+}
+
+#[test]
+fn shuffled_extension_order_sends_the_usual_extensions() {
+    let client_config = make_client_config(KeyType::Rsa);
+    let mut client =
+        ClientConnection::new(Arc::new(client_config.clone()), dns_name("localhost")).unwrap();
+    let (mut usual, _) = client_hello_shape(&mut client);
+    usual.sort_by_key(|typ| typ.get_u16());
+
+    let mut client_config = client_config;
+    client_config.extension_order = rustls::client::ExtensionOrder::shuffled();
+    let mut client = ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
+    let (mut shuffled, _) = client_hello_shape(&mut client);
+    shuffled.sort_by_key(|typ| typ.get_u16());
+    assert_eq!(shuffled, usual);
+}
+
+#[test]
+fn test_client_config_keyshare() {
+    let client_config =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+}
+
+#[test]
+fn test_client_config_keyshare_mismatch() {
+    let client_config =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+}
+
+#[test]
+fn test_client_sends_helloretryrequest() {
+    // client sends a secp384r1 key share
+    let mut client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+
+    let storage = Arc::new(ClientStorage::new());
+    client_config.session_storage = storage.clone();
+
+    // but server only accepts x25519, so a HRR is required
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    // client sends hello
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200);
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0].len() == 1);
+    }
+
+    // server sends HRR
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen < 100); // just the hello retry request
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 2); // hello retry request and CCS
+    }
+
+    // client sends fixed hello
+    {
+        let mut pipe = OtherSession::new(&mut server);
+        let wrlen = client.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200); // just the client hello retry
+        assert_eq!(pipe.writevs.len(), 1); // only one writev
+        assert!(pipe.writevs[0].len() == 2); // only a CCS & client hello retry
+    }
+
+    // server completes handshake
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        let wrlen = server.write_tls(&mut pipe).unwrap();
+        assert!(wrlen > 200);
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0].len() == 5); // server hello / encrypted exts / cert / cert-verify / finished
+    }
+
+    do_handshake_until_error(&mut client, &mut server).unwrap();
+
+    // client only did two storage queries: one for a session, another for a kx type
+    assert_eq!(storage.gets(), 2);
+    assert_eq!(storage.puts(), 2);
+}
+
+#[test]
+fn test_client_attempts_to_use_unsupported_kx_group() {
+    // common to both client configs
+    let shared_storage = Arc::new(ClientStorage::new());
+
+    // first, client sends a x25519 and server agrees. x25519 is inserted
+    //   into kx group cache.
+    let mut client_config_1 =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    client_config_1.session_storage = shared_storage.clone();
+
+    // second, client only supports secp-384 and so kx group cache
+    //   contains an unusable value.
+    let mut client_config_2 =
+        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
+    client_config_2.session_storage = shared_storage;
+
+    let server_config = make_server_config(KeyType::Rsa);
+
+    // first handshake
+    let (mut client_1, mut server) = make_pair_for_configs(client_config_1, server_config.clone());
+    do_handshake_until_error(&mut client_1, &mut server).unwrap();
+
+    // second handshake
+    let (mut client_2, mut server) = make_pair_for_configs(client_config_2, server_config);
+    do_handshake_until_error(&mut client_2, &mut server).unwrap();
+}
+
+#[test]
+fn negotiated_key_exchange_group_matches_forced_group() {
+    for version in rustls::ALL_VERSIONS {
+        for kx_group in rustls::ALL_KX_GROUPS.iter() {
+            let client_config = ClientConfig::builder()
+                .with_safe_default_cipher_suites()
+                .with_kx_groups(&[*kx_group])
+                .with_protocol_versions(&[version])
+                .unwrap();
+            let client_config = finish_client_config(KeyType::Rsa, client_config);
+
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+            assert_eq!(client.negotiated_key_exchange_group(), None);
+            assert_eq!(server.negotiated_key_exchange_group(), None);
+
+            do_handshake(&mut client, &mut server);
+            assert_eq!(client.negotiated_key_exchange_group(), Some(kx_group.name));
+            assert_eq!(server.negotiated_key_exchange_group(), Some(kx_group.name));
+        }
+    }
+}
+
+#[test]
+fn negotiated_key_exchange_group_after_helloretryrequest() {
+    // The client's first key share is for secp384r1, but the server
+    // only accepts x25519 so asks for another.
+    let client_config = make_client_config_with_kx_groups(
+        KeyType::Rsa,
+        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
+    );
+    let server_config =
+        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+    // The HelloRetryRequest does not complete a key exchange.
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    assert_eq!(client.negotiated_key_exchange_group(), None);
+    assert_eq!(server.negotiated_key_exchange_group(), None);
+
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_3));
+    assert_eq!(
+        client.negotiated_key_exchange_group(),
+        Some(NamedGroup::X25519)
+    );
+    assert_eq!(
+        server.negotiated_key_exchange_group(),
+        Some(NamedGroup::X25519)
+    );
+}
+
+#[test]
+fn test_client_mtu_reduction() {
+    struct CollectWrites {
+        writevs: Vec<Vec<usize>>,
+    }
+
+    impl io::Write for CollectWrites {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            panic!()
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            panic!()
+        }
+        fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
+            let writes = b
+                .iter()
+                .map(|slice| slice.len())
+                .collect::<Vec<usize>>();
+            let len = writes.iter().sum();
+            self.writevs.push(writes);
+            Ok(len)
+        }
+    }
+
+    fn collect_write_lengths(client: &mut ClientConnection) -> Vec<usize> {
+        let mut collector = CollectWrites { writevs: vec![] };
+
+        client
+            .write_tls(&mut collector)
+            .unwrap();
+        assert_eq!(collector.writevs.len(), 1);
+        collector.writevs[0].clone()
+    }
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let mut client_config = make_client_config(*kt);
+        client_config.max_fragment_size = Some(64);
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
+        let writes = collect_write_lengths(&mut client);
+        println!("writes at mtu=64: {:?}", writes);
+        assert!(writes.iter().all(|x| *x <= 64));
+        assert!(writes.len() > 1);
+    }
+}
+
+#[test]
+fn test_server_mtu_reduction() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.max_fragment_size = Some(64);
+    server_config.send_half_rtt_data = true;
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+
+    let big_data = [0u8; 2048];
+    server
+        .writer()
+        .write_all(&big_data)
+        .unwrap();
+
+    let encryption_overhead = 20; // FIXME: see issue #991
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        server.write_tls(&mut pipe).unwrap();
+
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0]
+            .iter()
+            .all(|x| *x <= 64 + encryption_overhead));
+    }
+
+    client.process_new_packets().unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    {
+        let mut pipe = OtherSession::new(&mut client);
+        server.write_tls(&mut pipe).unwrap();
+        assert_eq!(pipe.writevs.len(), 1);
+        assert!(pipe.writevs[0]
+            .iter()
+            .all(|x| *x <= 64 + encryption_overhead));
+    }
+
+    client.process_new_packets().unwrap();
+    check_read(&mut client.reader(), &big_data);
+}
+
+fn check_client_max_fragment_size(size: usize) -> Option<Error> {
+    let mut client_config = make_client_config(KeyType::Ed25519);
+    client_config.max_fragment_size = Some(size);
+    ClientConnection::new(Arc::new(client_config), dns_name("localhost")).err()
+}
+
+#[test]
+fn bad_client_max_fragment_sizes() {
+    assert_eq!(
+        check_client_max_fragment_size(31),
+        Some(Error::BadMaxFragmentSize)
+    );
+    assert_eq!(check_client_max_fragment_size(32), None);
+    assert_eq!(check_client_max_fragment_size(64), None);
+    assert_eq!(check_client_max_fragment_size(1460), None);
+    assert_eq!(check_client_max_fragment_size(0x4000), None);
+    assert_eq!(check_client_max_fragment_size(0x4005), None);
+    assert_eq!(
+        check_client_max_fragment_size(0x4006),
+        Some(Error::BadMaxFragmentSize)
+    );
+    assert_eq!(
+        check_client_max_fragment_size(0xffff),
+        Some(Error::BadMaxFragmentSize)
+    );
+}
+
+/// Like `transfer`, but check no record carries much more than
+/// `max_len` bytes.
+fn transfer_with_max_fragment_len(
+    left: &mut (impl DerefMut + Deref<Target = ConnectionCommon<impl SideData>>),
+    right: &mut (impl DerefMut + Deref<Target = ConnectionCommon<impl SideData>>),
+    max_len: usize,
+) {
+    // Allows for the header, explicit nonce, tag and TLS1.3 content type.
+    let record_overhead = 5 + 8 + 16 + 1;
+
+    let mut buf = Vec::new();
+    while left.wants_write() {
+        left.write_tls(&mut buf).unwrap();
+    }
+
+    let mut records = &buf[..];
+    while !records.is_empty() {
+        let len = usize::from(u16::from_be_bytes([records[3], records[4]]));
+        assert!(
+            5 + len <= max_len + record_overhead,
+            "record of {} bytes",
+            len
+        );
+        records = &records[5 + len..];
+    }
+
+    let mut rd = &buf[..];
+    while !rd.is_empty() {
+        right.read_tls(&mut rd).unwrap();
+    }
+}
+
+#[test]
+fn max_fragment_length_is_negotiated() {
+    let limits = [
+        MaxFragmentLength::Bytes512,
+        MaxFragmentLength::Bytes1024,
+        MaxFragmentLength::Bytes2048,
+        MaxFragmentLength::Bytes4096,
+    ];
+
+    for version in rustls::ALL_VERSIONS {
+        for mfl in limits.iter() {
+            let max_len = mfl.fragment_len().unwrap();
+            let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+            client_config.max_fragment_length = Some(*mfl);
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+
+            // Everything after the ClientHello is limited, including the
+            // server's certificate.
+            while server.is_handshaking() || client.is_handshaking() {
+                transfer_with_max_fragment_len(&mut client, &mut server, max_len);
+                server.process_new_packets().unwrap();
+                transfer_with_max_fragment_len(&mut server, &mut client, max_len);
+                client.process_new_packets().unwrap();
+            }
+
+            let big_data = [0u8; 5000];
+            client
+                .writer()
+                .write_all(&big_data)
+                .unwrap();
+            server
+                .writer()
+                .write_all(&big_data)
+                .unwrap();
+
+            transfer_with_max_fragment_len(&mut client, &mut server, max_len);
+            server.process_new_packets().unwrap();
+            check_read(&mut server.reader(), &big_data);
+
+            transfer_with_max_fragment_len(&mut server, &mut client, max_len);
+            client.process_new_packets().unwrap();
+            check_read(&mut client.reader(), &big_data);
+        }
+    }
+}
+
+#[test]
+fn client_rejects_unknown_max_fragment_length() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.max_fragment_length = Some(MaxFragmentLength::Unknown(5));
+    assert_eq!(
+        ClientConnection::new(Arc::new(client_config), dns_name("localhost")).err(),
+        Some(Error::BadMaxFragmentSize)
+    );
+}
+
+/// The expansion of each record once the handshake is done, and how much
+/// of a `record_size_limit` is used by the inner content type, for
+/// connections using `version` and the default cipher suites.
+fn record_size_overheads(version: &rustls::SupportedProtocolVersion) -> (usize, usize) {
+    match version.version {
+        ProtocolVersion::TLSv1_3 => (TLS13_RECORD_OVERHEAD, 1),
+        // The explicit nonce and tag of AES-GCM.
+        _ => (8 + 16, 0),
+    }
+}
+
+#[test]
+fn record_size_limits_are_independent() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.record_size_limit = Some(1000);
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.record_size_limit = Some(2000);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        let (overhead, content_type) = record_size_overheads(version);
+        let data = [0u8; 5000];
+
+        // Each side sends what the other asked for.
+        client
+            .writer()
+            .write_all(&data)
+            .unwrap();
+        let lengths = transfer_record_lengths(&mut client, &mut server);
+        assert_eq!(lengths[0], 2000 - content_type + overhead);
+        assert!(lengths
+            .iter()
+            .all(|&len| len <= lengths[0]));
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), &data);
+
+        server
+            .writer()
+            .write_all(&data)
+            .unwrap();
+        let lengths = transfer_record_lengths(&mut server, &mut client);
+        assert_eq!(lengths[0], 1000 - content_type + overhead);
+        assert!(lengths
+            .iter()
+            .all(|&len| len <= lengths[0]));
+        client.process_new_packets().unwrap();
+        check_read(&mut client.reader(), &data);
+    }
+}
+
+#[test]
+fn record_size_limit_is_only_used_if_both_sides_support_it() {
+    for &(client_limit, server_limit) in &[(Some(1000), None), (None, Some(1000))] {
+        let mut client_config = make_client_config(KeyType::Rsa);
+        client_config.record_size_limit = client_limit;
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.record_size_limit = server_limit;
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        let data = [0u8; 5000];
+        client
+            .writer()
+            .write_all(&data)
+            .unwrap();
+        assert_eq!(
+            transfer_record_lengths(&mut client, &mut server),
+            vec![5000 + TLS13_RECORD_OVERHEAD]
+        );
+        server.process_new_packets().unwrap();
+
+        server
+            .writer()
+            .write_all(&data)
+            .unwrap();
+        assert_eq!(
+            transfer_record_lengths(&mut server, &mut client),
+            vec![5000 + TLS13_RECORD_OVERHEAD]
+        );
+        client.process_new_packets().unwrap();
+    }
+}
+
+#[test]
+fn server_ignores_max_fragment_length_alongside_record_size_limit() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.max_fragment_length = Some(MaxFragmentLength::Bytes512);
+        client_config.record_size_limit = Some(1000);
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.record_size_limit = Some(2000);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        let (overhead, content_type) = record_size_overheads(version);
+        server
+            .writer()
+            .write_all(&[0u8; 5000])
+            .unwrap();
+        let lengths = transfer_record_lengths(&mut server, &mut client);
+        assert_eq!(lengths[0], 1000 - content_type + overhead);
+        client.process_new_packets().unwrap();
+    }
+}
+
+#[test]
+fn record_size_limit_must_be_at_least_64() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.record_size_limit = Some(63);
+    assert_eq!(
+        ClientConnection::new(Arc::new(client_config), dns_name("localhost")).err(),
+        Some(Error::BadMaxFragmentSize)
+    );
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.record_size_limit = Some(63);
+    assert_eq!(
+        ServerConnection::new(Arc::new(server_config)).err(),
+        Some(Error::BadMaxFragmentSize)
+    );
+
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.record_size_limit = Some(64);
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.record_size_limit = Some(64);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+}
+
+#[test]
+fn record_size_limit_must_be_at_most_the_protocol_maximum() {
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.record_size_limit = Some(16386);
+    assert_eq!(
+        ClientConnection::new(Arc::new(client_config), dns_name("localhost")).err(),
+        Some(Error::BadMaxFragmentSize)
+    );
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.record_size_limit = Some(16386);
+    assert_eq!(
+        ServerConnection::new(Arc::new(server_config)).err(),
+        Some(Error::BadMaxFragmentSize)
+    );
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.record_size_limit = Some(16385);
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.record_size_limit = Some(16385);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        let data = [0u8; 20000];
+        client
+            .writer()
+            .write_all(&data)
+            .unwrap();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), &data);
+    }
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn record_size_limit_is_clamped_for_tls12() {
+    fn sent_limits(msg: &Message, limits: &RefCell<Vec<u16>>) {
+        if let MessagePayload::Handshake(hs) = &msg.payload {
+            match &hs.payload {
+                HandshakePayload::ClientHello(ch) => {
+                    limits
+                        .borrow_mut()
+                        .extend(ch.get_record_size_limit());
+                }
+                HandshakePayload::ServerHello(sh) => {
+                    for ext in sh.extensions.iter() {
+                        if let ServerExtension::RecordSizeLimit(limit) = ext {
+                            limits.borrow_mut().push(*limit);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    client_config.record_size_limit = Some(16385);
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.record_size_limit = Some(16385);
+    let (client, server) = make_pair_for_configs(client_config, server_config);
+    let (mut client, mut server): (rustls::Connection, rustls::Connection) =
+        (client.into(), server.into());
+
+    let limits = RefCell::new(Vec::new());
+    let observe = |msg: &mut Message| {
+        sent_limits(msg, &limits);
+        Altered::InPlace
+    };
+    transfer_altered(&mut client, observe, &mut server);
+    server.process_new_packets().unwrap();
+    transfer_altered(&mut server, observe, &mut client);
+    client.process_new_packets().unwrap();
+    assert_eq!(limits.into_inner(), vec![16384, 16384]);
+}
+
+/// Like `transfer`, but return the length of each record sent.
+fn transfer_record_lengths(
+    left: &mut (impl DerefMut + Deref<Target = ConnectionCommon<impl SideData>>),
+    right: &mut (impl DerefMut + Deref<Target = ConnectionCommon<impl SideData>>),
+) -> Vec<usize> {
+    let mut buf = Vec::new();
+    while left.wants_write() {
+        left.write_tls(&mut buf).unwrap();
+    }
+
+    let mut lengths = vec![];
+    let mut records = &buf[..];
+    while !records.is_empty() {
+        let len = usize::from(u16::from_be_bytes([records[3], records[4]]));
+        lengths.push(len);
+        records = &records[5 + len..];
+    }
+
+    let mut rd = &buf[..];
+    while !rd.is_empty() {
+        right.read_tls(&mut rd).unwrap();
+    }
+    lengths
+}
+
+/// The TLS1.3 content type and AEAD tag.
+const TLS13_RECORD_OVERHEAD: usize = 1 + 16;
+
+#[test]
+fn max_plaintext_fragment_size_smaller() {
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+
+    client.set_max_plaintext_fragment_size(100);
+    let data = [0u8; 250];
+    client
+        .writer()
+        .write_all(&data)
+        .unwrap();
+
+    let lengths = transfer_record_lengths(&mut client, &mut server);
+    assert_eq!(
+        lengths,
+        vec![
+            100 + TLS13_RECORD_OVERHEAD,
+            100 + TLS13_RECORD_OVERHEAD,
+            50 + TLS13_RECORD_OVERHEAD
+        ]
+    );
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), &data);
+}
+
+#[test]
+fn max_plaintext_fragment_size_larger() {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.max_fragment_size = Some(1000);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+
+    // Replaces the smaller size from the config.
+    client.set_max_plaintext_fragment_size(8000);
+    let data = [0u8; 10000];
+    client
+        .writer()
+        .write_all(&data)
+        .unwrap();
+
+    let lengths = transfer_record_lengths(&mut client, &mut server);
+    assert_eq!(
+        lengths,
+        vec![8000 + TLS13_RECORD_OVERHEAD, 2000 + TLS13_RECORD_OVERHEAD]
+    );
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), &data);
+}
+
+#[test]
+fn max_plaintext_fragment_size_is_clamped() {
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+
+    client.set_max_plaintext_fragment_size(20000);
+    let data = [0u8; 20000];
+    client
+        .writer()
+        .write_all(&data)
+        .unwrap();
+
+    let lengths = transfer_record_lengths(&mut client, &mut server);
+    assert_eq!(
+        lengths,
+        vec![16384 + TLS13_RECORD_OVERHEAD, 3616 + TLS13_RECORD_OVERHEAD]
+    );
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), &data);
+}
+
+#[test]
+fn max_plaintext_fragment_size_coalesces_buffered_writes() {
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+
+    client.set_max_plaintext_fragment_size(100);
+    for _ in 0..10 {
+        client
+            .writer()
+            .write_all(b"012345678901234567890123456789")
+            .unwrap();
+    }
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    // CCS, finished, then the buffered writes packed into full records
+    let lengths = transfer_record_lengths(&mut client, &mut server);
+    assert_eq!(
+        lengths,
+        vec![
+            1,
+            69,
+            100 + TLS13_RECORD_OVERHEAD,
+            100 + TLS13_RECORD_OVERHEAD,
+            100 + TLS13_RECORD_OVERHEAD
+        ]
+    );
+    server.process_new_packets().unwrap();
+    check_read(
+        &mut server.reader(),
+        &b"012345678901234567890123456789".repeat(10),
+    );
+}
+
+#[test]
+fn max_plaintext_fragment_size_coalesces_vectored_writes() {
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+
+    client.set_max_plaintext_fragment_size(100);
+    assert_eq!(
+        client
+            .writer()
+            .write_vectored(&[
+                IoSlice::new(b"0123456789012345678901234567890123456789"),
+                IoSlice::new(b"0123456789012345678901234567890123456789"),
+                IoSlice::new(b"0123456789012345678901234567890123456789")
+            ])
+            .unwrap(),
+        120
+    );
+
+    let lengths = transfer_record_lengths(&mut client, &mut server);
+    assert_eq!(
+        lengths,
+        vec![100 + TLS13_RECORD_OVERHEAD, 20 + TLS13_RECORD_OVERHEAD]
+    );
+    server.process_new_packets().unwrap();
+    check_read(
+        &mut server.reader(),
+        &b"0123456789012345678901234567890123456789".repeat(3),
+    );
+}
+
+/// The length of a TLS1.3 `KeyUpdate` record: the handshake header and
+/// body, then the content type and tag.
+const TLS13_KEY_UPDATE_RECORD_LEN: usize = 4 + 1 + TLS13_RECORD_OVERHEAD;
+
+#[test]
+fn refresh_traffic_keys_self_initiated() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    client.refresh_traffic_keys().unwrap();
+    assert_eq!(
+        transfer_record_lengths(&mut client, &mut server),
+        vec![TLS13_KEY_UPDATE_RECORD_LEN]
+    );
+    server.process_new_packets().unwrap();
+
+    client
+        .writer()
+        .write_all(b"after")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"after");
+}
+
+#[test]
+fn refresh_traffic_keys_peer_initiated() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    server.refresh_traffic_keys().unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    // The client answers with its own KeyUpdate before its next data.
+    client
+        .writer()
+        .write_all(b"reply")
+        .unwrap();
+    assert_eq!(
+        transfer_record_lengths(&mut client, &mut server),
+        vec![TLS13_KEY_UPDATE_RECORD_LEN, 5 + TLS13_RECORD_OVERHEAD]
+    );
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"reply");
+
+    // But only once.
+    client
+        .writer()
+        .write_all(b"again")
+        .unwrap();
+    assert_eq!(
+        transfer_record_lengths(&mut client, &mut server),
+        vec![5 + TLS13_RECORD_OVERHEAD]
+    );
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"again");
+
+    server
+        .writer()
+        .write_all(b"done")
+        .unwrap();
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client.reader(), b"done");
+}
+
+#[test]
+fn refresh_traffic_keys_between_application_data() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    // Neither side processes anything until all of this is written.
+    for _ in 0..3 {
+        client
+            .writer()
+            .write_all(b"client ")
+            .unwrap();
+        client.refresh_traffic_keys().unwrap();
+        server
+            .writer()
+            .write_all(b"server ")
+            .unwrap();
+        server.refresh_traffic_keys().unwrap();
+    }
+    client
+        .writer()
+        .write_all(b"end")
+        .unwrap();
+    server
+        .writer()
+        .write_all(b"end")
+        .unwrap();
+
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"client client client end");
+
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    check_read(&mut client.reader(), b"server server server end");
+
+    // The client answers all of the server's requests with one update.
+    client
+        .writer()
+        .write_all(b"ping")
+        .unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"ping");
+}
+
+#[test]
+fn encrypt_into_writes_record_peer_can_read() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let server_config = make_server_config(KeyType::Rsa);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        let mut buf = [0u8; 256];
+        let len = client
+            .encrypt_into(b"hello", &mut buf)
+            .unwrap();
+        assert!(!client.wants_write());
+        server
+            .read_tls(&mut &buf[..len])
+            .unwrap();
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), b"hello");
+
+        // Records written either way arrive in order.
+        client
+            .writer()
+            .write_all(b"queued ")
+            .unwrap();
+        transfer(&mut client, &mut server);
+        let len = client
+            .encrypt_into(b"direct", &mut buf)
+            .unwrap();
+        server
+            .read_tls(&mut &buf[..len])
+            .unwrap();
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), b"queued direct");
+    }
+}
+
+#[test]
+fn encrypt_into_reports_buffer_size_needed() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    let mut buf = [0u8; 256];
+    let required = 5 + 5 + TLS13_RECORD_OVERHEAD;
+    assert_eq!(
+        client.encrypt_into(b"hello", &mut buf[..required - 1]),
+        Err(Error::EncryptBufferTooSmall { required })
+    );
+
+    // The connection carries on as if nothing happened.
+    assert_eq!(client.encrypt_into(b"hello", &mut buf), Ok(required));
+    server
+        .read_tls(&mut &buf[..required])
+        .unwrap();
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"hello");
+}
+
+#[test]
+fn encrypt_into_fails_after_close_notify() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        do_handshake(&mut client, &mut server);
+
+        client.send_close_notify();
+        let mut buf = [0u8; 256];
+        assert!(matches!(
+            client.encrypt_into(b"hello", &mut buf),
+            Err(Error::General(_))
+        ));
+    }
+}
+
+#[test]
+fn encrypt_into_fails_once_connection_has_failed() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    // An application data record too short to hold an AEAD tag.
+    let record = [0x17u8, 0x03, 0x03, 0x00, 0x01, 0x00];
+    server
+        .read_tls(&mut &record[..])
+        .unwrap();
+    let err = server
+        .process_new_packets()
+        .unwrap_err();
+
+    let mut buf = [0u8; 256];
+    assert_eq!(server.encrypt_into(b"hello", &mut buf), Err(err));
+    assert!(client
+        .encrypt_into(b"hello", &mut buf)
+        .is_ok());
+}
+
+#[test]
+fn encrypt_into_requires_completed_handshake() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    let mut buf = [0u8; 256];
+    assert_eq!(
+        client.encrypt_into(b"hello", &mut buf),
+        Err(Error::HandshakeNotComplete)
+    );
+
+    // Nor can it overtake records which are already queued.
+    do_handshake(&mut client, &mut server);
+    client
+        .writer()
+        .write_all(b"queued")
+        .unwrap();
+    assert!(client
+        .encrypt_into(b"hello", &mut buf)
+        .is_err());
+    transfer(&mut client, &mut server);
+    assert!(client
+        .encrypt_into(b"hello", &mut buf)
+        .is_ok());
+}
+
+#[test]
+fn many_records_in_one_read_are_processed_together() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let server_config = make_server_config(KeyType::Rsa);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        let mut expected = Vec::new();
+        for i in 0..20u8 {
+            let data = [b'a' + i; 10];
+            client
+                .writer()
+                .write_all(&data)
+                .unwrap();
+            expected.extend_from_slice(&data);
+        }
+        let mut tls = Vec::new();
+        while client.wants_write() {
+            client.write_tls(&mut tls).unwrap();
+        }
+
+        // Then the start of one more record.
+        client
+            .writer()
+            .write_all(b"last")
+            .unwrap();
+        let mut last = Vec::new();
+        client.write_tls(&mut last).unwrap();
+        tls.extend_from_slice(&last[..3]);
+
+        assert_eq!(server.read_tls(&mut &tls[..]).unwrap(), tls.len());
+        let io_state = server.process_new_packets().unwrap();
+        assert_eq!(io_state.plaintext_bytes_to_read(), expected.len());
+        check_read(&mut server.reader(), &expected);
+
+        server
+            .read_tls(&mut &last[3..])
+            .unwrap();
+        server.process_new_packets().unwrap();
+        check_read(&mut server.reader(), b"last");
+    }
+}
+
+#[test]
+fn process_new_packets_limited_stops_at_budget() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    for data in [
+        b"0123456789",
+        b"abcdefghij",
+        b"ABCDEFGHIJ",
+        b"klmnopqrst",
+        b"KLMNOPQRST",
+    ]
+    .iter()
+    {
+        client
+            .writer()
+            .write_all(*data)
+            .unwrap();
+    }
+    transfer(&mut client, &mut server);
+
+    // The third record takes the plaintext past the budget.
+    let io_state = server
+        .process_new_packets_limited(25)
+        .unwrap();
+    assert_eq!(io_state.plaintext_bytes_to_read(), 30);
+
+    // Nothing more is processed until some plaintext is read.
+    let io_state = server
+        .process_new_packets_limited(25)
+        .unwrap();
+    assert_eq!(io_state.plaintext_bytes_to_read(), 30);
+
+    let mut buf = [0u8; 15];
+    server
+        .reader()
+        .read_exact(&mut buf)
+        .unwrap();
+    assert_eq!(&buf, b"0123456789abcde");
+
+    let io_state = server
+        .process_new_packets_limited(25)
+        .unwrap();
+    assert_eq!(io_state.plaintext_bytes_to_read(), 25);
+    check_read(&mut server.reader(), b"fghijABCDEFGHIJklmnopqrst");
+
+    // Nothing more is read while a record is held back.
+    assert!(!server.wants_read());
+    client
+        .writer()
+        .write_all(b"later")
+        .unwrap();
+    let mut tls = Vec::new();
+    client.write_tls(&mut tls).unwrap();
+    assert_eq!(server.read_tls(&mut &tls[..]).unwrap(), 0);
+
+    let io_state = server.process_new_packets().unwrap();
+    assert_eq!(io_state.plaintext_bytes_to_read(), 10);
+
+    // Records read later come after those held back.
+    server.read_tls(&mut &tls[..]).unwrap();
+    let io_state = server.process_new_packets().unwrap();
+    assert_eq!(io_state.plaintext_bytes_to_read(), 15);
+    check_read(&mut server.reader(), b"KLMNOPQRSTlater");
+
+    // Refusing to read earlier was not taken as EOF.
+    let err = server
+        .reader()
+        .read(&mut [0u8; 1])
+        .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+}
+
+#[test]
+fn process_new_packets_limited_holds_back_close_notify() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    client.send_close_notify();
+    transfer(&mut client, &mut server);
+
+    let io_state = server
+        .process_new_packets_limited(1)
+        .unwrap();
+    assert_eq!(io_state.plaintext_bytes_to_read(), 5);
+    assert!(!io_state.peer_has_closed());
+
+    check_read(&mut server.reader(), b"hello");
+    let io_state = server
+        .process_new_packets_limited(1)
+        .unwrap();
+    assert!(io_state.peer_has_closed());
+}
+
+#[test]
+fn process_new_packets_limited_with_no_budget_processes_nothing() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+
+    client
+        .writer()
+        .write_all(b"hello")
+        .unwrap();
+    transfer(&mut client, &mut server);
+
+    let io_state = server
+        .process_new_packets_limited(0)
+        .unwrap();
+    assert_eq!(io_state.plaintext_bytes_to_read(), 0);
+    assert!(!server.wants_read());
+
+    let io_state = server
+        .process_new_packets_limited(1)
+        .unwrap();
+    assert_eq!(io_state.plaintext_bytes_to_read(), 5);
+    check_read(&mut server.reader(), b"hello");
+    assert!(server.wants_read());
+}
+
+#[test]
+fn refresh_traffic_keys_needs_tls13() {
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    assert_eq!(
+        client.refresh_traffic_keys(),
+        Err(Error::HandshakeNotComplete)
+    );
+
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+
+    let err = Error::General("key updates require TLS1.3 over TCP".to_string());
+    assert_eq!(client.refresh_traffic_keys(), Err(err.clone()));
+    assert_eq!(server.refresh_traffic_keys(), Err(err));
+    assert!(!client.wants_write());
+    assert!(!server.wants_write());
+}
+
+#[test]
+fn key_update_threshold_triggers_automatic_update() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    do_handshake(&mut client, &mut server);
+    client.set_key_update_threshold(4);
+
+    for i in 0..10u8 {
+        client.writer().write_all(&[i]).unwrap();
+    }
+
+    const DATA: usize = 1 + TLS13_RECORD_OVERHEAD;
+    const KU: usize = TLS13_KEY_UPDATE_RECORD_LEN;
+    assert_eq!(
+        transfer_record_lengths(&mut client, &mut server),
+        vec![DATA, DATA, DATA, DATA, KU, DATA, DATA, DATA, DATA, KU, DATA, DATA]
+    );
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+
+    // Both sides count afresh from the last KeyUpdate.
+    assert_eq!(client.sent_with_current_keys(), (2, 2));
+    assert_eq!(server.received_with_current_keys(), (2, 2));
+
+    // The server does not need to answer.
+    assert!(!server.wants_write());
+}
+
+#[test]
+fn key_update_threshold_is_ignored_for_tls12() {
+    let client_config = make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    let (mut client, mut server) =
+        make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+    do_handshake(&mut client, &mut server);
+    client.set_key_update_threshold(1);
+
+    for _ in 0..3 {
+        client.writer().write_all(b"x").unwrap();
+    }
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+    check_read(&mut server.reader(), b"xxx");
+}
+
+fn assert_lt(left: usize, right: usize) {
+    if left >= right {
+        panic!("expected {} < {}", left, right);
+    }
+}
+
+#[test]
+fn connection_types_are_not_huge() {
+    // Arbitrary sizes
+    assert_lt(mem::size_of::<ServerConnection>(), 1600);
+    assert_lt(mem::size_of::<ClientConnection>(), 1600);
+}
+
+use rustls::internal::msgs::{
+    enums::AlertDescription, enums::AlertLevel, handshake::ClientExtension,
+    handshake::HandshakePayload, handshake::ServerExtension, handshake::ServerHelloPayload,
+    message::Message, message::MessagePayload,
+};
+
+#[test]
+fn test_server_rejects_duplicate_sni_names() {
+    fn duplicate_sni_payload(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                for mut ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::ServerName(snr) = &mut ext {
+                        snr.push(snr[0].clone());
+                    }
+                }
+            }
+        }
+        Altered::InPlace
+    }
+
+    let (client, server) = make_pair(KeyType::Rsa);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, duplicate_sni_payload, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerMisbehavedError(
+            "ClientHello SNI contains duplicate name types".into()
+        ))
+    );
+}
+
+#[test]
+fn test_server_rejects_empty_sni_extension() {
+    fn empty_sni_payload(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                for mut ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::ServerName(snr) = &mut ext {
+                        snr.clear();
+                    }
+                }
+            }
+        }
+        Altered::InPlace
+    }
+
+    let (client, server) = make_pair(KeyType::Rsa);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, empty_sni_payload, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerMisbehavedError(
+            "ClientHello SNI did not contain a hostname".into()
+        ))
+    );
+}
+
+#[test]
+fn server_rejects_too_small_record_size_limit() {
+    fn shrink_record_size_limit(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                for ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::RecordSizeLimit(limit) = ext {
+                        *limit = 63;
+                    }
+                }
+            }
+        }
+        Altered::InPlace
+    }
+
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.record_size_limit = Some(1000);
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.record_size_limit = Some(2000);
+    let (client, server) = make_pair_for_configs(client_config, server_config);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, shrink_record_size_limit, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerMisbehavedError(
+            "client sent record_size_limit below 64".into()
+        ))
+    );
+}
+
+#[cfg(feature = "tls12")]
+/// Transfer the ClientHello from a TLS1.2 client with a record size
+/// limit to a server with one, then the server's reply altered by `filter`.
+fn record_size_limit_tls12_server_hello(filter: fn(&mut ServerHelloPayload)) -> Result<(), Error> {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    client_config.max_fragment_length = Some(MaxFragmentLength::Bytes512);
+    client_config.record_size_limit = Some(1000);
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.record_size_limit = Some(2000);
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    let (mut client, mut server): (rustls::Connection, rustls::Connection) =
+        (client.into(), server.into());
+    transfer_altered(
+        &mut server,
+        |msg| {
+            if let MessagePayload::Handshake(hs) = &mut msg.payload {
+                if let HandshakePayload::ServerHello(sh) = &mut hs.payload {
+                    filter(sh);
+                }
+            }
+            Altered::InPlace
+        },
+        &mut client,
+    );
+    client.process_new_packets().map(|_| ())
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn client_rejects_too_small_record_size_limit() {
+    assert_eq!(
+        record_size_limit_tls12_server_hello(|sh| {
+            for ext in sh.extensions.iter_mut() {
+                if let ServerExtension::RecordSizeLimit(limit) = ext {
+                    *limit = 63;
+                }
+            }
+        }),
+        Err(Error::PeerMisbehavedError(
+            "server sent record_size_limit below 64".into()
+        ))
+    );
+}
+
+#[cfg(feature = "tls12")]
+#[test]
+fn client_rejects_max_fragment_length_alongside_record_size_limit() {
+    assert_eq!(record_size_limit_tls12_server_hello(|_| {}), Ok(()));
+    assert_eq!(
+        record_size_limit_tls12_server_hello(|sh| {
+            sh.extensions
+                .push(ServerExtension::MaxFragmentLength(
+                    MaxFragmentLength::Bytes512,
+                ));
+        }),
+        Err(Error::PeerMisbehavedError(
+            "server sent max_fragment_length and record_size_limit".into()
+        ))
+    );
+}
+
+/// Replaces the SNI hostname in a `ClientHello` with `raw`, as if the
+/// client had sent that.
+fn rewrite_sni(raw: &'static str) -> impl Fn(&mut Message) -> Altered {
+    use rustls::internal::msgs::base::PayloadU16;
+    use rustls::internal::msgs::handshake::ServerNamePayload;
+
+    move |msg: &mut Message| {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                for ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::ServerName(snr) = ext {
+                        if let ServerNamePayload::HostName((name, _)) = &mut snr[0].payload {
+                            *name = PayloadU16::new(raw.as_bytes().to_vec());
+                        }
+                    }
+                }
+            }
+        }
+        Altered::InPlace
+    }
+}
+
+/// Sends `raw_sni` to a server whose SNI normalizer folds case and
+/// removes trailing dots, but rejects labels with hyphens in the third
+/// and fourth positions that aren't IDN A-labels (RFC5890 reserves
+/// them).  Returns the names the normalizer saw, and the server's error
+/// and the alert it sent.
+fn do_sni_normalizer_test(
+    raw_sni: &'static str,
+    expected_sni: &str,
+) -> (Vec<String>, Error, Error) {
+    let seen = Arc::new(Mutex::new(vec![]));
+    let seen_by_normalizer = seen.clone();
+
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.sni_normalizer = Some(Arc::new(move |name: &str| {
+        seen_by_normalizer
+            .lock()
+            .unwrap()
+            .push(name.to_string());
+        if name.split('.').any(|label| {
+            label.get(2..4) == Some("--")
+                && !label
+                    .to_ascii_lowercase()
+                    .starts_with("xn--")
+        }) {
+            return None;
+        }
+        Some(
+            name.trim_end_matches('.')
+                .to_ascii_lowercase(),
+        )
+    }));
+    server_config.cert_resolver = Arc::new(ServerCheckCertResolve {
+        expected_sni: Some(expected_sni.into()),
+        ..Default::default()
+    });
+
+    let (client, server) = make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, rewrite_sni(raw_sni), &mut server);
+    let err = server
+        .process_new_packets()
+        .unwrap_err();
+    transfer_altered(&mut server, |_| Altered::InPlace, &mut client);
+    let alert = client
+        .process_new_packets()
+        .unwrap_err();
+
+    let seen = seen.lock().unwrap().clone();
+    (seen, err, alert)
+}
+
+#[test]
+fn test_server_sni_normalizer_sees_uppercase_name() {
+    let (seen, err, alert) = do_sni_normalizer_test("LocalHost", "localhost");
+    assert_eq!(seen, vec!["LocalHost".to_string()]);
+    assert_eq!(
+        err,
+        Error::General("no server certificate chain resolved".into())
+    );
+    assert_eq!(alert, Error::AlertReceived(AlertDescription::AccessDenied));
+}
+
+#[test]
+fn test_server_sni_normalizer_sees_trailing_dot() {
+    let (seen, err, alert) = do_sni_normalizer_test("localhost.", "localhost");
+    assert_eq!(seen, vec!["localhost.".to_string()]);
+    assert_eq!(
+        err,
+        Error::General("no server certificate chain resolved".into())
+    );
+    assert_eq!(alert, Error::AlertReceived(AlertDescription::AccessDenied));
+}
+
+#[test]
+fn test_server_sni_normalizer_accepts_idn() {
+    let (seen, err, alert) =
+        do_sni_normalizer_test("XN--bcher-kva.example", "xn--bcher-kva.example");
+    assert_eq!(seen, vec!["XN--bcher-kva.example".to_string()]);
+    assert_eq!(
+        err,
+        Error::General("no server certificate chain resolved".into())
+    );
+    assert_eq!(alert, Error::AlertReceived(AlertDescription::AccessDenied));
+}
+
+#[test]
+fn test_server_sni_normalizer_rejects_name() {
+    let (seen, err, alert) = do_sni_normalizer_test("ab--cd.example", "unused");
+    assert_eq!(seen, vec!["ab--cd.example".to_string()]);
+    assert_eq!(err, Error::General("SNI hostname was rejected".into()));
+    assert_eq!(
+        alert,
+        Error::AlertReceived(AlertDescription::UnrecognisedName)
+    );
+}
+
+#[test]
+fn is_resumed_is_false_when_offered_psk_is_rejected() {
+    let client_config = Arc::new(make_client_config_with_versions(
+        KeyType::Rsa,
+        &[&rustls::version::TLS13],
+    ));
+
+    // Get a ticket from one server, then offer it to another.
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+    let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+    do_handshake(&mut client, &mut server);
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+
+    let other_server_config = Arc::new(make_server_config(KeyType::Rsa));
+    let (client, server) = make_pair_for_arc_configs(&client_config, &other_server_config);
+    let (mut client, mut server) = (client.into(), server.into());
+
+    let offered_psk = AtomicBool::new(false);
+    transfer_altered(
+        &mut client,
+        |msg: &mut Message| {
+            if let MessagePayload::Handshake(hs) = &msg.payload {
+                if let HandshakePayload::ClientHello(ch) = &hs.payload {
+                    offered_psk.store(ch.get_psk().is_some(), Ordering::SeqCst);
+                }
+            }
+            Altered::InPlace
+        },
+        &mut server,
+    );
+    assert!(offered_psk.load(Ordering::SeqCst));
+
+    while server.is_handshaking() || client.is_handshaking() {
+        server.process_new_packets().unwrap();
+        transfer_altered(&mut server, |_| Altered::InPlace, &mut client);
+        client.process_new_packets().unwrap();
+        transfer_altered(&mut client, |_| Altered::InPlace, &mut server);
+    }
+    assert!(!client.is_resumed());
+    assert!(!server.is_resumed());
+}
+
+fn make_alert_recording_server_config() -> (
+    ServerConfig,
+    Arc<Mutex<Vec<(AlertLevel, AlertDescription)>>>,
+) {
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.on_alert = Some(Arc::new({
+        let seen = seen.clone();
+        move |level: AlertLevel, description: AlertDescription| {
+            seen.lock()
+                .unwrap()
+                .push((level, description))
+        }
+    }));
+    (server_config, seen)
+}
+
+#[test]
+fn on_alert_sees_warning_alerts() {
+    let (server_config, seen) = make_alert_recording_server_config();
+    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+
+    // A plaintext certificate_expired warning, which TLS1.2 tolerates.
+    let alert = [0x15u8, 0x03, 0x03, 0x00, 0x02, 0x01, 0x2d];
+    server
+        .read_tls(&mut &alert[..])
+        .unwrap();
+    server.process_new_packets().unwrap();
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![(AlertLevel::Warning, AlertDescription::CertificateExpired)]
+    );
+}
+
+#[test]
+fn on_alert_sees_user_canceled_and_close_notify() {
+    for version in rustls::ALL_VERSIONS {
+        let (server_config, seen) = make_alert_recording_server_config();
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        client.send_user_canceled();
+        transfer(&mut client, &mut server);
+        server.process_new_packets().unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (AlertLevel::Warning, AlertDescription::UserCanceled),
+                (AlertLevel::Warning, AlertDescription::CloseNotify),
+            ]
+        );
+    }
+}
+
+#[test]
+fn on_alert_does_not_stop_fatal_alerts() {
+    let (server_config, seen) = make_alert_recording_server_config();
+    let mut server = ServerConnection::new(Arc::new(server_config)).unwrap();
+
+    // A plaintext handshake_failure fatal alert.
+    let alert = [0x15u8, 0x03, 0x03, 0x00, 0x02, 0x02, 0x28];
+    server
+        .read_tls(&mut &alert[..])
+        .unwrap();
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::AlertReceived(AlertDescription::HandshakeFailure))
+    );
+    assert_eq!(
+        *seen.lock().unwrap(),
+        vec![(AlertLevel::Fatal, AlertDescription::HandshakeFailure)]
+    );
+}
+
+#[test]
+fn server_rejects_handshake_message_over_configured_size() {
+    use rustls::internal::msgs::enums::ContentType;
+
+    for version in rustls::ALL_VERSIONS {
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.max_handshake_message_size = 64;
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+        let errors = do_handshake_until_both_error(&mut client, &mut server).unwrap_err();
+        match &errors[0] {
+            ErrorFromPeer::Server(Error::CorruptMessagePayload(c)) => {
+                assert_eq!(c.content_type(), ContentType::Handshake)
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert_eq!(
+            errors[1],
+            ErrorFromPeer::Client(Error::AlertReceived(AlertDescription::DecodeError))
+        );
+    }
+}
+
+#[test]
+fn client_rejects_server_handshake_over_configured_size() {
+    use rustls::internal::msgs::enums::ContentType;
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.max_handshake_size = 1024;
+        let server_config = make_server_config(KeyType::Rsa);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+
+        let errors = do_handshake_until_both_error(&mut client, &mut server).unwrap_err();
+        match &errors[0] {
+            ErrorFromPeer::Client(Error::CorruptMessagePayload(c)) => {
+                assert_eq!(c.content_type(), ContentType::Handshake)
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert_eq!(
+            errors[1],
+            ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::DecodeError))
+        );
+    }
+}
+
+#[test]
+fn server_rejects_oversized_handshake_message_header_by_default() {
+    use rustls::internal::msgs::enums::ContentType;
+
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+    let mut server = ServerConnection::new(server_config).unwrap();
+
+    // A ClientHello header claiming 65536 bytes, more than the default allows.
+    let record = [0x16u8, 0x03, 0x03, 0x00, 0x04, 0x01, 0x01, 0x00, 0x00];
+    server
+        .read_tls(&mut &record[..])
+        .unwrap();
+    match server.process_new_packets() {
+        Err(Error::CorruptMessagePayload(c)) => {
+            assert_eq!(c.content_type(), ContentType::Handshake)
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+/// Takes the client's `ClientHello` record and splits it into at
+/// most `count` records, of one byte each if `count` is large enough.
+fn split_client_hello(client: &mut ClientConnection, count: usize) -> Vec<u8> {
+    let mut record = Vec::new();
+    client.write_tls(&mut record).unwrap();
+    let (header, payload) = record.split_at(5);
+    assert_eq!(
+        usize::from(u16::from_be_bytes([header[3], header[4]])),
+        payload.len()
+    );
+
+    let mut records = Vec::new();
+    for fragment in payload.chunks((payload.len() - 1) / count + 1) {
+        records.extend_from_slice(&header[..3]);
+        records.extend_from_slice(&(fragment.len() as u16).to_be_bytes());
+        records.extend_from_slice(fragment);
+    }
+    records
+}
+
+#[test]
+fn server_accepts_client_hello_in_many_records() {
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    let records = split_client_hello(&mut client, 32);
+    server
+        .read_tls(&mut &records[..])
+        .unwrap();
+    server.process_new_packets().unwrap();
+    do_handshake(&mut client, &mut server);
+}
+
+#[test]
+fn server_rejects_client_hello_in_too_many_records() {
+    use rustls::internal::msgs::enums::ContentType;
+
+    let (mut client, mut server) = make_pair(KeyType::Rsa);
+    let records = split_client_hello(&mut client, usize::MAX);
+    server
+        .read_tls(&mut &records[..])
+        .unwrap();
+    match server.process_new_packets() {
+        Err(Error::CorruptMessagePayload(c)) => {
+            assert_eq!(c.content_type(), ContentType::Handshake)
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    transfer(&mut server, &mut client);
+    assert_eq!(
+        client.process_new_packets(),
+        Err(Error::AlertReceived(AlertDescription::DecodeError))
+    );
+}
+
+#[test]
+fn server_accepts_client_hello_in_many_records_if_configured() {
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.max_client_hello_records = usize::MAX;
+    let (mut client, mut server) =
+        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
+
+    let records = split_client_hello(&mut client, usize::MAX);
+    server
+        .read_tls(&mut &records[..])
+        .unwrap();
+    server.process_new_packets().unwrap();
+    do_handshake(&mut client, &mut server);
+}
+
+#[test]
+fn acceptor_rejects_client_hello_in_too_many_records() {
+    use rustls::server::Acceptor;
+
+    let (mut client, _) = make_pair(KeyType::Rsa);
+    let records = split_client_hello(&mut client, usize::MAX);
+
+    let mut acceptor = Acceptor::new().unwrap();
+    acceptor
+        .read_tls(&mut &records[..])
+        .unwrap();
+    assert!(acceptor.accept().is_err());
+}
 
-        assert_eq!(&buf, PROTECTED);
+#[test]
+fn client_accepts_server_cert_chain_at_limit() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.max_peer_cert_chain_len = KeyType::Rsa.get_chain().len();
+        let server_config = make_server_config(KeyType::Rsa);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+    }
+}
 
-        let (header, payload) = buf.split_at_mut(header_len);
-        let (first, rest) = header.split_at_mut(1);
-        let sample = &payload[..sample_len];
+#[test]
+fn client_rejects_server_cert_chain_over_limit() {
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.max_peer_cert_chain_len = KeyType::Rsa.get_chain().len() - 1;
+        let server_config = make_server_config(KeyType::Rsa);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-        let server_keys = Keys::initial(Version::V1, &CONNECTION_ID, false);
-        server_keys
-            .remote
-            .header
-            .decrypt_in_place(sample, &mut first[0], &mut rest[17..21])
-            .unwrap();
-        let payload = server_keys
-            .remote
-            .packet
-            .decrypt_in_place(PACKET_NUMBER, &*header, payload)
-            .unwrap();
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Client(Error::PeerMisbehavedError(
+                    "peer certificate chain too long".into()
+                )),
+                ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::BadCertificate)),
+            ])
+        );
+    }
+}
 
-        assert_eq!(&payload[..PAYLOAD.len()], PAYLOAD);
-        assert_eq!(payload.len(), buf.len() - header_len - tag_len);
+#[test]
+fn server_accepts_client_cert_chain_at_limit() {
+    for version in rustls::ALL_VERSIONS {
+        let mut server_config = make_server_config_with_mandatory_client_auth(KeyType::Rsa);
+        server_config.max_peer_cert_chain_len = KeyType::Rsa.get_client_chain().len();
+        let client_config = make_client_config_with_versions_with_auth(KeyType::Rsa, &[version]);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
     }
+}
 
-    #[test]
-    fn test_quic_exporter() {
-        for &kt in ALL_KEY_TYPES.iter() {
-            let client_config = make_client_config_with_versions(kt, &[&rustls::version::TLS13]);
-            let server_config = make_server_config_with_versions(kt, &[&rustls::version::TLS13]);
+#[test]
+fn server_rejects_client_cert_chain_over_limit() {
+    for version in rustls::ALL_VERSIONS {
+        let mut server_config = make_server_config_with_mandatory_client_auth(KeyType::Rsa);
+        server_config.max_peer_cert_chain_len = KeyType::Rsa.get_client_chain().len() - 1;
+        let client_config = make_client_config_with_versions_with_auth(KeyType::Rsa, &[version]);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
 
-            do_exporter_test(client_config, server_config);
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Server(Error::PeerMisbehavedError(
+                    "peer certificate chain too long".into()
+                )),
+                ErrorFromPeer::Client(Error::AlertReceived(AlertDescription::BadCertificate)),
+            ])
+        );
+    }
+}
+
+#[test]
+fn test_server_rejects_clients_without_any_kx_group_overlap() {
+    fn different_kx_group(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                for mut ext in ch.extensions.iter_mut() {
+                    if let ClientExtension::NamedGroups(ngs) = &mut ext {
+                        ngs.clear();
+                    }
+                    if let ClientExtension::KeyShare(ks) = &mut ext {
+                        ks.clear();
+                    }
+                }
+            }
         }
+        Altered::InPlace
     }
-} // mod test_quic
+
+    let (client, server) = make_pair(KeyType::Rsa);
+    let (mut client, mut server) = (client.into(), server.into());
+    transfer_altered(&mut client, different_kx_group, &mut server);
+    assert_eq!(
+        server.process_new_packets(),
+        Err(Error::PeerIncompatibleError(
+            "no kx group overlap with client".into()
+        ))
+    );
+}
 
 #[test]
-fn test_client_does_not_offer_sha1() {
-    use rustls::internal::msgs::{
-        codec::Reader, enums::HandshakeType, handshake::HandshakePayload, message::MessagePayload,
-        message::OpaqueMessage,
-    };
+fn test_server_staples_ocsp_only_when_requested() {
+    use rustls::internal::msgs::codec::Reader;
+    use rustls::internal::msgs::enums::{ContentType, ExtensionType};
+    use rustls::internal::msgs::handshake::ServerExtension;
+    use rustls::internal::msgs::message::OpaqueMessage;
 
-    for kt in ALL_KEY_TYPES.iter() {
-        for version in rustls::ALL_VERSIONS {
-            let client_config = make_client_config_with_versions(*kt, &[version]);
-            let (mut client, _) = make_pair_for_configs(client_config, make_server_config(*kt));
+    fn no_status_request(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                ch.extensions
+                    .retain(|ext| ext.get_type() != ExtensionType::StatusRequest);
+            }
+        }
+        Altered::InPlace
+    }
 
-            assert!(client.wants_write());
-            let mut buf = [0u8; 262144];
-            let sz = client
-                .write_tls(&mut buf.as_mut())
-                .unwrap();
-            let msg = OpaqueMessage::read(&mut Reader::init(&buf[..sz])).unwrap();
-            let msg = Message::try_from(msg.into_plain_message()).unwrap();
-            assert!(msg.is_handshake_type(HandshakeType::ClientHello));
+    // Returns whether the server's first flight acknowledged the
+    // status_request in its ServerHello, and the OCSP response it
+    // stapled, if any.  The TLS1.3 flight is decrypted with the
+    // server's logged handshake traffic secret.
+    fn server_flight_staples(
+        version: &'static rustls::SupportedProtocolVersion,
+        request_status: bool,
+    ) -> (bool, Option<Vec<u8>>) {
+        let key_log = Arc::new(KeyLogToVec::new("server"));
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let mut server_config = make_server_config_with_ocsp(KeyType::Rsa, b"ocsp response");
+        server_config.key_log = key_log.clone();
+        let (client, server) = make_pair_for_configs(client_config, server_config);
+        let (mut client, mut server): (rustls::Connection, rustls::Connection) =
+            (client.into(), server.into());
+        match request_status {
+            true => transfer_altered(&mut client, |_| Altered::InPlace, &mut server),
+            false => transfer_altered(&mut client, no_status_request, &mut server),
+        };
+        server.process_new_packets().unwrap();
 
-            let client_hello = match msg.payload {
-                MessagePayload::Handshake(hs) => match hs.payload {
-                    HandshakePayload::ClientHello(ch) => ch,
-                    _ => unreachable!(),
-                },
-                _ => unreachable!(),
-            };
+        let decrypter = match server.negotiated_cipher_suite() {
+            Some(rustls::SupportedCipherSuite::Tls13(suite)) => {
+                let secret = key_log
+                    .take()
+                    .into_iter()
+                    .find(|item| item.label == "SERVER_HANDSHAKE_TRAFFIC_SECRET")
+                    .unwrap()
+                    .secret;
+                let secret = ring::hkdf::Prk::new_less_safe(suite.hkdf_algorithm, &secret);
+                Some(suite.derive_decrypter(&secret))
+            }
+            _ => None,
+        };
 
-            let sigalgs = client_hello
-                .get_sigalgs_extension()
-                .unwrap();
-            assert!(
-                !sigalgs.contains(&SignatureScheme::RSA_PKCS1_SHA1),
-                "sha1 unexpectedly offered"
-            );
+        let mut buf = Vec::new();
+        server.write_tls(&mut buf).unwrap();
+        let mut reader = Reader::init(&buf);
+        let (mut acked, mut stapled) = (false, None);
+        let mut seq = 0;
+        while reader.any_left() {
+            let msg = OpaqueMessage::read(&mut reader).unwrap();
+            let msg = match (&decrypter, msg.typ) {
+                (_, ContentType::ChangeCipherSpec) => continue,
+                (Some(decrypter), ContentType::ApplicationData) => {
+                    seq += 1;
+                    decrypter.decrypt(msg, seq - 1).unwrap()
+                }
+                _ => msg.into_plain_message(),
+            };
+            if let MessagePayload::Handshake(hs) = Message::try_from(msg).unwrap().payload {
+                match hs.payload {
+                    HandshakePayload::ServerHello(sh) => {
+                        acked = sh
+                            .extensions
+                            .iter()
+                            .any(|ext| matches!(ext, ServerExtension::CertificateStatusAck));
+                    }
+                    HandshakePayload::CertificateStatus(status) => {
+                        stapled = Some(status.ocsp_response.0);
+                    }
+                    HandshakePayload::CertificateTLS13(cert) => {
+                        let ocsp = cert.get_end_entity_ocsp();
+                        stapled = Some(ocsp).filter(|ocsp| !ocsp.is_empty());
+                    }
+                    _ => {}
+                }
+            }
         }
+        (acked, stapled)
+    }
+
+    let ocsp = Some(b"ocsp response".to_vec());
+    for version in rustls::ALL_VERSIONS {
+        // Only TLS1.2 acknowledges the request in its ServerHello.
+        let acked = version.version == ProtocolVersion::TLSv1_2;
+        assert_eq!(server_flight_staples(version, true), (acked, ocsp.clone()));
+        assert_eq!(server_flight_staples(version, false), (false, None));
     }
 }
 
-#[test]
-fn test_client_config_keyshare() {
-    let client_config =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-    do_handshake_until_error(&mut client, &mut server).unwrap();
+#[cfg(feature = "tls12")]
+fn make_ems_configs(client_requires: bool, server_requires: bool) -> (ClientConfig, ServerConfig) {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS12]);
+    client_config.require_ems = client_requires;
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.require_ems = server_requires;
+    (client_config, server_config)
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn test_client_config_keyshare_mismatch() {
-    let client_config =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+fn required_ems_is_satisfied_by_peer_supporting_it() {
+    let (client_config, server_config) = make_ems_configs(true, true);
     let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
-    assert!(do_handshake_until_error(&mut client, &mut server).is_err());
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_2));
+
+    // TLS1.3 has no use for it, so is unaffected
+    let mut client_config = make_client_config(KeyType::Rsa);
+    client_config.require_ems = true;
+    let mut server_config = make_server_config(KeyType::Rsa);
+    server_config.require_ems = true;
+    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    do_handshake(&mut client, &mut server);
+    assert_eq!(client.protocol_version(), Some(ProtocolVersion::TLSv1_3));
 }
 
+#[cfg(feature = "tls12")]
 #[test]
-fn test_client_sends_helloretryrequest() {
-    // client sends a secp384r1 key share
-    let mut client_config = make_client_config_with_kx_groups(
-        KeyType::Rsa,
-        &[&rustls::kx_group::SECP384R1, &rustls::kx_group::X25519],
-    );
+fn server_requiring_ems_rejects_client_without_it() {
+    use rustls::internal::msgs::enums::ExtensionType;
 
-    let storage = Arc::new(ClientStorage::new());
-    client_config.session_storage = storage.clone();
-
-    // but server only accepts x25519, so a HRR is required
-    let server_config =
-        make_server_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
+    fn no_ems(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
+                ch.extensions
+                    .retain(|ext| ext.get_type() != ExtensionType::ExtendedMasterSecret);
+            }
+        }
+        Altered::InPlace
+    }
 
-    let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+    for &server_requires in &[false, true] {
+        let (client_config, server_config) = make_ems_configs(false, server_requires);
+        let (client, server) = make_pair_for_configs(client_config, server_config);
+        let (mut client, mut server): (rustls::Connection, rustls::Connection) =
+            (client.into(), server.into());
+        transfer_altered(&mut client, no_ems, &mut server);
 
-    // client sends hello
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200);
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0].len() == 1);
+        if server_requires {
+            assert_eq!(
+                server.process_new_packets(),
+                Err(Error::PeerIncompatibleError(
+                    "client did not offer extended master secret".into()
+                ))
+            );
+        } else {
+            assert!(server.process_new_packets().is_ok());
+        }
     }
+}
 
-    // server sends HRR
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert!(wrlen < 100); // just the hello retry request
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 2); // hello retry request and CCS
-    }
+#[cfg(feature = "tls12")]
+#[test]
+fn client_requiring_ems_rejects_server_without_it() {
+    use rustls::internal::msgs::enums::ExtensionType;
 
-    // client sends fixed hello
-    {
-        let mut pipe = OtherSession::new(&mut server);
-        let wrlen = client.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200); // just the client hello retry
-        assert_eq!(pipe.writevs.len(), 1); // only one writev
-        assert!(pipe.writevs[0].len() == 2); // only a CCS & client hello retry
+    fn no_ems_ack(msg: &mut Message) -> Altered {
+        if let MessagePayload::Handshake(hs) = &mut msg.payload {
+            if let HandshakePayload::ServerHello(sh) = &mut hs.payload {
+                sh.extensions
+                    .retain(|ext| ext.get_type() != ExtensionType::ExtendedMasterSecret);
+            }
+        }
+        Altered::InPlace
     }
 
-    // server completes handshake
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        let wrlen = server.write_tls(&mut pipe).unwrap();
-        assert!(wrlen > 200);
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0].len() == 5); // server hello / encrypted exts / cert / cert-verify / finished
-    }
+    for &client_requires in &[false, true] {
+        let (client_config, server_config) = make_ems_configs(client_requires, false);
+        let (client, server) = make_pair_for_configs(client_config, server_config);
+        let (mut client, mut server): (rustls::Connection, rustls::Connection) =
+            (client.into(), server.into());
+        transfer_altered(&mut client, |_| Altered::InPlace, &mut server);
+        server.process_new_packets().unwrap();
+        transfer_altered(&mut server, no_ems_ack, &mut client);
 
-    do_handshake_until_error(&mut client, &mut server).unwrap();
+        if client_requires {
+            assert_eq!(
+                client.process_new_packets(),
+                Err(Error::PeerIncompatibleError(
+                    "server did not agree to extended master secret".into()
+                ))
+            );
+        } else {
+            assert!(client.process_new_packets().is_ok());
+        }
+    }
+}
 
-    // client only did two storage queries: one for a session, another for a kx type
-    assert_eq!(storage.gets(), 2);
-    assert_eq!(storage.puts(), 2);
+#[cfg(feature = "dangerous_configuration")]
+fn make_seeded_client_config(
+    versions: &[&'static rustls::SupportedProtocolVersion],
+    seed: &[u8],
+) -> ClientConfig {
+    let mut client_config = make_client_config_with_versions(KeyType::Rsa, versions);
+    client_config
+        .dangerous()
+        .set_nonce_source(Arc::new(rustls::rand::SeededNonceSource::new(seed)));
+    client_config
 }
 
+#[cfg(all(feature = "dangerous_configuration", feature = "tls12"))]
 #[test]
-fn test_client_attempts_to_use_unsupported_kx_group() {
-    // common to both client configs
-    let shared_storage = Arc::new(ClientStorage::new());
-
-    // first, client sends a x25519 and server agrees. x25519 is inserted
-    //   into kx group cache.
-    let mut client_config_1 =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::X25519]);
-    client_config_1.session_storage = shared_storage.clone();
-
-    // second, client only supports secp-384 and so kx group cache
-    //   contains an unusable value.
-    let mut client_config_2 =
-        make_client_config_with_kx_groups(KeyType::Rsa, &[&rustls::kx_group::SECP384R1]);
-    client_config_2.session_storage = shared_storage;
+fn seeded_tls12_client_hellos_are_identical() {
+    fn first_flight(seed: &[u8]) -> Vec<u8> {
+        let client_config = make_seeded_client_config(&[&rustls::version::TLS12], seed);
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
+        let mut buf = Vec::new();
+        client.write_tls(&mut buf).unwrap();
+        buf
+    }
 
-    let server_config = make_server_config(KeyType::Rsa);
+    assert_eq!(first_flight(b"seed"), first_flight(b"seed"));
+    assert_ne!(first_flight(b"seed"), first_flight(b"other seed"));
+}
 
-    // first handshake
-    let (mut client_1, mut server) = make_pair_for_configs(client_config_1, server_config.clone());
-    do_handshake_until_error(&mut client_1, &mut server).unwrap();
+#[cfg(feature = "dangerous_configuration")]
+#[test]
+fn seeded_tls13_client_hellos_differ_only_in_key_shares() {
+    use rustls::client::ExtensionOrder;
+    use rustls::internal::msgs::codec::Codec;
+
+    fn hello_without_key_shares(seed: &[u8]) -> Vec<u8> {
+        let mut client_config = make_seeded_client_config(&[&rustls::version::TLS13], seed);
+        client_config.enable_grease = true;
+        client_config.extension_order = ExtensionOrder::shuffled();
+        let mut client =
+            ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
+        let (mut ch, _) = sent_client_hello(&mut client);
+        ch.extensions
+            .retain(|ext| ext.get_type() != rustls::ExtensionType::KeyShare);
+        ch.get_encoding()
+    }
 
-    // second handshake
-    let (mut client_2, mut server) = make_pair_for_configs(client_config_2, server_config);
-    do_handshake_until_error(&mut client_2, &mut server).unwrap();
+    assert_eq!(
+        hello_without_key_shares(b"seed"),
+        hello_without_key_shares(b"seed")
+    );
+    assert_ne!(
+        hello_without_key_shares(b"seed"),
+        hello_without_key_shares(b"other seed")
+    );
 }
 
+#[cfg(feature = "dangerous_configuration")]
 #[test]
-fn test_client_mtu_reduction() {
-    struct CollectWrites {
-        writevs: Vec<Vec<usize>>,
+fn seeded_handshakes_complete() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_seeded_client_config(&[version], b"client seed");
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config
+            .dangerous()
+            .set_nonce_source(Arc::new(rustls::rand::SeededNonceSource::new(
+                b"server seed",
+            )));
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &Arc::new(server_config));
+        do_handshake(&mut client, &mut server);
+        assert_eq!(client.protocol_version(), Some(version.version));
+
+        server
+            .writer()
+            .write_all(b"hello")
+            .unwrap();
+        transfer(&mut server, &mut client);
+        client.process_new_packets().unwrap();
+        check_read(&mut client.reader(), b"hello");
     }
+}
 
-    impl io::Write for CollectWrites {
-        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
-            panic!()
-        }
-        fn flush(&mut self) -> io::Result<()> {
-            panic!()
-        }
-        fn write_vectored<'b>(&mut self, b: &[io::IoSlice<'b>]) -> io::Result<usize> {
-            let writes = b
-                .iter()
-                .map(|slice| slice.len())
-                .collect::<Vec<usize>>();
-            let len = writes.iter().sum();
-            self.writevs.push(writes);
-            Ok(len)
+#[test]
+fn test_client_ocsp_verifier_accepts_good_response() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let verifier = MockCertCheck::new(Ok(OcspStatus::Good));
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.ocsp_verifier = Some(verifier.clone());
+            let server_config = make_server_config_with_ocsp(*kt, b"good");
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+            do_handshake(&mut client, &mut server);
+
+            // The end-entity certificate and its issuer.
+            let chain = kt.get_chain();
+            assert_eq!(verifier.checked(), vec![chain[..2].to_vec()]);
         }
     }
+}
 
-    fn collect_write_lengths(client: &mut ClientConnection) -> Vec<usize> {
-        let mut collector = CollectWrites { writevs: vec![] };
-
-        client
-            .write_tls(&mut collector)
-            .unwrap();
-        assert_eq!(collector.writevs.len(), 1);
-        collector.writevs[0].clone()
+#[test]
+fn test_client_ocsp_verifier_not_called_without_staple() {
+    for version in rustls::ALL_VERSIONS {
+        let verifier = MockCertCheck::new(Ok(OcspStatus::Good));
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.ocsp_verifier = Some(verifier.clone());
+        let server_config = make_server_config_with_ocsp(KeyType::Rsa, b"");
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+        assert!(verifier.checked().is_empty());
     }
+}
 
+#[test]
+fn test_client_ocsp_verifier_rejects_revoked_certificate() {
     for kt in ALL_KEY_TYPES.iter() {
-        let mut client_config = make_client_config(*kt);
-        client_config.max_fragment_size = Some(64);
-        let mut client =
-            ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
-        let writes = collect_write_lengths(&mut client);
-        println!("writes at mtu=64: {:?}", writes);
-        assert!(writes.iter().all(|x| *x <= 64));
-        assert!(writes.len() > 1);
+        for version in rustls::ALL_VERSIONS {
+            let verifier = MockCertCheck::new(Ok(OcspStatus::Revoked));
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.ocsp_verifier = Some(verifier.clone());
+            let server_config = make_server_config_with_ocsp(*kt, b"revoked");
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+            assert_eq!(
+                do_handshake_until_both_error(&mut client, &mut server),
+                Err(vec![
+                    ErrorFromPeer::Client(Error::CertificateRevoked),
+                    ErrorFromPeer::Server(Error::AlertReceived(
+                        AlertDescription::CertificateRevoked
+                    )),
+                ])
+            );
+        }
     }
 }
 
 #[test]
-fn test_server_mtu_reduction() {
-    let mut server_config = make_server_config(KeyType::Rsa);
-    server_config.max_fragment_size = Some(64);
-    server_config.send_half_rtt_data = true;
-    let (mut client, mut server) =
-        make_pair_for_configs(make_client_config(KeyType::Rsa), server_config);
-
-    let big_data = [0u8; 2048];
-    server
-        .writer()
-        .write_all(&big_data)
-        .unwrap();
+fn test_client_ocsp_verifier_rejects_malformed_response() {
+    for version in rustls::ALL_VERSIONS {
+        let verifier = MockCertCheck::<OcspStatus>::new(Err(Error::InvalidCertificateEncoding));
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.ocsp_verifier = Some(verifier.clone());
+        let server_config = make_server_config_with_ocsp(KeyType::Rsa, b"\x30\x03garbage");
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Client(Error::InvalidCertificateEncoding),
+                ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::DecodeError)),
+            ])
+        );
+    }
+}
 
-    let encryption_overhead = 20; // FIXME: see issue #991
+/// A CT log with a freshly generated key, for signing SCTs over the
+/// test certificates.
+struct TestCtLog {
+    key: ring::signature::EcdsaKeyPair,
+    log: &'static sct::Log<'static>,
+}
 
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        server.write_tls(&mut pipe).unwrap();
+impl TestCtLog {
+    fn new() -> Self {
+        use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
 
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0]
-            .iter()
-            .all(|x| *x <= 64 + encryption_overhead));
+        let rng = ring::rand::SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref()).unwrap();
+        let public_key: &'static [u8] = Box::leak(
+            key.public_key()
+                .as_ref()
+                .to_vec()
+                .into_boxed_slice(),
+        );
+        let mut id = [0u8; 32];
+        id.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, public_key).as_ref());
+
+        let log = Box::leak(Box::new(sct::Log {
+            description: "test log",
+            url: "",
+            operated_by: "rustls tests",
+            key: public_key,
+            id,
+            max_merge_delay: 0,
+        }));
+        Self { key, log }
     }
 
-    client.process_new_packets().unwrap();
-    transfer(&mut client, &mut server);
-    server.process_new_packets().unwrap();
-    {
-        let mut pipe = OtherSession::new(&mut client);
-        server.write_tls(&mut pipe).unwrap();
-        assert_eq!(pipe.writevs.len(), 1);
-        assert!(pipe.writevs[0]
-            .iter()
-            .all(|x| *x <= 64 + encryption_overhead));
+    /// Returns an encoded SCT (RFC6962 section 3.2) for `cert`.
+    fn sign_sct(&self, cert: &rustls::Certificate) -> Vec<u8> {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+            - 60_000;
+
+        // version v1, certificate_timestamp, then an x509_entry
+        let mut signed = vec![0, 0];
+        signed.extend_from_slice(&timestamp.to_be_bytes());
+        signed.extend_from_slice(&[0, 0]);
+        let len = cert.0.len();
+        signed.extend_from_slice(&[(len >> 16) as u8, (len >> 8) as u8, len as u8]);
+        signed.extend_from_slice(&cert.0);
+        signed.extend_from_slice(&[0, 0]);
+        let signature = self
+            .key
+            .sign(&ring::rand::SystemRandom::new(), &signed)
+            .unwrap();
+
+        let mut sct = vec![0];
+        sct.extend_from_slice(&self.log.id);
+        sct.extend_from_slice(&timestamp.to_be_bytes());
+        sct.extend_from_slice(&[0, 0]);
+        // ecdsa with sha256
+        sct.extend_from_slice(&[4, 3]);
+        sct.extend_from_slice(&(signature.as_ref().len() as u16).to_be_bytes());
+        sct.extend_from_slice(signature.as_ref());
+        sct
     }
+}
 
-    client.process_new_packets().unwrap();
-    check_read(&mut client.reader(), &big_data);
+fn make_ct_policy(logs: &[&TestCtLog], min_scts: usize) -> rustls::client::CtPolicy {
+    let logs = logs
+        .iter()
+        .map(|log| log.log)
+        .collect::<Vec<_>>();
+    rustls::client::CtPolicy::new(Box::leak(logs.into_boxed_slice()), min_scts)
 }
 
-fn check_client_max_fragment_size(size: usize) -> Option<Error> {
-    let mut client_config = make_client_config(KeyType::Ed25519);
-    client_config.max_fragment_size = Some(size);
-    ClientConnection::new(Arc::new(client_config), dns_name("localhost")).err()
+/// An encoded SCT list with an SCT from each of `signers` for `cert`.
+fn sct_list(signers: &[&TestCtLog], cert: &rustls::Certificate) -> Vec<u8> {
+    let mut scts = vec![];
+    for signer in signers {
+        let sct = signer.sign_sct(cert);
+        scts.extend_from_slice(&(sct.len() as u16).to_be_bytes());
+        scts.extend_from_slice(&sct);
+    }
+    let mut encoded = (scts.len() as u16)
+        .to_be_bytes()
+        .to_vec();
+    encoded.extend_from_slice(&scts);
+    encoded
 }
 
 #[test]
-fn bad_client_max_fragment_sizes() {
-    assert_eq!(
-        check_client_max_fragment_size(31),
-        Some(Error::BadMaxFragmentSize)
-    );
-    assert_eq!(check_client_max_fragment_size(32), None);
-    assert_eq!(check_client_max_fragment_size(64), None);
-    assert_eq!(check_client_max_fragment_size(1460), None);
-    assert_eq!(check_client_max_fragment_size(0x4000), None);
-    assert_eq!(check_client_max_fragment_size(0x4005), None);
-    assert_eq!(
-        check_client_max_fragment_size(0x4006),
-        Some(Error::BadMaxFragmentSize)
-    );
-    assert_eq!(
-        check_client_max_fragment_size(0xffff),
-        Some(Error::BadMaxFragmentSize)
-    );
-}
+fn test_client_ct_policy_accepts_enough_valid_scts() {
+    let (log_a, log_b) = (TestCtLog::new(), TestCtLog::new());
 
-fn assert_lt(left: usize, right: usize) {
-    if left >= right {
-        panic!("expected {} < {}", left, right);
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.ct_policy = Some(make_ct_policy(&[&log_a, &log_b], 2));
+            let scts = sct_list(&[&log_a, &log_b], &kt.get_chain()[0]);
+            let server_config = make_server_config_with_scts(*kt, &scts);
+            let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+            do_handshake(&mut client, &mut server);
+        }
     }
 }
 
 #[test]
-fn connection_types_are_not_huge() {
-    // Arbitrary sizes
-    assert_lt(mem::size_of::<ServerConnection>(), 1600);
-    assert_lt(mem::size_of::<ClientConnection>(), 1600);
-}
+fn test_client_ct_policy_rejects_too_few_scts() {
+    let (log_a, log_b) = (TestCtLog::new(), TestCtLog::new());
 
-use rustls::internal::msgs::{
-    handshake::ClientExtension, handshake::HandshakePayload, message::Message,
-    message::MessagePayload,
-};
+    for version in rustls::ALL_VERSIONS {
+        let kt = KeyType::Rsa;
+        let mut client_config = make_client_config_with_versions(kt, &[version]);
+        client_config.ct_policy = Some(make_ct_policy(&[&log_a, &log_b], 2));
+        // Two SCTs from the same log only count once.
+        let scts = sct_list(&[&log_a, &log_a], &kt.get_chain()[0]);
+        let server_config = make_server_config_with_scts(kt, &scts);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Client(Error::TooFewValidScts {
+                    required: 2,
+                    valid: 1
+                }),
+                ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::BadCertificate)),
+            ])
+        );
+    }
+}
 
 #[test]
-fn test_server_rejects_duplicate_sni_names() {
-    fn duplicate_sni_payload(msg: &mut Message) -> Altered {
-        if let MessagePayload::Handshake(hs) = &mut msg.payload {
-            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
-                for mut ext in ch.extensions.iter_mut() {
-                    if let ClientExtension::ServerName(snr) = &mut ext {
-                        snr.push(snr[0].clone());
-                    }
-                }
-            }
-        }
-        Altered::InPlace
-    }
+fn test_client_ct_policy_ignores_scts_from_unknown_logs() {
+    let (trusted, unknown) = (TestCtLog::new(), TestCtLog::new());
 
-    let (client, server) = make_pair(KeyType::Rsa);
-    let (mut client, mut server) = (client.into(), server.into());
-    transfer_altered(&mut client, duplicate_sni_payload, &mut server);
-    assert_eq!(
-        server.process_new_packets(),
-        Err(Error::PeerMisbehavedError(
-            "ClientHello SNI contains duplicate name types".into()
-        ))
-    );
+    for version in rustls::ALL_VERSIONS {
+        let kt = KeyType::Rsa;
+        let mut client_config = make_client_config_with_versions(kt, &[version]);
+        client_config.ct_policy = Some(make_ct_policy(&[&trusted], 1));
+        let scts = sct_list(&[&unknown], &kt.get_chain()[0]);
+        let server_config = make_server_config_with_scts(kt, &scts);
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Client(Error::TooFewValidScts {
+                    required: 1,
+                    valid: 0
+                }),
+                ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::BadCertificate)),
+            ])
+        );
+    }
 }
 
 #[test]
-fn test_server_rejects_empty_sni_extension() {
-    fn empty_sni_payload(msg: &mut Message) -> Altered {
-        if let MessagePayload::Handshake(hs) = &mut msg.payload {
-            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
-                for mut ext in ch.extensions.iter_mut() {
-                    if let ClientExtension::ServerName(snr) = &mut ext {
-                        snr.clear();
-                    }
-                }
-            }
+fn test_client_server_cert_policy_accepts_verified_certificate() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let policy = MockCertCheck::new(Ok(()));
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config.server_cert_policy = Some(policy.clone());
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, make_server_config(*kt));
+            do_handshake(&mut client, &mut server);
+            assert_eq!(policy.checked(), vec![vec![kt.get_chain()[0].clone()]]);
+            assert_eq!(policy.server_names(), vec![dns_name("localhost")]);
         }
-        Altered::InPlace
     }
-
-    let (client, server) = make_pair(KeyType::Rsa);
-    let (mut client, mut server) = (client.into(), server.into());
-    transfer_altered(&mut client, empty_sni_payload, &mut server);
-    assert_eq!(
-        server.process_new_packets(),
-        Err(Error::PeerMisbehavedError(
-            "ClientHello SNI did not contain a hostname".into()
-        ))
-    );
 }
 
 #[test]
-fn test_server_rejects_clients_without_any_kx_group_overlap() {
-    fn different_kx_group(msg: &mut Message) -> Altered {
-        if let MessagePayload::Handshake(hs) = &mut msg.payload {
-            if let HandshakePayload::ClientHello(ch) = &mut hs.payload {
-                for mut ext in ch.extensions.iter_mut() {
-                    if let ClientExtension::NamedGroups(ngs) = &mut ext {
-                        ngs.clear();
-                    }
-                    if let ClientExtension::KeyShare(ks) = &mut ext {
-                        ks.clear();
-                    }
-                }
-            }
-        }
-        Altered::InPlace
+fn test_client_server_cert_policy_rejects_verified_certificate() {
+    for version in rustls::ALL_VERSIONS {
+        let err = Error::InvalidCertificateData("organization not allowed".into());
+        let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        client_config.server_cert_policy = Some(MockCertCheck::new(Err(err.clone())));
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Client(err),
+                ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::BadCertificate)),
+            ])
+        );
     }
+}
 
-    let (client, server) = make_pair(KeyType::Rsa);
-    let (mut client, mut server) = (client.into(), server.into());
-    transfer_altered(&mut client, different_kx_group, &mut server);
-    assert_eq!(
-        server.process_new_packets(),
-        Err(Error::PeerIncompatibleError(
-            "no kx group overlap with client".into()
-        ))
-    );
+#[test]
+fn test_client_server_cert_policy_not_called_for_unverified_certificate() {
+    for version in rustls::ALL_VERSIONS {
+        let policy = MockCertCheck::new(Ok(()));
+        let mut client_config = make_client_config_with_versions(KeyType::Ecdsa, &[version]);
+        client_config.server_cert_policy = Some(policy.clone());
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, make_server_config(KeyType::Rsa));
+        assert_eq!(
+            do_handshake_until_both_error(&mut client, &mut server),
+            Err(vec![
+                ErrorFromPeer::Client(Error::InvalidCertificate(CertificateError::UnknownIssuer)),
+                ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::UnknownCA)),
+            ])
+        );
+        assert!(policy.checked().is_empty());
+    }
 }
 
 #[test]
@@ -0,0 +1,65 @@
+//! Compares the allocations made setting up client connections with and
+//! without a `ClientHelloTemplate`.
+//!
+//! This counts every allocation in the process, so it lives alone in
+//! its own test binary.
+
+mod common;
+use crate::common::{dns_name, make_client_config, KeyType};
+use rustls::client::ClientHelloTemplate;
+use rustls::ClientConnection;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Returns how many allocations making `count` connections with
+/// `connect` took.
+fn allocations_for(count: usize, connect: impl Fn() -> ClientConnection) -> usize {
+    // The first connection may set up state used by later ones.
+    drop(connect());
+
+    let before = ALLOCATIONS.load(Ordering::SeqCst);
+    for _ in 0..count {
+        drop(connect());
+    }
+    ALLOCATIONS.load(Ordering::SeqCst) - before
+}
+
+#[test]
+fn template_saves_allocations_in_connection_setup() {
+    let config = Arc::new(make_client_config(KeyType::Rsa));
+    let template = Arc::new(ClientHelloTemplate::new(config.clone()));
+
+    let without_template = allocations_for(100, || {
+        ClientConnection::new(config.clone(), dns_name("localhost")).unwrap()
+    });
+    let with_template = allocations_for(100, || {
+        ClientConnection::from_template(template.clone(), dns_name("localhost")).unwrap()
+    });
+
+    assert!(
+        with_template < without_template,
+        "{} allocations with template, {} without",
+        with_template,
+        without_template
+    );
+}
@@ -3,11 +3,13 @@
 use std::convert::{TryFrom, TryInto};
 use std::io;
 use std::ops::{Deref, DerefMut};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
+use rustls::client::{OcspStatus, OcspVerifier, ServerCertPolicy};
 use rustls::internal::msgs::codec::Reader;
 use rustls::internal::msgs::message::{Message, OpaqueMessage, PlainMessage};
-use rustls::server::AllowAnyAuthenticatedClient;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient};
+use rustls::sign;
 use rustls::Connection;
 use rustls::Error;
 use rustls::RootCertStore;
@@ -46,8 +48,11 @@ embed_files! {
     (ECDSA_CLIENT_FULLCHAIN, "ecdsa", "client.fullchain");
     (ECDSA_CLIENT_KEY, "ecdsa", "client.key");
     (ECDSA_CLIENT_REQ, "ecdsa", "client.req");
+    (ECDSA_DELEGATED_KEY, "ecdsa", "delegated.key");
+    (ECDSA_DELEGATED_SPKI, "ecdsa", "delegated.spki");
     (ECDSA_END_CERT, "ecdsa", "end.cert");
     (ECDSA_END_CHAIN, "ecdsa", "end.chain");
+    (ECDSA_END_DC_FULLCHAIN, "ecdsa", "end-dc.fullchain");
     (ECDSA_END_FULLCHAIN, "ecdsa", "end.fullchain");
     (ECDSA_END_KEY, "ecdsa", "end.key");
     (ECDSA_END_REQ, "ecdsa", "end.req");
@@ -81,12 +86,14 @@ embed_files! {
     (RSA_CLIENT_CHAIN, "rsa", "client.chain");
     (RSA_CLIENT_FULLCHAIN, "rsa", "client.fullchain");
     (RSA_CLIENT_KEY, "rsa", "client.key");
+    (RSA_CLIENT_PURPOSE_FULLCHAIN, "rsa", "client-purpose.fullchain");
     (RSA_CLIENT_REQ, "rsa", "client.req");
     (RSA_CLIENT_RSA, "rsa", "client.rsa");
     (RSA_END_CERT, "rsa", "end.cert");
     (RSA_END_CHAIN, "rsa", "end.chain");
     (RSA_END_FULLCHAIN, "rsa", "end.fullchain");
     (RSA_END_KEY, "rsa", "end.key");
+    (RSA_END_PURPOSE_FULLCHAIN, "rsa", "end-purpose.fullchain");
     (RSA_END_REQ, "rsa", "end.req");
     (RSA_END_RSA, "rsa", "end.rsa");
     (RSA_INTER_CERT, "rsa", "inter.cert");
@@ -177,7 +184,7 @@ where
     total
 }
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum KeyType {
     Rsa,
     Ecdsa,
@@ -219,6 +226,11 @@ impl KeyType {
             .collect()
     }
 
+    pub fn get_certified_client_key(&self) -> Arc<sign::CertifiedKey> {
+        let key = sign::any_supported_type(&self.get_client_key()).unwrap();
+        Arc::new(sign::CertifiedKey::new(self.get_client_chain(), key))
+    }
+
     fn get_client_key(&self) -> PrivateKey {
         PrivateKey(
             rustls_pemfile::pkcs8_private_keys(&mut io::BufReader::new(
@@ -243,6 +255,22 @@ pub fn make_server_config(kt: KeyType) -> ServerConfig {
     finish_server_config(kt, ServerConfig::builder().with_safe_defaults())
 }
 
+pub fn make_server_config_with_ocsp(kt: KeyType, ocsp: &[u8]) -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert_with_ocsp_and_sct(kt.get_chain(), kt.get_key(), ocsp.to_vec(), vec![])
+        .unwrap()
+}
+
+pub fn make_server_config_with_scts(kt: KeyType, sct_list: &[u8]) -> ServerConfig {
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert_with_ocsp_and_sct(kt.get_chain(), kt.get_key(), vec![], sct_list.to_vec())
+        .unwrap()
+}
+
 pub fn make_server_config_with_versions(
     kt: KeyType,
     versions: &[&'static rustls::SupportedProtocolVersion],
@@ -292,6 +320,18 @@ pub fn make_server_config_with_mandatory_client_auth(kt: KeyType) -> ServerConfi
         .unwrap()
 }
 
+pub fn make_server_config_with_optional_client_auth(kt: KeyType) -> ServerConfig {
+    let client_auth_roots = get_client_root_store(kt);
+
+    let client_auth = AllowAnyAnonymousOrAuthenticatedClient::new(client_auth_roots);
+
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(client_auth)
+        .with_single_cert(kt.get_chain(), kt.get_key())
+        .unwrap()
+}
+
 pub fn finish_client_config(
     kt: KeyType,
     config: rustls::ConfigBuilder<ClientConfig, rustls::WantsVerifier>,
@@ -472,3 +512,71 @@ impl io::Read for FailsReads {
         Err(io::Error::from(self.errkind))
     }
 }
+
+/// A check on the server's certificate, for the client to run once the
+/// chain is verified, which always answers `result`.  It records the
+/// certificates each call was given: the end-entity certificate, then
+/// any others the check is passed, like the OCSP responder's issuer.
+pub struct MockCertCheck<T> {
+    result: Result<T, Error>,
+    checked: Mutex<Vec<Vec<Certificate>>>,
+    server_names: Mutex<Vec<rustls::ServerName>>,
+}
+
+impl<T: Clone> MockCertCheck<T> {
+    pub fn new(result: Result<T, Error>) -> Arc<Self> {
+        Arc::new(Self {
+            result,
+            checked: Mutex::new(Vec::new()),
+            server_names: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// The certificates given to each call so far.
+    pub fn checked(&self) -> Vec<Vec<Certificate>> {
+        self.checked.lock().unwrap().clone()
+    }
+
+    /// The server names given to each call so far, for checks that take one.
+    pub fn server_names(&self) -> Vec<rustls::ServerName> {
+        self.server_names
+            .lock()
+            .unwrap()
+            .clone()
+    }
+
+    fn record(&self, certs: &[&Certificate]) -> Result<T, Error> {
+        self.checked.lock().unwrap().push(
+            certs
+                .iter()
+                .map(|&cert| cert.clone())
+                .collect(),
+        );
+        self.result.clone()
+    }
+}
+
+impl OcspVerifier for MockCertCheck<OcspStatus> {
+    fn verify(
+        &self,
+        _response_der: &[u8],
+        end_entity: &Certificate,
+        issuer: &Certificate,
+    ) -> Result<OcspStatus, Error> {
+        self.record(&[end_entity, issuer])
+    }
+}
+
+impl ServerCertPolicy for MockCertCheck<()> {
+    fn check(
+        &self,
+        end_entity: &Certificate,
+        server_name: &rustls::ServerName,
+    ) -> Result<(), Error> {
+        self.server_names
+            .lock()
+            .unwrap()
+            .push(server_name.clone());
+        self.record(&[end_entity])
+    }
+}
@@ -16,10 +16,9 @@
 //! one from a path directly (without using an environment variable), then those
 //! tests SHOULD NOT go in this file.
 //!
-//! XXX: These tests don't actually test the functionality; they just ensure
-//! the code coverage doesn't complain it isn't covered. TODO: Verify that the
-//! file was created successfully, with the right permissions, etc., and that it
-//! contains something like what we expect.
+//! XXX: Most of these tests don't actually test the functionality; they just
+//! ensure the code coverage doesn't complain it isn't covered.
+//! `key_log_file_has_expected_lines` checks the file's contents.
 
 #[allow(dead_code)]
 mod common;
@@ -29,7 +28,7 @@ use crate::common::{
     transfer, KeyType,
 };
 use std::{
-    env,
+    env, fs,
     io::Write,
     sync::{Arc, Mutex, Once},
 };
@@ -102,3 +101,67 @@ fn exercise_key_log_file_for_server() {
         }
     })
 }
+
+#[test]
+fn key_log_file_has_expected_lines() {
+    serialized(|| {
+        let path = env::temp_dir().join(format!("rustls-sslkeylogfile-{}.txt", std::process::id()));
+        let _ = fs::remove_file(&path);
+        env::set_var("SSLKEYLOGFILE", &path);
+
+        // Shared by client and server, so every secret appears twice.
+        let key_log = Arc::new(rustls::KeyLogFile::new());
+
+        let mut server_config = make_server_config(KeyType::Rsa);
+        server_config.key_log = key_log.clone();
+        let server_config = Arc::new(server_config);
+
+        let mut expected = vec![];
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+            client_config.key_log = key_log.clone();
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+
+            let labels: &[&str] = match version.version {
+                rustls::ProtocolVersion::TLSv1_3 => &[
+                    "CLIENT_HANDSHAKE_TRAFFIC_SECRET",
+                    "SERVER_HANDSHAKE_TRAFFIC_SECRET",
+                    "CLIENT_TRAFFIC_SECRET_0",
+                    "SERVER_TRAFFIC_SECRET_0",
+                    "EXPORTER_SECRET",
+                ],
+                _ => &["CLIENT_RANDOM"],
+            };
+            expected.extend(labels.iter().chain(labels));
+        }
+
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let mut labels = vec![];
+        for line in contents.lines() {
+            let fields: Vec<&str> = line.split(' ').collect();
+            assert_eq!(3, fields.len(), "bad line {:?}", line);
+            assert_eq!(64, fields[1].len(), "bad client random in {:?}", line);
+            assert!(fields[1..]
+                .iter()
+                .all(|f| f.bytes().all(|b| b.is_ascii_hexdigit())));
+            labels.push(fields[0]);
+        }
+
+        expected.sort_unstable();
+        labels.sort_unstable();
+        assert_eq!(expected, labels);
+    })
+}
+
+#[test]
+fn key_log_file_does_nothing_without_env_var() {
+    serialized(|| {
+        env::remove_var("SSLKEYLOGFILE");
+        let key_log = rustls::KeyLogFile::new();
+        assert!(!rustls::KeyLog::will_log(&key_log, "CLIENT_RANDOM"));
+    })
+}
@@ -0,0 +1,150 @@
+//! Tests for injecting messages with `ConnectionCommon::process_message`.
+
+#![cfg(feature = "message_api")]
+
+mod common;
+use crate::common::{
+    dns_name, do_handshake, make_client_config, make_client_config_with_versions,
+    make_server_config, transfer, KeyType,
+};
+use rustls::internal::msgs::base::PayloadU24;
+use rustls::internal::msgs::codec::{self, Reader};
+use rustls::internal::msgs::enums::HandshakeType;
+use rustls::internal::msgs::handshake::{
+    CompressedCertificatePayload, HandshakeMessagePayload, HandshakePayload,
+};
+use rustls::internal::msgs::message::{Message, MessagePayload, OpaqueMessage};
+use rustls::message_api::{decode_handshake, encode_handshake};
+use rustls::{CertDecompressor, CertificateCompressionAlgorithm};
+use rustls::{ClientConnection, Error, ProtocolVersion, ServerConnection};
+
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+/// Returns the single message `client` wants to send, unencrypted.
+fn first_message(client: &mut ClientConnection) -> Message {
+    let mut buf = Vec::new();
+    client.write_tls(&mut buf).unwrap();
+    let mut rd = Reader::init(&buf);
+    let msg = OpaqueMessage::read(&mut rd).unwrap();
+    assert!(!rd.any_left());
+    Message::try_from(msg.into_plain_message()).unwrap()
+}
+
+#[test]
+fn injected_client_hello_starts_handshake() {
+    let mut client = ClientConnection::new(
+        Arc::new(make_client_config(KeyType::Rsa)),
+        dns_name("localhost"),
+    )
+    .unwrap();
+    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
+
+    // round trip the ClientHello through the message API, then inject it
+    let mut client_hello = first_message(&mut client);
+    if let MessagePayload::Handshake(hs) = &mut client_hello.payload {
+        let bytes = encode_handshake(hs);
+        *hs = decode_handshake(&bytes, ProtocolVersion::TLSv1_2).unwrap();
+        assert_eq!(hs.typ, HandshakeType::ClientHello);
+        assert_eq!(encode_handshake(hs), bytes);
+    } else {
+        panic!("unexpected {:?}", client_hello);
+    }
+
+    let io_state = server
+        .process_message(client_hello)
+        .unwrap();
+    assert!(io_state.tls_bytes_to_write() > 0);
+    assert!(server.is_handshaking());
+
+    // the rest of the handshake goes over the usual path
+    transfer(&mut server, &mut client);
+    client.process_new_packets().unwrap();
+    do_handshake(&mut client, &mut server);
+    assert!(!server.is_handshaking());
+}
+
+#[test]
+fn injected_unexpected_message_is_fatal() {
+    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
+    let hello_request = Message {
+        version: ProtocolVersion::TLSv1_2,
+        payload: MessagePayload::Handshake(HandshakeMessagePayload {
+            typ: HandshakeType::HelloRequest,
+            payload: HandshakePayload::HelloRequest,
+        }),
+    };
+
+    let err = server
+        .process_message(hello_request)
+        .unwrap_err();
+    assert!(matches!(err, Error::InappropriateHandshakeMessage { .. }));
+    assert!(server.wants_write());
+
+    // the connection is now unusable, as with other errors
+    assert_eq!(
+        server
+            .process_new_packets()
+            .unwrap_err(),
+        err
+    );
+}
+
+struct NeverDecompresses(CertificateCompressionAlgorithm);
+
+impl CertDecompressor for NeverDecompresses {
+    fn algorithm(&self) -> CertificateCompressionAlgorithm {
+        self.0
+    }
+
+    fn decompress(&self, _input: &[u8], _output: &mut [u8]) -> bool {
+        panic!("unexpected decompression");
+    }
+}
+
+#[test]
+fn injected_certificate_with_unadvertised_compression_is_refused() {
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config.cert_decompressors = vec![Arc::new(NeverDecompresses(
+        CertificateCompressionAlgorithm::Zlib,
+    ))];
+    let mut client = ClientConnection::new(Arc::new(client_config), dns_name("localhost")).unwrap();
+    let mut server = ServerConnection::new(Arc::new(make_server_config(KeyType::Rsa))).unwrap();
+    transfer(&mut client, &mut server);
+    server.process_new_packets().unwrap();
+
+    // give the client the ServerHello, CCS and EncryptedExtensions
+    // records, leaving it waiting for the certificate
+    let mut flight = Vec::new();
+    server.write_tls(&mut flight).unwrap();
+    let mut used = 0;
+    for _ in 0..3 {
+        used += 5 + usize::from(u16::from_be_bytes([flight[used + 3], flight[used + 4]]));
+    }
+    client
+        .read_tls(&mut &flight[..used])
+        .unwrap();
+    client.process_new_packets().unwrap();
+
+    let compressed_cert = Message {
+        version: ProtocolVersion::TLSv1_3,
+        payload: MessagePayload::Handshake(HandshakeMessagePayload {
+            typ: HandshakeType::CompressedCertificate,
+            payload: HandshakePayload::CompressedCertificate(CompressedCertificatePayload {
+                alg: CertificateCompressionAlgorithm::Brotli,
+                uncompressed_len: codec::u24(100),
+                compressed: PayloadU24(vec![1, 2, 3]),
+            }),
+        }),
+    };
+
+    let err = client
+        .process_message(compressed_cert)
+        .unwrap_err();
+    assert_eq!(
+        err,
+        Error::PeerMisbehavedError("certificate compressed with unoffered algorithm".into())
+    );
+    assert!(client.wants_write());
+}
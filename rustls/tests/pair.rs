@@ -0,0 +1,90 @@
+//! Tests for the in-memory client/server pair in `rustls::pair`.
+
+#![cfg(feature = "test_support")]
+
+mod common;
+use crate::common::{
+    dns_name, make_client_config, make_client_config_with_versions, make_server_config, KeyType,
+};
+use rustls::pair::CompletedHandshake;
+use rustls::{CertificateError, Error};
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+#[test]
+fn pair_completes_full_handshake() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = make_client_config_with_versions(KeyType::Rsa, &[version]);
+        let mut pair = CompletedHandshake::new(
+            Arc::new(client_config),
+            dns_name("localhost"),
+            Arc::new(make_server_config(KeyType::Rsa)),
+        )
+        .unwrap();
+
+        assert!(!pair.client.is_handshaking());
+        assert!(!pair.server.is_handshaking());
+        assert!(!pair.client.is_resumed());
+        assert_eq!(pair.client.protocol_version(), Some(version.version));
+
+        pair.client
+            .writer()
+            .write_all(b"hello")
+            .unwrap();
+        pair.server
+            .writer()
+            .write_all(b"world")
+            .unwrap();
+        pair.transfer().unwrap();
+
+        let mut buf = [0u8; 5];
+        pair.server
+            .reader()
+            .read_exact(&mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"hello");
+        pair.client
+            .reader()
+            .read_exact(&mut buf)
+            .unwrap();
+        assert_eq!(&buf, b"world");
+    }
+}
+
+#[test]
+fn pair_completes_resumed_handshake() {
+    for version in rustls::ALL_VERSIONS {
+        let client_config = Arc::new(make_client_config_with_versions(KeyType::Rsa, &[version]));
+        let server_config = Arc::new(make_server_config(KeyType::Rsa));
+
+        let pair = CompletedHandshake::new(
+            Arc::clone(&client_config),
+            dns_name("localhost"),
+            Arc::clone(&server_config),
+        )
+        .unwrap();
+        assert!(!pair.client.is_resumed());
+
+        let pair =
+            CompletedHandshake::new(client_config, dns_name("localhost"), server_config).unwrap();
+        assert!(pair.client.is_resumed());
+        assert!(pair.server.is_resumed());
+    }
+}
+
+#[test]
+fn pair_reports_handshake_errors() {
+    let result = CompletedHandshake::new(
+        Arc::new(make_client_config(KeyType::Rsa)),
+        dns_name("not-the-right-hostname.com"),
+        Arc::new(make_server_config(KeyType::Rsa)),
+    );
+    assert_eq!(
+        result.err(),
+        Some(Error::InvalidCertificate(
+            CertificateError::NotValidForName {
+                name: "not-the-right-hostname.com".into()
+            }
+        ))
+    );
+}
@@ -0,0 +1,49 @@
+//! Tests for loading PKCS#12 bundles with `rustls::pkcs12`.
+
+#![cfg(feature = "pkcs12")]
+
+mod common;
+use crate::common::KeyType;
+use rustls::pkcs12::load_pkcs12;
+use rustls::{sign, Error};
+
+static END_P12: &[u8] = include_bytes!("../../test-ca/rsa/end.p12");
+static END_NOPASS_P12: &[u8] = include_bytes!("../../test-ca/rsa/end-nopass.p12");
+
+#[test]
+fn load_pkcs12_with_password() {
+    let (certs, key) = load_pkcs12(END_P12, "ponytown").unwrap();
+    assert_eq!(certs, KeyType::Rsa.get_chain());
+    assert_eq!(key, KeyType::Rsa.get_key());
+
+    let signing_key = sign::any_supported_type(&key).unwrap();
+    assert_eq!(
+        sign::CertifiedKey::new(certs, signing_key).validate(),
+        Ok(())
+    );
+}
+
+#[test]
+fn load_pkcs12_with_empty_password() {
+    let (certs, key) = load_pkcs12(END_NOPASS_P12, "").unwrap();
+    assert_eq!(certs, KeyType::Rsa.get_chain());
+    assert_eq!(key, KeyType::Rsa.get_key());
+}
+
+#[test]
+fn load_pkcs12_rejects_wrong_password() {
+    let wrong_password = Err(Error::General(
+        "PKCS#12 bundle password is incorrect".into(),
+    ));
+    assert_eq!(load_pkcs12(END_P12, "not ponytown"), wrong_password);
+    assert_eq!(load_pkcs12(END_P12, ""), wrong_password);
+    assert_eq!(load_pkcs12(END_NOPASS_P12, "ponytown"), wrong_password);
+}
+
+#[test]
+fn load_pkcs12_rejects_malformed_bundle() {
+    assert_eq!(
+        load_pkcs12(&END_P12[..END_P12.len() - 1], "ponytown"),
+        Err(Error::General("PKCS#12 bundle is malformed".into()))
+    );
+}
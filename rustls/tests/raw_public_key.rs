@@ -0,0 +1,271 @@
+//! Tests for negotiating raw public keys (RFC7250) in place of certificate chains.
+
+#![cfg(feature = "dangerous_configuration")]
+
+mod common;
+use crate::common::{
+    do_handshake, do_handshake_until_both_error, make_client_config_with_versions,
+    make_client_config_with_versions_with_auth, make_pair_for_configs, make_server_config,
+    make_server_config_with_mandatory_client_auth, ErrorFromPeer, KeyType, ALL_KEY_TYPES,
+};
+use rustls::client::{RawPublicKeyVerifier, ServerCertVerified, ServerCertVerifier};
+use rustls::internal::msgs::enums::AlertDescription;
+use rustls::x509::subject_public_key_info;
+use rustls::{Certificate, ClientConfig, Error, ServerConfig, ServerName};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+#[test]
+fn server_authenticates_with_raw_public_key() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_key = PinnedKey::new(&kt.get_chain()[0]);
+        let mut client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+        client_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoChainExpected));
+        client_config
+            .dangerous()
+            .set_raw_public_key_verifier(server_key.clone());
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, server_sending_raw_public_key(*kt));
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(server_key.calls(), 1);
+        assert_eq!(
+            client.peer_certificates(),
+            Some(&[server_key.as_certificate()][..])
+        );
+    }
+}
+
+#[test]
+fn client_authenticates_with_raw_public_key() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let client_key = PinnedKey::new(&kt.get_client_chain()[0]);
+        // No client auth is configured for X.509.
+        let mut server_config = make_server_config(*kt);
+        server_config
+            .dangerous()
+            .set_raw_public_key_verifier(client_key.clone());
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_sending_raw_public_key(*kt), server_config);
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(client_key.calls(), 1);
+        assert_eq!(
+            server.peer_certificates(),
+            Some(&[client_key.as_certificate()][..])
+        );
+    }
+}
+
+#[test]
+fn raw_public_keys_in_both_directions() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_key = PinnedKey::new(&kt.get_chain()[0]);
+        let client_key = PinnedKey::new(&kt.get_client_chain()[0]);
+
+        let mut client_config = client_sending_raw_public_key(*kt);
+        client_config
+            .dangerous()
+            .set_certificate_verifier(Arc::new(NoChainExpected));
+        client_config
+            .dangerous()
+            .set_raw_public_key_verifier(server_key.clone());
+
+        let mut server_config = server_sending_raw_public_key(*kt);
+        server_config
+            .dangerous()
+            .set_raw_public_key_verifier(client_key.clone());
+
+        let (mut client, mut server) = make_pair_for_configs(client_config, server_config);
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(
+            client.peer_certificates(),
+            Some(&[server_key.as_certificate()][..])
+        );
+        assert_eq!(
+            server.peer_certificates(),
+            Some(&[client_key.as_certificate()][..])
+        );
+    }
+}
+
+#[test]
+fn server_falls_back_to_x509_if_client_unwilling() {
+    for kt in ALL_KEY_TYPES.iter() {
+        for version in rustls::ALL_VERSIONS {
+            let client_config = make_client_config_with_versions(*kt, &[version]);
+
+            let (mut client, mut server) =
+                make_pair_for_configs(client_config, server_sending_raw_public_key(*kt));
+            do_handshake(&mut client, &mut server);
+
+            assert_eq!(client.peer_certificates(), Some(&kt.get_chain()[..]));
+        }
+    }
+}
+
+#[test]
+fn client_falls_back_to_x509_if_server_unwilling() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_key = PinnedKey::new(&kt.get_chain()[0]);
+        let mut client_config = client_sending_raw_public_key(*kt);
+        client_config
+            .dangerous()
+            .set_raw_public_key_verifier(server_key.clone());
+
+        let (mut client, mut server) = make_pair_for_configs(
+            client_config,
+            make_server_config_with_mandatory_client_auth(*kt),
+        );
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(server_key.calls(), 0);
+        assert_eq!(client.peer_certificates(), Some(&kt.get_chain()[..]));
+        assert_eq!(server.peer_certificates(), Some(&kt.get_client_chain()[..]));
+    }
+}
+
+#[test]
+fn raw_public_keys_are_not_offered_for_tls12() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let server_key = PinnedKey::new(&kt.get_chain()[0]);
+        let mut client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS12]);
+        client_config
+            .dangerous()
+            .set_raw_public_key_verifier(server_key.clone());
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, server_sending_raw_public_key(*kt));
+        do_handshake(&mut client, &mut server);
+
+        assert_eq!(server_key.calls(), 0);
+        assert_eq!(client.peer_certificates(), Some(&kt.get_chain()[..]));
+    }
+}
+
+#[test]
+fn client_rejects_unknown_raw_public_key() {
+    for kt in ALL_KEY_TYPES.iter() {
+        // Pinned to some other key.
+        let other_key = PinnedKey::new(&kt.get_client_chain()[0]);
+        let mut client_config = make_client_config_with_versions(*kt, &[&rustls::version::TLS13]);
+        client_config
+            .dangerous()
+            .set_raw_public_key_verifier(other_key.clone());
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_config, server_sending_raw_public_key(*kt));
+        let errs = do_handshake_until_both_error(&mut client, &mut server);
+
+        assert_eq!(other_key.calls(), 1);
+        assert_eq!(
+            errs,
+            Err(vec![
+                ErrorFromPeer::Client(Error::General("unknown raw public key".into())),
+                ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::BadCertificate)),
+            ])
+        );
+    }
+}
+
+#[test]
+fn server_rejects_unknown_raw_public_key() {
+    for kt in ALL_KEY_TYPES.iter() {
+        // Pinned to some other key.
+        let other_key = PinnedKey::new(&kt.get_chain()[0]);
+        let mut server_config = make_server_config(*kt);
+        server_config
+            .dangerous()
+            .set_raw_public_key_verifier(other_key.clone());
+
+        let (mut client, mut server) =
+            make_pair_for_configs(client_sending_raw_public_key(*kt), server_config);
+        let errs = do_handshake_until_both_error(&mut client, &mut server);
+
+        assert_eq!(other_key.calls(), 1);
+        assert_eq!(
+            errs,
+            Err(vec![
+                ErrorFromPeer::Server(Error::General("unknown raw public key".into())),
+                ErrorFromPeer::Client(Error::AlertReceived(AlertDescription::HandshakeFailure)),
+            ])
+        );
+    }
+}
+
+fn server_sending_raw_public_key(kt: KeyType) -> ServerConfig {
+    let mut server_config = make_server_config(kt);
+    server_config
+        .dangerous()
+        .set_send_raw_public_key(true);
+    server_config
+}
+
+fn client_sending_raw_public_key(kt: KeyType) -> ClientConfig {
+    let mut client_config =
+        make_client_config_with_versions_with_auth(kt, &[&rustls::version::TLS13]);
+    client_config
+        .dangerous()
+        .set_send_raw_public_key(true);
+    client_config
+}
+
+/// Accepts exactly one public key, counting how often it is asked.
+struct PinnedKey {
+    spki: Vec<u8>,
+    calls: AtomicUsize,
+}
+
+impl PinnedKey {
+    fn new(cert: &Certificate) -> Arc<Self> {
+        Arc::new(Self {
+            spki: subject_public_key_info(&cert.0)
+                .unwrap()
+                .to_vec(),
+            calls: AtomicUsize::new(0),
+        })
+    }
+
+    fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+
+    fn as_certificate(&self) -> Certificate {
+        Certificate(self.spki.clone())
+    }
+}
+
+impl RawPublicKeyVerifier for PinnedKey {
+    fn verify_raw_public_key(&self, spki: &[u8]) -> Result<(), Error> {
+        self.calls
+            .fetch_add(1, Ordering::SeqCst);
+        if spki == self.spki.as_slice() {
+            Ok(())
+        } else {
+            Err(Error::General("unknown raw public key".into()))
+        }
+    }
+}
+
+/// Must not be asked about certificate chains once raw public keys are agreed.
+struct NoChainExpected;
+
+impl ServerCertVerifier for NoChainExpected {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, Error> {
+        panic!("certificate chain verified in place of raw public key");
+    }
+}
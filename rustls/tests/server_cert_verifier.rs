@@ -4,15 +4,21 @@
 
 mod common;
 use crate::common::{
-    do_handshake, do_handshake_until_both_error, make_client_config_with_versions,
-    make_pair_for_arc_configs, make_server_config, ErrorFromPeer, ALL_KEY_TYPES,
+    do_handshake, do_handshake_until_both_error, get_client_root_store,
+    make_client_config_with_versions, make_pair_for_arc_configs, make_server_config,
+    make_server_config_with_ocsp, ErrorFromPeer, KeyType, ALL_KEY_TYPES,
 };
 use rustls::client::{
-    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier, WebPkiVerifier,
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier, SpkiPinVerifier,
+    WebPkiVerifier,
 };
 use rustls::internal::msgs::enums::AlertDescription;
 use rustls::internal::msgs::handshake::DigitallySignedStruct;
-use rustls::{Certificate, Error, SignatureScheme};
+use rustls::{
+    Certificate, CertificateError, ClientConfig, Error, OtherError, ServerConfig, SignatureScheme,
+};
+use std::error::Error as StdError;
+use std::io;
 use std::sync::Arc;
 
 #[test]
@@ -64,6 +70,37 @@ fn client_can_override_certificate_verification_and_reject_certificate() {
     }
 }
 
+#[test]
+fn client_reports_source_of_certificate_rejection() {
+    let cause = io::Error::new(io::ErrorKind::Other, "certificate lookup failed");
+    let verifier = Arc::new(MockServerVerifier::rejects_certificate(
+        OtherError::new(cause).into(),
+    ));
+
+    let server_config = Arc::new(make_server_config(KeyType::Rsa));
+    let mut client_config =
+        make_client_config_with_versions(KeyType::Rsa, &[&rustls::version::TLS13]);
+    client_config
+        .dangerous()
+        .set_certificate_verifier(verifier.clone());
+
+    let (mut client, mut server) =
+        make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+    let errs = do_handshake_until_both_error(&mut client, &mut server).unwrap_err();
+    let err = match &errs[0] {
+        ErrorFromPeer::Client(err) => err,
+        _ => panic!("unexpected errors {:?}", errs),
+    };
+
+    // The error is the one the verifier returned, source and all.
+    assert_eq!(Some(err), verifier.cert_rejection_error.as_ref());
+    let source = err
+        .source()
+        .and_then(|source| source.downcast_ref::<io::Error>())
+        .unwrap();
+    assert_eq!(source.to_string(), "certificate lookup failed");
+}
+
 #[test]
 fn client_can_override_certificate_verification_and_reject_tls12_signatures() {
     for kt in ALL_KEY_TYPES.iter() {
@@ -147,12 +184,208 @@ fn client_can_override_certificate_verification_and_offer_no_signature_schemes()
     }
 }
 
+#[test]
+fn client_offers_configured_signature_schemes_the_verifier_supports() {
+    let verifier = Arc::new(MockServerVerifier::offers_signature_schemes(&[
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ED25519,
+    ]));
+
+    for version in rustls::ALL_VERSIONS {
+        // RSA_PSS_SHA256 is configured, but isn't offered as the verifier
+        // doesn't support it.
+        let client_config = ClientConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[version])
+            .unwrap()
+            .with_signature_schemes(&[SignatureScheme::RSA_PSS_SHA256, SignatureScheme::ED25519])
+            .unwrap()
+            .with_custom_certificate_verifier(verifier.clone())
+            .with_no_client_auth();
+        let client_config = Arc::new(client_config);
+
+        let server_config = Arc::new(make_server_config(KeyType::Rsa));
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        let errs = do_handshake_until_both_error(&mut client, &mut server);
+        assert_eq!(
+            errs,
+            Err(vec![
+                ErrorFromPeer::Server(Error::PeerIncompatibleError(
+                    "no overlapping sigschemes".into()
+                )),
+                ErrorFromPeer::Client(Error::AlertReceived(AlertDescription::HandshakeFailure)),
+            ])
+        );
+
+        let server_config = Arc::new(make_server_config(KeyType::Ed25519));
+        let (mut client, mut server) = make_pair_for_arc_configs(&client_config, &server_config);
+        do_handshake(&mut client, &mut server);
+    }
+}
+
+#[test]
+fn client_verifier_receives_stapled_ocsp_response() {
+    let ocsp = b"a stapled ocsp response";
+
+    for kt in ALL_KEY_TYPES.iter() {
+        let verifier = Arc::new(MockServerVerifier::expects_ocsp_response(ocsp));
+        let server_config = Arc::new(make_server_config_with_ocsp(*kt, ocsp));
+
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config
+                .dangerous()
+                .set_certificate_verifier(verifier.clone());
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+        }
+    }
+}
+
+#[test]
+fn client_verifier_receives_no_ocsp_response_by_default() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let verifier = Arc::new(MockServerVerifier::expects_ocsp_response(&[]));
+        let server_config = Arc::new(make_server_config(*kt));
+
+        for version in rustls::ALL_VERSIONS {
+            let mut client_config = make_client_config_with_versions(*kt, &[version]);
+            client_config
+                .dangerous()
+                .set_certificate_verifier(verifier.clone());
+
+            let (mut client, mut server) =
+                make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+            do_handshake(&mut client, &mut server);
+        }
+    }
+}
+
+fn do_spki_pinned_handshake(
+    kt: KeyType,
+    verifier: &Arc<SpkiPinVerifier>,
+) -> Result<(), Vec<ErrorFromPeer>> {
+    do_spki_pinned_handshake_with_chain(kt, kt.get_chain(), verifier)
+}
+
+/// As `do_spki_pinned_handshake`, but with the server sending `chain`
+/// for its `kt` key.
+fn do_spki_pinned_handshake_with_chain(
+    kt: KeyType,
+    chain: Vec<Certificate>,
+    verifier: &Arc<SpkiPinVerifier>,
+) -> Result<(), Vec<ErrorFromPeer>> {
+    let server_config = Arc::new(
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(chain, kt.get_key())
+            .unwrap(),
+    );
+
+    for version in rustls::ALL_VERSIONS {
+        let mut client_config = make_client_config_with_versions(kt, &[version]);
+        client_config
+            .dangerous()
+            .set_certificate_verifier(verifier.clone());
+
+        let (mut client, mut server) =
+            make_pair_for_arc_configs(&Arc::new(client_config), &server_config);
+        do_handshake_until_both_error(&mut client, &mut server)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn spki_pin_verifier_accepts_pinned_end_entity() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let pin = SpkiPinVerifier::pin_for(&kt.get_chain()[0]).unwrap();
+        let verifier = Arc::new(SpkiPinVerifier::new(vec![pin], None));
+        assert_eq!(do_spki_pinned_handshake(*kt, &verifier), Ok(()));
+    }
+}
+
+#[test]
+fn spki_pin_verifier_accepts_pinned_intermediate() {
+    for kt in ALL_KEY_TYPES.iter() {
+        let pin = SpkiPinVerifier::pin_for(&kt.get_chain()[1]).unwrap();
+        let verifier = Arc::new(SpkiPinVerifier::new(
+            vec![pin],
+            Some(get_client_root_store(*kt)),
+        ));
+        assert_eq!(do_spki_pinned_handshake(*kt, &verifier), Ok(()));
+    }
+}
+
+#[test]
+fn spki_pin_verifier_rejects_unpinned_chain() {
+    let pin = SpkiPinVerifier::pin_for(&KeyType::Ecdsa.get_chain()[0]).unwrap();
+    let verifier = Arc::new(SpkiPinVerifier::new(
+        vec![pin],
+        Some(get_client_root_store(KeyType::Rsa)),
+    ));
+    assert_eq!(
+        do_spki_pinned_handshake(KeyType::Rsa, &verifier),
+        Err(vec![
+            ErrorFromPeer::Client(Error::NoPinMatched),
+            ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::BadCertificate)),
+        ])
+    );
+}
+
+#[test]
+fn spki_pin_verifier_ignores_pinned_intermediate_off_the_path() {
+    // The RSA intermediate didn't issue the ECDSA server certificate.
+    let pin = SpkiPinVerifier::pin_for(&KeyType::Rsa.get_chain()[1]).unwrap();
+    let ecdsa_chain = KeyType::Ecdsa.get_chain();
+    let rsa_inter = KeyType::Rsa.get_chain()[1].clone();
+    let expected = Err(vec![
+        ErrorFromPeer::Client(Error::NoPinMatched),
+        ErrorFromPeer::Server(Error::AlertReceived(AlertDescription::BadCertificate)),
+    ]);
+
+    let verifier = Arc::new(SpkiPinVerifier::new(vec![pin], None));
+    let chain = vec![ecdsa_chain[0].clone(), rsa_inter.clone()];
+    assert_eq!(
+        do_spki_pinned_handshake_with_chain(KeyType::Ecdsa, chain, &verifier),
+        expected
+    );
+
+    let verifier = Arc::new(SpkiPinVerifier::new(
+        vec![pin],
+        Some(get_client_root_store(KeyType::Ecdsa)),
+    ));
+    let chain = vec![ecdsa_chain[0].clone(), ecdsa_chain[1].clone(), rsa_inter];
+    assert_eq!(
+        do_spki_pinned_handshake_with_chain(KeyType::Ecdsa, chain, &verifier),
+        expected
+    );
+}
+
+#[test]
+fn spki_pin_verifier_checks_chain_before_pins() {
+    let pin = SpkiPinVerifier::pin_for(&KeyType::Rsa.get_chain()[0]).unwrap();
+    let verifier = Arc::new(SpkiPinVerifier::new(
+        vec![pin],
+        Some(get_client_root_store(KeyType::Ecdsa)),
+    ));
+    let errs = do_spki_pinned_handshake(KeyType::Rsa, &verifier).unwrap_err();
+    assert!(matches!(
+        errs[0],
+        ErrorFromPeer::Client(Error::InvalidCertificate(CertificateError::UnknownIssuer))
+    ));
+}
+
 pub struct MockServerVerifier {
     cert_rejection_error: Option<Error>,
     tls12_signature_error: Option<Error>,
     tls13_signature_error: Option<Error>,
     wants_scts: bool,
     signature_schemes: Vec<SignatureScheme>,
+    expected_ocsp_response: Option<Vec<u8>>,
 }
 
 impl ServerCertVerifier for MockServerVerifier {
@@ -170,6 +403,9 @@ impl ServerCertVerifier for MockServerVerifier {
             "verify_server_cert({:?}, {:?}, {:?}, {:?}, {:?}, {:?})",
             end_entity, intermediates, server_name, scts, oscp_response, now
         );
+        if let Some(expected) = &self.expected_ocsp_response {
+            assert_eq!(expected, oscp_response);
+        }
         if let Some(error) = &self.cert_rejection_error {
             Err(error.clone())
         } else {
@@ -250,12 +486,26 @@ impl MockServerVerifier {
         }
     }
 
+    pub fn expects_ocsp_response(response: &[u8]) -> Self {
+        MockServerVerifier {
+            expected_ocsp_response: Some(response.to_vec()),
+            ..Default::default()
+        }
+    }
+
     pub fn offers_no_signature_schemes() -> Self {
         MockServerVerifier {
             signature_schemes: vec![],
             ..Default::default()
         }
     }
+
+    pub fn offers_signature_schemes(schemes: &[SignatureScheme]) -> Self {
+        MockServerVerifier {
+            signature_schemes: schemes.to_vec(),
+            ..Default::default()
+        }
+    }
 }
 
 impl Default for MockServerVerifier {
@@ -266,6 +516,7 @@ impl Default for MockServerVerifier {
             tls13_signature_error: None,
             wants_scts: false,
             signature_schemes: WebPkiVerifier::verification_schemes(),
+            expected_ocsp_response: None,
         }
     }
 }
@@ -174,3 +174,109 @@ for kt in rsa ecdsa eddsa ; do
 
   openssl asn1parse -in $kt/ca.cert -out $kt/ca.der > /dev/null
 done
+
+# RSA end-entity and client certificates for the same keys, which
+# also assert the made-up key purpose 1.3.6.1.4.1.99999.1.
+openssl x509 -req \
+          -in rsa/end.req \
+          -out rsa/end-purpose.cert \
+          -CA rsa/inter.cert \
+          -CAkey rsa/inter.key \
+          -sha256 \
+          -days 2000 \
+          -set_serial 4567 \
+          -extensions v3_end_purpose -extfile openssl.cnf
+cat rsa/end-purpose.cert rsa/inter.cert rsa/ca.cert > rsa/end-purpose.fullchain
+
+openssl x509 -req \
+          -in rsa/client.req \
+          -out rsa/client-purpose.cert \
+          -CA rsa/inter.cert \
+          -CAkey rsa/inter.key \
+          -sha256 \
+          -days 2000 \
+          -set_serial 7890 \
+          -extensions v3_client_purpose -extfile openssl.cnf
+cat rsa/client-purpose.cert rsa/inter.cert rsa/ca.cert > rsa/client-purpose.fullchain
+
+# A third level of RSA intermediate, issued both by the RSA level 2
+# intermediate and, cross-signed, directly by the ECDSA CA; and the
+# RSA end-entity key certified by it.
+openssl req -nodes \
+          -newkey rsa:3072 \
+          -keyout rsa/inter3.key \
+          -out rsa/inter3.req \
+          -sha256 \
+          -batch \
+          -subj "/CN=ponytown RSA level 3 intermediate"
+
+openssl x509 -req \
+          -in rsa/inter3.req \
+          -out rsa/inter3.cert \
+          -CA rsa/inter.cert \
+          -CAkey rsa/inter.key \
+          -sha256 \
+          -days 2000 \
+          -set_serial 1234 \
+          -extensions v3_inter -extfile openssl.cnf
+
+openssl x509 -req \
+          -in rsa/inter3.req \
+          -out rsa/inter3-cross.cert \
+          -CA ecdsa/ca.cert \
+          -CAkey ecdsa/ca.key \
+          -sha384 \
+          -days 2000 \
+          -set_serial 1235 \
+          -extensions v3_inter -extfile openssl.cnf
+
+openssl x509 -req \
+          -in rsa/end.req \
+          -out rsa/end-inter3.cert \
+          -CA rsa/inter3.cert \
+          -CAkey rsa/inter3.key \
+          -sha256 \
+          -days 2000 \
+          -set_serial 4568 \
+          -extensions v3_end_cross -extfile openssl.cnf
+
+# The ECDSA end-entity key again, certified to issue delegated
+# credentials; and a P-256 key to delegate to, with its public key
+# as a DER SubjectPublicKeyInfo.
+openssl x509 -req \
+          -in ecdsa/end.req \
+          -out ecdsa/end-dc.cert \
+          -CA ecdsa/inter.cert \
+          -CAkey ecdsa/inter.key \
+          -sha256 \
+          -days 2000 \
+          -set_serial 4569 \
+          -extensions v3_end_dc -extfile openssl.cnf
+cat ecdsa/end-dc.cert ecdsa/inter.cert ecdsa/ca.cert > ecdsa/end-dc.fullchain
+
+openssl genpkey -algorithm EC \
+          -pkeyopt ec_paramgen_curve:P-256 \
+          -out ecdsa/delegated.key
+openssl pkey -in ecdsa/delegated.key \
+          -pubout -outform DER \
+          -out ecdsa/delegated.spki
+
+# PKCS#12 bundles of the RSA end-entity chain and key, one with a
+# password and one without.
+openssl pkcs12 -export \
+          -in rsa/end.fullchain \
+          -inkey rsa/end.key \
+          -out rsa/end.p12 \
+          -keypbe PBE-SHA1-3DES \
+          -certpbe PBE-SHA1-3DES \
+          -macalg sha1 \
+          -passout pass:ponytown
+
+openssl pkcs12 -export \
+          -in rsa/end.fullchain \
+          -inkey rsa/end.key \
+          -out rsa/end-nopass.p12 \
+          -keypbe PBE-SHA1-3DES \
+          -certpbe PBE-SHA1-3DES \
+          -macalg sha1 \
+          -passout pass: